@@ -32,6 +32,9 @@ pack() {
     cp "target/$TARGET/release/mkpatch" "$tempdir/$package_name/"
     strip "$tempdir/$package_name/mkpatch"
 
+    cp "target/$TARGET/release/grufctl" "$tempdir/$package_name/"
+    strip "$tempdir/$package_name/grufctl"
+
     # examples
     cp -r examples "$tempdir/$package_name/"
 
@@ -1,8 +1,11 @@
-use std::io::Write;
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Component, Path};
 
 use crate::{GrufError, Result};
 use encoding::label::encoding_from_whatwg_label;
-use encoding::EncoderTrap;
+use encoding::{DecoderTrap, EncoderTrap};
+use memmap2::Mmap;
 
 pub struct GenericFileEntry {
     pub offset: u64,
@@ -11,12 +14,65 @@ pub struct GenericFileEntry {
     pub size_compressed: u32,
 }
 
-/// Serializes string into a NULL-terminated list of win1252 chars and write it
-/// into writer.
+/// Indicates how the names of archives' entries are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// Names are decoded byte per byte as win1252. This is lossless and what
+    /// most tools expect
+    Windows1252,
+    /// Names are encoded in CP949 (EUC-KR), as written by Korean tools
+    Cp949,
+    /// Names are encoded in UTF-8
+    Utf8,
+    /// Names are decoded as UTF-8 or CP949 when valid, win1252 otherwise.
+    /// Names are written in CP949 when possible, win1252 otherwise
+    Auto,
+}
+
+/// Decodes an entry's name encoded with `name_encoding`.
+pub fn decode_name(bytes: &[u8], name_encoding: NameEncoding) -> Result<String> {
+    match name_encoding {
+        NameEncoding::Windows1252 => decode_with_label(bytes, "windows-1252"),
+        NameEncoding::Cp949 => decode_with_label(bytes, "euc-kr"),
+        NameEncoding::Utf8 => String::from_utf8(bytes.to_vec())
+            .map_err(|_| GrufError::parsing_error("Invalid UTF-8 name")),
+        NameEncoding::Auto => decode_name(bytes, NameEncoding::Utf8)
+            .or_else(|_| decode_name(bytes, NameEncoding::Cp949))
+            .or_else(|_| decode_name(bytes, NameEncoding::Windows1252)),
+    }
+}
+
+/// Encodes an entry's name with `name_encoding`.
+pub fn encode_name(name: &str, name_encoding: NameEncoding) -> Result<Vec<u8>> {
+    match name_encoding {
+        NameEncoding::Windows1252 => serialize_to_win1252(name),
+        NameEncoding::Cp949 => encode_with_label(name, "euc-kr"),
+        NameEncoding::Utf8 => Ok(name.as_bytes().to_vec()),
+        NameEncoding::Auto => encode_name(name, NameEncoding::Cp949)
+            .or_else(|_| encode_name(name, NameEncoding::Windows1252)),
+    }
+}
+
+/// Converts a name parsed as win1252 into a name decoded with `name_encoding`.
+pub fn transcode_win1252_name(name: String, name_encoding: NameEncoding) -> Result<String> {
+    if name_encoding == NameEncoding::Windows1252 || name.is_ascii() {
+        return Ok(name);
+    }
+    // Note: win1252 decoding is lossless, this gives us back the original bytes
+    let bytes = serialize_to_win1252(&name)?;
+    decode_name(&bytes, name_encoding)
+}
+
+/// Serializes an entry's name into a NULL-terminated list of chars encoded
+/// with `name_encoding` and write it into writer.
 ///
 /// Used in GRF archives
-pub fn serialize_as_win1252_cstr_into<W: Write>(mut writer: W, string: &str) -> Result<()> {
-    let mut vec = serialize_to_win1252(string)?;
+pub fn serialize_name_as_cstr_into<W: Write>(
+    mut writer: W,
+    name: &str,
+    name_encoding: NameEncoding,
+) -> Result<()> {
+    let mut vec = encode_name(name, name_encoding)?;
     vec.push(0); // NUL char terminator
     writer.write_all(vec.as_slice())?;
     Ok(())
@@ -32,9 +88,178 @@ pub fn serialize_as_win1252_str_into<W: Write>(mut writer: W, string: &str) -> R
 }
 
 pub fn serialize_to_win1252(string: &str) -> Result<Vec<u8>> {
-    let decoder = encoding_from_whatwg_label("windows-1252")
+    encode_with_label(string, "windows-1252")
+}
+
+/// Generates the name of the entry of a file located in `root_directory`
+/// (e.g. "data\\texture\\file.bmp").
+pub(crate) fn archive_entry_path(root_directory: &Path, file_path: &Path) -> Result<String> {
+    let relative_path = file_path.strip_prefix(root_directory).map_err(|_| {
+        GrufError::serialization_error(format!(
+            "'{}' isn't located in '{}'",
+            file_path.display(),
+            root_directory.display()
+        ))
+    })?;
+    let components = relative_path
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<&str>>>()
+        .ok_or_else(|| {
+            GrufError::serialization_error(format!(
+                "Invalid file path '{}'",
+                relative_path.display()
+            ))
+        })?;
+    Ok(components.join("\\"))
+}
+
+/// Indicates whether an entry's path matches a glob `pattern`.
+///
+/// `?` matches any character and `*` any sequence of characters, except path
+/// separators, while `**` matches across directories. Matching is
+/// case-insensitive and `/` is equivalent to `\`, like in game clients.
+pub fn match_entry_path(pattern: &str, entry_path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(normalize_path_char).collect();
+    let entry_path: Vec<char> = entry_path.chars().map(normalize_path_char).collect();
+    match_glob(&pattern, &entry_path)
+}
+
+fn normalize_path_char(c: char) -> char {
+    match c {
+        '/' => '\\',
+        _ => c.to_ascii_lowercase(),
+    }
+}
+
+fn match_glob(pattern: &[char], path: &[char]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some(('*', rest)) => {
+            let (crosses_directories, rest) = match rest.split_first() {
+                Some(('*', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            // Try every possible length for the sequence matched by the star
+            for i in 0..=path.len() {
+                if match_glob(rest, &path[i..]) {
+                    return true;
+                }
+                if i < path.len() && path[i] == '\\' && !crosses_directories {
+                    break;
+                }
+            }
+            false
+        }
+        Some(('?', rest)) => match path.split_first() {
+            Some((c, path_rest)) if *c != '\\' => match_glob(rest, path_rest),
+            _ => false,
+        },
+        Some((pc, rest)) => match path.split_first() {
+            Some((c, path_rest)) if c == pc => match_glob(rest, path_rest),
+            _ => false,
+        },
+    }
+}
+
+/// Maps the file located at `path` in memory, for archives to be read without
+/// going through system calls.
+pub fn map_file<P: AsRef<Path>>(path: P) -> Result<Cursor<Mmap>> {
+    let file = File::open(path)?;
+    // Safety: The mapping is read-only, archives are expected not to be
+    // modified by other processes while they're being read
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(Cursor::new(mmap))
+}
+
+fn encode_with_label(string: &str, label: &str) -> Result<Vec<u8>> {
+    let encoder = encoding_from_whatwg_label(label)
         .ok_or_else(|| GrufError::serialization_error("Encoder unavailable"))?;
-    decoder
+    encoder
         .encode(string, EncoderTrap::Strict)
         .map_err(|_| GrufError::serialization_error("Encoding failed"))
 }
+
+fn decode_with_label(bytes: &[u8], label: &str) -> Result<String> {
+    let decoder = encoding_from_whatwg_label(label)
+        .ok_or_else(|| GrufError::parsing_error("Decoder unavailable"))?;
+    decoder
+        .decode(bytes, DecoderTrap::Strict)
+        .map_err(GrufError::parsing_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_encodings() {
+        // "유저인터페이스" as stored by Korean tools
+        let cp949_bytes: &[u8] = &[
+            0xc0, 0xaf, 0xc0, 0xfa, 0xc0, 0xce, 0xc5, 0xcd, 0xc6, 0xe4, 0xc0, 0xcc, 0xbd, 0xba,
+        ];
+        let korean_name = "유저인터페이스";
+        let win1252_name = decode_name(cp949_bytes, NameEncoding::Windows1252).unwrap();
+        assert_eq!(
+            decode_name(cp949_bytes, NameEncoding::Cp949).unwrap(),
+            korean_name
+        );
+        assert_eq!(
+            decode_name(cp949_bytes, NameEncoding::Auto).unwrap(),
+            korean_name
+        );
+        assert!(decode_name(cp949_bytes, NameEncoding::Utf8).is_err());
+        assert_eq!(
+            decode_name(korean_name.as_bytes(), NameEncoding::Auto).unwrap(),
+            korean_name
+        );
+        assert_eq!(
+            transcode_win1252_name(win1252_name.clone(), NameEncoding::Cp949).unwrap(),
+            korean_name
+        );
+        assert_eq!(
+            transcode_win1252_name(win1252_name.clone(), NameEncoding::Windows1252).unwrap(),
+            win1252_name
+        );
+        // Names are normalized to CP949 when written
+        assert_eq!(
+            encode_name(korean_name, NameEncoding::Auto).unwrap(),
+            cp949_bytes
+        );
+        assert_eq!(
+            encode_name(&win1252_name, NameEncoding::Windows1252).unwrap(),
+            cp949_bytes
+        );
+        // Fallback on win1252 for names that aren't valid CP949
+        let invalid_cp949_bytes: &[u8] = b"data\\\xe9t\xe9.txt";
+        assert_eq!(
+            decode_name(invalid_cp949_bytes, NameEncoding::Auto).unwrap(),
+            "data\\\u{e9}t\u{e9}.txt"
+        );
+    }
+
+    #[test]
+    fn test_match_entry_path() {
+        let entry_path = "data\\texture\\Effect\\Smoke.bmp";
+        assert!(match_entry_path(entry_path, entry_path));
+        assert!(match_entry_path(
+            "data/texture/effect/smoke.bmp",
+            entry_path
+        ));
+        assert!(match_entry_path("data\\texture\\effect\\*.bmp", entry_path));
+        assert!(match_entry_path("data\\texture\\*\\sm?ke.*", entry_path));
+        assert!(match_entry_path("data\\**.bmp", entry_path));
+        assert!(match_entry_path("**\\*.bmp", entry_path));
+        assert!(match_entry_path("**", entry_path));
+        assert!(!match_entry_path("data\\*.bmp", entry_path));
+        assert!(!match_entry_path(
+            "data\\texture\\effect?smoke.bmp",
+            entry_path
+        ));
+        assert!(!match_entry_path("*.txt", entry_path));
+        assert!(!match_entry_path("data\\texture", entry_path));
+    }
+}
@@ -23,6 +23,8 @@ pub enum GrufError {
     SerializationError(String),
     #[error("dyn_alloc error")]
     DynAllocError,
+    #[error("archive exceeds the limits of its format: {0}")]
+    SizeLimitExceeded(String),
 }
 
 impl GrufError {
@@ -37,4 +39,8 @@ impl GrufError {
     pub fn serialization_error(msg: impl Into<String>) -> Self {
         Self::SerializationError(msg.into())
     }
+
+    pub fn size_limit_exceeded(msg: impl Into<String>) -> Self {
+        Self::SizeLimitExceeded(msg.into())
+    }
 }
@@ -1,14 +1,17 @@
 use std::boxed::Box;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-use crate::archive::{serialize_as_win1252_cstr_into, GenericFileEntry};
+use crate::archive::{
+    archive_entry_path, serialize_name_as_cstr_into, GenericFileEntry, NameEncoding,
+};
 use crate::grf::dyn_alloc::{self, AvailableChunkList};
-use crate::grf::{GrfArchive, GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
-use crate::thor::ThorArchive;
+use crate::grf::reader::{GrfFileEncryption, GrfHeader, GRF_TABLE_INFO3_PADDING};
+use crate::grf::{GrfArchive, GrfFileEntry, GrfIndex, GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
+use crate::thor::{ThorArchive, ThorCompression};
 use crate::{GrufError, Result};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
@@ -22,8 +25,18 @@ pub struct GrfArchiveBuilder<W: Write + Seek> {
     finished: bool,
     version_major: u32,
     version_minor: u32,
+    name_encoding: NameEncoding,
+    compression: Compression,
     entries: HashMap<String, GenericFileEntry>,
     chunks: AvailableChunkList,
+    written_table: Option<WrittenTable>, // Set once the archive is finished
+}
+
+/// Location and sizes of the file table written by `finish`.
+struct WrittenTable {
+    offset: u64,
+    size_compressed: usize,
+    size: usize,
 }
 
 #[derive(Debug, Serialize)]
@@ -35,6 +48,16 @@ struct SerializableGrfHeader {
     pub version: u32,
 }
 
+#[derive(Debug, Serialize)]
+struct SerializableGrfFileEntry300 {
+    // Note: relative_path isn't fixed-length
+    size_compressed: u32,
+    size_compressed_aligned: u32,
+    size: u32,
+    entry_type: u8,
+    offset: u64,
+}
+
 #[derive(Debug, Serialize)]
 struct SerializableGrfFileEntry200 {
     // Note(LinkZ): relative_path isn't fixed-length
@@ -47,7 +70,18 @@ struct SerializableGrfFileEntry200 {
 }
 
 impl<W: Write + Seek> GrfArchiveBuilder<W> {
-    pub fn create(mut obj: W, version_major: u32, version_minor: u32) -> Result<Self> {
+    pub fn create(obj: W, version_major: u32, version_minor: u32) -> Result<Self> {
+        Self::create_with_encoding(obj, version_major, version_minor, NameEncoding::Windows1252)
+    }
+
+    /// Create a new archive whose entries' names are encoded with
+    /// `name_encoding`.
+    pub fn create_with_encoding(
+        mut obj: W,
+        version_major: u32,
+        version_minor: u32,
+        name_encoding: NameEncoding,
+    ) -> Result<Self> {
         let start_offset = obj.seek(io::SeekFrom::Current(0)).unwrap_or(0);
         // Placeholder for the GRF header
         obj.write_all(&[0; GRF_HEADER_SIZE])?;
@@ -57,20 +91,28 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             finished: false,
             version_major,
             version_minor,
+            name_encoding,
+            compression: Compression::default(),
             entries: HashMap::new(),
             chunks: AvailableChunkList::new(),
+            written_table: None,
         })
     }
 
-    pub fn import_raw_entry_from_grf(
+    pub fn import_raw_entry_from_grf<R: Read + Seek>(
         &mut self,
-        archive: &mut GrfArchive,
+        archive: &mut GrfArchive<R>,
         relative_path: String,
     ) -> Result<()> {
         let entry = archive
             .get_file_entry(&relative_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
+        if entry.encryption != GrfFileEncryption::Unencrypted {
+            // Entries of GRF 1.x archives are scrambled, store them in clear
+            let content = archive.read_file_content(&relative_path)?;
+            return self.add_file(relative_path, content.as_slice());
+        }
         let content = archive.get_entry_raw_data(&relative_path)?;
         let offset = {
             if let Some(grf_entry) = self.entries.get(&relative_path) {
@@ -84,6 +126,7 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             }
         };
 
+        self.check_entry_offset(offset)?;
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
         let mut content_reader = Cursor::new(content);
         let content_size = io::copy(&mut content_reader, self.obj.by_ref())?;
@@ -108,6 +151,11 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             .get_file_entry(&relative_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
+        if entry.compression != ThorCompression::Zlib || entry.is_encrypted() {
+            // GRF entries can only be compressed with zlib, and aren't encrypted
+            let content = thor_archive.read_file_content(&relative_path)?;
+            return self.add_file(relative_path, content.as_slice());
+        }
         let content = thor_archive.get_entry_raw_data(&relative_path)?;
         let offset = {
             if let Some(grf_entry) = self.entries.get(&relative_path) {
@@ -121,6 +169,7 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             }
         };
 
+        self.check_entry_offset(offset)?;
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
         let mut content_reader = Cursor::new(content);
         let _ = io::copy(&mut content_reader, self.obj.by_ref())?;
@@ -135,13 +184,64 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
         Ok(())
     }
 
-    pub fn add_file<R: Read>(&mut self, relative_path: String, mut data: R) -> Result<()> {
-        // Compress it
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        let data_size = io::copy(data.by_ref(), &mut encoder)?;
+    /// Set the zlib compression level of the files added afterwards, from 0
+    /// (no compression) to 9 (best compression). Defaults to 6.
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression = Compression::new(level.min(9));
+    }
+
+    pub fn add_file<R: Read>(&mut self, relative_path: String, data: R) -> Result<()> {
+        let (data_size, compressed_data) = compress_with_level(data, self.compression)?;
+        self.add_compressed_file(relative_path, data_size, &compressed_data)
+    }
+
+    /// Add the given files, named after their path relative to
+    /// `root_directory` (with Windows-style separators).
+    pub fn add_files<I, P>(&mut self, root_directory: impl AsRef<Path>, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        for file_path in files {
+            let entry_path = archive_entry_path(root_directory.as_ref(), file_path.as_ref())?;
+            let file = File::open(file_path.as_ref())?;
+            self.add_file(entry_path, file)?;
+        }
+        Ok(())
+    }
+
+    /// Add all the files contained in `directory_path` and its
+    /// subdirectories, named after their path relative to `root_directory`
+    /// (with Windows-style separators).
+    pub fn add_directory(
+        &mut self,
+        root_directory: impl AsRef<Path>,
+        directory_path: impl AsRef<Path>,
+    ) -> Result<()> {
+        let mut dir_entries = fs::read_dir(directory_path)?.collect::<io::Result<Vec<_>>>()?;
+        // Keep the archive's content deterministic
+        dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
+        for dir_entry in dir_entries {
+            let file_type = dir_entry.file_type()?;
+            if file_type.is_dir() {
+                self.add_directory(root_directory.as_ref(), dir_entry.path())?;
+            } else if file_type.is_file() {
+                self.add_files(root_directory.as_ref(), &[dir_entry.path()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a file whose content has already been compressed with
+    /// `compress_file_content`. `data_size` is the size of the uncompressed
+    /// content.
+    pub fn add_compressed_file(
+        &mut self,
+        relative_path: String,
+        data_size: u64,
+        compressed_data: &[u8],
+    ) -> Result<()> {
         let data_size_u32 = u32::try_from(data_size)?;
-        // Write compressed data
-        let compressed_data = encoder.finish()?;
         let compressed_data_size = compressed_data.len();
         let offset = {
             if let Some(grf_entry) = self.entries.get(&relative_path) {
@@ -155,9 +255,9 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             }
         };
 
+        self.check_entry_offset(offset)?;
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut compressed_reader = Cursor::new(compressed_data);
-        let _ = io::copy(&mut compressed_reader, self.obj.by_ref())?;
+        self.obj.write_all(compressed_data)?;
         let compressed_data_size_u32 = u32::try_from(compressed_data_size)?;
         self.entries.insert(
             relative_path,
@@ -187,61 +287,183 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
         self.finished = true;
 
         let v_file_count = i32::try_from(self.entries.len() + 7)?;
-        let file_table_offset = match self.version_major {
-            2 => self.write_grf_table_200()?,
-            1 => std::unimplemented!(), // TODO(LinkZ): Implement
+        let written_table = match self.version_major {
+            2 | 3 => self.write_grf_table_200()?,
+            1 => {
+                return Err(GrufError::serialization_error(
+                    "Writing GRF 1.x archives isn't supported",
+                ))
+            }
             _ => return Err(GrufError::serialization_error("Wrong file format version")),
         };
         // Update the header
         self.obj.seek(SeekFrom::Start(self.start_offset))?;
         write_grf_header(
             (self.version_major << 8) | (self.version_minor),
-            (file_table_offset - GRF_HEADER_SIZE as u64) as u32,
+            written_table.offset - GRF_HEADER_SIZE as u64,
             v_file_count,
             &mut self.obj,
-        )
+        )?;
+        self.written_table = Some(written_table);
+        Ok(())
+    }
+
+    /// Returns the index of the archive, as it would be parsed by
+    /// `GrfArchive`, once the archive has been finished.
+    pub fn index(&self) -> Option<GrfIndex> {
+        let written_table = self.written_table.as_ref()?;
+        let header = GrfHeader {
+            key: GRF_FIXED_KEY,
+            file_table_offset: written_table.offset - GRF_HEADER_SIZE as u64,
+            seed: 0,
+            file_count: self.entries.len(),
+            version_major: self.version_major,
+            version_minor: self.version_minor,
+        };
+        let entries = self
+            .entries
+            .iter()
+            .map(|(relative_path, entry)| {
+                (
+                    relative_path.clone(),
+                    GrfFileEntry {
+                        relative_path: relative_path.clone(),
+                        size_compressed: entry.size_compressed as usize,
+                        size_compressed_aligned: entry.size_compressed as usize,
+                        size: entry.size as usize,
+                        entry_type: 1,
+                        offset: entry.offset,
+                        encryption: GrfFileEncryption::Unencrypted,
+                    },
+                )
+            })
+            .collect();
+        Some(GrfIndex::new_200(
+            header,
+            written_table.size_compressed,
+            written_table.size,
+            entries,
+        ))
+    }
+
+    /// Checks that an entry located at `offset` can be referenced in the
+    /// archive's file table.
+    fn check_entry_offset(&self, offset: u64) -> Result<()> {
+        // Only GRF 3.0 archives use 64-bit offsets
+        if self.version_major < 3 && u32::try_from(offset - GRF_HEADER_SIZE as u64).is_err() {
+            return Err(GrufError::size_limit_exceeded(
+                "GRF entries must be located in the first 4 GiB (use GRF 3.0 for larger archives)",
+            ));
+        }
+        Ok(())
     }
 
-    fn write_grf_table_200(&mut self) -> Result<u64> {
+    // Note: Also used for GRF 3.0 archives, which only differ by their offsets
+    fn write_grf_table_200(&mut self) -> Result<WrittenTable> {
         let mut table: Vec<u8> = Vec::new();
         // Generate table and write files' content
         for (relative_path, entry) in &self.entries {
-            let grf_file_entry = SerializableGrfFileEntry200 {
-                size_compressed: entry.size_compressed,
-                size_compressed_aligned: entry.size_compressed,
-                size: entry.size,
-                entry_type: 1,
-                offset: (entry.offset - GRF_HEADER_SIZE as u64) as u32,
-            };
-            serialize_as_win1252_cstr_into(&mut table, &relative_path)?;
-            bincode::serialize_into(&mut table, &grf_file_entry)?;
+            serialize_name_as_cstr_into(&mut table, &relative_path, self.name_encoding)?;
+            let offset = entry.offset - GRF_HEADER_SIZE as u64;
+            if self.version_major == 3 {
+                let grf_file_entry = SerializableGrfFileEntry300 {
+                    size_compressed: entry.size_compressed,
+                    size_compressed_aligned: entry.size_compressed,
+                    size: entry.size,
+                    entry_type: 1,
+                    offset,
+                };
+                bincode::serialize_into(&mut table, &grf_file_entry)?;
+            } else {
+                let grf_file_entry = SerializableGrfFileEntry200 {
+                    size_compressed: entry.size_compressed,
+                    size_compressed_aligned: entry.size_compressed,
+                    size: entry.size,
+                    entry_type: 1,
+                    offset: u32::try_from(offset).map_err(|_| {
+                        GrufError::size_limit_exceeded(
+                            "GRF entries must be located in the first 4 GiB",
+                        )
+                    })?,
+                };
+                bincode::serialize_into(&mut table, &grf_file_entry)?;
+            }
         }
         // Compress the table
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
         encoder.write_all(&table)?;
         let compressed_table = encoder.finish()?;
         let compressed_table_size = compressed_table.len();
+        let table_padding = match self.version_major {
+            3 => GRF_TABLE_INFO3_PADDING as usize,
+            _ => 0,
+        };
         let table_offset = self
             .chunks
-            .alloc_chunk(compressed_table_size + 2 * std::mem::size_of::<u32>())?;
+            .alloc_chunk(table_padding + compressed_table_size + 2 * std::mem::size_of::<u32>())?;
         let table_size_u32 = u32::try_from(table.len())?;
         let compressed_table_size_u32 = u32::try_from(compressed_table_size)?;
         self.obj
             .seek(SeekFrom::Start(self.start_offset + table_offset))?;
+        // GRF 3.0 file tables start with 4 unused bytes
+        self.obj.write_all(&vec![0; table_padding])?;
         // Write table's offset and size
         bincode::serialize_into(self.obj.by_ref(), &compressed_table_size_u32)?;
         bincode::serialize_into(self.obj.by_ref(), &table_size_u32)?;
         // Write table's content
         self.obj.write_all(&compressed_table)?;
-        // Return file table's offset
-        Ok(table_offset)
+        Ok(WrittenTable {
+            offset: table_offset,
+            size_compressed: compressed_table_size,
+            size: table.len(),
+        })
     }
 }
 
 impl GrfArchiveBuilder<File> {
     pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
-        let mut grf_archive = GrfArchive::open(&grf_path)?;
-        let chunks = dyn_alloc::list_available_chunks(&mut grf_archive)?;
+        Self::open_with_encoding(grf_path, NameEncoding::Windows1252)
+    }
+
+    /// Open an existing archive whose entries' names are encoded with
+    /// `name_encoding`.
+    pub fn open_with_encoding<P: AsRef<Path>>(
+        grf_path: P,
+        name_encoding: NameEncoding,
+    ) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(&grf_path)?;
+        Self::open_with_writer(grf_path, file, name_encoding)
+    }
+}
+
+impl<W: Write + Seek> GrfArchiveBuilder<W> {
+    /// Open the existing archive located at `grf_path` and write modifications
+    /// through `obj` (e.g. a `GrfJournal`), which must be positioned like the
+    /// archive's file.
+    pub fn open_with_writer<P: AsRef<Path>>(
+        grf_path: P,
+        obj: W,
+        name_encoding: NameEncoding,
+    ) -> Result<Self> {
+        let grf_archive = GrfArchive::open_with_encoding(&grf_path, name_encoding)?;
+        Self::from_archive(&grf_archive, obj, name_encoding)
+    }
+
+    /// Modify the existing archive `grf_archive` by writing modifications
+    /// through `obj`, which must be positioned like the archive's reader.
+    /// `grf_archive` must not be used once the archive has been modified.
+    pub fn from_archive<R: Read + Seek>(
+        grf_archive: &GrfArchive<R>,
+        obj: W,
+        name_encoding: NameEncoding,
+    ) -> Result<Self> {
+        // GRF 1.x file tables cannot be written, such archives must be rebuilt
+        if grf_archive.version_major() < 2 {
+            return Err(GrufError::serialization_error(
+                "GRF 1.x archives cannot be modified in place",
+            ));
+        }
+        let chunks = dyn_alloc::list_available_chunks(grf_archive)?;
         let mut entries = HashMap::with_capacity(grf_archive.file_count());
         for entry in grf_archive.get_entries() {
             entries.insert(
@@ -254,15 +476,17 @@ impl GrfArchiveBuilder<File> {
             );
         }
 
-        let file = OpenOptions::new().read(true).write(true).open(&grf_path)?;
         Ok(Self {
-            obj: Box::new(file),
+            obj: Box::new(obj),
             start_offset: 0,
             finished: false,
             version_major: grf_archive.version_major(),
             version_minor: grf_archive.version_minor(),
+            name_encoding,
+            compression: Compression::default(),
             entries,
             chunks,
+            written_table: None,
         })
     }
 }
@@ -274,16 +498,40 @@ impl<W: Write + Seek> Drop for GrfArchiveBuilder<W> {
     }
 }
 
+/// Compresses the content of a file the way GRF entries are compressed.
+/// Returns the size of the content and the compressed content.
+///
+/// This can be used to compress entries in parallel before adding them with
+/// `GrfArchiveBuilder::add_compressed_file`.
+pub fn compress_file_content<R: Read>(data: R) -> Result<(u64, Vec<u8>)> {
+    compress_with_level(data, Compression::default())
+}
+
+fn compress_with_level<R: Read>(mut data: R, compression: Compression) -> Result<(u64, Vec<u8>)> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), compression);
+    let data_size = io::copy(data.by_ref(), &mut encoder)?;
+    Ok((data_size, encoder.finish()?))
+}
+
 fn write_grf_header<W: Write>(
     version: u32,
-    file_table_offset: u32,
+    file_table_offset: u64,
     v_file_count: i32,
     writer: &mut W,
 ) -> Result<()> {
+    // GRF 3.0 archives store the high part of the offset in place of the seed
+    let (file_table_offset, seed) = if version >> 8 == 3 {
+        (file_table_offset as u32, (file_table_offset >> 32) as i32)
+    } else {
+        let file_table_offset = u32::try_from(file_table_offset).map_err(|_| {
+            GrufError::size_limit_exceeded("GRF file table must be located in the first 4 GiB")
+        })?;
+        (file_table_offset, 0)
+    };
     let grf_header = SerializableGrfHeader {
         key: GRF_FIXED_KEY,
         file_table_offset,
-        seed: 0,
+        seed,
         v_file_count,
         version,
     };
@@ -296,9 +544,16 @@ fn write_grf_header<W: Write>(
 mod tests {
     use std::collections::HashMap;
     use std::fs::File;
-    use std::path::PathBuf;
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
 
-    use crate::grf::{GrfArchive, GrfArchiveBuilder, GrfFileEntry};
+    use super::{compress_file_content, write_grf_header};
+    use crate::grf::{
+        GrfArchive, GrfArchiveBuilder, GrfFileEntry, GrfIndex, GrfJournal, GRF_HEADER_SIZE,
+    };
+    use crate::{GrufError, NameEncoding};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
     use tempfile::tempdir;
 
     #[test]
@@ -350,41 +605,409 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_add_directory() {
+        let temp_dir = tempdir().unwrap();
+        let root_directory = temp_dir.path().join("root");
+        std::fs::create_dir_all(root_directory.join("data").join("texture")).unwrap();
+        std::fs::write(root_directory.join("data").join("file.txt"), b"content").unwrap();
+        let texture_content = vec![0x42u8; 4096];
+        std::fs::write(
+            root_directory.join("data").join("texture").join("file.bmp"),
+            &texture_content,
+        )
+        .unwrap();
+        let mut grf_sizes = vec![];
+        for level in &[0, 9] {
+            let output_path = temp_dir.path().join(format!("{}.grf", level));
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
+                builder.set_compression_level(*level);
+                builder
+                    .add_directory(&root_directory, &root_directory)
+                    .unwrap();
+            }
+            let mut grf_archive = GrfArchive::open(&output_path).unwrap();
+            assert_eq!(grf_archive.file_count(), 2);
+            assert_eq!(
+                grf_archive.read_file_content("data\\file.txt").unwrap(),
+                b"content"
+            );
+            assert_eq!(
+                grf_archive
+                    .read_file_content("data\\texture\\file.bmp")
+                    .unwrap(),
+                texture_content
+            );
+            grf_sizes.push(std::fs::metadata(&output_path).unwrap().len());
+        }
+        // Files are stored without compression at level 0
+        assert!(grf_sizes[0] > grf_sizes[1]);
+    }
+
+    #[test]
+    fn test_add_compressed_file() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("200-builder.grf");
+        let content = vec![0xABu8; 1000];
+        {
+            let (data_size, compressed_data) = compress_file_content(content.as_slice()).unwrap();
+            assert_eq!(data_size, 1000);
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
+            builder
+                .add_compressed_file("data\\file.bin".to_string(), data_size, &compressed_data)
+                .unwrap();
+        }
+        let mut grf_archive = GrfArchive::open(&output_path).unwrap();
+        assert_eq!(
+            content,
+            grf_archive.read_file_content("data\\file.bin").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_index() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("small.grf");
+        std::fs::copy(grf_dir_path.join("200-small.grf"), &grf_path).unwrap();
+        let index = {
+            let grf_archive = GrfArchive::open(&grf_path).unwrap();
+            let grf_file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&grf_path)
+                .unwrap();
+            let mut builder =
+                GrfArchiveBuilder::from_archive(&grf_archive, grf_file, NameEncoding::Windows1252)
+                    .unwrap();
+            builder
+                .add_file("data\\new.txt".to_string(), &b"new file"[..])
+                .unwrap();
+            assert!(builder.remove_file("data\\06guild_r.rsw").unwrap());
+            assert!(builder.index().is_none());
+            builder.finish().unwrap();
+            builder.index().unwrap()
+        };
+
+        // The index matches the archive's file table
+        let parsed_archive = GrfArchive::open(&grf_path).unwrap();
+        let serialized_index = bincode::serialize(&index).unwrap();
+        let index: GrfIndex = bincode::deserialize(&serialized_index).unwrap();
+        assert_eq!(index, parsed_archive.index());
+        let mut grf_archive = GrfArchive::open_with_index(&grf_path, index).unwrap();
+        assert_eq!(grf_archive.file_count(), parsed_archive.file_count());
+        for entry in parsed_archive.get_entries() {
+            let indexed_entry = grf_archive.get_file_entry(&entry.relative_path).unwrap();
+            assert_eq!(
+                (
+                    indexed_entry.size,
+                    indexed_entry.size_compressed,
+                    indexed_entry.size_compressed_aligned,
+                    indexed_entry.entry_type,
+                    indexed_entry.offset,
+                ),
+                (
+                    entry.size,
+                    entry.size_compressed,
+                    entry.size_compressed_aligned,
+                    entry.entry_type,
+                    entry.offset,
+                )
+            );
+        }
+        assert_eq!(
+            grf_archive.read_file_content("data\\new.txt").unwrap(),
+            b"new file"
+        );
+        assert!(!grf_archive.contains_file("data\\06guild_r.rsw"));
+    }
+
     #[test]
     fn test_import_raw_entry_from_grf() {
         let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
-        let grf_path = grf_dir_path.join("200-small.grf");
+        // Entries of GRF 1.x archives are converted when imported
+        for grf_name in &["200-small.grf", "103-small.grf", "102-small.grf"] {
+            let grf_path = grf_dir_path.join(grf_name);
+            let temp_dir = tempdir().unwrap();
+            let output_path = temp_dir.path().join("200-builder.grf");
+            // Generate
+            {
+                let mut grf = GrfArchive::open(&grf_path).unwrap();
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
+                let grf_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+                for entry in grf_entries {
+                    builder
+                        .import_raw_entry_from_grf(&mut grf, entry.relative_path)
+                        .unwrap();
+                }
+            }
+            // Check result
+            {
+                let mut grf = GrfArchive::open(&grf_path).unwrap();
+                let mut ouput_archive = GrfArchive::open(&output_path).unwrap();
+                assert_eq!(grf.file_count(), ouput_archive.file_count());
+                let file_entries: Vec<GrfFileEntry> =
+                    ouput_archive.get_entries().cloned().collect();
+                for entry in file_entries {
+                    let expected_content = grf.read_file_content(&entry.relative_path).unwrap();
+                    // Size check
+                    assert_eq!(expected_content.len(), entry.size);
+                    // Content check
+                    assert_eq!(
+                        expected_content,
+                        ouput_archive
+                            .read_file_content(&entry.relative_path)
+                            .unwrap()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_grf_101() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("103-small.grf");
+        std::fs::copy(grf_dir_path.join("103-small.grf"), &grf_path).unwrap();
+        // GRF 1.x archives cannot be patched in place
+        assert!(matches!(
+            GrfArchiveBuilder::open(&grf_path),
+            Err(GrufError::SerializationError(_))
+        ));
+        let grf = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf.file_count(), 8);
+        assert_eq!(grf.version_major(), 1);
+    }
+
+    #[test]
+    fn test_open_with_journal() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-small.grf");
+        std::fs::copy(grf_dir_path.join("200-small.grf"), &grf_path).unwrap();
+        let original_grf = std::fs::read(&grf_path).unwrap();
+        let mut journal = GrfJournal::create(&grf_path).unwrap();
+        {
+            let mut builder = GrfArchiveBuilder::open_with_writer(
+                &grf_path,
+                &mut journal,
+                NameEncoding::Windows1252,
+            )
+            .unwrap();
+            builder
+                .add_file("data\\new.txt".to_string(), &b"content"[..])
+                .unwrap();
+            assert!(builder.remove_file("data\\06guild_r.gat").unwrap());
+            builder.finish().unwrap();
+        }
+        // Nothing is written to the archive before the journal is committed
+        assert_eq!(original_grf, std::fs::read(&grf_path).unwrap());
+        journal.commit().unwrap();
+
+        let mut original_archive = GrfArchive::new(Cursor::new(original_grf)).unwrap();
+        let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf_archive.file_count(), original_archive.file_count());
+        assert_eq!(
+            grf_archive.read_file_content("data\\new.txt").unwrap(),
+            b"content"
+        );
+        assert!(!grf_archive.contains_file("data\\06guild_r.gat"));
+        assert_eq!(
+            grf_archive
+                .read_file_content("data\\06guild_r.rsw")
+                .unwrap(),
+            original_archive
+                .read_file_content("data\\06guild_r.rsw")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_create_with_encoding() {
         let temp_dir = tempdir().unwrap();
         let output_path = temp_dir.path().join("200-builder.grf");
-        // Generate
+        let entry_path = "data\\유저인터페이스\\file.bmp";
+        // Generate, names written in CP949
         {
-            let mut grf = GrfArchive::open(&grf_path).unwrap();
             let output_file = File::create(&output_path).unwrap();
-            let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
-            let grf_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
-            for entry in grf_entries {
+            let mut builder =
+                GrfArchiveBuilder::create_with_encoding(output_file, 2, 0, NameEncoding::Auto)
+                    .unwrap();
+            builder
+                .add_file(entry_path.to_string(), [1u8; 32].as_ref())
+                .unwrap();
+            // Names that cannot be encoded are rejected
+            let mut cp949_builder = GrfArchiveBuilder::create_with_encoding(
+                Cursor::new(vec![]),
+                2,
+                0,
+                NameEncoding::Cp949,
+            )
+            .unwrap();
+            cp949_builder
+                .add_file("data\\\u{e9}t\u{e9}.txt".to_string(), [1u8; 32].as_ref())
+                .unwrap();
+            assert!(cp949_builder.finish().is_err());
+        }
+        // Check result
+        {
+            let grf_archive = GrfArchive::open(&output_path).unwrap();
+            assert!(!grf_archive.contains_file(entry_path));
+            let mut grf_archive =
+                GrfArchive::open_with_encoding(&output_path, NameEncoding::Cp949).unwrap();
+            assert_eq!(
+                grf_archive.read_file_content(entry_path).unwrap(),
+                [1u8; 32]
+            );
+            // Entries keep their names when the archive is updated
+            {
+                let mut builder =
+                    GrfArchiveBuilder::open_with_encoding(&output_path, NameEncoding::Cp949)
+                        .unwrap();
                 builder
-                    .import_raw_entry_from_grf(&mut grf, entry.relative_path)
+                    .add_file("data\\file.gat".to_string(), [2u8; 16].as_ref())
                     .unwrap();
             }
+            let grf_archive =
+                GrfArchive::open_with_encoding(&output_path, NameEncoding::Auto).unwrap();
+            assert!(grf_archive.contains_file(entry_path));
+            assert!(grf_archive.contains_file("data\\file.gat"));
         }
-        // Check result
+    }
+
+    #[test]
+    fn test_create_grf_300() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("300-builder.grf");
         {
-            let mut grf = GrfArchive::open(&grf_path).unwrap();
-            let mut ouput_archive = GrfArchive::open(&output_path).unwrap();
-            let file_entries: Vec<GrfFileEntry> = ouput_archive.get_entries().cloned().collect();
-            for entry in file_entries {
-                let expected_content = grf.read_file_content(&entry.relative_path).unwrap();
-                // Size check
-                assert_eq!(expected_content.len(), entry.size);
-                // Content check
-                assert_eq!(
-                    expected_content,
-                    ouput_archive
-                        .read_file_content(&entry.relative_path)
-                        .unwrap()
-                );
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(output_file, 3, 0).unwrap();
+            builder
+                .add_file("data\\file.gat".to_string(), [1u8; 60].as_ref())
+                .unwrap();
+            builder
+                .add_file("data\\file.gnd".to_string(), [2u8; 341].as_ref())
+                .unwrap();
+        }
+        let mut grf_archive = GrfArchive::open(&output_path).unwrap();
+        assert_eq!(grf_archive.version_major(), 3);
+        assert_eq!(grf_archive.version_minor(), 0);
+        assert_eq!(grf_archive.file_count(), 2);
+        assert_eq!(
+            grf_archive.read_file_content("data\\file.gat").unwrap(),
+            vec![1u8; 60]
+        );
+        assert_eq!(
+            grf_archive.read_file_content("data\\file.gnd").unwrap(),
+            vec![2u8; 341]
+        );
+    }
+
+    /// Writes a sparse archive whose last entry ends past the 4 GiB boundary.
+    fn write_sparse_grf(grf_path: &Path, version_major: u32, last_entry_content: &[u8]) {
+        let compress = |data: &[u8]| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let large_entry_offset: u64 = 4096;
+        let large_entry_size: u32 = u32::MAX - 4096 - 5;
+        let last_entry_offset = large_entry_offset + large_entry_size as u64;
+        let compressed_content = compress(last_entry_content);
+        let mut table = vec![];
+        for (name, offset, size, size_compressed) in &[
+            (
+                "data\\large.bin",
+                large_entry_offset,
+                large_entry_size,
+                large_entry_size,
+            ),
+            (
+                "data\\last.txt",
+                last_entry_offset,
+                last_entry_content.len() as u32,
+                compressed_content.len() as u32,
+            ),
+        ] {
+            table.extend_from_slice(name.as_bytes());
+            table.push(0);
+            table.extend_from_slice(&size_compressed.to_le_bytes());
+            table.extend_from_slice(&size_compressed.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.push(1);
+            if version_major == 3 {
+                table.extend_from_slice(&offset.to_le_bytes());
+            } else {
+                table.extend_from_slice(&(*offset as u32).to_le_bytes());
             }
         }
+        let compressed_table = compress(&table);
+        let mut grf_file = File::create(grf_path).unwrap();
+        // The file table is located right after the header
+        write_grf_header(version_major << 8, 0, 2 + 7, &mut grf_file).unwrap();
+        if version_major == 3 {
+            grf_file.write_all(&[0; 4]).unwrap();
+        }
+        grf_file
+            .write_all(&(compressed_table.len() as u32).to_le_bytes())
+            .unwrap();
+        grf_file
+            .write_all(&(table.len() as u32).to_le_bytes())
+            .unwrap();
+        grf_file.write_all(&compressed_table).unwrap();
+        grf_file
+            .seek(SeekFrom::Start(GRF_HEADER_SIZE as u64 + last_entry_offset))
+            .unwrap();
+        grf_file.write_all(&compressed_content).unwrap();
+    }
+
+    // Note: Relies on sparse files to avoid writing GiBs of data
+    #[cfg(unix)]
+    #[test]
+    fn test_large_offsets() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("large.grf");
+        let last_entry_content = b"end of the archive";
+        // GRF 2.0 entries must be located in the first 4 GiB
+        write_sparse_grf(&grf_path, 2, last_entry_content);
+        {
+            let mut builder = GrfArchiveBuilder::open(&grf_path).unwrap();
+            assert!(matches!(
+                builder.add_file("data\\new.txt".to_string(), [1u8; 16].as_ref()),
+                Err(GrufError::SizeLimitExceeded(_))
+            ));
+        }
+        // The archive is left untouched
+        let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf_archive.file_count(), 2);
+        assert_eq!(
+            grf_archive.read_file_content("data\\last.txt").unwrap(),
+            last_entry_content
+        );
+        // GRF 3.0 archives use 64-bit offsets
+        write_sparse_grf(&grf_path, 3, last_entry_content);
+        {
+            let mut builder = GrfArchiveBuilder::open(&grf_path).unwrap();
+            builder
+                .add_file("data\\new.txt".to_string(), [1u8; 16].as_ref())
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf_archive.file_count(), 3);
+        assert!(grf_archive.get_file_entry("data\\new.txt").unwrap().offset > u32::MAX as u64);
+        assert_eq!(
+            grf_archive.read_file_content("data\\new.txt").unwrap(),
+            vec![1u8; 16]
+        );
+        assert_eq!(
+            grf_archive.read_file_content("data\\last.txt").unwrap(),
+            last_entry_content
+        );
     }
 }
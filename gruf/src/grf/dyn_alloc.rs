@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
+use std::io::{Read, Seek};
 
 use crate::error::{GrufError, Result};
 use crate::grf::reader::{GrfArchive, GrfFileEntry, GRF_HEADER_SIZE};
@@ -16,7 +17,9 @@ pub struct AvailableChunkList {
     chunks: BTreeMap<u64, AvailableChunk>, // Indexed and ordered by offset
 }
 
-pub fn list_available_chunks(archive: &mut GrfArchive) -> Result<AvailableChunkList> {
+pub fn list_available_chunks<R: Read + Seek>(
+    archive: &GrfArchive<R>,
+) -> Result<AvailableChunkList> {
     if archive.file_count() == 0 {
         return Ok(AvailableChunkList::new());
     }
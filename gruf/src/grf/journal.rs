@@ -0,0 +1,351 @@
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{GrufError, Result};
+use sha2::{Digest, Sha256};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"GRFJRNL1";
+const JOURNAL_EXTENSION: &str = "journal";
+// Written in place of a record's offset once every record has been written
+const COMMIT_MARKER: u64 = u64::MAX;
+const CHECKSUM_SIZE: usize = 32;
+// Contiguous writes are merged into records of at most this size
+const MAX_RECORD_SIZE: usize = 1024 * 1024;
+
+/// Write-ahead journal that makes in-place modifications of a GRF crash-safe.
+///
+/// Data written to the journal (e.g. by a `GrfArchiveBuilder`) is stored in a
+/// sidecar file next to the GRF, which is left untouched until `commit` is
+/// called. Committed journals that couldn't be fully applied (e.g. because of
+/// a power loss) are replayed by `recover`, incomplete ones are discarded.
+pub struct GrfJournal {
+    grf_path: PathBuf,
+    journal_path: PathBuf,
+    journal: BufWriter<File>,
+    hasher: Sha256,
+    position: u64,
+    grf_size: u64,
+    pending_offset: u64,
+    pending_data: Vec<u8>,
+    sealed: bool,
+}
+
+impl GrfJournal {
+    /// Create an empty journal for the GRF located at `grf_path`.
+    ///
+    /// A journal left by a previous session is recovered first.
+    pub fn create<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
+        let grf_path = grf_path.as_ref().to_path_buf();
+        Self::recover(&grf_path)?;
+        let grf_size = fs::metadata(&grf_path)?.len();
+        let journal_path = Self::journal_path(&grf_path);
+        let mut journal = BufWriter::new(File::create(&journal_path)?);
+        journal.write_all(JOURNAL_MAGIC)?;
+        Ok(Self {
+            grf_path,
+            journal_path,
+            journal,
+            hasher: Sha256::new(),
+            position: 0,
+            grf_size,
+            pending_offset: 0,
+            pending_data: Vec::new(),
+            sealed: false,
+        })
+    }
+
+    /// Returns the path of the journal associated with the GRF located at
+    /// `grf_path`.
+    pub fn journal_path<P: AsRef<Path>>(grf_path: P) -> PathBuf {
+        let mut journal_path = grf_path.as_ref().as_os_str().to_owned();
+        journal_path.push(".");
+        journal_path.push(JOURNAL_EXTENSION);
+        PathBuf::from(journal_path)
+    }
+
+    /// Returns the path of the GRF associated with the journal located at
+    /// `journal_path`, if it's a journal's path.
+    pub fn grf_path<P: AsRef<Path>>(journal_path: P) -> Option<PathBuf> {
+        let journal_path = journal_path.as_ref();
+        match journal_path.extension() {
+            Some(extension) if extension == JOURNAL_EXTENSION => {
+                Some(journal_path.with_extension(""))
+            }
+            _ => None,
+        }
+    }
+
+    /// Make the journaled modifications durable, then apply them to the GRF
+    /// and remove the journal.
+    pub fn commit(mut self) -> Result<()> {
+        self.write_pending_record()?;
+        let checksum = self.hasher.finalize_reset();
+        self.journal.write_all(&COMMIT_MARKER.to_le_bytes())?;
+        self.journal.write_all(&checksum)?;
+        self.journal.flush()?;
+        self.journal.get_ref().sync_all()?;
+        // From now on, the journal must be kept until it's been applied
+        self.sealed = true;
+        apply_journal(&self.grf_path, &self.journal_path)?;
+        Ok(fs::remove_file(&self.journal_path)?)
+    }
+
+    /// Recover the journal of the GRF located at `grf_path`, if any.
+    ///
+    /// Committed journals are applied to the GRF, incomplete ones are
+    /// discarded (the GRF hasn't been modified). Returns true if the GRF has
+    /// been modified.
+    pub fn recover<P: AsRef<Path>>(grf_path: P) -> Result<bool> {
+        let journal_path = Self::journal_path(&grf_path);
+        if !journal_path.exists() {
+            return Ok(false);
+        }
+        let is_committed = is_journal_committed(&journal_path)?;
+        if is_committed {
+            apply_journal(grf_path, &journal_path)?;
+        }
+        fs::remove_file(&journal_path)?;
+        Ok(is_committed)
+    }
+
+    fn write_pending_record(&mut self) -> io::Result<()> {
+        if self.pending_data.is_empty() {
+            return Ok(());
+        }
+        let mut record_header = [0; 16];
+        record_header[..8].copy_from_slice(&self.pending_offset.to_le_bytes());
+        record_header[8..].copy_from_slice(&(self.pending_data.len() as u64).to_le_bytes());
+        self.hasher.update(record_header);
+        self.hasher.update(&self.pending_data);
+        self.journal.write_all(&record_header)?;
+        self.journal.write_all(&self.pending_data)?;
+        self.pending_data.clear();
+        Ok(())
+    }
+}
+
+impl Write for GrfJournal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pending_end = self.pending_offset + self.pending_data.len() as u64;
+        if self.position != pending_end || self.pending_data.len() >= MAX_RECORD_SIZE {
+            self.write_pending_record()?;
+            self.pending_offset = self.position;
+        }
+        self.pending_data.extend_from_slice(buf);
+        self.position += buf.len() as u64;
+        self.grf_size = self.grf_size.max(self.position);
+        Ok(buf.len())
+    }
+
+    // Note: Data only reaches the GRF when the journal is committed
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for GrfJournal {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => checked_add_signed(self.position, offset),
+            SeekFrom::End(offset) => checked_add_signed(self.grf_size, offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Drop for GrfJournal {
+    // Discard journals that haven't been committed
+    fn drop(&mut self) {
+        if !self.sealed {
+            let _ = fs::remove_file(&self.journal_path);
+        }
+    }
+}
+
+fn checked_add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+/// Returns true if the journal has been fully written and its checksum
+/// matches its content.
+fn is_journal_committed(journal_path: &Path) -> Result<bool> {
+    let mut reader = BufReader::new(File::open(journal_path)?);
+    let mut magic = [0; JOURNAL_MAGIC.len()];
+    if !read_exact_or_eof(&mut reader, &mut magic)? || &magic != JOURNAL_MAGIC {
+        return Ok(false);
+    }
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let mut record_header = [0; 16];
+        if !read_exact_or_eof(&mut reader, &mut record_header[..8])? {
+            return Ok(false);
+        }
+        if read_u64(&record_header[..8]) == COMMIT_MARKER {
+            let mut checksum = [0; CHECKSUM_SIZE];
+            if !read_exact_or_eof(&mut reader, &mut checksum)? {
+                return Ok(false);
+            }
+            return Ok(hasher.finalize()[..] == checksum[..]);
+        }
+        if !read_exact_or_eof(&mut reader, &mut record_header[8..])? {
+            return Ok(false);
+        }
+        hasher.update(record_header);
+        let mut remaining = read_u64(&record_header[8..]);
+        while remaining > 0 {
+            let chunk_size = usize::try_from(remaining)
+                .unwrap_or(buffer.len())
+                .min(buffer.len());
+            if !read_exact_or_eof(&mut reader, &mut buffer[..chunk_size])? {
+                return Ok(false);
+            }
+            hasher.update(&buffer[..chunk_size]);
+            remaining -= chunk_size as u64;
+        }
+    }
+}
+
+/// Writes the records of a committed journal into the GRF.
+fn apply_journal<P: AsRef<Path>>(grf_path: P, journal_path: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(journal_path)?);
+    reader.seek(SeekFrom::Start(JOURNAL_MAGIC.len() as u64))?;
+    let mut grf_file = OpenOptions::new().write(true).open(grf_path)?;
+    loop {
+        let mut record_header = [0; 16];
+        reader.read_exact(&mut record_header[..8])?;
+        let offset = read_u64(&record_header[..8]);
+        if offset == COMMIT_MARKER {
+            break;
+        }
+        reader.read_exact(&mut record_header[8..])?;
+        let size = read_u64(&record_header[8..]);
+        grf_file.seek(SeekFrom::Start(offset))?;
+        let copied_size = io::copy(&mut reader.by_ref().take(size), &mut grf_file)?;
+        if copied_size != size {
+            return Err(GrufError::parsing_error("Journal is truncated"));
+        }
+    }
+    Ok(grf_file.sync_all()?)
+}
+
+/// Fills `buf` entirely. Returns false if the end of the input is reached
+/// first.
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_journal() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("data.grf");
+        let journal_path = GrfJournal::journal_path(&grf_path);
+        assert_eq!(journal_path, temp_dir.path().join("data.grf.journal"));
+        assert_eq!(GrfJournal::grf_path(&journal_path), Some(grf_path.clone()));
+        assert_eq!(GrfJournal::grf_path(&grf_path), None);
+        fs::write(&grf_path, vec![0u8; 16]).unwrap();
+
+        // The GRF is only modified on commit
+        let mut journal = GrfJournal::create(&grf_path).unwrap();
+        journal.seek(SeekFrom::Start(4)).unwrap();
+        journal.write_all(&[1, 2]).unwrap();
+        journal.write_all(&[3]).unwrap();
+        journal.seek(SeekFrom::End(2)).unwrap();
+        journal.write_all(&[4; 4]).unwrap();
+        assert!(journal_path.exists());
+        assert_eq!(fs::read(&grf_path).unwrap(), vec![0u8; 16]);
+        journal.commit().unwrap();
+        assert!(!journal_path.exists());
+        let mut expected_content = vec![0u8; 22];
+        expected_content[4..7].copy_from_slice(&[1, 2, 3]);
+        expected_content[18..].copy_from_slice(&[4; 4]);
+        assert_eq!(fs::read(&grf_path).unwrap(), expected_content);
+
+        // Uncommitted journals are discarded
+        let mut journal = GrfJournal::create(&grf_path).unwrap();
+        journal.write_all(&[5; 8]).unwrap();
+        drop(journal);
+        assert!(!journal_path.exists());
+        assert_eq!(fs::read(&grf_path).unwrap(), expected_content);
+    }
+
+    #[test]
+    fn test_recover() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("data.grf");
+        let journal_path = GrfJournal::journal_path(&grf_path);
+        fs::write(&grf_path, vec![0u8; 8]).unwrap();
+        assert!(!GrfJournal::recover(&grf_path).unwrap());
+
+        // Build a committed journal without applying it, as if the patcher
+        // had crashed while applying it
+        let mut journal = GrfJournal::create(&grf_path).unwrap();
+        journal.seek(SeekFrom::Start(2)).unwrap();
+        journal.write_all(&[7; 4]).unwrap();
+        journal.write_pending_record().unwrap();
+        let checksum = journal.hasher.finalize_reset();
+        journal
+            .journal
+            .write_all(&COMMIT_MARKER.to_le_bytes())
+            .unwrap();
+        journal.journal.write_all(&checksum).unwrap();
+        journal.journal.flush().unwrap();
+        journal.sealed = true;
+        drop(journal);
+        let committed_journal = fs::read(&journal_path).unwrap();
+
+        // Truncated journals are discarded
+        fs::write(
+            &journal_path,
+            &committed_journal[..committed_journal.len() - 1],
+        )
+        .unwrap();
+        assert!(!GrfJournal::recover(&grf_path).unwrap());
+        assert!(!journal_path.exists());
+        assert_eq!(fs::read(&grf_path).unwrap(), vec![0u8; 8]);
+
+        // Corrupt journals are discarded
+        let mut corrupt_journal = committed_journal.clone();
+        corrupt_journal[JOURNAL_MAGIC.len() + 16] ^= 0xFF;
+        fs::write(&journal_path, &corrupt_journal).unwrap();
+        assert!(!GrfJournal::recover(&grf_path).unwrap());
+        assert_eq!(fs::read(&grf_path).unwrap(), vec![0u8; 8]);
+
+        // Committed journals are applied
+        fs::write(&journal_path, &committed_journal).unwrap();
+        assert!(GrfJournal::recover(&grf_path).unwrap());
+        assert!(!journal_path.exists());
+        assert_eq!(fs::read(&grf_path).unwrap(), vec![0, 0, 7, 7, 7, 7, 0, 0]);
+    }
+}
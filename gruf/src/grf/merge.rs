@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+use crate::grf::{GrfArchive, GrfArchiveBuilder};
+use crate::{NameEncoding, Result};
+
+// Entries without this flag are directories
+const GRF_FILE_ENTRY_FLAG: u8 = 0x01;
+
+/// Indicates how a GRF is merged into another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrfMergeMethod {
+    /// Modify the target GRF directly. This is faster but the target grows
+    /// and can be corrupted in case of error.
+    InPlace,
+    /// Rebuild the target GRF next to the original one, which is replaced
+    /// once the merge is complete.
+    OutOfPlace,
+}
+
+/// Merges the files of the GRF located at `source_path` into the GRF located
+/// at `target_path` (e.g. "rdata.grf" into "data.grf"). Files of the source
+/// replace those of the target that have the same name.
+///
+/// GRF 1.x targets are always rebuilt (as GRF 2.0 archives) since they cannot
+/// be modified in place. Returns the number of files merged.
+pub fn merge_grf_archives<P: AsRef<Path>, Q: AsRef<Path>>(
+    target_path: P,
+    source_path: Q,
+    method: GrfMergeMethod,
+    name_encoding: NameEncoding,
+) -> Result<usize> {
+    let mut source_archive = GrfArchive::open_with_encoding(&source_path, name_encoding)?;
+    let (target_version_major, _) = GrfArchive::read_version(&target_path)?;
+    match method {
+        GrfMergeMethod::InPlace if target_version_major >= 2 => {
+            merge_grf_archives_ip(target_path, &mut source_archive, name_encoding)
+        }
+        _ => merge_grf_archives_oop(target_path, &mut source_archive, name_encoding),
+    }
+}
+
+fn merge_grf_archives_ip(
+    target_path: impl AsRef<Path>,
+    source_archive: &mut GrfArchive,
+    name_encoding: NameEncoding,
+) -> Result<usize> {
+    let mut builder = GrfArchiveBuilder::open_with_encoding(target_path, name_encoding)?;
+    let mut source_entries: Vec<_> = source_archive
+        .get_entries()
+        .filter(|e| e.entry_type & GRF_FILE_ENTRY_FLAG != 0)
+        .cloned()
+        .collect();
+    // Read the source sequentially
+    source_entries.sort_unstable_by_key(|e| e.offset);
+    let merged_count = source_entries.len();
+    for entry in source_entries {
+        builder.import_raw_entry_from_grf(source_archive, entry.relative_path)?;
+    }
+    builder.finish()?;
+    Ok(merged_count)
+}
+
+fn merge_grf_archives_oop(
+    target_path: impl AsRef<Path>,
+    source_archive: &mut GrfArchive,
+    name_encoding: NameEncoding,
+) -> Result<usize> {
+    let target_path = target_path.as_ref();
+    let mut merged_file_path = target_path.to_path_buf();
+    merged_file_path.set_extension("grf.merge");
+    let mut target_archive = GrfArchive::open_with_encoding(target_path, name_encoding)?;
+    // Prepare the entries of the merged GRF, files of the source win
+    let mut merge_entries: HashMap<String, bool> = HashMap::new();
+    for entry in target_archive.get_entries() {
+        if entry.entry_type & GRF_FILE_ENTRY_FLAG != 0 {
+            merge_entries.insert(entry.relative_path.clone(), false);
+        }
+    }
+    let mut merged_count = 0;
+    for entry in source_archive.get_entries() {
+        if entry.entry_type & GRF_FILE_ENTRY_FLAG != 0 {
+            merge_entries.insert(entry.relative_path.clone(), true);
+            merged_count += 1;
+        }
+    }
+
+    // Keep 64-bit offsets for GRF 3.0 archives, use GRF 2.0 otherwise
+    let (version_major, version_minor) = match target_archive.version_major() {
+        3 => (3, target_archive.version_minor()),
+        _ => (2, 0),
+    };
+    let merged_file = File::create(&merged_file_path)?;
+    let build_result = GrfArchiveBuilder::create_with_encoding(
+        merged_file,
+        version_major,
+        version_minor,
+        name_encoding,
+    )
+    .and_then(|mut builder| {
+        for (relative_path, from_source) in merge_entries {
+            if from_source {
+                builder.import_raw_entry_from_grf(source_archive, relative_path)?;
+            } else {
+                builder.import_raw_entry_from_grf(&mut target_archive, relative_path)?;
+            }
+        }
+        builder.finish()
+    });
+    if let Err(e) = build_result {
+        let _ = fs::remove_file(&merged_file_path);
+        return Err(e);
+    }
+    // Note: The original GRF must be closed before being replaced
+    drop(target_archive);
+    fs::rename(&merged_file_path, target_path)?;
+    Ok(merged_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_merge_grf_archives() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for (target_name, method) in &[
+            ("200-empty.grf", GrfMergeMethod::InPlace),
+            ("200-empty.grf", GrfMergeMethod::OutOfPlace),
+            ("103-small.grf", GrfMergeMethod::InPlace),
+        ] {
+            let temp_dir = tempdir().unwrap();
+            let target_path = temp_dir.path().join("data.grf");
+            let source_path = temp_dir.path().join("rdata.grf");
+            fs::copy(grf_dir_path.join(target_name), &target_path).unwrap();
+            {
+                // Source containing an updated file and a new one
+                let source_file = File::create(&source_path).unwrap();
+                let mut builder = GrfArchiveBuilder::create(source_file, 2, 0).unwrap();
+                builder
+                    .add_file("data\\06guild_r.rsw".to_string(), &b"updated"[..])
+                    .unwrap();
+                builder
+                    .add_file("data\\new.txt".to_string(), &b"new"[..])
+                    .unwrap();
+            }
+            let original_file_count = GrfArchive::open(&target_path).unwrap().file_count();
+
+            let merged_count = merge_grf_archives(
+                &target_path,
+                &source_path,
+                *method,
+                NameEncoding::Windows1252,
+            )
+            .unwrap();
+
+            assert_eq!(merged_count, 2);
+            assert!(!temp_dir.path().join("data.grf.merge").exists());
+            let mut target_archive = GrfArchive::open(&target_path).unwrap();
+            assert!(target_archive.version_major() >= 2);
+            assert_eq!(
+                target_archive.read_file_content("data\\new.txt").unwrap(),
+                b"new"
+            );
+            assert_eq!(
+                target_archive
+                    .read_file_content("data\\06guild_r.rsw")
+                    .unwrap(),
+                b"updated"
+            );
+            if original_file_count > 0 {
+                assert_eq!(target_archive.file_count(), original_file_count + 1);
+                assert_eq!(
+                    target_archive
+                        .read_file_content("data\\06guild_r.gnd")
+                        .unwrap()
+                        .len(),
+                    454622
+                );
+            } else {
+                assert_eq!(target_archive.file_count(), 2);
+            }
+        }
+    }
+}
@@ -1,8 +1,14 @@
 pub mod builder;
+pub mod journal;
+pub mod merge;
 pub mod reader;
+pub mod verify;
 
 pub use builder::GrfArchiveBuilder;
-pub use reader::{GrfArchive, GrfFileEntry};
+pub use journal::GrfJournal;
+pub use merge::{merge_grf_archives, GrfMergeMethod};
+pub use reader::{GrfArchive, GrfFileEntry, GrfIndex};
+pub use verify::{repair_grf_archive, GrfEntryIssue, GrfEntryIssueKind, GrfVerificationReport};
 
 mod crypto;
 mod dyn_alloc;
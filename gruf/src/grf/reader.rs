@@ -3,44 +3,147 @@ use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::str;
 
+use crate::archive::{map_file, match_entry_path, transcode_win1252_name, NameEncoding};
 use crate::grf::crypto::{decrypt_file_content, decrypt_file_name};
 use crate::{GrufError, Result};
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
 use flate2::read::ZlibDecoder;
+use memmap2::Mmap;
 use nom::error::ErrorKind;
-use nom::number::complete::{le_i32, le_u32, le_u8};
+use nom::number::complete::{le_i32, le_u32, le_u64, le_u8};
 use nom::*;
+use serde::{Deserialize, Serialize};
 
 pub const GRF_HEADER_MAGIC: &str = "Master of Magic\0";
 // Packed structs' sizes in bytes
 pub const GRF_HEADER_SIZE: usize = GRF_HEADER_MAGIC.len() + 0x1E;
 const GRF_TABLE_INFO2_SIZE: usize = 2 * std::mem::size_of::<u32>();
+pub const GRF_TABLE_INFO3_PADDING: u64 = std::mem::size_of::<u32>() as u64;
 
 #[derive(Debug)]
-pub struct GrfArchive {
-    obj: Box<File>,
+pub struct GrfArchive<R: ?Sized = File> {
+    obj: Box<R>,
     container: GrfContainer,
 }
 
-impl GrfArchive {
-    /// Create a new archive with the underlying object as the reader.
+impl GrfArchive<File> {
+    /// Open the archive located at `grf_path`.
     pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
+        Self::open_with_encoding(grf_path, NameEncoding::Windows1252)
+    }
+
+    /// Open the archive located at `grf_path`. Entries' names are decoded with
+    /// `name_encoding`.
+    pub fn open_with_encoding<P: AsRef<Path>>(
+        grf_path: P,
+        name_encoding: NameEncoding,
+    ) -> Result<Self> {
+        let file = File::open(grf_path)?;
+        Self::new_with_encoding(file, name_encoding)
+    }
+
+    /// Open the archive located at `grf_path`, whose file table has already
+    /// been parsed into `index`.
+    pub fn open_with_index<P: AsRef<Path>>(grf_path: P, index: GrfIndex) -> Result<Self> {
+        let file = File::open(grf_path)?;
+        Ok(Self::new_with_index(file, index))
+    }
+
+    /// Read the version (major, minor) of the archive located at `grf_path`
+    /// without parsing its file table.
+    pub fn read_version<P: AsRef<Path>>(grf_path: P) -> Result<(u32, u32)> {
         let mut file = File::open(grf_path)?;
         let mut grf_header_buf = [0; GRF_HEADER_SIZE];
         file.read_exact(&mut grf_header_buf)?;
-        let (parser_output, grf_header) = parse_grf_header(&grf_header_buf)
+        let (_parser_output, grf_header) = parse_grf_header(&grf_header_buf)
+            .map_err(|_| GrufError::parsing_error("Failed to parse archive (header)"))?;
+        Ok((grf_header.version_major, grf_header.version_minor))
+    }
+}
+
+impl GrfArchive<Cursor<Mmap>> {
+    /// Open the archive located at `grf_path` through a memory mapping.
+    ///
+    /// This speeds up the parsing of the file table and random accesses to
+    /// entries but the file must not be modified while the archive is open.
+    pub fn open_mmap<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
+        Self::open_mmap_with_encoding(grf_path, NameEncoding::Windows1252)
+    }
+
+    /// Open the archive located at `grf_path` through a memory mapping.
+    /// Entries' names are decoded with `name_encoding`.
+    pub fn open_mmap_with_encoding<P: AsRef<Path>>(
+        grf_path: P,
+        name_encoding: NameEncoding,
+    ) -> Result<Self> {
+        let mapped_file = map_file(grf_path)?;
+        Self::new_with_encoding(mapped_file, name_encoding)
+    }
+
+    /// Open the archive located at `grf_path` through a memory mapping. Its
+    /// file table has already been parsed into `index`.
+    pub fn open_mmap_with_index<P: AsRef<Path>>(grf_path: P, index: GrfIndex) -> Result<Self> {
+        let mapped_file = map_file(grf_path)?;
+        Ok(Self::new_with_index(mapped_file, index))
+    }
+}
+
+impl<R: Read + Seek> GrfArchive<R> {
+    /// Create a new archive with the underlying object as the reader.
+    pub fn new(obj: R) -> Result<Self> {
+        Self::new_with_encoding(obj, NameEncoding::Windows1252)
+    }
+
+    /// Create a new archive with the underlying object as the reader. Entries'
+    /// names are decoded with `name_encoding`.
+    pub fn new_with_encoding(obj: R, name_encoding: NameEncoding) -> Result<Self> {
+        let mut archive = Self::new_raw(obj)?;
+        if name_encoding != NameEncoding::Windows1252 {
+            let mut entries = HashMap::with_capacity(archive.container.entries.len());
+            for (_, mut entry) in archive.container.entries {
+                entry.relative_path = transcode_win1252_name(entry.relative_path, name_encoding)?;
+                entries.insert(entry.relative_path.clone(), entry);
+            }
+            archive.container.entries = entries;
+        }
+        Ok(archive)
+    }
+
+    /// Create a new archive with the underlying object as the reader, whose
+    /// file table has already been parsed into `index`.
+    pub fn new_with_index(obj: R, index: GrfIndex) -> Self {
+        Self {
+            obj: Box::new(obj),
+            container: index.0,
+        }
+    }
+
+    /// Returns the parsed header and file table of the archive.
+    pub fn index(&self) -> GrfIndex {
+        GrfIndex(self.container.clone())
+    }
+
+    fn new_raw(mut file: R) -> Result<Self> {
+        let mut grf_header_buf = [0; GRF_HEADER_SIZE];
+        file.read_exact(&mut grf_header_buf)?;
+        let (_parser_output, grf_header) = parse_grf_header(&grf_header_buf)
             .map_err(|_| GrufError::parsing_error("Failed to parse archive (header)"))?;
 
         match grf_header.version_major {
-            2 => {
+            2 | 3 => {
+                // GRF 3.0 file tables start with 4 unused bytes
+                let table_info_padding = match grf_header.version_major {
+                    3 => GRF_TABLE_INFO3_PADDING,
+                    _ => 0,
+                };
                 let mut table_info_buf = [0; GRF_TABLE_INFO2_SIZE];
                 file.seek(SeekFrom::Start(
-                    GRF_HEADER_SIZE as u64 + grf_header.file_table_offset,
+                    GRF_HEADER_SIZE as u64 + grf_header.file_table_offset + table_info_padding,
                 ))?;
                 file.read_exact(&mut table_info_buf)?;
                 let (_parser_output, grf_table_info) = parse_grf_table_info_200(&table_info_buf)
@@ -69,11 +172,13 @@ impl GrfArchive {
                         GrufError::ParsingError(format!("Failed to decompress file table: {}", e))
                     })?;
                 // Parse entries
-                let (_output, entries) = parse_grf_file_entries_200(
-                    decompressed_table.as_slice(),
-                    grf_header.file_count,
-                )
-                .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
+                let parse_grf_file_entries = match grf_header.version_major {
+                    3 => parse_grf_file_entries_300,
+                    _ => parse_grf_file_entries_200,
+                };
+                let (_output, entries) =
+                    parse_grf_file_entries(decompressed_table.as_slice(), grf_header.file_count)
+                        .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
                 Ok(Self {
                     obj: Box::new(file),
                     container: GrfContainer {
@@ -88,8 +193,14 @@ impl GrfArchive {
                 if grf_header.version_minor < 1 || grf_header.version_minor > 3 {
                     return Err(GrufError::parsing_error("Unsupported archive version"));
                 }
-                let table_size = parser_output.len();
-                if table_size == 0 {
+                // GRF 1.x file tables aren't compressed and extend to the end
+                // of the archive
+                file.seek(SeekFrom::Start(
+                    GRF_HEADER_SIZE as u64 + grf_header.file_table_offset,
+                ))?;
+                let mut table = vec![];
+                let table_size = file.read_to_end(&mut table)?;
+                if table_size == 0 || grf_header.file_count == 0 {
                     return Ok(Self {
                         obj: Box::new(file),
                         container: GrfContainer {
@@ -100,11 +211,9 @@ impl GrfArchive {
                     });
                 }
                 // Parse entries
-                let (_parser_output, entries) = parse_grf_file_entries_101(
-                    &parser_output[grf_header.file_table_offset as usize..],
-                    grf_header.file_count,
-                )
-                .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
+                let (_parser_output, entries) =
+                    parse_grf_file_entries_101(table.as_slice(), grf_header.file_count)
+                        .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
 
                 Ok(Self {
                     obj: Box::new(file),
@@ -131,6 +240,14 @@ impl GrfArchive {
         self.container.header.version_minor
     }
 
+    pub(crate) fn file_table_offset(&self) -> u64 {
+        self.container.header.file_table_offset
+    }
+
+    pub(crate) fn archive_size(&mut self) -> Result<u64> {
+        Ok(self.obj.seek(SeekFrom::End(0))?)
+    }
+
     pub fn get_entry_raw_data<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
         let file_entry = self
             .get_file_entry(file_path)
@@ -189,16 +306,59 @@ impl GrfArchive {
     pub fn get_entries(&self) -> impl Iterator<Item = &'_ GrfFileEntry> {
         self.container.entries.values()
     }
+
+    /// Returns the entries whose path matches the glob `pattern`, sorted by
+    /// path. See `match_entry_path` for the syntax of patterns.
+    pub fn list(&self, pattern: &str) -> Vec<&GrfFileEntry> {
+        let mut entries: Vec<_> = self
+            .get_entries()
+            .filter(|entry| match_entry_path(pattern, &entry.relative_path))
+            .collect();
+        entries.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        entries
+    }
+}
+
+/// Parsed header and file table of a GRF archive.
+///
+/// Indexes can be saved (e.g. with `serde`) and used to open the archive
+/// again without parsing its file table, as long as the archive hasn't been
+/// modified in the meantime.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrfIndex(GrfContainer);
+
+impl GrfIndex {
+    /// Index of an archive freshly written by `GrfArchiveBuilder`, whose
+    /// entries are stored unencrypted.
+    pub(crate) fn new_200(
+        header: GrfHeader,
+        table_size_compressed: usize,
+        table_size: usize,
+        entries: HashMap<String, GrfFileEntry>,
+    ) -> Self {
+        Self(GrfContainer {
+            header,
+            table_info: GrfTableInfo::Compressed(GrfTableInfo2 {
+                table_size_compressed,
+                table_size,
+            }),
+            entries,
+        })
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.0.header.file_count
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct GrfContainer {
     pub header: GrfHeader,
     pub table_info: GrfTableInfo,
     pub entries: HashMap<String, GrfFileEntry>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GrfHeader {
     pub key: [u8; 14],
     pub file_table_offset: u64,
@@ -208,24 +368,24 @@ pub struct GrfHeader {
     pub version_minor: u32,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 enum GrfTableInfo {
     Uncompressed(GrfTableInfo1),
     Compressed(GrfTableInfo2),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct GrfTableInfo1 {
     pub table_size: usize,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct GrfTableInfo2 {
     pub table_size_compressed: usize,
     pub table_size: usize,
 }
 
-#[derive(Debug, Clone, Eq)]
+#[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct GrfFileEntry {
     pub relative_path: String,
     pub size_compressed: usize,
@@ -248,7 +408,7 @@ impl PartialEq for GrfFileEntry {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum GrfFileEncryption {
     Unencrypted,
     Encrypted(usize), // Contains the cycle as usize
@@ -262,17 +422,36 @@ named!(parse_grf_header<&[u8], GrfHeader>,
             >> seed: le_i32
             >> v_files_count: le_i32
             >> version: le_u32
-            >> (GrfHeader {
-                key: key.try_into().unwrap(),
-                file_table_offset: file_table_offset as u64,
-                seed,
-                file_count: (v_files_count - seed - 7) as usize,
-                version_major: (version >> 8) & 0xFF,
-                version_minor: version & 0xFF
-            }
-    )
+            >> (make_grf_header(key, file_table_offset, seed, v_files_count, version))
 ));
 
+/// Builds a GRF header from its raw fields. GRF 3.0 archives use the seed's
+/// field to store the high part of a 64-bit file table offset.
+fn make_grf_header(
+    key: &[u8],
+    file_table_offset: u32,
+    seed: i32,
+    v_files_count: i32,
+    version: u32,
+) -> GrfHeader {
+    let version_major = (version >> 8) & 0xFF;
+    let (file_table_offset, seed) = match version_major {
+        3 => (
+            u64::from(file_table_offset) | (u64::from(seed as u32) << 32),
+            0,
+        ),
+        _ => (u64::from(file_table_offset), seed),
+    };
+    GrfHeader {
+        key: key.try_into().unwrap(),
+        file_table_offset,
+        seed,
+        file_count: (v_files_count - seed - 7) as usize,
+        version_major,
+        version_minor: version & 0xFF,
+    }
+}
+
 named!(parse_grf_table_info_200<&[u8], GrfTableInfo2>,
     do_parse!(
         table_size_compressed: le_u32
@@ -381,8 +560,31 @@ named!(parse_grf_file_entry_200<&[u8], GrfFileEntry>,
     )
 );
 
+// Parses file table entries for GRF 3.0 (64-bit offsets)
+named!(parse_grf_file_entry_300<&[u8], GrfFileEntry>,
+    do_parse!(
+        relative_path: map_res!(take_while!(|ch: u8| ch != 0), string_from_win_1252)
+            >> take!(1) // Null char terminator
+            >> size_compressed: le_u32
+            >> size_compressed_aligned: le_u32
+            >> size: le_u32
+            >> entry_type: le_u8
+            >> offset: le_u64
+            >> (GrfFileEntry {
+                relative_path,
+                size_compressed: size_compressed as usize,
+                size_compressed_aligned: size_compressed_aligned as usize,
+                size: size as usize,
+                entry_type,
+                offset: GRF_HEADER_SIZE as u64 + offset,
+                encryption: GrfFileEncryption::Unencrypted,
+            }
+        )
+    )
+);
+
 named_args!(parse_grf_file_entries_101(files_count: usize)<&[u8], HashMap<String, GrfFileEntry>>,
-fold_many_m_n!(1, files_count - 1, parse_grf_file_entry_101, HashMap::new(), |mut acc: HashMap<_, _>, item| {
+fold_many_m_n!(1, files_count, parse_grf_file_entry_101, HashMap::new(), |mut acc: HashMap<_, _>, item| {
         acc.insert(item.relative_path.clone(), item);
         acc
     })
@@ -395,6 +597,13 @@ fold_many_m_n!(1, files_count, parse_grf_file_entry_200, HashMap::new(), |mut ac
     })
 );
 
+named_args!(parse_grf_file_entries_300(files_count: usize)<&[u8], HashMap<String, GrfFileEntry>>,
+fold_many_m_n!(1, files_count, parse_grf_file_entry_300, HashMap::new(), |mut acc: HashMap<_, _>, item| {
+        acc.insert(item.relative_path.clone(), item);
+        acc
+    })
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -444,6 +653,7 @@ mod tests {
         .collect();
         let check_small_grf_entries = |grf: &mut GrfArchive| {
             let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+            assert_eq!(file_entries.len(), expected_content.len());
             for file_entry in file_entries {
                 let file_path: &str = &file_entry.relative_path[..];
                 assert!(expected_content.contains_key(file_path));
@@ -508,6 +718,64 @@ mod tests {
             assert_eq!(grf.version_minor(), 2);
             check_small_grf_entries(&mut grf);
         }
+
+        for (grf_name, version) in &[
+            ("200-small.grf", (2, 0)),
+            ("103-small.grf", (1, 3)),
+            ("102-empty.grf", (1, 2)),
+        ] {
+            let grf_path = grf_dir_path.join(grf_name);
+            assert_eq!(GrfArchive::read_version(&grf_path).unwrap(), *version);
+        }
+    }
+
+    #[test]
+    fn test_open_mmap() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for grf_name in &[
+            "200-empty.grf",
+            "200-small.grf",
+            "103-small.grf",
+            "102-small.grf",
+        ] {
+            let grf_path = grf_dir_path.join(grf_name);
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            let mut mapped_grf = GrfArchive::open_mmap(&grf_path).unwrap();
+            assert_eq!(grf.file_count(), mapped_grf.file_count());
+            assert_eq!(grf.version_major(), mapped_grf.version_major());
+            assert_eq!(grf.version_minor(), mapped_grf.version_minor());
+            let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+            for file_entry in file_entries {
+                let file_path = &file_entry.relative_path;
+                assert_eq!(Some(&file_entry), mapped_grf.get_file_entry(file_path));
+                assert_eq!(
+                    grf.read_file_content(file_path).unwrap(),
+                    mapped_grf.read_file_content(file_path).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_list() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let grf = GrfArchive::open(grf_dir_path.join("200-small.grf")).unwrap();
+        let entry_paths: Vec<&str> = grf
+            .list("data/texture/*.bmp")
+            .iter()
+            .map(|entry| entry.relative_path.as_str())
+            .collect();
+        assert_eq!(
+            entry_paths,
+            vec![
+                "data\\texture\\chdesk-side1.bmp",
+                "data\\texture\\chdesk-side2.bmp",
+                "data\\texture\\chdesk-side3.bmp"
+            ]
+        );
+        assert_eq!(grf.list("data\\*").len(), 3);
+        assert_eq!(grf.list("**").len(), grf.file_count());
+        assert!(grf.list("*.txt").is_empty());
     }
 
     #[test]
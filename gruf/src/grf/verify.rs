@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use crate::grf::reader::GRF_HEADER_SIZE;
+use crate::grf::{GrfArchive, GrfArchiveBuilder, GrfFileEntry};
+use crate::{NameEncoding, Result};
+use serde::Serialize;
+
+// Entries without this flag are directories
+const GRF_FILE_ENTRY_FLAG: u8 = 0x01;
+
+/// Result of the verification of a GRF archive.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct GrfVerificationReport {
+    /// Number of file entries checked
+    pub entry_count: usize,
+    /// Inconsistencies found in the header or the file table
+    pub table_issues: Vec<String>,
+    /// Entries that cannot be read
+    pub broken_entries: Vec<GrfEntryIssue>,
+}
+
+impl GrfVerificationReport {
+    pub fn is_valid(&self) -> bool {
+        self.table_issues.is_empty() && self.broken_entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GrfEntryIssue {
+    pub relative_path: String,
+    #[serde(flatten)]
+    pub kind: GrfEntryIssueKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "issue", rename_all = "snake_case")]
+pub enum GrfEntryIssueKind {
+    /// Content is located (partly) outside of the archive's data section
+    OutOfBounds,
+    /// Content overlaps with the content of another entry
+    Overlapping { other_relative_path: String },
+    /// Content cannot be decrypted or decompressed
+    CorruptContent { reason: String },
+}
+
+impl<R: Read + Seek> GrfArchive<R> {
+    /// Checks the consistency of the archive: entries must be located between
+    /// the header and the file table, must not overlap and their content must
+    /// be readable.
+    pub fn verify(&mut self) -> Result<GrfVerificationReport> {
+        let archive_size = self.archive_size()?;
+        let data_end_offset = GRF_HEADER_SIZE as u64 + self.file_table_offset();
+        let mut report = GrfVerificationReport::default();
+        if data_end_offset > archive_size {
+            report
+                .table_issues
+                .push("File table is out of bounds".to_string());
+        }
+        let entry_count = self.get_entries().count();
+        if entry_count > self.file_count() {
+            report.table_issues.push(format!(
+                "Header declares {} entries but the file table contains {}",
+                self.file_count(),
+                entry_count
+            ));
+        }
+
+        let mut file_entries: Vec<GrfFileEntry> = self
+            .get_entries()
+            .filter(|entry| entry.entry_type & GRF_FILE_ENTRY_FLAG != 0)
+            .cloned()
+            .collect();
+        file_entries.sort_unstable_by(|a, b| {
+            (a.offset, &a.relative_path).cmp(&(b.offset, &b.relative_path))
+        });
+        report.entry_count = file_entries.len();
+        let mut previous_entry: Option<&GrfFileEntry> = None;
+        for entry in &file_entries {
+            let end_offset = entry.offset + entry.size_compressed_aligned as u64;
+            let kind = if entry.size == 0 {
+                // Empty entries have no content
+                None
+            } else if entry.offset < GRF_HEADER_SIZE as u64 || end_offset > data_end_offset {
+                Some(GrfEntryIssueKind::OutOfBounds)
+            } else {
+                match previous_entry {
+                    Some(previous)
+                        if previous.offset + previous.size_compressed_aligned as u64
+                            > entry.offset =>
+                    {
+                        Some(GrfEntryIssueKind::Overlapping {
+                            other_relative_path: previous.relative_path.clone(),
+                        })
+                    }
+                    _ => self.read_file_content(&entry.relative_path).err().map(|e| {
+                        GrfEntryIssueKind::CorruptContent {
+                            reason: e.to_string(),
+                        }
+                    }),
+                }
+            };
+            match kind {
+                Some(kind) => report.broken_entries.push(GrfEntryIssue {
+                    relative_path: entry.relative_path.clone(),
+                    kind,
+                }),
+                None if entry.size != 0 => previous_entry = Some(entry),
+                None => {}
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Checks the GRF located at `grf_path` and rebuilds it without its broken
+/// entries if needed. Returns the report of the verification, done before
+/// the repair.
+pub fn repair_grf_archive<P: AsRef<Path>>(
+    grf_path: P,
+    name_encoding: NameEncoding,
+) -> Result<GrfVerificationReport> {
+    let grf_path = grf_path.as_ref();
+    let mut grf_archive = GrfArchive::open_with_encoding(grf_path, name_encoding)?;
+    let report = grf_archive.verify()?;
+    if report.is_valid() {
+        return Ok(report);
+    }
+
+    // Note: Archives with overlapping entries cannot be modified in place,
+    // rebuild the archive next to the original one
+    let mut repaired_file_path = grf_path.to_path_buf();
+    repaired_file_path.set_extension("grf.repair");
+    let version_major = grf_archive.version_major().max(2);
+    let version_minor = match version_major {
+        3 => grf_archive.version_minor(),
+        _ => 0,
+    };
+    let entry_paths: Vec<String> = grf_archive
+        .get_entries()
+        .filter(|entry| entry.entry_type & GRF_FILE_ENTRY_FLAG != 0)
+        .filter(|entry| {
+            !report
+                .broken_entries
+                .iter()
+                .any(|issue| issue.relative_path == entry.relative_path)
+        })
+        .map(|entry| entry.relative_path.clone())
+        .collect();
+    let repaired_file = File::create(&repaired_file_path)?;
+    let build_result = GrfArchiveBuilder::create_with_encoding(
+        repaired_file,
+        version_major,
+        version_minor,
+        name_encoding,
+    )
+    .and_then(|mut builder| {
+        for relative_path in entry_paths {
+            builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
+        }
+        builder.finish()
+    });
+    if let Err(e) = build_result {
+        let _ = fs::remove_file(&repaired_file_path);
+        return Err(e);
+    }
+    // Note: The original GRF must be closed before being replaced
+    drop(grf_archive);
+    fs::rename(&repaired_file_path, grf_path)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{SeekFrom, Write};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_verify() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for grf_name in &[
+            "200-empty.grf",
+            "200-small.grf",
+            "103-small.grf",
+            "102-small.grf",
+        ] {
+            let mut grf = GrfArchive::open(grf_dir_path.join(grf_name)).unwrap();
+            let report = grf.verify().unwrap();
+            assert!(report.is_valid(), "{}: {:?}", grf_name, report);
+            assert_eq!(report.entry_count, grf.file_count());
+        }
+    }
+
+    #[test]
+    fn test_repair_grf_archive() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("data.grf");
+        fs::copy(grf_dir_path.join("200-small.grf"), &grf_path).unwrap();
+        let broken_entry_path = "data\\06guild_r.rsw";
+        let broken_entry_offset = GrfArchive::open(&grf_path)
+            .unwrap()
+            .get_file_entry(broken_entry_path)
+            .unwrap()
+            .offset;
+        {
+            // Overwrite the beginning of the entry's compressed content
+            let mut grf_file = fs::OpenOptions::new().write(true).open(&grf_path).unwrap();
+            grf_file.seek(SeekFrom::Start(broken_entry_offset)).unwrap();
+            grf_file.write_all(&[0xFF; 16]).unwrap();
+        }
+        let original_file_count = GrfArchive::open(&grf_path).unwrap().file_count();
+
+        let report = repair_grf_archive(&grf_path, NameEncoding::Windows1252).unwrap();
+
+        assert_eq!(report.broken_entries.len(), 1);
+        assert_eq!(report.broken_entries[0].relative_path, broken_entry_path);
+        assert!(matches!(
+            report.broken_entries[0].kind,
+            GrfEntryIssueKind::CorruptContent { .. }
+        ));
+        assert!(!temp_dir.path().join("data.grf.repair").exists());
+        let mut grf = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf.file_count(), original_file_count - 1);
+        assert!(!grf.contains_file(broken_entry_path));
+        assert!(grf.verify().unwrap().is_valid());
+        // Valid archives are left untouched
+        let report = repair_grf_archive(&grf_path, NameEncoding::Windows1252).unwrap();
+        assert!(report.is_valid());
+    }
+}
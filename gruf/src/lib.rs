@@ -3,4 +3,5 @@ mod error;
 pub mod grf;
 pub mod thor;
 
+pub use archive::{decode_name, match_entry_path, NameEncoding};
 pub use error::{GrufError, Result};
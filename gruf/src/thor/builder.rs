@@ -1,17 +1,26 @@
 use std::boxed::Box;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs::{self, File};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
-use crate::archive::{serialize_as_win1252_str_into, serialize_to_win1252, GenericFileEntry};
+use crate::archive::{
+    archive_entry_path, serialize_as_win1252_str_into, serialize_to_win1252, GenericFileEntry,
+};
+use crate::thor::crypto::{apply_keystream, entry_nonce, ENCRYPTION_NONCE_SIZE};
+use crate::thor::diff::{diff_directories, DirectoryChange};
 use crate::thor::{
-    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
+    ThorArchive, ThorCompression, ThorEncryptionKey, ThorMode, ENCRYPTED_ENTRY_FLAG,
+    INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
 };
-use crate::Result;
+use crate::{GrufError, Result};
 use crc::crc32::{self, Hasher32};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use serde::Serialize;
+use xz2::stream::{LzmaOptions, Stream};
+use xz2::write::XzEncoder;
 
 const THOR_HEADER_FIXED_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8;
 
@@ -22,11 +31,14 @@ pub struct ThorArchiveBuilder<W: Write + Seek> {
     use_grf_merging: bool,
     target_grf_name: String,
     include_checksums: bool,
+    compression: ThorCompression,
+    encryption_key: Option<ThorEncryptionKey>,
 }
 
 struct BuilderFileEntry {
     generic: GenericFileEntry,
     checksum: u32,
+    encryption_nonce: Option<[u8; ENCRYPTION_NONCE_SIZE]>,
 }
 
 #[derive(Debug, Serialize)]
@@ -59,10 +71,28 @@ pub struct SerializableThorFileEntryAdd {
 
 impl<W: Write + Seek> ThorArchiveBuilder<W> {
     pub fn new(
+        obj: W,
+        use_grf_merging: bool,
+        target_grf_name: Option<String>,
+        include_checksums: bool,
+    ) -> Result<Self> {
+        ThorArchiveBuilder::new_with_compression(
+            obj,
+            use_grf_merging,
+            target_grf_name,
+            include_checksums,
+            ThorCompression::Zlib,
+        )
+    }
+
+    /// Creates a builder that compresses entries with `compression`. Archives
+    /// using anything else than zlib can only be read by `gruf`.
+    pub fn new_with_compression(
         mut obj: W,
         use_grf_merging: bool,
         target_grf_name: Option<String>,
         include_checksums: bool,
+        compression: ThorCompression,
     ) -> Result<Self> {
         let target_grf_name = target_grf_name.unwrap_or_default();
         // Placeholder for the THOR header
@@ -79,25 +109,43 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
             use_grf_merging,
             target_grf_name,
             include_checksums,
+            compression,
+            encryption_key: None,
         })
     }
 
+    /// Sets the key used to encrypt the content of the entries appended from
+    /// now on. Archives containing encrypted entries can only be read by
+    /// `gruf`, with the same key.
+    pub fn set_encryption_key(&mut self, encryption_key: Option<ThorEncryptionKey>) {
+        self.encryption_key = encryption_key;
+    }
+
     pub fn append_file_update<R>(&mut self, entry_path: String, mut data: R) -> Result<()>
     where
         R: Read,
     {
         // Compress it
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        let (data_size, data_checksum) = if self.include_checksums {
-            copy_and_measure_crc32(data.by_ref(), &mut encoder)?
-        } else {
-            (io::copy(data.by_ref(), &mut encoder)?, 0)
+        let (mut compressed_data, data_size, data_checksum) = self.compress(data.by_ref())?;
+        // Encrypt it if needed
+        let encryption_nonce = match &self.encryption_key {
+            Some(encryption_key) => {
+                let nonce = entry_nonce(&entry_path, &compressed_data);
+                apply_keystream(encryption_key, &nonce, &mut compressed_data);
+                Some(nonce)
+            }
+            None => None,
         };
         // Write compressed data
-        let compressed_data = encoder.finish()?;
         let compressed_data_size = compressed_data.len();
 
         let offset = self.obj.seek(SeekFrom::Current(0))?;
+        // Offsets are stored as 32-bit integers
+        if u32::try_from(offset).is_err() {
+            return Err(GrufError::size_limit_exceeded(
+                "THOR entries must be located in the first 4 GiB",
+            ));
+        }
         let mut compressed_reader = Cursor::new(compressed_data);
         let _ = io::copy(&mut compressed_reader, self.obj.by_ref())?;
         self.entries.insert(
@@ -105,19 +153,128 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
             Some(BuilderFileEntry {
                 generic: GenericFileEntry {
                     offset,
-                    size: u32::try_from(data_size)?,
+                    size: u32::try_from(data_size).map_err(|_| {
+                        GrufError::size_limit_exceeded("THOR entries must be smaller than 4 GiB")
+                    })?,
                     size_compressed: u32::try_from(compressed_data_size)?,
                 },
                 checksum: data_checksum,
+                encryption_nonce,
             }),
         );
         Ok(())
     }
 
+    /// Adds the given files, named after their path relative to
+    /// `root_directory` (with Windows-style separators).
+    pub fn append_files<I, P>(&mut self, root_directory: impl AsRef<Path>, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        for file_path in files {
+            let entry_path = archive_entry_path(root_directory.as_ref(), file_path.as_ref())?;
+            let file = File::open(file_path.as_ref())?;
+            self.append_file_update(entry_path, file)?;
+        }
+        Ok(())
+    }
+
+    /// Adds all the files contained in `directory_path` and its
+    /// subdirectories, named after their path relative to `root_directory`
+    /// (with Windows-style separators).
+    pub fn append_directory(
+        &mut self,
+        root_directory: impl AsRef<Path>,
+        directory_path: impl AsRef<Path>,
+    ) -> Result<()> {
+        let mut dir_entries = fs::read_dir(directory_path)?.collect::<io::Result<Vec<_>>>()?;
+        // Keep the archive's content deterministic
+        dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
+        for dir_entry in dir_entries {
+            let file_type = dir_entry.file_type()?;
+            if file_type.is_dir() {
+                self.append_directory(root_directory.as_ref(), dir_entry.path())?;
+            } else if file_type.is_file() {
+                self.append_files(root_directory.as_ref(), &[dir_entry.path()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the changes needed to turn the content of `old_directory` into
+    /// the content of `new_directory`: added and modified files are updated,
+    /// missing files are removed.
+    ///
+    /// Returns the list of changes.
+    pub fn append_directory_diff(
+        &mut self,
+        old_directory: impl AsRef<Path>,
+        new_directory: impl AsRef<Path>,
+    ) -> Result<Vec<DirectoryChange>> {
+        let changes = diff_directories(old_directory, new_directory.as_ref())?;
+        for change in &changes {
+            match change {
+                DirectoryChange::Added(entry_path) | DirectoryChange::Modified(entry_path) => {
+                    let file_path = new_directory.as_ref().join(entry_path.replace("\\", "/"));
+                    self.append_file_update(entry_path.clone(), File::open(file_path)?)?;
+                }
+                DirectoryChange::Removed(entry_path) => {
+                    self.append_file_removal(entry_path.clone())
+                }
+            }
+        }
+        Ok(changes)
+    }
+
     pub fn append_file_removal(&mut self, entry_path: String) {
         self.entries.insert(entry_path, None);
     }
 
+    /// Adds the entries of the given archives, as if they were applied in
+    /// order: entries of later archives replace those of earlier ones, and
+    /// removals are kept.
+    ///
+    /// Only the final version of each entry is written. All the archives must
+    /// target the same destination as the archive being built.
+    pub fn append_archives<R: Read + Seek>(
+        &mut self,
+        archives: &mut [ThorArchive<R>],
+    ) -> Result<()> {
+        // Index of the archive holding the latest version of each entry
+        let mut latest_entries: HashMap<String, Option<usize>> = HashMap::new();
+        for (archive_index, archive) in archives.iter().enumerate() {
+            if archive.use_grf_merging() != self.use_grf_merging
+                || archive.target_grf_name() != self.target_grf_name
+            {
+                return Err(GrufError::serialization_error(
+                    "Archives targeting different destinations cannot be merged",
+                ));
+            }
+            for entry in archive.get_entries().filter(|entry| !entry.is_internal()) {
+                let source = if entry.is_removed {
+                    None
+                } else {
+                    Some(archive_index)
+                };
+                latest_entries.insert(entry.relative_path.clone(), source);
+            }
+        }
+        let mut latest_entries: Vec<_> = latest_entries.into_iter().collect();
+        // Keep the archive's content deterministic
+        latest_entries.sort_unstable();
+        for (entry_path, source) in latest_entries {
+            match source {
+                None => self.append_file_removal(entry_path),
+                Some(archive_index) => {
+                    let content = archives[archive_index].read_file_content(&entry_path)?;
+                    self.append_file_update(entry_path, content.as_slice())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn finish(&mut self) -> Result<()> {
         if self.finished {
             return Ok(());
@@ -131,16 +288,52 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         let (file_table_offset, compressed_table_size) = self.write_file_table()?;
         // Update the header
         self.obj.seek(SeekFrom::Start(0))?;
+        let is_encrypted = self
+            .entries
+            .values()
+            .any(|entry| matches!(entry, Some(entry) if entry.encryption_nonce.is_some()));
+        let mode = match self.compression {
+            ThorCompression::Zlib if !is_encrypted => ThorMode::MultipleFiles,
+            _ => ThorMode::MultipleFilesExtended,
+        };
         write_thor_header(
             &mut self.obj,
             self.use_grf_merging,
             self.entries.len(),
+            mode,
             self.target_grf_name.as_str(),
             compressed_table_size,
             file_table_offset,
         )
     }
 
+    /// Compresses `data` with the builder's compression method.
+    ///
+    /// Returns the compressed data, the size of `data` and its checksum (if
+    /// checksums are included).
+    fn compress<R: Read>(&self, data: &mut R) -> Result<(Vec<u8>, u64, u32)> {
+        let include_checksums = self.include_checksums;
+        match self.compression {
+            ThorCompression::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                let (data_size, data_checksum) = copy_data(include_checksums, data, &mut encoder)?;
+                Ok((encoder.finish()?, data_size, data_checksum))
+            }
+            ThorCompression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+                let (data_size, data_checksum) = copy_data(include_checksums, data, &mut encoder)?;
+                Ok((encoder.finish()?, data_size, data_checksum))
+            }
+            ThorCompression::Lzma => {
+                let options = LzmaOptions::new_preset(6).map_err(io::Error::from)?;
+                let stream = Stream::new_lzma_encoder(&options).map_err(io::Error::from)?;
+                let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+                let (data_size, data_checksum) = copy_data(include_checksums, data, &mut encoder)?;
+                Ok((encoder.finish()?, data_size, data_checksum))
+            }
+        }
+    }
+
     fn write_file_table(&mut self) -> Result<(u64, usize)> {
         let mut table: Vec<u8> = Vec::new();
         // Generate table and write files' content
@@ -156,14 +349,22 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
                 }
                 Some(entry) => {
                     // File update or file creation
+                    let mut flags = compression_flags(self.compression);
+                    if entry.encryption_nonce.is_some() {
+                        flags |= ENCRYPTED_ENTRY_FLAG;
+                    }
                     let thor_file_entry = SerializableThorFileEntryAdd {
-                        flags: 0,
+                        flags,
                         offset: u32::try_from(entry.generic.offset)?,
                         size: entry.generic.size,
                         size_compressed: entry.generic.size_compressed,
                     };
                     serialize_thor_slice_into(&mut table, rel_path_win1252.as_slice())?;
                     bincode::serialize_into(&mut table, &thor_file_entry)?;
+                    // Encrypted entries are followed by their nonce
+                    if let Some(nonce) = &entry.encryption_nonce {
+                        table.write_all(nonce)?;
+                    }
                 }
             }
         }
@@ -211,6 +412,7 @@ fn write_thor_header<W: Write>(
     writer: &mut W,
     use_grf_merging: bool,
     file_count: usize,
+    mode: ThorMode,
     target_grf_name: &str,
     file_table_compressed_size: usize,
     file_table_offset: u64,
@@ -220,11 +422,13 @@ fn write_thor_header<W: Write>(
         magic: THOR_HEADER_MAGIC,
         use_grf_merging,
         file_count: u32::try_from(file_count)?,
-        mode: thor_mode_to_i16(ThorMode::MultipleFiles).unwrap(),
+        mode: thor_mode_to_i16(mode).unwrap(),
     };
     let table_desc = SerializableFileTableDesc {
         file_table_compressed_size: u32::try_from(file_table_compressed_size)?,
-        file_table_offset: u32::try_from(file_table_offset)?,
+        file_table_offset: u32::try_from(file_table_offset).map_err(|_| {
+            GrufError::size_limit_exceeded("THOR file table must be located in the first 4 GiB")
+        })?,
     };
     bincode::serialize_into(writer.by_ref(), &grf_header)?;
     serialize_thor_str_into(writer.by_ref(), target_grf_name)?;
@@ -236,6 +440,7 @@ fn thor_mode_to_i16(mode: ThorMode) -> Option<i16> {
     match mode {
         ThorMode::SingleFile => Some(33),
         ThorMode::MultipleFiles => Some(48),
+        ThorMode::MultipleFilesExtended => Some(49),
         ThorMode::Invalid => None,
     }
 }
@@ -262,6 +467,30 @@ fn serialize_thor_slice_into<W: Write>(mut writer: W, slice: &[u8]) -> Result<()
     Ok(())
 }
 
+/// In the extended format, bits 1-2 of entries' flags indicate the
+/// compression method.
+fn compression_flags(compression: ThorCompression) -> u8 {
+    match compression {
+        ThorCompression::Zlib => 0,
+        ThorCompression::Zstd => 1 << 1,
+        ThorCompression::Lzma => 2 << 1,
+    }
+}
+
+/// Copies `reader` into `writer`, computing a CRC32 checksum of the data if
+/// `include_checksums` is set.
+fn copy_data<R: Read + ?Sized, W: Write + ?Sized>(
+    include_checksums: bool,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<(u64, u32)> {
+    if include_checksums {
+        copy_and_measure_crc32(reader, writer)
+    } else {
+        Ok((io::copy(reader, writer)?, 0))
+    }
+}
+
 /// Computes a CRC32 checksum from a reader.
 fn copy_and_measure_crc32<R: ?Sized, W: ?Sized>(
     reader: &mut R,
@@ -291,8 +520,8 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::thor::{ThorArchive, ThorFileEntry};
-    use std::fs::File;
+    use crate::grf::{GrfArchive, GrfArchiveBuilder};
+    use crate::thor::ThorFileEntry;
     use tempfile::tempdir;
 
     #[test]
@@ -389,6 +618,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_append_directory() {
+        let temp_dir = tempdir().unwrap();
+        let data_dir = temp_dir.path().join("data");
+        fs::create_dir_all(data_dir.join("texture")).unwrap();
+        fs::write(data_dir.join("test1"), vec![1, 2, 3]).unwrap();
+        fs::write(data_dir.join("texture").join("test2"), vec![5, 6]).unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, false).unwrap();
+            builder
+                .append_directory(temp_dir.path(), &data_dir)
+                .unwrap();
+            assert!(builder
+                .append_files(&data_dir, &[temp_dir.path().join("builder.thor")])
+                .is_err());
+        }
+        {
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            assert_eq!(thor_archive.file_count(), 2);
+            assert_eq!(
+                thor_archive.read_file_content("data\\test1").unwrap(),
+                vec![1, 2, 3]
+            );
+            assert_eq!(
+                thor_archive
+                    .read_file_content("data\\texture\\test2")
+                    .unwrap(),
+                vec![5, 6]
+            );
+        }
+    }
+
     #[test]
     fn test_data_integrity() {
         let temp_dir = tempdir().unwrap();
@@ -412,4 +675,246 @@ mod tests {
             assert!(thor_archive.is_valid().unwrap());
         }
     }
+
+    #[test]
+    fn test_append_archives() {
+        let temp_dir = tempdir().unwrap();
+        let patches = vec![
+            vec![
+                ("data\\test1", Some(vec![1, 2, 3])),
+                ("data\\test2", Some(vec![5, 6])),
+                ("data\\test3", Some(vec![7])),
+            ],
+            vec![("data\\test1", Some(vec![4])), ("data\\test2", None)],
+            vec![("data\\test3", Some(vec![8, 9])), ("data\\test4", None)],
+        ];
+        let mut archives = vec![];
+        for (i, patch) in patches.into_iter().enumerate() {
+            let patch_path = temp_dir.path().join(format!("{}.thor", i));
+            let output_file = File::create(&patch_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, true, None, true).unwrap();
+            for (entry_path, content) in patch {
+                match content {
+                    Some(content) => builder
+                        .append_file_update(entry_path.to_string(), content.as_slice())
+                        .unwrap(),
+                    None => builder.append_file_removal(entry_path.to_string()),
+                }
+            }
+            builder.finish().unwrap();
+            archives.push(ThorArchive::open(&patch_path).unwrap());
+        }
+
+        let output_path = temp_dir.path().join("merged.thor");
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, true, None, true).unwrap();
+            builder.append_archives(&mut archives).unwrap();
+        }
+        {
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            thor_archive.verify().unwrap();
+            // Including 'data.integrity'
+            assert_eq!(thor_archive.file_count(), 5);
+            assert_eq!(
+                thor_archive.read_file_content("data\\test1").unwrap(),
+                vec![4]
+            );
+            assert!(
+                thor_archive
+                    .get_file_entry("data\\test2")
+                    .unwrap()
+                    .is_removed
+            );
+            assert_eq!(
+                thor_archive.read_file_content("data\\test3").unwrap(),
+                vec![8, 9]
+            );
+            assert!(
+                thor_archive
+                    .get_file_entry("data\\test4")
+                    .unwrap()
+                    .is_removed
+            );
+        }
+        // Archives must target the same destination
+        let output_file = File::create(&output_path).unwrap();
+        let mut builder = ThorArchiveBuilder::new(output_file, false, None, false).unwrap();
+        assert!(builder.append_archives(&mut archives).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let temp_dir = tempdir().unwrap();
+        let expected_content: HashMap<&str, Vec<u8>> = [
+            ("data\\test1", vec![1, 2, 3]),
+            ("data\\test2", vec![0xAB; 64 * 1024]),
+            ("data\\empty", vec![]),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        for (compression, expected_mode) in &[
+            (ThorCompression::Zlib, 48),
+            (ThorCompression::Zstd, 49),
+            (ThorCompression::Lzma, 49),
+        ] {
+            let output_path = temp_dir.path().join("builder.thor");
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = ThorArchiveBuilder::new_with_compression(
+                    output_file,
+                    true,
+                    None,
+                    true,
+                    *compression,
+                )
+                .unwrap();
+                for entry in &expected_content {
+                    builder
+                        .append_file_update(entry.0.to_string(), entry.1.as_slice())
+                        .unwrap();
+                }
+                builder.append_file_removal("data\\removed".to_string());
+            }
+            // Only archives using zlib are compatible with other patchers
+            let content = fs::read(&output_path).unwrap();
+            let mode_offset = THOR_HEADER_MAGIC.len() + 5;
+            assert_eq!(
+                i16::from_le_bytes([content[mode_offset], content[mode_offset + 1]]),
+                *expected_mode
+            );
+
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            thor_archive.verify().unwrap();
+            assert!(
+                thor_archive
+                    .get_file_entry("data\\removed")
+                    .unwrap()
+                    .is_removed
+            );
+            for (file_path, expected_content) in &expected_content {
+                let entry = thor_archive.get_file_entry(file_path).unwrap();
+                assert_eq!(entry.compression, *compression);
+                assert_eq!(
+                    &thor_archive.read_file_content(file_path).unwrap(),
+                    expected_content
+                );
+            }
+            // Entries are re-compressed with zlib when merged into GRFs
+            let grf_path = temp_dir.path().join("data.grf");
+            {
+                let grf_file = File::create(&grf_path).unwrap();
+                let mut grf_builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+                for file_path in expected_content.keys() {
+                    grf_builder
+                        .import_raw_entry_from_thor(&mut thor_archive, file_path.to_string())
+                        .unwrap();
+                }
+            }
+            let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+            for (file_path, expected_content) in &expected_content {
+                assert_eq!(
+                    &grf_archive.read_file_content(file_path).unwrap(),
+                    expected_content
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("encrypted.thor");
+        let encryption_key = ThorEncryptionKey::from_secret(b"secret");
+        let content = b"some content".repeat(100);
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, true, None, true).unwrap();
+            builder.set_encryption_key(Some(encryption_key.clone()));
+            builder
+                .append_file_update("data\test".to_string(), content.as_slice())
+                .unwrap();
+            builder.append_file_removal("data\removed".to_string());
+        }
+        // Encrypted archives use the extended format
+        let raw_content = fs::read(&output_path).unwrap();
+        let mode_offset = THOR_HEADER_MAGIC.len() + 5;
+        assert_eq!(
+            i16::from_le_bytes([raw_content[mode_offset], raw_content[mode_offset + 1]]),
+            49
+        );
+
+        let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+        assert!(thor_archive
+            .get_file_entry("data\test")
+            .unwrap()
+            .is_encrypted());
+        assert!(
+            thor_archive
+                .get_file_entry("data\removed")
+                .unwrap()
+                .is_removed
+        );
+        // Entries can't be read without the right key
+        assert!(thor_archive.read_file_content("data\test").is_err());
+        thor_archive.set_encryption_key(Some(ThorEncryptionKey::from_secret(b"other")));
+        assert!(thor_archive.read_file_content("data\test").is_err());
+        thor_archive.set_encryption_key(Some(encryption_key));
+        thor_archive.verify().unwrap();
+        assert_eq!(
+            thor_archive.read_file_content("data\test").unwrap(),
+            content
+        );
+
+        // Entries are decrypted when merged into GRFs
+        let grf_path = temp_dir.path().join("data.grf");
+        {
+            let grf_file = File::create(&grf_path).unwrap();
+            let mut grf_builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            grf_builder
+                .import_raw_entry_from_thor(&mut thor_archive, "data\test".to_string())
+                .unwrap();
+        }
+        let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf_archive.read_file_content("data\test").unwrap(), content);
+    }
+
+    // Note: Relies on sparse files to avoid writing GiBs of data
+    #[cfg(unix)]
+    #[test]
+    fn test_large_offsets() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("large.thor");
+        // Entries located between 2 GiB and 4 GiB
+        {
+            let mut output_file = File::create(&output_path).unwrap();
+            output_file.seek(SeekFrom::Start(3 << 30)).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+            builder
+                .append_file_update("data\\test1".to_string(), [1u8; 64].as_ref())
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        {
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            let entry = thor_archive.get_file_entry("data\\test1").unwrap();
+            assert!(entry.offset > 3 << 30);
+            assert_eq!(
+                thor_archive.read_file_content("data\\test1").unwrap(),
+                vec![1u8; 64]
+            );
+            assert!(thor_archive.is_valid().unwrap());
+        }
+        // Entries located past 4 GiB cannot be referenced
+        {
+            let mut output_file = File::create(&output_path).unwrap();
+            output_file.seek(SeekFrom::Start(4 << 30)).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, false).unwrap();
+            assert!(matches!(
+                builder.append_file_update("data\\test1".to_string(), [1u8; 64].as_ref()),
+                Err(GrufError::SizeLimitExceeded(_))
+            ));
+        }
+    }
 }
@@ -0,0 +1,127 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::{GrufError, Result};
+use chacha20::cipher::{NewCipher, StreamCipher};
+use chacha20::{ChaCha20, Key, Nonce};
+use sha2::{Digest, Sha256};
+
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+pub const ENCRYPTION_NONCE_SIZE: usize = 12;
+// Prefix of the secrets encryption keys are derived from
+const KEY_DERIVATION_CONTEXT: &[u8] = b"gruf-thor-encryption-key";
+
+/// Key used to encrypt the content of THOR entries (with ChaCha20).
+#[derive(Clone, PartialEq, Eq)]
+pub struct ThorEncryptionKey([u8; ENCRYPTION_KEY_SIZE]);
+
+impl ThorEncryptionKey {
+    pub fn new(key: [u8; ENCRYPTION_KEY_SIZE]) -> Self {
+        Self(key)
+    }
+
+    /// Creates a key from its raw bytes, which must be 32 bytes long.
+    pub fn from_slice(key: &[u8]) -> Result<Self> {
+        let key = key.try_into().map_err(|_| {
+            GrufError::parsing_error(format!(
+                "Encryption keys must be {} bytes long",
+                ENCRYPTION_KEY_SIZE
+            ))
+        })?;
+        Ok(Self(key))
+    }
+
+    /// Derives a key from a secret of any length (e.g. a passphrase).
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_DERIVATION_CONTEXT);
+        hasher.update(secret);
+        Self(hasher.finalize().into())
+    }
+}
+
+impl fmt::Debug for ThorEncryptionKey {
+    // Never print the key itself
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ThorEncryptionKey(..)")
+    }
+}
+
+/// Generates the nonce used to encrypt an entry. Nonces depend on the entry's
+/// path and content so that they're never reused for different data.
+pub fn entry_nonce(relative_path: &str, compressed_data: &[u8]) -> [u8; ENCRYPTION_NONCE_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(relative_path.as_bytes());
+    hasher.update(compressed_data);
+    let mut nonce = [0; ENCRYPTION_NONCE_SIZE];
+    nonce.copy_from_slice(&hasher.finalize()[..ENCRYPTION_NONCE_SIZE]);
+    nonce
+}
+
+/// Encrypts or decrypts `data` in place.
+pub fn apply_keystream(
+    key: &ThorEncryptionKey,
+    nonce: &[u8; ENCRYPTION_NONCE_SIZE],
+    data: &mut [u8],
+) {
+    let mut cipher = ChaCha20::new(&Key::from(key.0), &Nonce::from(*nonce));
+    cipher.apply_keystream(data);
+}
+
+/// Reader that decrypts the data read from an encrypted entry.
+pub struct DecryptingReader<R: Read> {
+    reader: R,
+    cipher: ChaCha20,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(reader: R, key: &ThorEncryptionKey, nonce: &[u8; ENCRYPTION_NONCE_SIZE]) -> Self {
+        Self {
+            reader,
+            cipher: ChaCha20::new(&Key::from(key.0), &Nonce::from(*nonce)),
+        }
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = self.reader.read(buf)?;
+        self.cipher.apply_keystream(&mut buf[..read_size]);
+        Ok(read_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decrypting_reader() {
+        let key = ThorEncryptionKey::from_secret(b"secret");
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let nonce = entry_nonce("data\\file.txt", &data);
+        let mut encrypted_data = data.clone();
+        apply_keystream(&key, &nonce, &mut encrypted_data);
+        assert_ne!(data, encrypted_data);
+
+        // Decrypt in small chunks to make sure the keystream stays aligned
+        let mut reader = DecryptingReader::new(encrypted_data.as_slice(), &key, &nonce);
+        let mut decrypted_data = vec![];
+        let mut chunk = [0; 333];
+        loop {
+            let read_size = reader.read(&mut chunk).unwrap();
+            if read_size == 0 {
+                break;
+            }
+            decrypted_data.extend_from_slice(&chunk[..read_size]);
+        }
+        assert_eq!(data, decrypted_data);
+
+        assert!(ThorEncryptionKey::from_slice(&[0; 16]).is_err());
+        assert_eq!(
+            ThorEncryptionKey::new([1; ENCRYPTION_KEY_SIZE]),
+            ThorEncryptionKey::from_slice(&[1; ENCRYPTION_KEY_SIZE]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::archive::archive_entry_path;
+use crate::Result;
+
+/// Difference between two directory snapshots, identified by the name of the
+/// entry (e.g. "data\\texture\\file.bmp").
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectoryChange {
+    Added(String),    // File only present in the new directory
+    Modified(String), // File whose content changed
+    Removed(String),  // File only present in the old directory
+}
+
+/// Compares the files contained in `old_directory` with the files contained
+/// in `new_directory` (and their subdirectories).
+///
+/// Changes are sorted by entry name.
+pub fn diff_directories(
+    old_directory: impl AsRef<Path>,
+    new_directory: impl AsRef<Path>,
+) -> Result<Vec<DirectoryChange>> {
+    let old_directory = old_directory.as_ref();
+    let new_directory = new_directory.as_ref();
+    let mut old_entries = BTreeSet::new();
+    list_entries(old_directory, old_directory, &mut old_entries)?;
+    let mut new_entries = BTreeSet::new();
+    list_entries(new_directory, new_directory, &mut new_entries)?;
+
+    let mut changes = vec![];
+    for entry_path in old_entries.union(&new_entries) {
+        let file_path = entry_path.replace("\\", "/");
+        let change = match (
+            old_entries.contains(entry_path),
+            new_entries.contains(entry_path),
+        ) {
+            (false, _) => DirectoryChange::Added(entry_path.clone()),
+            (_, false) => DirectoryChange::Removed(entry_path.clone()),
+            _ => {
+                if files_are_equal(
+                    old_directory.join(&file_path),
+                    new_directory.join(&file_path),
+                )? {
+                    continue;
+                }
+                DirectoryChange::Modified(entry_path.clone())
+            }
+        };
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+/// Collects the names of the entries of all the files contained in
+/// `directory_path` and its subdirectories.
+fn list_entries(
+    root_directory: &Path,
+    directory_path: &Path,
+    entries: &mut BTreeSet<String>,
+) -> Result<()> {
+    for dir_entry in fs::read_dir(directory_path)? {
+        let dir_entry = dir_entry?;
+        let file_type = dir_entry.file_type()?;
+        if file_type.is_dir() {
+            list_entries(root_directory, &dir_entry.path(), entries)?;
+        } else if file_type.is_file() {
+            entries.insert(archive_entry_path(root_directory, &dir_entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+/// Compares the content of two files chunk by chunk.
+fn files_are_equal(file_path1: impl AsRef<Path>, file_path2: impl AsRef<Path>) -> Result<bool> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let file1 = File::open(file_path1)?;
+    let file2 = File::open(file_path2)?;
+    if file1.metadata()?.len() != file2.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut reader1 = BufReader::new(file1);
+    let mut reader2 = BufReader::new(file2);
+    let mut buf1 = vec![0_u8; CHUNK_SIZE];
+    let mut buf2 = vec![0_u8; CHUNK_SIZE];
+    loop {
+        let len = read_chunk(&mut reader1, &mut buf1)?;
+        if len != read_chunk(&mut reader2, &mut buf2)? || buf1[..len] != buf2[..len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fills `buf` as much as possible, returns the number of bytes read.
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thor::{ThorArchive, ThorArchiveBuilder};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_diff_directories() {
+        let temp_dir = tempdir().unwrap();
+        let old_dir = temp_dir.path().join("old");
+        let new_dir = temp_dir.path().join("new");
+        for dir in &[&old_dir, &new_dir] {
+            fs::create_dir_all(dir.join("data/texture")).unwrap();
+            fs::write(dir.join("unchanged.txt"), vec![1, 2, 3]).unwrap();
+        }
+        fs::write(old_dir.join("data/texture/modified.bmp"), vec![1, 2]).unwrap();
+        fs::write(new_dir.join("data/texture/modified.bmp"), vec![1, 3]).unwrap();
+        fs::write(old_dir.join("data/removed.txt"), vec![4]).unwrap();
+        fs::write(new_dir.join("data/added.txt"), vec![5]).unwrap();
+
+        let changes = diff_directories(&old_dir, &new_dir).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                DirectoryChange::Added("data\\added.txt".to_string()),
+                DirectoryChange::Removed("data\\removed.txt".to_string()),
+                DirectoryChange::Modified("data\\texture\\modified.bmp".to_string()),
+            ]
+        );
+
+        // Generate a patch targeting a GRF
+        let output_path = temp_dir.path().join("diff.thor");
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder =
+                ThorArchiveBuilder::new(output_file, true, Some("data.grf".to_string()), true)
+                    .unwrap();
+            assert_eq!(
+                changes,
+                builder.append_directory_diff(&old_dir, &new_dir).unwrap()
+            );
+        }
+        let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+        assert!(thor_archive.use_grf_merging());
+        assert_eq!(thor_archive.target_grf_name(), "data.grf");
+        assert_eq!(
+            thor_archive.read_file_content("data\\added.txt").unwrap(),
+            vec![5]
+        );
+        assert_eq!(
+            thor_archive
+                .read_file_content("data\\texture\\modified.bmp")
+                .unwrap(),
+            vec![1, 3]
+        );
+        assert!(
+            thor_archive
+                .get_file_entry("data\\removed.txt")
+                .unwrap()
+                .is_removed
+        );
+        assert!(thor_archive.get_file_entry("unchanged.txt").is_none());
+    }
+}
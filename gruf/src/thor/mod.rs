@@ -1,17 +1,40 @@
 pub mod builder;
+pub mod diff;
+pub mod patch_list;
 pub mod reader;
 
+mod crypto;
+
 pub use builder::ThorArchiveBuilder;
+pub use crypto::ThorEncryptionKey;
+pub use diff::{diff_directories, DirectoryChange};
+pub use patch_list::{
+    patch_list_to_json, patch_list_to_string, update_patch_list, PatchListOptions,
+};
 pub use reader::{
-    patch_list_from_string, ThorArchive, ThorFileEntry, ThorPatchInfo, ThorPatchList,
+    parse_thor_file_table_range, parse_thor_header_bytes, patch_list_from_json,
+    patch_list_from_string, ThorArchive, ThorEntryContent, ThorFileEntry, ThorHeader,
+    ThorPatchInfo, ThorPatchList,
 };
 
 const THOR_HEADER_MAGIC: &[u8; 24] = b"ASSF (C) 2007 Aeomin DEV";
 const INTEGRITY_FILE_NAME: &str = "data.integrity";
 const MULTIPLE_FILES_TABLE_DESC_SIZE: usize = 2 * std::mem::size_of::<i32>();
+// In the extended format, bit 3 of entries' flags indicates encrypted content
+const ENCRYPTED_ENTRY_FLAG: u8 = 1 << 3;
 #[derive(Debug, PartialEq, Eq)]
 enum ThorMode {
     SingleFile,
     MultipleFiles,
+    MultipleFilesExtended, // Entries may be encrypted or use other compression methods than zlib
     Invalid,
 }
+
+/// Compression method of THOR entries. Archives using anything else than zlib
+/// are written in an extended format, which other patchers don't support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThorCompression {
+    Zlib,
+    Zstd,
+    Lzma,
+}
@@ -0,0 +1,205 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use crate::thor::ThorPatchInfo;
+use crate::{GrufError, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Extensions of the files considered to be patches
+const PATCH_EXTENSIONS: [&str; 3] = ["thor", "rgz", "gpf"];
+
+#[derive(Debug, Default, Clone)]
+pub struct PatchListOptions {
+    pub include_sizes: bool,            // Set the size of every patch
+    pub include_hashes: bool,           // Set the SHA-256 hash of every patch
+    pub ignored_files: HashSet<String>, // Patches that must not be added (e.g. disabled ones)
+}
+
+/// Brings a patch list up to date with the patches located in
+/// `patch_directory`.
+///
+/// Patches already listed keep their index, new patches are appended in the
+/// order of their file names. Sizes and hashes are refreshed when present (or
+/// added when requested through `options`). Fails if an index or a file name
+/// is listed twice, or if a listed patch is missing.
+pub fn update_patch_list(
+    patch_list: &[ThorPatchInfo],
+    patch_directory: impl AsRef<Path>,
+    options: &PatchListOptions,
+) -> Result<Vec<ThorPatchInfo>> {
+    let patch_directory = patch_directory.as_ref();
+    // Validate the current list
+    let mut indices = HashSet::new();
+    let mut file_names = HashSet::new();
+    for patch_info in patch_list {
+        if !indices.insert(patch_info.index) {
+            return Err(GrufError::invalid_content(format!(
+                "Index {} is used more than once",
+                patch_info.index
+            )));
+        }
+        if !file_names.insert(patch_info.file_name.as_str()) {
+            return Err(GrufError::invalid_content(format!(
+                "'{}' is listed more than once",
+                patch_info.file_name
+            )));
+        }
+        if !patch_directory.join(&patch_info.file_name).is_file() {
+            return Err(GrufError::invalid_content(format!(
+                "'{}' doesn't exist",
+                patch_info.file_name
+            )));
+        }
+    }
+
+    // Look for new patches
+    let mut new_file_names = vec![];
+    for dir_entry in fs::read_dir(patch_directory)? {
+        let dir_entry = dir_entry?;
+        let file_name = dir_entry.file_name().to_string_lossy().into_owned();
+        if dir_entry.file_type()?.is_file()
+            && is_patch_file_name(&file_name)
+            && !file_names.contains(file_name.as_str())
+            && !options.ignored_files.contains(&file_name)
+        {
+            new_file_names.push(file_name);
+        }
+    }
+    new_file_names.sort_unstable();
+
+    let first_new_index = patch_list.iter().map(|p| p.index + 1).max().unwrap_or(1);
+    let mut updated_patch_list = patch_list.to_vec();
+    for (i, file_name) in new_file_names.into_iter().enumerate() {
+        updated_patch_list.push(ThorPatchInfo {
+            index: first_new_index + i,
+            file_name,
+            ..Default::default()
+        });
+    }
+    for patch_info in &mut updated_patch_list {
+        let patch_path = patch_directory.join(&patch_info.file_name);
+        if options.include_sizes || patch_info.size.is_some() {
+            patch_info.size = Some(fs::metadata(&patch_path)?.len());
+        }
+        if options.include_hashes || patch_info.hash.is_some() {
+            patch_info.hash = Some(sha256_file_hash(&patch_path)?);
+        }
+    }
+    updated_patch_list.sort_by_key(|patch_info| patch_info.index);
+    Ok(updated_patch_list)
+}
+
+/// Serializes a patch list to the plist.txt format.
+///
+/// Only the index, file name, size and torrent URL of patches are kept.
+pub fn patch_list_to_string(patch_list: &[ThorPatchInfo]) -> String {
+    patch_list
+        .iter()
+        .map(|patch_info| {
+            let mut line = format!("{} {}", patch_info.index, patch_info.file_name);
+            if let Some(size) = patch_info.size {
+                line += format!(" {}", size).as_str();
+            }
+            if let Some(torrent_url) = &patch_info.torrent_url {
+                line += format!(" {}", torrent_url).as_str();
+            }
+            line + "\r\n"
+        })
+        .collect()
+}
+
+/// Serializes a patch list to a JSON patch index.
+pub fn patch_list_to_json(patch_list: &[ThorPatchInfo]) -> Result<String> {
+    #[derive(Serialize)]
+    struct JsonPatchIndex<'a> {
+        patches: &'a [ThorPatchInfo],
+    }
+    serde_json::to_string_pretty(&JsonPatchIndex {
+        patches: patch_list,
+    })
+    .map_err(|e| GrufError::serialization_error(e.to_string()))
+}
+
+fn is_patch_file_name(file_name: &str) -> bool {
+    match Path::new(file_name).extension() {
+        Some(extension) => {
+            let extension = extension.to_string_lossy().to_ascii_lowercase();
+            PATCH_EXTENSIONS.contains(&extension.as_str())
+        }
+        None => false,
+    }
+}
+
+/// Computes the hex-encoded SHA-256 hash of a file.
+fn sha256_file_hash(file_path: impl AsRef<Path>) -> Result<String> {
+    let mut file = File::open(file_path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thor::{patch_list_from_json, patch_list_from_string};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_update_patch_list() {
+        let temp_dir = tempdir().unwrap();
+        for file_name in &["b.thor", "a.thor", "c.RGZ", "disabled.thor", "readme.txt"] {
+            fs::write(temp_dir.path().join(file_name), b"abc").unwrap();
+        }
+        let patch_list = patch_list_from_string("10 b.thor 1");
+        let options = PatchListOptions {
+            ignored_files: ["disabled.thor".to_string()].iter().cloned().collect(),
+            ..Default::default()
+        };
+        let patch_list = update_patch_list(&patch_list, temp_dir.path(), &options).unwrap();
+        let summary: Vec<_> = patch_list
+            .iter()
+            .map(|p| (p.index, p.file_name.as_str(), p.size))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (10, "b.thor", Some(3)),
+                (11, "a.thor", None),
+                (12, "c.RGZ", None)
+            ]
+        );
+        assert_eq!(
+            patch_list_to_string(&patch_list),
+            "10 b.thor 3\r\n11 a.thor\r\n12 c.RGZ\r\n"
+        );
+
+        // Hashes
+        let options = PatchListOptions {
+            include_hashes: true,
+            ..options
+        };
+        let patch_list = update_patch_list(&patch_list, temp_dir.path(), &options).unwrap();
+        assert_eq!(
+            patch_list[0].hash.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        let json_content = patch_list_to_json(&patch_list).unwrap();
+        assert_eq!(patch_list_from_json(&json_content).unwrap(), patch_list);
+
+        // Invalid lists
+        let invalid_lists = ["1 a.thor\n1 b.thor", "1 a.thor\n2 a.thor", "1 missing.thor"];
+        for content in &invalid_lists {
+            assert!(
+                update_patch_list(&patch_list_from_string(content), temp_dir.path(), &options)
+                    .is_err()
+            );
+        }
+    }
+}
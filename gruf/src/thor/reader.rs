@@ -1,25 +1,40 @@
 use std::boxed::Box;
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::Path;
 
+use crate::archive::{map_file, transcode_win1252_name, NameEncoding};
+use crate::thor::crypto::{apply_keystream, DecryptingReader, ENCRYPTION_NONCE_SIZE};
 use crate::thor::{
-    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
+    ThorCompression, ThorEncryptionKey, ThorMode, ENCRYPTED_ENTRY_FLAG, INTEGRITY_FILE_NAME,
+    MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
 };
 use crate::{GrufError, Result};
 use crc::crc32;
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
 use flate2::read::ZlibDecoder;
-use nom::number::complete::{le_i16, le_i32, le_u32, le_u8};
+use memmap2::Mmap;
+use nom::number::complete::{le_i16, le_u32, le_u8};
 use nom::*;
+use serde::{Deserialize, Serialize};
+use xz2::read::XzDecoder;
+use xz2::stream::Stream;
 
 // Packed structs' sizes in bytes
 const MAX_FILE_NAME_SIZE: usize = 256;
-const HEADER_MAX_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8 + MAX_FILE_NAME_SIZE;
+/// Size of the largest THOR header. Reading that many bytes (or the whole
+/// archive, if smaller) is enough to parse the header.
+pub const HEADER_MAX_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8 + MAX_FILE_NAME_SIZE;
 const SINGLE_FILE_ENTRY_MAX_SIZE: usize = 9 + MAX_FILE_NAME_SIZE;
+/// Size of the largest THOR header, followed by the description of the file
+/// table (or by the entry of single-file archives).
+pub const HEADER_EXTENDED_MAX_SIZE: usize =
+    HEADER_MAX_SIZE + MULTIPLE_FILES_TABLE_DESC_SIZE + SINGLE_FILE_ENTRY_MAX_SIZE;
 
 pub type ThorPatchList = Vec<ThorPatchInfo>;
 
@@ -35,14 +50,55 @@ pub fn patch_list_from_string(content: &str) -> ThorPatchList {
     sorted_patch_list
 }
 
-#[derive(Debug)]
+/// Parses a JSON patch index, either a list of patches or an object with a
+/// `patches` field.
+///
+/// Unlike plist.txt, JSON indexes can describe patches with metadata (hash,
+/// target GRF, channel, ...).
+pub fn patch_list_from_json(content: &str) -> Result<ThorPatchList> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum JsonPatchIndex {
+        List(ThorPatchList),
+        Object { patches: ThorPatchList },
+    }
+    let mut sorted_patch_list = match serde_json::from_str(content) {
+        Ok(JsonPatchIndex::List(patch_list)) => patch_list,
+        Ok(JsonPatchIndex::Object { patches }) => patches,
+        Err(e) => return Err(GrufError::parsing_error(e.to_string())),
+    };
+    // Sort patch list by index
+    sorted_patch_list.sort_by_key(|patch_info| patch_info.index);
+    Ok(sorted_patch_list)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ThorPatchInfo {
     pub index: usize,
     pub file_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>, // Size of the patch file in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub torrent_url: Option<String>, // Torrent file or magnet link that can be used to fetch the patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>, // Hex-encoded SHA-256 hash of the patch file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_grf: Option<String>, // GRF to patch, overrides the archive's target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>, // Release channel the patch belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_place: Option<bool>, // Whether GRFs are patched in-place, overrides the patcher's configuration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requires_index: Option<usize>, // Index of the patch that must be installed before this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_patcher_version: Option<String>, // Oldest patcher version able to apply the patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 impl ThorPatchInfo {
-    /// Parses a line to extract patch index and patch file name.
+    /// Parses a line to extract patch index, patch file name, optional size
+    /// (in bytes) and optional torrent URL.
     /// Returns a PatchInfo struct in case of success.
     /// Returns None in case of failure
     fn from_string(line: &str) -> Option<ThorPatchInfo> {
@@ -55,13 +111,37 @@ impl ThorPatchInfo {
             }
         };
         let file_name = words.get(1)?;
+        // Optional columns: numeric values are sizes, torrent URLs must be
+        // HTTP(S) URLs or magnet links. Anything else is ignored.
+        let mut size = None;
+        let mut torrent_url = None;
+        for word in words.iter().skip(2) {
+            match str::parse::<u64>(word) {
+                Ok(v) if size.is_none() => size = Some(v),
+                _ if is_torrent_url(word) => {
+                    torrent_url = torrent_url.or_else(|| Some((*word).to_string()))
+                }
+                _ => {}
+            }
+        }
         Some(ThorPatchInfo {
             index,
             file_name: (*file_name).to_string(),
+            size,
+            torrent_url,
+            ..Default::default()
         })
     }
 }
 
+/// Indicates whether `word` looks like a torrent file's URL or a magnet link.
+fn is_torrent_url(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    ["http://", "https://", "magnet:"]
+        .iter()
+        .any(|prefix| word.starts_with(prefix))
+}
+
 fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
     let vec_lines: Vec<_> = data.lines().collect();
     vec_lines
@@ -81,27 +161,78 @@ fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
 
 #[derive(Debug)]
 pub struct ThorArchive<R: ?Sized> {
+    name_encoding: NameEncoding,
+    encryption_key: Option<ThorEncryptionKey>,
     obj: Box<R>,
     container: ThorContainer,
 }
 
 impl ThorArchive<File> {
     pub fn open(thor_archive_path: &Path) -> Result<ThorArchive<File>> {
+        ThorArchive::open_with_encoding(thor_archive_path, NameEncoding::Windows1252)
+    }
+
+    pub fn open_with_encoding(
+        thor_archive_path: &Path,
+        name_encoding: NameEncoding,
+    ) -> Result<ThorArchive<File>> {
         let file = File::open(thor_archive_path)?;
-        ThorArchive::new(file)
+        ThorArchive::new_with_encoding(file, name_encoding)
+    }
+}
+
+impl ThorArchive<Cursor<Mmap>> {
+    /// Open the archive located at `thor_archive_path` through a memory
+    /// mapping. The file must not be modified while the archive is open.
+    pub fn open_mmap(thor_archive_path: &Path) -> Result<ThorArchive<Cursor<Mmap>>> {
+        ThorArchive::open_mmap_with_encoding(thor_archive_path, NameEncoding::Windows1252)
+    }
+
+    pub fn open_mmap_with_encoding(
+        thor_archive_path: &Path,
+        name_encoding: NameEncoding,
+    ) -> Result<ThorArchive<Cursor<Mmap>>> {
+        let mapped_file = map_file(thor_archive_path)?;
+        ThorArchive::new_with_encoding(mapped_file, name_encoding)
     }
 }
 
 impl<R: Read + Seek> ThorArchive<R> {
     /// Create a new archive with the underlying object as the reader.
-    pub fn new(mut obj: R) -> Result<ThorArchive<R>> {
-        let thor_patch = parse_thor_patch(&mut obj)?;
+    pub fn new(obj: R) -> Result<ThorArchive<R>> {
+        ThorArchive::new_with_encoding(obj, NameEncoding::Windows1252)
+    }
+
+    /// Create a new archive with the underlying object as the reader. Entries'
+    /// names are decoded with `name_encoding`.
+    pub fn new_with_encoding(mut obj: R, name_encoding: NameEncoding) -> Result<ThorArchive<R>> {
+        let mut thor_patch = parse_thor_patch(&mut obj)?;
+        if name_encoding != NameEncoding::Windows1252 {
+            let mut entries = HashMap::with_capacity(thor_patch.entries.len());
+            for (_, mut entry) in thor_patch.entries {
+                entry.relative_path = transcode_win1252_name(entry.relative_path, name_encoding)?;
+                entries.insert(entry.relative_path.clone(), entry);
+            }
+            thor_patch.entries = entries;
+        }
         Ok(ThorArchive {
+            name_encoding,
+            encryption_key: None,
             obj: Box::new(obj),
             container: thor_patch,
         })
     }
 
+    pub fn name_encoding(&self) -> NameEncoding {
+        self.name_encoding
+    }
+
+    /// Sets the key used to decrypt encrypted entries. Reading such entries
+    /// fails when no key has been set.
+    pub fn set_encryption_key(&mut self, encryption_key: Option<ThorEncryptionKey>) {
+        self.encryption_key = encryption_key;
+    }
+
     pub fn use_grf_merging(&self) -> bool {
         self.container.header.use_grf_merging
     }
@@ -114,6 +245,8 @@ impl<R: Read + Seek> ThorArchive<R> {
         self.container.header.target_grf_name.clone()
     }
 
+    /// Returns the content of an entry as stored in the archive (i.e.
+    /// compressed and possibly encrypted).
     pub fn get_entry_raw_data<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
         let file_entry = self
             .get_file_entry(file_path)
@@ -131,28 +264,71 @@ impl<R: Read + Seek> ThorArchive<R> {
     }
 
     pub fn read_file_content<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
+        self.read_compressed_file_content(file_path)?.decompress()
+    }
+
+    /// Reads (and decrypts) the content of an entry without decompressing it,
+    /// so that it can be decompressed separately (e.g. on another thread).
+    pub fn read_compressed_file_content<S: AsRef<str> + Hash>(
+        &mut self,
+        file_path: S,
+    ) -> Result<ThorEntryContent> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?
+            .clone();
+        let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed);
+        if file_entry.size_compressed > 0 {
+            self.obj.seek(SeekFrom::Start(file_entry.offset))?;
+            let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
+            file_chunk.read_to_end(&mut content)?;
+            if let Some(nonce) = &file_entry.encryption_nonce {
+                let encryption_key = required_encryption_key(&self.encryption_key, &file_entry)?;
+                apply_keystream(encryption_key, nonce, &mut content);
+            }
+        }
+        Ok(ThorEntryContent {
+            compression: file_entry.compression,
+            size: file_entry.size,
+            compressed_data: content,
+        })
+    }
+
+    /// Decompresses the content of an entry into `writer`, chunk by chunk,
+    /// without loading the whole entry in memory.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_file_content_into<S: AsRef<str> + Hash, W: Write + ?Sized>(
+        &mut self,
+        file_path: S,
+        writer: &mut W,
+    ) -> Result<u64> {
         let file_entry = self
             .get_file_entry(file_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
         if file_entry.size_compressed == 0 {
-            return Ok(vec![]);
+            return Ok(0);
         }
 
         self.obj.seek(SeekFrom::Start(file_entry.offset))?;
-        let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed);
-        let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
-        file_chunk.read_to_end(&mut content)?;
-        // Decompress the content with zlib
-        let mut decoder = ZlibDecoder::new(content.as_slice());
-        let mut decompressed_content = Vec::new();
-        let decompressed_size = decoder.read_to_end(&mut decompressed_content)?;
-        if decompressed_size != file_entry.size {
+        let file_chunk = self.obj.by_ref().take(file_entry.size_compressed as u64);
+        let file_chunk: Box<dyn Read> = match &file_entry.encryption_nonce {
+            Some(nonce) => {
+                let encryption_key = required_encryption_key(&self.encryption_key, &file_entry)?;
+                Box::new(DecryptingReader::new(file_chunk, encryption_key, nonce))
+            }
+            None => Box::new(file_chunk),
+        };
+        // Decompress the content
+        let mut decoder = decoder(file_entry.compression, file_chunk)?;
+        let decompressed_size = io::copy(&mut decoder, writer)?;
+        if decompressed_size != file_entry.size as u64 {
             return Err(GrufError::parsing_error(
                 "Decompressed content is not as expected",
             ));
         }
-        Ok(decompressed_content)
+        Ok(decompressed_size)
     }
 
     pub fn extract_file<S: AsRef<str> + Hash>(
@@ -160,9 +336,9 @@ impl<R: Read + Seek> ThorArchive<R> {
         file_path: S,
         destination_path: &Path,
     ) -> Result<()> {
-        let content = self.read_file_content(file_path)?;
-        let mut file = File::create(destination_path)?;
-        Ok(file.write_all(content.as_slice())?)
+        let mut file = BufWriter::new(File::create(destination_path)?);
+        self.write_file_content_into(file_path, &mut file)?;
+        Ok(file.flush()?)
     }
 
     pub fn get_file_entry<S: AsRef<str> + Hash>(&self, file_path: S) -> Option<&ThorFileEntry> {
@@ -175,19 +351,86 @@ impl<R: Read + Seek> ThorArchive<R> {
 
     /// Checks if the container has been unintentionnaly corrupted
     pub fn is_valid(&mut self) -> Result<bool> {
+        Ok(self.find_checksum_mismatch()?.is_none())
+    }
+
+    /// Checks the consistency of the whole archive: header, bounds of the file
+    /// table and of the entries, content of the entries and their checksums
+    /// (when the archive contains a 'data.integrity' file).
+    ///
+    /// Returns an `InvalidContent` error describing the first issue found.
+    pub fn verify(&mut self) -> Result<()> {
+        let archive_size = self.obj.seek(SeekFrom::End(0))?;
+        let header = &self.container.header;
+        // Some tools count directories in the header, it's only an upper bound
+        if self.container.entries.len() > header.file_count {
+            return Err(GrufError::invalid_content(format!(
+                "Header declares {} entries but the file table contains {}",
+                header.file_count,
+                self.container.entries.len()
+            )));
+        }
+        if let ThorTable::MultipleFiles(table) = &self.container.table {
+            if table.file_table_offset + table.file_table_compressed_size as u64 > archive_size {
+                return Err(GrufError::invalid_content("File table is out of bounds"));
+            }
+        }
+        let mut file_entries: Vec<ThorFileEntry> = self
+            .get_entries()
+            .filter(|entry| !entry.is_removed)
+            .cloned()
+            .collect();
+        file_entries.sort_unstable_by_key(|entry| entry.offset);
+        for entry in &file_entries {
+            if entry.offset + entry.size_compressed as u64 > archive_size {
+                return Err(GrufError::invalid_content(format!(
+                    "Entry '{}' is out of bounds",
+                    entry.relative_path
+                )));
+            }
+            if entry.size_compressed == 0 && entry.size != 0 {
+                return Err(GrufError::invalid_content(format!(
+                    "Entry '{}' has no content",
+                    entry.relative_path
+                )));
+            }
+        }
+        for entry in file_entries {
+            self.write_file_content_into(&entry.relative_path, &mut io::sink())
+                .map_err(|e| {
+                    GrufError::invalid_content(format!(
+                        "Entry '{}' is corrupt: {}",
+                        entry.relative_path, e
+                    ))
+                })?;
+        }
+        match self.find_checksum_mismatch() {
+            Ok(None) | Err(GrufError::EntryNotFound) => Ok(()),
+            Ok(Some(file_path)) => Err(GrufError::invalid_content(format!(
+                "Checksum of '{}' doesn't match",
+                file_path
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the path of the first entry whose content doesn't match the
+    /// checksum given in 'data.integrity'.
+    fn find_checksum_mismatch(&mut self) -> Result<Option<String>> {
         let integrity_data = self.read_file_content(INTEGRITY_FILE_NAME)?;
         let integrity_data_as_str = string_from_win_1252(integrity_data.as_slice())?;
         let integrity_info = parse_data_integrity_info(integrity_data_as_str.as_str());
         for (file_path, hash) in integrity_info {
-            let file_content = match self.read_file_content(file_path) {
+            let file_path = transcode_win1252_name(file_path.to_string(), self.name_encoding)?;
+            let file_content = match self.read_file_content(&file_path) {
                 Ok(v) => v,
-                Err(_) => return Ok(false),
+                Err(_) => return Ok(Some(file_path)),
             };
             if crc32::checksum_ieee(file_content.as_slice()) != hash {
-                return Ok(false);
+                return Ok(Some(file_path));
             }
         }
-        Ok(true)
+        Ok(None)
     }
 }
 
@@ -230,12 +473,18 @@ pub struct ThorFileEntry {
     pub relative_path: String,
     pub is_removed: bool,
     pub offset: u64,
+    pub compression: ThorCompression,
+    pub encryption_nonce: Option<[u8; ENCRYPTION_NONCE_SIZE]>, // Set for encrypted entries
 }
 
 impl ThorFileEntry {
     pub fn is_internal(&self) -> bool {
         self.relative_path == INTEGRITY_FILE_NAME
     }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption_nonce.is_some()
+    }
 }
 
 impl Hash for ThorFileEntry {
@@ -254,6 +503,7 @@ fn i16_to_thor_mode(i: i16) -> ThorMode {
     match i {
         33 => ThorMode::SingleFile,
         48 => ThorMode::MultipleFiles,
+        49 => ThorMode::MultipleFilesExtended,
         _ => ThorMode::Invalid,
     }
 }
@@ -264,6 +514,80 @@ fn is_file_removed(flags: u8) -> bool {
     (flags & 0b1) == 1
 }
 
+/// Extracts the compression method from entries' flags
+/// In the extended format, bits 1-2 indicate the compression method
+fn entry_compression(flags: u8, extended: bool) -> Option<ThorCompression> {
+    if !extended {
+        return Some(ThorCompression::Zlib);
+    }
+    match (flags >> 1) & 0b11 {
+        0 => Some(ThorCompression::Zlib),
+        1 => Some(ThorCompression::Zstd),
+        2 => Some(ThorCompression::Lzma),
+        _ => None,
+    }
+}
+
+/// Checks entries' flags
+/// In the extended format, bit 3 indicates that the entry's content is
+/// encrypted
+fn is_entry_encrypted(flags: u8, extended: bool) -> bool {
+    extended && !is_file_removed(flags) && (flags & ENCRYPTED_ENTRY_FLAG) != 0
+}
+
+/// Compressed content of a THOR entry, read from its archive.
+#[derive(Debug, Clone)]
+pub struct ThorEntryContent {
+    compression: ThorCompression,
+    size: usize, // Size of the decompressed content
+    compressed_data: Vec<u8>,
+}
+
+impl ThorEntryContent {
+    pub fn decompress(&self) -> Result<Vec<u8>> {
+        if self.compressed_data.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut decoder = decoder(self.compression, self.compressed_data.as_slice())?;
+        let mut decompressed_content = Vec::with_capacity(self.size);
+        let decompressed_size = decoder.read_to_end(&mut decompressed_content)?;
+        if decompressed_size != self.size {
+            return Err(GrufError::parsing_error(
+                "Decompressed content is not as expected",
+            ));
+        }
+        Ok(decompressed_content)
+    }
+}
+
+/// Returns the key needed to decrypt `entry`
+fn required_encryption_key<'a>(
+    encryption_key: &'a Option<ThorEncryptionKey>,
+    entry: &ThorFileEntry,
+) -> Result<&'a ThorEncryptionKey> {
+    encryption_key.as_ref().ok_or_else(|| {
+        GrufError::invalid_content(format!(
+            "'{}' is encrypted and no encryption key was given",
+            entry.relative_path
+        ))
+    })
+}
+
+/// Wraps `reader` with a decoder for the given compression method
+fn decoder<'a, R: Read + 'a>(
+    compression: ThorCompression,
+    reader: R,
+) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        ThorCompression::Zlib => Box::new(ZlibDecoder::new(reader)),
+        ThorCompression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        ThorCompression::Lzma => {
+            let stream = Stream::new_lzma_decoder(u64::MAX).map_err(io::Error::from)?;
+            Box::new(XzDecoder::new_stream(reader, stream))
+        }
+    })
+}
+
 named!(parse_thor_header<&[u8], ThorHeader>,
     do_parse!(
         tag!(THOR_HEADER_MAGIC)
@@ -292,8 +616,8 @@ named!(parse_single_file_table<&[u8], SingleFileTableDesc>,
 
 named!(parse_multiple_files_table<&[u8], MultipleFilesTableDesc>,
     do_parse!(
-        file_table_compressed_size: le_i32
-        >> file_table_offset: le_i32
+        file_table_compressed_size: le_u32
+        >> file_table_offset: le_u32
         >> (MultipleFilesTableDesc {
             file_table_compressed_size: file_table_compressed_size as usize,
             file_table_offset: file_table_offset as u64, // Offset in the 'data' field
@@ -320,8 +644,8 @@ macro_rules! take_string_ansi (
 
 named!(parse_single_file_entry<&[u8], ThorFileEntry>,
     do_parse!(
-        size_compressed: le_i32
-        >> size: le_i32
+        size_compressed: le_u32
+        >> size: le_u32
         >> relative_path_size: le_u8
         >> relative_path: take_string_ansi!(relative_path_size)
         >> (ThorFileEntry {
@@ -330,6 +654,8 @@ named!(parse_single_file_entry<&[u8], ThorFileEntry>,
             relative_path,
             is_removed: false,
             offset: 0, // This field is set outside the parser
+            compression: ThorCompression::Zlib,
+            encryption_nonce: None,
         }
     )
 ));
@@ -349,34 +675,39 @@ macro_rules! take_if_not_removed (
         );
 );
 
-named!(parse_multiple_files_entry<&[u8], ThorFileEntry>,
+named_args!(parse_multiple_files_entry(extended: bool)<&[u8], ThorFileEntry>,
     do_parse!(
         relative_path_size: le_u8
         >> relative_path: take_string_ansi!(relative_path_size)
         >> flags: le_u8
+        >> compression: map_opt!(value!(flags), |flags| entry_compression(flags, extended))
         >> offset: take_if_not_removed!(le_u32, flags)
-        >> size_compressed: take_if_not_removed!(le_i32, flags)
-        >> size: take_if_not_removed!(le_i32, flags)
+        >> size_compressed: take_if_not_removed!(le_u32, flags)
+        >> size: take_if_not_removed!(le_u32, flags)
+        >> encryption_nonce: cond!(
+            is_entry_encrypted(flags, extended),
+            map_opt!(take!(ENCRYPTION_NONCE_SIZE), |nonce: &[u8]| nonce.try_into().ok())
+        )
         >> (ThorFileEntry {
             size_compressed: size_compressed as usize,
             size: size as usize,
             relative_path,
             is_removed: is_file_removed(flags),
             offset: offset as u64,
+            compression,
+            encryption_nonce,
         }
     )
 ));
 
-named!(parse_multiple_files_entries<&[u8], HashMap<String, ThorFileEntry>>,
-    fold_many1!(parse_multiple_files_entry, HashMap::new(), |mut acc: HashMap<_, _>, item| {
+named_args!(parse_multiple_files_entries(extended: bool)<&[u8], HashMap<String, ThorFileEntry>>,
+    fold_many1!(call!(parse_multiple_files_entry, extended), HashMap::new(), |mut acc: HashMap<_, _>, item: ThorFileEntry| {
         acc.insert(item.relative_path.clone(), item);
         acc
     })
 );
 
 pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer> {
-    const HEADER_EXTENDED_MAX_SIZE: usize =
-        HEADER_MAX_SIZE + MULTIPLE_FILES_TABLE_DESC_SIZE + SINGLE_FILE_ENTRY_MAX_SIZE;
     let mut thor_header_buf = Vec::with_capacity(HEADER_EXTENDED_MAX_SIZE);
     let mut reader_chunk = reader.take(thor_header_buf.capacity() as u64);
     reader_chunk.read_to_end(&mut thor_header_buf)?;
@@ -401,7 +732,8 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
                     .collect(),
             })
         }
-        ThorMode::MultipleFiles => {
+        ThorMode::MultipleFiles | ThorMode::MultipleFilesExtended => {
+            let extended = header.mode == ThorMode::MultipleFilesExtended;
             let (output, table) = parse_multiple_files_table(output)
                 .map_err(|_| GrufError::parsing_error("Failed to parse THOR file table"))?;
             let consumed_bytes = output.as_ptr() as u64 - thor_header_buf.as_ptr() as u64;
@@ -421,10 +753,11 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
             let entries = match decompressed_size {
                 0 => HashMap::new(), // No entries
                 _ => {
-                    let (_, entries) = parse_multiple_files_entries(decompressed_table.as_slice())
-                        .map_err(|_| {
-                            GrufError::parsing_error("Failed to parse THOR file entries")
-                        })?;
+                    let (_, entries) =
+                        parse_multiple_files_entries(decompressed_table.as_slice(), extended)
+                            .map_err(|_| {
+                                GrufError::parsing_error("Failed to parse THOR file entries")
+                            })?;
                     entries
                 }
             };
@@ -437,9 +770,38 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
     }
 }
 
+/// Parses the header of a THOR archive from its first bytes, without needing
+/// the rest of the archive.
+pub fn parse_thor_header_bytes(data: &[u8]) -> Result<ThorHeader> {
+    let (_, header) = parse_thor_header(data)
+        .map_err(|_| GrufError::parsing_error("Failed to parse THOR header"))?;
+    Ok(header)
+}
+
+/// Returns the range of bytes containing the file table of a THOR archive,
+/// from its first `HEADER_EXTENDED_MAX_SIZE` bytes. Returns `None` for
+/// single-file archives, whose only entry is part of the header.
+pub fn parse_thor_file_table_range(data: &[u8]) -> Result<Option<Range<u64>>> {
+    let (output, header) = parse_thor_header(data)
+        .map_err(|_| GrufError::parsing_error("Failed to parse THOR header"))?;
+    match header.mode {
+        ThorMode::Invalid => Err(GrufError::parsing_error("Invalid THOR header mode")),
+        ThorMode::SingleFile => Ok(None),
+        ThorMode::MultipleFiles | ThorMode::MultipleFilesExtended => {
+            let (_, table) = parse_multiple_files_table(output)
+                .map_err(|_| GrufError::parsing_error("Failed to parse THOR file table"))?;
+            Ok(Some(
+                table.file_table_offset
+                    ..table.file_table_offset + table.file_table_compressed_size as u64,
+            ))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Cursor;
     use std::path::PathBuf;
 
     #[test]
@@ -475,6 +837,121 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_patch_list_with_torrent_urls() {
+        let plist_content = "1 patch1.thor
+2 patch2.thor https://myserver.com/torrents/patch2.thor.torrent";
+        let thor_patch_list = patch_list_from_string(plist_content);
+        assert_eq!(thor_patch_list.len(), 2);
+        assert_eq!(thor_patch_list[0].torrent_url, None);
+        assert_eq!(
+            thor_patch_list[1].torrent_url.as_deref(),
+            Some("https://myserver.com/torrents/patch2.thor.torrent")
+        );
+        // Only URLs are accepted, options can't be smuggled in
+        let plist_content = "1 patch1.thor --on-download-complete=cmd.exe
+2 patch2.thor magnet:?xt=urn:btih:0123";
+        let thor_patch_list = patch_list_from_string(plist_content);
+        assert_eq!(thor_patch_list[0].torrent_url, None);
+        assert_eq!(
+            thor_patch_list[1].torrent_url.as_deref(),
+            Some("magnet:?xt=urn:btih:0123")
+        );
+    }
+
+    #[test]
+    fn test_patch_list_with_sizes() {
+        let plist_content = "1 patch1.thor 1024
+2 patch2.thor
+3 patch3.thor 2048 https://myserver.com/torrents/patch3.thor.torrent";
+        let thor_patch_list = patch_list_from_string(plist_content);
+        assert_eq!(thor_patch_list.len(), 3);
+        assert_eq!(thor_patch_list[0].size, Some(1024));
+        assert_eq!(thor_patch_list[0].torrent_url, None);
+        assert_eq!(thor_patch_list[1].size, None);
+        assert_eq!(thor_patch_list[2].size, Some(2048));
+        assert_eq!(
+            thor_patch_list[2].torrent_url.as_deref(),
+            Some("https://myserver.com/torrents/patch3.thor.torrent")
+        );
+    }
+
+    #[test]
+    fn test_patch_list_from_json() {
+        let json_content = r#"{
+            "patches": [
+                {"index": 2, "file_name": "patch2.thor", "size": 2048,
+                 "hash": "ab12", "target_grf": "other.grf", "channel": "beta",
+                 "description": "New maps", "in_place": false, "requires_index": 1,
+                 "min_patcher_version": "0.3.0"},
+                {"index": 1, "file_name": "patch1.thor"}
+            ]
+        }"#;
+        let thor_patch_list = patch_list_from_json(json_content).unwrap();
+        assert_eq!(thor_patch_list.len(), 2);
+        assert_eq!(thor_patch_list[0].index, 1);
+        assert_eq!(thor_patch_list[0].size, None);
+        assert_eq!(thor_patch_list[1].file_name, "patch2.thor");
+        assert_eq!(thor_patch_list[1].size, Some(2048));
+        assert_eq!(thor_patch_list[1].hash.as_deref(), Some("ab12"));
+        assert_eq!(thor_patch_list[1].target_grf.as_deref(), Some("other.grf"));
+        assert_eq!(thor_patch_list[1].channel.as_deref(), Some("beta"));
+        assert_eq!(thor_patch_list[1].description.as_deref(), Some("New maps"));
+        assert_eq!(thor_patch_list[1].in_place, Some(false));
+        assert_eq!(thor_patch_list[0].in_place, None);
+        assert_eq!(thor_patch_list[1].requires_index, Some(1));
+        assert_eq!(
+            thor_patch_list[1].min_patcher_version.as_deref(),
+            Some("0.3.0")
+        );
+
+        // Bare lists are accepted as well
+        let json_content = r#"[{"index": 1, "file_name": "patch1.thor"}]"#;
+        assert_eq!(patch_list_from_json(json_content).unwrap().len(), 1);
+        assert!(patch_list_from_json("1 patch1.thor").is_err());
+    }
+
+    #[test]
+    fn test_parse_thor_header_bytes() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let read_header_bytes = |file_name| {
+            let mut content = std::fs::read(thor_dir_path.join(file_name)).unwrap();
+            content.truncate(HEADER_MAX_SIZE);
+            content
+        };
+        let header = parse_thor_header_bytes(&read_header_bytes("dir1.thor")).unwrap();
+        assert!(!header.use_grf_merging);
+        assert_eq!(header.target_grf_name, "");
+        let header = parse_thor_header_bytes(&read_header_bytes("small.thor")).unwrap();
+        assert!(header.use_grf_merging);
+        assert_eq!(header.target_grf_name, "data.grf");
+        assert!(parse_thor_header_bytes(b"ASSF").is_err());
+    }
+
+    #[test]
+    fn test_parse_thor_file_table_range() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        for file_name in &["dir1.thor", "small.thor", "tiny.thor", "empty.thor"] {
+            let content = std::fs::read(thor_dir_path.join(file_name)).unwrap();
+            let header_size = content.len().min(HEADER_EXTENDED_MAX_SIZE);
+            let table_range = parse_thor_file_table_range(&content[..header_size]).unwrap();
+            // The header and the file table are enough to list the entries
+            let mut partial_content = vec![0; content.len()];
+            partial_content[..header_size].copy_from_slice(&content[..header_size]);
+            if let Some(table_range) = table_range {
+                let table_range = table_range.start as usize..table_range.end as usize;
+                partial_content[table_range.clone()].copy_from_slice(&content[table_range]);
+            }
+            let partial_archive = ThorArchive::new(Cursor::new(partial_content)).unwrap();
+            let archive = ThorArchive::new(Cursor::new(content)).unwrap();
+            assert_eq!(partial_archive.file_count(), archive.file_count());
+            assert!(archive
+                .get_entries()
+                .all(|e| partial_archive.get_file_entry(&e.relative_path) == Some(e)));
+        }
+        assert!(parse_thor_file_table_range(b"ASSF").is_err());
+    }
+
     #[test]
     fn test_open_empty_container() {
         let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
@@ -575,4 +1052,122 @@ mod tests {
             assert!(thor_archive.is_valid().unwrap());
         }
     }
+
+    #[test]
+    fn test_open_with_encoding() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let thor_file_path = thor_dir_path.join("tiny.thor");
+        let entry_path = "data\\texture\\유저인터페이스\\inventory\\icon_num.bmp";
+        for &name_encoding in &[NameEncoding::Cp949, NameEncoding::Auto] {
+            let mut thor_archive =
+                ThorArchive::open_with_encoding(&thor_file_path, name_encoding).unwrap();
+            assert_eq!(thor_archive.name_encoding(), name_encoding);
+            assert_eq!(thor_archive.file_count(), 2);
+            assert_eq!(thor_archive.get_file_entry(entry_path).unwrap().size, 560);
+            assert_eq!(
+                thor_archive.read_file_content(entry_path).unwrap().len(),
+                560
+            );
+            assert!(thor_archive.is_valid().unwrap());
+        }
+        // Names aren't valid UTF-8
+        assert!(ThorArchive::open_with_encoding(&thor_file_path, NameEncoding::Utf8).is_err());
+    }
+
+    #[test]
+    fn test_open_mmap() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        for thor_name in &["small.thor", "tiny.thor"] {
+            let thor_file_path = thor_dir_path.join(thor_name);
+            let mut thor_archive = ThorArchive::open(&thor_file_path).unwrap();
+            let mut mapped_archive = ThorArchive::open_mmap(&thor_file_path).unwrap();
+            assert_eq!(thor_archive.file_count(), mapped_archive.file_count());
+            assert_eq!(
+                thor_archive.use_grf_merging(),
+                mapped_archive.use_grf_merging()
+            );
+            let entries: Vec<ThorFileEntry> = thor_archive.get_entries().cloned().collect();
+            for entry in entries {
+                if entry.is_removed {
+                    continue;
+                }
+                assert_eq!(
+                    thor_archive
+                        .read_file_content(&entry.relative_path)
+                        .unwrap(),
+                    mapped_archive
+                        .read_file_content(&entry.relative_path)
+                        .unwrap()
+                );
+            }
+            assert!(mapped_archive.is_valid().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_write_file_content_into() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut thor_archive = ThorArchive::open(&thor_dir_path.join("small.thor")).unwrap();
+        let entry_paths: Vec<String> = thor_archive
+            .get_entries()
+            .filter(|entry| !entry.is_removed)
+            .map(|entry| entry.relative_path.clone())
+            .collect();
+        for entry_path in entry_paths {
+            let expected_content = thor_archive.read_file_content(&entry_path).unwrap();
+            let mut content = vec![];
+            let written_size = thor_archive
+                .write_file_content_into(&entry_path, &mut content)
+                .unwrap();
+            assert_eq!(written_size, expected_content.len() as u64);
+            assert_eq!(content, expected_content);
+            let destination_path = temp_dir.path().join("extracted");
+            thor_archive
+                .extract_file(&entry_path, &destination_path)
+                .unwrap();
+            assert_eq!(std::fs::read(&destination_path).unwrap(), expected_content);
+        }
+        assert!(matches!(
+            thor_archive.write_file_content_into("missing", &mut vec![]),
+            Err(GrufError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_verify() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        for file_name in &[
+            "empty.thor",
+            "small.thor",
+            "tiny.thor",
+            "dir1.thor",
+            "dir2.thor",
+        ] {
+            let mut thor_archive = ThorArchive::open(&thor_dir_path.join(file_name)).unwrap();
+            thor_archive.verify().unwrap();
+        }
+
+        let content = std::fs::read(thor_dir_path.join("tiny.thor")).unwrap();
+        let entry_offset = {
+            let thor_archive = ThorArchive::new(Cursor::new(content.as_slice())).unwrap();
+            let entry = thor_archive.get_file_entry("data.integrity").unwrap();
+            entry.offset as usize
+        };
+        // Corrupt content
+        let mut corrupt_content = content.clone();
+        corrupt_content[entry_offset + 4] ^= 0xFF;
+        let mut thor_archive = ThorArchive::new(Cursor::new(corrupt_content)).unwrap();
+        assert!(matches!(
+            thor_archive.verify(),
+            Err(GrufError::InvalidContent(_))
+        ));
+        // Truncated archive
+        let mut thor_archive = ThorArchive::new(Cursor::new(content)).unwrap();
+        *thor_archive.obj = Cursor::new(vec![0u8; entry_offset]);
+        assert!(matches!(
+            thor_archive.verify(),
+            Err(GrufError::InvalidContent(_))
+        ));
+    }
 }
@@ -0,0 +1,596 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{anyhow, Context, Result};
+use gruf::grf::reader::GrfFileEncryption;
+use gruf::grf::{
+    merge_grf_archives, repair_grf_archive, GrfArchive, GrfArchiveBuilder, GrfEntryIssueKind,
+    GrfMergeMethod,
+};
+use gruf::thor::{ThorArchive, ThorCompression};
+use gruf::{match_entry_path, NameEncoding};
+use log::LevelFilter;
+use serde::Serialize;
+use simple_logger::SimpleLogger;
+use structopt::StructOpt;
+
+const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+const PKG_AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const PKG_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = PKG_NAME, about = PKG_DESCRIPTION, author = PKG_AUTHORS)]
+struct Opt {
+    #[structopt(short, long, help = "Enable verbose logging")]
+    verbose: bool,
+    #[structopt(
+        long,
+        default_value = "legacy",
+        parse(try_from_str = parse_name_encoding),
+        help = "Encoding of the entries' names (legacy, auto, cp949 or utf8)"
+    )]
+    encoding: NameEncoding,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "Inspect a THOR archive")]
+    Thor(ArchiveCommand),
+    #[structopt(about = "Inspect or create a GRF archive")]
+    Grf(GrfCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum GrfCommand {
+    #[structopt(flatten)]
+    Inspect(ArchiveCommand),
+    #[structopt(about = "Create a GRF archive from the content of a directory")]
+    Create {
+        #[structopt(
+            parse(from_os_str),
+            help = "Directory containing the files to add (e.g. the parent of 'data')"
+        )]
+        source_directory: PathBuf,
+        #[structopt(parse(from_os_str), help = "Path to the new archive")]
+        archive_path: PathBuf,
+        #[structopt(
+            long,
+            default_value = "6",
+            help = "Compression level, from 0 (none) to 9 (best)"
+        )]
+        compression_level: u32,
+        #[structopt(
+            long,
+            default_value = "0x200",
+            parse(try_from_str = parse_grf_version),
+            help = "Version of the archive (0x200, or 0x300 for archives larger than 4 GiB)"
+        )]
+        format: u32,
+    },
+    #[structopt(about = "Merge the files of a GRF archive into another one")]
+    Merge {
+        #[structopt(parse(from_os_str), help = "Archive to merge (e.g. rdata.grf)")]
+        source_path: PathBuf,
+        #[structopt(parse(from_os_str), help = "Archive to merge into (e.g. data.grf)")]
+        target_path: PathBuf,
+        #[structopt(
+            long,
+            help = "Modify the target archive directly instead of rebuilding it"
+        )]
+        in_place: bool,
+    },
+    #[structopt(about = "Check an archive and remove its broken entries")]
+    Repair {
+        #[structopt(parse(from_os_str), help = "Path to the archive")]
+        archive_path: PathBuf,
+        #[structopt(long, help = "Print the verification report as JSON")]
+        json: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ArchiveCommand {
+    #[structopt(about = "List the entries of an archive")]
+    List {
+        #[structopt(parse(from_os_str), help = "Path to the archive")]
+        archive_path: PathBuf,
+        #[structopt(
+            default_value = "**",
+            help = "Only list entries matching this glob pattern (e.g. 'data/texture/**.bmp')"
+        )]
+        pattern: String,
+        #[structopt(
+            long,
+            default_value = "text",
+            parse(try_from_str = parse_list_format),
+            help = "Output format (text, json or csv)"
+        )]
+        format: ListFormat,
+    },
+    #[structopt(about = "Extract the files contained in an archive")]
+    Extract {
+        #[structopt(parse(from_os_str), help = "Path to the archive")]
+        archive_path: PathBuf,
+        #[structopt(parse(from_os_str), help = "Directory to extract the files into")]
+        destination_directory: PathBuf,
+    },
+    #[structopt(about = "Check the consistency of an archive")]
+    Verify {
+        #[structopt(parse(from_os_str), help = "Path to the archive")]
+        archive_path: PathBuf,
+        #[structopt(long, help = "Print the result as JSON")]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Serialize)]
+struct ThorEntryRecord<'a> {
+    path: &'a str,
+    size: usize,
+    size_compressed: usize,
+    offset: u64,
+    removed: bool,
+    compression: &'static str,
+    encrypted: bool,
+}
+
+#[derive(Serialize)]
+struct GrfEntryRecord<'a> {
+    path: &'a str,
+    size: usize,
+    size_compressed: usize,
+    size_compressed_aligned: usize,
+    flags: u8,
+    offset: u64,
+    encrypted: bool,
+}
+
+fn run(cli_args: Opt) -> Result<()> {
+    let encoding = cli_args.encoding;
+    match cli_args.command {
+        Command::Thor(ArchiveCommand::List {
+            archive_path,
+            pattern,
+            format,
+        }) => list_thor_archive(&archive_path, &pattern, format, encoding),
+        Command::Thor(ArchiveCommand::Extract {
+            archive_path,
+            destination_directory,
+        }) => extract_thor_archive(&archive_path, &destination_directory, encoding),
+        Command::Thor(ArchiveCommand::Verify { archive_path, json }) => {
+            verify_thor_archive(&archive_path, json, encoding)
+        }
+        Command::Grf(GrfCommand::Inspect(ArchiveCommand::List {
+            archive_path,
+            pattern,
+            format,
+        })) => list_grf_archive(&archive_path, &pattern, format, encoding),
+        Command::Grf(GrfCommand::Inspect(ArchiveCommand::Extract {
+            archive_path,
+            destination_directory,
+        })) => extract_grf_archive(&archive_path, &destination_directory, encoding),
+        Command::Grf(GrfCommand::Inspect(ArchiveCommand::Verify { archive_path, json })) => {
+            verify_grf_archive(&archive_path, false, json, encoding)
+        }
+        Command::Grf(GrfCommand::Create {
+            source_directory,
+            archive_path,
+            compression_level,
+            format,
+        }) => create_grf_archive(
+            &source_directory,
+            &archive_path,
+            compression_level,
+            format,
+            encoding,
+        ),
+        Command::Grf(GrfCommand::Merge {
+            source_path,
+            target_path,
+            in_place,
+        }) => merge_grf_archive(&source_path, &target_path, in_place, encoding),
+        Command::Grf(GrfCommand::Repair { archive_path, json }) => {
+            verify_grf_archive(&archive_path, true, json, encoding)
+        }
+    }
+}
+
+fn parse_grf_version(value: &str) -> Result<u32> {
+    match value {
+        "0x200" => Ok(0x200),
+        "0x300" => Ok(0x300),
+        _ => Err(anyhow!("Unsupported GRF version '{}'", value)),
+    }
+}
+
+fn parse_list_format(value: &str) -> Result<ListFormat> {
+    match value {
+        "text" => Ok(ListFormat::Text),
+        "json" => Ok(ListFormat::Json),
+        "csv" => Ok(ListFormat::Csv),
+        _ => Err(anyhow!("Unknown format '{}'", value)),
+    }
+}
+
+fn parse_name_encoding(value: &str) -> Result<NameEncoding> {
+    match value {
+        "legacy" => Ok(NameEncoding::Windows1252),
+        "auto" => Ok(NameEncoding::Auto),
+        "cp949" => Ok(NameEncoding::Cp949),
+        "utf8" => Ok(NameEncoding::Utf8),
+        _ => Err(anyhow!("Unknown encoding '{}'", value)),
+    }
+}
+
+fn list_thor_archive(
+    archive_path: &Path,
+    pattern: &str,
+    format: ListFormat,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let thor_archive = ThorArchive::open_with_encoding(archive_path, encoding)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    let mut entries: Vec<_> = thor_archive
+        .get_entries()
+        .filter(|entry| match_entry_path(pattern, &entry.relative_path))
+        .collect();
+    entries.sort_by_key(|entry| &entry.relative_path);
+    if format != ListFormat::Text {
+        let records: Vec<ThorEntryRecord> = entries
+            .iter()
+            .map(|entry| ThorEntryRecord {
+                path: &entry.relative_path,
+                size: entry.size,
+                size_compressed: entry.size_compressed,
+                offset: entry.offset,
+                removed: entry.is_removed,
+                compression: match entry.compression {
+                    ThorCompression::Zlib => "zlib",
+                    ThorCompression::Zstd => "zstd",
+                    ThorCompression::Lzma => "lzma",
+                },
+                encrypted: entry.encryption_nonce.is_some(),
+            })
+            .collect();
+        let columns = [
+            "path",
+            "size",
+            "size_compressed",
+            "offset",
+            "removed",
+            "compression",
+            "encrypted",
+        ];
+        return print_records(&records, &columns, format);
+    }
+
+    println!("GRF merging: {}", thor_archive.use_grf_merging());
+    if thor_archive.use_grf_merging() {
+        println!("Target GRF: '{}'", thor_archive.target_grf_name());
+    }
+    println!("Entries: {}", thor_archive.file_count());
+    println!();
+    println!("{:<8} {:>12} {:>12}  Path", "Flags", "Size", "Compressed");
+    for entry in entries {
+        let flags = if entry.is_removed {
+            "removed"
+        } else if entry.is_internal() {
+            "internal"
+        } else {
+            "-"
+        };
+        println!(
+            "{:<8} {:>12} {:>12}  {}",
+            flags, entry.size, entry.size_compressed, entry.relative_path
+        );
+    }
+    Ok(())
+}
+
+fn extract_thor_archive(
+    archive_path: &Path,
+    destination_directory: &Path,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let mut thor_archive = ThorArchive::open_with_encoding(archive_path, encoding)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    let entry_paths: Vec<String> = thor_archive
+        .get_entries()
+        .filter(|entry| !entry.is_removed)
+        .map(|entry| entry.relative_path.clone())
+        .collect();
+    for entry_path in entry_paths {
+        let content = thor_archive
+            .read_file_content(&entry_path)
+            .with_context(|| format!("Failed to read '{}'", entry_path))?;
+        write_extracted_file(destination_directory, &entry_path, &content)?;
+    }
+    Ok(())
+}
+
+fn verify_thor_archive(archive_path: &Path, json: bool, encoding: NameEncoding) -> Result<()> {
+    let mut thor_archive = ThorArchive::open_with_encoding(archive_path, encoding)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    let result = thor_archive.verify();
+    if json {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let report = serde_json::json!({ "valid": error.is_none(), "error": error });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    result.with_context(|| format!("'{}' is corrupt", archive_path.display()))?;
+    log::info!("'{}' is valid", archive_path.display());
+    Ok(())
+}
+
+fn list_grf_archive(
+    archive_path: &Path,
+    pattern: &str,
+    format: ListFormat,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let grf_archive = GrfArchive::open_with_encoding(archive_path, encoding)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    let entries = grf_archive.list(pattern);
+    if format != ListFormat::Text {
+        let records: Vec<GrfEntryRecord> = entries
+            .iter()
+            .map(|entry| GrfEntryRecord {
+                path: &entry.relative_path,
+                size: entry.size,
+                size_compressed: entry.size_compressed,
+                size_compressed_aligned: entry.size_compressed_aligned,
+                flags: entry.entry_type,
+                offset: entry.offset,
+                encrypted: entry.encryption != GrfFileEncryption::Unencrypted,
+            })
+            .collect();
+        let columns = [
+            "path",
+            "size",
+            "size_compressed",
+            "size_compressed_aligned",
+            "flags",
+            "offset",
+            "encrypted",
+        ];
+        return print_records(&records, &columns, format);
+    }
+
+    println!(
+        "Version: 0x{:x}{:02x}",
+        grf_archive.version_major(),
+        grf_archive.version_minor()
+    );
+    println!("Entries: {}", grf_archive.file_count());
+    println!();
+    println!("{:<8} {:>12} {:>12}  Path", "Flags", "Size", "Compressed");
+    for entry in entries {
+        println!(
+            "0x{:02x}     {:>12} {:>12}  {}",
+            entry.entry_type, entry.size, entry.size_compressed, entry.relative_path
+        );
+    }
+    Ok(())
+}
+
+/// Prints entry records as a JSON array or as CSV rows, whose columns are the
+/// given fields of the records.
+fn print_records<T: Serialize>(records: &[T], columns: &[&str], format: ListFormat) -> Result<()> {
+    if format == ListFormat::Json {
+        println!("{}", serde_json::to_string_pretty(records)?);
+        return Ok(());
+    }
+    println!("{}", columns.join(","));
+    for record in records {
+        let record = serde_json::to_value(record)?;
+        let row: Vec<String> = columns
+            .iter()
+            .map(|column| match &record[column] {
+                serde_json::Value::String(s) => csv_field(s),
+                value => value.to_string(),
+            })
+            .collect();
+        println!("{}", row.join(","));
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn extract_grf_archive(
+    archive_path: &Path,
+    destination_directory: &Path,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let mut grf_archive = GrfArchive::open_with_encoding(archive_path, encoding)
+        .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+    let entry_paths: Vec<String> = grf_archive
+        .get_entries()
+        .map(|entry| entry.relative_path.clone())
+        .collect();
+    for entry_path in entry_paths {
+        let content = grf_archive
+            .read_file_content(&entry_path)
+            .with_context(|| format!("Failed to read '{}'", entry_path))?;
+        write_extracted_file(destination_directory, &entry_path, &content)?;
+    }
+    Ok(())
+}
+
+fn verify_grf_archive(
+    archive_path: &Path,
+    repair: bool,
+    json: bool,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let report = if repair {
+        repair_grf_archive(archive_path, encoding)
+            .with_context(|| format!("Failed to repair '{}'", archive_path.display()))?
+    } else {
+        let mut grf_archive = GrfArchive::open_with_encoding(archive_path, encoding)
+            .with_context(|| format!("Failed to open '{}'", archive_path.display()))?;
+        grf_archive
+            .verify()
+            .with_context(|| format!("Failed to verify '{}'", archive_path.display()))?
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    for table_issue in &report.table_issues {
+        log::warn!("{}", table_issue);
+    }
+    for entry_issue in &report.broken_entries {
+        let issue = match &entry_issue.kind {
+            GrfEntryIssueKind::OutOfBounds => "is out of bounds".to_string(),
+            GrfEntryIssueKind::Overlapping {
+                other_relative_path,
+            } => format!("overlaps with '{}'", other_relative_path),
+            GrfEntryIssueKind::CorruptContent { reason } => format!("is corrupt: {}", reason),
+        };
+        log::warn!("Entry '{}' {}", entry_issue.relative_path, issue);
+    }
+    if report.is_valid() {
+        log::info!("'{}' is valid", archive_path.display());
+        Ok(())
+    } else if repair {
+        log::info!(
+            "'{}' repaired, {} broken entries removed",
+            archive_path.display(),
+            report.broken_entries.len()
+        );
+        Ok(())
+    } else {
+        Err(anyhow!("'{}' is corrupt", archive_path.display()))
+    }
+}
+
+fn create_grf_archive(
+    source_directory: &Path,
+    archive_path: &Path,
+    compression_level: u32,
+    version: u32,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let archive_file = fs::File::create(archive_path)
+        .with_context(|| format!("Failed to create '{}'", archive_path.display()))?;
+    let mut builder = GrfArchiveBuilder::create_with_encoding(
+        archive_file,
+        version >> 8,
+        version & 0xFF,
+        encoding,
+    )?;
+    builder.set_compression_level(compression_level);
+    builder
+        .add_directory(source_directory, source_directory)
+        .with_context(|| format!("Failed to add '{}'", source_directory.display()))?;
+    builder.finish()?;
+    log::info!("'{}' created", archive_path.display());
+    Ok(())
+}
+
+fn merge_grf_archive(
+    source_path: &Path,
+    target_path: &Path,
+    in_place: bool,
+    encoding: NameEncoding,
+) -> Result<()> {
+    let method = if in_place {
+        GrfMergeMethod::InPlace
+    } else {
+        GrfMergeMethod::OutOfPlace
+    };
+    let merged_count = merge_grf_archives(target_path, source_path, method, encoding)
+        .with_context(|| {
+            format!(
+                "Failed to merge '{}' into '{}'",
+                source_path.display(),
+                target_path.display()
+            )
+        })?;
+    log::info!(
+        "Merged {} files into '{}'",
+        merged_count,
+        target_path.display()
+    );
+    Ok(())
+}
+
+fn write_extracted_file(
+    destination_directory: &Path,
+    entry_path: &str,
+    content: &[u8],
+) -> Result<()> {
+    // Never write outside of the destination directory
+    let is_unsafe_path = entry_path.starts_with(&['\\', '/'][..])
+        || entry_path
+            .split(&['\\', '/'][..])
+            .any(|component| component == ".." || component.contains(':'));
+    if is_unsafe_path {
+        log::warn!("Skipping entry with an unsafe path '{}'", entry_path);
+        return Ok(());
+    }
+    let destination_path = destination_directory.join(posix_path(entry_path));
+    log::trace!("Extracting '{}'", destination_path.to_string_lossy());
+    if let Some(parent_directory) = destination_path.parent() {
+        fs::create_dir_all(parent_directory)?;
+    }
+    fs::write(&destination_path, content)
+        .with_context(|| format!("Failed to write '{}'", destination_path.display()))
+}
+
+fn main() {
+    const SUCCESS_EXIT_CODE: i32 = 0;
+    const FAILURE_EXIT_CODE: i32 = 1;
+
+    // Parse CLI arguments
+    let cli_args = Opt::from_args();
+    // Initialize the logger
+    init_logger(cli_args.verbose).expect("Failed to initalize the logger");
+
+    // Run the actual program
+    let result = run(cli_args);
+    match result {
+        Ok(()) => {
+            process::exit(SUCCESS_EXIT_CODE);
+        }
+        Err(err) => {
+            log::error!("{:#}", err);
+            process::exit(FAILURE_EXIT_CODE);
+        }
+    }
+}
+
+fn init_logger(verbose: bool) -> Result<()> {
+    let level_filter = if verbose {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Info
+    };
+
+    SimpleLogger::new()
+        .with_level(LevelFilter::Off)
+        .with_module_level(PKG_NAME, level_filter)
+        .init()?;
+    Ok(())
+}
+
+// Entries' paths use Windows separators
+fn posix_path<S: AsRef<str>>(path: S) -> String {
+    path.as_ref().replace("\\", "/")
+}
@@ -1,16 +1,18 @@
 mod patch_definition;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::{env, process};
 
 use anyhow::{anyhow, Context, Result};
-use gruf::thor::ThorArchiveBuilder;
+use gruf::thor::{
+    self, patch_list_to_json, patch_list_to_string, update_patch_list, DirectoryChange,
+    PatchListOptions, ThorArchive, ThorArchiveBuilder, ThorCompression, ThorEncryptionKey,
+};
 use log::LevelFilter;
 use patch_definition::{parse_patch_definition, PatchDefinition};
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
-use walkdir::WalkDir;
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -21,8 +23,67 @@ const PKG_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 struct Opt {
     #[structopt(short, long, help = "Enable verbose logging")]
     verbose: bool,
-    #[structopt(parse(from_os_str), help = "Path to a patch definition file")]
-    patch_definition_file: PathBuf,
+    #[structopt(
+        parse(from_os_str),
+        required_unless_one = &["merge", "diff", "plist"],
+        help = "Path to a patch definition file"
+    )]
+    patch_definition_file: Option<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        short,
+        long,
+        conflicts_with = "patch-definition-file",
+        help = "Merge the given THOR archives, in order, into a single patch"
+    )]
+    merge: Vec<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        long,
+        number_of_values = 2,
+        value_names = &["old-directory", "new-directory"],
+        conflicts_with_all = &["patch-definition-file", "merge"],
+        help = "Generate a patch containing the differences between two directories"
+    )]
+    diff: Vec<PathBuf>,
+    #[structopt(long, help = "Merge the generated diff into a GRF")]
+    grf_merging: bool,
+    #[structopt(
+        long,
+        help = "GRF targeted by the generated diff (default: the patcher's default GRF)"
+    )]
+    target_grf: Option<String>,
+    #[structopt(long, help = "Include checksums in the generated diff")]
+    include_checksums: bool,
+    #[structopt(
+        long,
+        default_value = "zlib",
+        parse(try_from_str = parse_compression),
+        help = "Compression method of the generated patch (zlib, zstd or lzma). Patches not using zlib can only be applied by this patcher"
+    )]
+    compression: ThorCompression,
+    #[structopt(
+        long,
+        conflicts_with = "encryption-secret",
+        help = "Base64-encoded 32-byte key used to encrypt the generated patch (and to decrypt merged patches). Encrypted patches can only be applied by this patcher"
+    )]
+    encryption_key: Option<String>,
+    #[structopt(
+        long,
+        help = "Secret from which the key used to encrypt the generated patch is derived"
+    )]
+    encryption_secret: Option<String>,
+    #[structopt(
+        parse(from_os_str),
+        long,
+        conflicts_with_all = &["patch-definition-file", "merge", "diff"],
+        help = "Generate or update the patch list of a directory containing patches"
+    )]
+    plist: Option<PathBuf>,
+    #[structopt(long, help = "Include the size of the patches in the patch list")]
+    sizes: bool,
+    #[structopt(long, help = "Include the hash of the patches in the patch list")]
+    hashes: bool,
     #[structopt(
         parse(from_os_str),
         short,
@@ -34,34 +95,82 @@ struct Opt {
         parse(from_os_str),
         short,
         long,
-        help = "Path to the output archive (default: <patch_definition_file_name>.thor)"
+        help = "Path to the output archive (default: <patch_definition_file_name>.thor, merged.thor or diff.thor) or patch list (default: <plist>/plist.txt)"
     )]
     output_file: Option<PathBuf>,
 }
 
 fn run(cli_args: Opt) -> Result<()> {
+    let encryption_key =
+        parse_encryption_key(&cli_args.encryption_key, &cli_args.encryption_secret)?;
+    let patch_definition_file = match cli_args.patch_definition_file {
+        Some(patch_definition_file) => patch_definition_file,
+        None if cli_args.plist.is_some() => {
+            let patch_directory = cli_args.plist.unwrap_or_default();
+            let output_file_path = cli_args
+                .output_file
+                .unwrap_or_else(|| patch_directory.join("plist.txt"));
+            let options = PatchListOptions {
+                include_sizes: cli_args.sizes,
+                include_hashes: cli_args.hashes,
+                ..Default::default()
+            };
+            return generate_patch_list(&patch_directory, &output_file_path, options);
+        }
+        None if !cli_args.diff.is_empty() => {
+            let output_file_path = cli_args
+                .output_file
+                .unwrap_or_else(|| PathBuf::from("diff.thor"));
+            // Specifying a target GRF implies GRF merging
+            let use_grf_merging = cli_args.grf_merging || cli_args.target_grf.is_some();
+            let output_file = File::create(&output_file_path)?;
+            let mut archive_builder = ThorArchiveBuilder::new_with_compression(
+                output_file,
+                use_grf_merging,
+                cli_args.target_grf,
+                cli_args.include_checksums,
+                cli_args.compression,
+            )?;
+            archive_builder.set_encryption_key(encryption_key);
+            return generate_patch_from_diff(
+                &mut archive_builder,
+                &cli_args.diff[0],
+                &cli_args.diff[1],
+                &output_file_path,
+            );
+        }
+        None => {
+            let output_file_path = cli_args
+                .output_file
+                .unwrap_or_else(|| PathBuf::from("merged.thor"));
+            return merge_patches(
+                &cli_args.merge,
+                &output_file_path,
+                cli_args.compression,
+                encryption_key,
+            );
+        }
+    };
     let patch_data_directory = cli_args
         .patch_data_directory
         .unwrap_or_else(|| PathBuf::from("."));
     let output_file_path = cli_args.output_file.unwrap_or(PathBuf::from(
-        cli_args
-            .patch_definition_file
+        patch_definition_file
             .with_extension("thor")
             .file_name()
             .ok_or_else(|| anyhow!("Invalid patch definition file name"))?,
     ));
 
     // Parse the YAML definition file
-    log::info!(
-        "Processing '{}'",
-        cli_args.patch_definition_file.to_string_lossy()
-    );
-    let patch_definition = parse_patch_definition(&cli_args.patch_definition_file)
+    log::info!("Processing '{}'", patch_definition_file.to_string_lossy());
+    let patch_definition = parse_patch_definition(&patch_definition_file)
         .context("Failed to parse the patch definition")?;
 
     // Display patch info
     log::info!("GRF merging: {}", patch_definition.use_grf_merging);
     log::info!("Checksums included: {}", patch_definition.include_checksums);
+    log::info!("Compression: {:?}", cli_args.compression);
+    log::info!("Encrypted: {}", encryption_key.is_some());
     if let Some(target_grf_name) = &patch_definition.target_grf_name {
         log::info!("Target GRF: '{}'", target_grf_name);
     } else {
@@ -69,8 +178,14 @@ fn run(cli_args: Opt) -> Result<()> {
     }
 
     // Generate THOR archive
-    generate_patch_from_definition(patch_definition, patch_data_directory, &output_file_path)
-        .context("Failed to generate patch from definition")?;
+    generate_patch_from_definition(
+        patch_definition,
+        patch_data_directory,
+        &output_file_path,
+        cli_args.compression,
+        encryption_key,
+    )
+    .context("Failed to generate patch from definition")?;
     log::info!(
         "Patch generated at '{}'",
         output_file_path.to_string_lossy()
@@ -82,18 +197,22 @@ fn generate_patch_from_definition<P1, P2>(
     patch_definition: PatchDefinition,
     patch_data_directory: P1,
     output_path: P2,
+    compression: ThorCompression,
+    encryption_key: Option<ThorEncryptionKey>,
 ) -> Result<()>
 where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
 {
     let output_file = File::create(output_path)?;
-    let mut archive_builder = ThorArchiveBuilder::new(
+    let mut archive_builder = ThorArchiveBuilder::new_with_compression(
         output_file,
         patch_definition.use_grf_merging,
         patch_definition.target_grf_name,
         patch_definition.include_checksums,
+        compression,
     )?;
+    archive_builder.set_encryption_key(encryption_key);
     for entry in patch_definition.entries {
         let win32_relative_path = win32_path(&entry.relative_path);
         let target_win32_relative_path = entry.in_grf_path.unwrap_or(win32_relative_path.clone());
@@ -114,11 +233,8 @@ where
             archive_builder.append_file_update(target_win32_relative_path, file)?;
         } else if native_path.is_dir() {
             // Path points to a directory
-            append_directory_update(
-                &mut archive_builder,
-                patch_data_directory.as_ref(),
-                native_path,
-            )?;
+            log::trace!("'{}' will be UPDATED", &target_win32_relative_path);
+            archive_builder.append_directory(patch_data_directory.as_ref(), native_path)?;
         } else {
             return Err(anyhow!(
                 "Path '{}' is invalid or does not exist",
@@ -129,32 +245,167 @@ where
     Ok(())
 }
 
-fn append_directory_update<P1, P2>(
+/// Generates a patch list from the patches located in `patch_directory`. If
+/// the patch list already exists, it's validated and completed with the new
+/// patches.
+///
+/// The patch list is written as a JSON index if its extension is `.json`.
+/// Disabled patches of plist.txt files (commented out with `//`) are kept
+/// as-is.
+fn generate_patch_list(
+    patch_directory: &Path,
+    output_path: &Path,
+    mut options: PatchListOptions,
+) -> Result<()> {
+    let is_json = match output_path.extension() {
+        Some(extension) => extension.eq_ignore_ascii_case("json"),
+        None => false,
+    };
+    let mut disabled_lines = vec![];
+    let patch_list = if output_path.exists() {
+        log::info!("Updating '{}'", output_path.to_string_lossy());
+        let content = fs::read_to_string(output_path)?;
+        if is_json {
+            thor::patch_list_from_json(&content).context("Invalid JSON patch index")?
+        } else {
+            for line in content.lines().map(str::trim) {
+                if let Some(disabled_line) = line.strip_prefix("//") {
+                    if let Some(file_name) = disabled_line.split_whitespace().nth(1) {
+                        options.ignored_files.insert(file_name.to_string());
+                    }
+                    disabled_lines.push(line.to_string());
+                }
+            }
+            thor::patch_list_from_string(&content)
+        }
+    } else {
+        vec![]
+    };
+
+    let patch_list = update_patch_list(&patch_list, patch_directory, &options)
+        .context("Failed to update the patch list")?;
+    let content = if is_json {
+        patch_list_to_json(&patch_list)?
+    } else {
+        disabled_lines
+            .iter()
+            .map(|line| format!("{}\r\n", line))
+            .collect::<String>()
+            + patch_list_to_string(&patch_list).as_str()
+    };
+    fs::write(output_path, content)?;
+    log::info!("{} patch(es) listed", patch_list.len());
+    log::info!(
+        "Patch list generated at '{}'",
+        output_path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Generates a patch that turns the content of `old_directory` into the
+/// content of `new_directory`.
+fn generate_patch_from_diff(
     archive_builder: &mut ThorArchiveBuilder<File>,
-    patch_data_directory: P1,
-    directory_path: P2,
-) -> Result<()>
-where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
-{
-    let walker = WalkDir::new(directory_path).follow_links(false).into_iter();
-    for entry in walker {
-        let entry = entry?;
-        if entry.file_type().is_file() {
-            let rel_path = entry.path().strip_prefix(patch_data_directory.as_ref())?;
-            let rel_path_str = rel_path
-                .to_str()
-                .ok_or_else(|| anyhow!("Invalid file path encountered"))?;
-            let win32_relative_path = win32_path(rel_path_str);
-            log::trace!("'{}' will be UPDATED", &win32_relative_path);
-            let file = File::open(entry.path())?;
-            archive_builder.append_file_update(win32_relative_path, file)?;
+    old_directory: &Path,
+    new_directory: &Path,
+    output_path: &Path,
+) -> Result<()> {
+    log::info!(
+        "Comparing '{}' with '{}'",
+        old_directory.to_string_lossy(),
+        new_directory.to_string_lossy()
+    );
+    let changes = archive_builder
+        .append_directory_diff(old_directory, new_directory)
+        .context("Failed to generate patch from diff")?;
+    for change in &changes {
+        match change {
+            DirectoryChange::Added(entry_path) => log::trace!("'{}' will be ADDED", entry_path),
+            DirectoryChange::Modified(entry_path) => {
+                log::trace!("'{}' will be UPDATED", entry_path)
+            }
+            DirectoryChange::Removed(entry_path) => {
+                log::trace!("'{}' will be REMOVED", entry_path)
+            }
         }
     }
+    archive_builder.finish()?;
+    log::info!("{} change(s) found", changes.len());
+    log::info!("Patch generated at '{}'", output_path.to_string_lossy());
+    Ok(())
+}
+
+/// Merges THOR patches into a single one, equivalent to applying them in
+/// order. The output targets the same destination as the patches and includes
+/// checksums if any of them does.
+fn merge_patches(
+    patch_paths: &[PathBuf],
+    output_path: &Path,
+    compression: ThorCompression,
+    encryption_key: Option<ThorEncryptionKey>,
+) -> Result<()> {
+    let mut archives = patch_paths
+        .iter()
+        .map(|patch_path| {
+            log::info!("Processing '{}'", patch_path.to_string_lossy());
+            let mut archive = ThorArchive::open(patch_path)
+                .with_context(|| format!("Failed to open '{}'", patch_path.display()))?;
+            archive.set_encryption_key(encryption_key.clone());
+            Ok(archive)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let first_archive = archives
+        .first()
+        .ok_or_else(|| anyhow!("No patch to merge"))?;
+    let use_grf_merging = first_archive.use_grf_merging();
+    let target_grf_name = first_archive.target_grf_name();
+    let include_checksums = archives
+        .iter()
+        .any(|archive| archive.get_entries().any(|entry| entry.is_internal()));
+
+    let output_file = File::create(output_path)?;
+    let mut archive_builder = ThorArchiveBuilder::new_with_compression(
+        output_file,
+        use_grf_merging,
+        Some(target_grf_name),
+        include_checksums,
+        compression,
+    )?;
+    archive_builder.set_encryption_key(encryption_key);
+    archive_builder
+        .append_archives(&mut archives)
+        .context("Failed to merge patches")?;
+    archive_builder.finish()?;
+    log::info!("Patch generated at '{}'", output_path.to_string_lossy());
     Ok(())
 }
 
+fn parse_compression(value: &str) -> Result<ThorCompression> {
+    match value {
+        "zlib" => Ok(ThorCompression::Zlib),
+        "zstd" => Ok(ThorCompression::Zstd),
+        "lzma" => Ok(ThorCompression::Lzma),
+        _ => Err(anyhow!("Unknown compression method '{}'", value)),
+    }
+}
+
+/// Returns the key used to encrypt patches, given as a base64-encoded key or
+/// derived from a secret.
+fn parse_encryption_key(
+    encoded_key: &Option<String>,
+    secret: &Option<String>,
+) -> Result<Option<ThorEncryptionKey>> {
+    if let Some(encoded_key) = encoded_key {
+        let key = base64::decode(encoded_key.trim()).context("Invalid encryption key")?;
+        return Ok(Some(
+            ThorEncryptionKey::from_slice(&key).context("Invalid encryption key")?,
+        ));
+    }
+    Ok(secret
+        .as_ref()
+        .map(|secret| ThorEncryptionKey::from_secret(secret.as_bytes())))
+}
+
 fn main() {
     const SUCCESS_EXIT_CODE: i32 = 0;
     const FAILURE_EXIT_CODE: i32 = 1;
@@ -1,9 +1,48 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
 #[cfg(windows)]
 fn main() {
+    embed_git_hash();
+    println!("cargo:rerun-if-changed=resources/rpatchur.ico");
     let mut res = winres::WindowsResource::new();
     res.set_icon("resources/rpatchur.ico");
     res.compile().unwrap();
 }
 
 #[cfg(unix)]
-fn main() {}
+fn main() {
+    embed_git_hash();
+}
+
+/// Exposes the hash of the commit being built as `RPATCHUR_GIT_HASH`, when
+/// building from a git repository.
+fn embed_git_hash() {
+    // Rebuild when HEAD moves to another commit
+    let git_directory = Path::new("../.git");
+    let head_path = git_directory.join("HEAD");
+    if let Ok(head) = fs::read_to_string(&head_path) {
+        println!("cargo:rerun-if-changed={}", head_path.display());
+        if let Some(head_ref) = head.trim().strip_prefix("ref: ") {
+            let ref_path = git_directory.join(head_ref);
+            if ref_path.exists() {
+                println!("cargo:rerun-if-changed={}", ref_path.display());
+            }
+            // Refs may also be packed
+            let packed_refs_path = git_directory.join("packed-refs");
+            if packed_refs_path.exists() {
+                println!("cargo:rerun-if-changed={}", packed_refs_path.display());
+            }
+        }
+    }
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    if let Some(output) = output {
+        let git_hash = String::from_utf8_lossy(&output.stdout);
+        println!("cargo:rustc-env=RPATCHUR_GIT_HASH={}", git_hash.trim());
+    }
+}
@@ -0,0 +1,363 @@
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::patcher::tr;
+use crate::ui::{PatchOutcome, PatchingStatus, UiFrontend};
+
+/// Exit code of the patcher when the requested operation succeeded
+pub const EXIT_SUCCESS: i32 = 0;
+/// Exit code of the patcher when the requested operation failed
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code of the patcher when an update completed but skipped some patches
+pub const EXIT_PATCHES_SKIPPED: i32 = 2;
+
+// Width of the progress bars, in characters
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// Reports the progress of the patcher on the standard output, to run it
+/// without a UI (e.g. in containers or scripts).
+#[derive(Clone)]
+pub struct ConsoleUi {
+    state: Arc<Mutex<ConsoleState>>,
+    interactive: bool, // Whether prompts can be answered on the standard input
+}
+
+#[derive(Default)]
+struct ConsoleState {
+    failed: bool,
+    skipped_patches: bool,
+    progress_line: bool, // Whether the last line printed is a progress bar
+}
+
+impl ConsoleUi {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ConsoleState::default())),
+            interactive: atty::is(atty::Stream::Stdin),
+        }
+    }
+
+    /// Exit code that reflects the outcome of the operations reported so far.
+    pub fn exit_code(&self) -> i32 {
+        let state = self.state.lock().unwrap();
+        if state.failed {
+            EXIT_FAILURE
+        } else if state.skipped_patches {
+            EXIT_PATCHES_SKIPPED
+        } else {
+            EXIT_SUCCESS
+        }
+    }
+
+    /// Asks a yes/no question on the standard input. Answers no when the
+    /// standard input isn't a terminal, for unattended runs.
+    fn prompt_yes_no(&self, message: &str) -> bool {
+        if !self.interactive {
+            self.print_line(&format!("{} [y/N] n", message));
+            return false;
+        }
+        self.end_progress_line();
+        print!("{} [y/N] ", message);
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer).is_err() {
+            return false;
+        }
+        matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+    }
+
+    fn print_line(&self, line: &str) {
+        self.end_progress_line();
+        println!("{}", line);
+    }
+
+    /// Overwrites the last progress bar, if that's the last line printed.
+    fn print_progress(&self, line: &str) {
+        self.state.lock().unwrap().progress_line = true;
+        // Note: Trailing spaces erase longer lines printed previously
+        print!("\r{:<80}", line);
+        let _ = io::stdout().flush();
+    }
+
+    fn end_progress_line(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.progress_line {
+            state.progress_line = false;
+            println!();
+        }
+    }
+}
+
+impl UiFrontend for ConsoleUi {
+    fn dispatch_patching_status(&self, status: PatchingStatus) {
+        match status {
+            PatchingStatus::Ready | PatchingStatus::DownloadThrottled(..) => {}
+            PatchingStatus::Error(msg) => {
+                self.state.lock().unwrap().failed = true;
+                self.print_line(&tr("console.error", &[("message", &msg)]));
+            }
+            PatchingStatus::PatchServerSelected(name) => {
+                self.print_line(&tr("console.patch_server_selected", &[("server", &name)]))
+            }
+            PatchingStatus::ChannelSelected(name) => {
+                let default_channel = tr("console.default_channel", &[]);
+                self.print_line(&tr(
+                    "console.channel_selected",
+                    &[("channel", name.as_deref().unwrap_or(&default_channel))],
+                ))
+            }
+            PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => self
+                .print_progress(&tr(
+                    "console.download_in_progress",
+                    &[
+                        (
+                            "progress",
+                            &progress_bar(nb_downloaded as u64, nb_total as u64),
+                        ),
+                        ("done", &nb_downloaded.to_string()),
+                        ("total", &nb_total.to_string()),
+                        ("speed", &format_size(bytes_per_sec)),
+                    ],
+                )),
+            PatchingStatus::DownloadProgress(
+                downloaded_bytes,
+                total_bytes,
+                bytes_per_sec,
+                _,
+                eta,
+            ) => self.print_progress(&tr(
+                "console.download_progress",
+                &[
+                    ("progress", &progress_bar(downloaded_bytes, total_bytes)),
+                    ("done", &format_size(downloaded_bytes)),
+                    ("total", &format_size(total_bytes)),
+                    ("speed", &format_size(bytes_per_sec)),
+                    (
+                        "eta",
+                        &eta.map_or("?".to_string(), |secs| format!("{}s", secs)),
+                    ),
+                ],
+            )),
+            PatchingStatus::DownloadRetry(name, attempt, max_attempts) => self.print_line(&tr(
+                "console.download_retry",
+                &[
+                    ("file", &name),
+                    ("attempt", &attempt.to_string()),
+                    ("max_attempts", &max_attempts.to_string()),
+                ],
+            )),
+            PatchingStatus::InstallationInProgress(nb_installed, nb_total) => {
+                self.print_progress(&tr(
+                    "console.installation_in_progress",
+                    &[
+                        (
+                            "progress",
+                            &progress_bar(nb_installed as u64, nb_total as u64),
+                        ),
+                        ("done", &nb_installed.to_string()),
+                        ("total", &nb_total.to_string()),
+                    ],
+                ))
+            }
+            PatchingStatus::PatchInProgress {
+                file,
+                entry,
+                total_entries,
+                ..
+            } => self.print_progress(&tr(
+                "console.patch_in_progress",
+                &[
+                    ("file", &file),
+                    (
+                        "progress",
+                        &progress_bar(entry as u64, total_entries as u64),
+                    ),
+                    ("done", &entry.to_string()),
+                    ("total", &total_entries.to_string()),
+                ],
+            )),
+            PatchingStatus::GrfRebuildInProgress {
+                grf,
+                entries_done,
+                entries_total,
+            } => self.print_progress(&tr(
+                "console.grf_rebuild_in_progress",
+                &[
+                    ("grf", &grf),
+                    (
+                        "progress",
+                        &progress_bar(entries_done as u64, entries_total as u64),
+                    ),
+                    ("done", &entries_done.to_string()),
+                    ("total", &entries_total.to_string()),
+                ],
+            )),
+            PatchingStatus::ManualPatchApplied(name) => {
+                self.print_line(&tr("console.manual_patch_applied", &[("patch", &name)]))
+            }
+            PatchingStatus::PatchesSkipped(names) => {
+                self.state.lock().unwrap().skipped_patches = true;
+                self.print_line(&tr(
+                    "console.patches_skipped",
+                    &[("patches", &names.join(", "))],
+                ));
+            }
+            PatchingStatus::Summary(summary) => self.print_line(&tr(
+                "console.summary",
+                &[
+                    ("applied", &summary.applied_patch_count.to_string()),
+                    ("skipped", &summary.skipped_patches.len().to_string()),
+                    ("downloaded", &format_size(summary.downloaded_bytes)),
+                    ("elapsed", &summary.elapsed_secs.to_string()),
+                ],
+            )),
+            PatchingStatus::Report(patch_reports) => {
+                let unknown_reason = tr("console.unknown_reason", &[]);
+                for report in patch_reports
+                    .iter()
+                    .filter(|report| report.outcome != PatchOutcome::Applied)
+                {
+                    self.print_line(&tr(
+                        "console.report",
+                        &[
+                            ("outcome", &format!("{:?}", report.outcome)),
+                            ("patch", &report.name),
+                            (
+                                "reason",
+                                report.reason.as_deref().unwrap_or(&unknown_reason),
+                            ),
+                        ],
+                    ));
+                }
+            }
+            PatchingStatus::Preview(previews) => {
+                let game_directory = tr("console.game_directory", &[]);
+                for preview in previews {
+                    self.print_line(&tr(
+                        "console.preview",
+                        &[
+                            ("patch", &preview.patch_name),
+                            ("count", &preview.changes.len().to_string()),
+                            (
+                                "target",
+                                preview.target_grf.as_deref().unwrap_or(&game_directory),
+                            ),
+                        ],
+                    ));
+                }
+            }
+            PatchingStatus::RolledBack(file_count) => self.print_line(&tr(
+                "console.rolled_back",
+                &[("count", &file_count.to_string())],
+            )),
+            PatchingStatus::VerificationInProgress(nb_checked, nb_total) => {
+                self.print_progress(&tr(
+                    "console.verification_in_progress",
+                    &[
+                        (
+                            "progress",
+                            &progress_bar(nb_checked as u64, nb_total as u64),
+                        ),
+                        ("done", &nb_checked.to_string()),
+                        ("total", &nb_total.to_string()),
+                    ],
+                ))
+            }
+            PatchingStatus::Repaired(paths) => self.print_line(&tr(
+                "console.repaired",
+                &[("count", &paths.len().to_string())],
+            )),
+            PatchingStatus::UpdatesAvailable(patch_count) => self.print_line(&tr(
+                "console.updates_available",
+                &[("count", &patch_count.to_string())],
+            )),
+            PatchingStatus::FileLocked(path, _) => {
+                self.print_line(&tr("console.file_locked", &[("path", &path)]))
+            }
+            PatchingStatus::Paused => self.print_line(&tr("console.paused", &[])),
+            PatchingStatus::Resumed => self.print_line(&tr("console.resumed", &[])),
+            PatchingStatus::Finishing => self.print_line(&tr("console.finishing", &[])),
+            PatchingStatus::ElevatedUpdateInProgress => {
+                self.print_line(&tr("console.elevated_update", &[]))
+            }
+            PatchingStatus::AlreadyPatching => {
+                self.state.lock().unwrap().failed = true;
+                self.print_line(&tr("console.already_patching", &[]));
+            }
+        }
+    }
+
+    fn prompt_skip_patch(&self, patch_name: &str, error_msg: &str) -> bool {
+        self.prompt_yes_no(&tr(
+            "console.skip_patch",
+            &[("error", error_msg), ("patch", patch_name)],
+        ))
+    }
+
+    fn warn_running_client(&self, executable_name: &str) {
+        self.print_line(&tr(
+            "console.client_running_warning",
+            &[("client", executable_name)],
+        ));
+    }
+
+    fn prompt_running_client(&self, executable_name: &str) -> bool {
+        self.prompt_yes_no(&tr(
+            "console.client_running_prompt",
+            &[("client", executable_name)],
+        ))
+    }
+}
+
+fn progress_bar(done: u64, total: u64) -> String {
+    let ratio = if total == 0 {
+        1.0
+    } else {
+        (done as f64 / total as f64).min(1.0)
+    };
+    let filled = (ratio * PROGRESS_BAR_WIDTH as f64) as usize;
+    format!(
+        "[{}{}] {:>3}%",
+        "#".repeat(filled),
+        "-".repeat(PROGRESS_BAR_WIDTH - filled),
+        (ratio * 100.0) as u32
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_bar() {
+        assert_eq!(progress_bar(0, 4), format!("[{}]   0%", "-".repeat(30)));
+        assert_eq!(
+            progress_bar(1, 2),
+            format!("[{}{}]  50%", "#".repeat(15), "-".repeat(15))
+        );
+        assert_eq!(progress_bar(3, 2), format!("[{}] 100%", "#".repeat(30)));
+        assert_eq!(progress_bar(0, 0), format!("[{}] 100%", "#".repeat(30)));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
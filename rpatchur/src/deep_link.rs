@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Context, Result};
+use url::Url;
+
+use crate::patcher::{tr, PatcherCommand};
+
+/// Scheme of the links websites can use to drive the patcher (e.g.
+/// 'rpatchur://patch?profile=renewal').
+pub const DEEP_LINK_SCHEME: &str = "rpatchur";
+
+/// Action requested through a deep link.
+#[derive(Debug, PartialEq)]
+pub enum DeepLink {
+    Update { profile: Option<String> }, // rpatchur://patch[?profile=<name>]
+    ApplyPatch(Url),                    // rpatchur://apply?url=<URL of the patch>
+    Repair,                             // rpatchur://repair
+}
+
+impl DeepLink {
+    pub fn parse(link: &str) -> Result<DeepLink> {
+        let url = Url::parse(link).with_context(|| format!("Invalid link '{}'", link))?;
+        if url.scheme() != DEEP_LINK_SCHEME {
+            return Err(anyhow!("Unsupported link '{}'", link));
+        }
+        // Note: The action is the host of the link ('rpatchur://patch'), or
+        // its path if it has none ('rpatchur:patch')
+        let action = match url.host_str() {
+            Some(host) if !host.is_empty() => host,
+            _ => url.path(),
+        };
+        let query_parameter = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        match action.trim_matches('/').to_ascii_lowercase().as_str() {
+            "patch" | "update" => Ok(DeepLink::Update {
+                profile: query_parameter("profile").filter(|profile| !profile.is_empty()),
+            }),
+            "apply" => {
+                let patch_url = query_parameter("url")
+                    .with_context(|| format!("Missing patch URL in '{}'", link))?;
+                let patch_url = Url::parse(&patch_url)
+                    .with_context(|| format!("Invalid patch URL '{}'", patch_url))?;
+                if patch_url.scheme() != "http" && patch_url.scheme() != "https" {
+                    return Err(anyhow!("Unsupported patch URL '{}'", patch_url));
+                }
+                Ok(DeepLink::ApplyPatch(patch_url))
+            }
+            "repair" => Ok(DeepLink::Repair),
+            _ => Err(anyhow!("Unknown action in '{}'", link)),
+        }
+    }
+
+    /// Returns the command that performs the requested action.
+    ///
+    /// Note: Profiles must be selected before sending the command.
+    pub fn command(&self) -> PatcherCommand {
+        match self {
+            DeepLink::Update { .. } => PatcherCommand::StartUpdate,
+            DeepLink::ApplyPatch(patch_url) => PatcherCommand::ApplyRemote(patch_url.clone()),
+            DeepLink::Repair => PatcherCommand::Repair,
+        }
+    }
+
+    /// Returns the question asked to the user before performing the requested
+    /// action, since any website can open deep links.
+    pub fn confirmation_message(&self) -> String {
+        match self {
+            DeepLink::Update { profile: None } => tr("dialog.deep_link.update", &[]),
+            DeepLink::Update {
+                profile: Some(profile),
+            } => tr("dialog.deep_link.update_profile", &[("profile", profile)]),
+            DeepLink::ApplyPatch(patch_url) => {
+                tr("dialog.deep_link.apply", &[("url", patch_url.as_str())])
+            }
+            DeepLink::Repair => tr("dialog.deep_link.repair", &[]),
+        }
+    }
+}
+
+/// Registers the patcher as the handler of deep links for the current user.
+pub fn register_url_protocol() -> Result<()> {
+    let exe_path = std::env::current_exe().context("Failed to find the patcher's executable")?;
+    let working_directory = exe_path
+        .parent()
+        .context("Failed to find the patcher's directory")?;
+    platform::register_url_protocol(&exe_path, working_directory)
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    use anyhow::{anyhow, Result};
+    use winapi::shared::minwindef::{DWORD, HKEY};
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::winnt::{KEY_WRITE, REG_OPTION_NON_VOLATILE, REG_SZ};
+    use winapi::um::winreg::{RegCloseKey, RegCreateKeyExW, RegSetValueExW, HKEY_CURRENT_USER};
+
+    use super::DEEP_LINK_SCHEME;
+
+    /// Registers the scheme under 'HKEY_CURRENT_USER\Software\Classes', which
+    /// doesn't require administrator privileges.
+    pub fn register_url_protocol(exe_path: &Path, working_directory: &Path) -> Result<()> {
+        let class_key = format!(r"Software\Classes\{}", DEEP_LINK_SCHEME);
+        let command = format!(
+            "\"{}\" --working-directory \"{}\" --deep-link \"%1\"",
+            exe_path.display(),
+            working_directory.display()
+        );
+        set_registry_value(&class_key, None, "URL:RPatchur Protocol")?;
+        set_registry_value(&class_key, Some("URL Protocol"), "")?;
+        set_registry_value(
+            &format!(r"{}\shell\open\command", class_key),
+            None,
+            &command,
+        )
+    }
+
+    /// Sets a string value of a key of 'HKEY_CURRENT_USER', creating the key if
+    /// needed. `None` is the default value of the key.
+    fn set_registry_value(key_path: &str, value_name: Option<&str>, value: &str) -> Result<()> {
+        let key_path = to_wide(key_path);
+        let value_name = value_name.map(to_wide);
+        let value = to_wide(value);
+        unsafe {
+            let mut key: HKEY = ptr::null_mut();
+            let res = RegCreateKeyExW(
+                HKEY_CURRENT_USER,
+                key_path.as_ptr(),
+                0,
+                ptr::null_mut(),
+                REG_OPTION_NON_VOLATILE,
+                KEY_WRITE,
+                ptr::null_mut(),
+                &mut key,
+                ptr::null_mut(),
+            );
+            if res != ERROR_SUCCESS as i32 {
+                return Err(anyhow!("Failed to create registry key (error {})", res));
+            }
+            let res = RegSetValueExW(
+                key,
+                value_name
+                    .as_ref()
+                    .map_or(ptr::null(), |value_name| value_name.as_ptr()),
+                0,
+                REG_SZ,
+                value.as_ptr() as *const u8,
+                (value.len() * 2) as DWORD,
+            );
+            RegCloseKey(key);
+            if res != ERROR_SUCCESS as i32 {
+                return Err(anyhow!("Failed to set registry value (error {})", res));
+            }
+        }
+        Ok(())
+    }
+
+    fn to_wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    use anyhow::{anyhow, Context, Result};
+
+    use super::DEEP_LINK_SCHEME;
+
+    /// Installs a desktop entry handling the scheme in the user's
+    /// applications and makes it the default handler with `xdg-mime`.
+    pub fn register_url_protocol(exe_path: &Path, working_directory: &Path) -> Result<()> {
+        let data_home = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+            .context("Failed to find the user's data directory")?;
+        let applications_directory = data_home.join("applications");
+        fs::create_dir_all(&applications_directory)
+            .context("Failed to create the applications directory")?;
+        let desktop_file_name = format!("{}-url-handler.desktop", DEEP_LINK_SCHEME);
+        let desktop_entry = format!(
+            "[Desktop Entry]\n\
+             Type=Application\n\
+             Name=RPatchur\n\
+             Exec=\"{}\" --working-directory \"{}\" --deep-link %u\n\
+             MimeType=x-scheme-handler/{};\n\
+             NoDisplay=true\n",
+            exe_path.display(),
+            working_directory.display(),
+            DEEP_LINK_SCHEME
+        );
+        fs::write(
+            applications_directory.join(&desktop_file_name),
+            desktop_entry,
+        )
+        .context("Failed to write the desktop entry")?;
+        let status = Command::new("xdg-mime")
+            .args(["default", &desktop_file_name])
+            .arg(format!("x-scheme-handler/{}", DEEP_LINK_SCHEME))
+            .status()
+            .context("Failed to run xdg-mime")?;
+        if !status.success() {
+            return Err(anyhow!("xdg-mime failed ({})", status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_deep_link() {
+        assert_eq!(
+            DeepLink::parse("rpatchur://patch?profile=renewal").unwrap(),
+            DeepLink::Update {
+                profile: Some("renewal".to_string())
+            }
+        );
+        assert_eq!(
+            DeepLink::parse("rpatchur://update/").unwrap(),
+            DeepLink::Update { profile: None }
+        );
+        assert_eq!(
+            DeepLink::parse("rpatchur:repair").unwrap(),
+            DeepLink::Repair
+        );
+        assert_eq!(
+            DeepLink::parse("rpatchur://apply?url=https%3A%2F%2Fexample.com%2Fpatch%2Fevent.thor")
+                .unwrap(),
+            DeepLink::ApplyPatch(Url::parse("https://example.com/patch/event.thor").unwrap())
+        );
+        assert!(DeepLink::parse("rpatchur://apply?url=file:///etc/passwd").is_err());
+        assert!(DeepLink::parse("rpatchur://apply").is_err());
+        assert!(DeepLink::parse("rpatchur://uninstall").is_err());
+        assert!(DeepLink::parse("https://patch?profile=renewal").is_err());
+    }
+}
@@ -0,0 +1,622 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use url::Url;
+
+use crate::patcher::{
+    get_history_file_path, patcher_info, read_history, IpcConfiguration, LockedFileAction,
+    NewsItem, PatcherChangelog, PatcherCommand, PreviewMode, HISTORY_LIMIT,
+};
+use crate::ui::{patching_status_call, PatchingStatus, UiFrontend};
+
+// GUID appended to the client's key to compute the handshake's answer (see
+// RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Maximum size of the messages received from clients
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+// Maximum size of the clients' opening handshake, and number of headers in it
+const MAX_HANDSHAKE_SIZE: u64 = 8 * 1024;
+const MAX_HANDSHAKE_HEADERS: usize = 32;
+// Time between two checks for connected clients while waiting for an answer
+const PROMPT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Frontend that streams the patcher's events to the clients of the local
+/// WebSocket endpoint, so that custom launchers can drive the patcher
+/// without its web view.
+///
+/// Events are JSON objects containing the name of the function the web view
+/// would call and its arguments (e.g. '{"function": "patchingStatusReady",
+/// "arguments": []}').
+#[derive(Clone)]
+pub struct IpcFrontend {
+    clients: Arc<Mutex<Vec<flume::Sender<String>>>>,
+    last_status: Arc<Mutex<Option<String>>>, // Sent to clients when they connect
+    prompt_answers: (flume::Sender<bool>, flume::Receiver<bool>),
+    patching_thread_tx: flume::Sender<PatcherCommand>,
+}
+
+impl IpcFrontend {
+    pub fn new(patching_thread_tx: flume::Sender<PatcherCommand>) -> Self {
+        Self {
+            clients: Arc::new(Mutex::new(vec![])),
+            last_status: Arc::new(Mutex::new(None)),
+            prompt_answers: flume::unbounded(),
+            patching_thread_tx,
+        }
+    }
+
+    /// Returns the events sent to a newly connected client, starting with the
+    /// last status reported.
+    fn subscribe(&self) -> flume::Receiver<String> {
+        let (tx, rx) = flume::unbounded();
+        if let Some(last_status) = self.last_status.lock().unwrap().as_ref() {
+            let _ = tx.send(last_status.clone());
+        }
+        self.clients.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn broadcast(&self, function_name: &str, arguments: Vec<Value>) -> String {
+        let event = json!({
+            "function": function_name,
+            "arguments": arguments,
+        })
+        .to_string();
+        // Note: Clients that are gone are forgotten
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.send(event.clone()).is_ok());
+        event
+    }
+
+    fn has_clients(&self) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| !client.is_disconnected());
+        !clients.is_empty()
+    }
+
+    /// Asks the connected clients a yes/no question, which one of them
+    /// answers with 'answer_prompt'. Answers no if no client is connected.
+    fn prompt(&self, function_name: &str, arguments: Vec<Value>) -> bool {
+        let (_, answers) = &self.prompt_answers;
+        // Discard answers given too late to previous prompts
+        answers.drain();
+        if !self.has_clients() {
+            return false;
+        }
+        self.broadcast(function_name, arguments);
+        loop {
+            match answers.recv_timeout(PROMPT_POLL_INTERVAL) {
+                Ok(answer) => return answer,
+                Err(flume::RecvTimeoutError::Timeout) if self.has_clients() => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn answer_prompt(&self, answer: bool) {
+        let (answers, _) = &self.prompt_answers;
+        let _ = answers.send(answer);
+    }
+}
+
+impl UiFrontend for IpcFrontend {
+    fn dispatch_patching_status(&self, status: PatchingStatus) {
+        let (function_name, arguments) = patching_status_call(status);
+        let event = self.broadcast(function_name, arguments);
+        *self.last_status.lock().unwrap() = Some(event);
+    }
+
+    fn prompt_skip_patch(&self, patch_name: &str, error_msg: &str) -> bool {
+        self.prompt("promptSkipPatch", vec![json!(patch_name), json!(error_msg)])
+    }
+
+    fn warn_running_client(&self, executable_name: &str) {
+        self.broadcast("warnRunningClient", vec![json!(executable_name)]);
+    }
+
+    fn prompt_running_client(&self, executable_name: &str) -> bool {
+        self.prompt("promptRunningClient", vec![json!(executable_name)])
+    }
+
+    fn set_patch_in_progress(&self, value: bool) {
+        self.broadcast("patchInProgress", vec![json!(value)]);
+    }
+
+    fn dispatch_news(&self, news: Vec<NewsItem>) {
+        self.broadcast("patcherNews", vec![json!(news)]);
+    }
+
+    fn dispatch_changelog(&self, changelog: PatcherChangelog) {
+        self.broadcast("patcherChangelog", vec![json!(changelog)]);
+    }
+
+    fn request_exit(&self) {
+        self.broadcast("patcherExit", vec![]);
+        let _ = self.patching_thread_tx.try_send(PatcherCommand::Quit);
+    }
+}
+
+/// Request sent by a client, either a JSON object like those the web view
+/// sends or the name of a function without parameters.
+#[derive(Deserialize)]
+struct IpcRequest {
+    function: String,
+    #[serde(default)]
+    parameters: Value,
+}
+
+#[derive(Deserialize)]
+struct PathParameters {
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct PreviewUpdateParameters {
+    #[serde(default)]
+    headers_only: bool,
+}
+
+#[derive(Deserialize)]
+struct SelectChannelParameters {
+    channel: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AnswerPromptParameters {
+    answer: bool,
+}
+
+/// Listens to the local endpoint configured in `ipc_config` on a dedicated
+/// thread, forwarding the requests of its clients to the patching thread.
+pub fn start_ipc_server(
+    ipc_config: &IpcConfiguration,
+    frontend: IpcFrontend,
+    patching_thread_tx: flume::Sender<PatcherCommand>,
+) -> Result<()> {
+    // Note: Only local programs can connect
+    let listener = std::net::TcpListener::bind(("127.0.0.1", ipc_config.port))
+        .with_context(|| format!("Failed to listen to port {}", ipc_config.port))?;
+    listener.set_nonblocking(true)?;
+    log::info!("Listening to ws://127.0.0.1:{}/", ipc_config.port);
+    let token = ipc_config.token.clone();
+    thread::spawn(move || {
+        let tokio_rt = match runtime::Builder::new_current_thread().enable_all().build() {
+            Err(e) => {
+                log::error!("Failed to build a tokio runtime: {}", e);
+                return;
+            }
+            Ok(v) => v,
+        };
+        tokio_rt.block_on(async move {
+            let listener = match TcpListener::from_std(listener) {
+                Err(e) => {
+                    log::error!("Failed to listen for IPC clients: {}", e);
+                    return;
+                }
+                Ok(v) => v,
+            };
+            loop {
+                let stream = match listener.accept().await {
+                    Err(e) => {
+                        log::warn!("Failed to accept IPC client: {}", e);
+                        continue;
+                    }
+                    Ok((stream, _)) => stream,
+                };
+                let connection = serve_client(
+                    stream,
+                    token.clone(),
+                    frontend.clone(),
+                    patching_thread_tx.clone(),
+                );
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        log::warn!("IPC client disconnected: {:#}", e);
+                    }
+                });
+            }
+        });
+    });
+    Ok(())
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    token: Option<String>,
+    frontend: IpcFrontend,
+    patching_thread_tx: flume::Sender<PatcherCommand>,
+) -> Result<()> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let accept_key = match read_handshake(&mut reader, token.as_deref()).await {
+        Err(e) => {
+            let _ = write_half
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            return Err(e);
+        }
+        Ok(accept_key) => accept_key,
+    };
+    write_half
+        .write_all(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key
+            )
+            .as_bytes(),
+        )
+        .await?;
+    log::info!("IPC client connected");
+
+    // Frames are written by a separate task, so that events are sent while
+    // waiting for requests
+    let events = frontend.subscribe();
+    let (frame_tx, frame_rx) = flume::unbounded::<(u8, Vec<u8>)>();
+    let writer = tokio::spawn(async move {
+        loop {
+            let (opcode, payload) = tokio::select! {
+                event = events.recv_async() => match event {
+                    Err(_) => break,
+                    Ok(event) => (OPCODE_TEXT, event.into_bytes()),
+                },
+                frame = frame_rx.recv_async() => match frame {
+                    Err(_) => break,
+                    Ok(frame) => frame,
+                },
+            };
+            if write_frame(&mut write_half, opcode, &payload)
+                .await
+                .is_err()
+                || opcode == OPCODE_CLOSE
+            {
+                break;
+            }
+        }
+    });
+    let _guard = scopeguard::guard((), |_| writer.abort());
+
+    let mut message = vec![];
+    loop {
+        let (is_final, opcode, payload) = read_frame(&mut reader).await?;
+        match opcode {
+            OPCODE_PING => {
+                let _ = frame_tx.send((OPCODE_PONG, payload));
+                continue;
+            }
+            OPCODE_PONG => continue,
+            OPCODE_CLOSE => {
+                let _ = frame_tx.send((OPCODE_CLOSE, vec![]));
+                log::info!("IPC client disconnected");
+                return Ok(());
+            }
+            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {}
+            _ => return Err(anyhow!("Unknown opcode {}", opcode)),
+        }
+        if message.len() + payload.len() > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("Message is too large"));
+        }
+        message.extend_from_slice(&payload);
+        if !is_final {
+            continue;
+        }
+        let request = String::from_utf8_lossy(&message).into_owned();
+        message.clear();
+        if let Err(e) = handle_request(&request, &frontend, &patching_thread_tx) {
+            log::warn!("Invalid IPC request: {:#}", e);
+            let error = json!({
+                "function": "ipcError",
+                "arguments": [format!("{:#}", e)],
+            });
+            let _ = frame_tx.send((OPCODE_TEXT, error.to_string().into_bytes()));
+        }
+    }
+}
+
+fn handle_request(
+    request: &str,
+    frontend: &IpcFrontend,
+    patching_thread_tx: &flume::Sender<PatcherCommand>,
+) -> Result<()> {
+    let request = match serde_json::from_str::<IpcRequest>(request) {
+        Ok(request) => request,
+        Err(_) => IpcRequest {
+            function: request.trim().to_string(),
+            parameters: Value::Null,
+        },
+    };
+    if request.function == "answer_prompt" {
+        let params: AnswerPromptParameters = serde_json::from_value(request.parameters)?;
+        frontend.answer_prompt(params.answer);
+        return Ok(());
+    }
+    if request.function == "get_patcher_info" {
+        frontend.broadcast("patcherInfo", vec![json!(patcher_info())]);
+        return Ok(());
+    }
+    if request.function == "get_history" {
+        let history =
+            get_history_file_path().and_then(|path| read_history(path, Some(HISTORY_LIMIT)))?;
+        frontend.broadcast("patchHistory", vec![json!(history)]);
+        return Ok(());
+    }
+    let command = parse_command(&request.function, request.parameters)?;
+    patching_thread_tx
+        .send(command)
+        .map_err(|_| anyhow!("The patching thread has stopped"))
+}
+
+/// Returns the command requested by a call to `function`.
+fn parse_command(function: &str, parameters: Value) -> Result<PatcherCommand> {
+    let command = match function {
+        "start_update" => PatcherCommand::StartUpdate,
+        "check_for_updates" => PatcherCommand::CheckForUpdates,
+        "cancel_update" => PatcherCommand::CancelUpdate,
+        "pause_update" => PatcherCommand::PauseUpdate,
+        "resume_update" => PatcherCommand::ResumeUpdate,
+        "rollback" => PatcherCommand::Rollback,
+        "repair" => PatcherCommand::Repair,
+        "get_news" => PatcherCommand::FetchNews,
+        "get_changelog" => PatcherCommand::FetchChangelog,
+        "retry_locked_file" => PatcherCommand::ResolveLockedFile(LockedFileAction::Retry),
+        "schedule_locked_file" => PatcherCommand::ResolveLockedFile(LockedFileAction::Schedule),
+        "exit" => PatcherCommand::Quit,
+        "preview_update" => {
+            let params: PreviewUpdateParameters = serde_json::from_value(parameters)?;
+            PatcherCommand::Preview(if params.headers_only {
+                PreviewMode::HeadersOnly
+            } else {
+                PreviewMode::FullDownload
+            })
+        }
+        "select_channel" => {
+            let params: SelectChannelParameters = serde_json::from_value(parameters)?;
+            PatcherCommand::SelectChannel(params.channel)
+        }
+        "apply_patch" => {
+            let params: PathParameters = serde_json::from_value(parameters)?;
+            PatcherCommand::ApplyPatch(params.path)
+        }
+        "apply_local" => {
+            let params: PathParameters = serde_json::from_value(parameters)?;
+            PatcherCommand::ApplyLocal(params.path)
+        }
+        "apply_file" => {
+            let params: PathParameters = serde_json::from_value(parameters)?;
+            PatcherCommand::ApplyFile(params.path)
+        }
+        _ => return Err(anyhow!("Unknown function '{}'", function)),
+    };
+    Ok(command)
+}
+
+/// Reads the client's opening handshake and returns the value of the
+/// 'Sec-WebSocket-Accept' header to answer with.
+///
+/// Requests sent by web pages (i.e. with an 'Origin' header) are refused
+/// unless `token` is set, in which case all clients must give it with
+/// '?token='.
+async fn read_handshake<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    token: Option<&str>,
+) -> Result<String> {
+    // Read up to the empty line ending the request, without reading past it
+    let mut request = Vec::new();
+    let mut limited_reader = reader.take(MAX_HANDSHAKE_SIZE);
+    while !request.ends_with(b"\r\n\r\n") && !request.ends_with(b"\n\n") {
+        if limited_reader.read_until(b'\n', &mut request).await? == 0 {
+            return Err(anyhow!("Handshake is incomplete or too large"));
+        }
+    }
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HANDSHAKE_HEADERS];
+    let mut parsed_request = httparse::Request::new(&mut headers);
+    if parsed_request
+        .parse(&request)
+        .context("Invalid handshake")?
+        .is_partial()
+    {
+        return Err(anyhow!("Handshake is incomplete"));
+    }
+    let path = match (parsed_request.method, parsed_request.path) {
+        (Some("GET"), Some(path)) => path,
+        _ => return Err(anyhow!("Invalid request, expected a GET request")),
+    };
+    let mut websocket_key = None;
+    let mut has_origin = false;
+    for header in parsed_request.headers.iter() {
+        if header.name.eq_ignore_ascii_case("sec-websocket-key") {
+            websocket_key = Some(String::from_utf8_lossy(header.value).trim().to_string());
+        } else if header.name.eq_ignore_ascii_case("origin") {
+            has_origin = true;
+        }
+    }
+    let websocket_key = websocket_key.context("Not a WebSocket request")?;
+    let url = Url::parse(&format!("ws://127.0.0.1{}", path))?;
+    match token {
+        Some(token) => {
+            let is_token_valid = url.query_pairs().any(|(name, value)| {
+                name == "token" && bool::from(value.as_bytes().ct_eq(token.as_bytes()))
+            });
+            if !is_token_valid {
+                return Err(anyhow!("Invalid token"));
+            }
+        }
+        None if has_origin => return Err(anyhow!("Web pages require a token to connect")),
+        None => {}
+    }
+    Ok(websocket_accept_key(&websocket_key))
+}
+
+fn websocket_accept_key(websocket_key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", websocket_key, WEBSOCKET_GUID).as_bytes(),
+    );
+    base64::encode(digest.as_ref())
+}
+
+/// Reads a frame sent by a client. Returns whether it's the final fragment of
+/// a message, its opcode and its unmasked payload.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let is_final = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0F;
+    if header[1] & 0x80 == 0 {
+        return Err(anyhow!("Frames sent by clients must be masked"));
+    }
+    let payload_size = match header[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        size => size as u64,
+    };
+    if payload_size > MAX_MESSAGE_SIZE as u64 {
+        return Err(anyhow!("Frame is too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; payload_size as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((is_final, opcode, payload))
+}
+
+/// Writes a single, unmasked frame, as servers do.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        size if size < 126 => frame.push(size as u8),
+        size if size <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(size as u16).to_be_bytes());
+        }
+        size => {
+            frame.push(127);
+            frame.extend_from_slice(&(size as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    Ok(writer.flush().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_websocket_accept_key() {
+        // Example given by RFC 6455
+        assert_eq!(
+            websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_handshake() {
+        let request = "GET /?token=secret HTTP/1.1\r\n\
+                       Host: 127.0.0.1\r\n\
+                       Upgrade: websocket\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Origin: app://launcher\r\n\r\n";
+        let accept_key = read_handshake(&mut request.as_bytes(), Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(accept_key, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(read_handshake(&mut request.as_bytes(), Some("other"))
+            .await
+            .is_err());
+        // Web pages cannot connect without a token
+        assert!(read_handshake(&mut request.as_bytes(), None).await.is_err());
+
+        // Handshakes are limited in size and in number of headers
+        let oversized_request = format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\n\r\n",
+            "a".repeat(MAX_HANDSHAKE_SIZE as usize)
+        );
+        assert!(read_handshake(&mut oversized_request.as_bytes(), None)
+            .await
+            .is_err());
+        let many_headers_request = format!(
+            "GET / HTTP/1.1\r\n{}Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            "X-Header: value\r\n".repeat(MAX_HANDSHAKE_HEADERS)
+        );
+        assert!(read_handshake(&mut many_headers_request.as_bytes(), None)
+            .await
+            .is_err());
+        let request = "GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert!(read_handshake(&mut request.as_bytes(), None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let payload = "start_update".repeat(20);
+        let mask = [0x12u8, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | 126];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .bytes()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        let (is_final, opcode, content) = read_frame(&mut frame.as_slice()).await.unwrap();
+        assert!(is_final);
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(content, payload.as_bytes());
+
+        let mut frame = vec![];
+        write_frame(&mut frame, OPCODE_TEXT, b"ready")
+            .await
+            .unwrap();
+        assert_eq!(frame, b"\x81\x05ready");
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert!(matches!(
+            parse_command("start_update", Value::Null),
+            Ok(PatcherCommand::StartUpdate)
+        ));
+        assert!(matches!(
+            parse_command("preview_update", json!({"headers_only": true})),
+            Ok(PatcherCommand::Preview(PreviewMode::HeadersOnly))
+        ));
+        assert!(matches!(
+            parse_command("select_channel", json!({"channel": "beta"})),
+            Ok(PatcherCommand::SelectChannel(Some(channel))) if channel == "beta"
+        ));
+        assert!(parse_command("apply_file", Value::Null).is_err());
+        assert!(parse_command("unknown", Value::Null).is_err());
+    }
+}
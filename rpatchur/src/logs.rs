@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simple_logger::SimpleLogger;
+
+// Number of records kept in memory, older ones are discarded
+const LOG_BUFFER_CAPACITY: usize = 500;
+// Size above which the log file is emptied on startup
+const MAX_LOG_FILE_SIZE: u64 = 1024 * 1024;
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer::new(LOG_BUFFER_CAPACITY));
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Log record kept in memory, to be displayed by the UI.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    pub timestamp: u64, // Seconds since the Unix epoch
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// Ring buffer of the most recent log records.
+struct LogBuffer {
+    records: VecDeque<LogRecord>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, record: LogRecord) {
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+/// Logger that keeps the records it prints in memory, so that the UI can
+/// display them.
+struct BufferedLogger {
+    inner: SimpleLogger,
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let log_record = LogRecord {
+            timestamp,
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(log_file) = log_file.as_mut() {
+                // Note: Failing to write logs mustn't disturb the patcher
+                let _ = writeln!(
+                    log_file,
+                    "{} {:<5} [{}] {}",
+                    log_record.timestamp, log_record.level, log_record.target, log_record.message
+                );
+            }
+        }
+        if let Ok(mut log_buffer) = LOG_BUFFER.lock() {
+            log_buffer.push(log_record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(log_file) = log_file.as_mut() {
+                let _ = log_file.flush();
+            }
+        }
+    }
+}
+
+/// Prints the records of `module` up to `level` on the standard output and
+/// keeps the most recent ones in memory.
+pub fn init_logger(module: &str, level: LevelFilter) -> Result<(), log::SetLoggerError> {
+    let inner = SimpleLogger::new()
+        .with_level(LevelFilter::Off)
+        .with_module_level(module, level);
+    log::set_boxed_logger(Box::new(BufferedLogger { inner }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Writes the records to the file at `file_path` as well. Records are
+/// appended to the file, unless it has grown too big.
+pub fn log_to_file(file_path: impl AsRef<Path>) -> io::Result<()> {
+    let file_path = file_path.as_ref();
+    let is_too_big = file_path
+        .metadata()
+        .map_or(false, |metadata| metadata.len() > MAX_LOG_FILE_SIZE);
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(!is_too_big)
+        .truncate(is_too_big)
+        .open(file_path)?;
+    if let Ok(mut log_file) = LOG_FILE.lock() {
+        *log_file = Some(file);
+    }
+    Ok(())
+}
+
+/// Returns the most recent log records, from oldest to newest.
+pub fn get_recent_logs() -> Vec<LogRecord> {
+    match LOG_BUFFER.lock() {
+        Err(_) => vec![],
+        Ok(log_buffer) => log_buffer.records.iter().cloned().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_buffer() {
+        let record = |message: &str| LogRecord {
+            timestamp: 0,
+            level: "INFO".to_string(),
+            target: "rpatchur".to_string(),
+            message: message.to_string(),
+        };
+        let mut log_buffer = LogBuffer::new(2);
+        log_buffer.push(record("first"));
+        log_buffer.push(record("second"));
+        assert_eq!(log_buffer.records.len(), 2);
+        // Oldest records are discarded first
+        log_buffer.push(record("third"));
+        assert_eq!(
+            log_buffer.records.iter().cloned().collect::<Vec<_>>(),
+            vec![record("second"), record("third")]
+        );
+    }
+}
@@ -1,23 +1,36 @@
 #![windows_subsystem = "windows"]
 
+mod console;
+mod deep_link;
+mod ipc;
+mod logs;
 mod patcher;
 mod process;
+mod tray;
 mod ui;
+#[cfg(feature = "wry")]
+mod wry_ui;
 
 use log::LevelFilter;
 use std::env;
 use std::path::PathBuf;
 
 use anyhow::{anyhow, Context, Result};
-use simple_logger::SimpleLogger;
 use structopt::StructOpt;
 use tinyfiledialogs as tfd;
 use tokio::runtime;
 
+use console::ConsoleUi;
+use deep_link::DeepLink;
+use ipc::IpcFrontend;
 use patcher::{
-    patcher_thread_routine, retrieve_patcher_configuration, PatcherCommand, PatcherConfiguration,
+    apply_remote_configuration, format_timestamp, get_history_file_path, get_installed_files_path,
+    get_instance_data_file_path, get_user_settings_file_path, init_data_directory,
+    init_localization, patcher_thread_routine, read_history, retrieve_patcher_configuration,
+    run_patcher_command, tr, ConfigurationOverride, InstalledFiles, PatcherCommand,
+    PatcherConfiguration, PreviewMode, UserSettings,
 };
-use ui::{UiController, WebViewUserData};
+use ui::{UiController, WebViewFrontend, WebViewUserData};
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -30,13 +43,79 @@ struct Opt {
     /// Sets a custom working directory
     #[structopt(short, long, parse(from_os_str))]
     working_directory: Option<PathBuf>,
+    /// Sets the directory the game client is installed in, overrides the
+    /// configuration
+    #[structopt(short, long, parse(from_os_str))]
+    install_directory: Option<PathBuf>,
+    /// Previews the changes of updates instead of applying them
+    #[structopt(long)]
+    dry_run: bool,
+    /// Only fetches the headers of the patches when previewing updates
+    #[structopt(long, requires = "dry-run")]
+    headers_only: bool,
+    /// Restores the files modified by the last update (see `patching.backup`)
+    #[structopt(long, conflicts_with = "dry-run")]
+    rollback: bool,
+    /// Verifies the client's files and repairs the broken ones (see
+    /// `web.repair_url`)
+    #[structopt(long, conflicts_with_all = &["dry-run", "rollback"])]
+    repair: bool,
+    /// Runs without the web view, prints progress on the standard output and
+    /// exits once done (0: success, 1: failure, 2: some patches were skipped)
+    #[structopt(long)]
+    no_ui: bool,
+    /// Runs without the web view, driven by other programs through the local
+    /// WebSocket endpoint configured with `ipc`, until one of them asks the
+    /// patcher to exit
+    #[structopt(long, conflicts_with_all = &["no-ui", "dry-run", "rollback", "repair"])]
+    ipc: bool,
+    /// Performs the action of a deep link (e.g. 'rpatchur://patch'), once
+    /// confirmed by the user
+    #[structopt(long, conflicts_with_all = &["no-ui", "ipc", "dry-run", "rollback", "repair"])]
+    deep_link: Option<String>,
+    /// Selects the profile to update (see `profiles`), in headless mode
+    #[structopt(long, requires = "no-ui")]
+    profile: Option<String>,
+    /// Registers the patcher as the handler of 'rpatchur://' deep links for
+    /// the current user and exits
+    #[structopt(long)]
+    register_url_protocol: bool,
+    /// Prints which patch wrote a file of the client (e.g.
+    /// 'System/iteminfo.lub' or 'data.grf:data\texture\file.bmp', see
+    /// `patching.track_installed_files`) and exits
+    #[structopt(long)]
+    installed_by: Option<String>,
+    /// Overrides a field of the configuration (e.g.
+    /// 'web.patch_servers[0].plist_url=https://staging.myserver.com/plist.txt'),
+    /// can be repeated. Takes precedence over the environment variables that
+    /// override it (e.g. 'RPATCHUR_WEB__READ_TIMEOUT=30')
+    #[structopt(
+        long = "set",
+        value_name = "field=value",
+        number_of_values = 1,
+        parse(try_from_str = ConfigurationOverride::from_argument)
+    )]
+    overrides: Vec<ConfigurationOverride>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Prints the patches processed on this machine, along with when they
+    /// were applied and their outcome
+    History {
+        /// Number of patches to print, the most recent ones
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: usize,
+        /// Prints all the patches
+        #[structopt(long, conflicts_with = "limit")]
+        all: bool,
+    },
 }
 
 fn main() -> Result<()> {
-    SimpleLogger::new()
-        .with_level(LevelFilter::Off)
-        .with_module_level(PKG_NAME, LevelFilter::Info)
-        .init()
+    logs::init_logger(PKG_NAME, LevelFilter::Info)
         .with_context(|| "Failed to initalize the logger")?;
 
     // Parse CLI arguments
@@ -46,30 +125,123 @@ fn main() -> Result<()> {
             .with_context(|| "Specified working directory is invalid or inaccessible")?;
     };
 
-    let config = match retrieve_patcher_configuration(None) {
+    if cli_args.no_ui
+        || cli_args.ipc
+        || cli_args.register_url_protocol
+        || cli_args.installed_by.is_some()
+        || cli_args.command.is_some()
+    {
+        attach_console();
+    }
+    if cli_args.register_url_protocol {
+        deep_link::register_url_protocol()
+            .with_context(|| "Failed to register the URL protocol")?;
+        log::info!("Registered the patcher as the handler of deep links");
+        return Ok(());
+    }
+    // Note: The OS's language is used until the configuration is loaded
+    init_localization(None);
+
+    let mut config = match retrieve_patcher_configuration(None, &cli_args.overrides) {
+        Err(e) if cli_args.no_ui || cli_args.ipc => return Err(e),
         Err(e) => {
-            let err_msg = "Failed to retrieve the patcher's configuration";
+            let err_msg = tr("error.configuration", &[("details", &format!("{:#}", e))]);
             tfd::message_box_ok(
-                "Error",
-                format!("Error: {}: {:#}.", err_msg, e).as_str(),
+                &tr("error.title", &[]),
+                &err_msg,
                 tfd::MessageBoxIcon::Error,
             );
             return Err(e);
         }
         Ok(v) => v,
     };
+    if config.language.is_some() {
+        init_localization(config.language.as_deref());
+    }
+    if init_data_directory(config.use_data_directory.unwrap_or(true)).is_some() {
+        let log_file_path = get_instance_data_file_path("log")?;
+        if let Err(e) = logs::log_to_file(&log_file_path) {
+            log::warn!("Failed to open '{}': {}", log_file_path.display(), e);
+        }
+    }
+    if let Some(location) = &cli_args.installed_by {
+        return print_installed_file(location);
+    }
+    if let Some(Command::History { limit, all }) = cli_args.command {
+        return print_history(if all { None } else { Some(limit) });
+    }
+    apply_remote_configuration(&mut config);
+    // Choices made by the user through the UI override the configuration
+    match get_user_settings_file_path().and_then(UserSettings::load) {
+        Err(e) => log::warn!("Failed to load user settings: {:#}", e),
+        Ok(user_settings) => user_settings.apply(&mut config),
+    }
+    if let Some(profile) = &cli_args.profile {
+        config = config.with_profile(Some(profile))?;
+    }
+    if let Some(install_directory) = cli_args.install_directory {
+        config.client.install_directory = Some(install_directory.to_string_lossy().into_owned());
+    }
+
+    let dry_run = match (cli_args.dry_run, cli_args.headers_only) {
+        (false, _) => None,
+        (true, false) => Some(PreviewMode::FullDownload),
+        (true, true) => Some(PreviewMode::HeadersOnly),
+    };
+
+    if cli_args.no_ui {
+        let command = if cli_args.rollback {
+            PatcherCommand::Rollback
+        } else if cli_args.repair {
+            PatcherCommand::Repair
+        } else if let Some(preview_mode) = dry_run {
+            PatcherCommand::Preview(preview_mode)
+        } else {
+            PatcherCommand::StartUpdate
+        };
+        let exit_code = run_without_ui(command, config)?;
+        std::process::exit(exit_code);
+    }
+    if cli_args.ipc {
+        return run_with_ipc(config);
+    }
 
     // Create a channel to allow the webview's thread to communicate with the patching thread
     let (tx, rx) = flume::bounded(32);
+    if cli_args.rollback {
+        tx.send(PatcherCommand::Rollback)
+            .with_context(|| "Failed to request a rollback")?;
+    }
+    if cli_args.repair {
+        tx.send(PatcherCommand::Repair)
+            .with_context(|| "Failed to request a repair")?;
+    }
     let window_title = config.window.title.clone();
-    let webview = ui::build_webview(
-        window_title.as_str(),
-        WebViewUserData::new(config.clone(), tx),
-    )
-    .with_context(|| "Failed to build a web view")?;
+    let user_data = WebViewUserData::new(config.clone(), tx, dry_run);
+    #[cfg(not(feature = "wry"))]
+    let mut webview = ui::build_webview(window_title.as_str(), user_data)
+        .with_context(|| "Failed to build a web view")?;
+    #[cfg(feature = "wry")]
+    let mut webview = wry_ui::build_webview(window_title.as_str(), user_data)
+        .with_context(|| "Failed to build a web view")?;
+    if config.window.tray {
+        if let Err(e) = ui::minimize_to_tray(&mut webview) {
+            log::warn!("Failed to minimize to the tray: {:#}", e);
+        }
+    }
+    if let Some(link) = &cli_args.deep_link {
+        match DeepLink::parse(link) {
+            Err(e) => log::warn!("Ignoring deep link: {:#}", e),
+            Ok(deep_link) => ui::open_deep_link(&mut webview, &deep_link),
+        }
+    }
 
     // Spawn a patching thread
-    let patching_thread = new_patching_thread(rx, UiController::new(&webview), config);
+    let patching_thread = new_patching_thread(
+        rx,
+        UiController::new(WebViewFrontend::new(&webview)),
+        config,
+    );
     webview
         .run()
         .with_context(|| "Failed to run the web view")?;
@@ -82,6 +254,103 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Runs a single command on the current thread, reports its progress on the
+/// standard output and returns the patcher's exit code.
+fn run_without_ui(command: PatcherCommand, config: PatcherConfiguration) -> Result<i32> {
+    let console_ui = ConsoleUi::new();
+    // Note: The sender is kept alive so that the command isn't interrupted
+    let (_tx, rx) = flume::bounded(32);
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_context(|| "Failed to build a tokio runtime")?;
+    tokio_rt.block_on(run_patcher_command(
+        command,
+        UiController::new(console_ui.clone()),
+        config,
+        rx,
+    ));
+
+    Ok(console_ui.exit_code())
+}
+
+/// Runs the patching thread without the web view, with the clients of the
+/// IPC endpoint as its frontend.
+fn run_with_ipc(config: PatcherConfiguration) -> Result<()> {
+    let ipc_config = config
+        .ipc
+        .clone()
+        .with_context(|| "The 'ipc' section of the configuration is missing")?;
+    let (tx, rx) = flume::bounded(32);
+    let frontend = IpcFrontend::new(tx.clone());
+    ipc::start_ipc_server(&ipc_config, frontend.clone(), tx)
+        .with_context(|| "Failed to start the IPC server")?;
+    let patching_thread = new_patching_thread(rx, UiController::new(frontend), config);
+    patching_thread
+        .join()
+        .map_err(|_| anyhow!("Failed to join patching thread"))?
+        .with_context(|| "Patching thread ran into an error")?;
+
+    Ok(())
+}
+
+/// Prints which patch wrote the file located at `location`, according to the
+/// installed files database.
+fn print_installed_file(location: &str) -> Result<()> {
+    let installed_files = get_installed_files_path()
+        .and_then(InstalledFiles::load)
+        .with_context(|| "Failed to load the installed files database")?;
+    match installed_files.find(location) {
+        None => println!("'{}' hasn't been written by a patch", location),
+        Some(installed_file) => println!(
+            "{}: written by '{}' (index {}), SHA-256: {}",
+            installed_file.display_path(),
+            installed_file.patch_name,
+            installed_file.patch_index,
+            installed_file.sha256.as_deref().unwrap_or("unknown")
+        ),
+    }
+    Ok(())
+}
+
+/// Prints the last `limit` patches of the history (all of them if `None`).
+fn print_history(limit: Option<usize>) -> Result<()> {
+    let history = get_history_file_path()
+        .and_then(|history_file_path| read_history(history_file_path, limit))
+        .with_context(|| "Failed to read the patch history")?;
+    if history.is_empty() {
+        println!("No patch has been processed yet");
+    }
+    for record in history {
+        println!(
+            "{}  {:<7}  {} (index {}, {} bytes, {} ms){}",
+            format_timestamp(record.timestamp),
+            format!("{:?}", record.outcome).to_lowercase(),
+            record.name,
+            record.index,
+            record
+                .size
+                .map_or_else(|| "?".to_string(), |size| size.to_string()),
+            record.duration_ms,
+            record
+                .reason
+                .map_or_else(String::new, |reason| format!(": {}", reason))
+        );
+    }
+    Ok(())
+}
+
+/// Attaches the patcher to the console it was started from, which GUI
+/// applications don't have on Windows.
+fn attach_console() {
+    #[cfg(windows)]
+    {
+        use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+        // Note: This fails if the patcher wasn't started from a console
+        unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+    }
+}
+
 /// Spawns a new thread that runs a single threaded tokio runtime to execute the patcher routine
 fn new_patching_thread(
     rx: flume::Receiver<PatcherCommand>,
@@ -0,0 +1,85 @@
+use serde::Serialize;
+
+use crate::PKG_VERSION;
+
+/// Version and build information of the patcher, displayed by the UI.
+#[derive(Serialize, Debug)]
+pub struct PatcherInfo {
+    pub version: &'static str,
+    pub git_hash: Option<&'static str>, // Commit the patcher was built from, if known
+    pub os: &'static str,
+    pub arch: &'static str,
+}
+
+/// Changelog of the patcher, published by the server.
+#[derive(Serialize, Debug)]
+pub struct PatcherChangelog {
+    pub content: String,
+    pub latest_version: Option<String>, // Most recent version listed in the changelog
+    pub update_available: bool,         // Whether `latest_version` is newer than this patcher
+}
+
+/// Returns the version and build information of this patcher.
+pub fn patcher_info() -> PatcherInfo {
+    PatcherInfo {
+        version: PKG_VERSION,
+        // Note: Set by build.rs when building from a git repository
+        git_hash: option_env!("RPATCHUR_GIT_HASH"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+    }
+}
+
+/// Returns the most recent version listed in a changelog, that is the version
+/// found in its first heading that contains one (e.g. "## [1.4.2] - 2021-06-01"
+/// or "# v1.4.2"). Headings without a version, such as "Unreleased", are
+/// ignored.
+pub fn latest_changelog_version(changelog: &str) -> Option<String> {
+    changelog
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix('#'))
+        .flat_map(|heading| heading.split(|c: char| c.is_whitespace() || "[]()#".contains(c)))
+        .find(|word| is_version(word))
+        .map(|version| version.to_string())
+}
+
+/// Indicates whether `word` looks like a version number (e.g. "1.4.2",
+/// "v1.4.2" or "1.5.0-beta").
+fn is_version(word: &str) -> bool {
+    let numbers = word.strip_prefix('v').unwrap_or(word);
+    let numbers = numbers.split(['-', '+']).next().unwrap_or_default();
+    numbers.contains('.')
+        && numbers
+            .split('.')
+            .all(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_changelog_version() {
+        let changelog = "# Changelog\n\
+                         All notable changes to this project will be documented in this file.\n\
+                         \n\
+                         ## [Unreleased]\n\
+                         ### Added\n\
+                         - Support for version 2.0 of the patch format.\n\
+                         \n\
+                         ## [1.4.2] - 2021-06-01\n\
+                         ### Fixed\n\
+                         - Crash on startup.\n\
+                         \n\
+                         ## [1.4.1] - 2021-05-20\n";
+        assert_eq!(
+            latest_changelog_version(changelog),
+            Some("1.4.2".to_string())
+        );
+        assert_eq!(
+            latest_changelog_version("# v2.0.0-beta\n- New UI\n# v1.9\n"),
+            Some("v2.0.0-beta".to_string())
+        );
+        assert_eq!(latest_changelog_version("# Changelog\n- 1.0.0\n"), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// File modified during a patching session, along with where its previous
+/// version has been saved.
+#[derive(Serialize, Deserialize)]
+struct BackedUpFile {
+    path: PathBuf,
+    backup_file_name: Option<String>, // `None` if the file didn't exist
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct BackupManifest {
+    files: Vec<BackedUpFile>,
+}
+
+/// Previous versions of the files modified during a patching session, saved
+/// so that the session can be rolled back.
+pub struct BackupSession {
+    directory: PathBuf,
+    manifest: BackupManifest,
+    saved_paths: HashSet<PathBuf>,
+}
+
+impl BackupSession {
+    /// Starts a session backed up into `directory`. The backup of the
+    /// previous session, if any, is discarded.
+    pub fn create(directory: impl Into<PathBuf>) -> Result<Self> {
+        let directory = directory.into();
+        if directory.exists() {
+            fs::remove_dir_all(&directory).with_context(|| {
+                format!("Failed to remove previous backup '{}'", directory.display())
+            })?;
+        }
+        fs::create_dir_all(&directory).with_context(|| {
+            format!(
+                "Failed to create backup directory '{}'",
+                directory.display()
+            )
+        })?;
+        Ok(Self {
+            directory,
+            manifest: BackupManifest::default(),
+            saved_paths: HashSet::new(),
+        })
+    }
+
+    /// Saves the current version of the file located at `path` (or the fact
+    /// that it doesn't exist), before it gets modified. Only the first
+    /// version saved during the session is kept.
+    pub fn save_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.save(path.as_ref(), false)
+    }
+
+    /// Same as `save_file`, for files that get replaced with a new file
+    /// instead of being modified. Their backup shares their content when
+    /// possible, instead of being a copy.
+    pub fn save_replaced_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.save(path.as_ref(), true)
+    }
+
+    fn save(&mut self, path: &Path, is_replaced: bool) -> Result<()> {
+        let path = std::env::current_dir()?.join(path);
+        if !self.saved_paths.insert(path.clone()) {
+            return Ok(());
+        }
+        let backup_file_name = if path.is_file() {
+            let backup_file_name = self.manifest.files.len().to_string();
+            let backup_file_path = self.directory.join(&backup_file_name);
+            let is_linked = is_replaced && fs::hard_link(&path, &backup_file_path).is_ok();
+            if !is_linked {
+                fs::copy(&path, &backup_file_path)
+                    .with_context(|| format!("Failed to back up '{}'", path.display()))?;
+            }
+            Some(backup_file_name)
+        } else {
+            None
+        };
+        self.manifest.files.push(BackedUpFile {
+            path,
+            backup_file_name,
+        });
+        Ok(())
+    }
+
+    /// Writes the list of the saved files. The backup can only be rolled back
+    /// once committed.
+    pub fn commit(self) -> Result<()> {
+        let manifest_file = File::create(self.directory.join(MANIFEST_FILE_NAME))?;
+        serde_json::to_writer(manifest_file, &self.manifest)
+            .context("Failed to serialize backup manifest")
+    }
+}
+
+/// Restores the files saved in the backup located at `directory`, then
+/// removes the backup. Files that didn't exist before the session are
+/// removed.
+///
+/// Returns the number of files restored or removed.
+pub fn rollback_session(directory: impl AsRef<Path>) -> Result<usize> {
+    let directory = directory.as_ref();
+    let manifest_file = match File::open(directory.join(MANIFEST_FILE_NAME)) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(anyhow::anyhow!("There is no patching session to roll back"))
+        }
+        result => result?,
+    };
+    let manifest: BackupManifest = serde_json::from_reader(BufReader::new(manifest_file))
+        .context("Failed to deserialize backup manifest")?;
+    for file in manifest.files.iter().rev() {
+        match &file.backup_file_name {
+            Some(backup_file_name) => {
+                let backup_file_path = directory.join(backup_file_name);
+                if let Some(parent_dir) = file.path.parent() {
+                    fs::create_dir_all(parent_dir)?;
+                }
+                // Note: Backups may be located on another file system
+                if fs::rename(&backup_file_path, &file.path).is_err() {
+                    fs::copy(&backup_file_path, &file.path)
+                        .with_context(|| format!("Failed to restore '{}'", file.path.display()))?;
+                }
+            }
+            None => match fs::remove_file(&file.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to remove '{}'", file.path.display()))
+                }
+                _ => {}
+            },
+        }
+    }
+    fs::remove_dir_all(directory)?;
+    Ok(manifest.files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rollback_session() {
+        let temp_dir = tempdir().unwrap();
+        let game_dir = temp_dir.path().join("game");
+        let backup_dir = temp_dir.path().join("backup");
+        fs::create_dir_all(game_dir.join("data")).unwrap();
+        fs::write(game_dir.join("data.grf"), b"original grf").unwrap();
+        fs::write(game_dir.join("data/modified.txt"), b"original").unwrap();
+        fs::write(game_dir.join("data/removed.txt"), b"removed").unwrap();
+        {
+            let mut session = BackupSession::create(&backup_dir).unwrap();
+            session
+                .save_replaced_file(game_dir.join("data.grf"))
+                .unwrap();
+            session
+                .save_file(game_dir.join("data/modified.txt"))
+                .unwrap();
+            session
+                .save_file(game_dir.join("data/removed.txt"))
+                .unwrap();
+            session.save_file(game_dir.join("data/new.txt")).unwrap();
+            session.commit().unwrap();
+        }
+        // Patch the game
+        fs::write(game_dir.join("data.grf.new"), b"patched grf").unwrap();
+        fs::rename(game_dir.join("data.grf.new"), game_dir.join("data.grf")).unwrap();
+        fs::write(game_dir.join("data/modified.txt"), b"patched").unwrap();
+        fs::remove_file(game_dir.join("data/removed.txt")).unwrap();
+        fs::write(game_dir.join("data/new.txt"), b"new").unwrap();
+
+        assert_eq!(rollback_session(&backup_dir).unwrap(), 4);
+
+        assert_eq!(
+            fs::read(game_dir.join("data.grf")).unwrap(),
+            b"original grf"
+        );
+        assert_eq!(
+            fs::read(game_dir.join("data/modified.txt")).unwrap(),
+            b"original"
+        );
+        assert_eq!(
+            fs::read(game_dir.join("data/removed.txt")).unwrap(),
+            b"removed"
+        );
+        assert!(!game_dir.join("data/new.txt").exists());
+        assert!(!backup_dir.exists());
+        // Sessions can only be rolled back once
+        assert!(rollback_session(&backup_dir).is_err());
+    }
+}
@@ -1,23 +1,404 @@
-use std::fs::File;
-use std::path::Path;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::ThorPatchInfo;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::installed_files::sha256_hex;
+
+/// Version of the cache format written by this patcher.
+///
+/// Note: Fields with a default value can be added without changing it. It
+/// must be bumped, along with a new step in `migrate_cache`, when existing
+/// fields are renamed, moved or reinterpreted.
+const CACHE_FORMAT_VERSION: u32 = 2;
+/// Version of the caches written before the format was versioned
+const LEGACY_CACHE_FORMAT_VERSION: u32 = 1;
+/// Maximum number of patches remembered in `PatcherCache::applied_patches`
+const MAX_APPLIED_PATCHES: usize = 64;
+/// Field of the cache file holding the checksum of the rest of the cache
+const CHECKSUM_FIELD: &str = "checksum";
+/// Suffix of the copy of the last valid cache, used if the cache gets corrupted
+const BACKUP_FILE_SUFFIX: &str = "bak";
+/// Suffix of the file the cache is written to before replacing the current one
+const TEMPORARY_FILE_SUFFIX: &str = "tmp";
 
 #[derive(Serialize, Deserialize)]
 pub struct PatcherCache {
+    pub version: u32, // Format of the cache, see `CACHE_FORMAT_VERSION`
     pub last_patch_index: usize,
+    #[serde(default)]
+    pub patch_list_validators: Option<PatchListValidators>, // Set once an update is complete
+    #[serde(default)]
+    pub applied_patches: Vec<AppliedPatch>, // Last patches applied, oldest first
+}
+
+impl PatcherCache {
+    /// Returns the cache of a client whose last applied patch is
+    /// `patch_info`.
+    pub fn new(patch_info: &ThorPatchInfo) -> Self {
+        let mut patcher_cache = Self {
+            version: CACHE_FORMAT_VERSION,
+            last_patch_index: patch_info.index,
+            patch_list_validators: None,
+            applied_patches: vec![],
+        };
+        patcher_cache.record_applied_patch(patch_info);
+        patcher_cache
+    }
+
+    /// Records that `patch_info` has been applied.
+    ///
+    /// Re-released patches (see `is_rereleased`) don't move the last patch
+    /// index back.
+    pub fn record_applied_patch(&mut self, patch_info: &ThorPatchInfo) {
+        let is_reapplied = patch_info.index < self.last_patch_index
+            && self
+                .applied_patches
+                .iter()
+                .any(|applied_patch| applied_patch.index == patch_info.index);
+        if !is_reapplied {
+            self.last_patch_index = patch_info.index;
+        }
+        self.applied_patches
+            .retain(|applied_patch| applied_patch.index != patch_info.index);
+        self.applied_patches.push(AppliedPatch {
+            index: patch_info.index,
+            file_name: patch_info.file_name.clone(),
+            hash: patch_info.hash.clone(),
+        });
+        if self.applied_patches.len() > MAX_APPLIED_PATCHES {
+            let excess = self.applied_patches.len() - MAX_APPLIED_PATCHES;
+            self.applied_patches.drain(..excess);
+        }
+    }
+
+    /// Indicates whether a listed patch has been applied before and
+    /// re-released since, that is with the same index but under another file
+    /// name or with another hash.
+    pub fn is_rereleased(&self, patch_info: &ThorPatchInfo) -> bool {
+        self.applied_patches
+            .iter()
+            .find(|applied_patch| applied_patch.index == patch_info.index)
+            .map_or(false, |applied_patch| {
+                let is_hash_different = match (&applied_patch.hash, &patch_info.hash) {
+                    (Some(applied_hash), Some(hash)) => !applied_hash.eq_ignore_ascii_case(hash),
+                    _ => false,
+                };
+                applied_patch.file_name != patch_info.file_name || is_hash_different
+            })
+    }
 }
 
+/// Identity of a patch that has been applied.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AppliedPatch {
+    pub index: usize,
+    pub file_name: String,
+    #[serde(default)]
+    pub hash: Option<String>, // Hash announced by the patch list, if any
+}
+
+/// HTTP validators of a patch list, used to only process the list again once
+/// it's been modified.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PatchListValidators {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Reads the cache stored at `cache_file_path`.
+///
+/// If the cache is corrupted (e.g. truncated by a crash), the copy of the
+/// last valid cache is used instead and restored.
 pub async fn read_cache_file(cache_file_path: impl AsRef<Path>) -> Result<PatcherCache> {
+    let cache_file_path = cache_file_path.as_ref();
+    let err = match read_cache(cache_file_path) {
+        Ok(patcher_cache) => return Ok(patcher_cache),
+        Err(e) => e,
+    };
+    // Note: Missing caches aren't corrupted, they may have been reset
+    if !cache_file_path.exists() {
+        return Err(err);
+    }
+    log::warn!("Patcher cache is corrupted: {:#}", err);
+    let backup_file_path = sibling_file_path(cache_file_path, BACKUP_FILE_SUFFIX);
+    let patcher_cache = read_cache(&backup_file_path).context("Failed to recover patcher cache")?;
+    log::info!(
+        "Recovered patcher cache from '{}'",
+        backup_file_path.display()
+    );
+    if let Err(e) = fs::copy(&backup_file_path, cache_file_path) {
+        log::warn!("Failed to restore patcher cache: {}", e);
+    }
+    Ok(patcher_cache)
+}
+
+fn read_cache(cache_file_path: &Path) -> Result<PatcherCache> {
     let file = File::open(cache_file_path)?;
-    serde_json::from_reader(file).context("Failed to deserialize patcher cache")
+    let patcher_cache = serde_json::from_reader(BufReader::new(file))
+        .context("Failed to deserialize patcher cache")?;
+    verify_checksum(&patcher_cache)?;
+    parse_cache(patcher_cache)
+}
+
+/// Checks that a cache hasn't been altered since it was written.
+///
+/// Note: Caches written by previous versions have no checksum.
+fn verify_checksum(patcher_cache: &Value) -> Result<()> {
+    let checksum = match patcher_cache.get(CHECKSUM_FIELD) {
+        None => return Ok(()),
+        Some(checksum) => checksum
+            .as_str()
+            .context("Invalid patcher cache checksum")?,
+    };
+    if !checksum.eq_ignore_ascii_case(&cache_checksum(patcher_cache)?) {
+        return Err(anyhow!("Patcher cache checksum mismatch"));
+    }
+    Ok(())
 }
 
+/// Returns the hex-encoded SHA-256 hash of a cache, without its checksum.
+///
+/// Note: Objects are serialized with sorted keys, which doesn't depend on the
+/// order of the fields in the file.
+fn cache_checksum(patcher_cache: &Value) -> Result<String> {
+    let mut patcher_cache = patcher_cache.clone();
+    if let Some(fields) = patcher_cache.as_object_mut() {
+        fields.remove(CHECKSUM_FIELD);
+    }
+    let content =
+        serde_json::to_vec(&patcher_cache).context("Failed to serialize patcher cache")?;
+    sha256_hex(content.as_slice())
+}
+
+/// Reads a cache written in any format, migrating it to the current one.
+///
+/// Caches written by newer patchers are read as well as possible: unknown
+/// fields are ignored and the cache is written back in the current format.
+fn parse_cache(mut patcher_cache: Value) -> Result<PatcherCache> {
+    if !patcher_cache.is_object() {
+        return Err(anyhow!("Invalid patcher cache"));
+    }
+    let version = match patcher_cache.get("version") {
+        None => LEGACY_CACHE_FORMAT_VERSION,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .context("Invalid patcher cache version")?,
+    };
+    if version > CACHE_FORMAT_VERSION {
+        log::warn!(
+            "Patcher cache was written in a newer format (version {}), some information might be lost",
+            version
+        );
+    }
+    for from_version in version..CACHE_FORMAT_VERSION {
+        log::info!(
+            "Migrating patcher cache from version {} to {}",
+            from_version,
+            from_version + 1
+        );
+        migrate_cache(&mut patcher_cache, from_version)?;
+    }
+    patcher_cache["version"] = CACHE_FORMAT_VERSION.into();
+    serde_json::from_value(patcher_cache).context("Failed to deserialize patcher cache")
+}
+
+/// Migrates a cache from format `from_version` to the next one.
+fn migrate_cache(patcher_cache: &mut Value, from_version: u32) -> Result<()> {
+    match from_version {
+        // Version 2 only introduced the `version` field, fields added to the
+        // legacy format since then all have default values
+        LEGACY_CACHE_FORMAT_VERSION => {}
+        _ => return Err(anyhow!("Unknown patcher cache version {}", from_version)),
+    }
+    patcher_cache["version"] = (from_version + 1).into();
+    Ok(())
+}
+
+/// Writes `new_cache` to `cache_file_path`, along with its checksum.
+///
+/// The cache is written to a temporary file that then replaces the current
+/// one, which is kept as a backup if it's valid.
 pub async fn write_cache_file(
     cache_file_path: impl AsRef<Path>,
     new_cache: PatcherCache,
 ) -> Result<()> {
-    let file = File::create(cache_file_path)?;
-    serde_json::to_writer(file, &new_cache).context("Failed to serialize patcher cache")
+    let cache_file_path = cache_file_path.as_ref();
+    let mut patcher_cache =
+        serde_json::to_value(&new_cache).context("Failed to serialize patcher cache")?;
+    patcher_cache[CHECKSUM_FIELD] = cache_checksum(&patcher_cache)?.into();
+
+    let temporary_file_path = sibling_file_path(cache_file_path, TEMPORARY_FILE_SUFFIX);
+    let mut writer = BufWriter::new(File::create(&temporary_file_path)?);
+    serde_json::to_writer(&mut writer, &patcher_cache)
+        .context("Failed to serialize patcher cache")?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    if read_cache(cache_file_path).is_ok() {
+        let backup_file_path = sibling_file_path(cache_file_path, BACKUP_FILE_SUFFIX);
+        if let Err(e) = fs::copy(cache_file_path, &backup_file_path) {
+            log::warn!("Failed to back up patcher cache: {}", e);
+        }
+    }
+    fs::rename(&temporary_file_path, cache_file_path)?;
+    Ok(())
+}
+
+/// Removes the cache stored at `cache_file_path`, along with its backup.
+pub fn remove_cache_file(cache_file_path: impl AsRef<Path>) -> io::Result<()> {
+    let cache_file_path = cache_file_path.as_ref();
+    match fs::remove_file(sibling_file_path(cache_file_path, BACKUP_FILE_SUFFIX)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::remove_file(cache_file_path)
+}
+
+/// Returns the path of a file stored next to the cache (e.g. 'rpatchur.dat.bak'
+/// for the "bak" suffix).
+fn sibling_file_path(cache_file_path: &Path, suffix: &str) -> PathBuf {
+    let mut file_path = OsString::from(cache_file_path.as_os_str());
+    file_path.push(".");
+    file_path.push(suffix);
+    PathBuf::from(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn patch_info(index: usize, file_name: &str, hash: Option<&str>) -> ThorPatchInfo {
+        ThorPatchInfo {
+            index,
+            file_name: file_name.to_string(),
+            hash: hash.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_cache() {
+        // Legacy caches
+        let patcher_cache = parse_cache(serde_json::json!({"last_patch_index": 12})).unwrap();
+        assert_eq!(patcher_cache.version, CACHE_FORMAT_VERSION);
+        assert_eq!(patcher_cache.last_patch_index, 12);
+        assert!(patcher_cache.patch_list_validators.is_none());
+        assert!(patcher_cache.applied_patches.is_empty());
+
+        let patcher_cache = PatcherCache::new(&patch_info(3, "patch3.thor", Some("aa")));
+        let patcher_cache = parse_cache(serde_json::to_value(patcher_cache).unwrap()).unwrap();
+        assert_eq!(patcher_cache.last_patch_index, 3);
+        assert_eq!(patcher_cache.applied_patches.len(), 1);
+
+        // Caches written by newer patchers
+        let patcher_cache = parse_cache(serde_json::json!({
+            "version": CACHE_FORMAT_VERSION + 1,
+            "last_patch_index": 7,
+            "last_update_time": 1621234567
+        }))
+        .unwrap();
+        assert_eq!(patcher_cache.version, CACHE_FORMAT_VERSION);
+        assert_eq!(patcher_cache.last_patch_index, 7);
+
+        assert!(parse_cache(serde_json::json!({"version": "2", "last_patch_index": 7})).is_err());
+        assert!(parse_cache(serde_json::json!({"version": 0, "last_patch_index": 7})).is_err());
+        assert!(parse_cache(serde_json::json!([12])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_file_recovery() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache_file_path = temp_dir.path().join("rpatchur.dat");
+        let backup_file_path = temp_dir.path().join("rpatchur.dat.bak");
+        assert!(read_cache_file(&cache_file_path).await.is_err());
+
+        write_cache_file(
+            &cache_file_path,
+            PatcherCache::new(&patch_info(1, "1.thor", None)),
+        )
+        .await
+        .unwrap();
+        assert!(!backup_file_path.exists());
+        write_cache_file(
+            &cache_file_path,
+            PatcherCache::new(&patch_info(2, "2.thor", None)),
+        )
+        .await
+        .unwrap();
+        assert!(!temp_dir.path().join("rpatchur.dat.tmp").exists());
+        let patcher_cache = read_cache_file(&cache_file_path).await.unwrap();
+        assert_eq!(patcher_cache.last_patch_index, 2);
+
+        // Truncated caches are recovered from the backup
+        let content = fs::read(&cache_file_path).unwrap();
+        fs::write(&cache_file_path, &content[..content.len() / 2]).unwrap();
+        let patcher_cache = read_cache_file(&cache_file_path).await.unwrap();
+        assert_eq!(patcher_cache.last_patch_index, 1);
+        assert_eq!(read_cache(&cache_file_path).unwrap().last_patch_index, 1);
+
+        // Altered caches as well
+        write_cache_file(
+            &cache_file_path,
+            PatcherCache::new(&patch_info(3, "3.thor", None)),
+        )
+        .await
+        .unwrap();
+        let content = fs::read_to_string(&cache_file_path).unwrap();
+        fs::write(
+            &cache_file_path,
+            content.replace("\"last_patch_index\":3", "\"last_patch_index\":30"),
+        )
+        .unwrap();
+        let patcher_cache = read_cache_file(&cache_file_path).await.unwrap();
+        assert_eq!(patcher_cache.last_patch_index, 1);
+
+        // Caches written by previous versions have no checksum
+        fs::write(&cache_file_path, "{\"last_patch_index\": 12}").unwrap();
+        let patcher_cache = read_cache_file(&cache_file_path).await.unwrap();
+        assert_eq!(patcher_cache.last_patch_index, 12);
+
+        // The backup isn't used once the cache has been removed
+        remove_cache_file(&cache_file_path).unwrap();
+        assert!(!backup_file_path.exists());
+        assert!(read_cache_file(&cache_file_path).await.is_err());
+    }
+
+    #[test]
+    fn test_rereleased_patches() {
+        // Caches written by previous versions don't list applied patches
+        let patcher_cache = parse_cache(serde_json::json!({"last_patch_index": 2})).unwrap();
+        assert!(!patcher_cache.is_rereleased(&patch_info(2, "patch2.thor", None)));
+
+        let mut patcher_cache = PatcherCache::new(&patch_info(1, "patch1.thor", Some("aa")));
+        patcher_cache.record_applied_patch(&patch_info(2, "patch2.thor", None));
+        assert_eq!(patcher_cache.last_patch_index, 2);
+        assert!(!patcher_cache.is_rereleased(&patch_info(1, "patch1.thor", Some("AA"))));
+        assert!(!patcher_cache.is_rereleased(&patch_info(1, "patch1.thor", None)));
+        assert!(patcher_cache.is_rereleased(&patch_info(1, "patch1.thor", Some("bb"))));
+        assert!(patcher_cache.is_rereleased(&patch_info(1, "patch1_fix.thor", Some("aa"))));
+        assert!(!patcher_cache.is_rereleased(&patch_info(3, "patch3.thor", None)));
+
+        // Applying a re-released patch doesn't move the last patch index back
+        patcher_cache.record_applied_patch(&patch_info(1, "patch1.thor", Some("bb")));
+        assert_eq!(patcher_cache.last_patch_index, 2);
+        assert!(!patcher_cache.is_rereleased(&patch_info(1, "patch1.thor", Some("bb"))));
+        assert_eq!(patcher_cache.applied_patches.len(), 2);
+
+        for index in 3..100 {
+            patcher_cache.record_applied_patch(&patch_info(index, "patch.thor", None));
+        }
+        assert_eq!(patcher_cache.last_patch_index, 99);
+        assert_eq!(patcher_cache.applied_patches.len(), MAX_APPLIED_PATCHES);
+    }
 }
@@ -1,4 +1,11 @@
-use super::PatcherCommand;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant};
+
+use super::{LockedFileAction, PatcherCommand};
+use crate::ui::{PatchingStatus, UiController};
 
 pub type InterruptibleFnResult<T> = std::result::Result<T, InterruptibleFnError>;
 
@@ -7,36 +14,195 @@ pub enum InterruptibleFnError {
     Interrupted, // An interruption
 }
 
+/// Pause flag shared with the tasks of an update, which have to wait for it to
+/// be cleared before making progress.
+pub struct PauseState {
+    tx: watch::Sender<bool>,
+    rx: watch::Receiver<bool>,
+}
+
+impl PauseState {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self { tx, rx }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    fn set_paused(&self, paused: bool, ui_controller: &UiController) {
+        if self.is_paused() == paused {
+            return;
+        }
+        // Cannot fail since we own a receiver
+        let _ = self.tx.send(paused);
+        if paused {
+            log::info!("Patching paused");
+            ui_controller.dispatch_patching_status(PatchingStatus::Paused);
+        } else {
+            log::info!("Patching resumed");
+            ui_controller.dispatch_patching_status(PatchingStatus::Resumed);
+        }
+    }
+
+    /// Returns immediately if the update isn't paused, waits for it to be
+    /// resumed otherwise.
+    pub async fn wait_while_paused(&self) {
+        let mut rx = self.rx.clone();
+        while *rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Waits for the update to be canceled, while processing pause and resume
+/// commands.
 pub async fn wait_for_cancellation(
     patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
 ) -> InterruptibleFnError {
-    if let Ok(cmd) = patching_thread_rx.recv_async().await {
-        match cmd {
-            PatcherCommand::CancelUpdate | PatcherCommand::Quit => {
-                InterruptibleFnError::Interrupted
-            }
-            _ => InterruptibleFnError::Err("Unexpected command received".to_string()),
+    loop {
+        match patching_thread_rx.recv_async().await {
+            Ok(cmd) => match cmd {
+                PatcherCommand::CancelUpdate | PatcherCommand::Quit => {
+                    return InterruptibleFnError::Interrupted;
+                }
+                PatcherCommand::PauseUpdate => pause_state.set_paused(true, ui_controller),
+                PatcherCommand::ResumeUpdate => pause_state.set_paused(false, ui_controller),
+                _ => return InterruptibleFnError::Err("Unexpected command received".to_string()),
+            },
+            Err(_) => return InterruptibleFnError::Err("Channel was closed".to_string()),
         }
-    } else {
-        InterruptibleFnError::Err("Channel was closed".to_string())
     }
 }
 
-pub fn process_incoming_commands(
+/// Processes pending commands.
+///
+/// If the update has been paused, this waits for it to be resumed (or
+/// canceled) before returning.
+pub async fn process_incoming_commands(
     patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
 ) -> InterruptibleFnResult<()> {
     match patching_thread_rx.try_recv() {
         Ok(cmd) => match cmd {
             PatcherCommand::CancelUpdate | PatcherCommand::Quit => {
-                Err(InterruptibleFnError::Interrupted)
+                return Err(InterruptibleFnError::Interrupted);
             }
-            _ => Ok(()),
+            PatcherCommand::PauseUpdate => pause_state.set_paused(true, ui_controller),
+            PatcherCommand::ResumeUpdate => pause_state.set_paused(false, ui_controller),
+            _ => {}
         },
         Err(e) => match e {
             flume::TryRecvError::Disconnected => {
-                Err(InterruptibleFnError::Err("Channel was closed".to_string()))
+                return Err(InterruptibleFnError::Err("Channel was closed".to_string()));
             }
-            flume::TryRecvError::Empty => Ok(()),
+            flume::TryRecvError::Empty => {}
         },
     }
+    if pause_state.is_paused() {
+        wait_for_resumption(patching_thread_rx, pause_state, ui_controller).await
+    } else {
+        Ok(())
+    }
+}
+
+/// Waits for a task running on tokio's blocking thread pool (e.g. the
+/// extraction of a patch) to complete, while processing incoming commands.
+///
+/// Such tasks cannot be interrupted without leaving files half-written (e.g. a
+/// GRF in the middle of a merge), so cancellation requests received in the
+/// meantime are returned along with the task's result, for the caller to
+/// handle once the task is done. The UI is told that the current task is being
+/// finished in the meantime.
+pub async fn wait_for_blocking_task<T>(
+    mut task: JoinHandle<anyhow::Result<T>>,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
+) -> (anyhow::Result<T>, Option<InterruptibleFnError>) {
+    let mut interruption = None;
+    let result = loop {
+        tokio::select! {
+            result = &mut task => break result,
+            cmd = patching_thread_rx.recv_async(), if interruption.is_none() => match cmd {
+                Ok(PatcherCommand::CancelUpdate) | Ok(PatcherCommand::Quit) => {
+                    log::info!("Update will be canceled once the current task is done");
+                    interruption = Some(InterruptibleFnError::Interrupted);
+                    ui_controller.dispatch_patching_status(PatchingStatus::Finishing);
+                }
+                Ok(PatcherCommand::PauseUpdate) => pause_state.set_paused(true, ui_controller),
+                Ok(PatcherCommand::ResumeUpdate) => pause_state.set_paused(false, ui_controller),
+                Ok(_) => {}
+                Err(_) => {
+                    interruption = Some(InterruptibleFnError::Err("Channel was closed".to_string()));
+                }
+            },
+        }
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => Err(anyhow::anyhow!("Blocking task failed: {}", e)),
+    };
+    (result, interruption)
+}
+
+/// Waits for the user to decide what to do with a locked file, while
+/// processing incoming commands. `is_unlocked` is polled every
+/// `poll_interval`, the patch is retried as soon as it returns true (e.g.
+/// once the game client has exited).
+pub async fn wait_for_locked_file_action(
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
+    poll_interval: Duration,
+    mut is_unlocked: impl FnMut() -> bool,
+) -> InterruptibleFnResult<LockedFileAction> {
+    let mut poll_timer = interval_at(Instant::now() + poll_interval, poll_interval);
+    loop {
+        tokio::select! {
+            cmd = patching_thread_rx.recv_async() => match cmd {
+                Ok(PatcherCommand::CancelUpdate) | Ok(PatcherCommand::Quit) => {
+                    return Err(InterruptibleFnError::Interrupted);
+                }
+                Ok(PatcherCommand::PauseUpdate) => pause_state.set_paused(true, ui_controller),
+                Ok(PatcherCommand::ResumeUpdate) => pause_state.set_paused(false, ui_controller),
+                Ok(PatcherCommand::ResolveLockedFile(action)) => return Ok(action),
+                Ok(_) => {}
+                Err(_) => return Err(InterruptibleFnError::Err("Channel was closed".to_string())),
+            },
+            _ = poll_timer.tick() => {
+                if !pause_state.is_paused() && is_unlocked() {
+                    return Ok(LockedFileAction::Retry);
+                }
+            }
+        }
+    }
+}
+
+async fn wait_for_resumption(
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
+) -> InterruptibleFnResult<()> {
+    loop {
+        match patching_thread_rx.recv_async().await {
+            Ok(cmd) => match cmd {
+                PatcherCommand::CancelUpdate | PatcherCommand::Quit => {
+                    return Err(InterruptibleFnError::Interrupted);
+                }
+                PatcherCommand::ResumeUpdate => {
+                    pause_state.set_paused(false, ui_controller);
+                    return Ok(());
+                }
+                _ => {}
+            },
+            Err(_) => return Err(InterruptibleFnError::Err("Channel was closed".to_string())),
+        }
+    }
 }
@@ -0,0 +1,137 @@
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Result};
+use gruf::grf::builder::compress_file_content;
+
+// Number of pending entries per worker, to limit memory usage
+const QUEUED_ENTRIES_PER_WORKER: usize = 2;
+
+/// Entry compressed by a `CompressionPool`, ready to be added to a GRF.
+pub struct CompressedEntry {
+    pub relative_path: String,
+    pub data_size: u64,
+    pub compressed_data: Vec<u8>,
+}
+
+/// Pool of threads that compress GRF entries in the background.
+///
+/// Entries are returned in the order in which they've been compressed, which
+/// may differ from the order in which they've been submitted.
+pub struct CompressionPool {
+    job_tx: Option<flume::Sender<(String, Vec<u8>)>>,
+    result_rx: flume::Receiver<Result<CompressedEntry>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl CompressionPool {
+    /// Starts a pool with one worker per available CPU.
+    pub fn new() -> Self {
+        let worker_count = thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1);
+        Self::with_worker_count(worker_count)
+    }
+
+    pub fn with_worker_count(worker_count: usize) -> Self {
+        let worker_count = worker_count.max(1);
+        let (job_tx, job_rx) =
+            flume::bounded::<(String, Vec<u8>)>(worker_count * QUEUED_ENTRIES_PER_WORKER);
+        // Note: Results are consumed by the thread submitting entries, which
+        // must never block workers
+        let (result_tx, result_rx) = flume::unbounded();
+        let workers = (0..worker_count)
+            .map(|_| {
+                let job_rx = job_rx.clone();
+                let result_tx = result_tx.clone();
+                thread::spawn(move || {
+                    for (relative_path, content) in job_rx.iter() {
+                        let result = compress_file_content(content.as_slice())
+                            .map(|(data_size, compressed_data)| CompressedEntry {
+                                relative_path,
+                                data_size,
+                                compressed_data,
+                            })
+                            .map_err(anyhow::Error::from);
+                        if result_tx.send(result).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            job_tx: Some(job_tx),
+            result_rx,
+            workers,
+        }
+    }
+
+    /// Queues an entry for compression. Blocks while the queue is full.
+    pub fn submit(&self, relative_path: String, content: Vec<u8>) -> Result<()> {
+        match &self.job_tx {
+            Some(job_tx) => job_tx
+                .send((relative_path, content))
+                .map_err(|_| anyhow!("Compression workers stopped unexpectedly")),
+            None => Err(anyhow!("Compression pool is stopped")),
+        }
+    }
+
+    /// Returns the entries compressed so far, without blocking.
+    pub fn completed_entries(&self) -> impl Iterator<Item = Result<CompressedEntry>> + '_ {
+        self.result_rx.try_iter()
+    }
+
+    /// Waits for the remaining entries to be compressed and returns them.
+    pub fn finish(mut self) -> Vec<Result<CompressedEntry>> {
+        self.stop();
+        self.result_rx.try_iter().collect()
+    }
+
+    fn stop(&mut self) {
+        // Workers stop once the queue is empty and closed
+        self.job_tx = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for CompressionPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::collections::HashMap;
+    use std::io::Read;
+
+    #[test]
+    fn test_compression_pool() {
+        let pool = CompressionPool::with_worker_count(3);
+        let entries: HashMap<String, Vec<u8>> = (0..20)
+            .map(|i| (format!("data\\file{}.bin", i), vec![i as u8; 1000 * i]))
+            .collect();
+        let mut compressed_entries = vec![];
+        for (relative_path, content) in &entries {
+            pool.submit(relative_path.clone(), content.clone()).unwrap();
+            compressed_entries.extend(pool.completed_entries());
+        }
+        compressed_entries.extend(pool.finish());
+
+        assert_eq!(compressed_entries.len(), entries.len());
+        for compressed_entry in compressed_entries {
+            let compressed_entry = compressed_entry.unwrap();
+            let expected_content = &entries[&compressed_entry.relative_path];
+            assert_eq!(compressed_entry.data_size, expected_content.len() as u64);
+            let mut content = vec![];
+            ZlibDecoder::new(compressed_entry.compressed_data.as_slice())
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(&content, expected_content);
+        }
+    }
+}
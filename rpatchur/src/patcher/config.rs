@@ -1,10 +1,109 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
 use super::get_patcher_name;
-use anyhow::{Context, Result};
+use super::source::parse_source_url;
+use crate::PKG_VERSION;
+use anyhow::{anyhow, Context, Result};
+use gruf::match_entry_path;
 use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use url::Url;
+
+/// Schemes of the URLs patches, patch lists and documents are fetched from
+const SOURCE_URL_SCHEMES: &[&str] = &["http", "https", "file"];
+/// Schemes of the proxies supported by the HTTP client
+const PROXY_URL_SCHEMES: &[&str] = &["http", "https", "socks5", "socks5h"];
+/// Prefix of the environment variables that override the configuration
+/// (e.g. 'RPATCHUR_WEB__INDEX_URL')
+const ENV_OVERRIDE_PREFIX: &str = "RPATCHUR_";
+/// Separator of the fields' names in the environment variables' names
+const ENV_OVERRIDE_SEPARATOR: &str = "__";
+/// Top-level fields of the configuration, which the environment variables
+/// overriding it must start with
+const CONFIGURATION_SECTIONS: &[&str] = &[
+    "window",
+    "play",
+    "setup",
+    "web",
+    "client",
+    "patching",
+    "proxy",
+    "channels",
+    "hooks",
+    "profiles",
+    "language",
+    "ipc",
+    "use_data_directory",
+    "remote_config_url",
+];
+/// Name of the default profile, given to '{profile}'
+const DEFAULT_PROFILE_NAME: &str = "default";
+/// Examples of valid values, reported along with invalid fields. '[]' and '*'
+/// stand for any index of a list and any key of a map.
+const FIELD_EXAMPLES: &[(&str, &str)] = &[
+    ("window.title", "RPatchur"),
+    ("window.width", "780"),
+    ("window.height", "580"),
+    ("window.resizable", "false"),
+    ("play.path", "ragexe.exe"),
+    ("play.arguments", "[\"1sak1\"]"),
+    ("play.remember_credentials", "login"),
+    ("setup.path", "Setup.exe"),
+    ("setup.arguments", "[]"),
+    ("web.index_url", "https://myserver.com/index.html"),
+    (
+        "web.patch_servers",
+        "[{name: EU, plist_url: ..., patch_url: ...}]",
+    ),
+    ("web.patch_servers[].name", "EU Patch Server"),
+    (
+        "web.patch_servers[].plist_url",
+        "https://eu.myserver.com/plist.txt",
+    ),
+    (
+        "web.patch_servers[].patch_url",
+        "https://eu.myserver.com/data/",
+    ),
+    ("web.max_download_speed", "2048"),
+    ("web.connect_timeout", "30"),
+    ("web.read_timeout", "60"),
+    ("web.download_segments", "4"),
+    ("web.repair_url", "https://example.com/client/"),
+    ("web.update_check_interval", "30"),
+    ("web.news_url", "https://example.com/news.json"),
+    ("web.changelog_url", "https://example.com/CHANGELOG.md"),
+    ("client.default_grf_name", "myserver.grf"),
+    ("client.install_directory", "${HOME}/games/myserver"),
+    ("client.executables", "[ragexe.exe]"),
+    ("client.when_running", "prompt"),
+    ("client.on_ready", "launch"),
+    ("client.elevation", "helper"),
+    ("proxy.url", "socks5://127.0.0.1:1080"),
+    (
+        "channels.*.plist_url",
+        "https://beta.myserver.com/plist.txt",
+    ),
+    ("channels.*.patch_url", "https://beta.myserver.com/data/"),
+    (
+        "profiles.*.plist_url",
+        "https://classic.myserver.com/plist.txt",
+    ),
+    ("profiles.*.patch_url", "https://classic.myserver.com/data/"),
+    ("patching.in_place", "true"),
+    ("patching.in_place_max_size", "16"),
+    ("patching.check_integrity", "true"),
+    ("patching.create_grf", "true"),
+    ("patching.on_failure", "abort"),
+    ("patching.path_validation", "strict"),
+    ("patching.path_case", "sensitive"),
+    ("patching.name_encoding", "legacy"),
+    ("ipc.port", "8989"),
+    ("remote_config_url", "https://myserver.com/rpatchur.json"),
+];
 
 #[derive(Deserialize, Clone)]
 pub struct PatcherConfiguration {
@@ -14,6 +113,67 @@ pub struct PatcherConfiguration {
     pub web: WebConfiguration,
     pub client: ClientConfiguration,
     pub patching: PatchingConfiguration,
+    #[serde(default)]
+    pub proxy: ProxyConfiguration,
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelConfiguration>, // Release channels selectable at runtime
+    #[serde(default)]
+    pub hooks: HooksConfiguration, // Actions run before and after patching
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileConfiguration>, // Game clients selectable at runtime
+    #[serde(skip)]
+    pub profile: Option<String>, // Profile selected by the user, `None` for the default one
+    pub language: Option<String>, // Language of the messages (e.g. 'fr'), the OS's by default
+    pub ipc: Option<IpcConfiguration>, // Local endpoint used to drive the patcher with '--ipc'
+    pub use_data_directory: Option<bool>, // Store the cache, journals and logs in the user's data directory, `true` by default
+    pub remote_config_url: Option<String>, // URL of a configuration merged over this one at startup
+    #[serde(skip)]
+    pub overridden_fields: Vec<String>, // Fields set by environment variables or on the command line (e.g. 'web.patch_servers.0.plist_url')
+}
+
+impl PatcherConfiguration {
+    /// Indicates whether `field` (e.g. 'web.patch_servers'), or one of its
+    /// subfields, has been set by an environment variable or on the command
+    /// line.
+    pub fn is_overridden(&self, field: &str) -> bool {
+        self.overridden_fields.iter().any(|overridden_field| {
+            overridden_field == field
+                || (overridden_field.starts_with(field)
+                    && overridden_field[field.len()..].starts_with('.'))
+        })
+    }
+
+    /// Returns the configuration of the profile named `profile_name` (of the
+    /// default profile if `None`). Settings a profile doesn't override are
+    /// the same as the default profile's, whose configuration `self` must be.
+    pub fn with_profile(&self, profile_name: Option<&str>) -> Result<PatcherConfiguration> {
+        let mut config = self.clone();
+        if let Some(profile_name) = profile_name {
+            let profile = self
+                .profiles
+                .get(profile_name)
+                .ok_or_else(|| anyhow!("Unknown profile '{}'", profile_name))?;
+            if let Some(play_path) = &profile.play_path {
+                config.play.path = play_path.clone();
+            }
+            if let Some(default_grf_name) = &profile.default_grf_name {
+                config.client.default_grf_name = default_grf_name.clone();
+            }
+            if profile.install_directory.is_some() {
+                config.client.install_directory = profile.install_directory.clone();
+            }
+            config.web.patch_servers = vec![PatchServerInfo {
+                name: profile_name.to_string(),
+                plist_url: profile.plist_url.clone(),
+                patch_url: profile.patch_url.clone(),
+            }];
+            config.web.preferred_patch_server = None;
+            // Note: Channels are release channels of the default profile
+            config.channels.clear();
+        }
+        config.profile = profile_name.map(str::to_string);
+        Ok(config)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -22,6 +182,10 @@ pub struct WindowConfiguration {
     pub width: i32,
     pub height: i32,
     pub resizable: bool,
+    #[serde(default)]
+    pub tray: bool, // Start minimized to the system tray (icon on Windows only)
+    #[serde(default)]
+    pub notifications: bool, // Pop desktop notifications when patching completes, fails or is stuck
 }
 
 #[derive(Deserialize, Clone)]
@@ -29,6 +193,18 @@ pub struct PlayConfiguration {
     pub path: String,
     pub arguments: Vec<String>,
     pub exit_on_success: Option<bool>,
+    // Arguments passed before `arguments` when logging in, '{login}',
+    // '{password}' and '{token}' are replaced with the user's credentials
+    pub login_arguments: Option<Vec<String>>,
+    pub remember_credentials: Option<CredentialPolicy>, // What to remember from the last login
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialPolicy {
+    Nothing,
+    Login, // Only the user name
+    All,   // The user name and the password, stored in plain text
 }
 
 #[derive(Deserialize, Clone)]
@@ -42,7 +218,51 @@ pub struct SetupConfiguration {
 pub struct WebConfiguration {
     pub index_url: String, // URL of the index file implementing the UI
     pub preferred_patch_server: Option<String>, // Name of the patch server to use in priority
+    pub plist_public_key: Option<String>, // Base64-encoded ed25519 key used to verify patch lists
     pub patch_servers: Vec<PatchServerInfo>,
+    #[serde(default)]
+    pub retry: RetryConfiguration, // Retry policy for transient HTTP failures
+    pub max_download_speed: Option<u64>, // Download speed limit in KiB/s
+    pub connect_timeout: Option<u64>,    // Connection timeout in seconds
+    pub read_timeout: Option<u64>,       // Maximum time without receiving data, in seconds
+    pub download_segments: Option<usize>, // Number of connections used to download large patches
+    #[serde(default)]
+    pub keep_downloads: bool, // Keep downloaded patches until they're applied
+    pub torrent: Option<TorrentConfiguration>, // BitTorrent client used for patches with a torrent URL
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // Additional headers sent with every request
+    pub auth_token: Option<String>,            // Bearer token sent with every request
+    pub user_agent: Option<String>,            // User-Agent sent with every request
+    #[serde(default)]
+    pub tls: TlsConfiguration, // Verification of the servers' certificates
+    pub repair_url: Option<String>, // URL of the directory containing the client's manifest and pristine files
+    pub update_check_interval: Option<u64>, // Minutes between two background checks for new patches
+    #[serde(default)]
+    pub auto_update: bool, // Start updating when a background check finds new patches
+    pub news_url: Option<String>,   // URL of the news feed (JSON or RSS) displayed by the UI
+    pub changelog_url: Option<String>, // URL of the patcher's changelog, tells the UI about new versions
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IpcConfiguration {
+    pub port: u16, // Port of the WebSocket endpoint, which only accepts local connections
+    pub token: Option<String>, // Secret clients must give with '?token=', required for web pages
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TlsConfiguration {
+    pub ca_certificate: Option<String>, // Path to an additional root certificate (PEM or DER)
+    pub pinned_certificates: Vec<String>, // SHA-256 fingerprints of the only accepted certificates
+    pub pinned_public_keys: Vec<String>, // SHA-256 fingerprints of the only accepted public keys (DER-encoded SubjectPublicKeyInfo)
+    pub accept_invalid_certs: bool,      // Disable certificate verification (insecure)
+}
+
+impl TlsConfiguration {
+    /// Indicates whether only pinned certificates or public keys are accepted.
+    pub fn is_pinning_enabled(&self) -> bool {
+        !self.pinned_certificates.is_empty() || !self.pinned_public_keys.is_empty()
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -52,31 +272,1260 @@ pub struct PatchServerInfo {
     pub patch_url: String, // URL of the directory containing .thor files
 }
 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfiguration {
+    pub max_attempts: u32,     // Maximum number of attempts per request
+    pub initial_delay_ms: u64, // Delay before the first retry
+    pub max_delay_ms: u64,     // Upper bound for the delay between two attempts
+    pub jitter: bool,          // Randomize delays to avoid synchronized retries
+}
+
+impl Default for RetryConfiguration {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ChannelConfiguration {
+    pub plist_url: String, // URL of the channel's plist.txt file
+    pub patch_url: String, // URL of the directory containing the channel's .thor files
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ProfileConfiguration {
+    pub display_name: Option<String>, // Name shown by the UI, defaults to the profile's name
+    pub play_path: Option<String>,    // Game executable, overrides `play.path`
+    pub default_grf_name: Option<String>, // Overrides `client.default_grf_name`
+    pub install_directory: Option<String>, // Overrides `client.install_directory`
+    pub plist_url: String,            // URL of the profile's plist.txt file
+    pub patch_url: String,            // URL of the directory containing the profile's .thor files
+}
+
 #[derive(Deserialize, Clone)]
 pub struct ClientConfiguration {
     pub default_grf_name: String, // GRF file to patch by default
+    // Directory the game client is installed in, defaults to the working
+    // directory. Environment variables ('${VAR}' or '%VAR%') are expanded
+    pub install_directory: Option<String>,
+    #[serde(default)]
+    pub executables: Vec<String>, // Names of the client's executables, checked before patching
+    pub when_running: Option<RunningClientPolicy>, // What to do when the client is running before patching
+    pub on_ready: Option<ReadyAction>, // What to do once an update requested by the user is complete
+    pub elevation: Option<ElevationPolicy>, // How to update when the game's directory requires administrator privileges
+    #[serde(default)]
+    pub grf_routes: Vec<GrfRoute>, // Rules selecting the GRF of patches that don't target a specific one
+}
+
+impl ClientConfiguration {
+    /// Returns the name of the GRF a patch that doesn't target a specific
+    /// GRF is merged into: the GRF of the first route matching the patch
+    /// named `patch_name`, whose entries are located at `entry_paths`, or
+    /// `default_grf_name` if none matches.
+    pub fn routed_grf_name(&self, patch_name: &str, entry_paths: &[&str]) -> &str {
+        self.grf_routes
+            .iter()
+            .find(|route| route.matches(patch_name, entry_paths))
+            .map_or(&self.default_grf_name, |route| &route.grf)
+    }
+}
+
+/// Rule routing the patches that match it to a GRF. A patch matches a rule
+/// when it matches all of its patterns.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct GrfRoute {
+    pub grf: String,                // GRF matching patches are merged into
+    pub patch_name: Option<String>, // Glob pattern the patch's file name must match
+    pub entries: Option<String>,    // Glob pattern all of the patch's entries must match
+}
+
+impl GrfRoute {
+    fn matches(&self, patch_name: &str, entry_paths: &[&str]) -> bool {
+        let is_patch_name_matching = self
+            .patch_name
+            .as_ref()
+            .map_or(true, |pattern| match_entry_path(pattern, patch_name));
+        let are_entries_matching = self.entries.as_ref().map_or(true, |pattern| {
+            !entry_paths.is_empty()
+                && entry_paths
+                    .iter()
+                    .all(|entry_path| match_entry_path(pattern, entry_path))
+        });
+        is_patch_name_matching && are_entries_matching
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunningClientPolicy {
+    Warn,   // Warn the user and patch anyway
+    Prompt, // Ask the user whether to patch anyway
+    Block,  // Refuse to patch until the client is closed
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadyAction {
+    Stay,          // Keep the patcher open
+    Launch,        // Start the game client and keep the patcher open
+    LaunchAndExit, // Start the game client and close the patcher
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ElevationPolicy {
+    Never,    // Let the update fail
+    Relaunch, // Restart the patcher with administrator privileges
+    Helper,   // Update from a separate process with administrator privileges
 }
 
 #[derive(Deserialize, Clone)]
 pub struct PatchingConfiguration {
-    pub in_place: bool,        // In-place GRF patching
-    pub check_integrity: bool, // Check THOR archives' integrity
-    pub create_grf: bool,      // Create new GRFs if they don't exist
+    pub in_place: bool,                           // In-place GRF patching
+    pub in_place_max_size: Option<u64>, // Size in MiB above which patches are merged out-of-place
+    pub check_integrity: bool,          // Check THOR archives' integrity
+    pub create_grf: bool,               // Create new GRFs if they don't exist
+    pub on_failure: Option<FailurePolicy>, // What to do when a patch cannot be downloaded or applied
+    pub path_validation: Option<PathValidation>, // What to do with entries extracted outside of the game directory
+    pub path_case: Option<PathCase>, // How the case of entries' paths is compared with existing files
+    pub name_encoding: Option<EntryNameEncoding>, // Encoding of the entries' names in patches and GRFs
+    #[serde(default)]
+    pub verify_archives: bool, // Verify THOR archives' consistency before applying them
+    #[serde(default)]
+    pub mmap_archives: bool, // Read THOR and GRF archives through memory mappings
+    #[serde(default)]
+    pub journaled: bool,     // Write in-place GRF modifications to a journal first
+    #[serde(default)]
+    pub backup: bool, // Back up the files modified by updates, so that they can be rolled back
+    #[serde(default)]
+    pub track_installed_files: bool, // Record the files written by patches along with their hash
+    #[serde(default)]
+    pub protected_files: Vec<String>, // Glob patterns of the files patches must never modify
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>, // Glob patterns of the entries skipped by this installation
+    pub encryption_key: Option<String>, // Base64-encoded key used to decrypt encrypted THOR patches
+    pub encryption_secret: Option<String>, // Secret from which the decryption key is derived
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    Abort,  // Stop the update
+    Skip,   // Skip the patch and continue with the next ones
+    Prompt, // Ask the user whether to skip the patch or abort
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathValidation {
+    Strict,  // Reject patches containing such entries
+    Lenient, // Skip such entries with a warning
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathCase {
+    Sensitive,   // Paths that differ in case designate different files
+    Insensitive, // Paths that differ in case designate the same file
 }
 
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryNameEncoding {
+    Legacy, // Decode names byte per byte (Windows-1252), as most tools do
+    Auto,   // Detect UTF-8 and CP949 names, write names in CP949
+    Cp949,  // Names are encoded in CP949 (EUC-KR)
+    Utf8,   // Names are encoded in UTF-8
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HooksConfiguration {
+    pub pre_patch: Vec<Hook>, // Run before patching starts, a failure aborts patching
+    pub post_patch: Vec<Hook>, // Run once patching has completed
+    pub on_failure: Vec<Hook>, // Run once patching has failed
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Hook {
+    // Run an external command from the installation directory
+    Command {
+        path: String,
+        #[serde(default)]
+        arguments: Vec<String>,
+    },
+    // Terminate the processes of an executable (e.g. the game client)
+    KillProcess {
+        name: String,
+    },
+    // Remove the content of a directory, relative to the installation directory
+    ClearDirectory {
+        path: String,
+    },
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TorrentConfiguration {
+    pub client_path: String, // Path to the BitTorrent client's executable
+    // Arguments passed to the client. '{url}' and '{dir}' are replaced with
+    // the torrent's URL and the directory to download into
+    pub arguments: Vec<String>,
+}
+impl Default for TorrentConfiguration {
+    fn default() -> Self {
+        Self {
+            client_path: "aria2c".to_string(),
+            arguments: vec![
+                "--seed-time=0".to_string(),
+                "--follow-torrent=mem".to_string(),
+                "--dir={dir}".to_string(),
+                "{url}".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ProxyConfiguration {
+    pub url: Option<String>,      // URL of the HTTP(S) or SOCKS5 proxy to use
+    pub username: Option<String>, // Proxy credentials
+    pub password: Option<String>,
+    pub use_system_proxy: bool, // Use the system's proxy when `url` isn't set
+}
+
+impl Default for ProxyConfiguration {
+    fn default() -> Self {
+        Self {
+            url: None,
+            username: None,
+            password: None,
+            use_system_proxy: true,
+        }
+    }
+}
+
+/// Loads the configuration file, then applies the overrides set through
+/// environment variables and `cli_overrides`, which take precedence.
 pub fn retrieve_patcher_configuration(
     config_file_path: Option<PathBuf>,
+    cli_overrides: &[ConfigurationOverride],
 ) -> Result<PatcherConfiguration> {
     let patcher_name = get_patcher_name()?;
     // Use given configuration path if present
     let config_file_path =
         config_file_path.unwrap_or_else(|| PathBuf::from(patcher_name).with_extension("yml"));
+    let mut overrides = env_overrides();
+    overrides.extend_from_slice(cli_overrides);
     // Read the YAML content of the file as an instance of `PatcherConfiguration`.
-    parse_configuration(config_file_path)
+    parse_configuration(config_file_path, &overrides)
+}
+
+fn parse_configuration(
+    config_file_path: impl AsRef<Path>,
+    overrides: &[ConfigurationOverride],
+) -> Result<PatcherConfiguration> {
+    let content = fs::read_to_string(config_file_path)?;
+    // Note: The file is deserialized as is first, so that its errors are
+    // reported with their location
+    let mut config = serde_yaml::from_str(&content)
+        .map_err(|e| InvalidConfiguration(vec![ConfigurationIssue::from_yaml_error(&e)]))
+        .context("Invalid configuration")?;
+    if !overrides.is_empty() {
+        let content = serde_yaml::from_str(&content).context("Invalid configuration")?;
+        config = apply_overrides(content, overrides).context("Invalid configuration")?;
+    }
+    expand_templates(&mut config)?;
+    let issues = validate_configuration(&config);
+    if !issues.is_empty() {
+        return Err(InvalidConfiguration(issues)).context("Invalid configuration");
+    }
+    Ok(config)
+}
+
+/// Value of a field of the configuration, set by an environment variable
+/// (e.g. 'RPATCHUR_WEB__READ_TIMEOUT=30') or on the command line (e.g.
+/// '--set web.read_timeout=30').
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigurationOverride {
+    pub field: String, // Path of the field, with indices as segments (e.g. 'web.patch_servers.0.plist_url')
+    pub value: Value,  // Parsed as YAML, strings don't need to be quoted
+    pub source: String, // Environment variable or argument the value comes from
+}
+
+impl ConfigurationOverride {
+    /// Parses an argument of the form 'field=value'. Indices can also be
+    /// written between brackets (e.g. 'web.patch_servers[0].plist_url').
+    pub fn from_argument(argument: &str) -> Result<Self> {
+        let (field, value) = argument
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected 'field=value', got '{}'", argument))?;
+        let field = field.trim().replace('[', ".").replace(']', "");
+        if field.is_empty() || field.split('.').any(str::is_empty) {
+            return Err(anyhow!("Invalid field '{}'", field));
+        }
+        Ok(Self {
+            field,
+            value: parse_override_value(value),
+            source: format!("--set {}", argument),
+        })
+    }
+
+    /// Parses an environment variable overriding a field (e.g.
+    /// 'RPATCHUR_WEB__PATCH_SERVERS__0__PLIST_URL'). Returns `None` for
+    /// variables that aren't meant to override the configuration.
+    fn from_env_var(name: &str, value: &str) -> Option<Self> {
+        let field = name
+            .strip_prefix(ENV_OVERRIDE_PREFIX)?
+            .to_ascii_lowercase()
+            .replace(ENV_OVERRIDE_SEPARATOR, ".");
+        let section = field.split('.').next()?;
+        if !CONFIGURATION_SECTIONS.contains(&section) || field.split('.').any(str::is_empty) {
+            return None;
+        }
+        Some(Self {
+            field,
+            value: parse_override_value(value),
+            source: name.to_string(),
+        })
+    }
 }
 
-fn parse_configuration(config_file_path: impl AsRef<Path>) -> Result<PatcherConfiguration> {
-    let config_file = File::open(config_file_path)?;
-    let config_reader = BufReader::new(config_file);
-    serde_yaml::from_reader(config_reader).context("Invalid configuration")
+/// Parses the value of an override as YAML (e.g. '30', 'true' or '[a, b]').
+/// Values that aren't valid YAML are used as strings.
+fn parse_override_value(value: &str) -> Value {
+    serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/// Returns the overrides set through environment variables, sorted by name.
+fn env_overrides() -> Vec<ConfigurationOverride> {
+    let mut overrides: Vec<_> = env::vars_os()
+        .filter_map(|(name, value)| {
+            ConfigurationOverride::from_env_var(name.to_str()?, value.to_str()?)
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.source.cmp(&b.source));
+    overrides
+}
+
+/// Applies `overrides` in order to the content of the configuration file.
+/// Overrides that make the configuration invalid are reported along with
+/// their source.
+fn apply_overrides(
+    mut content: Value,
+    overrides: &[ConfigurationOverride],
+) -> Result<PatcherConfiguration> {
+    let mut issues = vec![];
+    for config_override in overrides {
+        let segments: Vec<&str> = config_override.field.split('.').collect();
+        let mut new_content = content.clone();
+        let res =
+            set_field(&mut new_content, &segments, config_override.value.clone()).and_then(|_| {
+                serde_yaml::from_value::<PatcherConfiguration>(new_content.clone())
+                    .map_err(Into::into)
+            });
+        match res {
+            Err(e) => issues.push(ConfigurationIssue::new(
+                indexed_field_path(&config_override.field),
+                format!("{:#} (set by {})", e, config_override.source),
+            )),
+            Ok(_) => {
+                log::info!(
+                    "Overriding '{}' ({})",
+                    config_override.field,
+                    config_override.source
+                );
+                content = new_content;
+            }
+        }
+    }
+    if !issues.is_empty() {
+        return Err(InvalidConfiguration(issues).into());
+    }
+    let mut config: PatcherConfiguration = serde_yaml::from_value(content)?;
+    config.overridden_fields = overrides
+        .iter()
+        .map(|config_override| config_override.field.clone())
+        .collect();
+    Ok(config)
+}
+
+/// Writes the indices of a field's path between brackets, like in the
+/// issues reported for the file (e.g. 'web.patch_servers[0].plist_url').
+fn indexed_field_path(field: &str) -> String {
+    let mut path = String::with_capacity(field.len());
+    for segment in field.split('.') {
+        if !path.is_empty() && segment.parse::<usize>().is_ok() {
+            path.push_str(&format!("[{}]", segment));
+        } else {
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(segment);
+        }
+    }
+    path
+}
+
+/// Sets the field located at `segments` under `node` to `value`. Missing
+/// sections are created, list items must exist.
+fn set_field(node: &mut Value, segments: &[&str], value: Value) -> Result<()> {
+    let (segment, rest) = match segments.split_first() {
+        None => {
+            *node = value;
+            return Ok(());
+        }
+        Some(split) => split,
+    };
+    if node.is_null() {
+        *node = Value::Mapping(Mapping::new());
+    }
+    let child = match node {
+        Value::Mapping(mapping) => {
+            let key = Value::String(segment.to_string());
+            if !mapping.contains_key(&key) {
+                mapping.insert(key.clone(), Value::Null);
+            }
+            mapping.get_mut(&key)
+        }
+        Value::Sequence(sequence) => match segment.parse::<usize>() {
+            Err(_) => return Err(anyhow!("'{}' isn't an index of a list", segment)),
+            Ok(index) => sequence.get_mut(index),
+        },
+        _ => {
+            return Err(anyhow!(
+                "cannot set '{}', its parent isn't a section",
+                segment
+            ))
+        }
+    };
+    match child {
+        None => Err(anyhow!("no item at index {}", segment)),
+        Some(child) => set_field(child, rest, value),
+    }
+}
+
+/// Error returned when the configuration file cannot be used as is.
+#[derive(Debug)]
+pub struct InvalidConfiguration(pub Vec<ConfigurationIssue>);
+
+impl fmt::Display for InvalidConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, issue) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidConfiguration {}
+
+/// Invalid field of the configuration.
+#[derive(Debug, PartialEq)]
+pub struct ConfigurationIssue {
+    pub field: String, // Path of the field (e.g. 'web.patch_servers[0].plist_url'), empty for the whole file
+    pub problem: String, // What's wrong with the value, including the expected type
+    pub location: Option<(usize, usize)>, // Line and column of the value, if known
+}
+
+impl ConfigurationIssue {
+    fn new(field: String, problem: String) -> Self {
+        Self {
+            field,
+            problem,
+            location: None,
+        }
+    }
+
+    /// Converts a YAML deserialization error, whose message starts with the
+    /// path of the field it relates to (e.g. "web.read_timeout: invalid type:
+    /// string \"60s\", expected u64 at line 12 column 17").
+    fn from_yaml_error(error: &serde_yaml::Error) -> Self {
+        let location = error
+            .location()
+            .map(|location| (location.line(), location.column()));
+        let mut message = error.to_string();
+        if let Some((line, column)) = location {
+            let suffix = format!(" at line {} column {}", line, column);
+            if message.ends_with(&suffix) {
+                message.truncate(message.len() - suffix.len());
+            }
+        }
+        let (path, problem) = match message.split_once(": ") {
+            Some((path, problem)) if is_field_path(path) => (path.to_string(), problem.to_string()),
+            _ => (String::new(), message),
+        };
+        // Missing fields are reported on the structure that lacks them
+        let missing_field = problem
+            .strip_prefix("missing field `")
+            .and_then(|tail| tail.strip_suffix('`'));
+        let field = match missing_field {
+            Some(name) if path.is_empty() => name.to_string(),
+            Some(name) => format!("{}.{}", path, name),
+            None => path,
+        };
+        Self {
+            field,
+            problem,
+            location,
+        }
+    }
+
+    /// Returns an example of a valid value for the field, if there's one.
+    pub fn example(&self) -> Option<&'static str> {
+        FIELD_EXAMPLES
+            .iter()
+            .find(|(pattern, _)| matches_field_pattern(pattern, &self.field))
+            .map(|(_, example)| *example)
+    }
+}
+
+impl fmt::Display for ConfigurationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field.is_empty() {
+            write!(f, "{}", self.problem)?;
+        } else {
+            write!(f, "'{}': {}", self.field, self.problem)?;
+        }
+        if let Some(example) = self.example() {
+            write!(f, " (e.g. {})", example)?;
+        }
+        if let Some((line, column)) = self.location {
+            write!(f, " at line {} column {}", line, column)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_field_path(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-.[]?".contains(c))
+}
+
+/// Indicates whether `field` (e.g. 'channels.beta.plist_url') matches a
+/// pattern of `FIELD_EXAMPLES` (e.g. 'channels.*.plist_url').
+fn matches_field_pattern(pattern: &str, field: &str) -> bool {
+    let mut field_segments = field.split('.');
+    let mut pattern_segments = pattern.split('.');
+    loop {
+        match (pattern_segments.next(), field_segments.next()) {
+            (None, None) => return true,
+            (Some("*"), Some(_)) => {}
+            (Some(pattern_segment), Some(field_segment)) => {
+                let field_segment = match field_segment.find('[') {
+                    Some(start) if pattern_segment.ends_with("[]") => &field_segment[..start],
+                    _ => field_segment,
+                };
+                if pattern_segment.trim_end_matches("[]") != field_segment {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Checks what deserialization can't (e.g. URLs), so that invalid values are
+/// reported when the configuration is loaded rather than when they're used.
+pub fn validate_configuration(config: &PatcherConfiguration) -> Vec<ConfigurationIssue> {
+    let web = &config.web;
+    let mut issues = vec![];
+    issues.extend(check_url("web.index_url", &web.index_url, None));
+    // Pins replace the verification against root certificates
+    if web.tls.is_pinning_enabled() {
+        let conflicting_options = [
+            ("web.tls.ca_certificate", web.tls.ca_certificate.is_some()),
+            ("web.tls.accept_invalid_certs", web.tls.accept_invalid_certs),
+        ];
+        for (field, is_set) in conflicting_options.iter() {
+            if *is_set {
+                issues.push(ConfigurationIssue::new(
+                    field.to_string(),
+                    "cannot be combined with `pinned_certificates` or `pinned_public_keys`"
+                        .to_string(),
+                ));
+            }
+        }
+    }
+    for (i, server) in web.patch_servers.iter().enumerate() {
+        let field = format!("web.patch_servers[{}]", i);
+        issues.extend(check_source_urls(
+            &field,
+            &server.plist_url,
+            &server.patch_url,
+        ));
+    }
+    let mut channels: Vec<_> = config.channels.iter().collect();
+    channels.sort_by_key(|(name, _)| name.as_str());
+    for (name, channel) in channels {
+        let field = format!("channels.{}", name);
+        issues.extend(check_source_urls(
+            &field,
+            &channel.plist_url,
+            &channel.patch_url,
+        ));
+    }
+    let mut profiles: Vec<_> = config.profiles.iter().collect();
+    profiles.sort_by_key(|(name, _)| name.as_str());
+    for (name, profile) in profiles {
+        let field = format!("profiles.{}", name);
+        issues.extend(check_source_urls(
+            &field,
+            &profile.plist_url,
+            &profile.patch_url,
+        ));
+    }
+    let optional_urls = [
+        ("web.repair_url", &web.repair_url, SOURCE_URL_SCHEMES),
+        ("web.news_url", &web.news_url, SOURCE_URL_SCHEMES),
+        ("web.changelog_url", &web.changelog_url, SOURCE_URL_SCHEMES),
+        ("proxy.url", &config.proxy.url, PROXY_URL_SCHEMES),
+        (
+            "remote_config_url",
+            &config.remote_config_url,
+            SOURCE_URL_SCHEMES,
+        ),
+    ];
+    for (field, url, schemes) in optional_urls.iter() {
+        if let Some(url) = url {
+            issues.extend(check_url(field, url, Some(schemes)));
+        }
+    }
+    let hook_stages = [
+        ("hooks.pre_patch", &config.hooks.pre_patch),
+        ("hooks.post_patch", &config.hooks.post_patch),
+        ("hooks.on_failure", &config.hooks.on_failure),
+    ];
+    for (field, hooks) in hook_stages.iter() {
+        for (i, hook) in hooks.iter().enumerate() {
+            if let Hook::ClearDirectory { path } = hook {
+                if !is_contained_path(path) {
+                    issues.push(ConfigurationIssue::new(
+                        format!("{}[{}].path", field, i),
+                        format!(
+                            "'{}' must be a subdirectory of the installation directory, \
+                             without '..'",
+                            path
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    issues
+}
+
+/// Indicates whether `path` designates a location strictly under the
+/// directory it's relative to (i.e. it isn't absolute, doesn't contain '..'
+/// and isn't empty).
+pub fn is_contained_path(path: &str) -> bool {
+    let components: Vec<Component> = Path::new(path).components().collect();
+    components
+        .iter()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        && components
+            .iter()
+            .any(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Checks that `value` is an absolute URL, using one of `schemes` if given.
+fn check_url(field: &str, value: &str, schemes: Option<&[&str]>) -> Option<ConfigurationIssue> {
+    let problem = match Url::parse(value) {
+        Err(e) => format!("invalid URL '{}' ({}), expected an absolute URL", value, e),
+        Ok(url) => match schemes {
+            Some(schemes) if !schemes.contains(&url.scheme()) => format!(
+                "unsupported URL scheme '{}', expected one of {}",
+                url.scheme(),
+                schemes.join(", ")
+            ),
+            _ => return None,
+        },
+    };
+    Some(ConfigurationIssue::new(field.to_string(), problem))
+}
+
+/// Checks the URLs of a patch list and of the directory containing its
+/// patches, which can be local paths as well.
+///
+/// Note: Values containing '://' are meant to be URLs, they aren't accepted
+/// as paths when they're invalid.
+fn check_source_urls(field: &str, plist_url: &str, patch_url: &str) -> Vec<ConfigurationIssue> {
+    [
+        ("plist_url", plist_url, false),
+        ("patch_url", patch_url, true),
+    ]
+    .iter()
+    .filter_map(|(name, value, is_directory)| {
+        let field = format!("{}.{}", field, name);
+        if value.contains("://") {
+            return check_url(&field, value, Some(SOURCE_URL_SCHEMES));
+        }
+        match parse_source_url(value, *is_directory) {
+            Err(_) => Some(ConfigurationIssue::new(
+                field,
+                format!("invalid URL or path '{}'", value),
+            )),
+            Ok(url) => check_url(&field, url.as_str(), Some(SOURCE_URL_SCHEMES)),
+        }
+    })
+    .collect()
+}
+
+/// Returns the directory the game client is installed in, where patches are
+/// applied: `client.install_directory` if set, the working directory
+/// otherwise. Relative directories are resolved from the working directory.
+pub fn resolve_install_directory(client_config: &ClientConfiguration) -> Result<PathBuf> {
+    resolve_directory(client_config.install_directory.as_deref())
+}
+
+fn resolve_directory(install_directory: Option<&str>) -> Result<PathBuf> {
+    let current_working_dir =
+        env::current_dir().context("Failed to resolve current working directory")?;
+    match install_directory {
+        None => Ok(current_working_dir),
+        Some(install_directory) => {
+            let install_directory =
+                expand_variables(install_directory).context("Invalid installation directory")?;
+            Ok(current_working_dir.join(install_directory))
+        }
+    }
+}
+
+/// Values of the variables that can be used in the configuration's strings
+/// (e.g. 'https://myserver.com/{profile}/plist.txt').
+struct TemplateVariables<'a> {
+    install_dir: Option<String>, // `None` while the installation directory itself is expanded
+    patcher_name: &'a str,
+    profile: &'a str,
+}
+
+impl TemplateVariables<'_> {
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "install_dir" => self.install_dir.as_deref(),
+            "patcher_name" => Some(self.patcher_name),
+            "profile" => Some(self.profile),
+            "version" => Some(PKG_VERSION),
+            _ => None,
+        }
+    }
+
+    /// Replaces the variables referenced in `value` (e.g. '{version}') with
+    /// their value. Other references (e.g. '{login}') are kept as is.
+    fn expand(&self, value: &mut String) {
+        if !value.contains('{') {
+            return;
+        }
+        let mut expanded = String::with_capacity(value.len());
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find('{') {
+            expanded.push_str(&rest[..start]);
+            let reference = &rest[start..];
+            let variable = reference
+                .find('}')
+                .and_then(|end| Some((self.get(&reference[1..end])?, end + 1)));
+            match variable {
+                Some((variable_value, reference_len)) => {
+                    expanded.push_str(variable_value);
+                    rest = &reference[reference_len..];
+                }
+                None => {
+                    expanded.push('{');
+                    rest = &reference[1..];
+                }
+            }
+        }
+        expanded.push_str(rest);
+        *value = expanded;
+    }
+
+    /// Resolves '{install_dir}' from `install_directory`, once expanded. It's
+    /// left as is if the directory cannot be resolved.
+    fn resolve_install_dir(&mut self, install_directory: Option<&mut String>) {
+        let install_directory = install_directory.map(|install_directory| {
+            self.expand(install_directory);
+            install_directory.as_str()
+        });
+        self.install_dir = match resolve_directory(install_directory) {
+            Ok(install_dir) => Some(install_dir.to_string_lossy().into_owned()),
+            Err(e) => {
+                log::warn!("Cannot expand '{{install_dir}}': {:#}", e);
+                None
+            }
+        };
+    }
+}
+
+/// Expands the variables ('{install_dir}', '{patcher_name}', '{profile}' and
+/// '{version}') referenced in the URLs, the client's path and arguments and
+/// the installation directory. Profiles are expanded with their own name and
+/// installation directory.
+pub fn expand_templates(config: &mut PatcherConfiguration) -> Result<()> {
+    let patcher_name = get_patcher_name()?.to_string_lossy().into_owned();
+    let mut variables = TemplateVariables {
+        install_dir: None,
+        patcher_name: &patcher_name,
+        profile: DEFAULT_PROFILE_NAME,
+    };
+    variables.resolve_install_dir(config.client.install_directory.as_mut());
+    let web = &mut config.web;
+    variables.expand(&mut web.index_url);
+    for server in &mut web.patch_servers {
+        variables.expand(&mut server.plist_url);
+        variables.expand(&mut server.patch_url);
+    }
+    let mut optional_urls = [
+        &mut web.repair_url,
+        &mut web.news_url,
+        &mut web.changelog_url,
+        &mut config.remote_config_url,
+    ];
+    for url in optional_urls.iter_mut().filter_map(|url| url.as_mut()) {
+        variables.expand(url);
+    }
+    let play = &mut config.play;
+    variables.expand(&mut play.path);
+    for argument in play
+        .arguments
+        .iter_mut()
+        .chain(play.login_arguments.iter_mut().flatten())
+    {
+        variables.expand(argument);
+    }
+    for channel in config.channels.values_mut() {
+        variables.expand(&mut channel.plist_url);
+        variables.expand(&mut channel.patch_url);
+    }
+    for (name, profile) in config.profiles.iter_mut() {
+        let mut profile_variables = TemplateVariables {
+            install_dir: variables.install_dir.clone(),
+            patcher_name: &patcher_name,
+            profile: name,
+        };
+        if profile.install_directory.is_some() {
+            profile_variables.resolve_install_dir(profile.install_directory.as_mut());
+        }
+        if let Some(play_path) = &mut profile.play_path {
+            profile_variables.expand(play_path);
+        }
+        profile_variables.expand(&mut profile.plist_url);
+        profile_variables.expand(&mut profile.patch_url);
+    }
+    Ok(())
+}
+
+/// Replaces the environment variables referenced in `value` (as '${VAR}' or
+/// '%VAR%') with their value. A leading '~' is replaced with the user's home
+/// directory.
+fn expand_variables(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    if let Some(tail) = rest.strip_prefix('~') {
+        if tail.is_empty() || tail.starts_with(&['/', '\\'][..]) {
+            let home_directory = env::var("HOME")
+                .or_else(|_| env::var("USERPROFILE"))
+                .context("Failed to resolve the home directory")?;
+            expanded.push_str(&home_directory);
+            rest = tail;
+        }
+    }
+    while let Some(start) = rest.find(&['$', '%'][..]) {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        let variable = if let Some(tail) = reference.strip_prefix("${") {
+            tail.find('}').map(|end| (&tail[..end], end + 3))
+        } else if let Some(tail) = reference.strip_prefix('%') {
+            tail.find('%').map(|end| (&tail[..end], end + 2))
+        } else {
+            None
+        };
+        match variable {
+            Some((name, reference_len)) if !name.is_empty() => {
+                let value = env::var(name)
+                    .with_context(|| format!("Environment variable '{}' is not defined", name))?;
+                expanded.push_str(&value);
+                rest = &reference[reference_len..];
+            }
+            // Not a reference, keep the character as is
+            _ => {
+                expanded.push_str(&reference[..1]);
+                rest = &reference[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn example_configuration() -> String {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        fs::read_to_string(config_file_path).unwrap()
+    }
+
+    /// Returns the issues reported for the example configuration, once
+    /// `from` has been replaced with `to`.
+    fn configuration_issues(from: &str, to: &str) -> Vec<ConfigurationIssue> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config_file_path = temp_dir.path().join("rpatchur.yml");
+        let config = example_configuration();
+        assert!(config.contains(from));
+        fs::write(&config_file_path, config.replace(from, to)).unwrap();
+        match parse_configuration(&config_file_path, &[]) {
+            Ok(_) => vec![],
+            Err(e) => e.downcast::<InvalidConfiguration>().unwrap().0,
+        }
+    }
+
+    #[test]
+    fn test_parse_configuration() {
+        assert!(configuration_issues("read_timeout: 60", "read_timeout: 60").is_empty());
+
+        // Type errors point at the field, with the expected type and an example
+        let issues = configuration_issues("read_timeout: 60", "read_timeout: 60s");
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "web.read_timeout");
+        assert!(issues[0].problem.contains("expected u64"));
+        assert_eq!(issues[0].example(), Some("60"));
+        assert!(issues[0].location.is_some());
+        let issues = configuration_issues(
+            "index_url: https://myserver.com/index.html",
+            "index_uri: https://myserver.com/index.html",
+        );
+        assert_eq!(issues[0].field, "web.index_url");
+        assert_eq!(issues[0].problem, "missing field `index_url`");
+        let issues = configuration_issues("on_failure: abort", "on_failure: retry");
+        assert_eq!(issues[0].field, "patching.on_failure");
+        assert!(issues[0].problem.starts_with("unknown variant `retry`"));
+        let issues = configuration_issues("  width: 780", "  width: [780]");
+        assert_eq!(
+            issues[0].to_string(),
+            "'window.width': invalid type: sequence, expected i32 (e.g. 780) at line 4 column 10"
+        );
+
+        // Invalid URLs are all reported
+        let issues = configuration_issues(
+            "https://us.myserver.com/plist.txt",
+            "https://us.myserver.com:http/plist.txt",
+        );
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "web.patch_servers[1].plist_url");
+        assert_eq!(
+            issues[0].example(),
+            Some("https://eu.myserver.com/plist.txt")
+        );
+        let issues = configuration_issues(
+            "#news_url: https://example.com/news.json",
+            "news_url: ftp://example.com/news.json",
+        );
+        assert_eq!(issues[0].field, "web.news_url");
+        assert!(issues[0]
+            .problem
+            .starts_with("unsupported URL scheme 'ftp'"));
+        let issues = configuration_issues(
+            "index_url: https://myserver.com/index.html",
+            "index_url: index.html",
+        );
+        assert_eq!(issues[0].field, "web.index_url");
+        // Local paths are valid patch sources
+        assert!(configuration_issues(
+            "patch_url: https://us.myserver.com/data/",
+            "patch_url: patches/data/"
+        )
+        .is_empty());
+        // Pins replace the other ways of verifying certificates
+        let issues = configuration_issues(
+            "  user_agent: rpatchur/{version}",
+            "  tls:\n    ca_certificate: my_ca.pem\n    pinned_public_keys: ['AB:CD']\n",
+        );
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "web.tls.ca_certificate");
+    }
+
+    #[test]
+    fn test_hook_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config_file_path = temp_dir.path().join("rpatchur.yml");
+        fs::write(&config_file_path, example_configuration()).unwrap();
+        let mut config = parse_configuration(&config_file_path, &[]).unwrap();
+        let clear_directory = |path: &str| Hook::ClearDirectory {
+            path: path.to_string(),
+        };
+        config.hooks.post_patch = vec![
+            clear_directory("cache"),
+            clear_directory("./data/cache"),
+            clear_directory("/"),
+            clear_directory(".."),
+            clear_directory("cache/../.."),
+            clear_directory(""),
+            clear_directory("."),
+        ];
+        let issues = validate_configuration(&config);
+        let fields: Vec<&str> = issues.iter().map(|issue| issue.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "hooks.post_patch[2].path",
+                "hooks.post_patch[3].path",
+                "hooks.post_patch[4].path",
+                "hooks.post_patch[5].path",
+                "hooks.post_patch[6].path",
+            ]
+        );
+        #[cfg(windows)]
+        assert!(!is_contained_path("C:\\"));
+    }
+
+    #[test]
+    fn test_configuration_override() {
+        let config_override =
+            ConfigurationOverride::from_argument("web.patch_servers[1].name=Staging").unwrap();
+        assert_eq!(config_override.field, "web.patch_servers.1.name");
+        assert_eq!(config_override.value, Value::String("Staging".to_string()));
+        assert_eq!(
+            ConfigurationOverride::from_argument("web.read_timeout= 30")
+                .unwrap()
+                .value,
+            Value::Number(30.into())
+        );
+        assert!(ConfigurationOverride::from_argument("web.read_timeout").is_err());
+        assert!(ConfigurationOverride::from_argument("web..read_timeout=30").is_err());
+
+        let config_override = ConfigurationOverride::from_env_var(
+            "RPATCHUR_WEB__PATCH_SERVERS__0__PLIST_URL",
+            "https://staging.myserver.com/plist.txt",
+        )
+        .unwrap();
+        assert_eq!(config_override.field, "web.patch_servers.0.plist_url");
+        assert_eq!(
+            config_override.source,
+            "RPATCHUR_WEB__PATCH_SERVERS__0__PLIST_URL"
+        );
+        // Other variables are ignored
+        assert!(ConfigurationOverride::from_env_var("RPATCHUR_HOOK", "pre_patch").is_none());
+        assert!(ConfigurationOverride::from_env_var("WEB__INDEX_URL", "index.html").is_none());
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config_file_path = temp_dir.path().join("rpatchur.yml");
+        fs::write(&config_file_path, example_configuration()).unwrap();
+        let env_override =
+            |name: &str, value: &str| ConfigurationOverride::from_env_var(name, value).unwrap();
+        let cli_override = |argument: &str| ConfigurationOverride::from_argument(argument).unwrap();
+
+        let config = parse_configuration(
+            &config_file_path,
+            &[
+                env_override("RPATCHUR_WEB__READ_TIMEOUT", "90"),
+                env_override(
+                    "RPATCHUR_WEB__PATCH_SERVERS__1__PLIST_URL",
+                    "https://staging.myserver.com/plist.txt",
+                ),
+                cli_override("web.read_timeout=120"),
+                cli_override("ipc.port=8989"),
+            ],
+        )
+        .unwrap();
+        // Later overrides (i.e. command-line ones) win
+        assert_eq!(config.web.read_timeout, Some(120));
+        assert_eq!(
+            config.web.patch_servers[1].plist_url,
+            "https://staging.myserver.com/plist.txt"
+        );
+        assert_eq!(config.web.patch_servers[1].name, "US Patch Server");
+        assert_eq!(config.ipc.as_ref().unwrap().port, 8989);
+        assert!(config.is_overridden("web.patch_servers"));
+        assert!(!config.is_overridden("web.patch"));
+        assert!(!config.is_overridden("web.patch_servers.0"));
+
+        // Invalid values are reported along with their source
+        let issues = parse_configuration(
+            &config_file_path,
+            &[
+                cli_override("web.read_timeout=soon"),
+                env_override("RPATCHUR_WEB__PATCH_SERVERS__5__NAME", "Staging"),
+                cli_override("web.patch_servers[0].plist_url=https://"),
+            ],
+        )
+        .err()
+        .unwrap()
+        .downcast::<InvalidConfiguration>()
+        .unwrap()
+        .0;
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].field, "web.read_timeout");
+        assert!(issues[0]
+            .problem
+            .ends_with("(set by --set web.read_timeout=soon)"));
+        assert_eq!(issues[1].field, "web.patch_servers[5].name");
+        assert!(issues[1]
+            .problem
+            .ends_with("(set by RPATCHUR_WEB__PATCH_SERVERS__5__NAME)"));
+    }
+
+    #[test]
+    fn test_expand_templates() {
+        let mut config: PatcherConfiguration =
+            serde_yaml::from_str(&example_configuration()).unwrap();
+        config.client.install_directory = Some("games/{patcher_name}".to_string());
+        config.web.patch_servers[0].plist_url =
+            "https://eu.myserver.com/{profile}/plist-{version}.txt".to_string();
+        config.play.arguments = vec!["-path={install_dir}".to_string(), "{unknown}".to_string()];
+        config.play.login_arguments = Some(vec!["-t:{token}".to_string()]);
+        config.profiles.insert(
+            "classic".to_string(),
+            serde_yaml::from_str(
+                "install_directory: '{install_dir}/{profile}'\n\
+                 plist_url: https://myserver.com/{profile}/plist.txt\n\
+                 patch_url: '{install_dir}/patches'\n",
+            )
+            .unwrap(),
+        );
+        expand_templates(&mut config).unwrap();
+
+        let patcher_name = get_patcher_name().unwrap().to_string_lossy().into_owned();
+        let install_dir = env::current_dir()
+            .unwrap()
+            .join("games")
+            .join(&patcher_name);
+        assert_eq!(
+            config.client.install_directory,
+            Some(format!("games/{}", patcher_name))
+        );
+        assert_eq!(
+            config.web.patch_servers[0].plist_url,
+            format!("https://eu.myserver.com/default/plist-{}.txt", PKG_VERSION)
+        );
+        // Unknown variables, such as the credentials', are kept as is
+        assert_eq!(
+            config.play.arguments,
+            vec![
+                format!("-path={}", install_dir.display()),
+                "{unknown}".to_string()
+            ]
+        );
+        assert_eq!(
+            config.play.login_arguments,
+            Some(vec!["-t:{token}".to_string()])
+        );
+        // Profiles are expanded with their own name and directory
+        let profile = &config.profiles["classic"];
+        assert_eq!(
+            profile.install_directory,
+            Some(format!("{}/classic", install_dir.display()))
+        );
+        assert_eq!(profile.plist_url, "https://myserver.com/classic/plist.txt");
+        assert_eq!(
+            profile.patch_url,
+            format!("{}/classic/patches", install_dir.display())
+        );
+    }
+
+    #[test]
+    fn test_matches_field_pattern() {
+        assert!(matches_field_pattern("web.index_url", "web.index_url"));
+        assert!(matches_field_pattern(
+            "web.patch_servers[].name",
+            "web.patch_servers[12].name"
+        ));
+        assert!(matches_field_pattern(
+            "channels.*.plist_url",
+            "channels.beta.plist_url"
+        ));
+        assert!(!matches_field_pattern("web.index_url", "web.index_url.x"));
+        assert!(!matches_field_pattern(
+            "web.patch_servers[].name",
+            "web.name"
+        ));
+        assert!(!matches_field_pattern(
+            "channels.*.plist_url",
+            "channels.plist_url"
+        ));
+    }
+
+    #[test]
+    fn test_expand_variables() {
+        env::set_var("RPATCHUR_TEST_GAME_DIR", "/opt/game");
+        assert_eq!(
+            "/opt/game/client",
+            expand_variables("${RPATCHUR_TEST_GAME_DIR}/client").unwrap()
+        );
+        assert_eq!(
+            "/opt/game\\client",
+            expand_variables("%RPATCHUR_TEST_GAME_DIR%\\client").unwrap()
+        );
+        // Lone markers are kept
+        assert_eq!("100% $5", expand_variables("100% $5").unwrap());
+        assert_eq!(
+            "${unterminated",
+            expand_variables("${unterminated").unwrap()
+        );
+        assert!(expand_variables("${RPATCHUR_TEST_UNDEFINED}").is_err());
+        if let Ok(home_directory) = env::var("HOME") {
+            assert_eq!(
+                format!("{}/game", home_directory),
+                expand_variables("~/game").unwrap()
+            );
+        }
+        assert_eq!("~user/game", expand_variables("~user/game").unwrap());
+    }
+
+    #[test]
+    fn test_routed_grf_name() {
+        let client_config: ClientConfiguration = serde_yaml::from_str(
+            "
+            default_grf_name: data.grf
+            grf_routes:
+              - grf: rdata.grf
+                entries: data\\sprite\\**
+              - grf: custom.grf
+                patch_name: custom_*.thor
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            "rdata.grf",
+            client_config.routed_grf_name(
+                "2021-01-01.thor",
+                &["data\\sprite\\a.spr", "data/sprite/b/b.act"]
+            )
+        );
+        assert_eq!(
+            "custom.grf",
+            client_config.routed_grf_name("custom_1.thor", &["data\\texture\\a.bmp"])
+        );
+        // All the entries must match
+        assert_eq!(
+            "data.grf",
+            client_config.routed_grf_name(
+                "2021-01-01.thor",
+                &["data\\sprite\\a.spr", "data\\texture\\a.bmp"]
+            )
+        );
+        assert_eq!(
+            "data.grf",
+            client_config.routed_grf_name("2021-01-01.thor", &[])
+        );
+    }
 }
@@ -1,137 +1,800 @@
-use std::env;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use advisory_lock::{AdvisoryFileLock, FileLockMode};
 use anyhow::{anyhow, Context, Result};
 use futures::executor::block_on;
+use futures::future::FutureExt;
 use futures::stream::{StreamExt, TryStreamExt};
-use gruf::thor::{self, ThorArchive, ThorPatchInfo, ThorPatchList};
-use gruf::GrufError;
+use gruf::thor::{
+    self, ThorArchive, ThorArchiveBuilder, ThorEncryptionKey, ThorPatchInfo, ThorPatchList,
+};
+use gruf::{GrufError, NameEncoding};
+use sha2::{Digest, Sha256};
 use tokio::fs::File;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
 use url::Url;
 
-use super::cache::{read_cache_file, write_cache_file, PatcherCache};
+use super::about::{latest_changelog_version, PatcherChangelog};
+use super::backup::{rollback_session, BackupSession};
+use super::cache::{read_cache_file, write_cache_file, PatchListValidators, PatcherCache};
 use super::cancellation::{
-    process_incoming_commands, wait_for_cancellation, InterruptibleFnError, InterruptibleFnResult,
+    process_incoming_commands, wait_for_blocking_task, wait_for_cancellation,
+    wait_for_locked_file_action, InterruptibleFnError, InterruptibleFnResult, PauseState,
+};
+use super::config::{
+    resolve_install_directory, ClientConfiguration, EntryNameEncoding, FailurePolicy,
+    PatchServerInfo, PathCase, PathValidation, ReadyAction, RetryConfiguration,
+    RunningClientPolicy, TorrentConfiguration, WebConfiguration,
+};
+use super::disk::{ensure_available_space, estimate_required_space};
+use super::elevation::{elevate_update_if_needed, ElevatedUpdate};
+use super::history::append_history;
+use super::hooks::{run_hooks, HookStage};
+use super::http::{build_http_client, fetch_text, read_timeout, with_read_timeout};
+use super::i18n::tr;
+use super::installed_files::InstalledFiles;
+use super::locked_files::{
+    complete_pending_renames, is_file_locked, is_locked_file_error, locked_file_path,
+    with_locked_file_path, PendingRenames,
 };
-use super::config::PatchServerInfo;
-use super::patching::{apply_patch_to_disk, apply_patch_to_grf, GrfPatchingMethod};
-use super::{get_patcher_name, PatcherCommand, PatcherConfiguration};
-use crate::ui::{PatchingStatus, UiController};
+use super::news::fetch_news;
+use super::patch_format::{
+    apply_gpf_patch, apply_rgz_patch, list_gpf_files, list_rgz_files, PatchFormat,
+};
+use super::patching::{
+    apply_patch_to_disk, apply_patch_to_grf, apply_patches_to_grf, contains_delta_entries,
+    destination_path, is_protected, recover_grf_journals, resolve_path_case, GrfPatchingMethod,
+    GrfPatchingOptions, PatchProgress, PathMatching, UnsafePathHandling,
+};
+use super::preview::{PartialPatch, PatchPreview, PatchPreviewer};
+use super::progress::DownloadProgress;
+use super::repair::{find_broken_entries, parse_manifest, ManifestEntry, MANIFEST_FILE_NAME};
+use super::retry::{Backoff, TruncatedBody};
+use super::session_journal::{record_applied_patch, remove_session_journal, SessionJournal};
+use super::signature::verify_signature;
+use super::source::{is_local_url, local_path_from_url, parse_source_url};
+use super::throttling::BandwidthLimiter;
+use super::torrent::download_torrent;
+use super::update_lock::{AlreadyPatching, UpdateLock};
+use super::{
+    get_instance_data_file_path, get_patcher_name, LockedFileAction, PatcherCommand,
+    PatcherConfiguration, PreviewMode,
+};
+use crate::process::{is_process_running, start_executable};
+use crate::ui::{PatchOutcome, PatchReport, PatchingStatus, UiController, UpdateSummary};
+
+/// Default number of connections used to download large patches
+const DEFAULT_DOWNLOAD_SEGMENTS: usize = 4;
+/// Minimum size of the patches downloaded with several connections
+const SEGMENTED_DOWNLOAD_MIN_SIZE: u64 = 64 * 1024 * 1024;
+/// Maximum size of the disk-merge patches that are kept in memory instead of
+/// being downloaded to a temporary file
+const IN_MEMORY_PATCH_MAX_SIZE: u64 = 16 * 1024 * 1024;
+/// Minimum interval between two reports of the progress of a patch's
+/// application
+const PATCH_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Interval at which locked files are checked, to retry patching as soon as
+/// they're released
+const LOCKED_FILE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Version of the patcher, compared with the versions required by patches
+const PATCHER_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Representation of a pending patch (a patch that's been downloaded but has
 /// not been applied yet).
 #[derive(Debug)]
 struct PendingPatch {
     info: thor::ThorPatchInfo,
-    local_file_path: PathBuf,
+    content: PatchContent,
+}
+
+/// Consecutive pending patches applied together. Patches merged into the same
+/// GRF are batched so that the GRF is opened and rebuilt only once.
+#[derive(Debug)]
+struct PatchBatch {
+    target_grf_path: Option<PathBuf>, // Set when patches are batched
+    patches: Vec<PendingPatch>,
+}
+
+/// Location of a pending patch's content.
+#[derive(Debug)]
+enum PatchContent {
+    File(PathBuf),   // Path of the downloaded patch
+    Memory(Vec<u8>), // Small disk-merge patch, streamed into memory
+}
+
+/// Outcome of the download of a list of patches.
+struct DownloadOutcome {
+    pending_patches: Vec<PendingPatch>,
+    skipped_patches: Vec<String>, // Patches that couldn't be downloaded
+    downloaded_bytes: u64,
+}
+
+/// Outcome of the application of a list of patches.
+struct InstallationOutcome {
+    skipped_patches: Vec<String>, // Patches that couldn't be applied
+    protected_files: Vec<String>, // Files left untouched because they're protected
+    excluded_files: Vec<String>,  // Files left untouched because they're excluded
+}
+
+/// Patch list retrieved from a patch server.
+struct RemotePatchList {
+    patches: Option<ThorPatchList>, // `None` if unchanged since the last update
+    validators: Option<PatchListValidators>,
+}
+
+/// Patches to update the game with, fetched from an available patch server.
+struct UpdatePlan {
+    patch_list: Option<ThorPatchList>, // `None` if unchanged since the last update
+    validators: Option<PatchListValidators>,
+    patch_url: Url,
+    cache_file_path: PathBuf,
+    download_directory: PathBuf, // Used when downloads are kept between sessions
+}
+
+/// Content of a text file fetched with a (possibly) conditional request.
+enum RemoteTextFile {
+    Modified(String, Option<PatchListValidators>), // Content, Validators
+    NotModified,
+}
+
+/// Backends that can be used to download patches.
+enum DownloadBackend<'a> {
+    Http,
+    LocalFile(PathBuf), // Path of the patch on the local file system
+    BitTorrent(&'a TorrentConfiguration, &'a str), // Client configuration, Torrent URL
+}
+
+/// Settings that control how patches are downloaded.
+struct DownloadSettings<'a> {
+    ensure_integrity: bool,
+    retry_config: &'a RetryConfiguration,
+    bandwidth_limiter: Option<&'a BandwidthLimiter>,
+    read_timeout: Duration,
+    pause_state: &'a PauseState,
+    torrent_config: Option<&'a TorrentConfiguration>,
+    failure_policy: FailurePolicy,
+    download_segments: usize,
+    in_memory_patches: bool, // Keep small disk-merge patches in memory
+}
+
+/// State kept by the patching task between updates.
+#[derive(Default)]
+struct SessionState {
+    patch_server: Option<String>, // Patch server selected during this session
+    channel: Option<String>,      // Channel selected by the user, `None` for the default one
+}
+
+/// Patch servers to update from, along with the cache file that keeps track
+/// of the patches applied from them.
+struct UpdateChannel {
+    patch_servers: Vec<PatchServerInfo>,
+    cache_file_path: PathBuf,
+    download_directory: PathBuf,
 }
 
 /// Entry point of the patching task.
 ///
 /// This waits for a `PatcherCommand::Start` command before starting an
-/// interruptible patching task.
+/// interruptible patching task. Whether it succeeds or fails, the task then
+/// waits for the next command (e.g. to retry), until `PatcherCommand::Quit`
+/// is received.
 pub async fn patcher_thread_routine(
     ui_controller: UiController,
-    config: PatcherConfiguration,
+    mut config: PatcherConfiguration,
     mut patcher_thread_rx: flume::Receiver<PatcherCommand>,
 ) {
     log::trace!("Patching thread started. Waiting for commands ...");
     let rx = &mut patcher_thread_rx;
-    let config = &config;
+    initialize_patcher(&config);
+    // Note: If the HTTP client cannot be built, this is reported right away
+    // and building it is attempted again for the next commands
+    let mut http_client = None;
+    match get_http_client(&mut http_client, &config) {
+        Err(err) => dispatch_error(&ui_controller, "error.http_client", &err),
+        Ok(http_client) => fetch_news_feed(&ui_controller, &config, http_client).await,
+    }
+    // Patch server and channel selected during this session, reused for
+    // subsequent updates
+    let mut session = SessionState::default();
+    // Background checks for new patches, if enabled
+    let mut update_check_period = get_update_check_period(&config);
+    let mut next_update_check = update_check_period.map(|period| Instant::now() + period);
     loop {
-        let cmd = rx.recv_async().await;
+        let cmd = tokio::select! {
+            cmd = rx.recv_async() => Some(cmd),
+            _ = wait_for_update_check(next_update_check) => None,
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => {
+                match get_http_client(&mut http_client, &config) {
+                    Err(err) => log::warn!("Failed to check for updates: {:#}", err),
+                    Ok(http_client) => {
+                        check_for_updates(&ui_controller, &config, http_client, &mut session, rx)
+                            .await
+                    }
+                }
+                // Note: Checks missed while busy aren't made up for
+                next_update_check = update_check_period.map(|period| Instant::now() + period);
+                continue;
+            }
+        };
         match cmd {
             Err(e) => {
                 log::error!("Failed to read from channel: {}", e);
                 return;
             }
-            Ok(cmd) => match cmd {
-                PatcherCommand::Quit => break,
-                PatcherCommand::StartUpdate => {
-                    update_game(&ui_controller, config, rx).await;
+            Ok(PatcherCommand::Quit) => break,
+            Ok(PatcherCommand::UpdateConfiguration(new_config)) => {
+                // Note: Settings only take effect between two commands
+                config = *new_config;
+                // Patch servers and channels may differ from one profile to
+                // another
+                session.patch_server = None;
+                if let Some(channel) = &session.channel {
+                    if !config.channels.contains_key(channel) {
+                        session.channel = None;
+                    }
                 }
-                PatcherCommand::ApplyPatch(patch_file_path) => {
-                    apply_single_patch(patch_file_path, &ui_controller, config);
+                update_check_period = get_update_check_period(&config);
+                next_update_check = update_check_period.map(|period| Instant::now() + period);
+            }
+            Ok(cmd) => match get_http_client(&mut http_client, &config) {
+                Err(err) => dispatch_error(&ui_controller, "error.http_client", &err),
+                Ok(http_client) => {
+                    process_command(cmd, &ui_controller, &config, http_client, &mut session, rx)
+                        .await
                 }
-                _ => {}
             },
         }
     }
 }
 
-/// Starts the automatic update process (download + patching)
-async fn update_game(
+/// Executes a single command and returns once it's done, to run the patcher
+/// without a UI.
+///
+/// `patcher_thread_rx` must stay open until then, commands received in the
+/// meantime are processed like during any update (e.g. to cancel it).
+pub async fn run_patcher_command(
+    command: PatcherCommand,
+    ui_controller: UiController,
+    config: PatcherConfiguration,
+    mut patcher_thread_rx: flume::Receiver<PatcherCommand>,
+) {
+    let config = &config;
+    initialize_patcher(config);
+    match build_http_client(config) {
+        Err(err) => dispatch_error(&ui_controller, "error.http_client", &err),
+        Ok(http_client) => {
+            let mut session = SessionState::default();
+            process_command(
+                command,
+                &ui_controller,
+                config,
+                &http_client,
+                &mut session,
+                &mut patcher_thread_rx,
+            )
+            .await;
+        }
+    }
+}
+
+/// Finishes the work interrupted when the patcher last exited.
+fn initialize_patcher(config: &PatcherConfiguration) {
+    // Finish in-place GRF modifications interrupted by a crash
+    if let Err(err) = resolve_install_directory(&config.client).and_then(recover_grf_journals) {
+        log::warn!("Failed to recover GRF journals: {:#}", err);
+    }
+    // Replace the files that were locked during the last update
+    match get_pending_renames_file_path().and_then(complete_pending_renames) {
+        Err(err) => log::warn!("Failed to replace locked files: {:#}", err),
+        Ok(0) => {}
+        Ok(replaced_count) => log::info!("Replaced {} previously locked files", replaced_count),
+    }
+}
+
+/// Returns the HTTP client shared by all requests for the whole session,
+/// building it if that hasn't been done yet or failed previously.
+fn get_http_client<'a>(
+    http_client: &'a mut Option<reqwest::Client>,
+    config: &PatcherConfiguration,
+) -> Result<&'a reqwest::Client> {
+    match http_client {
+        Some(http_client) => Ok(http_client),
+        None => Ok(http_client.insert(build_http_client(config)?)),
+    }
+}
+
+async fn process_command(
+    cmd: PatcherCommand,
     ui_controller: &UiController,
     config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
+    rx: &mut flume::Receiver<PatcherCommand>,
+) {
+    match cmd {
+        PatcherCommand::StartUpdate => {
+            let is_up_to_date = update_game(ui_controller, config, http_client, session, rx).await;
+            if is_up_to_date {
+                on_client_ready(ui_controller, config);
+            }
+        }
+        PatcherCommand::CheckForUpdates => {
+            check_for_updates(ui_controller, config, http_client, session, rx).await;
+        }
+        PatcherCommand::Preview(preview_mode) => {
+            preview_update(
+                preview_mode,
+                ui_controller,
+                config,
+                http_client,
+                session,
+                rx,
+            )
+            .await;
+        }
+        PatcherCommand::ApplyPatch(patch_file_path) => {
+            apply_single_patch(patch_file_path, ui_controller, config).await;
+        }
+        PatcherCommand::ApplyLocal(patch_path) | PatcherCommand::ApplyFile(patch_path) => {
+            apply_local_patches(patch_path, ui_controller, config, rx).await;
+        }
+        PatcherCommand::ApplyRemote(patch_url) => {
+            apply_remote_patch(patch_url, ui_controller, config, http_client, rx).await;
+        }
+        PatcherCommand::Rollback => rollback_update(ui_controller),
+        PatcherCommand::Repair => {
+            repair_installation(ui_controller, config, http_client, rx).await;
+        }
+        PatcherCommand::SelectChannel(channel) => {
+            select_channel(channel, ui_controller, config, session);
+        }
+        PatcherCommand::FetchNews => fetch_news_feed(ui_controller, config, http_client).await,
+        PatcherCommand::FetchChangelog => {
+            fetch_patcher_changelog(ui_controller, config, http_client).await;
+        }
+        _ => {}
+    }
+}
+
+/// Fetches the news feed, if there's one, and reports it to the UI.
+///
+/// Note: Failures are only logged, news aren't essential to patching
+async fn fetch_news_feed(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+) {
+    let news_url = match &config.web.news_url {
+        None => return,
+        Some(news_url) => news_url,
+    };
+    match fetch_news(http_client, news_url, &config.web).await {
+        Err(err) => log::warn!("Failed to fetch news: {:#}", err),
+        Ok(news) => {
+            log::info!("Fetched {} news item(s)", news.len());
+            ui_controller.dispatch_news(news);
+        }
+    }
+}
+
+/// Fetches the changelog of the patcher, if there's one, and reports it to the
+/// UI along with whether a newer version of the patcher is available.
+async fn fetch_patcher_changelog(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+) {
+    let changelog_url = match &config.web.changelog_url {
+        None => return,
+        Some(changelog_url) => changelog_url,
+    };
+    match fetch_text(http_client, changelog_url, &config.web).await {
+        Err(err) => log::warn!("Failed to fetch changelog: {:#}", err),
+        Ok(content) => {
+            let latest_version = latest_changelog_version(&content);
+            let update_available = latest_version
+                .as_ref()
+                .map_or(false, |version| is_older_version(PATCHER_VERSION, version));
+            if update_available {
+                log::info!(
+                    "A newer version of the patcher is available: {}",
+                    latest_version.as_deref().unwrap_or_default()
+                );
+            }
+            ui_controller.dispatch_changelog(PatcherChangelog {
+                content,
+                latest_version,
+                update_available,
+            });
+        }
+    }
+}
+
+/// Returns the time between two background checks for new patches, `None` if
+/// they're disabled.
+fn get_update_check_period(config: &PatcherConfiguration) -> Option<Duration> {
+    config
+        .web
+        .update_check_interval
+        .map(|minutes| Duration::from_secs(minutes.max(1) * 60))
+}
+
+/// Waits until `next_update_check`, forever if background update checks are
+/// disabled.
+async fn wait_for_update_check(next_update_check: Option<Instant>) {
+    match next_update_check {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Checks whether new patches have been published since the last update and
+/// either notifies the UI or starts updating the game.
+async fn check_for_updates(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
     patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
 ) {
-    // Try taking the update lock
-    match take_update_lock().with_context(|| "Failed to take the update lock") {
+    log::info!("Checking for updates ...");
+    let res = fetch_update_plan(
+        ui_controller,
+        config,
+        http_client,
+        session,
+        &PauseState::new(),
+        patcher_thread_rx,
+    )
+    .await;
+    let patch_count = match res {
         Err(err) => {
-            log::error!("{:#}", err);
-            ui_controller.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+            log::warn!("Failed to check for updates: {:#}", err);
             return;
         }
-        Ok(lock_file) => {
+        Ok(update_plan) => update_plan
+            .patch_list
+            .map_or(0, |patch_list| patch_list.len()),
+    };
+    if patch_count == 0 {
+        return;
+    }
+    log::info!("{} new patch(es) available", patch_count);
+    if config.web.auto_update {
+        update_game(
+            ui_controller,
+            config,
+            http_client,
+            session,
+            patcher_thread_rx,
+        )
+        .await;
+    } else {
+        ui_controller.dispatch_patching_status(PatchingStatus::UpdatesAvailable(patch_count));
+    }
+}
+
+/// Starts the automatic update process (download + patching). Returns true
+/// if every pending patch has been applied.
+async fn update_game(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> bool {
+    // Note: This must be done before taking the update lock, which the
+    // elevated helper takes
+    if let Some(is_up_to_date) = update_with_elevation(ui_controller, config) {
+        return is_up_to_date;
+    }
+    // Try taking the update lock
+    match take_update_lock() {
+        Err(err) => {
+            dispatch_update_lock_error(ui_controller, &err);
+            false
+        }
+        Ok(update_lock) => {
             // Tell the UI and other processes that we're currently working
             ui_controller.set_patch_in_progress(true);
             let _guard = scopeguard::guard((), |_| {
-                let _ = lock_file.unlock();
+                update_lock.release();
+                ui_controller.set_patch_in_progress(false);
+            });
+
+            let res = with_patching_hooks(
+                config,
+                interruptible_update_routine(
+                    ui_controller,
+                    config,
+                    http_client,
+                    session,
+                    patcher_thread_rx,
+                ),
+            )
+            .await;
+            dispatch_update_result(res, ui_controller)
+        }
+    }
+}
+
+/// Gets the update done with administrator privileges if the game's
+/// directory requires them (see `client.elevation`). Returns `None` if the
+/// update can go on in this process, whether every pending patch has been
+/// applied otherwise.
+fn update_with_elevation(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+) -> Option<bool> {
+    // Note: Failing to resolve the directory is reported by the update
+    let install_directory = resolve_install_directory(&config.client).ok()?;
+    match elevate_update_if_needed(config, &install_directory, ui_controller) {
+        Ok(ElevatedUpdate::NotNeeded) => None,
+        Ok(ElevatedUpdate::Relaunched) => {
+            ui_controller.request_exit();
+            Some(false)
+        }
+        Ok(ElevatedUpdate::Completed(is_up_to_date)) => {
+            ui_controller.dispatch_patching_status(PatchingStatus::Ready);
+            Some(is_up_to_date)
+        }
+        Err(err) => {
+            dispatch_error(ui_controller, "error.elevation", &err);
+            Some(false)
+        }
+    }
+}
+
+/// Does what `client.on_ready` says once the client is up to date (e.g.
+/// starts it).
+fn on_client_ready(ui_controller: &UiController, config: &PatcherConfiguration) {
+    let ready_action = config.client.on_ready.unwrap_or(ReadyAction::Stay);
+    if ready_action == ReadyAction::Stay {
+        return;
+    }
+    log::info!("Starting the game client");
+    let res = resolve_install_directory(&config.client).and_then(|install_directory| {
+        start_executable(
+            &config.play.path,
+            &install_directory,
+            &config.play.arguments,
+        )
+    });
+    match res {
+        Err(err) => log::warn!("Failed to start client: {:#}", err),
+        Ok(false) => log::warn!("Client wasn't started"),
+        Ok(true) if ready_action == ReadyAction::LaunchAndExit => ui_controller.request_exit(),
+        Ok(true) => {}
+    }
+}
+
+/// Reports the changes an update would make, without applying any patch
+async fn preview_update(
+    preview_mode: PreviewMode,
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) {
+    // Note: The lock is taken as well so that GRFs aren't read while another
+    // instance modifies them
+    match take_update_lock() {
+        Err(err) => dispatch_update_lock_error(ui_controller, &err),
+        Ok(update_lock) => {
+            ui_controller.set_patch_in_progress(true);
+            let _guard = scopeguard::guard((), |_| {
+                update_lock.release();
                 ui_controller.set_patch_in_progress(false);
             });
 
-            let res = interruptible_update_routine(ui_controller, config, patcher_thread_rx).await;
+            let res = interruptible_preview_routine(
+                ui_controller,
+                config,
+                http_client,
+                session,
+                patcher_thread_rx,
+                preview_mode,
+            )
+            .await;
             match res {
-                Err(err) => {
-                    log::error!("{:#}", err);
-                    ui_controller
-                        .dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+                Err(err) => dispatch_error(ui_controller, "error.preview", &err),
+                Ok(previews) => {
+                    for preview in &previews {
+                        log::info!(
+                            "Patch '{}' would modify {}:",
+                            preview.patch_name,
+                            preview
+                                .target_grf
+                                .as_deref()
+                                .unwrap_or("the game directory")
+                        );
+                        for entry in &preview.changes {
+                            log::info!("  {:?} {}", entry.change, entry.relative_path);
+                        }
+                    }
+                    log::info!("Preview finished, {} patch(es) pending", previews.len());
+                    ui_controller.dispatch_patching_status(PatchingStatus::Ready);
+                    ui_controller.dispatch_patching_status(PatchingStatus::Preview(previews));
                 }
-                Ok(()) => {
+            }
+        }
+    }
+}
+
+/// Restores the files modified by the last update, backed up before it was
+/// applied
+fn rollback_update(ui_controller: &UiController) {
+    // Try taking the update lock
+    match take_update_lock() {
+        Err(err) => dispatch_update_lock_error(ui_controller, &err),
+        Ok(update_lock) => {
+            ui_controller.set_patch_in_progress(true);
+            let _guard = scopeguard::guard((), |_| {
+                update_lock.release();
+                ui_controller.set_patch_in_progress(false);
+            });
+
+            log::info!("Rolling back the last update ...");
+            match get_backup_directory_path().and_then(rollback_session) {
+                Err(err) => dispatch_error(ui_controller, "error.rollback", &err),
+                Ok(file_count) => {
+                    log::info!(
+                        "Rolled back the last update, {} file(s) restored",
+                        file_count
+                    );
                     ui_controller.dispatch_patching_status(PatchingStatus::Ready);
-                    log::info!("Patching finished!");
+                    ui_controller.dispatch_patching_status(PatchingStatus::RolledBack(file_count));
                 }
             }
         }
     }
 }
 
-/// Applies a manual patch given by the user
-fn apply_single_patch(
-    patch_file_path: impl AsRef<Path>,
+/// Verifies the client's files against the manifest published by the server
+/// and repairs the broken ones
+async fn repair_installation(
     ui_controller: &UiController,
     config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
 ) {
     // Try taking the update lock
-    match take_update_lock().with_context(|| "Failed to take the update lock") {
+    match take_update_lock() {
+        Err(err) => dispatch_update_lock_error(ui_controller, &err),
+        Ok(update_lock) => {
+            ui_controller.set_patch_in_progress(true);
+            let _guard = scopeguard::guard((), |_| {
+                update_lock.release();
+                ui_controller.set_patch_in_progress(false);
+            });
+
+            let res = with_patching_hooks(
+                config,
+                interruptible_repair_routine(ui_controller, config, http_client, patcher_thread_rx),
+            )
+            .await;
+            match res {
+                Err(err) => dispatch_error(ui_controller, "error.repair", &err),
+                Ok(repaired_paths) => {
+                    log::info!("Repair finished, {} file(s) repaired", repaired_paths.len());
+                    ui_controller.dispatch_patching_status(PatchingStatus::Ready);
+                    ui_controller
+                        .dispatch_patching_status(PatchingStatus::Repaired(repaired_paths));
+                }
+            }
+        }
+    }
+}
+
+/// Switches to another channel for the next updates
+fn select_channel(
+    channel: Option<String>,
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    session: &mut SessionState,
+) {
+    if let Some(channel_name) = &channel {
+        if !config.channels.contains_key(channel_name) {
+            let err_msg = tr("error.unknown_channel", &[("channel", channel_name)]);
+            log::error!("{}", err_msg);
+            ui_controller.dispatch_patching_status(PatchingStatus::Error(err_msg));
+            return;
+        }
+    }
+    log::info!(
+        "Selected channel '{}'",
+        channel.as_deref().unwrap_or("default")
+    );
+    session.channel = channel.clone();
+    // Patch servers differ from one channel to another
+    session.patch_server = None;
+    ui_controller.dispatch_patching_status(PatchingStatus::ChannelSelected(channel));
+}
+
+/// Reports that the operation described by the message `message_key` failed
+/// because of `err`, in the user's language
+fn dispatch_error(ui_controller: &UiController, message_key: &str, err: &anyhow::Error) {
+    let err_msg = tr(message_key, &[("details", &format!("{:#}", err))]);
+    log::error!("{}", err_msg);
+    ui_controller.dispatch_patching_status(PatchingStatus::Error(err_msg));
+}
+
+/// Reports the outcome of an update to the UI. Returns true if every pending
+/// patch has been applied.
+fn dispatch_update_result(res: Result<UpdateSummary>, ui_controller: &UiController) -> bool {
+    match res {
         Err(err) => {
-            log::error!("{:#}", err);
-            ui_controller.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+            dispatch_error(ui_controller, "error.update", &err);
+            false
+        }
+        Ok(summary) => {
+            ui_controller.dispatch_patching_status(PatchingStatus::Ready);
+            log::info!("Patching finished!");
+            log::info!(
+                "Downloaded {} bytes in {} seconds ({} bytes/s), {} patch(es) applied, {} skipped",
+                summary.downloaded_bytes,
+                summary.elapsed_secs,
+                summary.average_bytes_per_sec,
+                summary.applied_patch_count,
+                summary.skipped_patches.len()
+            );
+            if !summary.protected_files.is_empty() {
+                log::info!(
+                    "Protected files left untouched: {}",
+                    summary.protected_files.join(", ")
+                );
+            }
+            if !summary.excluded_files.is_empty() {
+                log::info!(
+                    "{} excluded file(s) left untouched: {}",
+                    summary.excluded_files.len(),
+                    summary.excluded_files.join(", ")
+                );
+            }
+            let skipped_patches = summary.skipped_patches.clone();
+            ui_controller.dispatch_patching_status(PatchingStatus::Summary(summary));
+            if skipped_patches.is_empty() {
+                return true;
+            }
+            log::warn!("Skipped patches: {}", skipped_patches.join(", "));
+            ui_controller.dispatch_patching_status(PatchingStatus::PatchesSkipped(skipped_patches));
+            false
         }
-        Ok(lock_file) => {
+    }
+}
+
+/// Applies a manual patch given by the user
+async fn apply_single_patch(
+    patch_file_path: impl AsRef<Path>,
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+) {
+    // Try taking the update lock
+    match take_update_lock() {
+        Err(err) => dispatch_update_lock_error(ui_controller, &err),
+        Ok(update_lock) => {
             // Tell the UI and other processes that we're currently working
             ui_controller.set_patch_in_progress(true);
             let _guard = scopeguard::guard((), |_| {
-                let _ = lock_file.unlock();
+                update_lock.release();
                 ui_controller.set_patch_in_progress(false);
             });
 
-            let current_working_dir =
-                env::current_dir().with_context(|| "Failed to resolve current working directory");
-            match current_working_dir {
-                Err(err) => {
-                    log::error!("{:#}", err);
-                    ui_controller
-                        .dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
-                }
-                Ok(current_working_dir) => {
+            match resolve_install_directory(&config.client) {
+                Err(err) => dispatch_error(ui_controller, "error.install_directory", &err),
+                Ok(install_directory) => {
                     let patch_file_name = patch_file_path
                         .as_ref()
                         .file_name()
@@ -140,16 +803,39 @@ fn apply_single_patch(
                         .unwrap_or_default()
                         .to_string();
                     log::info!("Applying patch '{}'", patch_file_name);
-                    let res = apply_patch(patch_file_path, config, current_working_dir);
+                    let mut report_progress =
+                        patch_progress_reporter(patch_file_name.clone(), ui_controller.clone());
+                    let res = with_patching_hooks(config, async {
+                        check_running_client(config, ui_controller)?;
+                        let pending_renames =
+                            get_pending_renames_file_path().and_then(PendingRenames::load)?;
+                        let patch_info = ThorPatchInfo {
+                            file_name: patch_file_name.clone(),
+                            ..Default::default()
+                        };
+                        apply_patch(
+                            patch_file_path,
+                            &patch_info,
+                            config,
+                            install_directory,
+                            &pending_renames,
+                            &mut report_progress,
+                        )
+                    })
+                    .await;
                     match res {
                         Err(err) => {
-                            log::error!("{:#}", err);
-                            ui_controller.dispatch_patching_status(PatchingStatus::Error(format!(
-                                "{:#}",
-                                err
-                            )));
+                            let err_msg = tr(
+                                "error.manual_patch",
+                                &[
+                                    ("patch", &patch_file_name),
+                                    ("details", &format!("{:#}", err)),
+                                ],
+                            );
+                            log::error!("{}", err_msg);
+                            ui_controller.dispatch_patching_status(PatchingStatus::Error(err_msg));
                         }
-                        Ok(()) => {
+                        Ok(_) => {
                             log::info!("Done");
                             ui_controller.dispatch_patching_status(
                                 PatchingStatus::ManualPatchApplied(patch_file_name),
@@ -162,65 +848,320 @@ fn apply_single_patch(
     }
 }
 
-/// Takes an advisory lock that prevents multiple instances of the patcher to
-/// update the game at the same time
-fn take_update_lock() -> Result<std::fs::File> {
-    let lock_file_name = get_update_lock_file_path()?;
-    let lock_file = std::fs::File::create(lock_file_name)?;
-    lock_file.try_lock(FileLockMode::Exclusive)?;
+/// Applies a local patch file or all the patches found in a local directory,
+/// without contacting any patch server
+async fn apply_local_patches(
+    patch_path: impl AsRef<Path>,
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) {
+    // Try taking the update lock
+    match take_update_lock() {
+        Err(err) => dispatch_update_lock_error(ui_controller, &err),
+        Ok(update_lock) => {
+            // Tell the UI and other processes that we're currently working
+            ui_controller.set_patch_in_progress(true);
+            let _guard = scopeguard::guard((), |_| {
+                update_lock.release();
+                ui_controller.set_patch_in_progress(false);
+            });
 
-    Ok(lock_file)
+            let res = with_patching_hooks(
+                config,
+                interruptible_local_update_routine(
+                    patch_path,
+                    config,
+                    ui_controller,
+                    patcher_thread_rx,
+                ),
+            )
+            .await;
+            dispatch_update_result(res, ui_controller);
+        }
+    }
 }
 
-/// Main routine of the patching task.
-///
-/// This routine is written in a way that makes it interuptible (or cancellable)
-/// with a relatively low latency.
-async fn interruptible_update_routine(
+/// Downloads a patch located in the patch directory of one of the patch
+/// servers (e.g. a patch requested through a deep link) and applies it like a
+/// local patch.
+async fn apply_remote_patch(
+    patch_url: Url,
     ui_controller: &UiController,
     config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
     patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
-) -> Result<()> {
-    log::info!("Start patching");
+) {
+    match download_remote_patch(&patch_url, ui_controller, config, http_client).await {
+        Err(err) => dispatch_error(ui_controller, "error.remote_patch", &err),
+        // Note: The download directory is removed once the patch is applied
+        Ok((_download_dir, patch_file_path)) => {
+            apply_local_patches(patch_file_path, ui_controller, config, patcher_thread_rx).await;
+        }
+    }
+}
 
-    // Find a patch server that we can connect to
-    log::info!("Looking for an available patch server ...");
-    let (mut patch_list, patch_data_url) = find_available_patch_server(
-        config.web.patch_servers.as_slice(),
-        &config.web.preferred_patch_server,
-        patcher_thread_rx,
+/// Downloads the patch located at `patch_url` into a temporary directory.
+async fn download_remote_patch(
+    patch_url: &Url,
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+) -> Result<(tempfile::TempDir, PathBuf)> {
+    if !is_patch_server_url(&config.web.patch_servers, patch_url) {
+        return Err(anyhow!(
+            "'{}' isn't located on one of the patch servers",
+            patch_url
+        ));
+    }
+    let file_name = patch_url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|file_name| !file_name.is_empty())
+        .with_context(|| format!("Invalid patch URL '{}'", patch_url))?;
+    let patch_info = ThorPatchInfo {
+        file_name: file_name.to_string(),
+        ..Default::default()
+    };
+    let download_dir =
+        tempfile::tempdir().with_context(|| "Failed to create temporary directory")?;
+    let patch_file_path = download_file_path(download_dir.path(), file_name)?;
+    let pause_state = PauseState::new();
+    let bandwidth_limiter = bandwidth_limiter(config);
+    let settings = DownloadSettings {
+        in_memory_patches: false,
+        ..download_settings(config, bandwidth_limiter.as_ref(), &pause_state)
+    };
+    log::info!("Downloading '{}'", patch_url);
+    download_patch_over_http(
+        http_client,
+        patch_url,
+        &patch_info,
+        &patch_file_path,
+        &settings,
+        ui_controller,
+        |downloaded_bytes, total_bytes| {
+            ui_controller.dispatch_patching_status(PatchingStatus::DownloadProgress(
+                downloaded_bytes,
+                total_bytes,
+                0,
+                0,
+                None,
+            ));
+        },
     )
-    .await
-    .map_err(|e| match e {
-        InterruptibleFnError::Err(msg) => anyhow!(msg),
-        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
-    })?;
-    log::debug!("Successfully fetched patch list: {:?}", patch_list);
+    .await?;
+    Ok((download_dir, patch_file_path))
+}
 
-    // Try to read cache
-    let cache_file_path =
-        get_cache_file_path().with_context(|| "Failed to resolve patcher name")?;
-    if let Ok(patcher_cache) = read_cache_file(&cache_file_path).await {
-        // Ignore already applied patches if needed
-        // First we verify that our cached index looks relevant
-        let should_filter_patch_list = patch_list
-            .iter()
-            .any(|x| x.index == patcher_cache.last_patch_index);
-        if should_filter_patch_list {
-            patch_list.retain(|x| x.index > patcher_cache.last_patch_index);
+/// Indicates whether `url` is located in the patch directory of one of
+/// `patch_servers`, the only places patches requested from outside the
+/// patcher are downloaded from.
+fn is_patch_server_url(patch_servers: &[PatchServerInfo], url: &Url) -> bool {
+    patch_servers
+        .iter()
+        .any(|patch_server| match Url::parse(&patch_server.patch_url) {
+            Err(_) => false,
+            Ok(patch_directory_url) => {
+                let directory = patch_directory_url.path().trim_end_matches('/');
+                patch_directory_url.origin() == url.origin()
+                    && url
+                        .path()
+                        .strip_prefix(directory)
+                        .map_or(false, |file_path| file_path.starts_with('/'))
+            }
+        })
+}
+
+/// Runs `patching` between the pre-patch hooks and the post-patch (or
+/// failure) hooks. Patching doesn't start if a pre-patch hook fails, while
+/// failures of the other hooks are only logged.
+async fn with_patching_hooks<T>(
+    config: &PatcherConfiguration,
+    patching: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let res = match run_patching_hooks(HookStage::PrePatch, config).await {
+        Err(err) => Err(err),
+        Ok(()) => patching.await,
+    };
+    let stage = match res {
+        Ok(_) => HookStage::PostPatch,
+        Err(_) => HookStage::PatchFailed,
+    };
+    if let Err(err) = run_patching_hooks(stage, config).await {
+        log::warn!("{:#}", err);
+    }
+    res
+}
+
+/// Runs the hooks configured for `stage` from the installation directory.
+async fn run_patching_hooks(stage: HookStage, config: &PatcherConfiguration) -> Result<()> {
+    let install_directory = resolve_install_directory(&config.client)?;
+    run_hooks(stage, &config.hooks, &install_directory).await
+}
+
+/// Checks that the game client isn't running, since the files it has opened
+/// cannot be patched safely. What happens otherwise depends on
+/// `client.when_running`.
+fn check_running_client(config: &PatcherConfiguration, ui_controller: &UiController) -> Result<()> {
+    let running_executable = config.client.executables.iter().find(|executable_name| {
+        is_process_running(executable_name).unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to check whether '{}' is running: {:#}",
+                executable_name,
+                e
+            );
+            false
+        })
+    });
+    let executable_name = match running_executable {
+        Some(executable_name) => executable_name,
+        None => return Ok(()),
+    };
+    log::warn!("'{}' is running", executable_name);
+    match config
+        .client
+        .when_running
+        .unwrap_or(RunningClientPolicy::Prompt)
+    {
+        RunningClientPolicy::Warn => ui_controller.warn_running_client(executable_name),
+        RunningClientPolicy::Prompt => {
+            if !ui_controller.prompt_running_client(executable_name) {
+                return Err(anyhow!("Patching was canceled"));
+            }
+        }
+        RunningClientPolicy::Block => {
+            return Err(anyhow!(
+                "'{}' is running, close the game client before patching",
+                executable_name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Takes the lock that prevents multiple instances of the patcher to update
+/// the game at the same time
+fn take_update_lock() -> Result<UpdateLock> {
+    UpdateLock::acquire(get_update_lock_file_path()?)
+}
+
+/// Reports a failure to take the update lock, which is usually held by
+/// another instance of the patcher
+fn dispatch_update_lock_error(ui_controller: &UiController, err: &anyhow::Error) {
+    match err.downcast_ref::<AlreadyPatching>() {
+        Some(already_patching) => {
+            log::warn!("{}", already_patching);
+            ui_controller.dispatch_patching_status(PatchingStatus::AlreadyPatching);
+        }
+        None => dispatch_error(ui_controller, "error.update_lock", err),
+    }
+}
+
+/// Main routine of the patching task.
+///
+/// This routine is written in a way that makes it interuptible (or cancellable)
+/// with a relatively low latency.
+///
+/// Returns statistics about the session.
+async fn interruptible_update_routine(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> Result<UpdateSummary> {
+    log::info!("Start patching");
+    check_running_client(config, ui_controller)?;
+    let start = Instant::now();
+    let pause_state = PauseState::new();
+    // The journal of the last session is only left behind if the patcher
+    // crashed while updating the game
+    let session_journal_file_path = get_session_journal_file_path()?;
+    let interrupted_session = SessionJournal::load(&session_journal_file_path)
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to read session journal: {:#}", e);
+            None
+        })
+        .filter(|session_journal| session_journal.download_directory.is_dir());
+    let resumed_download_dir = interrupted_session
+        .as_ref()
+        .map(|session_journal| session_journal.download_directory.clone());
+    let _session_guard = scopeguard::guard((), |_| {
+        if let Err(e) = remove_session_journal(&session_journal_file_path) {
+            log::warn!("Failed to remove session journal: {:#}", e);
+        }
+        // Note: Temporary directories are only removed automatically by the
+        // session that creates them
+        if let Some(download_dir) = &resumed_download_dir {
+            if !config.web.keep_downloads {
+                let _ = std::fs::remove_dir_all(download_dir);
+            }
+        }
+    });
+    let update_plan = fetch_update_plan(
+        ui_controller,
+        config,
+        http_client,
+        session,
+        &pause_state,
+        patcher_thread_rx,
+    )
+    .await?;
+    let mut patch_list = match update_plan.patch_list {
+        Some(patch_list) => patch_list,
+        None => {
+            return Ok(UpdateSummary {
+                elapsed_secs: start.elapsed().as_secs(),
+                ..Default::default()
+            });
         }
     };
+    if let Some(session_journal) = &interrupted_session {
+        log::info!(
+            "Resuming interrupted update ({} patch(es) downloaded, {} applied)",
+            session_journal.downloaded_patches.len(),
+            session_journal.applied_patches.len()
+        );
+        patch_list.retain(|patch_info| !session_journal.is_applied(&patch_info.file_name));
+    }
+    // Refuse to apply patches onto a base they weren't built for
+    let last_patch_index = read_cache_file(&update_plan.cache_file_path)
+        .await
+        .ok()
+        .map(|patcher_cache| patcher_cache.last_patch_index);
+    check_patch_prerequisites(&patch_list, last_patch_index, PATCHER_VERSION)?;
+    check_patch_file_names(&patch_list)?;
 
     // Try fetching patch files
     log::info!("Downloading patches ...");
-    let patch_url =
-        Url::parse(patch_data_url.as_str()).with_context(|| "Failed to parse 'patch_url'")?;
-    let tmp_dir = tempfile::tempdir().with_context(|| "Failed to create temporary directory")?;
-    let pending_patch_queue = download_patches_concurrent(
+    let patch_url = update_plan.patch_url;
+    // Note: Patches downloaded by the interrupted session are reused
+    let (download_dir, _tmp_dir) = match &resumed_download_dir {
+        Some(download_dir) => (download_dir.clone(), None),
+        None => prepare_download_directory(config, &update_plan.download_directory)?,
+    };
+    let mut session_journal = SessionJournal {
+        download_directory: download_dir.clone(),
+        ..interrupted_session.unwrap_or_default()
+    };
+    if let Err(e) = session_journal.save(&session_journal_file_path) {
+        log::warn!("Failed to write session journal: {:#}", e);
+    }
+    // Abort early if there isn't enough space to download and apply patches
+    if !patch_list.is_empty() {
+        check_available_disk_space(http_client, &patch_url, &patch_list, &download_dir, config)
+            .await?;
+    }
+    let bandwidth_limiter = bandwidth_limiter(config);
+    let download_settings = download_settings(config, bandwidth_limiter.as_ref(), &pause_state);
+    let download_start = Instant::now();
+    let download_outcome = download_patches_concurrent(
+        http_client,
         patch_url,
         patch_list,
-        tmp_dir.path(),
-        config.patching.check_integrity,
+        &download_dir,
+        &download_settings,
         &ui_controller,
         patcher_thread_rx,
     )
@@ -230,13 +1171,36 @@ async fn interruptible_update_routine(
         InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
     })?;
     log::info!("Patches have been downloaded");
+    let download_duration = download_start.elapsed();
+    session_journal.downloaded_patches = download_outcome
+        .pending_patches
+        .iter()
+        .filter(|patch| matches!(patch.content, PatchContent::File(_)))
+        .map(|patch| patch.info.file_name.clone())
+        .collect();
+    if let Err(e) = session_journal.save(&session_journal_file_path) {
+        log::warn!("Failed to write session journal: {:#}", e);
+    }
 
     // Proceed with actual patching
+    let cache_file_path = update_plan.cache_file_path;
+    if config.patching.backup && !download_outcome.pending_patches.is_empty() {
+        log::info!("Backing up files ...");
+        back_up_pending_patches(
+            &download_outcome.pending_patches,
+            config,
+            Some(&cache_file_path),
+        )?;
+    }
     log::info!("Applying patches ...");
-    apply_patches(
-        pending_patch_queue,
+    let pending_patch_count = download_outcome.pending_patches.len();
+    let mut skipped_patches = download_outcome.skipped_patches;
+    let installation_outcome = apply_patches(
+        download_outcome.pending_patches,
         config,
-        &cache_file_path,
+        Some(&cache_file_path),
+        Some(&session_journal_file_path),
+        &pause_state,
         &ui_controller,
         patcher_thread_rx,
     )
@@ -246,395 +1210,3169 @@ async fn interruptible_update_routine(
         InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
     })?;
     log::info!("Patches have been applied");
+    let applied_patch_count = pending_patch_count - installation_outcome.skipped_patches.len();
+    skipped_patches.extend(installation_outcome.skipped_patches);
 
-    Ok(())
+    // Remember the patch list's validators, so that it's only processed again
+    // once it's been modified (or so that skipped patches are retried)
+    let validators = update_plan
+        .validators
+        .filter(|_| skipped_patches.is_empty());
+    if let Some(validators) = validators {
+        if let Ok(mut patcher_cache) = read_cache_file(&cache_file_path).await {
+            patcher_cache.patch_list_validators = Some(validators);
+            if let Err(e) = write_cache_file(&cache_file_path, patcher_cache).await {
+                log::warn!("Failed to write cache file: {}.", e);
+            }
+        }
+    }
+
+    Ok(UpdateSummary {
+        downloaded_bytes: download_outcome.downloaded_bytes,
+        elapsed_secs: start.elapsed().as_secs(),
+        average_bytes_per_sec: (download_outcome.downloaded_bytes as f64
+            / download_duration.as_secs_f64().max(f64::EPSILON))
+        .round() as u64,
+        applied_patch_count,
+        skipped_patches,
+        protected_files: installation_outcome.protected_files,
+        excluded_files: installation_outcome.excluded_files,
+    })
 }
 
-/// Iterates through `server_list` and returns the first available server's info.
-/// `preferred_server_name` is checked first if present.
-async fn find_available_patch_server(
-    server_list: &[PatchServerInfo],
-    preferred_server_name: &Option<String>,
-    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
-) -> InterruptibleFnResult<(ThorPatchList, Url)> {
-    // Probe the preferred server first if it's specified and valid
-    if let Some(preferred_server_name) = preferred_server_name {
-        let preferred_server = server_list
-            .iter()
-            .find(|s| &s.name == preferred_server_name);
-        if let Some(preferred_server) = preferred_server {
-            if let Ok((patch_list, patch_url)) = probe_patch_server(preferred_server).await {
-                return Ok((patch_list, patch_url));
-            } else {
-                log::warn!("'{}' is unavailable", preferred_server_name);
+/// Verifies the client's files (and GRF entries) against the manifest located
+/// under `web.repair_url`, then downloads the pristine content of the broken
+/// ones and applies it like a patch.
+///
+/// Returns the paths of the repaired files.
+async fn interruptible_repair_routine(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> Result<Vec<String>> {
+    let repair_url = config
+        .web
+        .repair_url
+        .as_deref()
+        .context("No repair URL is configured")?;
+    let mut repair_url = Url::parse(repair_url).context("Invalid repair URL")?;
+    // Note: Files are located relatively to the URL's directory
+    if !repair_url.path().ends_with('/') {
+        repair_url.set_path(&format!("{}/", repair_url.path()));
+    }
+    check_running_client(config, ui_controller)?;
+    let install_directory = resolve_install_directory(&config.client)?;
+    let pause_state = PauseState::new();
+
+    log::info!("Fetching repair manifest ...");
+    let manifest_url = repair_url.join(MANIFEST_FILE_NAME)?;
+    let manifest = match fetch_text_file(
+        http_client,
+        &manifest_url,
+        None,
+        &config.web,
+        ui_controller,
+    )
+    .await?
+    {
+        RemoteTextFile::Modified(content, _) => parse_manifest(&content)?,
+        RemoteTextFile::NotModified => return Err(anyhow!("Failed to fetch repair manifest")),
+    };
+    log::info!("Verifying {} files ...", manifest.files.len());
+    let verification_task = {
+        let install_directory = install_directory.clone();
+        let name_encoding = name_encoding(config);
+        let ui_controller = ui_controller.clone();
+        let mut last_report: Option<Instant> = None;
+        tokio::task::spawn_blocking(move || {
+            find_broken_entries(
+                &manifest,
+                &install_directory,
+                name_encoding,
+                &mut |nb_checked, nb_total| {
+                    let now = Instant::now();
+                    let is_throttled = last_report.map_or(false, |last_report| {
+                        now.duration_since(last_report) < PATCH_PROGRESS_INTERVAL
+                    });
+                    if is_throttled && nb_checked < nb_total {
+                        return;
+                    }
+                    last_report = Some(now);
+                    ui_controller.dispatch_patching_status(PatchingStatus::VerificationInProgress(
+                        nb_checked, nb_total,
+                    ));
+                },
+            )
+        })
+    };
+    let (res, interruption) = wait_for_blocking_task(
+        verification_task,
+        patcher_thread_rx,
+        &pause_state,
+        ui_controller,
+    )
+    .await;
+    if interruption.is_some() {
+        return Err(anyhow!("Repair was canceled"));
+    }
+    let broken_entries = res?;
+    if broken_entries.is_empty() {
+        log::info!("All files are intact");
+        return Ok(vec![]);
+    }
+    for entry in &broken_entries {
+        log::info!("'{}' is missing or corrupt", entry.display_path());
+    }
+
+    // Pristine content is gathered into THOR archives, one per GRF (and one
+    // for the files on disk), and applied like patches
+    log::info!("Downloading {} files ...", broken_entries.len());
+    let download_dir =
+        tempfile::tempdir().with_context(|| "Failed to create temporary directory")?;
+    let mut entries_by_target: BTreeMap<Option<&str>, Vec<&ManifestEntry>> = BTreeMap::new();
+    for entry in &broken_entries {
+        entries_by_target
+            .entry(entry.grf.as_deref())
+            .or_default()
+            .push(entry);
+    }
+    let mut pending_patches = vec![];
+    let mut nb_downloaded = 0;
+    for (target_grf, entries) in entries_by_target {
+        let file_name = format!("repair_{}.thor", pending_patches.len());
+        let archive_path = download_dir.path().join(&file_name);
+        let mut builder = ThorArchiveBuilder::new(
+            std::fs::File::create(&archive_path)?,
+            target_grf.is_some(),
+            target_grf.map(str::to_string),
+            false,
+        )?;
+        for entry in entries {
+            process_incoming_commands(patcher_thread_rx, &pause_state, ui_controller)
+                .await
+                .map_err(|_| anyhow!("Repair was canceled"))?;
+            let content = fetch_repair_entry(http_client, &entry.url(&repair_url)?, config).await?;
+            if !entry.is_content_valid(content.as_slice())? {
+                return Err(anyhow!(
+                    "Content of '{}' doesn't match the manifest",
+                    entry.display_path()
+                ));
             }
-        } else {
-            log::warn!(
-                "'{}' isn't in the list of patch servers",
-                preferred_server_name
-            );
+            builder.append_file_update(entry.path.clone(), content.as_slice())?;
+            nb_downloaded += 1;
+            ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(
+                nb_downloaded,
+                broken_entries.len(),
+                0,
+            ));
         }
+        builder.finish()?;
+        pending_patches.push(PendingPatch {
+            info: ThorPatchInfo {
+                file_name,
+                target_grf: target_grf.map(str::to_string),
+                ..Default::default()
+            },
+            content: PatchContent::File(archive_path),
+        });
     }
 
-    // Probe other servers, if any
-    for server in server_list {
-        // Cancel the patching process if we've been asked to or if the other
-        // end of the channel has been disconnected
-        process_incoming_commands(patching_thread_rx)?;
-        if let Ok((patch_list, patch_url)) = probe_patch_server(server).await {
-            return Ok((patch_list, patch_url));
-        } else {
-            log::warn!("'{}' is unavailable", server.name);
-        }
+    if config.patching.backup {
+        log::info!("Backing up files ...");
+        back_up_pending_patches(&pending_patches, config, None)?;
+    }
+    log::info!("Repairing files ...");
+    let installation_outcome = apply_patches(
+        pending_patches,
+        config,
+        None,
+        None,
+        &pause_state,
+        ui_controller,
+        patcher_thread_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => anyhow!("Failed to repair files: {}", msg),
+        InterruptibleFnError::Interrupted => anyhow!("Repair was canceled"),
+    })?;
+    if !installation_outcome.skipped_patches.is_empty() {
+        return Err(anyhow!("Some files couldn't be repaired"));
     }
+    Ok(broken_entries
+        .iter()
+        .filter(|entry| {
+            !installation_outcome.protected_files.contains(&entry.path)
+                && !installation_outcome.excluded_files.contains(&entry.path)
+        })
+        .map(ManifestEntry::display_path)
+        .collect())
+}
 
-    Err(InterruptibleFnError::Err(
-        "None of the patch servers are available at the moment".to_string(),
-    ))
+/// Downloads the pristine content of a file listed in the repair manifest.
+async fn fetch_repair_entry(
+    client: &reqwest::Client,
+    url: &Url,
+    config: &PatcherConfiguration,
+) -> Result<Vec<u8>> {
+    if is_local_url(url) {
+        let path = local_path_from_url(url)?;
+        return tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read '{}'", path.display()));
+    }
+    let read_timeout = read_timeout(&config.web);
+    let mut resp = with_read_timeout(read_timeout, client.get(url.clone()).send())
+        .await
+        .with_context(|| format!("Failed to download '{}'", url))?
+        .error_for_status()
+        .with_context(|| format!("'{}' is unavailable on the remote server", url))?;
+    let mut content = Vec::with_capacity(resp.content_length().unwrap_or(0) as usize);
+    while let Some(chunk) = with_read_timeout(read_timeout, resp.chunk())
+        .await
+        .with_context(|| format!("Failed to download '{}'", url))?
+    {
+        content.extend_from_slice(&chunk[..]);
+    }
+    Ok(content)
 }
 
-/// Checks whether a patch server is up or not.
-/// Returns the list of patches served by the server as well as the URL to
-/// download them from.
-async fn probe_patch_server(server_info: &PatchServerInfo) -> Result<(ThorPatchList, Url)> {
-    let client = reqwest::Client::new();
-    // Parse URLs
-    let patch_list_url = Url::parse(server_info.plist_url.as_str())
-        .with_context(|| "Failed to parse 'plist_url'")?;
-    let patch_url = Url::parse(server_info.patch_url.as_str())
-        .with_context(|| "Failed to parse 'patch_url'")?;
+/// Finds an available patch server and fetches the list of the patches that
+/// haven't been applied yet.
+async fn fetch_update_plan(
+    ui_controller: &UiController,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
+    pause_state: &PauseState,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> Result<UpdatePlan> {
+    let channel = resolve_update_channel(config, session.channel.as_deref())?;
 
-    // Fetch plist
-    let patch_list = fetch_patch_list(patch_list_url)
-        .await
-        .with_context(|| "Failed to retrieve the patch list")?;
+    // Find a patch server that we can connect to. The server selected earlier
+    // in the session (if any) takes precedence over the preferred server.
+    log::info!("Looking for an available patch server ...");
+    let preferred_patch_server = session
+        .patch_server
+        .clone()
+        .or_else(|| config.web.preferred_patch_server.clone());
+    let (remote_patch_list, patch_url, patch_server_name) = find_available_patch_server(
+        http_client,
+        &channel,
+        &preferred_patch_server,
+        &config.web,
+        pause_state,
+        ui_controller,
+        patcher_thread_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => anyhow!(msg),
+        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
+    })?;
+    log::info!("Using patch server '{}'", patch_server_name);
+    ui_controller.dispatch_patching_status(PatchingStatus::PatchServerSelected(
+        patch_server_name.clone(),
+    ));
+    session.patch_server = Some(patch_server_name);
+    let mut update_plan = UpdatePlan {
+        patch_list: None,
+        validators: remote_patch_list.validators,
+        patch_url,
+        cache_file_path: channel.cache_file_path,
+        download_directory: channel.download_directory,
+    };
+    let mut patch_list = match remote_patch_list.patches {
+        Some(patch_list) => patch_list,
+        None => {
+            log::info!("Patch list hasn't changed since the last update");
+            return Ok(update_plan);
+        }
+    };
+    log::debug!("Successfully fetched patch list: {:?}", patch_list);
 
-    // Ensure that the server serves the patches (check the first patch of the list)
-    if let Some(patch_info) = patch_list.get(0) {
-        let patch_resp = client
-            .head(patch_url.join(patch_info.file_name.as_str())?)
-            .send()
-            .await
-            .with_context(|| "Failed to HEAD URL")?;
-        // Return on error
-        patch_resp.error_for_status()?;
-    }
+    // Try to read cache
+    if let Ok(patcher_cache) = read_cache_file(&update_plan.cache_file_path).await {
+        // Ignore already applied patches if needed
+        // First we verify that our cached index looks relevant
+        let should_filter_patch_list = patch_list
+            .iter()
+            .any(|x| x.index == patcher_cache.last_patch_index);
+        if should_filter_patch_list {
+            // Patches re-released under an index that's already been applied
+            // must be applied again
+            patch_list.retain(|x| {
+                let is_rereleased =
+                    x.index <= patcher_cache.last_patch_index && patcher_cache.is_rereleased(x);
+                if is_rereleased {
+                    log::info!("Patch '{}' has been re-released", x.file_name);
+                }
+                x.index > patcher_cache.last_patch_index || is_rereleased
+            });
+        }
+    };
+    update_plan.patch_list = Some(patch_list);
+    Ok(update_plan)
+}
 
-    Ok((patch_list, patch_url))
+/// Verifies that the names of the patches in `patch_list` are plain file
+/// names, as patches are fetched and downloaded under these names.
+fn check_patch_file_names(patch_list: &[ThorPatchInfo]) -> Result<()> {
+    for patch_info in patch_list {
+        check_patch_file_name(&patch_info.file_name)?;
+    }
+    Ok(())
 }
 
-/// Downloads and parses a 'plist.txt' file located as the URL contained in the
-/// `patch_list_url` argument.
-///
-/// Returns a vector of `ThorPatchInfo` in case of success.
-async fn fetch_patch_list(patch_list_url: Url) -> Result<ThorPatchList> {
-    let resp = reqwest::get(patch_list_url)
-        .await
-        .with_context(|| "Failed to GET URL")?;
-    if !resp.status().is_success() {
-        return Err(anyhow!("Patch list file not found on the remote server"));
+/// Rejects names that would designate a file outside of the patch server's or
+/// the download directory (e.g. '../data.grf', '/tmp/file.thor',
+/// 'C:file.thor' or 'https://other.host/file.thor').
+fn check_patch_file_name(file_name: &str) -> Result<()> {
+    let is_plain_file_name =
+        !matches!(file_name, "" | "." | "..") && !file_name.contains(&['/', '\\', ':', '\0'][..]);
+    if !is_plain_file_name {
+        return Err(anyhow!("Invalid patch file name '{}'", file_name));
     }
-    let patch_index_content = resp.text().await.with_context(|| "Invalid responde body")?;
-    log::info!("Parsing patch index...");
+    Ok(())
+}
 
-    Ok(thor::patch_list_from_string(patch_index_content.as_str()))
+/// Returns the path of the file named `file_name` in `download_directory`.
+fn download_file_path(download_directory: &Path, file_name: &str) -> Result<PathBuf> {
+    check_patch_file_name(file_name)?;
+    Ok(download_directory.join(file_name))
 }
 
-/// Returns the patcher cache file's name as a `PathBuf` on success.
-fn get_cache_file_path() -> Result<PathBuf> {
-    get_instance_asset_file_name("dat")
+/// Returns the URL of the patch named `file_name` on the patch server serving
+/// patches from `patch_url`.
+fn patch_file_url(patch_url: &Url, file_name: &str) -> Result<Url> {
+    check_patch_file_name(file_name)?;
+    patch_url
+        .join(file_name)
+        .with_context(|| "Failed to generate URL for patch file")
 }
 
-/// Returns the patcher update lock file's name as a `PathBuf` on success.
-fn get_update_lock_file_path() -> Result<PathBuf> {
-    get_instance_asset_file_name("lock")
+/// Verifies that the pending patches in `patch_list` can be applied by this
+/// version of the patcher, and that the patches they require are installed
+/// (`last_patch_index` being the index of the last patch installed) or pending.
+fn check_patch_prerequisites(
+    patch_list: &[ThorPatchInfo],
+    last_patch_index: Option<usize>,
+    patcher_version: &str,
+) -> Result<()> {
+    for (position, patch_info) in patch_list.iter().enumerate() {
+        if let Some(min_patcher_version) = &patch_info.min_patcher_version {
+            if is_older_version(patcher_version, min_patcher_version) {
+                return Err(anyhow!(
+                    "Patch '{}' requires version {} of the patcher (current version: {}), \
+                     download the latest version of the patcher",
+                    patch_info.file_name,
+                    min_patcher_version,
+                    patcher_version
+                ));
+            }
+        }
+        if let Some(required_index) = patch_info.requires_index {
+            let is_installed = last_patch_index.map_or(false, |index| index >= required_index);
+            let is_pending = patch_list[..position]
+                .iter()
+                .any(|pending_patch| pending_patch.index == required_index);
+            if !is_installed && !is_pending {
+                return Err(anyhow!(
+                    "Patch '{}' requires patch #{}, which isn't installed. \
+                     Reinstall the game client or apply the missing patches manually",
+                    patch_info.file_name,
+                    required_index
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Generates asset file names which are associated with the current 'instance'
-/// of the patcher.
-fn get_instance_asset_file_name(extension: impl AsRef<std::ffi::OsStr>) -> Result<PathBuf> {
-    let patcher_name = get_patcher_name()?;
+/// Indicates whether `version` is older than `other_version`. Versions are
+/// compared component by component ("0.10.0" is newer than "0.9.1"), suffixes
+/// such as "-beta" being ignored.
+fn is_older_version(version: &str, other_version: &str) -> bool {
+    let components = |version: &str| -> Vec<u64> {
+        let mut components = version
+            .trim()
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|component| component.parse().unwrap_or(0))
+            .collect::<Vec<u64>>();
+        // Note: "1.0" and "1.0.0" are the same version
+        while components.last() == Some(&0) {
+            components.pop();
+        }
+        components
+    };
+    components(version) < components(other_version)
+}
 
-    Ok(PathBuf::from(patcher_name).with_extension(extension))
+/// Returns the limiter of the download speed, if one is configured.
+fn bandwidth_limiter(config: &PatcherConfiguration) -> Option<BandwidthLimiter> {
+    config
+        .web
+        .max_download_speed
+        .map(|kib_per_sec| BandwidthLimiter::new(kib_per_sec.saturating_mul(1024)))
 }
 
-/// Downloads a list of patches (described with a `ThorPatchList`).
-///
-/// Files are downloaded from the remote directory located at the URL
-/// contained in the 'patch_url' argument.
-///
-/// This function is interruptible.
-async fn download_patches_concurrent(
-    patch_url: Url,
-    patch_list: ThorPatchList,
-    download_directory: impl AsRef<Path>,
-    ensure_integrity: bool,
-    ui_controller: &UiController,
-    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
-) -> InterruptibleFnResult<Vec<PendingPatch>> {
-    let patch_count = patch_list.len();
-    ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(0, patch_count, 0));
-    // Download files in a cancelable manner
-    let mut vec = tokio::select! {
-        cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
-        download_res = download_patches_concurrent_inner(patch_url, patch_list, download_directory, ensure_integrity, ui_controller) => {
-            download_res.map_err(|e| InterruptibleFnError::Err(format!("{:#}", e)))
-        },
-    }?;
-    // Sort patches by index before returning
-    vec.sort_unstable_by(|l, r| l.info.index.cmp(&r.info.index));
-    Ok(vec)
+fn download_settings<'a>(
+    config: &'a PatcherConfiguration,
+    bandwidth_limiter: Option<&'a BandwidthLimiter>,
+    pause_state: &'a PauseState,
+) -> DownloadSettings<'a> {
+    DownloadSettings {
+        ensure_integrity: config.patching.check_integrity,
+        retry_config: &config.web.retry,
+        bandwidth_limiter,
+        read_timeout: read_timeout(&config.web),
+        pause_state,
+        torrent_config: config.web.torrent.as_ref(),
+        failure_policy: config.patching.on_failure.unwrap_or(FailurePolicy::Abort),
+        download_segments: config
+            .web
+            .download_segments
+            .unwrap_or(DEFAULT_DOWNLOAD_SEGMENTS)
+            .max(1),
+        // Patches kept across restarts must be stored on disk
+        in_memory_patches: !config.web.keep_downloads,
+    }
 }
 
-/// Actual implementation of the concurrent file download
+/// Dry-run counterpart of `interruptible_update_routine`, which reports the
+/// changes the pending patches would make instead of applying them.
 ///
-/// Returns an unordered vector of `PendingPatch`.
-async fn download_patches_concurrent_inner(
-    patch_url: Url,
-    patch_list: ThorPatchList,
-    download_directory: impl AsRef<Path>,
-    ensure_integrity: bool,
+/// With `PreviewMode::HeadersOnly`, only the parts of the patches needed to
+/// list their entries are fetched.
+async fn interruptible_preview_routine(
     ui_controller: &UiController,
-) -> Result<Vec<PendingPatch>> {
-    const CONCURRENT_DOWNLOADS: usize = 32;
-    const ONE_SECOND: Duration = Duration::from_secs(1);
-    // Shared reqwest client
-    let client = reqwest::Client::new();
-    // Shared value that contains the number of downloaded patches
-    let shared_patch_number = AtomicUsize::new(0_usize);
-    // Shared tuple that's used to compute the download speed
-    let shared_progress_state = Arc::new(std::sync::Mutex::new((Instant::now(), 0_u64)));
-
-    // Collect stream of "PendingPatch" concurrently with an unordered_buffer
-    let patch_count = patch_list.len();
-    futures::stream::iter(patch_list.into_iter().map(|patch_info| async {
-        let client = &client;
-        let patch_file_url = patch_url
-            .join(patch_info.file_name.as_str())
-            .with_context(|| "Failed to generate URL for patch file")?;
-        let local_file_path = download_directory
-            .as_ref()
-            .join(patch_info.file_name.as_str());
-        let mut tmp_file = File::create(&local_file_path)
-            .await
-            .with_context(|| "Failed to create temporary file")?;
-
-        // Setup a progress callback that'll send the current download speed to the UI
-        let shared_patch_number_ref = &shared_patch_number;
-        let shared_state = shared_progress_state.clone();
-        let mut last_downloaded_bytes: u64 = 0;
-        let mut progress_callback = move |dl_now, _| {
-            let dl_delta = dl_now - last_downloaded_bytes;
-            // Return download speed if the required time has elapsed (1s)
-            let downloaded_bytes_per_sec = {
-                if let Ok(mut shared_state) = shared_state.lock() {
-                    shared_state.1 += dl_delta;
-                    if shared_state.0.elapsed() >= ONE_SECOND {
-                        let downloaded_bytes_per_sec = (shared_state.1 as f32
-                            / shared_state.0.elapsed().as_secs_f32())
-                        .round() as u64;
-                        shared_state.0 = Instant::now();
-                        shared_state.1 = 0;
-                        Some(downloaded_bytes_per_sec)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            };
-            // If speed is "available", update UI
-            if let Some(downloaded_bytes_per_sec) = downloaded_bytes_per_sec {
-                block_on(async {
-                    ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(
-                        shared_patch_number_ref.load(Ordering::SeqCst),
-                        patch_count,
-                        downloaded_bytes_per_sec,
+    config: &PatcherConfiguration,
+    http_client: &reqwest::Client,
+    session: &mut SessionState,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    preview_mode: PreviewMode,
+) -> Result<Vec<PatchPreview>> {
+    log::info!("Start previewing");
+    let pause_state = PauseState::new();
+    let update_plan = fetch_update_plan(
+        ui_controller,
+        config,
+        http_client,
+        session,
+        &pause_state,
+        patcher_thread_rx,
+    )
+    .await?;
+    let patch_list = update_plan.patch_list.unwrap_or_default();
+    check_patch_file_names(&patch_list)?;
+    let patch_url = update_plan.patch_url;
+    let install_directory = resolve_install_directory(&config.client)?;
+    let mut previewer = PatchPreviewer::new(
+        install_directory,
+        name_encoding(config),
+        unsafe_path_handling(config),
+        path_matching(config),
+        skipped_file_patterns(config),
+    );
+    match preview_mode {
+        PreviewMode::HeadersOnly => {
+            log::info!("Fetching patches' headers ...");
+            let mut previews = Vec::with_capacity(patch_list.len());
+            for patch_info in patch_list {
+                process_incoming_commands(patcher_thread_rx, &pause_state, ui_controller)
+                    .await
+                    .map_err(|e| match e {
+                        InterruptibleFnError::Err(msg) => anyhow!(msg),
+                        InterruptibleFnError::Interrupted => anyhow!("Preview was canceled"),
+                    })?;
+                if PatchFormat::from_file_name(&patch_info.file_name) != PatchFormat::Thor {
+                    return Err(anyhow!(
+                        "'{}' isn't a THOR patch and can only be previewed once downloaded",
+                        patch_info.file_name
                     ));
-                });
+                }
+                let patch_file_url = patch_file_url(&patch_url, &patch_info.file_name)?;
+                let partial_patch =
+                    fetch_patch_headers(http_client, &patch_file_url, read_timeout(&config.web))
+                        .await
+                        .with_context(|| format!("Failed to fetch '{}'", patch_info.file_name))?;
+                let thor_archive =
+                    ThorArchive::new_with_encoding(partial_patch, name_encoding(config))
+                        .with_context(|| format!("Failed to parse '{}'", patch_info.file_name))?;
+                let target_grf_name = target_grf_name(
+                    &thor_archive,
+                    &patch_info.file_name,
+                    patch_info.target_grf.as_deref(),
+                    &config.client,
+                );
+                previews.push(previewer.preview_thor_archive(
+                    patch_info.file_name,
+                    &thor_archive,
+                    &target_grf_name,
+                )?);
+            }
+            Ok(previews)
+        }
+        PreviewMode::FullDownload => {
+            log::info!("Downloading patches ...");
+            let (download_dir, _tmp_dir) =
+                prepare_download_directory(config, &update_plan.download_directory)?;
+            let bandwidth_limiter = bandwidth_limiter(config);
+            let download_settings =
+                download_settings(config, bandwidth_limiter.as_ref(), &pause_state);
+            let download_outcome = download_patches_concurrent(
+                http_client,
+                patch_url,
+                patch_list,
+                &download_dir,
+                &download_settings,
+                ui_controller,
+                patcher_thread_rx,
+            )
+            .await
+            .map_err(|e| match e {
+                InterruptibleFnError::Err(msg) => anyhow!("Failed to download patches: {}", msg),
+                InterruptibleFnError::Interrupted => anyhow!("Preview was canceled"),
+            })?;
+            preview_pending_patches(&download_outcome.pending_patches, config, &mut previewer)
+        }
+    }
+}
+
+/// Computes the changes `pending_patches` would make if they were applied in
+/// order.
+fn preview_pending_patches(
+    pending_patches: &[PendingPatch],
+    config: &PatcherConfiguration,
+    previewer: &mut PatchPreviewer,
+) -> Result<Vec<PatchPreview>> {
+    let mut previews = Vec::with_capacity(pending_patches.len());
+    for pending_patch in pending_patches {
+        let patch_name = pending_patch.info.file_name.clone();
+        let target_grf_override = pending_patch.info.target_grf.as_deref();
+        let preview = match &pending_patch.content {
+            PatchContent::Memory(content) => {
+                let thor_archive =
+                    ThorArchive::new_with_encoding(Cursor::new(content), name_encoding(config))?;
+                let target_grf_name = target_grf_name(
+                    &thor_archive,
+                    &patch_name,
+                    target_grf_override,
+                    &config.client,
+                );
+                previewer.preview_thor_archive(patch_name, &thor_archive, &target_grf_name)
+            }
+            PatchContent::File(local_file_path) => preview_patch_file(
+                local_file_path,
+                patch_name,
+                target_grf_override,
+                config,
+                previewer,
+            ),
+        };
+        previews
+            .push(preview.with_context(|| {
+                format!("Failed to preview '{}'", pending_patch.info.file_name)
+            })?);
+    }
+    Ok(previews)
+}
+
+/// Saves the files `pending_patches` would modify (as well as the cache file,
+/// if any) before they're applied, so that the update can be rolled back.
+/// The backup replaces the one of the previous update.
+fn back_up_pending_patches(
+    pending_patches: &[PendingPatch],
+    config: &PatcherConfiguration,
+    cache_file_path: Option<&Path>,
+) -> Result<()> {
+    let install_directory = resolve_install_directory(&config.client)?;
+    let mut previewer = PatchPreviewer::new(
+        &install_directory,
+        name_encoding(config),
+        unsafe_path_handling(config),
+        path_matching(config),
+        skipped_file_patterns(config),
+    );
+    let previews = preview_pending_patches(pending_patches, config, &mut previewer)?;
+    let mut backup_session = BackupSession::create(get_backup_directory_path()?)?;
+    if let Some(cache_file_path) = cache_file_path {
+        backup_session.save_file(cache_file_path)?;
+    }
+    if config.patching.track_installed_files {
+        backup_session.save_file(get_installed_files_path()?)?;
+    }
+    for (pending_patch, preview) in pending_patches.iter().zip(previews) {
+        match preview.target_grf {
+            Some(target_grf_name) => {
+                let grf_file_path = install_directory.join(target_grf_name);
+                // Note: GRFs patched out-of-place are replaced with a rebuilt
+                // GRF, which leaves the original file untouched
+                let is_replaced = !is_patched_in_place(
+                    config,
+                    &pending_patch.info,
+                    pending_patch_size(pending_patch),
+                ) && PatchFormat::from_file_name(&pending_patch.info.file_name)
+                    == PatchFormat::Thor;
+                if is_replaced {
+                    backup_session.save_replaced_file(grf_file_path)?;
+                } else {
+                    backup_session.save_file(grf_file_path)?;
+                }
+            }
+            None => {
+                for entry in preview.changes {
+                    let file_path = destination_path(
+                        &install_directory,
+                        &entry.relative_path,
+                        unsafe_path_handling(config),
+                    )?;
+                    if let Some(file_path) = file_path {
+                        backup_session.save_file(resolve_path_case(
+                            &install_directory,
+                            file_path,
+                            path_matching(config),
+                        ))?;
+                    }
+                }
+            }
+        }
+    }
+    backup_session.commit()
+}
+
+/// Computes the changes the patch located at `patch_file_path` would make,
+/// whatever its format.
+fn preview_patch_file(
+    patch_file_path: &Path,
+    patch_name: String,
+    target_grf_override: Option<&str>,
+    config: &PatcherConfiguration,
+    previewer: &mut PatchPreviewer,
+) -> Result<PatchPreview> {
+    match PatchFormat::from_file_name(patch_file_path) {
+        PatchFormat::Thor => {
+            let thor_archive =
+                ThorArchive::open_with_encoding(patch_file_path, name_encoding(config))?;
+            let target_grf_name = target_grf_name(
+                &thor_archive,
+                &patch_name,
+                target_grf_override,
+                &config.client,
+            );
+            previewer.preview_thor_archive(patch_name, &thor_archive, &target_grf_name)
+        }
+        PatchFormat::Rgz => {
+            let rgz_file = std::fs::File::open(patch_file_path)?;
+            let entries = list_rgz_files(std::io::BufReader::new(rgz_file), name_encoding(config))?;
+            previewer.preview_entries(
+                patch_name,
+                None,
+                entries
+                    .into_iter()
+                    .map(|relative_path| (relative_path, false)),
+            )
+        }
+        PatchFormat::Gpf => {
+            let entries = list_gpf_files(patch_file_path, name_encoding(config))?;
+            let target_grf_name = match target_grf_override {
+                Some(target_grf_name) => target_grf_name,
+                None => {
+                    let entry_paths: Vec<&str> = entries.iter().map(String::as_str).collect();
+                    config.client.routed_grf_name(&patch_name, &entry_paths)
+                }
+            };
+            let target_grf_name = target_grf_name.to_string();
+            previewer.preview_entries(
+                patch_name,
+                Some(&target_grf_name),
+                entries
+                    .into_iter()
+                    .map(|relative_path| (relative_path, false)),
+            )
+        }
+    }
+}
+
+/// Fetches the parts of a THOR patch needed to list its entries: its header
+/// and its file table. Patches are fetched whole from local sources and from
+/// servers that don't support range requests.
+async fn fetch_patch_headers(
+    client: &reqwest::Client,
+    patch_file_url: &Url,
+    read_timeout: Duration,
+) -> Result<PartialPatch> {
+    let mut partial_patch = PartialPatch::default();
+    if is_local_url(patch_file_url) {
+        let content = tokio::fs::read(local_path_from_url(patch_file_url)?).await?;
+        partial_patch.add_part(0, content);
+        return Ok(partial_patch);
+    }
+    let header_range = 0..thor::reader::HEADER_EXTENDED_MAX_SIZE as u64;
+    let (header, is_partial) =
+        fetch_byte_range(client, patch_file_url, header_range, read_timeout).await?;
+    if !is_partial {
+        partial_patch.add_part(0, header);
+        return Ok(partial_patch);
+    }
+    let table_range = thor::parse_thor_file_table_range(&header)?;
+    partial_patch.add_part(0, header);
+    if let Some(table_range) = table_range.filter(|range| !range.is_empty()) {
+        let table_offset = table_range.start;
+        let (table, is_partial) =
+            fetch_byte_range(client, patch_file_url, table_range, read_timeout).await?;
+        // Note: Servers may answer with the whole file to any range request
+        partial_patch.add_part(if is_partial { table_offset } else { 0 }, table);
+    }
+    Ok(partial_patch)
+}
+
+/// Fetches the `range` byte range of a file. Returns the content along with
+/// whether it's only the requested range, servers that don't support range
+/// requests send the whole file instead.
+async fn fetch_byte_range(
+    client: &reqwest::Client,
+    file_url: &Url,
+    range: Range<u64>,
+    read_timeout: Duration,
+) -> Result<(Vec<u8>, bool)> {
+    let request = client.get(file_url.clone()).header(
+        reqwest::header::RANGE,
+        format!("bytes={}-{}", range.start, range.end - 1),
+    );
+    let resp = with_read_timeout(read_timeout, request.send())
+        .await
+        .with_context(|| "Failed to GET URL")?
+        .error_for_status()?;
+    let is_partial = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let content = with_read_timeout(read_timeout, resp.bytes()).await?;
+    Ok((content.to_vec(), is_partial))
+}
+
+/// Offline counterpart of `interruptible_update_routine`, which applies the
+/// patch file `patch_path` (or the patches found in that directory) instead
+/// of downloading them.
+async fn interruptible_local_update_routine(
+    patch_path: impl AsRef<Path>,
+    config: &PatcherConfiguration,
+    ui_controller: &UiController,
+    patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> Result<UpdateSummary> {
+    log::info!(
+        "Applying patches from '{}' ...",
+        patch_path.as_ref().display()
+    );
+    check_running_client(config, ui_controller)?;
+    let start = Instant::now();
+    let pending_patch_queue = collect_local_patches(patch_path)?;
+    let pending_patch_count = pending_patch_queue.len();
+    log::debug!("Found local patches: {:?}", pending_patch_queue);
+    if config.patching.backup && !pending_patch_queue.is_empty() {
+        log::info!("Backing up files ...");
+        back_up_pending_patches(&pending_patch_queue, config, None)?;
+    }
+    // Local patches aren't tracked in the cache, as they don't come from the
+    // patch list
+    let installation_outcome = apply_patches(
+        pending_patch_queue,
+        config,
+        None,
+        None,
+        &PauseState::new(),
+        ui_controller,
+        patcher_thread_rx,
+    )
+    .await
+    .map_err(|e| match e {
+        InterruptibleFnError::Err(msg) => anyhow!("Failed to apply patches: {}", msg),
+        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
+    })?;
+    log::info!("Patches have been applied");
+
+    Ok(UpdateSummary {
+        elapsed_secs: start.elapsed().as_secs(),
+        applied_patch_count: pending_patch_count - installation_outcome.skipped_patches.len(),
+        skipped_patches: installation_outcome.skipped_patches,
+        protected_files: installation_outcome.protected_files,
+        excluded_files: installation_outcome.excluded_files,
+        ..Default::default()
+    })
+}
+
+/// Lists the patches (THOR, RGZ and GPF archives) contained in the directory
+/// `patch_path`, or returns `patch_path` itself if it's a patch file.
+///
+/// Patches are sorted by the index their file name starts with (if any), then
+/// by file name.
+fn collect_local_patches(patch_path: impl AsRef<Path>) -> Result<Vec<PendingPatch>> {
+    let patch_path = patch_path.as_ref();
+    if patch_path.is_file() {
+        if !is_patch_file(patch_path) {
+            return Err(anyhow!(
+                "'{}' isn't a patch (THOR, RGZ or GPF archive)",
+                patch_path.display()
+            ));
+        }
+        return Ok(vec![PendingPatch {
+            info: ThorPatchInfo {
+                index: 0,
+                file_name: patch_path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                ..Default::default()
+            },
+            content: PatchContent::File(patch_path.to_path_buf()),
+        }]);
+    }
+    let patch_directory = patch_path;
+    let dir_entries = std::fs::read_dir(patch_directory)
+        .with_context(|| format!("Failed to read '{}'", patch_directory.display()))?;
+    let mut local_patches = vec![];
+    for dir_entry in dir_entries {
+        let local_file_path = dir_entry?.path();
+        if !is_patch_file(&local_file_path) || !local_file_path.is_file() {
+            continue;
+        }
+        let file_name = local_file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let index_str: String = file_name.chars().take_while(char::is_ascii_digit).collect();
+        local_patches.push((index_str.parse::<usize>().ok(), file_name, local_file_path));
+    }
+    local_patches.sort();
+
+    Ok(local_patches
+        .into_iter()
+        .enumerate()
+        .map(|(index, (_, file_name, local_file_path))| PendingPatch {
+            info: ThorPatchInfo {
+                index,
+                file_name,
+                ..Default::default()
+            },
+            content: PatchContent::File(local_file_path),
+        })
+        .collect())
+}
+
+/// Returns true if `file_path` has the extension of a patch (THOR, RGZ or GPF
+/// archive).
+fn is_patch_file(file_path: &Path) -> bool {
+    let extension = file_path.extension().unwrap_or_default();
+    ["thor", "rgz", "gpf"].iter().any(|patch_extension| {
+        extension
+            .to_string_lossy()
+            .eq_ignore_ascii_case(patch_extension)
+    })
+}
+
+/// Returns the patch servers and the cache file of a channel (or of the
+/// default channel if `channel_name` is `None`).
+fn resolve_update_channel(
+    config: &PatcherConfiguration,
+    channel_name: Option<&str>,
+) -> Result<UpdateChannel> {
+    let patch_servers = match channel_name {
+        None => config.web.patch_servers.clone(),
+        Some(channel_name) => {
+            let channel = config
+                .channels
+                .get(channel_name)
+                .ok_or_else(|| anyhow!("Unknown channel '{}'", channel_name))?;
+            vec![PatchServerInfo {
+                name: channel_name.to_string(),
+                plist_url: channel.plist_url.clone(),
+                patch_url: channel.patch_url.clone(),
+            }]
+        }
+    };
+    Ok(UpdateChannel {
+        patch_servers,
+        cache_file_path: get_cache_file_path(config.profile.as_deref(), channel_name)
+            .with_context(|| "Failed to resolve patcher name")?,
+        download_directory: get_download_directory_path(config.profile.as_deref(), channel_name)
+            .with_context(|| "Failed to resolve patcher name")?,
+    })
+}
+
+/// Iterates through the channel's patch servers and returns the first
+/// available server's patch list, patch URL and name.
+/// `preferred_server_name` is checked first if present.
+async fn find_available_patch_server(
+    client: &reqwest::Client,
+    channel: &UpdateChannel,
+    preferred_server_name: &Option<String>,
+    web_config: &WebConfiguration,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<(RemotePatchList, Url, String)> {
+    let server_list = &channel.patch_servers;
+    let cache_file_path = &channel.cache_file_path;
+    // Probe the preferred server first if it's specified and valid
+    if let Some(preferred_server_name) = preferred_server_name {
+        let preferred_server = server_list
+            .iter()
+            .find(|s| &s.name == preferred_server_name);
+        if let Some(preferred_server) = preferred_server {
+            if let Ok((patch_list, patch_url)) = probe_patch_server(
+                client,
+                preferred_server,
+                cache_file_path,
+                web_config,
+                ui_controller,
+            )
+            .await
+            {
+                return Ok((patch_list, patch_url, preferred_server.name.clone()));
+            } else {
+                log::warn!("'{}' is unavailable", preferred_server_name);
+            }
+        } else {
+            log::warn!(
+                "'{}' isn't in the list of patch servers",
+                preferred_server_name
+            );
+        }
+    }
+
+    // Probe other servers, if any
+    for server in server_list {
+        // Cancel the patching process if we've been asked to or if the other
+        // end of the channel has been disconnected
+        process_incoming_commands(patching_thread_rx, pause_state, ui_controller).await?;
+        if let Ok((patch_list, patch_url)) =
+            probe_patch_server(client, server, cache_file_path, web_config, ui_controller).await
+        {
+            return Ok((patch_list, patch_url, server.name.clone()));
+        } else {
+            log::warn!("'{}' is unavailable", server.name);
+        }
+    }
+
+    Err(InterruptibleFnError::Err(
+        "None of the patch servers are available at the moment".to_string(),
+    ))
+}
+
+/// Checks whether a patch server is up or not.
+/// Returns the list of patches served by the server as well as the URL to
+/// download them from.
+async fn probe_patch_server(
+    client: &reqwest::Client,
+    server_info: &PatchServerInfo,
+    cache_file_path: &Path,
+    web_config: &WebConfiguration,
+    ui_controller: &UiController,
+) -> Result<(RemotePatchList, Url)> {
+    // Parse URLs
+    let patch_list_url = parse_source_url(server_info.plist_url.as_str(), false)
+        .with_context(|| "Failed to parse 'plist_url'")?;
+    let patch_url = parse_source_url(server_info.patch_url.as_str(), true)
+        .with_context(|| "Failed to parse 'patch_url'")?;
+
+    // Fetch plist
+    let patch_list = fetch_patch_list(
+        client,
+        patch_list_url,
+        cache_file_path,
+        web_config,
+        ui_controller,
+    )
+    .await
+    .with_context(|| "Failed to retrieve the patch list")?;
+
+    // Ensure that the server serves the patches (check the first patch of the list)
+    let first_patch = patch_list
+        .patches
+        .as_ref()
+        .and_then(|patches| patches.first());
+    if let Some(patch_info) = first_patch {
+        let patch_file_url = patch_file_url(&patch_url, &patch_info.file_name)?;
+        if is_local_url(&patch_file_url) {
+            let patch_file_path = local_path_from_url(&patch_file_url)?;
+            tokio::fs::metadata(&patch_file_path)
+                .await
+                .with_context(|| format!("Cannot access '{}'", patch_file_path.display()))?;
+            return Ok((patch_list, patch_url));
+        }
+        let request = client.head(patch_file_url);
+        let patch_resp = with_read_timeout(read_timeout(web_config), request.send())
+            .await
+            .with_context(|| "Failed to HEAD URL")?;
+        // Return on error
+        patch_resp.error_for_status()?;
+    }
+
+    Ok((patch_list, patch_url))
+}
+
+/// Downloads and parses a patch index ('plist.txt' or JSON file) located as the
+/// URL contained in the `patch_list_url` argument.
+///
+/// If a public key is configured, the list's signature is downloaded from
+/// '<patch_list_url>.sig' and the list is rejected if it isn't valid.
+///
+/// Returns a vector of `ThorPatchInfo` in case of success.
+async fn fetch_patch_list(
+    client: &reqwest::Client,
+    patch_list_url: Url,
+    cache_file_path: &Path,
+    web_config: &WebConfiguration,
+    ui_controller: &UiController,
+) -> Result<RemotePatchList> {
+    // Only download the list if it's been modified since the last update
+    let cached_validators = read_patch_list_validators(cache_file_path, &patch_list_url).await;
+    let remote_file = fetch_text_file(
+        client,
+        &patch_list_url,
+        cached_validators.as_ref(),
+        web_config,
+        ui_controller,
+    )
+    .await
+    .with_context(|| "Patch list file is unavailable on the remote server")?;
+    let (patch_index_content, validators) = match remote_file {
+        RemoteTextFile::Modified(content, validators) => (content, validators),
+        RemoteTextFile::NotModified => {
+            return Ok(RemotePatchList {
+                patches: None,
+                validators: cached_validators,
+            })
+        }
+    };
+    if let Some(public_key) = &web_config.plist_public_key {
+        let mut signature_url = patch_list_url.clone();
+        signature_url.set_path(&format!("{}.sig", patch_list_url.path()));
+        let signature =
+            match fetch_text_file(client, &signature_url, None, web_config, ui_controller)
+                .await
+                .with_context(|| "Patch list signature is unavailable on the remote server")?
+            {
+                RemoteTextFile::Modified(content, _) => content,
+                RemoteTextFile::NotModified => return Err(anyhow!("Unexpected response")),
+            };
+        verify_signature(patch_index_content.as_bytes(), &signature, public_key)
+            .with_context(|| "Failed to verify the patch list's signature")?;
+        log::info!("Patch list signature is valid");
+    }
+    log::info!("Parsing patch index...");
+
+    Ok(RemotePatchList {
+        patches: Some(parse_patch_index(&patch_list_url, &patch_index_content)?),
+        validators,
+    })
+}
+
+/// Parses a patch index, either a JSON index or a legacy 'plist.txt' file.
+///
+/// JSON indexes are detected from their extension or their content.
+fn parse_patch_index(patch_list_url: &Url, content: &str) -> Result<ThorPatchList> {
+    let is_json = patch_list_url
+        .path()
+        .to_ascii_lowercase()
+        .ends_with(".json")
+        || content.trim_start().starts_with(&['{', '['][..]);
+    if is_json {
+        thor::patch_list_from_json(content).with_context(|| "Invalid JSON patch index")
+    } else {
+        Ok(thor::patch_list_from_string(content))
+    }
+}
+
+/// Returns the validators of the last fully applied patch list, if it's been
+/// downloaded from `patch_list_url`.
+async fn read_patch_list_validators(
+    cache_file_path: &Path,
+    patch_list_url: &Url,
+) -> Option<PatchListValidators> {
+    let patcher_cache = read_cache_file(cache_file_path).await.ok()?;
+    patcher_cache
+        .patch_list_validators
+        .filter(|validators| validators.url == patch_list_url.as_str())
+}
+
+/// Downloads a text file. If `cached_validators` are given, the file is only
+/// downloaded if it's been modified.
+///
+/// Transient failures are retried according to `web_config.retry`.
+async fn fetch_text_file(
+    client: &reqwest::Client,
+    url: &Url,
+    cached_validators: Option<&PatchListValidators>,
+    web_config: &WebConfiguration,
+    ui_controller: &UiController,
+) -> Result<RemoteTextFile> {
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default()
+        .to_string();
+    let read_timeout = read_timeout(web_config);
+    let mut backoff = Backoff::new(&web_config.retry);
+    loop {
+        match fetch_text_file_content(client, url.clone(), cached_validators, read_timeout).await {
+            Ok(remote_file) => return Ok(remote_file),
+            Err(err) => match backoff.next_delay(&err) {
+                None => return Err(err),
+                Some(delay) => {
+                    log::warn!("{:#}, retrying in {:?}", err, delay);
+                    ui_controller.dispatch_patching_status(PatchingStatus::DownloadRetry(
+                        file_name.clone(),
+                        backoff.attempt(),
+                        backoff.max_attempts(),
+                    ));
+                    tokio::time::sleep(delay).await;
+                }
+            },
+        }
+    }
+}
+
+async fn fetch_text_file_content(
+    client: &reqwest::Client,
+    url: Url,
+    cached_validators: Option<&PatchListValidators>,
+    read_timeout: Duration,
+) -> Result<RemoteTextFile> {
+    if is_local_url(&url) {
+        let path = local_path_from_url(&url)?;
+        let content = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+        return Ok(RemoteTextFile::Modified(content, None));
+    }
+    let mut request = client.get(url.clone());
+    if let Some(validators) = cached_validators {
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let resp = with_read_timeout(read_timeout, request.send())
+        .await
+        .with_context(|| "Failed to GET URL")?
+        .error_for_status()?;
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(RemoteTextFile::NotModified);
+    }
+    let header_value = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let etag = header_value(reqwest::header::ETAG);
+    let last_modified = header_value(reqwest::header::LAST_MODIFIED);
+    let validators = if etag.is_some() || last_modified.is_some() {
+        Some(PatchListValidators {
+            url: url.to_string(),
+            etag,
+            last_modified,
+        })
+    } else {
+        None
+    };
+    let content = with_read_timeout(read_timeout, resp.text())
+        .await
+        .with_context(|| "Invalid responde body")?;
+    Ok(RemoteTextFile::Modified(content, validators))
+}
+
+/// Checks that the download directory and the game's directory have enough
+/// space available to download and apply the patches in `patch_list`.
+async fn check_available_disk_space(
+    client: &reqwest::Client,
+    patch_url: &Url,
+    patch_list: &[ThorPatchInfo],
+    download_directory: &Path,
+    config: &PatcherConfiguration,
+) -> Result<()> {
+    let patches_size = fetch_patches_size(client, patch_url, patch_list, &config.web).await;
+    let install_directory = resolve_install_directory(&config.client)?;
+    let grf_size = std::fs::metadata(install_directory.join(&config.client.default_grf_name))
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let required_space = estimate_required_space(patches_size, grf_size, config.patching.in_place);
+    log::debug!("Estimated required disk space: {:?}", required_space);
+    ensure_available_space(download_directory, required_space.download_dir)?;
+    ensure_available_space(install_directory, required_space.game_dir)
+}
+
+/// Returns the total size of the patches in `patch_list`, as announced by the
+/// patch list or the patch server.
+///
+/// Patches whose size cannot be retrieved are ignored.
+async fn fetch_patches_size(
+    client: &reqwest::Client,
+    patch_url: &Url,
+    patch_list: &[ThorPatchInfo],
+    web_config: &WebConfiguration,
+) -> u64 {
+    const CONCURRENT_REQUESTS: usize = 32;
+    let read_timeout = read_timeout(web_config);
+    futures::stream::iter(patch_list.iter().map(|patch_info| async move {
+        if patch_info.size.is_some() {
+            return patch_info.size;
+        }
+        let patch_file_url = patch_file_url(patch_url, &patch_info.file_name).ok()?;
+        if is_local_url(&patch_file_url) {
+            let patch_file_path = local_path_from_url(&patch_file_url).ok()?;
+            return tokio::fs::metadata(patch_file_path)
+                .await
+                .ok()
+                .map(|metadata| metadata.len());
+        }
+        let resp = with_read_timeout(read_timeout, client.head(patch_file_url).send())
+            .await
+            .ok()?
+            .error_for_status()
+            .ok()?;
+        content_length_header(&resp)
+    }))
+    .buffer_unordered(CONCURRENT_REQUESTS)
+    .fold(0_u64, |total, size| async move {
+        match size {
+            Some(size) => total.saturating_add(size),
+            None => total,
+        }
+    })
+    .await
+}
+
+/// Returns the value of the `Content-Length` header of a response.
+///
+/// Note: `Response::content_length` is always 0 for HEAD requests
+fn content_length_header(resp: &reqwest::Response) -> Option<u64> {
+    resp.headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()
+}
+
+/// Returns the directory patches should be downloaded into, which is
+/// `download_dir` if downloads are kept between sessions.
+///
+/// Otherwise, a temporary directory (deleted when dropped) is returned as
+/// well.
+fn prepare_download_directory(
+    config: &PatcherConfiguration,
+    download_dir: &Path,
+) -> Result<(PathBuf, Option<tempfile::TempDir>)> {
+    if config.web.keep_downloads {
+        let download_dir = download_dir.to_path_buf();
+        std::fs::create_dir_all(&download_dir).with_context(|| {
+            format!(
+                "Failed to create download directory '{}'",
+                download_dir.display()
+            )
+        })?;
+        Ok((download_dir, None))
+    } else {
+        let tmp_dir =
+            tempfile::tempdir().with_context(|| "Failed to create temporary directory")?;
+        Ok((tmp_dir.path().to_path_buf(), Some(tmp_dir)))
+    }
+}
+
+/// Returns the path of the directory patches are kept in between sessions
+/// (e.g. 'rpatchur_downloads/default').
+///
+/// Each profile and channel has its own directory, as their patches may share
+/// names.
+fn get_download_directory_path(
+    profile_name: Option<&str>,
+    channel_name: Option<&str>,
+) -> Result<PathBuf> {
+    let mut dir_name = get_patcher_name()?;
+    dir_name.push("_downloads");
+    let sub_dir_name = match (profile_name, channel_name) {
+        (None, None) => "default".to_string(),
+        (None, Some(channel_name)) => channel_name.to_string(),
+        (Some(profile_name), None) => format!("profile-{}", profile_name),
+        (Some(profile_name), Some(channel_name)) => {
+            format!("profile-{}.{}", profile_name, channel_name)
+        }
+    };
+    Ok(PathBuf::from(dir_name).join(sub_dir_name))
+}
+
+/// Returns the path of the directory the files modified by the last update
+/// are backed up into.
+fn get_backup_directory_path() -> Result<PathBuf> {
+    let mut dir_name = get_patcher_name()?;
+    dir_name.push("_backup");
+    Ok(PathBuf::from(dir_name))
+}
+
+/// Returns the patcher cache file's name as a `PathBuf` on success.
+///
+/// Each channel has its own cache file.
+fn get_cache_file_path(profile_name: Option<&str>, channel_name: Option<&str>) -> Result<PathBuf> {
+    match (profile_name, channel_name) {
+        (None, None) => get_instance_asset_file_name("dat"),
+        (None, Some(channel_name)) => get_instance_asset_file_name(format!("{}.dat", channel_name)),
+        (Some(profile_name), None) => {
+            get_instance_asset_file_name(format!("profile-{}.dat", profile_name))
+        }
+        (Some(profile_name), Some(channel_name)) => {
+            get_instance_asset_file_name(format!("profile-{}.{}.dat", profile_name, channel_name))
+        }
+    }
+}
+
+/// Returns the path of the file that caches the index of the GRF located at
+/// `grf_path` (e.g. 'rpatchur.data.grf.idx').
+fn get_grf_index_cache_file_path(grf_path: impl AsRef<Path>) -> Result<PathBuf> {
+    let grf_file_name = grf_path
+        .as_ref()
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| anyhow!("Invalid GRF path '{}'", grf_path.as_ref().display()))?;
+    get_instance_asset_file_name(format!("{}.idx", grf_file_name))
+}
+
+/// Returns the path of the journal of the update session in progress, which
+/// is left behind when the patcher crashes.
+fn get_session_journal_file_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("session")
+}
+
+/// Returns the path of the file listing the locked files to replace on the
+/// next start.
+fn get_pending_renames_file_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("pending")
+}
+
+/// Returns the path of the history of the patches processed by the patcher.
+pub fn get_history_file_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("history.jsonl")
+}
+
+/// Returns the path of the database of the files written by patches.
+pub fn get_installed_files_path() -> Result<PathBuf> {
+    get_instance_asset_file_name("files.json")
+}
+
+/// Returns the patcher update lock file's name as a `PathBuf` on success.
+///
+/// Note: The lock is shared by all the users of the installation, so it stays
+/// in the working directory.
+fn get_update_lock_file_path() -> Result<PathBuf> {
+    let patcher_name = get_patcher_name()?;
+
+    Ok(PathBuf::from(patcher_name).with_extension("lock"))
+}
+
+/// Generates asset file names which are associated with the current 'instance'
+/// of the patcher, located in the data directory.
+fn get_instance_asset_file_name(extension: impl AsRef<std::ffi::OsStr>) -> Result<PathBuf> {
+    get_instance_data_file_path(extension)
+}
+
+/// Downloads a list of patches (described with a `ThorPatchList`).
+///
+/// Files are downloaded from the remote directory located at the URL
+/// contained in the 'patch_url' argument.
+///
+/// Returns the downloaded patches, along with the names of the patches that
+/// were skipped because of failures and the amount of downloaded data.
+///
+/// This function is interruptible.
+async fn download_patches_concurrent(
+    client: &reqwest::Client,
+    patch_url: Url,
+    patch_list: ThorPatchList,
+    download_directory: impl AsRef<Path>,
+    settings: &DownloadSettings<'_>,
+    ui_controller: &UiController,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<DownloadOutcome> {
+    let patch_count = patch_list.len();
+    ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(0, patch_count, 0));
+    // Download files in a cancelable manner
+    let mut download_outcome = tokio::select! {
+        cancel_res = wait_for_cancellation(patching_thread_rx, settings.pause_state, ui_controller) => return Err(cancel_res),
+        download_res = download_patches_concurrent_inner(client, patch_url, patch_list, download_directory, settings, ui_controller) => {
+            download_res.map_err(|e| InterruptibleFnError::Err(format!("{:#}", e)))
+        },
+    }?;
+    // Sort patches by index before returning
+    download_outcome
+        .pending_patches
+        .sort_unstable_by(|l, r| l.info.index.cmp(&r.info.index));
+    Ok(download_outcome)
+}
+
+/// Actual implementation of the concurrent file download
+///
+/// Returns an unordered vector of `PendingPatch` and the names of the skipped
+/// patches.
+async fn download_patches_concurrent_inner(
+    client: &reqwest::Client,
+    patch_url: Url,
+    patch_list: ThorPatchList,
+    download_directory: impl AsRef<Path>,
+    settings: &DownloadSettings<'_>,
+    ui_controller: &UiController,
+) -> Result<DownloadOutcome> {
+    const CONCURRENT_DOWNLOADS: usize = 32;
+    // Note: Checked before anything is downloaded, hostile patch lists mustn't
+    // be skipped by the failure policy
+    check_patch_file_names(&patch_list)?;
+    // Shared value that contains the number of downloaded patches
+    let shared_patch_number = AtomicUsize::new(0_usize);
+    let patch_count = patch_list.len();
+    // Shared state that's used to compute the download speed and ETA
+    let shared_progress = DownloadProgress::new(patch_count);
+    // Sizes announced by the patch list are known before the downloads start
+    for patch_size in patch_list.iter().filter_map(|patch_info| patch_info.size) {
+        shared_progress.add_file_size(patch_size);
+    }
+    if let Some(total_bytes) = shared_progress.total_bytes() {
+        ui_controller.dispatch_patching_status(PatchingStatus::DownloadProgress(
+            0,
+            total_bytes,
+            0,
+            0,
+            None,
+        ));
+    }
+    let bandwidth_limiter = settings.bandwidth_limiter;
+
+    let download_patch = |patch_info: ThorPatchInfo| async {
+        let patch_file_url = patch_file_url(&patch_url, &patch_info.file_name)?;
+        let local_file_path =
+            download_file_path(download_directory.as_ref(), &patch_info.file_name)?;
+        // Reuse patches downloaded (but not applied) during a previous session
+        if is_download_reusable(&local_file_path, &patch_info, &patch_file_url) {
+            log::info!("Reusing downloaded patch '{}'", patch_info.file_name);
+            let file_size = std::fs::metadata(&local_file_path)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            if patch_info.size.is_none() {
+                shared_progress.add_file_size(file_size);
+            }
+            shared_progress.add_completed_bytes(file_size);
+            shared_patch_number.fetch_add(1, Ordering::SeqCst);
+            return Ok(PendingPatch {
+                info: patch_info,
+                content: PatchContent::File(local_file_path),
+            });
+        }
+        // Download to a separate file, so that incomplete downloads are never
+        // mistaken for complete ones
+        let part_file_path = download_file_path(
+            download_directory.as_ref(),
+            &format!("{}.part", patch_info.file_name),
+        )?;
+
+        // Setup a progress callback that'll send the download statistics to the UI
+        let shared_patch_number_ref = &shared_patch_number;
+        let shared_progress_ref = &shared_progress;
+        let mut file_size_known = patch_info.size.is_some();
+        let mut last_downloaded_bytes: u64 = 0;
+        let progress_callback = move |dl_now, dl_total| {
+            if !file_size_known {
+                shared_progress_ref.add_file_size(dl_total);
+                file_size_known = true;
+            }
+            // Statistics are "available" once per second, update UI then
+            if let Some(stats) = shared_progress_ref.update(last_downloaded_bytes, dl_now) {
+                block_on(async {
+                    ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(
+                        shared_patch_number_ref.load(Ordering::SeqCst),
+                        patch_count,
+                        stats.bytes_per_sec,
+                    ));
+                    if let Some(bandwidth_limiter) = bandwidth_limiter {
+                        ui_controller.dispatch_patching_status(PatchingStatus::DownloadThrottled(
+                            stats.bytes_per_sec,
+                            bandwidth_limiter.max_bytes_per_sec(),
+                        ));
+                    }
+                    ui_controller.dispatch_patching_status(PatchingStatus::DownloadProgress(
+                        stats.downloaded_bytes,
+                        stats.total_bytes,
+                        stats.bytes_per_sec,
+                        stats.average_bytes_per_sec,
+                        stats.eta_secs,
+                    ));
+                });
             }
             last_downloaded_bytes = dl_now;
         };
 
-        download_patch_to_file(
+        let use_http = match select_download_backend(&patch_info, &patch_file_url, settings) {
+            DownloadBackend::Http => true,
+            DownloadBackend::LocalFile(source_path) => {
+                let file_size = tokio::fs::copy(&source_path, &part_file_path)
+                    .await
+                    .with_context(|| format!("Failed to copy '{}'", source_path.display()))?;
+                if patch_info.size.is_none() {
+                    shared_progress_ref.add_file_size(file_size);
+                }
+                shared_progress_ref.update(0, file_size);
+                false
+            }
+            DownloadBackend::BitTorrent(torrent_config, torrent_url) => {
+                let res = download_torrent(
+                    torrent_config,
+                    torrent_url,
+                    &patch_info.file_name,
+                    &part_file_path,
+                )
+                .await;
+                match res {
+                    Ok(()) => {
+                        match patch_info.size {
+                            Some(file_size) => shared_progress_ref.add_completed_bytes(file_size),
+                            None => shared_progress_ref.add_file_size(0),
+                        }
+                        false
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "Failed to download '{}' with BitTorrent, falling back to HTTP: {:#}",
+                            patch_info.file_name,
+                            err
+                        );
+                        true
+                    }
+                }
+            }
+        };
+        if use_http {
+            let content = download_patch_over_http(
+                client,
+                &patch_file_url,
+                &patch_info,
+                &part_file_path,
+                settings,
+                ui_controller,
+                progress_callback,
+            )
+            .await?;
+            if let Some(content) = content {
+                validate_downloaded_patch(
+                    Cursor::new(content.as_slice()),
+                    &patch_info,
+                    settings.ensure_integrity,
+                )?;
+                shared_patch_number_ref.fetch_add(1, Ordering::SeqCst);
+                return Ok(PendingPatch {
+                    info: patch_info,
+                    content: PatchContent::Memory(content),
+                });
+            }
+        }
+
+        let part_file = std::fs::File::open(&part_file_path)
+            .with_context(|| format!("Failed to open '{}'", part_file_path.display()))?;
+        validate_downloaded_patch(part_file, &patch_info, settings.ensure_integrity)?;
+        tokio::fs::rename(&part_file_path, &local_file_path)
+            .await
+            .with_context(|| format!("Failed to move '{}'", part_file_path.display()))?;
+        let source_file_path = download_source_file_path(&local_file_path);
+        if let Err(e) = tokio::fs::write(&source_file_path, patch_file_url.as_str()).await {
+            log::warn!("Failed to write '{}': {}", source_file_path.display(), e);
+        }
+
+        // Update status
+        shared_patch_number_ref.fetch_add(1, Ordering::SeqCst);
+
+        // File's been downloaded, add it to the queue
+        Ok(PendingPatch {
+            info: patch_info,
+            content: PatchContent::File(local_file_path),
+        }) as Result<PendingPatch>
+    };
+
+    // Collect stream of "PendingPatch" concurrently with an unordered_buffer
+    let results: Vec<std::result::Result<PendingPatch, String>> =
+        futures::stream::iter(patch_list.into_iter().map(|patch_info| {
+            let patch_name = patch_info.file_name.clone();
+            download_patch(patch_info).map(move |res| (patch_name, res))
+        }))
+        .buffer_unordered(CONCURRENT_DOWNLOADS)
+        .then(|(patch_name, res)| async move {
+            match res {
+                Ok(pending_patch) => Ok(Ok(pending_patch)),
+                Err(err) => {
+                    let err_msg = format!("{:#}", err);
+                    if should_skip_failed_patch(
+                        settings.failure_policy,
+                        &patch_name,
+                        &err_msg,
+                        ui_controller,
+                    ) {
+                        log::warn!("Skipping patch '{}': {}", patch_name, err_msg);
+                        Ok(Err(patch_name))
+                    } else {
+                        Err(err)
+                    }
+                }
+            }
+        })
+        .try_collect()
+        .await?;
+
+    let mut pending_patches = vec![];
+    let mut skipped_patches = vec![];
+    for result in results {
+        match result {
+            Ok(pending_patch) => pending_patches.push(pending_patch),
+            Err(patch_name) => skipped_patches.push(patch_name),
+        }
+    }
+    Ok(DownloadOutcome {
+        pending_patches,
+        skipped_patches,
+        downloaded_bytes: shared_progress.transferred_bytes(),
+    })
+}
+
+/// Returns whether a patch that couldn't be downloaded or applied should be
+/// skipped, according to `failure_policy`.
+fn should_skip_failed_patch(
+    failure_policy: FailurePolicy,
+    patch_name: &str,
+    error_msg: &str,
+    ui_controller: &UiController,
+) -> bool {
+    match failure_policy {
+        FailurePolicy::Abort => false,
+        FailurePolicy::Skip => true,
+        FailurePolicy::Prompt => ui_controller.prompt_skip_patch(patch_name, error_msg),
+    }
+}
+
+/// Returns the backend to use in order to download a patch.
+fn select_download_backend<'a>(
+    patch_info: &'a ThorPatchInfo,
+    patch_file_url: &Url,
+    settings: &DownloadSettings<'a>,
+) -> DownloadBackend<'a> {
+    if is_local_url(patch_file_url) {
+        if let Ok(path) = local_path_from_url(patch_file_url) {
+            return DownloadBackend::LocalFile(path);
+        }
+    }
+    match (settings.torrent_config, &patch_info.torrent_url) {
+        (Some(torrent_config), Some(torrent_url)) => {
+            DownloadBackend::BitTorrent(torrent_config, torrent_url)
+        }
+        _ => DownloadBackend::Http,
+    }
+}
+
+/// Downloads a single patch over HTTP, retrying on transient failures.
+///
+/// Returns the patch's content if it's been kept in memory (see
+/// `download_patch_content`), it's written to `part_file_path` otherwise.
+async fn download_patch_over_http<CB: FnMut(u64, u64)>(
+    client: &reqwest::Client,
+    patch_file_url: &Url,
+    patch_info: &ThorPatchInfo,
+    part_file_path: &Path,
+    settings: &DownloadSettings<'_>,
+    ui_controller: &UiController,
+    mut progress_callback: CB,
+) -> Result<Option<Vec<u8>>> {
+    // Download large patches with several connections, if possible
+    if settings.download_segments > 1 {
+        let file_size =
+            fetch_ranged_download_size(client, patch_file_url, settings.read_timeout).await;
+        if let Some(file_size) = file_size.filter(|size| *size >= SEGMENTED_DOWNLOAD_MIN_SIZE) {
+            let on_retry = |attempt, max_attempts| {
+                ui_controller.dispatch_patching_status(PatchingStatus::DownloadRetry(
+                    patch_info.file_name.clone(),
+                    attempt,
+                    max_attempts,
+                ));
+            };
+            let res = download_patch_segmented(
+                client,
+                patch_file_url,
+                part_file_path,
+                file_size,
+                settings,
+                &on_retry,
+                &mut progress_callback,
+            )
+            .await;
+            match res {
+                Ok(()) => return Ok(None),
+                Err(err) => log::warn!(
+                    "Segmented download of '{}' failed, retrying with a single connection: {:#}",
+                    patch_info.file_name,
+                    err
+                ),
+            }
+        }
+    }
+
+    // Note: The file is only created once content has to be written to it
+    let mut part_file = None;
+    let mut backoff = Backoff::new(settings.retry_config);
+    loop {
+        let res = download_patch_content(
+            client,
+            patch_file_url,
+            patch_info,
+            part_file_path,
+            &mut part_file,
+            settings,
+            &mut progress_callback,
+        )
+        .await;
+        match res {
+            Ok(Some(content)) => {
+                // Content written by previous attempts isn't needed anymore
+                if part_file.take().is_some() {
+                    let _ = tokio::fs::remove_file(part_file_path).await;
+                }
+                return Ok(Some(content));
+            }
+            Ok(None) => return Ok(None),
+            Err(err) => match backoff.next_delay(&err) {
+                None => return Err(err),
+                Some(delay) => {
+                    log::warn!("{:#}, retrying in {:?}", err, delay);
+                    ui_controller.dispatch_patching_status(PatchingStatus::DownloadRetry(
+                        patch_info.file_name.clone(),
+                        backoff.attempt(),
+                        backoff.max_attempts(),
+                    ));
+                    tokio::time::sleep(delay).await;
+                    // Discard partially downloaded content
+                    if let Some(part_file) = &mut part_file {
+                        part_file
+                            .set_len(0)
+                            .await
+                            .with_context(|| "Failed to truncate temporary file")?;
+                        part_file
+                            .seek(SeekFrom::Start(0))
+                            .await
+                            .with_context(|| "Failed to truncate temporary file")?;
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Returns the size of a remote file, if the server supports range requests
+/// for it.
+async fn fetch_ranged_download_size(
+    client: &reqwest::Client,
+    file_url: &Url,
+    read_timeout: Duration,
+) -> Option<u64> {
+    let resp = with_read_timeout(read_timeout, client.head(file_url.clone()).send())
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    let accept_ranges = resp
+        .headers()
+        .get(reqwest::header::ACCEPT_RANGES)?
+        .to_str()
+        .ok()?;
+    if !accept_ranges.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    content_length_header(&resp)
+}
+
+/// Downloads a file with `settings.download_segments` concurrent range
+/// requests, written directly at their offset in `part_file_path`.
+///
+/// Each segment is retried (and resumed) on its own, `on_retry` being called
+/// with the attempt number and the maximum number of attempts.
+async fn download_patch_segmented<CB: FnMut(u64, u64)>(
+    client: &reqwest::Client,
+    file_url: &Url,
+    part_file_path: &Path,
+    file_size: u64,
+    settings: &DownloadSettings<'_>,
+    on_retry: &impl Fn(u32, u32),
+    progress_callback: CB,
+) -> Result<()> {
+    let part_file = File::create(part_file_path)
+        .await
+        .with_context(|| "Failed to create temporary file")?;
+    part_file
+        .set_len(file_size)
+        .await
+        .with_context(|| "Failed to allocate temporary file")?;
+
+    // Aggregate the progress of all segments
+    let downloaded_bytes = Cell::new(0_u64);
+    let progress_callback = RefCell::new(progress_callback);
+    let on_progress = |byte_count: u64| {
+        downloaded_bytes.set(downloaded_bytes.get() + byte_count);
+        (progress_callback.borrow_mut())(downloaded_bytes.get(), file_size);
+    };
+    (progress_callback.borrow_mut())(0, file_size);
+
+    let segment_count = settings.download_segments as u64;
+    let segments = (0..segment_count)
+        .map(|i| file_size * i / segment_count..file_size * (i + 1) / segment_count)
+        .filter(|segment| !segment.is_empty());
+    futures::future::try_join_all(segments.map(|segment| {
+        download_segment(
             client,
-            &patch_file_url,
-            &patch_info,
-            &mut tmp_file,
-            &mut progress_callback,
+            file_url,
+            part_file_path,
+            segment,
+            settings,
+            on_retry,
+            &on_progress,
         )
-        .await?;
+    }))
+    .await?;
+    part_file
+        .sync_all()
+        .await
+        .with_context(|| "Failed to sync downloaded file")
+}
 
-        // Check the archive's integrity if required
-        let context = || {
-            format!(
-                "Failed to check archive's integrity: '{}'",
-                patch_info.file_name
-            )
+/// Downloads the `segment` byte range of a file into the same range of
+/// `part_file_path`.
+async fn download_segment(
+    client: &reqwest::Client,
+    file_url: &Url,
+    part_file_path: &Path,
+    segment: Range<u64>,
+    settings: &DownloadSettings<'_>,
+    on_retry: &impl Fn(u32, u32),
+    on_progress: &impl Fn(u64),
+) -> Result<()> {
+    let mut part_file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(part_file_path)
+        .await
+        .with_context(|| "Failed to open temporary file")?;
+    let mut backoff = Backoff::new(settings.retry_config);
+    let mut offset = segment.start;
+    while offset < segment.end {
+        let res = download_segment_from(
+            client,
+            file_url,
+            &mut part_file,
+            &mut offset,
+            segment.end,
+            settings,
+            on_progress,
+        )
+        .await;
+        if let Err(err) = res {
+            match backoff.next_delay(&err) {
+                None => return Err(err),
+                Some(delay) => {
+                    log::warn!("{:#}, retrying in {:?}", err, delay);
+                    on_retry(backoff.attempt(), backoff.max_attempts());
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Downloads the bytes of a file from `offset` to `end`. `offset` is updated
+/// as data is written, so that interrupted downloads can be resumed.
+async fn download_segment_from(
+    client: &reqwest::Client,
+    file_url: &Url,
+    part_file: &mut File,
+    offset: &mut u64,
+    end: u64,
+    settings: &DownloadSettings<'_>,
+    on_progress: &impl Fn(u64),
+) -> Result<()> {
+    part_file.seek(SeekFrom::Start(*offset)).await?;
+    let request = client.get(file_url.clone()).header(
+        reqwest::header::RANGE,
+        format!("bytes={}-{}", offset, end - 1),
+    );
+    let mut resp = with_read_timeout(settings.read_timeout, request.send())
+        .await
+        .with_context(|| "Failed to GET URL")?
+        .error_for_status()?;
+    if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err(anyhow!("Server doesn't support range requests"));
+    }
+    while *offset < end {
+        // Stop reading from the server while the update is paused
+        settings.pause_state.wait_while_paused().await;
+        let chunk = match with_read_timeout(settings.read_timeout, resp.chunk()).await? {
+            Some(chunk) => chunk,
+            None => {
+                return Err(TruncatedBody {
+                    end: *offset,
+                    expected: end,
+                })
+                .with_context(|| "Segment is incomplete")
+            }
         };
-        if ensure_integrity && !is_archive_valid(&local_file_path).with_context(context)? {
+        let chunk_size = chunk.len().min((end - *offset) as usize);
+        part_file
+            .write_all(&chunk[..chunk_size])
+            .await
+            .with_context(|| "Failed to write to temporary file")?;
+        *offset += chunk_size as u64;
+        on_progress(chunk_size as u64);
+        if let Some(bandwidth_limiter) = settings.bandwidth_limiter {
+            bandwidth_limiter.consume(chunk_size).await;
+        }
+    }
+    Ok(())
+}
+
+/// Checks a downloaded patch's integrity (if `ensure_integrity` is set) and
+/// hash (if announced by the patch index).
+fn validate_downloaded_patch<R: Read + Seek>(
+    mut patch: R,
+    patch_info: &ThorPatchInfo,
+    ensure_integrity: bool,
+) -> Result<()> {
+    let context = || {
+        format!(
+            "Failed to check archive's integrity: '{}'",
+            patch_info.file_name
+        )
+    };
+    // Only THOR archives embed checksums
+    let is_thor_archive = PatchFormat::from_file_name(&patch_info.file_name) == PatchFormat::Thor;
+    if ensure_integrity && is_thor_archive {
+        let mut archive = ThorArchive::new(&mut patch)
+            .with_context(|| "Failed to open archive")
+            .with_context(context)?;
+        if !is_thor_archive_valid(&mut archive).with_context(context)? {
             return Err(anyhow!("Archive '{}' is corrupt", patch_info.file_name));
         }
+        patch.seek(SeekFrom::Start(0)).with_context(context)?;
+    }
+    if !is_content_hash_valid(patch, patch_info).with_context(context)? {
+        return Err(anyhow!(
+            "Archive '{}' doesn't match the expected hash",
+            patch_info.file_name
+        ));
+    }
+    Ok(())
+}
 
-        // Update status
-        shared_patch_number_ref.fetch_add(1, Ordering::SeqCst);
+/// Indicates whether the patch left at `local_file_path` by a previous session
+/// can be used as `patch_info`, served at `patch_file_url`.
+///
+/// Patches without a hash are only reused if they were downloaded from the
+/// same URL, and their size must match if it's known.
+fn is_download_reusable(
+    local_file_path: &Path,
+    patch_info: &ThorPatchInfo,
+    patch_file_url: &Url,
+) -> bool {
+    if let Some(expected_size) = patch_info.size {
+        match std::fs::metadata(local_file_path) {
+            Ok(metadata) if metadata.len() == expected_size => {}
+            _ => return false,
+        }
+    }
+    if patch_info.hash.is_none() {
+        let source = std::fs::read_to_string(download_source_file_path(local_file_path));
+        if !matches!(source, Ok(source) if source == patch_file_url.as_str()) {
+            return false;
+        }
+    }
+    matches!(is_archive_valid(local_file_path), Ok(true))
+        && matches!(is_hash_valid(local_file_path, patch_info), Ok(true))
+}
 
-        // File's been downloaded, add it to the queue
-        Ok(PendingPatch {
-            info: patch_info,
-            local_file_path,
-        }) as Result<PendingPatch>
-    }))
-    .buffer_unordered(CONCURRENT_DOWNLOADS)
-    .try_collect()
-    .await
+/// Returns the path of the file recording the URL the patch downloaded at
+/// `local_file_path` was fetched from (e.g. '2021-05-01.thor.source').
+fn download_source_file_path(local_file_path: &Path) -> PathBuf {
+    let mut file_path = local_file_path.as_os_str().to_owned();
+    file_path.push(".source");
+    PathBuf::from(file_path)
 }
 
 fn is_archive_valid(archive_path: impl AsRef<Path>) -> Result<bool> {
     let mut archive =
         ThorArchive::open(archive_path.as_ref()).with_context(|| "Failed to open archive")?;
+    is_thor_archive_valid(&mut archive)
+}
+
+fn is_thor_archive_valid<R: Read + Seek>(archive: &mut ThorArchive<R>) -> Result<bool> {
     match archive.is_valid() {
         Err(e) => {
             if let GrufError::EntryNotFound = e {
                 // No integrity file present, consider the archive valid
                 Ok(true)
             } else {
-                // Only consider this an error if the integrity file was found
-                Err(anyhow!(
-                    "Archive's integrity file is invalid: {}",
-                    e.to_string(),
-                ))
+                // Only consider this an error if the integrity file was found
+                Err(anyhow!(
+                    "Archive's integrity file is invalid: {}",
+                    e.to_string(),
+                ))
+            }
+        }
+        Ok(v) => Ok(v),
+    }
+}
+
+/// Checks that a patch file matches the SHA-256 hash announced by the patch
+/// index, if any.
+fn is_hash_valid(patch_file_path: impl AsRef<Path>, patch_info: &ThorPatchInfo) -> Result<bool> {
+    if patch_info.hash.is_none() {
+        return Ok(true);
+    }
+    is_content_hash_valid(std::fs::File::open(patch_file_path)?, patch_info)
+}
+
+/// Checks that a patch's content matches the SHA-256 hash announced by the
+/// patch index, if any.
+fn is_content_hash_valid(mut patch: impl Read, patch_info: &ThorPatchInfo) -> Result<bool> {
+    let expected_hash = match &patch_info.hash {
+        Some(v) => v,
+        None => return Ok(true),
+    };
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut patch, &mut hasher)?;
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(hash.eq_ignore_ascii_case(expected_hash.trim()))
+}
+
+/// Downloads a single patch described with a `ThorPatchInfo`, to `part_file`
+/// (created at `part_file_path` if `None`).
+///
+/// Small disk-merge patches are kept in memory if `settings.in_memory_patches`
+/// is set, and returned, so that they never hit the file system. This is
+/// decided from the size of the patch, patches of unknown size are written to
+/// the file. Patches that turn out to target a GRF (GRF merging needs random
+/// access to archives) are written to the file as they're received.
+///
+/// If a bandwidth limiter is given in `settings`, the download is slowed down
+/// to respect its limit.
+async fn download_patch_content<CB: FnMut(u64, u64)>(
+    client: &reqwest::Client,
+    patch_file_url: &Url,
+    patch: &ThorPatchInfo,
+    part_file_path: &Path,
+    part_file: &mut Option<File>,
+    settings: &DownloadSettings<'_>,
+    mut progress_callback: CB,
+) -> Result<Option<Vec<u8>>> {
+    let mut resp = with_read_timeout(
+        settings.read_timeout,
+        client.get(patch_file_url.clone()).send(),
+    )
+    .await
+    .with_context(|| format!("Failed to download file '{}'", patch.file_name))?
+    .error_for_status()
+    .with_context(|| {
+        format!(
+            "Patch file '{}' is unavailable on the remote server",
+            patch.file_name
+        )
+    })?;
+    let content_length = resp.content_length();
+    let bytes_to_download = content_length.or(patch.size);
+    let mut content = match bytes_to_download {
+        Some(size)
+            if settings.in_memory_patches
+                && size <= IN_MEMORY_PATCH_MAX_SIZE
+                && PatchFormat::from_file_name(&patch.file_name) == PatchFormat::Thor =>
+        {
+            Some(Vec::with_capacity(size as usize))
+        }
+        _ => None,
+    };
+    let bytes_to_download = bytes_to_download.unwrap_or(0);
+    let mut downloaded_bytes: u64 = 0;
+    let mut header_checked = false;
+    progress_callback(downloaded_bytes, bytes_to_download);
+    loop {
+        // Stop reading from the server while the update is paused
+        settings.pause_state.wait_while_paused().await;
+        let chunk = match with_read_timeout(settings.read_timeout, resp.chunk())
+            .await
+            .with_context(|| format!("Failed to download file '{}'", patch.file_name))?
+        {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        if let Some(buffer) = &mut content {
+            buffer.extend_from_slice(&chunk[..]);
+            // Stop buffering as soon as we know the patch targets a GRF
+            let is_header_received = buffer.len() >= thor::reader::HEADER_MAX_SIZE;
+            let must_write_to_file = buffer.len() as u64 > IN_MEMORY_PATCH_MAX_SIZE
+                || (is_header_received && !header_checked && !is_disk_merge_patch(buffer));
+            header_checked |= is_header_received;
+            if must_write_to_file {
+                write_part_file(part_file, part_file_path, buffer).await?;
+                content = None;
+            }
+        } else {
+            write_part_file(part_file, part_file_path, &chunk[..]).await?;
+        }
+        downloaded_bytes += chunk.len() as u64;
+        progress_callback(downloaded_bytes, bytes_to_download);
+        if let Some(bandwidth_limiter) = settings.bandwidth_limiter {
+            bandwidth_limiter.consume(chunk.len()).await;
+        }
+    }
+    if let Some(expected) = content_length.filter(|expected| downloaded_bytes < *expected) {
+        return Err(TruncatedBody {
+            end: downloaded_bytes,
+            expected,
+        })
+        .with_context(|| format!("Failed to download file '{}'", patch.file_name));
+    }
+    match content {
+        Some(buffer) if header_checked || is_disk_merge_patch(&buffer) => return Ok(Some(buffer)),
+        Some(buffer) => write_part_file(part_file, part_file_path, &buffer).await?,
+        // Note: Empty patches are written to a file as well
+        None => write_part_file(part_file, part_file_path, &[]).await?,
+    }
+    if let Some(part_file) = part_file {
+        part_file
+            .sync_all()
+            .await
+            .with_context(|| format!("Failed to sync downloaded file '{}'", patch.file_name))?;
+    }
+    Ok(None)
+}
+
+/// Indicates whether `content` starts with the header of a THOR patch merged
+/// into the game's directory, rather than into a GRF.
+fn is_disk_merge_patch(content: &[u8]) -> bool {
+    matches!(thor::parse_thor_header_bytes(content), Ok(header) if !header.use_grf_merging)
+}
+
+/// Appends `data` to `part_file`, which is created at `part_file_path` if
+/// `None`.
+async fn write_part_file(
+    part_file: &mut Option<File>,
+    part_file_path: &Path,
+    data: &[u8],
+) -> Result<()> {
+    if part_file.is_none() {
+        let file = File::create(part_file_path)
+            .await
+            .with_context(|| "Failed to create temporary file")?;
+        *part_file = Some(file);
+    }
+    if let Some(part_file) = part_file {
+        part_file
+            .write_all(data)
+            .await
+            .with_context(|| "Failed to write to temporary file")?;
+    }
+    Ok(())
+}
+
+/// Parses and applies a list of patches to GRFs and/or to the game client's
+/// files.
+///
+/// This function is interruptible.
+///
+/// Patches that come from a patch server are tracked in the cache file and
+/// removed once applied. Patches are left untouched when `cache_file_path` is
+/// `None`.
+///
+/// Consecutive THOR patches merged into the same GRF are applied together, so
+/// that the GRF is only rebuilt once. If that fails, they're applied one by
+/// one.
+///
+/// Returns the names of the patches that were skipped because of failures,
+/// along with the protected files patches weren't allowed to modify.
+async fn apply_patches(
+    pending_patch_queue: Vec<PendingPatch>,
+    config: &PatcherConfiguration,
+    cache_file_path: Option<&Path>,
+    session_journal_file_path: Option<&Path>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<InstallationOutcome> {
+    let install_directory = resolve_install_directory(&config.client)
+        .map_err(|e| InterruptibleFnError::Err(format!("{:#}.", e)))?;
+    let patch_count = pending_patch_queue.len();
+    let mut skipped_patches = vec![];
+    let mut protected_files: Vec<String> = vec![];
+    let mut patch_number = 0;
+    // Note: Changes are computed before patches are applied, since they
+    // depend on the files that exist beforehand
+    let mut installed_file_previews = if config.patching.track_installed_files {
+        preview_installed_files(&pending_patch_queue, config, &install_directory)
+    } else {
+        HashMap::new()
+    };
+    // Note: The outcome of each patch is reported however the installation
+    // ends
+    let mut patch_reports = scopeguard::guard(vec![], |patch_reports| {
+        report_patch_outcomes(patch_reports, ui_controller)
+    });
+    ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(0, patch_count));
+    let patch_batches = batch_pending_patches(
+        pending_patch_queue,
+        name_encoding(config),
+        &config.client,
+        &install_directory,
+    );
+    for patch_batch in patch_batches {
+        // Cancel (or pause) the patching process if we've been asked to or if
+        // the other end of the channel has been disconnected
+        process_incoming_commands(patching_thread_rx, pause_state, ui_controller).await?;
+
+        if let (Some(target_grf_path), [.., last_patch]) =
+            (&patch_batch.target_grf_path, patch_batch.patches.as_slice())
+        {
+            log::info!(
+                "Processing {} patches merged into '{}'",
+                patch_batch.patches.len(),
+                target_grf_path.display()
+            );
+            let patching_task = {
+                let patch_file_paths: Vec<PathBuf> = patch_batch
+                    .patches
+                    .iter()
+                    .filter_map(|patch| match &patch.content {
+                        PatchContent::File(local_file_path) => Some(local_file_path.clone()),
+                        PatchContent::Memory(_) => None,
+                    })
+                    .collect();
+                let patch_file_paths = Arc::new(patch_file_paths);
+                // Note: Batches are merged out-of-place if any of their
+                // patches requires it
+                let in_place = patch_batch.patches.iter().all(|patch| {
+                    is_patched_in_place(config, &patch.info, pending_patch_size(patch))
+                });
+                move |pending_renames: &PendingRenames| {
+                    let patch_file_paths = patch_file_paths.clone();
+                    let target_grf_path = target_grf_path.clone();
+                    let pending_renames = pending_renames.clone();
+                    let config = config.clone();
+                    // Note: Batches are reported under the name of their last patch
+                    let mut report_progress = patch_progress_reporter(
+                        last_patch.info.file_name.clone(),
+                        ui_controller.clone(),
+                    );
+                    tokio::task::spawn_blocking(move || {
+                        apply_patches_to_same_grf(
+                            &patch_file_paths,
+                            target_grf_path,
+                            in_place,
+                            &config,
+                            &pending_renames,
+                            &mut report_progress,
+                        )
+                    })
+                }
+            };
+            let patch_sizes: Vec<Option<u64>> =
+                patch_batch.patches.iter().map(pending_patch_size).collect();
+            let batch_start = Instant::now();
+            let (res, interruption) = apply_until_unlocked(
+                patching_task,
+                pause_state,
+                ui_controller,
+                patching_thread_rx,
+            )
+            .await;
+            match res {
+                Ok(protected_paths) => {
+                    protected_files.extend(protected_paths);
+                    // Note: Patches applied together share the duration of
+                    // their batch
+                    let duration_ms = batch_start.elapsed().as_millis() as u64;
+                    for (patch, size) in patch_batch.patches.iter().zip(patch_sizes) {
+                        patch_reports.push(PatchReport {
+                            name: patch.info.file_name.clone(),
+                            index: patch.info.index,
+                            timestamp: unix_timestamp(),
+                            size,
+                            duration_ms,
+                            outcome: PatchOutcome::Applied,
+                            reason: None,
+                        });
+                    }
+                    for patch in &patch_batch.patches {
+                        if let Some(preview) = installed_file_previews.remove(&patch.info.file_name)
+                        {
+                            record_installed_files(
+                                &patch.info,
+                                preview,
+                                config,
+                                &install_directory,
+                            )
+                            .await;
+                        }
+                        if let PatchContent::File(local_file_path) = &patch.content {
+                            complete_applied_patch(
+                                cache_file_path,
+                                session_journal_file_path,
+                                &patch.info,
+                                Some(local_file_path),
+                            )
+                            .await;
+                        }
+                    }
+                    patch_number += patch_batch.patches.len();
+                    ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(
+                        patch_number,
+                        patch_count,
+                    ));
+                    if let Some(interruption) = interruption {
+                        return Err(interruption);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    if let Some(interruption) = interruption {
+                        return Err(interruption);
+                    }
+                    // Apply patches one by one to find out which one fails
+                    log::warn!(
+                        "Failed to apply patches together ({:#}), applying them one by one",
+                        e
+                    );
+                }
+            }
+        }
+
+        for pending_patch in patch_batch.patches {
+            process_incoming_commands(patching_thread_rx, pause_state, ui_controller).await?;
+
+            let patch_size = pending_patch_size(&pending_patch);
+            let PendingPatch { info, content } = pending_patch;
+            let patch_name = info.file_name.clone();
+            log::info!("Processing {}", patch_name);
+            let local_file_path = match &content {
+                PatchContent::File(local_file_path) => Some(local_file_path.clone()),
+                PatchContent::Memory(_) => None,
+            };
+            // Archives are read and written synchronously, apply the patch on the
+            // blocking thread pool to keep processing commands in the meantime
+            let patching_task = {
+                // Note: The content is shared with the tasks of the retries
+                let content = Arc::new(content);
+                let (info, patch_name, install_directory) =
+                    (&info, &patch_name, &install_directory);
+                move |pending_renames: &PendingRenames| {
+                    let content = content.clone();
+                    let info = info.clone();
+                    let pending_renames = pending_renames.clone();
+                    let config = config.clone();
+                    let install_directory = install_directory.clone();
+                    let mut report_progress =
+                        patch_progress_reporter(patch_name.clone(), ui_controller.clone());
+                    tokio::task::spawn_blocking(move || match content.as_ref() {
+                        PatchContent::File(local_file_path) => apply_patch(
+                            local_file_path,
+                            &info,
+                            &config,
+                            install_directory,
+                            &pending_renames,
+                            &mut report_progress,
+                        ),
+                        PatchContent::Memory(content) => apply_patch_from_memory(
+                            content,
+                            &info,
+                            &config,
+                            install_directory,
+                            &pending_renames,
+                            &mut report_progress,
+                        ),
+                    })
+                }
+            };
+            let patch_start = Instant::now();
+            let (res, interruption) = apply_until_unlocked(
+                patching_task,
+                pause_state,
+                ui_controller,
+                patching_thread_rx,
+            )
+            .await;
+            let mut patch_report = PatchReport {
+                name: patch_name.clone(),
+                index: info.index,
+                timestamp: unix_timestamp(),
+                size: patch_size,
+                duration_ms: patch_start.elapsed().as_millis() as u64,
+                outcome: PatchOutcome::Applied,
+                reason: None,
+            };
+            match res {
+                Err(e) => {
+                    if let Some(interruption) = interruption {
+                        return Err(interruption);
+                    }
+                    let err_msg = format!("{:#}", e);
+                    patch_report.reason = Some(err_msg.clone());
+                    if !should_skip_failed_patch(
+                        config.patching.on_failure.unwrap_or(FailurePolicy::Abort),
+                        &patch_name,
+                        &err_msg,
+                        ui_controller,
+                    ) {
+                        patch_report.outcome = PatchOutcome::Failed;
+                        patch_reports.push(patch_report);
+                        return Err(InterruptibleFnError::Err(format!(
+                            "Failed to apply patch '{}': {}.",
+                            patch_name, e
+                        )));
+                    }
+                    log::warn!("Skipping patch '{}': {}", patch_name, err_msg);
+                    patch_report.outcome = PatchOutcome::Skipped;
+                    patch_reports.push(patch_report);
+                    skipped_patches.push(patch_name);
+                }
+                Ok(protected_paths) => {
+                    patch_reports.push(patch_report);
+                    protected_files.extend(protected_paths);
+                    if let Some(preview) = installed_file_previews.remove(&info.file_name) {
+                        record_installed_files(&info, preview, config, &install_directory).await;
+                    }
+                    complete_applied_patch(
+                        cache_file_path,
+                        session_journal_file_path,
+                        &info,
+                        local_file_path.as_deref(),
+                    )
+                    .await;
+                }
+            }
+            // Update status
+            patch_number += 1;
+            ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(
+                patch_number,
+                patch_count,
+            ));
+            // The update has been canceled while the patch was being applied
+            if let Some(interruption) = interruption {
+                return Err(interruption);
+            }
+        }
+    }
+    protected_files.sort_unstable();
+    protected_files.dedup();
+    // Note: Excluded files are reported apart from the protected ones
+    let (excluded_files, protected_files): (Vec<String>, Vec<String>) = protected_files
+        .into_iter()
+        .partition(|path| is_protected(path, &config.patching.exclude_patterns));
+    Ok(InstallationOutcome {
+        skipped_patches,
+        protected_files,
+        excluded_files,
+    })
+}
+
+/// Logs the outcome of each patch of an installation and reports it to the
+/// UI, so that users can share a single report with support staff.
+fn report_patch_outcomes(patch_reports: Vec<PatchReport>, ui_controller: &UiController) {
+    if patch_reports.is_empty() {
+        return;
+    }
+    log::info!("Patch report:");
+    for patch_report in &patch_reports {
+        log::info!(
+            "  {}: {:?} ({} bytes, {} ms){}",
+            patch_report.name,
+            patch_report.outcome,
+            patch_report
+                .size
+                .map_or_else(|| "?".to_string(), |size| size.to_string()),
+            patch_report.duration_ms,
+            patch_report
+                .reason
+                .as_ref()
+                .map_or_else(String::new, |reason| format!(": {}", reason))
+        );
+    }
+    if let Err(e) = get_history_file_path().and_then(|path| append_history(path, &patch_reports)) {
+        log::warn!("Failed to record the patch history: {:#}", e);
+    }
+    ui_controller.dispatch_patching_status(PatchingStatus::Report(patch_reports));
+}
+
+/// Returns the number of seconds elapsed since the Unix epoch.
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Runs the patching tasks spawned by `spawn_patching_task` until one of them
+/// isn't prevented from completing by a locked file (e.g. a GRF opened by the
+/// game client).
+///
+/// When a file is locked, the user is asked whether the patch should be
+/// retried or whether the file should be replaced on the next start, with a
+/// copy that gets patched in its place. Patches are also retried as soon as
+/// the file is released.
+async fn apply_until_unlocked(
+    mut spawn_patching_task: impl FnMut(&PendingRenames) -> JoinHandle<Result<Vec<String>>>,
+    pause_state: &PauseState,
+    ui_controller: &UiController,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> (Result<Vec<String>>, Option<InterruptibleFnError>) {
+    loop {
+        let pending_renames_file_path = match get_pending_renames_file_path() {
+            Ok(path) => path,
+            Err(err) => return (Err(err), None),
+        };
+        let mut pending_renames = match PendingRenames::load(&pending_renames_file_path) {
+            Ok(pending_renames) => pending_renames,
+            Err(err) => return (Err(err), None),
+        };
+        let (res, interruption) = wait_for_blocking_task(
+            spawn_patching_task(&pending_renames),
+            patching_thread_rx,
+            pause_state,
+            ui_controller,
+        )
+        .await;
+        let err = match res {
+            Err(err) if interruption.is_none() && is_locked_file_error(&err) => err,
+            res => return (res, interruption),
+        };
+        log::warn!("{:#}", err);
+        let locked_file_path = locked_file_path(&err).map(Path::to_path_buf);
+        ui_controller.dispatch_patching_status(PatchingStatus::FileLocked(
+            locked_file_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            locked_file_path.is_some(),
+        ));
+        let action = wait_for_locked_file_action(
+            patching_thread_rx,
+            pause_state,
+            ui_controller,
+            LOCKED_FILE_POLL_INTERVAL,
+            // Note: Patches are only retried automatically when we know which
+            // file is locked
+            || {
+                locked_file_path
+                    .as_ref()
+                    .map_or(false, |path| !is_file_locked(path))
+            },
+        )
+        .await;
+        match (action, &locked_file_path) {
+            (Err(interruption), _) => return (Err(err), Some(interruption)),
+            (Ok(LockedFileAction::Schedule), Some(locked_file_path)) => {
+                let res = pending_renames
+                    .schedule_replacement(locked_file_path)
+                    .and_then(|_| pending_renames.save(&pending_renames_file_path));
+                if let Err(err) = res {
+                    return (Err(err), None);
+                }
+            }
+            (Ok(_), _) => {}
+        }
+        log::info!("Retrying patch");
+    }
+}
+
+/// Computes the changes each of the pending patches will make, indexed by
+/// patch name, to record the files they write once they're applied. Patches
+/// that can't be previewed aren't recorded.
+fn preview_installed_files(
+    pending_patches: &[PendingPatch],
+    config: &PatcherConfiguration,
+    install_directory: &Path,
+) -> HashMap<String, PatchPreview> {
+    let mut previewer = PatchPreviewer::new(
+        install_directory,
+        name_encoding(config),
+        unsafe_path_handling(config),
+        path_matching(config),
+        skipped_file_patterns(config),
+    );
+    let mut previews = HashMap::new();
+    for pending_patch in pending_patches {
+        match preview_pending_patches(std::slice::from_ref(pending_patch), config, &mut previewer) {
+            Err(e) => log::warn!("Installed files won't be recorded: {:#}", e),
+            Ok(patch_previews) => previews.extend(
+                patch_previews
+                    .into_iter()
+                    .map(|preview| (pending_patch.info.file_name.clone(), preview)),
+            ),
+        }
+    }
+    previews
+}
+
+/// Records the files written by a patch that's been applied in the installed
+/// files database. Failures are only logged, they don't affect the update.
+async fn record_installed_files(
+    patch_info: &ThorPatchInfo,
+    preview: PatchPreview,
+    config: &PatcherConfiguration,
+    install_directory: &Path,
+) {
+    let patch_info = patch_info.clone();
+    let install_directory = install_directory.to_path_buf();
+    let (name_encoding, path_matching) = (name_encoding(config), path_matching(config));
+    // Note: Written files are read back to be hashed
+    let res = tokio::task::spawn_blocking(move || -> Result<()> {
+        let installed_files_path = get_installed_files_path()?;
+        let mut installed_files = InstalledFiles::load(&installed_files_path)?;
+        installed_files.record_patch(
+            &patch_info,
+            &preview,
+            &install_directory,
+            name_encoding,
+            path_matching,
+        )?;
+        installed_files.save(&installed_files_path)
+    })
+    .await;
+    match res {
+        Err(e) => log::warn!("Failed to record installed files: {}", e),
+        Ok(Err(e)) => log::warn!("Failed to record installed files: {:#}", e),
+        Ok(Ok(())) => {}
+    }
+}
+
+/// Removes the local file of a patch that's been applied and records it as
+/// the last successful patch in the cache file. Does nothing when
+/// `cache_file_path` is `None`.
+async fn complete_applied_patch(
+    cache_file_path: Option<&Path>,
+    session_journal_file_path: Option<&Path>,
+    patch_info: &ThorPatchInfo,
+    local_file_path: Option<&Path>,
+) {
+    if let Some(session_journal_file_path) = session_journal_file_path {
+        if let Err(e) = record_applied_patch(session_journal_file_path, &patch_info.file_name) {
+            log::warn!("Failed to write session journal: {:#}.", e);
+        }
+    }
+    let cache_file_path = match cache_file_path {
+        Some(cache_file_path) => cache_file_path,
+        None => return,
+    };
+    // Applied patches aren't needed anymore
+    if let Some(local_file_path) = local_file_path {
+        if let Err(e) = tokio::fs::remove_file(local_file_path).await {
+            log::warn!("Failed to remove '{}': {}.", patch_info.file_name, e);
+        }
+        let _ = tokio::fs::remove_file(download_source_file_path(local_file_path)).await;
+    }
+    // Update the cache file with the last successful patch
+    let patcher_cache = match read_cache_file(cache_file_path).await {
+        Ok(mut patcher_cache) => {
+            patcher_cache.patch_list_validators = None;
+            patcher_cache.record_applied_patch(patch_info);
+            patcher_cache
+        }
+        Err(_) => PatcherCache::new(patch_info),
+    };
+    if let Err(e) = write_cache_file(cache_file_path, patcher_cache).await {
+        log::warn!("Failed to write cache file: {}.", e);
+    }
+}
+
+/// Splits a queue of pending patches into batches of consecutive THOR patches
+/// merged into the same GRF. Other patches (and patches containing delta
+/// entries) are applied on their own.
+fn batch_pending_patches(
+    pending_patch_queue: Vec<PendingPatch>,
+    name_encoding: NameEncoding,
+    client_config: &ClientConfiguration,
+    install_directory: impl AsRef<Path>,
+) -> Vec<PatchBatch> {
+    let mut patch_batches: Vec<PatchBatch> = vec![];
+    for pending_patch in pending_patch_queue {
+        let target_grf_path = batchable_patch_target(
+            &pending_patch,
+            name_encoding,
+            client_config,
+            install_directory.as_ref(),
+        );
+        match patch_batches.last_mut() {
+            Some(patch_batch)
+                if target_grf_path.is_some() && patch_batch.target_grf_path == target_grf_path =>
+            {
+                patch_batch.patches.push(pending_patch);
+            }
+            _ => patch_batches.push(PatchBatch {
+                target_grf_path,
+                patches: vec![pending_patch],
+            }),
+        }
+    }
+    // Lone patches are applied the usual way
+    for patch_batch in &mut patch_batches {
+        if patch_batch.patches.len() < 2 {
+            patch_batch.target_grf_path = None;
+        }
+    }
+    patch_batches
+}
+
+/// Returns the path of the GRF a pending patch is merged into, if the patch
+/// can be applied along with other patches.
+fn batchable_patch_target(
+    pending_patch: &PendingPatch,
+    name_encoding: NameEncoding,
+    client_config: &ClientConfiguration,
+    install_directory: &Path,
+) -> Option<PathBuf> {
+    let patch_file_path = match &pending_patch.content {
+        PatchContent::File(local_file_path) => local_file_path,
+        PatchContent::Memory(_) => return None,
+    };
+    if PatchFormat::from_file_name(patch_file_path) != PatchFormat::Thor {
+        return None;
+    }
+    let thor_archive = ThorArchive::open_with_encoding(patch_file_path, name_encoding).ok()?;
+    if !thor_archive.use_grf_merging() || contains_delta_entries(&thor_archive) {
+        return None;
+    }
+    Some(install_directory.join(target_grf_name(
+        &thor_archive,
+        &pending_patch.info.file_name,
+        pending_patch.info.target_grf.as_deref(),
+        client_config,
+    )))
+}
+
+/// Returns a callback that reports the progress of the application of
+/// `patch_name` to the UI.
+///
+/// Reports are sent at most every `PATCH_PROGRESS_INTERVAL`, except for the
+/// last entry.
+fn patch_progress_reporter(
+    patch_name: String,
+    ui_controller: UiController,
+) -> impl FnMut(PatchProgress) + Send + 'static {
+    let mut last_report: Option<Instant> = None;
+    move |progress| {
+        let now = Instant::now();
+        let is_throttled = match last_report {
+            Some(last_report) => now.duration_since(last_report) < PATCH_PROGRESS_INTERVAL,
+            None => false,
+        };
+        if is_throttled && progress.processed_entries < progress.total_entries {
+            return;
+        }
+        last_report = Some(now);
+        let status = match progress.rebuilt_grf {
+            Some(grf_name) => PatchingStatus::GrfRebuildInProgress {
+                grf: grf_name.to_string(),
+                entries_done: progress.processed_entries,
+                entries_total: progress.total_entries,
+            },
+            None => PatchingStatus::PatchInProgress {
+                file: patch_name.clone(),
+                entry: progress.processed_entries,
+                total_entries: progress.total_entries,
+                bytes_written: progress.bytes_written,
+            },
+        };
+        ui_controller.dispatch_patching_status(status);
+    }
+}
+
+/// Applies a THOR, RGZ or GPF patch, depending on its extension. The GRF
+/// given by the patch index in `patch_info` takes precedence over the GRF
+/// targeted by the archive.
+///
+/// Returns the paths of the protected files the patch wasn't allowed to
+/// modify.
+fn apply_patch(
+    patch_file_path: impl AsRef<Path>,
+    patch_info: &ThorPatchInfo,
+    config: &PatcherConfiguration,
+    install_directory: impl AsRef<Path>,
+    pending_renames: &PendingRenames,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    match PatchFormat::from_file_name(patch_file_path.as_ref()) {
+        PatchFormat::Thor => {
+            let patch_size = std::fs::metadata(patch_file_path.as_ref())
+                .ok()
+                .map(|metadata| metadata.len());
+            if config.patching.mmap_archives {
+                let mut thor_archive = ThorArchive::open_mmap_with_encoding(
+                    patch_file_path.as_ref(),
+                    name_encoding(config),
+                )?;
+                apply_thor_archive(
+                    &mut thor_archive,
+                    patch_info,
+                    patch_size,
+                    config,
+                    install_directory,
+                    pending_renames,
+                    on_progress,
+                )
+            } else {
+                let mut thor_archive = ThorArchive::open_with_encoding(
+                    patch_file_path.as_ref(),
+                    name_encoding(config),
+                )?;
+                apply_thor_archive(
+                    &mut thor_archive,
+                    patch_info,
+                    patch_size,
+                    config,
+                    install_directory,
+                    pending_renames,
+                    on_progress,
+                )
             }
         }
-        Ok(v) => Ok(v),
+        PatchFormat::Rgz => {
+            let rgz_file = std::fs::File::open(patch_file_path.as_ref())?;
+            apply_rgz_patch(
+                install_directory,
+                std::io::BufReader::new(rgz_file),
+                name_encoding(config),
+                unsafe_path_handling(config),
+                path_matching(config),
+                &skipped_file_patterns(config),
+                on_progress,
+            )
+        }
+        PatchFormat::Gpf => {
+            let target_grf_name = match &patch_info.target_grf {
+                Some(target_grf_name) => target_grf_name.clone(),
+                None if config.client.grf_routes.is_empty() => {
+                    config.client.default_grf_name.clone()
+                }
+                None => {
+                    let entries = list_gpf_files(patch_file_path.as_ref(), name_encoding(config))?;
+                    let entry_paths: Vec<&str> = entries.iter().map(String::as_str).collect();
+                    config
+                        .client
+                        .routed_grf_name(&patch_info.file_name, &entry_paths)
+                        .to_string()
+                }
+            };
+            log::trace!("Target GRF: {:?}", target_grf_name);
+            let target_grf_path = install_directory.as_ref().join(target_grf_name);
+            let res = apply_gpf_patch(
+                pending_renames.redirect(&target_grf_path),
+                patch_file_path,
+                config.patching.create_grf,
+                name_encoding(config),
+                path_matching(config),
+                &skipped_file_patterns(config),
+                on_progress,
+            );
+            with_locked_file_path(res, target_grf_path)
+        }
     }
 }
 
-/// Downloads a single patch described with a `ThorPatchInfo`.
-async fn download_patch_to_file<CB: FnMut(u64, u64)>(
-    client: &reqwest::Client,
-    patch_url: &Url,
-    patch: &ThorPatchInfo,
-    tmp_file: &mut File,
-    mut progress_callback: CB,
-) -> Result<()> {
-    let patch_file_url = patch_url.join(patch.file_name.as_str()).with_context(|| {
-        format!(
-            "Invalid file name '{}' given in patch list file",
-            patch.file_name
-        )
-    })?;
-    let mut resp = client
-        .get(patch_file_url)
-        .send()
-        .await
-        .with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
-    if !resp.status().is_success() {
-        return Err(anyhow!(
-            "Patch file '{}' not found on the remote server",
-            patch.file_name
+/// Applies a THOR patch whose content has been downloaded into memory.
+fn apply_patch_from_memory(
+    content: &[u8],
+    patch_info: &ThorPatchInfo,
+    config: &PatcherConfiguration,
+    install_directory: impl AsRef<Path>,
+    pending_renames: &PendingRenames,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    let mut thor_archive =
+        ThorArchive::new_with_encoding(Cursor::new(content), name_encoding(config))?;
+    apply_thor_archive(
+        &mut thor_archive,
+        patch_info,
+        Some(content.len() as u64),
+        config,
+        install_directory,
+        pending_renames,
+        on_progress,
+    )
+}
+
+/// Returns the encoding used to decode THOR and GRF entries' names. GRFs are
+/// opened with the encoding of the patch applied to them.
+fn name_encoding(config: &PatcherConfiguration) -> NameEncoding {
+    match config.patching.name_encoding {
+        Some(EntryNameEncoding::Auto) => NameEncoding::Auto,
+        Some(EntryNameEncoding::Cp949) => NameEncoding::Cp949,
+        Some(EntryNameEncoding::Utf8) => NameEncoding::Utf8,
+        Some(EntryNameEncoding::Legacy) | None => NameEncoding::Windows1252,
+    }
+}
+
+/// Returns the key used to decrypt encrypted THOR patches, given as a
+/// base64-encoded key or derived from a secret.
+fn encryption_key(config: &PatcherConfiguration) -> Result<Option<ThorEncryptionKey>> {
+    if let Some(encoded_key) = &config.patching.encryption_key {
+        let key = base64::decode(encoded_key.trim()).context("Invalid encryption key")?;
+        return Ok(Some(
+            ThorEncryptionKey::from_slice(&key).context("Invalid encryption key")?,
         ));
     }
-    let bytes_to_download = resp.content_length().unwrap_or(0);
-    let mut downloaded_bytes: u64 = 0;
-    while let Some(chunk) = resp
-        .chunk()
-        .await
-        .with_context(|| format!("Failed to download file '{}'", patch.file_name))?
-    {
-        tmp_file
-            .write_all(&chunk[..])
-            .await
-            .with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
-        downloaded_bytes += chunk.len() as u64;
-        progress_callback(downloaded_bytes, bytes_to_download);
+    Ok(config
+        .patching
+        .encryption_secret
+        .as_ref()
+        .map(|secret| ThorEncryptionKey::from_secret(secret.as_bytes())))
+}
+
+/// Returns how the paths of patches' entries are compared with the paths of
+/// existing files.
+fn path_matching(config: &PatcherConfiguration) -> PathMatching {
+    match config.patching.path_case {
+        Some(PathCase::Insensitive) => PathMatching::CaseInsensitive,
+        Some(PathCase::Sensitive) | None => PathMatching::Exact,
     }
-    tmp_file
-        .sync_all()
-        .await
-        .with_context(|| format!("Failed to sync downloaded file '{}'", patch.file_name,))?;
-    Ok(())
 }
 
-/// Parses and applies a list of patches to GRFs and/or to the game client's
-/// files.
-///
-/// This function is interruptible.
-async fn apply_patches(
-    pending_patch_queue: Vec<PendingPatch>,
-    config: &PatcherConfiguration,
-    cache_file_path: impl AsRef<Path>,
-    ui_controller: &UiController,
-    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
-) -> InterruptibleFnResult<()> {
-    let current_working_dir = env::current_dir().map_err(|e| {
-        InterruptibleFnError::Err(format!(
-            "Failed to resolve current working directory: {}.",
-            e
-        ))
-    })?;
-    let patch_count = pending_patch_queue.len();
-    ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(0, patch_count));
-    for (patch_number, pending_patch) in pending_patch_queue.into_iter().enumerate() {
-        // Cancel the patching process if we've been asked to or if the other
-        // end of the channel has been disconnected
-        process_incoming_commands(patching_thread_rx)?;
+/// Returns what to do with entries that would be extracted outside of the game
+/// directory.
+fn unsafe_path_handling(config: &PatcherConfiguration) -> UnsafePathHandling {
+    match config.patching.path_validation {
+        Some(PathValidation::Lenient) => UnsafePathHandling::Skip,
+        Some(PathValidation::Strict) | None => UnsafePathHandling::Reject,
+    }
+}
 
-        let patch_name = pending_patch.info.file_name;
-        log::info!("Processing {}", patch_name);
-        apply_patch(pending_patch.local_file_path, config, &current_working_dir).map_err(|e| {
-            InterruptibleFnError::Err(format!("Failed to apply patch '{}': {}.", patch_name, e))
-        })?;
-        // Update the cache file with the last successful patch's index
-        if let Err(e) = write_cache_file(
-            &cache_file_path,
-            PatcherCache {
-                last_patch_index: pending_patch.info.index,
-            },
+/// Applies THOR patches stored at `patch_file_paths`, in order, to the GRF
+/// located at `target_grf_path` in a single pass.
+fn apply_patches_to_same_grf(
+    patch_file_paths: &[PathBuf],
+    target_grf_path: impl AsRef<Path>,
+    in_place: bool,
+    config: &PatcherConfiguration,
+    pending_renames: &PendingRenames,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    if config.patching.mmap_archives {
+        let mut thor_archives = patch_file_paths
+            .iter()
+            .map(|path| ThorArchive::open_mmap_with_encoding(path, name_encoding(config)))
+            .collect::<Result<Vec<_>, _>>()?;
+        apply_thor_archives_to_grf(
+            &mut thor_archives,
+            target_grf_path,
+            in_place,
+            config,
+            pending_renames,
+            on_progress,
+        )
+    } else {
+        let mut thor_archives = patch_file_paths
+            .iter()
+            .map(|path| ThorArchive::open_with_encoding(path, name_encoding(config)))
+            .collect::<Result<Vec<_>, _>>()?;
+        apply_thor_archives_to_grf(
+            &mut thor_archives,
+            target_grf_path,
+            in_place,
+            config,
+            pending_renames,
+            on_progress,
         )
-        .await
-        {
-            log::warn!("Failed to write cache file: {}.", e);
-        }
-        // Update status
-        ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(
-            1 + patch_number,
-            patch_count,
-        ));
     }
-    Ok(())
 }
 
-fn apply_patch(
-    thor_archive_path: impl AsRef<Path>,
+fn apply_thor_archive<R: Read + Seek>(
+    thor_archive: &mut ThorArchive<R>,
+    patch_info: &ThorPatchInfo,
+    patch_size: Option<u64>,
     config: &PatcherConfiguration,
-    current_working_dir: impl AsRef<Path>,
-) -> Result<()> {
-    let mut thor_archive = ThorArchive::open(thor_archive_path.as_ref())?;
+    install_directory: impl AsRef<Path>,
+    pending_renames: &PendingRenames,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    prepare_thor_archive(thor_archive, config)?;
     if thor_archive.use_grf_merging() {
         // Patch GRF file
-        let target_grf_name = {
-            if thor_archive.target_grf_name().is_empty() {
-                config.client.default_grf_name.clone()
-            } else {
-                thor_archive.target_grf_name()
-            }
-        };
+        let target_grf_name = target_grf_name(
+            thor_archive,
+            &patch_info.file_name,
+            patch_info.target_grf.as_deref(),
+            &config.client,
+        );
         log::trace!("Target GRF: {:?}", target_grf_name);
-        let grf_patching_method = match config.patching.in_place {
-            true => GrfPatchingMethod::InPlace,
-            false => GrfPatchingMethod::OutOfPlace,
-        };
-        let target_grf_path = current_working_dir.as_ref().join(&target_grf_name);
-        apply_patch_to_grf(
-            grf_patching_method,
-            config.patching.create_grf,
-            target_grf_path,
-            &mut thor_archive,
-        )
+        let target_grf_path = install_directory.as_ref().join(&target_grf_name);
+        // Note: Locked GRFs scheduled for replacement are patched through
+        // their copy
+        let patched_grf_path = pending_renames.redirect(&target_grf_path);
+        let index_cache_file_path = get_grf_index_cache_file_path(&patched_grf_path).ok();
+        let in_place = is_patched_in_place(config, patch_info, patch_size);
+        let skipped_files = skipped_file_patterns(config);
+        let res = apply_patch_to_grf(
+            patched_grf_path,
+            thor_archive,
+            &grf_patching_options(
+                config,
+                in_place,
+                index_cache_file_path.as_deref(),
+                &skipped_files,
+            ),
+            on_progress,
+        );
+        with_locked_file_path(res, target_grf_path)
     } else {
         // Patch root directory
-        apply_patch_to_disk(current_working_dir, &mut thor_archive)
+        apply_patch_to_disk(
+            install_directory,
+            thor_archive,
+            unsafe_path_handling(config),
+            path_matching(config),
+            &skipped_file_patterns(config),
+            on_progress,
+        )
+    }
+}
+
+/// Merges THOR archives, in order, into the GRF located at `target_grf_path`.
+fn apply_thor_archives_to_grf<R: Read + Seek>(
+    thor_archives: &mut [ThorArchive<R>],
+    target_grf_path: impl AsRef<Path>,
+    in_place: bool,
+    config: &PatcherConfiguration,
+    pending_renames: &PendingRenames,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    for thor_archive in thor_archives.iter_mut() {
+        prepare_thor_archive(thor_archive, config)?;
+    }
+    let patched_grf_path = pending_renames.redirect(&target_grf_path);
+    let index_cache_file_path = get_grf_index_cache_file_path(&patched_grf_path).ok();
+    let skipped_files = skipped_file_patterns(config);
+    let res = apply_patches_to_grf(
+        patched_grf_path,
+        thor_archives,
+        &grf_patching_options(
+            config,
+            in_place,
+            index_cache_file_path.as_deref(),
+            &skipped_files,
+        ),
+        on_progress,
+    );
+    with_locked_file_path(res, target_grf_path)
+}
+
+/// Indicates whether a THOR patch of `patch_size` bytes is merged into its GRF
+/// in-place. The patch index takes precedence over `in_place_max_size`, which
+/// takes precedence over `in_place`.
+fn is_patched_in_place(
+    config: &PatcherConfiguration,
+    patch_info: &ThorPatchInfo,
+    patch_size: Option<u64>,
+) -> bool {
+    if let Some(in_place) = patch_info.in_place {
+        return in_place;
+    }
+    match (
+        config.patching.in_place_max_size,
+        patch_size.or(patch_info.size),
+    ) {
+        (Some(max_size_mib), Some(patch_size)) => patch_size <= max_size_mib * 1024 * 1024,
+        _ => config.patching.in_place,
+    }
+}
+
+/// Returns the size of a pending patch's content in bytes, if known.
+fn pending_patch_size(pending_patch: &PendingPatch) -> Option<u64> {
+    match &pending_patch.content {
+        PatchContent::File(local_file_path) => std::fs::metadata(local_file_path)
+            .ok()
+            .map(|metadata| metadata.len()),
+        PatchContent::Memory(content) => Some(content.len() as u64),
+    }
+}
+
+fn grf_patching_options<'a>(
+    config: &'a PatcherConfiguration,
+    in_place: bool,
+    index_cache_file_path: Option<&'a Path>,
+    skipped_files: &'a [String],
+) -> GrfPatchingOptions<'a> {
+    GrfPatchingOptions {
+        method: match (in_place, config.patching.journaled) {
+            (true, false) => GrfPatchingMethod::InPlace,
+            (true, true) => GrfPatchingMethod::Journaled,
+            (false, _) => GrfPatchingMethod::OutOfPlace,
+        },
+        create_if_needed: config.patching.create_grf,
+        memory_mapped: config.patching.mmap_archives,
+        index_cache_file_path,
+        path_matching: path_matching(config),
+        protected_files: skipped_files,
+    }
+}
+
+/// Returns the glob patterns of the files patches must leave untouched: the
+/// protected files and the files excluded from this installation.
+fn skipped_file_patterns(config: &PatcherConfiguration) -> Vec<String> {
+    config
+        .patching
+        .protected_files
+        .iter()
+        .chain(&config.patching.exclude_patterns)
+        .cloned()
+        .collect()
+}
+
+/// Sets the decryption key of a THOR archive and verifies it if needed.
+fn prepare_thor_archive<R: Read + Seek>(
+    thor_archive: &mut ThorArchive<R>,
+    config: &PatcherConfiguration,
+) -> Result<()> {
+    thor_archive.set_encryption_key(encryption_key(config)?);
+    if config.patching.verify_archives {
+        thor_archive.verify().context("Archive is corrupt")?;
+    }
+    Ok(())
+}
+
+/// Returns the name of the GRF a THOR archive is merged into.
+/// `target_grf_override` (given by the patch index) takes precedence over the
+/// GRF targeted by the archive. Archives that don't target a specific GRF are
+/// routed according to `client.grf_routes`.
+fn target_grf_name<R: Read + Seek>(
+    thor_archive: &ThorArchive<R>,
+    patch_name: &str,
+    target_grf_override: Option<&str>,
+    client_config: &ClientConfiguration,
+) -> String {
+    if let Some(target_grf_name) = target_grf_override {
+        target_grf_name.to_string()
+    } else if thor_archive.target_grf_name().is_empty() {
+        let entry_paths: Vec<&str> = thor_archive
+            .get_entries()
+            .filter(|entry| !entry.is_internal())
+            .map(|entry| entry.relative_path.as_str())
+            .collect();
+        client_config
+            .routed_grf_name(patch_name, &entry_paths)
+            .to_string()
+    } else {
+        thor_archive.target_grf_name()
     }
 }
 
@@ -667,18 +4405,33 @@ mod tests {
         let patch_info = ThorPatchInfo {
             index: 0,
             file_name: patch_name.to_string(),
+            ..Default::default()
         };
-        let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
-        download_patch_to_file(
+        let mut part_file = Some(File::from_std(tempfile::tempfile().unwrap()));
+        let content = download_patch_content(
             &reqwest::Client::new(),
-            &from_url,
+            &from_url.join(patch_name).unwrap(),
             &patch_info,
-            &mut tmp_file,
+            Path::new(patch_name),
+            &mut part_file,
+            &DownloadSettings {
+                ensure_integrity: false,
+                retry_config: &RetryConfiguration::default(),
+                bandwidth_limiter: None,
+                read_timeout: Duration::from_secs(10),
+                pause_state: &PauseState::new(),
+                torrent_config: None,
+                failure_policy: FailurePolicy::Abort,
+                download_segments: 1,
+                in_memory_patches: false,
+            },
             |_, _| {},
         )
         .await
         .unwrap();
+        assert!(content.is_none());
 
+        let mut tmp_file = part_file.unwrap();
         tmp_file.seek(SeekFrom::Start(0)).await.unwrap();
         let mut file_content = Vec::with_capacity(data_size);
         tmp_file.read_to_end(&mut file_content).await.unwrap();
@@ -688,4 +4441,625 @@ mod tests {
         // Content check
         assert_eq!(body_content, file_content);
     }
+
+    #[test]
+    fn test_collect_local_patches() {
+        let patch_dir = tempfile::tempdir().unwrap();
+        for file_name in &[
+            "10_b.thor",
+            "2_a.THOR",
+            "extra.thor",
+            "notes.txt",
+            "3_c.rgz",
+            "11_d.GPF",
+        ] {
+            std::fs::write(patch_dir.path().join(file_name), b"").unwrap();
+        }
+        std::fs::create_dir(patch_dir.path().join("dir.thor")).unwrap();
+
+        let local_patches = collect_local_patches(patch_dir.path()).unwrap();
+        let file_names: Vec<&str> = local_patches
+            .iter()
+            .map(|patch| patch.info.file_name.as_str())
+            .collect();
+        assert_eq!(
+            vec!["extra.thor", "2_a.THOR", "3_c.rgz", "10_b.thor", "11_d.GPF"],
+            file_names
+        );
+        assert_eq!(3, local_patches[3].info.index);
+        assert!(matches!(
+            &local_patches[3].content,
+            PatchContent::File(path) if *path == patch_dir.path().join("10_b.thor")
+        ));
+
+        // A single patch file can be given as well
+        let local_patches = collect_local_patches(patch_dir.path().join("3_c.rgz")).unwrap();
+        assert_eq!(1, local_patches.len());
+        assert_eq!("3_c.rgz", local_patches[0].info.file_name);
+        assert!(collect_local_patches(patch_dir.path().join("notes.txt")).is_err());
+    }
+
+    #[test]
+    fn test_batch_pending_patches() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let working_dir = tempfile::tempdir().unwrap();
+        let pending_patch = |file_name: &str, target_grf: Option<&str>| PendingPatch {
+            info: ThorPatchInfo {
+                file_name: file_name.to_string(),
+                target_grf: target_grf.map(str::to_string),
+                ..Default::default()
+            },
+            content: PatchContent::File(thor_dir_path.join(file_name)),
+        };
+        let pending_patches = vec![
+            // small.thor targets "data.grf", tiny.thor the default GRF
+            pending_patch("small.thor", None),
+            pending_patch("tiny.thor", None),
+            pending_patch("small.thor", None),
+            pending_patch("dir1.thor", None),
+            pending_patch("small.thor", None),
+            pending_patch("tiny.thor", Some("rdata.grf")),
+            PendingPatch {
+                info: ThorPatchInfo::default(),
+                content: PatchContent::Memory(vec![]),
+            },
+        ];
+
+        let client_config: ClientConfiguration =
+            serde_yaml::from_str("default_grf_name: data.grf").unwrap();
+
+        let patch_batches = batch_pending_patches(
+            pending_patches,
+            NameEncoding::Windows1252,
+            &client_config,
+            working_dir.path(),
+        );
+
+        let batch_sizes: Vec<usize> = patch_batches
+            .iter()
+            .map(|patch_batch| patch_batch.patches.len())
+            .collect();
+        assert_eq!(vec![3, 1, 1, 1, 1], batch_sizes);
+        assert_eq!(
+            Some(working_dir.path().join("data.grf")),
+            patch_batches[0].target_grf_path
+        );
+        // Lone patches aren't batched
+        assert!(patch_batches[1..]
+            .iter()
+            .all(|patch_batch| patch_batch.target_grf_path.is_none()));
+    }
+
+    #[test]
+    fn test_parse_patch_index() {
+        let plist_url = Url::parse("https://example.com/plist.txt").unwrap();
+        let json_url = Url::parse("https://example.com/patches.json").unwrap();
+        let patch_list = parse_patch_index(&plist_url, "1 patch1.thor").unwrap();
+        assert_eq!("patch1.thor", patch_list[0].file_name);
+        // JSON content is detected regardless of the extension
+        let json_content = r#"[{"index": 1, "file_name": "patch1.thor", "hash": "ab"}]"#;
+        let patch_list = parse_patch_index(&plist_url, json_content).unwrap();
+        assert_eq!(Some("ab"), patch_list[0].hash.as_deref());
+        assert!(parse_patch_index(&json_url, "1 patch1.thor").is_err());
+    }
+
+    #[test]
+    fn test_is_hash_valid() {
+        let patch_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(patch_file.path(), b"abc").unwrap();
+        let mut patch_info = ThorPatchInfo::default();
+        assert!(is_hash_valid(patch_file.path(), &patch_info).unwrap());
+        patch_info.hash =
+            Some("BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_string());
+        assert!(is_hash_valid(patch_file.path(), &patch_info).unwrap());
+        patch_info.hash = Some("00".repeat(32));
+        assert!(!is_hash_valid(patch_file.path(), &patch_info).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_download_patch_segmented() {
+        let body_content: Vec<u8> = (0..1000).map(|x| x as u8).collect();
+        let server = Server::run();
+        // Expect 3 segments
+        for (start, end) in &[(0, 332), (333, 665), (666, 999)] {
+            server.expect(
+                Expectation::matching(all_of![
+                    request::method_path("GET", "/patch.thor"),
+                    request::headers(contains(("range", format!("bytes={}-{}", start, end)))),
+                ])
+                .respond_with(
+                    status_code(206).body(body_content[*start as usize..=*end as usize].to_vec()),
+                ),
+            );
+        }
+
+        let file_url = Url::parse(&server.url("/patch.thor").to_string()).unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let part_file_path = tmp_dir.path().join("patch.thor.part");
+        let mut last_progress = (0, 0);
+        download_patch_segmented(
+            &reqwest::Client::new(),
+            &file_url,
+            &part_file_path,
+            body_content.len() as u64,
+            &DownloadSettings {
+                ensure_integrity: false,
+                retry_config: &RetryConfiguration::default(),
+                bandwidth_limiter: None,
+                read_timeout: Duration::from_secs(10),
+                pause_state: &PauseState::new(),
+                torrent_config: None,
+                failure_policy: FailurePolicy::Abort,
+                download_segments: 3,
+                in_memory_patches: false,
+            },
+            &|_, _| {},
+            |downloaded, total| last_progress = (downloaded, total),
+        )
+        .await
+        .unwrap();
+        assert_eq!(body_content, std::fs::read(&part_file_path).unwrap());
+        assert_eq!((1000, 1000), last_progress);
+    }
+
+    #[tokio::test]
+    async fn test_download_truncated_segment() {
+        let server = Server::run();
+        // Segments cut short are resumed from where they ended, until no
+        // attempts are left
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/patch.thor"),
+                request::headers(contains(("range", "bytes=0-999"))),
+            ])
+            .respond_with(status_code(206).body(vec![0_u8; 400])),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/patch.thor"),
+                request::headers(contains(("range", "bytes=400-999"))),
+            ])
+            .times(2)
+            .respond_with(status_code(206).body(vec![])),
+        );
+        let file_url = Url::parse(&server.url_str("/patch.thor")).unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let part_file_path = tmp_dir.path().join("patch.thor.part");
+        let retries = RefCell::new(vec![]);
+        let err = download_patch_segmented(
+            &reqwest::Client::new(),
+            &file_url,
+            &part_file_path,
+            1000,
+            &DownloadSettings {
+                ensure_integrity: false,
+                retry_config: &RetryConfiguration {
+                    max_attempts: 3,
+                    initial_delay_ms: 0,
+                    max_delay_ms: 0,
+                    jitter: false,
+                },
+                bandwidth_limiter: None,
+                read_timeout: Duration::from_secs(10),
+                pause_state: &PauseState::new(),
+                torrent_config: None,
+                failure_policy: FailurePolicy::Abort,
+                download_segments: 1,
+                in_memory_patches: false,
+            },
+            &|attempt, max_attempts| retries.borrow_mut().push((attempt, max_attempts)),
+            |_, _| {},
+        )
+        .await
+        .unwrap_err();
+        let truncated_body = err.downcast_ref::<TruncatedBody>().unwrap();
+        assert_eq!((400, 1000), (truncated_body.end, truncated_body.expected));
+        assert_eq!(vec![(2, 3), (3, 3)], retries.into_inner());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_patch_headers() {
+        let thor_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor/small.thor");
+        let content = std::fs::read(thor_file_path).unwrap();
+        let table_range = thor::parse_thor_file_table_range(&content)
+            .unwrap()
+            .unwrap();
+        let server = Server::run();
+        // Expect the header and the file table to be fetched
+        for range in &[
+            0..thor::reader::HEADER_EXTENDED_MAX_SIZE as u64,
+            table_range,
+        ] {
+            let body_end = (range.end as usize).min(content.len());
+            server.expect(
+                Expectation::matching(all_of![
+                    request::method_path("GET", "/small.thor"),
+                    request::headers(contains((
+                        "range",
+                        format!("bytes={}-{}", range.start, range.end - 1)
+                    ))),
+                ])
+                .respond_with(
+                    status_code(206).body(content[range.start as usize..body_end].to_vec()),
+                ),
+            );
+        }
+
+        let file_url = Url::parse(&server.url("/small.thor").to_string()).unwrap();
+        let partial_patch =
+            fetch_patch_headers(&reqwest::Client::new(), &file_url, Duration::from_secs(10))
+                .await
+                .unwrap();
+
+        let partial_archive = ThorArchive::new(partial_patch).unwrap();
+        let archive = ThorArchive::new(Cursor::new(content)).unwrap();
+        assert_eq!(partial_archive.file_count(), archive.file_count());
+        assert!(archive
+            .get_entries()
+            .all(|e| partial_archive.get_file_entry(&e.relative_path).is_some()));
+    }
+
+    #[test]
+    fn test_resolve_update_channel() {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = std::fs::File::open(config_file_path).unwrap();
+        let mut config: PatcherConfiguration = serde_yaml::from_reader(config_file).unwrap();
+        config.channels.insert(
+            "beta".to_string(),
+            serde_yaml::from_str(
+                "plist_url: https://beta.myserver.com/plist.txt\n\
+                 patch_url: https://beta.myserver.com/data/\n",
+            )
+            .unwrap(),
+        );
+
+        let default_channel = resolve_update_channel(&config, None).unwrap();
+        assert_eq!(2, default_channel.patch_servers.len());
+        let beta_channel = resolve_update_channel(&config, Some("beta")).unwrap();
+        assert_eq!(1, beta_channel.patch_servers.len());
+        assert_eq!("beta", beta_channel.patch_servers[0].name);
+        assert_eq!(
+            "https://beta.myserver.com/data/",
+            beta_channel.patch_servers[0].patch_url
+        );
+        // Channels don't share their cache
+        assert_ne!(
+            default_channel.cache_file_path,
+            beta_channel.cache_file_path
+        );
+        assert!(beta_channel
+            .cache_file_path
+            .to_string_lossy()
+            .ends_with(".beta.dat"));
+        assert!(resolve_update_channel(&config, Some("alpha")).is_err());
+    }
+
+    #[test]
+    fn test_resolve_profile_update_channel() {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = std::fs::File::open(config_file_path).unwrap();
+        let mut config: PatcherConfiguration = serde_yaml::from_reader(config_file).unwrap();
+        config.profiles.insert(
+            "classic".to_string(),
+            serde_yaml::from_str(
+                "play_path: ragexe_classic.exe
+\
+                 default_grf_name: classic.grf
+\
+                 plist_url: https://classic.myserver.com/plist.txt
+\
+                 patch_url: https://classic.myserver.com/data/
+",
+            )
+            .unwrap(),
+        );
+
+        let classic_config = config.with_profile(Some("classic")).unwrap();
+        assert_eq!("ragexe_classic.exe", classic_config.play.path);
+        assert_eq!("classic.grf", classic_config.client.default_grf_name);
+        assert_eq!(config.play.arguments, classic_config.play.arguments);
+        let default_channel = resolve_update_channel(&config, None).unwrap();
+        let classic_channel = resolve_update_channel(&classic_config, None).unwrap();
+        assert_eq!(1, classic_channel.patch_servers.len());
+        assert_eq!(
+            "https://classic.myserver.com/plist.txt",
+            classic_channel.patch_servers[0].plist_url
+        );
+        // Profiles don't share their cache
+        assert_ne!(
+            default_channel.cache_file_path,
+            classic_channel.cache_file_path
+        );
+        assert!(classic_channel
+            .cache_file_path
+            .to_string_lossy()
+            .ends_with(".profile-classic.dat"));
+        assert!(config.with_profile(Some("renewal")).is_err());
+    }
+
+    #[test]
+    fn test_check_patch_prerequisites() {
+        let patch_info = |index, requires_index, min_patcher_version: Option<&str>| ThorPatchInfo {
+            index,
+            file_name: format!("patch{}.thor", index),
+            requires_index,
+            min_patcher_version: min_patcher_version.map(str::to_string),
+            ..Default::default()
+        };
+        let patch_list = vec![
+            patch_info(3, None, None),
+            patch_info(4, Some(3), Some("0.3")),
+        ];
+        assert!(check_patch_prerequisites(&patch_list, None, "0.3.0").is_ok());
+        assert!(check_patch_prerequisites(&patch_list, Some(2), "0.10.1").is_ok());
+        assert!(check_patch_prerequisites(&patch_list, Some(2), "0.2.9").is_err());
+        // Required patches must be installed or applied first
+        assert!(check_patch_prerequisites(&patch_list[1..], Some(3), "0.3.0").is_ok());
+        assert!(check_patch_prerequisites(&patch_list[1..], Some(2), "0.3.0").is_err());
+        assert!(check_patch_prerequisites(&patch_list[1..], None, "0.3.0").is_err());
+    }
+
+    #[test]
+    fn test_is_download_reusable() {
+        let download_dir = tempfile::tempdir().unwrap();
+        let local_file_path = download_dir.path().join("small.thor");
+        std::fs::copy(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor/small.thor"),
+            &local_file_path,
+        )
+        .unwrap();
+        let file_size = std::fs::metadata(&local_file_path).unwrap().len();
+        let patch_file_url = Url::parse("https://myserver.com/data/small.thor").unwrap();
+        let patch_info = ThorPatchInfo {
+            index: 1,
+            file_name: "small.thor".to_string(),
+            ..Default::default()
+        };
+        // Patches without a hash must come from the same URL
+        assert!(!is_download_reusable(
+            &local_file_path,
+            &patch_info,
+            &patch_file_url
+        ));
+        std::fs::write(
+            download_source_file_path(&local_file_path),
+            "https://other.myserver.com/data/small.thor",
+        )
+        .unwrap();
+        assert!(!is_download_reusable(
+            &local_file_path,
+            &patch_info,
+            &patch_file_url
+        ));
+        std::fs::write(
+            download_source_file_path(&local_file_path),
+            patch_file_url.as_str(),
+        )
+        .unwrap();
+        assert!(is_download_reusable(
+            &local_file_path,
+            &patch_info,
+            &patch_file_url
+        ));
+        // Sizes must match when known
+        let sized_patch_info = ThorPatchInfo {
+            size: Some(file_size + 1),
+            ..patch_info.clone()
+        };
+        assert!(!is_download_reusable(
+            &local_file_path,
+            &sized_patch_info,
+            &patch_file_url
+        ));
+        let sized_patch_info = ThorPatchInfo {
+            size: Some(file_size),
+            ..patch_info
+        };
+        assert!(is_download_reusable(
+            &local_file_path,
+            &sized_patch_info,
+            &patch_file_url
+        ));
+    }
+
+    #[test]
+    fn test_patch_file_url() {
+        let patch_url = Url::parse("https://myserver.com/data/").unwrap();
+        assert_eq!(
+            patch_file_url(&patch_url, "2021-05-01.thor")
+                .unwrap()
+                .as_str(),
+            "https://myserver.com/data/2021-05-01.thor"
+        );
+        // Names must not point outside of the patch directory
+        for hostile_name in &["https://other.host/x", "../x", "/x", "//other.host/x"] {
+            assert!(patch_file_url(&patch_url, hostile_name).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_patch_file_names() {
+        let download_dir = Path::new("downloads");
+        assert_eq!(
+            download_file_path(download_dir, "2021-05-01.thor").unwrap(),
+            download_dir.join("2021-05-01.thor")
+        );
+        let patch_list = thor::patch_list_from_string("1 2021-05-01.thor\n2 ..\\data.grf\n");
+        assert!(check_patch_file_names(&patch_list[..1]).is_ok());
+        assert!(check_patch_file_names(&patch_list).is_err());
+        for hostile_name in &[
+            "../data.grf",
+            "../../x.exe",
+            "..",
+            "/tmp/file.thor",
+            "\\\\server\\share\\file.thor",
+            "C:file.thor",
+            "C:\\Windows\\file.thor",
+            "",
+        ] {
+            assert!(download_file_path(download_dir, hostile_name).is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_patch_server_url() {
+        let patch_servers = vec![PatchServerInfo {
+            name: "primary".to_string(),
+            plist_url: "https://example.com/plist.txt".to_string(),
+            patch_url: "https://example.com/patch".to_string(),
+        }];
+        let is_patch_server_url =
+            |url: &str| is_patch_server_url(&patch_servers, &Url::parse(url).unwrap());
+        assert!(is_patch_server_url("https://example.com/patch/event.thor"));
+        assert!(is_patch_server_url(
+            "https://example.com/patch/2021/event.thor"
+        ));
+        assert!(!is_patch_server_url(
+            "https://example.com/patches/event.thor"
+        ));
+        assert!(!is_patch_server_url(
+            "https://example.com/patch/../event.thor"
+        ));
+        assert!(!is_patch_server_url("http://example.com/patch/event.thor"));
+        assert!(!is_patch_server_url(
+            "https://example.com.evil.net/patch/event.thor"
+        ));
+    }
+
+    #[test]
+    fn test_is_older_version() {
+        assert!(is_older_version("0.3.0", "0.10.0"));
+        assert!(is_older_version("0.3.0-beta", "0.3.1"));
+        assert!(!is_older_version("0.3.0", "0.3"));
+        assert!(!is_older_version("v1.0.0", "0.9.9"));
+    }
+
+    #[test]
+    fn test_is_patched_in_place() {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = std::fs::File::open(config_file_path).unwrap();
+        let mut config: PatcherConfiguration = serde_yaml::from_reader(config_file).unwrap();
+        let mut patch_info = ThorPatchInfo::default();
+        assert!(is_patched_in_place(&config, &patch_info, Some(1 << 30)));
+
+        config.patching.in_place_max_size = Some(1);
+        assert!(is_patched_in_place(&config, &patch_info, Some(1024)));
+        assert!(!is_patched_in_place(&config, &patch_info, Some(1 << 30)));
+        // Patches of unknown size fall back to `in_place`
+        assert!(is_patched_in_place(&config, &patch_info, None));
+        // The patch index takes precedence
+        patch_info.in_place = Some(true);
+        assert!(is_patched_in_place(&config, &patch_info, Some(1 << 30)));
+    }
+
+    #[tokio::test]
+    async fn test_download_patch_to_memory() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        // Disk-merge patch
+        let dir1_content = std::fs::read(thor_dir_path.join("dir1.thor")).unwrap();
+        // GRF-merge patch
+        let small_content = std::fs::read(thor_dir_path.join("small.thor")).unwrap();
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/dir1.thor"))
+                .respond_with(status_code(200).body(dir1_content.clone())),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/small.thor"))
+                .respond_with(status_code(200).body(small_content.clone())),
+        );
+
+        let settings = DownloadSettings {
+            ensure_integrity: false,
+            retry_config: &RetryConfiguration::default(),
+            bandwidth_limiter: None,
+            read_timeout: Duration::from_secs(10),
+            pause_state: &PauseState::new(),
+            torrent_config: None,
+            failure_policy: FailurePolicy::Abort,
+            download_segments: 1,
+            in_memory_patches: true,
+        };
+        let client = reqwest::Client::new();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let download = |file_name: &str| {
+            let patch_info = ThorPatchInfo {
+                file_name: file_name.to_string(),
+                ..Default::default()
+            };
+            let file_url = Url::parse(&server.url(&format!("/{}", file_name)).to_string()).unwrap();
+            let part_file_path = tmp_dir.path().join(format!("{}.part", file_name));
+            let settings = &settings;
+            let client = &client;
+            async move {
+                download_patch_content(
+                    client,
+                    &file_url,
+                    &patch_info,
+                    &part_file_path,
+                    &mut None,
+                    settings,
+                    |_, _| {},
+                )
+                .await
+                .unwrap()
+            }
+        };
+        assert_eq!(Some(dir1_content), download("dir1.thor").await);
+        assert!(!tmp_dir.path().join("dir1.thor.part").exists());
+        // GRF-merge patches are written to a file, without being requested again
+        assert_eq!(None, download("small.thor").await);
+        assert_eq!(
+            small_content,
+            std::fs::read(tmp_dir.path().join("small.thor.part")).unwrap()
+        );
+    }
+
+    /// Frontend that records the errors dispatched by the patching task
+    #[derive(Clone, Default)]
+    struct ErrorRecorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl crate::ui::UiFrontend for ErrorRecorder {
+        fn dispatch_patching_status(&self, status: PatchingStatus) {
+            if let PatchingStatus::Error(msg) = status {
+                self.0.lock().unwrap().push(msg);
+            }
+        }
+
+        fn prompt_skip_patch(&self, _patch_name: &str, _error_msg: &str) -> bool {
+            false
+        }
+
+        fn warn_running_client(&self, _executable_name: &str) {}
+
+        fn prompt_running_client(&self, _executable_name: &str) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn test_patcher_thread_routine_keeps_running_after_errors() {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = std::fs::File::open(config_file_path).unwrap();
+        let mut config: PatcherConfiguration = serde_yaml::from_reader(config_file).unwrap();
+        // The HTTP client cannot be built with an invalid header
+        config
+            .web
+            .headers
+            .insert("invalid name".to_string(), "value".to_string());
+        let error_recorder = ErrorRecorder::default();
+        let (tx, rx) = flume::unbounded();
+        tx.send(PatcherCommand::StartUpdate).unwrap();
+        tx.send(PatcherCommand::StartUpdate).unwrap();
+        tx.send(PatcherCommand::Quit).unwrap();
+
+        patcher_thread_routine(UiController::new(error_recorder.clone()), config, rx).await;
+        // Reported once on start and once for each command
+        assert_eq!(error_recorder.0.lock().unwrap().len(), 3);
+    }
 }
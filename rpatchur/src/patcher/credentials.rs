@@ -0,0 +1,425 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::config::{CredentialPolicy, PlayConfiguration};
+use super::get_instance_data_file_path;
+
+/// Arguments passed to the game client on login when `play.login_arguments`
+/// isn't set
+const DEFAULT_LOGIN_ARGUMENTS: [&str; 3] = ["-t:{password}", "{login}", "server"];
+
+/// Credentials entered by the user to log in.
+pub struct Credentials {
+    pub login: String,
+    pub password: String,
+    pub token: Option<String>, // One-time token issued by the server, never stored
+}
+
+/// Credentials remembered from the last login.
+#[derive(Debug, PartialEq)]
+pub struct StoredCredentials {
+    pub login: String,
+    pub password: Option<String>,
+}
+
+/// What the UI is told about the stored credentials, to fill in its login
+/// form. The password itself never leaves the patcher.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SavedCredentials {
+    pub login: String,
+    pub has_saved_password: bool,
+}
+
+/// Layout of the credentials file
+#[derive(Serialize, Deserialize)]
+struct CredentialsFile {
+    login: String,
+    password: Option<String>, // Protected with `protect_password`
+}
+
+impl StoredCredentials {
+    /// Reads the credentials saved in `file_path`, if any.
+    ///
+    /// A password that can't be recovered (e.g. one saved by another user) is
+    /// dropped.
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let credentials_file: CredentialsFile = match File::open(file_path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .context("Failed to deserialize stored credentials")?,
+        };
+        let password = credentials_file.password.and_then(|protected_password| {
+            unprotect_password(&protected_password)
+                .map_err(|e| log::warn!("Failed to recover the stored password: {:#}", e))
+                .ok()
+        });
+        Ok(Some(Self {
+            login: credentials_file.login,
+            password,
+        }))
+    }
+
+    /// Saves the credentials in `file_path`, readable by the current user
+    /// only.
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        let credentials_file = CredentialsFile {
+            login: self.login.clone(),
+            password: self.password.as_deref().map(protect_password).transpose()?,
+        };
+        let file = create_private_file(file_path)?;
+        serde_json::to_writer(file, &credentials_file)
+            .context("Failed to serialize stored credentials")
+    }
+
+    /// Returns the stored password if it was saved for `login`.
+    pub fn password_for(&self, login: &str) -> Option<&str> {
+        if self.login == login {
+            self.password.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Returns what can be shown to the UI about these credentials.
+    pub fn to_saved_credentials(&self) -> SavedCredentials {
+        SavedCredentials {
+            login: self.login.clone(),
+            has_saved_password: self.password.is_some(),
+        }
+    }
+}
+
+/// Creates (or truncates) the file at `file_path` with owner-only
+/// permissions.
+#[cfg(unix)]
+fn create_private_file(file_path: impl AsRef<Path>) -> Result<File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(file_path)?;
+    // `mode` only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+/// Creates (or truncates) the file at `file_path`.
+///
+/// This is the non-Unix version, the file inherits the permissions of the
+/// (per-user) data directory.
+#[cfg(not(unix))]
+fn create_private_file(file_path: impl AsRef<Path>) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file_path)?;
+    Ok(file)
+}
+
+/// Encrypts `password` for the current user with DPAPI.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn protect_password(password: &str) -> Result<String> {
+    let protected_password =
+        dpapi::protect(password.as_bytes()).context("Failed to protect the password")?;
+    Ok(base64::encode(protected_password))
+}
+
+/// Decrypts a password protected with `protect_password`.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+fn unprotect_password(protected_password: &str) -> Result<String> {
+    let protected_password = base64::decode(protected_password)?;
+    let password = dpapi::unprotect(&protected_password)?;
+    String::from_utf8(password).context("Invalid password")
+}
+
+/// Returns `password` as is.
+///
+/// This is the non-Windows version, the password is only protected by the
+/// permissions of the credentials file.
+#[cfg(not(windows))]
+fn protect_password(password: &str) -> Result<String> {
+    Ok(password.to_string())
+}
+
+/// Returns `protected_password` as is.
+///
+/// This is the non-Windows version, see `protect_password`.
+#[cfg(not(windows))]
+fn unprotect_password(protected_password: &str) -> Result<String> {
+    Ok(protected_password.to_string())
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use std::io;
+    use std::ptr;
+    use std::slice;
+
+    use anyhow::Result;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::dpapi::{CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN};
+    use winapi::um::winbase::LocalFree;
+    use winapi::um::wincrypt::DATA_BLOB;
+
+    pub fn protect(data: &[u8]) -> Result<Vec<u8>> {
+        transform(data, true)
+    }
+
+    pub fn unprotect(data: &[u8]) -> Result<Vec<u8>> {
+        transform(data, false)
+    }
+
+    fn transform(data: &[u8], protect: bool) -> Result<Vec<u8>> {
+        let mut input = DATA_BLOB {
+            cbData: data.len() as DWORD,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let mut output = DATA_BLOB {
+            cbData: 0,
+            pbData: ptr::null_mut(),
+        };
+        let succeeded = unsafe {
+            if protect {
+                CryptProtectData(
+                    &mut input,
+                    ptr::null(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    CRYPTPROTECT_UI_FORBIDDEN,
+                    &mut output,
+                )
+            } else {
+                CryptUnprotectData(
+                    &mut input,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    CRYPTPROTECT_UI_FORBIDDEN,
+                    &mut output,
+                )
+            }
+        };
+        if succeeded == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let result =
+            unsafe { slice::from_raw_parts(output.pbData, output.cbData as usize) }.to_vec();
+        unsafe { LocalFree(output.pbData as _) };
+        Ok(result)
+    }
+}
+
+/// Returns the command-line arguments to start the game client with to log
+/// in with `credentials`.
+pub fn build_login_arguments(
+    play_config: &PlayConfiguration,
+    credentials: &Credentials,
+) -> Vec<String> {
+    let expand = |template: &str| expand_credentials(template, credentials);
+    let login_arguments: Vec<String> = match &play_config.login_arguments {
+        Some(templates) => templates.iter().map(|template| expand(template)).collect(),
+        None => DEFAULT_LOGIN_ARGUMENTS
+            .iter()
+            .map(|template| expand(template))
+            .collect(),
+    };
+    login_arguments
+        .into_iter()
+        .chain(play_config.arguments.iter().cloned())
+        .collect()
+}
+
+/// Replaces '{login}', '{password}' and '{token}' in `template` with the
+/// user's credentials. The template is expanded in a single pass, so that
+/// credentials containing these references aren't expanded in turn.
+fn expand_credentials(template: &str, credentials: &Credentials) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        let value = reference.find('}').and_then(|end| {
+            let value = match &reference[1..end] {
+                "login" => credentials.login.as_str(),
+                "password" => credentials.password.as_str(),
+                "token" => credentials.token.as_deref().unwrap_or_default(),
+                _ => return None,
+            };
+            Some((value, end + 1))
+        });
+        match value {
+            Some((value, reference_len)) => {
+                expanded.push_str(value);
+                rest = &reference[reference_len..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &reference[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Saves what `play.remember_credentials` allows of `credentials` in
+/// `file_path`, or forgets the stored credentials if `remember` is false.
+pub fn store_credentials(
+    file_path: impl AsRef<Path>,
+    play_config: &PlayConfiguration,
+    credentials: &Credentials,
+    remember: bool,
+) -> Result<()> {
+    let policy = match play_config.remember_credentials {
+        Some(policy) if remember => policy,
+        _ => CredentialPolicy::Nothing,
+    };
+    let stored_credentials = match policy {
+        CredentialPolicy::Nothing => None,
+        CredentialPolicy::Login => Some(StoredCredentials {
+            login: credentials.login.clone(),
+            password: None,
+        }),
+        CredentialPolicy::All => Some(StoredCredentials {
+            login: credentials.login.clone(),
+            password: Some(credentials.password.clone()),
+        }),
+    };
+    match stored_credentials {
+        Some(stored_credentials) => stored_credentials.save(file_path),
+        None => match fs::remove_file(file_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Returns the path of the file the credentials of the last login are stored
+/// in (e.g. 'rpatchur.credentials' in the data directory).
+pub fn get_credentials_file_path() -> Result<PathBuf> {
+    get_instance_data_file_path("credentials")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn play_config(yaml: &str) -> PlayConfiguration {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_build_login_arguments() {
+        let credentials = Credentials {
+            login: "user".to_string(),
+            password: "secret".to_string(),
+            token: Some("0123".to_string()),
+        };
+        let default_config = play_config("path: ragexe.exe\narguments: [\"1sak1\"]\n");
+        assert_eq!(
+            build_login_arguments(&default_config, &credentials),
+            vec!["-t:secret", "user", "server", "1sak1"]
+        );
+        let token_config = play_config(
+            "path: ragexe.exe\narguments: []\nlogin_arguments: [\"-u:{login}\", \"-t:{token}\"]\n",
+        );
+        assert_eq!(
+            build_login_arguments(&token_config, &credentials),
+            vec!["-u:user", "-t:0123"]
+        );
+        // Credentials are inserted as is, references they contain aren't
+        // expanded
+        let hostile_credentials = Credentials {
+            login: "{password}{token}".to_string(),
+            password: "{login}".to_string(),
+            token: Some("{password}".to_string()),
+        };
+        assert_eq!(
+            build_login_arguments(&token_config, &hostile_credentials),
+            vec!["-u:{password}{token}", "-t:{password}"]
+        );
+        assert_eq!(
+            expand_credentials("{unknown} {login", &credentials),
+            "{unknown} {login"
+        );
+    }
+
+    #[test]
+    fn test_store_credentials() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("rpatchur.credentials");
+        let credentials = Credentials {
+            login: "user".to_string(),
+            password: "secret".to_string(),
+            token: None,
+        };
+        let login_config =
+            play_config("path: ragexe.exe\narguments: []\nremember_credentials: login\n");
+        store_credentials(&file_path, &login_config, &credentials, true).unwrap();
+        assert_eq!(
+            StoredCredentials::load(&file_path).unwrap(),
+            Some(StoredCredentials {
+                login: "user".to_string(),
+                password: None
+            })
+        );
+        let all_config =
+            play_config("path: ragexe.exe\narguments: []\nremember_credentials: all\n");
+        store_credentials(&file_path, &all_config, &credentials, true).unwrap();
+        assert_eq!(
+            StoredCredentials::load(&file_path)
+                .unwrap()
+                .unwrap()
+                .password,
+            Some("secret".to_string())
+        );
+        // Unchecking 'remember me' forgets the stored credentials
+        store_credentials(&file_path, &all_config, &credentials, false).unwrap();
+        assert_eq!(StoredCredentials::load(&file_path).unwrap(), None);
+        let default_config = play_config("path: ragexe.exe\narguments: []\n");
+        store_credentials(&file_path, &default_config, &credentials, true).unwrap();
+        assert_eq!(StoredCredentials::load(&file_path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_saved_credentials() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("rpatchur.credentials");
+        let stored_credentials = StoredCredentials {
+            login: "user".to_string(),
+            password: Some("secret".to_string()),
+        };
+        stored_credentials.save(&file_path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&file_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let stored_credentials = StoredCredentials::load(&file_path).unwrap().unwrap();
+        assert_eq!(stored_credentials.password_for("user"), Some("secret"));
+        assert_eq!(stored_credentials.password_for("other"), None);
+        // The UI only learns that a password is saved
+        let saved_credentials = serde_json::to_value(stored_credentials.to_saved_credentials());
+        assert_eq!(
+            saved_credentials.unwrap(),
+            serde_json::json!({"login": "user", "has_saved_password": true})
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+
+use super::get_patcher_name;
+
+// Directory of the patcher's data, under the user's local data directory
+const DATA_DIRECTORY_NAME: &str = "rpatchur";
+
+static DATA_DIRECTORY: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Selects where the patcher stores its cache, journals and logs: under the
+/// user's local data directory ('<data>/rpatchur/<patcher name>/') if
+/// `use_data_directory` is set and that directory can be created, in the
+/// working directory otherwise.
+///
+/// Returns the selected data directory, `None` for the working directory.
+pub fn init_data_directory(use_data_directory: bool) -> Option<PathBuf> {
+    let data_directory = if use_data_directory {
+        match create_data_directory() {
+            Err(e) => {
+                log::warn!("Failed to create the data directory: {:#}", e);
+                None
+            }
+            Ok(data_directory) => {
+                log::info!("Using data directory '{}'", data_directory.display());
+                Some(data_directory)
+            }
+        }
+    } else {
+        None
+    };
+    if let Ok(mut current_data_directory) = DATA_DIRECTORY.write() {
+        current_data_directory.clone_from(&data_directory);
+    }
+    data_directory
+}
+
+fn create_data_directory() -> Result<PathBuf> {
+    let local_data_directory = local_data_directory()
+        .ok_or_else(|| anyhow!("Failed to find the user's data directory"))?;
+    let data_directory = local_data_directory
+        .join(DATA_DIRECTORY_NAME)
+        .join(get_patcher_name()?);
+    fs::create_dir_all(&data_directory)?;
+    Ok(data_directory)
+}
+
+/// Returns the path of a data file associated with the current 'instance' of
+/// the patcher (e.g. 'rpatchur.dat' for the "dat" extension).
+///
+/// Files left in the working directory by previous versions are moved to the
+/// data directory the first time they're needed.
+pub fn get_instance_data_file_path(extension: impl AsRef<OsStr>) -> Result<PathBuf> {
+    let legacy_file_path = PathBuf::from(get_patcher_name()?).with_extension(extension);
+    let data_directory = DATA_DIRECTORY
+        .read()
+        .ok()
+        .and_then(|data_directory| data_directory.clone());
+    Ok(resolve_data_file_path(
+        data_directory.as_deref(),
+        legacy_file_path,
+    ))
+}
+
+/// Returns the location of the file at `legacy_file_path` in `data_directory`
+/// and moves the file there if it hasn't been yet. The file is left in place
+/// if it can't be moved.
+fn resolve_data_file_path(data_directory: Option<&Path>, legacy_file_path: PathBuf) -> PathBuf {
+    let data_directory = match data_directory {
+        Some(data_directory) => data_directory,
+        None => return legacy_file_path,
+    };
+    let file_name = match legacy_file_path.file_name() {
+        Some(file_name) => file_name,
+        None => return legacy_file_path,
+    };
+    let file_path = data_directory.join(file_name);
+    if file_path.exists() || !legacy_file_path.is_file() {
+        return file_path;
+    }
+    match move_file(&legacy_file_path, &file_path) {
+        Err(e) => {
+            log::warn!(
+                "Failed to move '{}' to the data directory: {}",
+                legacy_file_path.display(),
+                e
+            );
+            legacy_file_path
+        }
+        Ok(()) => {
+            log::info!(
+                "Moved '{}' to the data directory",
+                legacy_file_path.display()
+            );
+            file_path
+        }
+    }
+}
+
+/// Moves a file, copying it when it can't simply be renamed (e.g. when the
+/// data directory is on another volume).
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Returns the user's local data directory, like `dirs::data_local_dir`:
+/// '%LOCALAPPDATA%' on Windows, '~/Library/Application Support' on macOS and
+/// '$XDG_DATA_HOME' (or '~/.local/share') elsewhere.
+fn local_data_directory() -> Option<PathBuf> {
+    let absolute_path = |path: PathBuf| Some(path).filter(|path| path.is_absolute());
+    if cfg!(windows) {
+        env::var_os("LOCALAPPDATA")
+            .map(PathBuf::from)
+            .and_then(absolute_path)
+    } else {
+        let home_directory = env::var_os("HOME")
+            .map(PathBuf::from)
+            .and_then(absolute_path);
+        if cfg!(target_os = "macos") {
+            home_directory.map(|home| home.join("Library/Application Support"))
+        } else {
+            env::var_os("XDG_DATA_HOME")
+                .map(PathBuf::from)
+                .and_then(absolute_path)
+                .or_else(|| home_directory.map(|home| home.join(".local/share")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_data_file_path() {
+        let working_directory = tempfile::tempdir().unwrap();
+        let data_directory = tempfile::tempdir().unwrap();
+        let legacy_file_path = working_directory.path().join("rpatchur.dat");
+
+        // The working directory is used when there's no data directory
+        assert_eq!(
+            resolve_data_file_path(None, legacy_file_path.clone()),
+            legacy_file_path
+        );
+        let file_path = data_directory.path().join("rpatchur.dat");
+        assert_eq!(
+            resolve_data_file_path(Some(data_directory.path()), legacy_file_path.clone()),
+            file_path
+        );
+        assert!(!file_path.exists());
+
+        // Files left in the working directory are moved
+        fs::write(&legacy_file_path, "{\"last_patch_index\": 3}").unwrap();
+        assert_eq!(
+            resolve_data_file_path(Some(data_directory.path()), legacy_file_path.clone()),
+            file_path
+        );
+        assert!(!legacy_file_path.exists());
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "{\"last_patch_index\": 3}"
+        );
+
+        // But never overwrite files of the data directory
+        fs::write(&legacy_file_path, "{\"last_patch_index\": 1}").unwrap();
+        assert_eq!(
+            resolve_data_file_path(Some(data_directory.path()), legacy_file_path.clone()),
+            file_path
+        );
+        assert!(legacy_file_path.exists());
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "{\"last_patch_index\": 3}"
+        );
+    }
+}
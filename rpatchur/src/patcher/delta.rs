@@ -0,0 +1,89 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Context, Result};
+use crc::crc32;
+
+/// Suffix of the THOR entries containing a binary diff against the file they
+/// are named after (e.g. 'data\sprite.spr.rpdelta' patches 'data\sprite.spr').
+pub const DELTA_EXTENSION: &str = ".rpdelta";
+
+const DELTA_MAGIC: &[u8; 8] = b"RPDELTA1";
+// Magic, base CRC32, target size, target CRC32
+const DELTA_HEADER_SIZE: usize = 8 + 4 + 8 + 4;
+
+/// Returns the path of the file patched by a delta entry, or `None` if
+/// `relative_path` doesn't designate a delta entry.
+pub fn delta_target_path(relative_path: &str) -> Option<&str> {
+    if relative_path.len() > DELTA_EXTENSION.len()
+        && relative_path
+            .to_ascii_lowercase()
+            .ends_with(DELTA_EXTENSION)
+    {
+        Some(&relative_path[..relative_path.len() - DELTA_EXTENSION.len()])
+    } else {
+        None
+    }
+}
+
+/// Applies a delta patch to `base`.
+///
+/// Returns `None` if the delta wasn't made against `base`.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Option<Vec<u8>>> {
+    if delta.len() < DELTA_HEADER_SIZE || &delta[..8] != DELTA_MAGIC {
+        return Err(anyhow!("Invalid delta header"));
+    }
+    let base_crc = u32::from_le_bytes(delta[8..12].try_into()?);
+    let target_size = u64::from_le_bytes(delta[12..20].try_into()?);
+    let target_crc = u32::from_le_bytes(delta[20..24].try_into()?);
+    if crc32::checksum_ieee(base) != base_crc {
+        return Ok(None);
+    }
+
+    let mut target = vec![0; target_size as usize];
+    bsdiff::patch::patch(base, &mut &delta[DELTA_HEADER_SIZE..], &mut target)
+        .context("Failed to apply binary diff")?;
+    if crc32::checksum_ieee(&target) != target_crc {
+        return Err(anyhow!(
+            "Patched content doesn't match the expected checksum"
+        ));
+    }
+    Ok(Some(target))
+}
+
+/// Generates a delta patch that turns `base` into `target`.
+#[cfg(test)]
+pub fn make_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = DELTA_MAGIC.to_vec();
+    delta.extend_from_slice(&crc32::checksum_ieee(base).to_le_bytes());
+    delta.extend_from_slice(&(target.len() as u64).to_le_bytes());
+    delta.extend_from_slice(&crc32::checksum_ieee(target).to_le_bytes());
+    bsdiff::diff::diff(base, target, &mut delta).unwrap();
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_target_path() {
+        assert_eq!(
+            Some("data\\sprite.spr"),
+            delta_target_path("data\\sprite.spr.rpdelta")
+        );
+        assert_eq!(None, delta_target_path("data\\sprite.spr"));
+        assert_eq!(None, delta_target_path(".rpdelta"));
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let base = b"The quick brown fox jumps over the lazy dog".repeat(10);
+        let target = b"The quick brown cat jumps over the lazy dog".repeat(12);
+        let delta = make_delta(&base, &target);
+        assert_eq!(Some(target.clone()), apply_delta(&base, &delta).unwrap());
+        // Base mismatch
+        assert_eq!(None, apply_delta(&target, &delta).unwrap());
+        // Invalid delta
+        assert!(apply_delta(&base, b"RPDELTA0").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+/// Estimation of the disk space needed to apply an update, in bytes.
+#[derive(Debug, PartialEq)]
+pub struct RequiredSpace {
+    pub download_dir: u64, // Downloaded patches
+    pub game_dir: u64,     // Patched GRFs and files
+}
+
+/// Estimates the space required to download and apply patches whose total
+/// size is `patches_size`.
+///
+/// When patching GRFs out of place, the target GRF (of size `grf_size`) is
+/// rebuilt next to its backup, which requires enough space for a full copy.
+pub fn estimate_required_space(patches_size: u64, grf_size: u64, in_place: bool) -> RequiredSpace {
+    let game_dir = if in_place {
+        patches_size
+    } else {
+        grf_size.saturating_add(patches_size)
+    };
+    RequiredSpace {
+        download_dir: patches_size,
+        game_dir,
+    }
+}
+
+/// Returns an error if the file system containing `path` has less than
+/// `required_bytes` bytes available.
+pub fn ensure_available_space(path: impl AsRef<Path>, required_bytes: u64) -> Result<()> {
+    let path = path.as_ref();
+    let available_bytes = fs2::available_space(path).with_context(|| {
+        format!(
+            "Failed to retrieve available disk space for '{}'",
+            path.display()
+        )
+    })?;
+    if available_bytes < required_bytes {
+        return Err(anyhow!(
+            "Not enough disk space available in '{}' ({} required, {} available)",
+            path.display(),
+            format_size(required_bytes),
+            format_size(available_bytes)
+        ));
+    }
+    Ok(())
+}
+
+fn format_size(byte_count: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = byte_count as f64;
+    let mut unit = "B";
+    for next_unit in UNITS.iter() {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_required_space() {
+        assert_eq!(
+            RequiredSpace {
+                download_dir: 100,
+                game_dir: 100
+            },
+            estimate_required_space(100, 1000, true)
+        );
+        assert_eq!(
+            RequiredSpace {
+                download_dir: 100,
+                game_dir: 1100
+            },
+            estimate_required_space(100, 1000, false)
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!("512.0 B", format_size(512));
+        assert_eq!("1.5 KiB", format_size(1536));
+        assert_eq!("2.0 GiB", format_size(2 * 1024 * 1024 * 1024));
+    }
+}
@@ -0,0 +1,173 @@
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::config::{ElevationPolicy, PatcherConfiguration};
+use crate::process::{is_elevated, run_patcher_elevated, spawn_patcher_elevated};
+use crate::ui::{PatchingStatus, UiController};
+
+/// What happened to an update that required administrator privileges.
+#[derive(Debug, PartialEq)]
+pub enum ElevatedUpdate {
+    NotNeeded,       // The game's directory is writable, the update can go on
+    Relaunched,      // The patcher has been started again with privileges, this one must exit
+    Completed(bool), // An elevated helper applied the update, whether every patch was applied
+}
+
+/// Checks whether the game's directory can be written to (it can't when the
+/// client is installed in 'Program Files', for example) and, if it can't,
+/// gets the update done with administrator privileges as
+/// `client.elevation` says.
+///
+/// Note: The helper blocks until the update is done.
+pub fn elevate_update_if_needed(
+    config: &PatcherConfiguration,
+    install_directory: &Path,
+    ui_controller: &UiController,
+) -> Result<ElevatedUpdate> {
+    let policy = config.client.elevation.unwrap_or(ElevationPolicy::Never);
+    if policy == ElevationPolicy::Never || is_elevated() || is_directory_writable(install_directory)
+    {
+        return Ok(ElevatedUpdate::NotNeeded);
+    }
+    log::info!(
+        "'{}' requires administrator privileges",
+        install_directory.display()
+    );
+    let working_directory =
+        std::env::current_dir().context("Failed to find the working directory")?;
+    match policy {
+        ElevationPolicy::Never => Ok(ElevatedUpdate::NotNeeded),
+        ElevationPolicy::Relaunch => {
+            let arguments = relaunch_arguments(std::env::args().skip(1), &working_directory);
+            if !spawn_patcher_elevated(&arguments)? {
+                return Err(anyhow!("Administrator privileges were denied"));
+            }
+            log::info!("Patcher restarted with administrator privileges");
+            Ok(ElevatedUpdate::Relaunched)
+        }
+        ElevationPolicy::Helper => {
+            let arguments = helper_arguments(
+                config.profile.as_deref(),
+                install_directory,
+                &working_directory,
+            );
+            log::info!("Updating with administrator privileges");
+            ui_controller.dispatch_patching_status(PatchingStatus::ElevatedUpdateInProgress);
+            // Note: The helper exits with the exit codes of the headless mode
+            match run_patcher_elevated(&arguments)? {
+                0 => Ok(ElevatedUpdate::Completed(true)),
+                2 => Ok(ElevatedUpdate::Completed(false)),
+                exit_code => Err(anyhow!(
+                    "The update failed with administrator privileges (exit code: {}), see the logs for details",
+                    exit_code
+                )),
+            }
+        }
+    }
+}
+
+/// Indicates whether files can be created in `directory`. Errors other than
+/// a denied access (e.g. a missing directory) are left to the update to
+/// report.
+fn is_directory_writable(directory: &Path) -> bool {
+    match tempfile::tempfile_in(directory) {
+        Err(err) => err.kind() != io::ErrorKind::PermissionDenied,
+        Ok(_) => true,
+    }
+}
+
+/// Returns the arguments the patcher is restarted with, that is the current
+/// ones with an absolute working directory.
+fn relaunch_arguments(
+    arguments: impl Iterator<Item = String>,
+    working_directory: &Path,
+) -> Vec<String> {
+    let mut relaunch_arguments = vec![
+        "--working-directory".to_string(),
+        working_directory.to_string_lossy().into_owned(),
+    ];
+    let mut arguments = arguments;
+    while let Some(argument) = arguments.next() {
+        if argument == "-w" || argument == "--working-directory" {
+            arguments.next();
+        } else if !argument.starts_with("--working-directory=") {
+            relaunch_arguments.push(argument);
+        }
+    }
+    relaunch_arguments
+}
+
+/// Returns the arguments of the helper, which updates the same installation
+/// (and profile) in headless mode.
+fn helper_arguments(
+    profile: Option<&str>,
+    install_directory: &Path,
+    working_directory: &Path,
+) -> Vec<String> {
+    let mut arguments = vec![
+        "--working-directory".to_string(),
+        working_directory.to_string_lossy().into_owned(),
+        "--install-directory".to_string(),
+        install_directory.to_string_lossy().into_owned(),
+        "--no-ui".to_string(),
+    ];
+    if let Some(profile) = profile {
+        arguments.push("--profile".to_string());
+        arguments.push(profile.to_string());
+    }
+    arguments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_directory_writable() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert!(is_directory_writable(temp_dir.path()));
+        // Missing directories are reported by the update itself
+        assert!(is_directory_writable(&temp_dir.path().join("missing")));
+    }
+
+    #[test]
+    fn test_elevation_arguments() {
+        let arguments = [
+            "-w",
+            "client",
+            "--install-directory",
+            "/games/ro",
+            "--working-directory=client",
+        ];
+        assert_eq!(
+            relaunch_arguments(
+                arguments.iter().map(|argument| argument.to_string()),
+                Path::new("/opt/patcher/client")
+            ),
+            vec![
+                "--working-directory",
+                "/opt/patcher/client",
+                "--install-directory",
+                "/games/ro"
+            ]
+        );
+        assert_eq!(
+            helper_arguments(
+                Some("renewal"),
+                Path::new("/games/ro"),
+                Path::new("/opt/patcher")
+            ),
+            vec![
+                "--working-directory",
+                "/opt/patcher",
+                "--install-directory",
+                "/games/ro",
+                "--no-ui",
+                "--profile",
+                "renewal"
+            ]
+        );
+    }
+}
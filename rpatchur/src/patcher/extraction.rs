@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::ThorEntryContent;
+
+// Number of pending entries per worker, to limit memory usage
+const QUEUED_ENTRIES_PER_WORKER: usize = 2;
+
+/// File written by an `ExtractionPool`.
+pub struct ExtractedFile {
+    pub path: PathBuf,
+    pub size: u64, // Number of bytes written
+}
+
+/// Pool of threads that decompress THOR entries and write them to disk in the
+/// background.
+///
+/// Files are returned in the order in which they've been written, which may
+/// differ from the order in which they've been submitted. Entries that write
+/// the same file must not be submitted while one of them is pending.
+pub struct ExtractionPool {
+    job_tx: Option<flume::Sender<(PathBuf, ThorEntryContent)>>,
+    result_rx: flume::Receiver<Result<ExtractedFile>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ExtractionPool {
+    /// Starts a pool with one worker per available CPU.
+    pub fn new() -> Self {
+        let worker_count = thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1);
+        Self::with_worker_count(worker_count)
+    }
+
+    pub fn with_worker_count(worker_count: usize) -> Self {
+        let worker_count = worker_count.max(1);
+        let (job_tx, job_rx) =
+            flume::bounded::<(PathBuf, ThorEntryContent)>(worker_count * QUEUED_ENTRIES_PER_WORKER);
+        // Note: Results are consumed by the thread submitting entries, which
+        // must never block workers
+        let (result_tx, result_rx) = flume::unbounded();
+        let workers = (0..worker_count)
+            .map(|_| {
+                let job_rx = job_rx.clone();
+                let result_tx = result_tx.clone();
+                thread::spawn(move || {
+                    for (path, content) in job_rx.iter() {
+                        let result = extract_entry(path, &content);
+                        if result_tx.send(result).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            job_tx: Some(job_tx),
+            result_rx,
+            workers,
+        }
+    }
+
+    /// Queues an entry to be written at `path`. Blocks while the queue is
+    /// full.
+    pub fn submit(&self, path: PathBuf, content: ThorEntryContent) -> Result<()> {
+        match &self.job_tx {
+            Some(job_tx) => job_tx
+                .send((path, content))
+                .map_err(|_| anyhow!("Extraction workers stopped unexpectedly")),
+            None => Err(anyhow!("Extraction pool is stopped")),
+        }
+    }
+
+    /// Returns the files written so far, without blocking.
+    pub fn completed_files(&self) -> impl Iterator<Item = Result<ExtractedFile>> + '_ {
+        self.result_rx.try_iter()
+    }
+
+    /// Waits for the next file to be written. Must only be called while
+    /// entries are pending.
+    pub fn next_completed_file(&self) -> Result<ExtractedFile> {
+        self.result_rx
+            .recv()
+            .map_err(|_| anyhow!("Extraction workers stopped unexpectedly"))?
+    }
+
+    /// Waits for the remaining entries to be written and returns them.
+    pub fn finish(mut self) -> Vec<Result<ExtractedFile>> {
+        self.stop();
+        self.result_rx.try_iter().collect()
+    }
+
+    fn stop(&mut self) {
+        // Workers stop once the queue is empty and closed
+        self.job_tx = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for ExtractionPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn extract_entry(path: PathBuf, content: &ThorEntryContent) -> Result<ExtractedFile> {
+    let content = content.decompress()?;
+    fs::write(&path, &content).with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(ExtractedFile {
+        path,
+        size: content.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::ThorArchive;
+    use std::collections::HashSet;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_extraction_pool() {
+        let thor_archive_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor/dir1.thor");
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let entries: Vec<String> = thor_archive
+            .get_entries()
+            .filter(|entry| !entry.is_internal() && !entry.is_removed)
+            .map(|entry| entry.relative_path.clone())
+            .collect();
+        let temp_dir = tempdir().unwrap();
+        let pool = ExtractionPool::with_worker_count(3);
+        let mut expected_paths = HashSet::new();
+        for (i, relative_path) in entries.iter().enumerate() {
+            let path = temp_dir.path().join(format!("file{}", i));
+            let content = thor_archive
+                .read_compressed_file_content(relative_path)
+                .unwrap();
+            pool.submit(path.clone(), content).unwrap();
+            expected_paths.insert(path);
+        }
+
+        let extracted_files: Vec<ExtractedFile> =
+            pool.finish().into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(extracted_files.len(), entries.len());
+        for extracted_file in extracted_files {
+            assert!(expected_paths.contains(&extracted_file.path));
+            assert_eq!(
+                fs::metadata(&extracted_file.path).unwrap().len(),
+                extracted_file.size
+            );
+        }
+        for (i, relative_path) in entries.iter().enumerate() {
+            assert_eq!(
+                fs::read(temp_dir.path().join(format!("file{}", i))).unwrap(),
+                thor_archive.read_file_content(relative_path).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use gruf::grf::{GrfArchive, GrfIndex};
+use gruf::NameEncoding;
+use serde::{Deserialize, Serialize};
+
+/// Index of a GRF saved to disk, along with what identifies the version of
+/// the GRF it was generated from.
+#[derive(Serialize, Deserialize)]
+struct CachedGrfIndex {
+    grf_path: PathBuf,
+    grf_size: u64,
+    grf_modified: SystemTime,
+    name_encoding: String,
+    index: GrfIndex,
+}
+
+/// Returns the index saved in `cache_file_path`, if it was generated from the
+/// current version of the GRF located at `grf_path`.
+pub fn load_grf_index(
+    cache_file_path: impl AsRef<Path>,
+    grf_path: impl AsRef<Path>,
+    name_encoding: NameEncoding,
+) -> Option<GrfIndex> {
+    let (grf_size, grf_modified) = grf_version(&grf_path).ok()?;
+    let cache_file = File::open(cache_file_path).ok()?;
+    let cached_index: CachedGrfIndex =
+        bincode::deserialize_from(BufReader::new(cache_file)).ok()?;
+    let is_up_to_date = cached_index.grf_path == grf_path.as_ref()
+        && cached_index.grf_size == grf_size
+        && cached_index.grf_modified == grf_modified
+        && cached_index.name_encoding == format!("{:?}", name_encoding);
+    if is_up_to_date {
+        Some(cached_index.index)
+    } else {
+        None
+    }
+}
+
+/// Saves the index of the GRF located at `grf_path` into `cache_file_path`.
+/// The GRF must not be modified afterwards for the index to be reused.
+pub fn save_grf_index(
+    cache_file_path: impl AsRef<Path>,
+    grf_path: impl AsRef<Path>,
+    name_encoding: NameEncoding,
+    index: GrfIndex,
+) -> Result<()> {
+    let (grf_size, grf_modified) = grf_version(&grf_path)?;
+    let cached_index = CachedGrfIndex {
+        grf_path: grf_path.as_ref().to_path_buf(),
+        grf_size,
+        grf_modified,
+        name_encoding: format!("{:?}", name_encoding),
+        index,
+    };
+    // Write to a temporary file first, to never leave a truncated index
+    let mut temporary_file_path = cache_file_path.as_ref().to_path_buf();
+    temporary_file_path.set_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temporary_file_path)?);
+    bincode::serialize_into(&mut writer, &cached_index).context("Failed to serialize GRF index")?;
+    writer.flush()?;
+    fs::rename(temporary_file_path, cache_file_path)?;
+    Ok(())
+}
+
+/// Opens the GRF located at `grf_path` through `obj`, with the index saved in
+/// `cache_file_path` if it's up to date.
+pub fn open_grf_with_cached_index<R: Read + Seek>(
+    obj: R,
+    grf_path: impl AsRef<Path>,
+    name_encoding: NameEncoding,
+    cache_file_path: Option<&Path>,
+) -> Result<GrfArchive<R>> {
+    let index = cache_file_path
+        .and_then(|cache_file_path| load_grf_index(cache_file_path, &grf_path, name_encoding));
+    match index {
+        Some(index) => {
+            log::trace!("Using cached index of '{}'", grf_path.as_ref().display());
+            Ok(GrfArchive::new_with_index(obj, index))
+        }
+        None => Ok(GrfArchive::new_with_encoding(obj, name_encoding)?),
+    }
+}
+
+/// Saves the index of the GRF located at `grf_path` if `cache_file_path` is
+/// set. Failures are only logged since the index can always be rebuilt.
+pub fn update_grf_index(
+    cache_file_path: Option<&Path>,
+    grf_path: impl AsRef<Path>,
+    name_encoding: NameEncoding,
+    index: Option<GrfIndex>,
+) {
+    if let (Some(cache_file_path), Some(index)) = (cache_file_path, index) {
+        if let Err(e) = save_grf_index(cache_file_path, &grf_path, name_encoding, index) {
+            log::warn!(
+                "Failed to save the index of '{}': {:#}",
+                grf_path.as_ref().display(),
+                e
+            );
+        }
+    }
+}
+
+/// Returns what identifies the version of a GRF: its size and the time of its
+/// last modification.
+fn grf_version(grf_path: impl AsRef<Path>) -> Result<(u64, SystemTime)> {
+    let metadata = fs::metadata(grf_path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_grf_index_cache() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("small.grf");
+        let cache_file_path = temp_dir.path().join("rpatchur.small.grf.idx");
+        fs::copy(grf_dir_path.join("200-small.grf"), &grf_path).unwrap();
+        let index = GrfArchive::open(&grf_path).unwrap().index();
+        let encoding = NameEncoding::Windows1252;
+        assert!(load_grf_index(&cache_file_path, &grf_path, encoding).is_none());
+
+        save_grf_index(&cache_file_path, &grf_path, encoding, index.clone()).unwrap();
+
+        assert_eq!(
+            load_grf_index(&cache_file_path, &grf_path, encoding),
+            Some(index)
+        );
+        // Indexes are specific to an encoding and a GRF
+        assert!(load_grf_index(&cache_file_path, &grf_path, NameEncoding::Cp949).is_none());
+        let other_grf_path = temp_dir.path().join("other.grf");
+        fs::copy(&grf_path, &other_grf_path).unwrap();
+        assert!(load_grf_index(&cache_file_path, &other_grf_path, encoding).is_none());
+        // Modified GRFs must be parsed again
+        OpenOptions::new()
+            .append(true)
+            .open(&grf_path)
+            .unwrap()
+            .write_all(&[0; 16])
+            .unwrap();
+        assert!(load_grf_index(&cache_file_path, &grf_path, encoding).is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::ui::PatchReport;
+
+/// Number of history records reported to the UI
+pub const HISTORY_LIMIT: usize = 100;
+
+/// Appends the outcomes of the patches processed by an installation to the
+/// history file located at `file_path`, one JSON record per line.
+pub fn append_history(file_path: impl AsRef<Path>, patch_reports: &[PatchReport]) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)?;
+    let mut writer = BufWriter::new(file);
+    for patch_report in patch_reports {
+        serde_json::to_writer(&mut writer, patch_report)
+            .context("Failed to serialize history record")?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns the last `limit` records of the history file located at
+/// `file_path` (all of them if `None`), from oldest to newest. The history is
+/// empty if the file doesn't exist yet.
+///
+/// Note: Lines that can't be parsed (e.g. cut short by a crash) are ignored.
+pub fn read_history(file_path: impl AsRef<Path>, limit: Option<usize>) -> Result<Vec<PatchReport>> {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = VecDeque::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Err(e) => log::warn!("Ignoring invalid history record: {}", e),
+            Ok(record) => {
+                if limit == Some(records.len()) {
+                    records.pop_front();
+                }
+                if limit != Some(0) {
+                    records.push_back(record);
+                }
+            }
+        }
+    }
+    Ok(records.into())
+}
+
+/// Formats a Unix timestamp as a UTC date and time (e.g.
+/// "2021-05-07 14:03:12 UTC").
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+    // Note: Converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::PatchOutcome;
+    use std::fs;
+
+    fn patch_report(index: usize, outcome: PatchOutcome) -> PatchReport {
+        PatchReport {
+            name: format!("patch{}.thor", index),
+            index,
+            timestamp: 1_620_396_192,
+            size: Some(1024),
+            duration_ms: 120,
+            outcome,
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let history_file_path = temp_dir.path().join("rpatchur.history.jsonl");
+        assert!(read_history(&history_file_path, None).unwrap().is_empty());
+
+        append_history(
+            &history_file_path,
+            &[
+                patch_report(1, PatchOutcome::Applied),
+                patch_report(2, PatchOutcome::Skipped),
+            ],
+        )
+        .unwrap();
+        // Records are appended, invalid ones are ignored
+        let mut history_file = OpenOptions::new()
+            .append(true)
+            .open(&history_file_path)
+            .unwrap();
+        history_file.write_all(b"{\"name\": \"patch3.th").unwrap();
+        writeln!(history_file).unwrap();
+        append_history(&history_file_path, &[patch_report(4, PatchOutcome::Failed)]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&history_file_path)
+                .unwrap()
+                .lines()
+                .count(),
+            4
+        );
+
+        let indexes = |records: Vec<PatchReport>| -> Vec<(usize, PatchOutcome)> {
+            records
+                .into_iter()
+                .map(|record| (record.index, record.outcome))
+                .collect()
+        };
+        assert_eq!(
+            indexes(read_history(&history_file_path, None).unwrap()),
+            vec![
+                (1, PatchOutcome::Applied),
+                (2, PatchOutcome::Skipped),
+                (4, PatchOutcome::Failed)
+            ]
+        );
+        assert_eq!(
+            indexes(read_history(&history_file_path, Some(2)).unwrap()),
+            vec![(2, PatchOutcome::Skipped), (4, PatchOutcome::Failed)]
+        );
+        assert!(read_history(&history_file_path, Some(0))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_620_396_192), "2021-05-07 14:03:12 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(4_107_542_399), "2100-02-28 23:59:59 UTC");
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::process::Command;
+
+use super::config::{is_contained_path, Hook, HooksConfiguration};
+
+/// Moment of a patching session at which hooks are run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookStage {
+    PrePatch,    // Before patching starts
+    PostPatch,   // Once patching has completed
+    PatchFailed, // Once patching has failed
+}
+
+impl HookStage {
+    /// Returns the name of the stage, as used in the configuration. It's also
+    /// passed to commands through the `RPATCHUR_HOOK` environment variable.
+    pub fn name(self) -> &'static str {
+        match self {
+            HookStage::PrePatch => "pre_patch",
+            HookStage::PostPatch => "post_patch",
+            HookStage::PatchFailed => "on_failure",
+        }
+    }
+}
+
+/// Runs the hooks configured for `stage`, in order, from
+/// `install_directory`. Stops at the first hook that fails.
+pub async fn run_hooks(
+    stage: HookStage,
+    config: &HooksConfiguration,
+    install_directory: &Path,
+) -> Result<()> {
+    let hooks = match stage {
+        HookStage::PrePatch => &config.pre_patch,
+        HookStage::PostPatch => &config.post_patch,
+        HookStage::PatchFailed => &config.on_failure,
+    };
+    for hook in hooks {
+        log::info!("Running {} hook: {:?}", stage.name(), hook);
+        run_hook(stage, hook, install_directory)
+            .await
+            .with_context(|| format!("{} hook {:?} failed", stage.name(), hook))?;
+    }
+    Ok(())
+}
+
+async fn run_hook(stage: HookStage, hook: &Hook, install_directory: &Path) -> Result<()> {
+    match hook {
+        Hook::Command { path, arguments } => {
+            // Note: Executables that aren't in `install_directory` are looked
+            // up in the PATH
+            let local_path = install_directory.join(path);
+            let mut command = if local_path.is_file() {
+                Command::new(local_path)
+            } else {
+                Command::new(path)
+            };
+            let output = command
+                .args(arguments)
+                .current_dir(install_directory)
+                .env("RPATCHUR_HOOK", stage.name())
+                .output()
+                .await
+                .with_context(|| format!("Failed to start '{}'", path))?;
+            log_output(path, &output.stdout, &output.stderr);
+            if !output.status.success() {
+                return Err(anyhow!("'{}' exited with status '{}'", path, output.status));
+            }
+            Ok(())
+        }
+        Hook::KillProcess { name } => kill_process(name).await,
+        Hook::ClearDirectory { path } => {
+            let directory_path = resolve_hook_directory(install_directory, path)?;
+            let removed_count = clear_directory(directory_path)?;
+            log::info!("Removed {} entries from '{}'", removed_count, path);
+            Ok(())
+        }
+    }
+}
+
+/// Logs the output of a command, line by line.
+fn log_output(command_name: &str, stdout: &[u8], stderr: &[u8]) {
+    for line in String::from_utf8_lossy(stdout).lines() {
+        log::info!("[{}] {}", command_name, line);
+    }
+    for line in String::from_utf8_lossy(stderr).lines() {
+        log::warn!("[{}] {}", command_name, line);
+    }
+}
+
+/// Terminates the processes started from the executable named
+/// `executable_name`. Succeeds if no such process is running.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+async fn kill_process(executable_name: &str) -> Result<()> {
+    // Note: taskkill exits with code 128 when no process matches
+    const NO_MATCHING_PROCESS: i32 = 128;
+    let output = Command::new("taskkill")
+        .args(["/F", "/IM", executable_name])
+        .output()
+        .await
+        .context("Failed to start 'taskkill'")?;
+    log_output("taskkill", &output.stdout, &output.stderr);
+    match output.status.code() {
+        Some(0) | Some(NO_MATCHING_PROCESS) => Ok(()),
+        _ => Err(anyhow!("'taskkill' exited with status '{}'", output.status)),
+    }
+}
+
+/// Terminates the processes started from the executable named
+/// `executable_name`. Succeeds if no such process is running.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+async fn kill_process(executable_name: &str) -> Result<()> {
+    // Note: pkill exits with code 1 when no process matches
+    const NO_MATCHING_PROCESS: i32 = 1;
+    let output = Command::new("pkill")
+        .args(["-x", executable_name])
+        .output()
+        .await
+        .context("Failed to start 'pkill'")?;
+    log_output("pkill", &output.stdout, &output.stderr);
+    match output.status.code() {
+        Some(0) | Some(NO_MATCHING_PROCESS) => Ok(()),
+        _ => Err(anyhow!("'pkill' exited with status '{}'", output.status)),
+    }
+}
+
+/// Returns the location of the directory at `path`, relative to
+/// `install_directory`. Fails if it isn't located under `install_directory`
+/// (e.g. '..', an absolute path or a symbolic link pointing elsewhere).
+fn resolve_hook_directory(install_directory: &Path, path: &str) -> Result<PathBuf> {
+    if !is_contained_path(path) {
+        return Err(anyhow!(
+            "'{}' isn't a subdirectory of the installation directory",
+            path
+        ));
+    }
+    let directory_path = install_directory.join(path);
+    if !directory_path.exists() {
+        return Ok(directory_path);
+    }
+    let install_directory = install_directory
+        .canonicalize()
+        .context("Failed to resolve the installation directory")?;
+    let directory_path = directory_path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve '{}'", path))?;
+    if directory_path == install_directory || !directory_path.starts_with(&install_directory) {
+        return Err(anyhow!(
+            "'{}' resolves to '{}', outside of the installation directory",
+            path,
+            directory_path.display()
+        ));
+    }
+    Ok(directory_path)
+}
+
+/// Removes the content of the directory located at `directory_path`, but not
+/// the directory itself. Returns the number of entries removed.
+fn clear_directory(directory_path: impl AsRef<Path>) -> Result<usize> {
+    let directory_path = directory_path.as_ref();
+    if !directory_path.exists() {
+        return Ok(0);
+    }
+    let mut removed_count = 0;
+    for dir_entry in fs::read_dir(directory_path)? {
+        let entry_path = dir_entry?.path();
+        if entry_path.is_dir() {
+            fs::remove_dir_all(&entry_path)
+        } else {
+            fs::remove_file(&entry_path)
+        }
+        .with_context(|| format!("Failed to remove '{}'", entry_path.display()))?;
+        removed_count += 1;
+    }
+    Ok(removed_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_clear_directory() {
+        let temp_dir = tempdir().unwrap();
+        let cache_dir = temp_dir.path().join("cache");
+        fs::create_dir_all(cache_dir.join("textures")).unwrap();
+        fs::write(cache_dir.join("textures/file.bmp"), b"content").unwrap();
+        fs::write(cache_dir.join("file.txt"), b"content").unwrap();
+
+        assert_eq!(clear_directory(&cache_dir).unwrap(), 2);
+
+        assert!(cache_dir.is_dir());
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 0);
+        // Missing directories have nothing to clear
+        assert_eq!(clear_directory(temp_dir.path().join("missing")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_resolve_hook_directory() {
+        let temp_dir = tempdir().unwrap();
+        let install_dir = temp_dir.path().join("client");
+        fs::create_dir_all(install_dir.join("cache")).unwrap();
+        assert_eq!(
+            resolve_hook_directory(&install_dir, "cache").unwrap(),
+            install_dir.join("cache").canonicalize().unwrap()
+        );
+        assert_eq!(
+            resolve_hook_directory(&install_dir, "missing").unwrap(),
+            install_dir.join("missing")
+        );
+        for path in &["..", "cache/../..", "/", ""] {
+            assert!(resolve_hook_directory(&install_dir, path).is_err());
+        }
+        // Symbolic links can't lead outside of the installation directory
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(temp_dir.path(), install_dir.join("link")).unwrap();
+            assert!(resolve_hook_directory(&install_dir, "link").is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_hooks() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("cache")).unwrap();
+        fs::write(temp_dir.path().join("cache/file.txt"), b"content").unwrap();
+        let config: HooksConfiguration = serde_yaml::from_str(
+            "
+            post_patch:
+              - action: clear_directory
+                path: cache
+            on_failure:
+              - action: command
+                path: missing-executable
+            ",
+        )
+        .unwrap();
+
+        // Stages without hooks do nothing
+        run_hooks(HookStage::PrePatch, &config, temp_dir.path())
+            .await
+            .unwrap();
+        run_hooks(HookStage::PostPatch, &config, temp_dir.path())
+            .await
+            .unwrap();
+        assert!(!temp_dir.path().join("cache/file.txt").exists());
+        assert!(run_hooks(HookStage::PatchFailed, &config, temp_dir.path())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use url::Url;
+
+use super::config::{ProxyConfiguration, WebConfiguration};
+use super::source::{is_local_url, local_path_from_url};
+use super::tls::configure_tls;
+use super::{get_patcher_name, PatcherConfiguration};
+use crate::PKG_VERSION;
+
+/// Maximum number of idle connections kept alive per host
+const POOL_MAX_IDLE_PER_HOST: usize = 32;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_USER_AGENT: &str = "rpatchur/{version}";
+
+/// Builds the HTTP client shared by all the requests issued by the patcher.
+///
+/// Reusing a single client allows connections to be pooled and kept alive
+/// between requests.
+pub fn build_http_client(config: &PatcherConfiguration) -> Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .connect_timeout(connect_timeout(&config.web))
+        .user_agent(user_agent(&config.web))
+        .default_headers(default_headers(&config.web)?);
+    let builder = configure_proxy(builder, &config.proxy)?;
+    let builder = configure_tls(builder, &config.web.tls)?;
+    builder.build().context("Failed to build HTTP client")
+}
+
+/// Returns the User-Agent sent with every request.
+///
+/// '{patcher_name}' and '{version}' are replaced with the name of the
+/// patcher's executable and rpatchur's version.
+fn user_agent(web_config: &WebConfiguration) -> String {
+    let patcher_name = get_patcher_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    web_config
+        .user_agent
+        .as_deref()
+        .unwrap_or(DEFAULT_USER_AGENT)
+        .replace("{patcher_name}", &patcher_name)
+        .replace("{version}", PKG_VERSION)
+}
+
+/// Returns the headers sent with every request (custom headers and
+/// authentication token).
+fn default_headers(web_config: &WebConfiguration) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &web_config.headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name '{}'", name))?;
+        let header_value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value for header '{}'", name))?;
+        headers.insert(header_name, header_value);
+    }
+    if let Some(auth_token) = &web_config.auth_token {
+        let mut header_value = HeaderValue::from_str(&format!("Bearer {}", auth_token))
+            .with_context(|| "Invalid authentication token")?;
+        // Keep the token out of debug logs
+        header_value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, header_value);
+    }
+    Ok(headers)
+}
+
+/// Configures the proxy used by the client.
+///
+/// Note: System proxies are detected automatically by `reqwest` unless a proxy
+/// is explicitly configured.
+fn configure_proxy(
+    builder: reqwest::ClientBuilder,
+    proxy_config: &ProxyConfiguration,
+) -> Result<reqwest::ClientBuilder> {
+    match &proxy_config.url {
+        Some(proxy_url) => {
+            let mut proxy = reqwest::Proxy::all(proxy_url.as_str())
+                .with_context(|| format!("Invalid proxy URL '{}'", proxy_url))?;
+            if let Some(username) = &proxy_config.username {
+                proxy = proxy.basic_auth(
+                    username,
+                    proxy_config.password.as_deref().unwrap_or_default(),
+                );
+            }
+            log::info!("Using proxy '{}'", proxy_url);
+            Ok(builder.proxy(proxy))
+        }
+        None => {
+            if proxy_config.use_system_proxy {
+                Ok(builder)
+            } else {
+                Ok(builder.no_proxy())
+            }
+        }
+    }
+}
+
+fn connect_timeout(web_config: &WebConfiguration) -> Duration {
+    web_config
+        .connect_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+}
+
+/// Returns the maximum amount of time to wait for data from a server before
+/// giving up.
+pub fn read_timeout(web_config: &WebConfiguration) -> Duration {
+    web_config
+        .read_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_READ_TIMEOUT)
+}
+
+/// Awaits an HTTP operation (sending a request, reading a chunk of a body,
+/// ...), failing if it doesn't complete within `read_timeout`.
+///
+/// Note: `reqwest` only supports timeouts covering whole requests, which
+/// isn't suitable for large downloads.
+pub async fn with_read_timeout<T>(
+    read_timeout: Duration,
+    future: impl Future<Output = reqwest::Result<T>>,
+) -> Result<T> {
+    let res = tokio::time::timeout(read_timeout, future)
+        .await
+        .with_context(|| {
+            format!(
+                "Timed out after {} seconds without receiving data",
+                read_timeout.as_secs()
+            )
+        })?;
+    Ok(res?)
+}
+
+/// Downloads the text document located at `url` (e.g. a news feed). Local
+/// files can be given with 'file://' URLs.
+pub async fn fetch_text(
+    client: &reqwest::Client,
+    url: &str,
+    web_config: &WebConfiguration,
+) -> Result<String> {
+    let url = Url::parse(url).with_context(|| format!("Invalid URL '{}'", url))?;
+    if is_local_url(&url) {
+        let path = local_path_from_url(&url)?;
+        return tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read '{}'", path.display()));
+    }
+    let read_timeout = read_timeout(web_config);
+    let resp = with_read_timeout(read_timeout, client.get(url).send())
+        .await
+        .context("Failed to GET URL")?
+        .error_for_status()?;
+    with_read_timeout(read_timeout, resp.text())
+        .await
+        .context("Invalid response body")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_headers() {
+        let web_config: WebConfiguration = serde_yaml::from_str(
+            "index_url: https://example.com/\n\
+             patch_servers: []\n\
+             headers:\n  X-Client: rpatchur\n\
+             auth_token: secret\n",
+        )
+        .unwrap();
+        let headers = default_headers(&web_config).unwrap();
+        assert_eq!("rpatchur", headers["x-client"]);
+        assert_eq!("Bearer secret", headers[AUTHORIZATION]);
+        assert!(headers[AUTHORIZATION].is_sensitive());
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let mut web_config: WebConfiguration =
+            serde_yaml::from_str("index_url: https://example.com/\npatch_servers: []\n").unwrap();
+        assert_eq!(format!("rpatchur/{}", PKG_VERSION), user_agent(&web_config));
+
+        web_config.user_agent = Some("MyServer Patcher {version} ({patcher_name})".to_string());
+        let patcher_name = get_patcher_name().unwrap();
+        assert_eq!(
+            format!(
+                "MyServer Patcher {} ({})",
+                PKG_VERSION,
+                patcher_name.to_string_lossy()
+            ),
+            user_agent(&web_config)
+        );
+    }
+}
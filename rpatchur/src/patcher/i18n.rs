@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::{Context, Result};
+
+/// Catalogs shipped with the patcher. The English one is the reference and
+/// must contain every message key.
+const BUILTIN_CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../../locales/en.json")),
+    ("fr", include_str!("../../locales/fr.json")),
+];
+const DEFAULT_LANGUAGE: &str = "en";
+// Directory custom catalogs are loaded from, relative to the working directory
+const CUSTOM_CATALOGS_DIRECTORY: &str = "locales";
+
+static CATALOG: RwLock<Option<Catalog>> = RwLock::new(None);
+
+/// Messages of a language, indexed by key.
+struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    /// Builds the catalog of `language`, falling back to English for missing
+    /// messages. Catalogs found in `custom_directory` (e.g. 'locales/de.json')
+    /// override the built-in ones.
+    fn load(language: &str, custom_directory: Option<&Path>) -> Catalog {
+        let mut messages = parse_builtin_catalog(DEFAULT_LANGUAGE).unwrap_or_default();
+        for candidate in language_candidates(language).into_iter().rev() {
+            if let Some(builtin_messages) = parse_builtin_catalog(&candidate) {
+                messages.extend(builtin_messages);
+            }
+            if let Some(custom_directory) = custom_directory {
+                let catalog_path = custom_directory.join(format!("{}.json", candidate));
+                match load_catalog_file(&catalog_path) {
+                    Err(e) => log::warn!("Failed to load {}: {:#}", catalog_path.display(), e),
+                    Ok(custom_messages) => messages.extend(custom_messages),
+                }
+            }
+        }
+        Catalog { messages }
+    }
+
+    fn translate(&self, key: &str, args: &[(&str, &str)]) -> String {
+        let template = self.messages.get(key).map_or(key, String::as_str);
+        format_message(template, args)
+    }
+}
+
+/// Selects the language messages are translated to, the OS's if `None`.
+pub fn init_localization(language: Option<&str>) {
+    let language = language
+        .map(str::to_string)
+        .or_else(get_system_language)
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+    log::info!("Using language '{}'", language);
+    let catalog = Catalog::load(&language, Some(&PathBuf::from(CUSTOM_CATALOGS_DIRECTORY)));
+    if let Ok(mut current_catalog) = CATALOG.write() {
+        *current_catalog = Some(catalog);
+    }
+}
+
+/// Returns the message identified by `key` in the selected language, with
+/// its placeholders (e.g. '{patch}') replaced by `args`.
+pub fn tr(key: &str, args: &[(&str, &str)]) -> String {
+    if let Ok(catalog) = CATALOG.read() {
+        if let Some(catalog) = catalog.as_ref() {
+            return catalog.translate(key, args);
+        }
+    }
+    // Note: Messages are in English until a language is selected
+    Catalog::load(DEFAULT_LANGUAGE, None).translate(key, args)
+}
+
+/// Returns the languages to look for, from the most to the least specific
+/// (e.g. 'pt-br' then 'pt' for 'pt_BR.UTF-8').
+fn language_candidates(language: &str) -> Vec<String> {
+    let language = language
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .replace('_', "-")
+        .to_lowercase();
+    let mut candidates = vec![];
+    if let Some((primary_language, _)) = language.split_once('-') {
+        candidates.push(language.clone());
+        candidates.push(primary_language.to_string());
+    } else if !language.is_empty() {
+        candidates.push(language);
+    }
+    candidates
+}
+
+fn parse_builtin_catalog(language: &str) -> Option<HashMap<String, String>> {
+    BUILTIN_CATALOGS
+        .iter()
+        .find(|(name, _)| *name == language)
+        .and_then(|(_, content)| match serde_json::from_str(content) {
+            Err(e) => {
+                log::error!("Invalid built-in catalog '{}': {}", language, e);
+                None
+            }
+            Ok(messages) => Some(messages),
+        })
+}
+
+fn load_catalog_file(file_path: impl AsRef<Path>) -> Result<HashMap<String, String>> {
+    match File::open(file_path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+        Ok(file) => {
+            serde_json::from_reader(BufReader::new(file)).context("Failed to deserialize catalog")
+        }
+    }
+}
+
+fn format_message(template: &str, args: &[(&str, &str)]) -> String {
+    args.iter()
+        .fold(template.to_string(), |message, (name, value)| {
+            message.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+#[cfg(not(windows))]
+fn get_system_language() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty() && value != "C" && value != "POSIX")
+}
+
+#[cfg(windows)]
+fn get_system_language() -> Option<String> {
+    use winapi::um::winnls::GetUserDefaultLocaleName;
+    use winapi::um::winnt::LOCALE_NAME_MAX_LENGTH;
+
+    // Note: The environment takes precedence, as on other platforms
+    if let Ok(language) = env::var("LANG") {
+        return Some(language);
+    }
+    let mut buffer = [0u16; LOCALE_NAME_MAX_LENGTH];
+    let length = unsafe { GetUserDefaultLocaleName(buffer.as_mut_ptr(), buffer.len() as i32) };
+    if length <= 1 {
+        return None;
+    }
+    // Note: The length includes the null terminator
+    Some(String::from_utf16_lossy(&buffer[..length as usize - 1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_builtin_catalogs() {
+        let reference = parse_builtin_catalog(DEFAULT_LANGUAGE).unwrap();
+        for (language, _) in BUILTIN_CATALOGS {
+            let messages = parse_builtin_catalog(language).unwrap();
+            for (key, message) in &messages {
+                // Translations use the same keys and placeholders
+                let reference_message = reference.get(key).unwrap();
+                for placeholder in reference_message.split('{').skip(1) {
+                    let placeholder = placeholder.split('}').next().unwrap();
+                    assert!(
+                        message.contains(&format!("{{{}}}", placeholder)),
+                        "'{}' is missing {{{}}} in '{}'",
+                        key,
+                        placeholder,
+                        language
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let catalog = Catalog::load("fr_FR.UTF-8", None);
+        assert_eq!(
+            catalog.translate("error.unknown_channel", &[("channel", "beta")]),
+            "Canal inconnu 'beta'"
+        );
+        // Unknown keys are returned as is
+        assert_eq!(catalog.translate("unknown.key", &[]), "unknown.key");
+
+        let temp_dir = tempdir().unwrap();
+        std::fs::write(
+            temp_dir.path().join("de.json"),
+            r#"{"error.unknown_channel": "Unbekannter Kanal '{channel}'"}"#,
+        )
+        .unwrap();
+        let catalog = Catalog::load("de-DE", Some(temp_dir.path()));
+        assert_eq!(
+            catalog.translate("error.unknown_channel", &[("channel", "beta")]),
+            "Unbekannter Kanal 'beta'"
+        );
+        // Missing messages fall back to English
+        assert_eq!(
+            catalog.translate("console.paused", &[]),
+            Catalog::load(DEFAULT_LANGUAGE, None).translate("console.paused", &[])
+        );
+    }
+
+    #[test]
+    fn test_language_candidates() {
+        assert_eq!(language_candidates("pt_BR.UTF-8"), vec!["pt-br", "pt"]);
+        assert_eq!(language_candidates("fr"), vec!["fr"]);
+        assert!(language_candidates("").is_empty());
+    }
+}
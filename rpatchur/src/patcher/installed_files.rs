@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use gruf::grf::GrfArchive;
+use gruf::thor::ThorPatchInfo;
+use gruf::NameEncoding;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::patching::{destination_path, resolve_path_case, PathMatching, UnsafePathHandling};
+use super::preview::{EntryChange, PatchPreview};
+
+/// File of the game directory, or entry of one of its GRFs, written by a
+/// patch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InstalledFile {
+    pub path: String, // Path relative to the installation directory or to `grf`, with Windows-style separators
+    pub grf: Option<String>, // GRF containing the entry, `None` for files on disk
+    pub sha256: Option<String>, // Hex-encoded SHA-256 hash of the content written, `None` if it couldn't be read back
+    pub patch_index: usize,     // Index of the patch that wrote the file
+    pub patch_name: String,
+}
+
+impl InstalledFile {
+    /// Returns the location of the file, as reported to the user.
+    pub fn display_path(&self) -> String {
+        match &self.grf {
+            Some(grf_name) => format!("{}:{}", grf_name, self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// Database of the files written by the patcher, indexed by their folded
+/// location (e.g. 'data.grf:data\\texture\\file.bmp').
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct InstalledFiles {
+    files: BTreeMap<String, InstalledFile>,
+}
+
+impl InstalledFiles {
+    /// Loads the database stored at `file_path`, which is empty if the file
+    /// doesn't exist yet.
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Self> {
+        let file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_reader(BufReader::new(file)).context("Invalid installed files database")
+    }
+
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer(BufWriter::new(file), self)
+            .context("Failed to serialize installed files database")
+    }
+
+    /// Returns the record of the file located at `location`, either a path
+    /// relative to the installation directory or a GRF entry (e.g.
+    /// 'data.grf:data\\texture\\file.bmp').
+    pub fn find(&self, location: &str) -> Option<&InstalledFile> {
+        let (grf_name, path) = match location.split_once(':') {
+            Some((grf_name, path)) => (Some(grf_name), path),
+            None => (None, location),
+        };
+        self.files.get(&entry_key(grf_name, path))
+    }
+
+    /// Records the changes a patch (described by `preview`) made to the
+    /// client installed in `install_directory`, once it's been applied. The
+    /// content of written files is read back to be hashed.
+    pub fn record_patch(
+        &mut self,
+        patch_info: &ThorPatchInfo,
+        preview: &PatchPreview,
+        install_directory: &Path,
+        name_encoding: NameEncoding,
+        path_matching: PathMatching,
+    ) -> Result<()> {
+        let target_grf = preview.target_grf.as_deref();
+        let mut grf_archive = match target_grf {
+            None => None,
+            Some(grf_name) => {
+                let grf_path = install_directory.join(grf_name);
+                let grf_archive = GrfArchive::open_with_encoding(&grf_path, name_encoding)
+                    .with_context(|| format!("Failed to open '{}'", grf_path.display()))?;
+                Some(grf_archive)
+            }
+        };
+        for entry in &preview.changes {
+            let key = entry_key(target_grf, &entry.relative_path);
+            if entry.change == EntryChange::Delete {
+                self.files.remove(&key);
+                continue;
+            }
+            let sha256 = match grf_archive.as_mut() {
+                Some(grf_archive) => hash_grf_entry(grf_archive, &entry.relative_path),
+                None => hash_file(install_directory, &entry.relative_path, path_matching),
+            };
+            if sha256.is_none() {
+                log::warn!(
+                    "Failed to read back '{}' written by '{}'",
+                    entry.relative_path,
+                    patch_info.file_name
+                );
+            }
+            self.files.insert(
+                key,
+                InstalledFile {
+                    path: entry.relative_path.replace('/', "\\"),
+                    grf: target_grf.map(str::to_string),
+                    sha256,
+                    patch_index: patch_info.index,
+                    patch_name: patch_info.file_name.clone(),
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns the key of an entry in the database, which doesn't depend on
+/// the case or the separators of its path.
+fn entry_key(grf_name: Option<&str>, path: &str) -> String {
+    let path = PathMatching::CaseInsensitive.fold(path);
+    match grf_name {
+        Some(grf_name) => format!("{}:{}", grf_name.to_ascii_lowercase(), path),
+        None => path.into_owned(),
+    }
+}
+
+fn hash_file(
+    install_directory: &Path,
+    relative_path: &str,
+    path_matching: PathMatching,
+) -> Option<String> {
+    let file_path = destination_path(install_directory, relative_path, UnsafePathHandling::Skip)
+        .ok()
+        .flatten()?;
+    let file_path = resolve_path_case(install_directory, file_path, path_matching);
+    let file = File::open(file_path).ok()?;
+    sha256_hex(BufReader::new(file)).ok()
+}
+
+fn hash_grf_entry(grf_archive: &mut GrfArchive, relative_path: &str) -> Option<String> {
+    let content = grf_archive.read_file_content(relative_path).or_else(|_| {
+        // The entry may have been merged into an entry that differs in case
+        let folded_path = PathMatching::CaseInsensitive.fold(relative_path);
+        let existing_path = grf_archive
+            .get_entries()
+            .find(|entry| PathMatching::CaseInsensitive.fold(&entry.relative_path) == folded_path)
+            .map(|entry| entry.relative_path.clone());
+        match existing_path {
+            Some(existing_path) => grf_archive.read_file_content(existing_path),
+            None => Err(gruf::GrufError::EntryNotFound),
+        }
+    });
+    sha256_hex(content.ok()?.as_slice()).ok()
+}
+
+/// Returns the hex-encoded SHA-256 hash of `content`.
+pub fn sha256_hex(mut content: impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut content, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patcher::preview::EntryPreview;
+    use gruf::grf::GrfArchiveBuilder;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // SHA-256 hash of "content"
+    const CONTENT_HASH: &str = "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73";
+
+    fn patch_info(index: usize, file_name: &str) -> ThorPatchInfo {
+        ThorPatchInfo {
+            index,
+            file_name: file_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn preview(target_grf: Option<&str>, changes: &[(&str, EntryChange)]) -> PatchPreview {
+        PatchPreview {
+            patch_name: String::new(),
+            target_grf: target_grf.map(str::to_string),
+            changes: changes
+                .iter()
+                .map(|(relative_path, change)| EntryPreview {
+                    relative_path: relative_path.to_string(),
+                    change: *change,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_record_patch() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir(temp_dir.path().join("System")).unwrap();
+        fs::write(temp_dir.path().join("System/iteminfo.lub"), b"content").unwrap();
+        {
+            let grf_file = File::create(temp_dir.path().join("data.grf")).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\Texture\\file.bmp".to_string(), &b"content"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+
+        let mut installed_files = InstalledFiles::default();
+        installed_files
+            .record_patch(
+                &patch_info(1, "2021-05-01.thor"),
+                &preview(
+                    None,
+                    &[
+                        ("system\\iteminfo.lub", EntryChange::Add),
+                        ("readme.txt", EntryChange::Add),
+                    ],
+                ),
+                temp_dir.path(),
+                NameEncoding::Windows1252,
+                PathMatching::CaseInsensitive,
+            )
+            .unwrap();
+        installed_files
+            .record_patch(
+                &patch_info(2, "2021-05-02.thor"),
+                &preview(
+                    Some("data.grf"),
+                    &[("data/texture/file.bmp", EntryChange::Replace)],
+                ),
+                temp_dir.path(),
+                NameEncoding::Windows1252,
+                PathMatching::CaseInsensitive,
+            )
+            .unwrap();
+
+        let item_info = installed_files.find("System/ItemInfo.lub").unwrap();
+        assert_eq!(item_info.patch_index, 1);
+        assert_eq!(item_info.sha256.as_deref(), Some(CONTENT_HASH));
+        // Files that couldn't be read back are recorded without a hash
+        assert_eq!(installed_files.find("readme.txt").unwrap().sha256, None);
+        let texture = installed_files
+            .find("DATA.GRF:data\\texture\\file.bmp")
+            .unwrap();
+        assert_eq!(texture.display_path(), "data.grf:data\\texture\\file.bmp");
+        assert_eq!(texture.patch_name, "2021-05-02.thor");
+        assert_eq!(texture.sha256.as_deref(), Some(CONTENT_HASH));
+        assert!(installed_files.find("data\\texture\\file.bmp").is_none());
+
+        // Later patches take over the files they modify or remove
+        installed_files
+            .record_patch(
+                &patch_info(3, "2021-05-03.thor"),
+                &preview(None, &[("System\\iteminfo.lub", EntryChange::Delete)]),
+                temp_dir.path(),
+                NameEncoding::Windows1252,
+                PathMatching::CaseInsensitive,
+            )
+            .unwrap();
+        assert!(installed_files.find("System\\iteminfo.lub").is_none());
+
+        let database_path = temp_dir.path().join("rpatchur.files.json");
+        installed_files.save(&database_path).unwrap();
+        let installed_files = InstalledFiles::load(&database_path).unwrap();
+        assert_eq!(installed_files.find("readme.txt").unwrap().patch_index, 1);
+        assert!(InstalledFiles::load(temp_dir.path().join("missing.json"))
+            .unwrap()
+            .find("readme.txt")
+            .is_none());
+    }
+}
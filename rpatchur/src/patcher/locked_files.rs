@@ -0,0 +1,231 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use gruf::GrufError;
+use serde::{Deserialize, Serialize};
+
+// Windows error codes returned when a file is opened by another process
+const ERROR_SHARING_VIOLATION: i32 = 32;
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+/// Context attached to the errors caused by a locked file, when its path is
+/// known.
+#[derive(Debug)]
+struct LockedFile(PathBuf);
+
+impl fmt::Display for LockedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' is used by another process", self.0.display())
+    }
+}
+
+/// Indicates whether `err` was caused by a file being locked by another
+/// process (e.g. a GRF opened by the game client). Files are only locked on
+/// Windows.
+pub fn is_locked_file_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let io_error = match cause.downcast_ref::<GrufError>() {
+            Some(GrufError::IoError(io_error)) => Some(io_error),
+            _ => cause.downcast_ref::<io::Error>(),
+        };
+        io_error.map_or(false, is_sharing_violation)
+    })
+}
+
+fn is_sharing_violation(io_error: &io::Error) -> bool {
+    cfg!(windows)
+        && matches!(
+            io_error.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+        )
+}
+
+/// Attaches `file_path` to `res`'s error if it's been caused by a locked file.
+pub fn with_locked_file_path<T>(res: Result<T>, file_path: impl AsRef<Path>) -> Result<T> {
+    res.map_err(|err| {
+        if is_locked_file_error(&err) {
+            err.context(LockedFile(file_path.as_ref().to_path_buf()))
+        } else {
+            err
+        }
+    })
+}
+
+/// Returns the path of the locked file that caused `err`, if known.
+pub fn locked_file_path(err: &anyhow::Error) -> Option<&Path> {
+    err.downcast_ref::<LockedFile>()
+        .map(|locked_file| locked_file.0.as_path())
+}
+
+/// Indicates whether the file located at `file_path` is currently locked by
+/// another process.
+pub fn is_file_locked(file_path: impl AsRef<Path>) -> bool {
+    match OpenOptions::new().read(true).write(true).open(file_path) {
+        Ok(_) => false,
+        Err(e) => is_sharing_violation(&e),
+    }
+}
+
+/// Returns the path of the file that replaces the file located at `path` on
+/// the next start, when it's locked.
+fn pending_file_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut pending_path = OsString::from(path.as_ref());
+    pending_path.push(".pending");
+    PathBuf::from(pending_path)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct PendingRename {
+    source: PathBuf,
+    destination: PathBuf,
+}
+
+/// Files that couldn't be modified because they were locked, and whose
+/// modified copy replaces them the next time the patcher starts.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PendingRenames {
+    renames: Vec<PendingRename>,
+}
+
+impl PendingRenames {
+    /// Reads the renames saved in `file_path`. There's none if the file
+    /// doesn't exist.
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Self> {
+        match File::open(file_path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .context("Failed to deserialize pending renames"),
+        }
+    }
+
+    /// Saves the renames into `file_path`, which is removed if there's none
+    /// left.
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        if self.renames.is_empty() {
+            return match fs::remove_file(file_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let file = File::create(file_path)?;
+        serde_json::to_writer(file, self).context("Failed to serialize pending renames")
+    }
+
+    /// Copies the file located at `file_path`, which is locked, so that the
+    /// copy gets modified in its place and replaces it on the next start.
+    /// Does nothing if the file has already been copied.
+    pub fn schedule_replacement(&mut self, file_path: impl AsRef<Path>) -> Result<()> {
+        let file_path = file_path.as_ref();
+        if self
+            .renames
+            .iter()
+            .any(|rename| rename.destination == file_path)
+        {
+            return Ok(());
+        }
+        let pending_file_path = pending_file_path(file_path);
+        fs::copy(file_path, &pending_file_path)
+            .with_context(|| format!("Failed to copy '{}'", file_path.display()))?;
+        log::info!(
+            "'{}' will be replaced with '{}' on the next start",
+            file_path.display(),
+            pending_file_path.display()
+        );
+        self.renames.push(PendingRename {
+            source: pending_file_path,
+            destination: file_path.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    /// Returns the path of the file that stands for the file located at
+    /// `path` until the pending renames are completed: its replacement, if
+    /// any, or `path` itself.
+    pub fn redirect(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        self.renames
+            .iter()
+            .find(|rename| rename.destination == path)
+            .map_or_else(|| path.to_path_buf(), |rename| rename.source.clone())
+    }
+}
+
+/// Replaces the files listed in `file_path` with their modified copies.
+/// Files that are still locked are kept for later.
+///
+/// Returns the number of files replaced.
+pub fn complete_pending_renames(file_path: impl AsRef<Path>) -> Result<usize> {
+    let pending_renames = PendingRenames::load(&file_path)?;
+    let mut remaining_renames = PendingRenames::default();
+    let mut replaced_count = 0;
+    for rename in pending_renames.renames {
+        match fs::rename(&rename.source, &rename.destination) {
+            Ok(()) => replaced_count += 1,
+            Err(e) if is_sharing_violation(&e) => {
+                log::warn!("'{}' is still locked", rename.destination.display());
+                remaining_renames.renames.push(rename);
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to replace '{}': {}",
+                    rename.destination.display(),
+                    e
+                );
+            }
+        }
+    }
+    remaining_renames.save(file_path)?;
+    Ok(replaced_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_complete_pending_renames() {
+        let temp_dir = tempdir().unwrap();
+        let pending_renames_path = temp_dir.path().join("rpatchur.pending");
+        let grf_path = temp_dir.path().join("data.grf");
+        let pending_grf_path = temp_dir.path().join("data.grf.pending");
+        fs::write(&grf_path, b"original").unwrap();
+        {
+            let mut pending_renames = PendingRenames::load(&pending_renames_path).unwrap();
+            assert_eq!(pending_renames.redirect(&grf_path), grf_path);
+            pending_renames.schedule_replacement(&grf_path).unwrap();
+            pending_renames.save(&pending_renames_path).unwrap();
+        }
+        let pending_renames = PendingRenames::load(&pending_renames_path).unwrap();
+        assert_eq!(pending_renames.redirect(&grf_path), pending_grf_path);
+        assert_eq!(fs::read(&pending_grf_path).unwrap(), b"original");
+        // Patch the copy
+        fs::write(&pending_grf_path, b"patched").unwrap();
+
+        assert_eq!(complete_pending_renames(&pending_renames_path).unwrap(), 1);
+
+        assert_eq!(fs::read(&grf_path).unwrap(), b"patched");
+        assert!(!pending_grf_path.exists());
+        assert!(!pending_renames_path.exists());
+        assert_eq!(complete_pending_renames(&pending_renames_path).unwrap(), 0);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_is_locked_file_error() {
+        let err = anyhow::Error::from(GrufError::IoError(io::Error::from_raw_os_error(
+            ERROR_SHARING_VIOLATION,
+        )))
+        .context("Failed to patch GRF");
+        assert!(is_locked_file_error(&err));
+        let err = with_locked_file_path::<()>(Err(err), "data.grf").unwrap_err();
+        assert_eq!(locked_file_path(&err), Some(Path::new("data.grf")));
+        let err = anyhow::Error::from(io::Error::from(ErrorKind::NotFound));
+        assert!(!is_locked_file_error(&err));
+    }
+}
@@ -1,22 +1,102 @@
+mod about;
+mod backup;
 mod cache;
 mod cancellation;
+mod compression;
 mod config;
 mod core;
+mod credentials;
+mod data_dir;
+mod delta;
+mod disk;
+mod elevation;
+mod extraction;
+mod grf_index;
+mod history;
+mod hooks;
+mod http;
+mod i18n;
+mod installed_files;
+mod locked_files;
+mod news;
+mod patch_format;
 mod patching;
+mod preview;
+mod progress;
+mod remote_config;
+mod repair;
+mod retry;
+mod session_journal;
+mod settings;
+mod signature;
+mod source;
+mod throttling;
+mod tls;
+mod torrent;
+mod update_lock;
 
 use std::env;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
-pub use self::config::{retrieve_patcher_configuration, PatcherConfiguration};
-pub use self::core::patcher_thread_routine;
+pub use self::about::{patcher_info, PatcherChangelog};
+pub use self::cache::remove_cache_file;
+pub use self::config::{
+    resolve_install_directory, retrieve_patcher_configuration, ConfigurationOverride,
+    IpcConfiguration, PatcherConfiguration,
+};
+pub use self::core::{
+    get_history_file_path, get_installed_files_path, patcher_thread_routine, run_patcher_command,
+};
+pub use self::credentials::{
+    build_login_arguments, get_credentials_file_path, store_credentials, Credentials,
+    StoredCredentials,
+};
+pub use self::data_dir::{get_instance_data_file_path, init_data_directory};
+pub use self::history::{format_timestamp, read_history, HISTORY_LIMIT};
+pub use self::i18n::{init_localization, tr};
+pub use self::installed_files::InstalledFiles;
+pub use self::news::NewsItem;
+pub use self::preview::PatchPreview;
+pub use self::remote_config::apply_remote_configuration;
+pub use self::settings::{get_user_settings, get_user_settings_file_path, UserSettings};
 use anyhow::{Context, Result};
+use url::Url;
 
 pub enum PatcherCommand {
     StartUpdate,
-    CancelUpdate,        // Canceled by the user
-    ApplyPatch(PathBuf), // Manual patch submitted by the user
-    Quit,                // Exit requested
+    CheckForUpdates,               // Check for new patches requested by the user
+    FetchNews,                     // News feed requested by the UI
+    FetchChangelog,                // Changelog of the patcher requested by the UI
+    Preview(PreviewMode),          // Dry run of an update, requested by the user
+    CancelUpdate,                  // Canceled by the user
+    PauseUpdate,                   // Paused by the user
+    ResumeUpdate,                  // Resumed by the user
+    ApplyPatch(PathBuf),           // Manual patch submitted by the user
+    ApplyLocal(PathBuf),           // Directory of patches submitted by the user
+    ApplyFile(PathBuf), // Patch file dropped by the user, applied like the patches of an update
+    ApplyRemote(Url),   // Patch located on a patch server, requested through a deep link
+    Rollback,           // Restoration of the files modified by the last update
+    Repair,             // Verification and repair of the client's files
+    SelectChannel(Option<String>), // Channel selected by the user, `None` for the default one
+    ResolveLockedFile(LockedFileAction), // Decision of the user about a locked file
+    UpdateConfiguration(Box<PatcherConfiguration>), // Settings changed by the user
+    Quit,               // Exit requested
+}
+
+/// Indicates how much of the pending patches is fetched to preview an update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreviewMode {
+    FullDownload, // Download the patches like for an actual update
+    HeadersOnly,  // Only fetch the headers and file tables of THOR patches
+}
+
+/// Indicates what to do with a file locked by another process (e.g. the game
+/// client), which prevents a patch from being applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockedFileAction {
+    Retry,    // Try applying the patch again
+    Schedule, // Modify a copy of the file, which replaces it on the next start
 }
 
 pub fn get_patcher_name() -> Result<OsString> {
@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::config::WebConfiguration;
+use super::http::fetch_text;
+
+/// Announcement of the news feed, displayed by the UI.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NewsItem {
+    pub title: String,
+    #[serde(default, alias = "url")]
+    pub link: Option<String>, // Page of the full announcement
+    #[serde(default)]
+    pub date: Option<String>, // Publication date, as given by the feed
+    #[serde(default)]
+    pub summary: Option<String>, // May contain HTML
+}
+
+/// News feeds in JSON are either a list of items or an object with an
+/// 'items' field.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonNewsFeed {
+    Items(Vec<NewsItem>),
+    Object { items: Vec<NewsItem> },
+}
+
+/// Downloads and parses the news feed located at `news_url`.
+pub async fn fetch_news(
+    client: &reqwest::Client,
+    news_url: &str,
+    web_config: &WebConfiguration,
+) -> Result<Vec<NewsItem>> {
+    let content = fetch_text(client, news_url, web_config).await?;
+    parse_news(&content)
+}
+
+/// Parses a news feed, either in JSON or in RSS 2.0.
+pub fn parse_news(content: &str) -> Result<Vec<NewsItem>> {
+    let content = content.trim_start_matches('\u{feff}').trim_start();
+    if content.starts_with('<') {
+        parse_rss(content)
+    } else {
+        let feed: JsonNewsFeed = serde_json::from_str(content).context("Invalid JSON news feed")?;
+        match feed {
+            JsonNewsFeed::Items(items) | JsonNewsFeed::Object { items } => Ok(items),
+        }
+    }
+}
+
+/// Extracts the items of an RSS 2.0 document. Items without a title are
+/// ignored.
+///
+/// Note: This isn't a complete XML parser, only what's needed to read the
+/// usual RSS feeds.
+fn parse_rss(content: &str) -> Result<Vec<NewsItem>> {
+    if find_element(content, "rss").is_none() {
+        return Err(anyhow!("Unsupported news feed format"));
+    }
+    let mut items = vec![];
+    let mut remaining = content;
+    while let Some((item, rest)) = find_element(remaining, "item") {
+        if let Some(title) = element_text(item, "title") {
+            items.push(NewsItem {
+                title,
+                link: element_text(item, "link"),
+                date: element_text(item, "pubDate"),
+                summary: element_text(item, "description"),
+            });
+        }
+        remaining = rest;
+    }
+    Ok(items)
+}
+
+/// Finds the first element named `name` in `content`, returns its content
+/// and what follows it.
+///
+/// Note: Elements nested in an element of the same name aren't supported.
+fn find_element<'a>(content: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let open_tag = format!("<{}", name);
+    let close_tag = format!("</{}>", name);
+    let mut offset = 0;
+    loop {
+        let name_end = offset + content[offset..].find(&open_tag)? + open_tag.len();
+        let tag_end = name_end + content[name_end..].find('>')?;
+        // Skip elements whose name starts with `name` (e.g. 'itemized')
+        let next_char = content[name_end..].chars().next()?;
+        if next_char != '>' && next_char != '/' && !next_char.is_whitespace() {
+            offset = name_end;
+            continue;
+        }
+        if content[..tag_end].ends_with('/') {
+            return Some(("", &content[tag_end + 1..]));
+        }
+        let body_start = tag_end + 1;
+        let body_end = body_start + content[body_start..].find(&close_tag)?;
+        return Some((
+            &content[body_start..body_end],
+            &content[body_end + close_tag.len()..],
+        ));
+    }
+}
+
+/// Returns the text of the first element named `name` in `content`, `None`
+/// if there's no such element or if it's empty.
+fn element_text(content: &str, name: &str) -> Option<String> {
+    let (text, _) = find_element(content, name)?;
+    let text = text.trim();
+    let text = match text
+        .strip_prefix("<![CDATA[")
+        .and_then(|text| text.strip_suffix("]]>"))
+    {
+        Some(raw_text) => raw_text.trim().to_string(),
+        None => decode_entities(text),
+    };
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Replaces XML character references (e.g. '&amp;', '&#233;') with the
+/// characters they represent. Invalid references are kept as is.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut remaining = text;
+    while let Some(start) = remaining.find('&') {
+        decoded.push_str(&remaining[..start]);
+        remaining = &remaining[start..];
+        let reference = remaining
+            .find(';')
+            .and_then(|end| decode_entity(&remaining[1..end]).map(|c| (c, end)));
+        match reference {
+            Some((c, end)) => {
+                decoded.push(c);
+                remaining = &remaining[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                remaining = &remaining[1..];
+            }
+        }
+    }
+    decoded.push_str(remaining);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(&['x', 'X'][..]) {
+                Some(hex_code) => u32::from_str_radix(hex_code, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json_news() {
+        let expected_items = vec![
+            NewsItem {
+                title: "Maintenance".to_string(),
+                link: Some("https://example.com/news/1".to_string()),
+                date: Some("2021-05-01".to_string()),
+                summary: None,
+            },
+            NewsItem {
+                title: "Event".to_string(),
+                link: None,
+                date: None,
+                summary: Some("<b>Double EXP</b>".to_string()),
+            },
+        ];
+        let items = parse_news(
+            r#"[
+                {"title": "Maintenance", "url": "https://example.com/news/1", "date": "2021-05-01"},
+                {"title": "Event", "summary": "<b>Double EXP</b>"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(items, expected_items);
+        let items = parse_news(&format!(
+            r#"{{"items": {}}}"#,
+            serde_json::to_string(&expected_items).unwrap()
+        ))
+        .unwrap();
+        assert_eq!(items, expected_items);
+        assert!(parse_news("{\"title\": \"Maintenance\"}").is_err());
+    }
+
+    #[test]
+    fn test_parse_rss_news() {
+        let items = parse_news(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <rss version="2.0">
+              <channel>
+                <title>My Server</title>
+                <image><url>https://example.com/logo.png</url></image>
+                <item>
+                  <title>Patch notes &amp; fixes</title>
+                  <link>https://example.com/news/2</link>
+                  <pubDate>Sat, 01 May 2021 10:00:00 GMT</pubDate>
+                  <description><![CDATA[<p>New maps & quests</p>]]></description>
+                </item>
+                <item>
+                  <title>Caf&#233; &#x263A;</title>
+                  <description/>
+                </item>
+                <item><link>https://example.com/untitled</link></item>
+              </channel>
+            </rss>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            items,
+            vec![
+                NewsItem {
+                    title: "Patch notes & fixes".to_string(),
+                    link: Some("https://example.com/news/2".to_string()),
+                    date: Some("Sat, 01 May 2021 10:00:00 GMT".to_string()),
+                    summary: Some("<p>New maps & quests</p>".to_string()),
+                },
+                NewsItem {
+                    title: "Caf\u{e9} \u{263a}".to_string(),
+                    link: None,
+                    date: None,
+                    summary: None,
+                },
+            ]
+        );
+        assert!(parse_news("<html><body></body></html>").is_err());
+    }
+}
@@ -0,0 +1,422 @@
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use gruf::grf::{GrfArchive, GrfArchiveBuilder};
+use gruf::{decode_name, NameEncoding};
+
+use super::patching::{
+    create_missing_grf, destination_path, is_legacy_grf, is_protected, protected_entries,
+    resolve_path_case, upgrade_legacy_grf, GrfEntryNames, PatchProgress, PathMatching,
+    UnsafePathHandling,
+};
+
+// Entries without this flag are directories
+const GRF_FILE_ENTRY_FLAG: u8 = 0x01;
+
+/// Indicates the format of a patch, deduced from its file name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatchFormat {
+    Thor, // THOR archive, extracted to the game directory or merged into a GRF
+    Rgz,  // Gzipped list of files, extracted to the game directory
+    Gpf,  // GRF archive, merged into a GRF
+}
+
+impl PatchFormat {
+    /// Returns the format of a patch given its file name. Patches with an
+    /// unknown extension are considered to be THOR archives.
+    pub fn from_file_name(file_name: impl AsRef<Path>) -> Self {
+        let extension = file_name
+            .as_ref()
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "rgz" => PatchFormat::Rgz,
+            "gpf" => PatchFormat::Gpf,
+            _ => PatchFormat::Thor,
+        }
+    }
+}
+
+/// Entry of an RGZ archive.
+#[derive(Debug, PartialEq)]
+enum RgzEntry {
+    Directory(String),
+    File(String, Vec<u8>),
+}
+
+/// Parses the entries of an RGZ archive.
+///
+/// RGZ archives are gzipped streams of records made of a type ('d' for
+/// directories, 'f' for files, 'e' for the end of the archive), the size of
+/// the name, the NUL-terminated name and, for files, the size of the content
+/// followed by the content itself.
+fn parse_rgz_entries<R: Read>(rgz: R, name_encoding: NameEncoding) -> Result<Vec<RgzEntry>> {
+    let mut decoder = GzDecoder::new(rgz);
+    let mut entries = vec![];
+    loop {
+        let mut entry_type = [0u8; 1];
+        match decoder.read_exact(&mut entry_type) {
+            Ok(()) => {}
+            // Some tools don't bother writing the end record
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context("Failed to decompress RGZ archive"),
+        }
+        let mut name_size = [0u8; 1];
+        decoder.read_exact(&mut name_size)?;
+        let mut name_bytes = vec![0u8; name_size[0] as usize];
+        decoder.read_exact(&mut name_bytes)?;
+        let name_end = name_bytes
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(name_bytes.len());
+        let name = decode_name(&name_bytes[..name_end], name_encoding)?;
+        match entry_type[0] {
+            b'd' => entries.push(RgzEntry::Directory(name)),
+            b'f' => {
+                let mut content_size = [0u8; 4];
+                decoder.read_exact(&mut content_size)?;
+                let mut content = vec![0u8; u32::from_le_bytes(content_size) as usize];
+                decoder
+                    .read_exact(&mut content)
+                    .with_context(|| format!("Failed to read '{}'", name))?;
+                entries.push(RgzEntry::File(name, content));
+            }
+            b'e' => break,
+            other => return Err(anyhow!("Invalid RGZ entry type 0x{:02x}", other)),
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the paths of the files contained in an RGZ archive.
+pub fn list_rgz_files<R: Read>(rgz: R, name_encoding: NameEncoding) -> Result<Vec<String>> {
+    Ok(parse_rgz_entries(rgz, name_encoding)?
+        .into_iter()
+        .filter_map(|entry| match entry {
+            RgzEntry::Directory(_) => None,
+            RgzEntry::File(relative_path, _) => Some(relative_path),
+        })
+        .collect())
+}
+
+/// Patches files located in the game client's directory with an RGZ archive.
+///
+/// Entries are never extracted outside of `root_directory`. `on_progress` is
+/// called each time an entry has been extracted.
+///
+/// Files matching `protected_files` are skipped. Returns their paths.
+pub fn apply_rgz_patch<R: Read>(
+    root_directory: impl AsRef<Path>,
+    rgz: R,
+    name_encoding: NameEncoding,
+    unsafe_path_handling: UnsafePathHandling,
+    path_matching: PathMatching,
+    protected_files: &[String],
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    let mut entries = parse_rgz_entries(rgz, name_encoding)?;
+    let protected_paths = protected_entries(
+        entries.iter().filter_map(|entry| match entry {
+            RgzEntry::Directory(_) => None,
+            RgzEntry::File(relative_path, _) => Some(relative_path.as_str()),
+        }),
+        protected_files,
+    );
+    entries.retain(|entry| match entry {
+        RgzEntry::Directory(_) => true,
+        RgzEntry::File(relative_path, _) => !is_protected(relative_path, protected_files),
+    });
+    // Validate all the paths before modifying anything
+    let mut extracted_entries: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (relative_path, content) = match entry {
+            RgzEntry::Directory(relative_path) => (relative_path, None),
+            RgzEntry::File(relative_path, content) => (relative_path, Some(content)),
+        };
+        if let Some(dest_path) = destination_path(
+            root_directory.as_ref(),
+            &relative_path,
+            unsafe_path_handling,
+        )? {
+            extracted_entries.push((dest_path, content));
+        }
+    }
+    let mut progress = PatchProgress::new(extracted_entries.len());
+    for (dest_path, content) in extracted_entries {
+        let dest_path = resolve_path_case(root_directory.as_ref(), dest_path, path_matching);
+        match content {
+            None => {
+                fs::create_dir_all(dest_path)?;
+                progress.advance(0, on_progress);
+            }
+            Some(content) => {
+                // Create parent directory if needed
+                if let Some(parent_dir) = dest_path.parent() {
+                    fs::create_dir_all(parent_dir)?
+                }
+                fs::write(dest_path, &content)?;
+                progress.advance(content.len() as u64, on_progress);
+            }
+        }
+    }
+    Ok(protected_paths)
+}
+
+/// Returns the paths of the files contained in a GPF archive.
+pub fn list_gpf_files(
+    gpf_file_path: impl AsRef<Path>,
+    name_encoding: NameEncoding,
+) -> Result<Vec<String>> {
+    let gpf_archive = GrfArchive::open_with_encoding(&gpf_file_path, name_encoding)
+        .with_context(|| "Failed to open GPF archive")?;
+    Ok(gpf_archive
+        .get_entries()
+        .filter(|e| e.entry_type & GRF_FILE_ENTRY_FLAG != 0)
+        .map(|e| e.relative_path.clone())
+        .collect())
+}
+
+/// Patches a GRF file with a GPF archive (a GRF meant to be merged into
+/// another).
+///
+/// GPF patches are always merged in an in-place manner. `on_progress` is
+/// called each time an entry has been written to the GRF.
+///
+/// Entries matching `protected_files` are skipped. Returns their paths.
+pub fn apply_gpf_patch(
+    grf_file_path: impl AsRef<Path>,
+    gpf_file_path: impl AsRef<Path>,
+    create_if_needed: bool,
+    name_encoding: NameEncoding,
+    path_matching: PathMatching,
+    protected_files: &[String],
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    let mut gpf_archive = GrfArchive::open_with_encoding(&gpf_file_path, name_encoding)
+        .with_context(|| "Failed to open GPF archive")?;
+    create_missing_grf(&grf_file_path, create_if_needed, name_encoding)?;
+    if is_legacy_grf(&grf_file_path)? {
+        // GRF 1.x archives cannot be written, convert them to GRF 2.0 first
+        log::info!(
+            "Converting '{}' to GRF 2.0",
+            grf_file_path.as_ref().display()
+        );
+        upgrade_legacy_grf(&grf_file_path, name_encoding)?;
+    }
+    let grf_archive = GrfArchive::open_with_encoding(&grf_file_path, name_encoding)?;
+    let mut entry_names = GrfEntryNames::new(&grf_archive, path_matching);
+    let grf_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&grf_file_path)?;
+    let mut builder = GrfArchiveBuilder::from_archive(&grf_archive, grf_file, name_encoding)?;
+    drop(grf_archive);
+    let protected_paths = protected_entries(
+        gpf_archive
+            .get_entries()
+            .filter(|e| e.entry_type & GRF_FILE_ENTRY_FLAG != 0)
+            .map(|e| e.relative_path.as_str()),
+        protected_files,
+    );
+    let mut gpf_entries: Vec<_> = gpf_archive
+        .get_entries()
+        .filter(|e| {
+            e.entry_type & GRF_FILE_ENTRY_FLAG != 0
+                && !is_protected(&e.relative_path, protected_files)
+        })
+        .cloned()
+        .collect();
+    gpf_entries.sort_unstable_by_key(|e| e.offset);
+    let mut progress = PatchProgress::new(gpf_entries.len());
+    for entry in gpf_entries {
+        if let Some(previous_name) = entry_names.rename(&entry.relative_path) {
+            let _ = builder.remove_file(previous_name);
+        }
+        // Note: Encrypted entries of older GRFs are re-compressed
+        builder.import_raw_entry_from_grf(&mut gpf_archive, entry.relative_path)?;
+        progress.advance(entry.size_compressed as u64, on_progress);
+    }
+    builder.finish()?;
+    Ok(protected_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn build_rgz(records: &[(u8, &str, &[u8])]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for (entry_type, name, content) in records {
+            encoder
+                .write_all(&[*entry_type, name.len() as u8 + 1])
+                .unwrap();
+            encoder.write_all(name.as_bytes()).unwrap();
+            encoder.write_all(&[0]).unwrap();
+            if *entry_type == b'f' {
+                encoder
+                    .write_all(&(content.len() as u32).to_le_bytes())
+                    .unwrap();
+                encoder.write_all(content).unwrap();
+            }
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_patch_format_from_file_name() {
+        assert_eq!(PatchFormat::Thor, PatchFormat::from_file_name("1.thor"));
+        assert_eq!(PatchFormat::Rgz, PatchFormat::from_file_name("2.RGZ"));
+        assert_eq!(PatchFormat::Gpf, PatchFormat::from_file_name("3.gpf"));
+        assert_eq!(PatchFormat::Thor, PatchFormat::from_file_name("patch"));
+    }
+
+    #[test]
+    fn test_apply_rgz_patch() {
+        let root_directory = tempdir().unwrap();
+        let rgz = build_rgz(&[
+            (b'd', "data\\empty", b""),
+            (b'f', "data\\file.txt", b"content"),
+            (b'f', "System\\iteminfo.lub", &[0xCC; 512]),
+            (b'e', "end", b""),
+        ]);
+        apply_rgz_patch(
+            &root_directory,
+            rgz.as_slice(),
+            NameEncoding::Windows1252,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .unwrap();
+        assert!(root_directory.path().join("data/empty").is_dir());
+        assert_eq!(
+            b"content".to_vec(),
+            fs::read(root_directory.path().join("data/file.txt")).unwrap()
+        );
+        assert_eq!(
+            vec![0xCC; 512],
+            fs::read(root_directory.path().join("System/iteminfo.lub")).unwrap()
+        );
+
+        // Nothing is extracted if an entry has an unsafe path
+        let rgz = build_rgz(&[
+            (b'f', "data\\other.txt", b"content"),
+            (b'f', "..\\outside.txt", b"content"),
+        ]);
+        assert!(apply_rgz_patch(
+            &root_directory,
+            rgz.as_slice(),
+            NameEncoding::Windows1252,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .is_err());
+        assert!(!root_directory.path().join("data/other.txt").exists());
+        // Invalid archives are rejected
+        assert!(apply_rgz_patch(
+            &root_directory,
+            &b"not an rgz"[..],
+            NameEncoding::Windows1252,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply_gpf_patch() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for gpf_name in &["200-small.grf", "102-small.grf"] {
+            let temp_dir = tempdir().unwrap();
+            let grf_file_path = temp_dir.path().join("data.grf");
+            let gpf_file_path = grf_dir_path.join(gpf_name);
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_file_path).unwrap();
+            apply_gpf_patch(
+                &grf_file_path,
+                &gpf_file_path,
+                false,
+                NameEncoding::Windows1252,
+                PathMatching::Exact,
+                &[],
+                &mut |_| {},
+            )
+            .unwrap();
+
+            let mut gpf_archive = GrfArchive::open(&gpf_file_path).unwrap();
+            let mut grf_archive = GrfArchive::open(&grf_file_path).unwrap();
+            let gpf_entries: Vec<_> = gpf_archive.get_entries().cloned().collect();
+            assert_eq!(gpf_entries.len(), grf_archive.file_count());
+            for entry in gpf_entries {
+                assert_eq!(
+                    gpf_archive.read_file_content(&entry.relative_path).unwrap(),
+                    grf_archive.read_file_content(&entry.relative_path).unwrap()
+                );
+            }
+        }
+        // Legacy GRFs are converted before being patched
+        let temp_dir = tempdir().unwrap();
+        let grf_file_path = temp_dir.path().join("data.grf");
+        let gpf_file_path = grf_dir_path.join("200-small.grf");
+        fs::copy(grf_dir_path.join("103-small.grf"), &grf_file_path).unwrap();
+        apply_gpf_patch(
+            &grf_file_path,
+            &gpf_file_path,
+            false,
+            NameEncoding::Windows1252,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .unwrap();
+        let mut gpf_archive = GrfArchive::open(&gpf_file_path).unwrap();
+        let mut grf_archive = GrfArchive::open(&grf_file_path).unwrap();
+        assert_eq!(grf_archive.version_major(), 2);
+        assert_eq!(grf_archive.file_count(), gpf_archive.file_count());
+        let gpf_entries: Vec<_> = gpf_archive.get_entries().cloned().collect();
+        for entry in gpf_entries {
+            assert_eq!(
+                gpf_archive.read_file_content(&entry.relative_path).unwrap(),
+                grf_archive.read_file_content(&entry.relative_path).unwrap()
+            );
+        }
+        // GRFs are only created when asked to
+        let temp_dir = tempdir().unwrap();
+        let gpf_file_path = grf_dir_path.join("200-small.grf");
+        let grf_file_path = temp_dir.path().join("new.grf");
+        assert!(apply_gpf_patch(
+            &grf_file_path,
+            &gpf_file_path,
+            false,
+            NameEncoding::Windows1252,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .is_err());
+        apply_gpf_patch(
+            &grf_file_path,
+            &gpf_file_path,
+            true,
+            NameEncoding::Windows1252,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .unwrap();
+        assert!(grf_file_path.exists());
+    }
+}
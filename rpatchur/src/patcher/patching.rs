@@ -1,22 +1,129 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::Result;
-use gruf::grf::{GrfArchive, GrfArchiveBuilder};
-use gruf::thor::{ThorArchive, ThorFileEntry};
+use anyhow::{anyhow, Result};
+use gruf::grf::reader::GrfFileEncryption;
+use gruf::grf::{repair_grf_archive, GrfArchive, GrfArchiveBuilder, GrfIndex, GrfJournal};
+use gruf::thor::{ThorArchive, ThorCompression, ThorFileEntry};
+use gruf::{match_entry_path, NameEncoding};
+
+use super::compression::{CompressedEntry, CompressionPool};
+use super::delta::{apply_delta, delta_target_path};
+use super::extraction::ExtractionPool;
+use super::grf_index::{load_grf_index, open_grf_with_cached_index, update_grf_index};
+
+// Entries larger than this are extracted chunk by chunk on the patching
+// thread, instead of being decompressed in memory by extraction workers
+const PARALLEL_EXTRACTION_MAX_SIZE: usize = 16 * 1024 * 1024;
 
 /// Indicates the method that should be used when patching GRF files.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GrfPatchingMethod {
     OutOfPlace,
     InPlace,
+    Journaled, // In-place, through a journal that survives crashes
+}
+
+/// Indicates what to do with entries whose path would make them land outside
+/// of the target directory (e.g. "..\\file" or "C:\\file").
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnsafePathHandling {
+    Reject, // Abort the patching process
+    Skip,   // Ignore the entry and log a warning
+}
+
+/// Indicates how the paths of patches' entries are compared with the paths of
+/// the files they replace, in GRFs and on disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathMatching {
+    Exact,           // Paths that differ in case designate different files
+    CaseInsensitive, // Paths are compared regardless of their (ASCII) case
+}
+
+impl PathMatching {
+    /// Returns the form of `relative_path` used to compare it with other
+    /// paths. Forward slashes are also treated as backslashes when paths are
+    /// compared case-insensitively.
+    pub fn fold(self, relative_path: &str) -> Cow<'_, str> {
+        match self {
+            PathMatching::Exact => Cow::Borrowed(relative_path),
+            PathMatching::CaseInsensitive => {
+                // Note: Names decoded byte per byte must not be lowercased
+                // beyond ASCII, their other characters aren't letters
+                Cow::Owned(relative_path.replace('/', "\\").to_ascii_lowercase())
+            }
+        }
+    }
+}
+
+/// Options of the application of patches to GRFs.
+pub struct GrfPatchingOptions<'a> {
+    pub method: GrfPatchingMethod,
+    pub create_if_needed: bool, // Create the GRF if it doesn't exist
+    pub memory_mapped: bool,    // Read the GRF through a memory mapping
+    // File the GRF's index (its parsed file table) is cached into
+    pub index_cache_file_path: Option<&'a Path>,
+    pub path_matching: PathMatching,
+    pub protected_files: &'a [String], // Patterns of the entries to leave untouched
+}
+
+impl Default for GrfPatchingOptions<'_> {
+    fn default() -> Self {
+        Self {
+            method: GrfPatchingMethod::OutOfPlace,
+            create_if_needed: false,
+            memory_mapped: false,
+            index_cache_file_path: None,
+            path_matching: PathMatching::Exact,
+            protected_files: &[],
+        }
+    }
+}
+
+/// Indicates whether the file located at `relative_path` matches one of the
+/// glob patterns of `protected_files`, in which case patches must never
+/// modify it. Delta entries are protected along with the files they patch.
+pub fn is_protected(relative_path: &str, protected_files: &[String]) -> bool {
+    let target_path = delta_target_path(relative_path).unwrap_or(relative_path);
+    protected_files
+        .iter()
+        .any(|pattern| match_entry_path(pattern, target_path))
+}
+
+/// Returns the paths of the protected files among `relative_paths`, which are
+/// skipped when patching, and logs them.
+pub fn protected_entries<'a>(
+    relative_paths: impl IntoIterator<Item = &'a str>,
+    protected_files: &[String],
+) -> Vec<String> {
+    if protected_files.is_empty() {
+        return vec![];
+    }
+    let mut protected_paths: Vec<String> = relative_paths
+        .into_iter()
+        .filter(|relative_path| is_protected(relative_path, protected_files))
+        .map(|relative_path| {
+            delta_target_path(relative_path)
+                .unwrap_or(relative_path)
+                .to_string()
+        })
+        .collect();
+    protected_paths.sort_unstable();
+    protected_paths.dedup();
+    for relative_path in &protected_paths {
+        log::info!("Skipping protected or excluded file '{}'", relative_path);
+    }
+    protected_paths
 }
 
 /// Indicates the type of archive a "file" comes from.
 enum MergeEntrySource {
     GrfArchive,
-    ThorArchive,
+    ThorArchive(usize), // Index of the patch, in the order of application
 }
 
 /// Indicates the transformation that should be applied to the data when copied
@@ -27,80 +134,468 @@ enum DataTransformation {
 }
 
 struct MergeEntry {
+    pub relative_path: String,
     pub source: MergeEntrySource,
     pub source_offset: u64,
     pub data_size: usize,
     pub transformation: DataTransformation,
 }
 
+/// Progress of the application of a patch, reported after each entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchProgress {
+    pub processed_entries: usize,
+    pub total_entries: usize,
+    pub bytes_written: u64, // Bytes written to GRFs or to disk so far
+    // Name of the GRF being rebuilt out-of-place, in which case its original
+    // entries are accounted for too
+    pub rebuilt_grf: Option<Arc<str>>,
+}
+
+impl PatchProgress {
+    pub fn new(total_entries: usize) -> Self {
+        Self {
+            processed_entries: 0,
+            total_entries,
+            bytes_written: 0,
+            rebuilt_grf: None,
+        }
+    }
+
+    /// Accounts for an entry that has been processed and reports the new
+    /// progress to `on_progress`.
+    pub fn advance(&mut self, bytes_written: u64, on_progress: &mut dyn FnMut(PatchProgress)) {
+        self.processed_entries += 1;
+        self.bytes_written += bytes_written;
+        on_progress(self.clone());
+    }
+}
+
 /// Patches a GRF file with a THOR archive/patch.
+///
+/// `on_progress` is called each time an entry has been written to the GRF.
+///
+/// If patching fails and the GRF turns out to be corrupt, its broken entries
+/// are removed and the patch is applied once more.
+///
+/// When `options.index_cache_file_path` is set, the GRF's index is read from
+/// this file if it's up to date, and saved into it once the GRF has been
+/// patched.
+///
+/// Entries matching `options.protected_files` are skipped. Returns their
+/// paths.
 pub fn apply_patch_to_grf<R: Read + Seek>(
-    patching_method: GrfPatchingMethod,
-    create_if_needed: bool,
     grf_file_path: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
+    options: &GrfPatchingOptions,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    apply_patches_to_grf(
+        grf_file_path,
+        std::slice::from_mut(thor_archive),
+        options,
+        on_progress,
+    )
+}
+
+/// Patches a GRF file with a sequence of THOR archives/patches, in a single
+/// pass. Later patches take precedence over earlier ones.
+///
+/// This behaves like `apply_patch_to_grf` but the GRF is opened (and, when
+/// patched out-of-place, rebuilt) only once. Patches containing delta entries
+/// must be applied one at a time since their base depends on the previous
+/// patches.
+pub fn apply_patches_to_grf<R: Read + Seek>(
+    grf_file_path: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+    options: &GrfPatchingOptions,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
+    let name_encoding = match thor_archives.first() {
+        Some(thor_archive) => thor_archive.name_encoding(),
+        None => return Ok(vec![]),
+    };
+    if thor_archives.len() > 1 && thor_archives.iter().any(contains_delta_entries) {
+        return Err(anyhow!(
+            "Patches containing delta entries cannot be applied together"
+        ));
+    }
+    create_missing_grf(&grf_file_path, options.create_if_needed, name_encoding)?;
+    // Finish modifications interrupted by a crash before anything else
+    recover_grf_journal(&grf_file_path)?;
+    let protected_paths = protected_entries(
+        thor_archives
+            .iter()
+            .flat_map(ThorArchive::get_entries)
+            .filter(|entry| !entry.is_internal())
+            .map(|entry| entry.relative_path.as_str()),
+        options.protected_files,
+    );
+    let result =
+        apply_patches_to_grf_with_method(&grf_file_path, thor_archives, options, on_progress);
+    let result = match result {
+        Err(e) if grf_file_path.as_ref().exists() => {
+            // The GRF might be corrupt, remove its broken entries and retry
+            let report = match repair_grf_archive(&grf_file_path, name_encoding) {
+                Ok(report) if !report.is_valid() => report,
+                _ => return Err(e),
+            };
+            log::warn!(
+                "'{}' was corrupt ({}), {} broken entries removed",
+                grf_file_path.as_ref().display(),
+                e,
+                report.broken_entries.len()
+            );
+            apply_patches_to_grf_with_method(&grf_file_path, thor_archives, options, on_progress)
+        }
+        result => result,
+    };
+    result.map(|_| protected_paths)
+}
+
+fn apply_patches_to_grf_with_method<R: Read + Seek>(
+    grf_file_path: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+    options: &GrfPatchingOptions,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<()> {
+    match options.method {
+        GrfPatchingMethod::InPlace | GrfPatchingMethod::Journaled
+            if is_legacy_grf(&grf_file_path)? =>
+        {
+            // GRF 1.x archives cannot be written, they're converted to GRF 2.0
+            log::info!(
+                "Converting '{}' to GRF 2.0",
+                grf_file_path.as_ref().display()
+            );
+            apply_patches_to_grf_oop(grf_file_path, thor_archives, options, on_progress)
+        }
+        GrfPatchingMethod::InPlace => {
+            apply_patches_to_grf_ip(grf_file_path, thor_archives, options, false, on_progress)
+        }
+        GrfPatchingMethod::Journaled => {
+            apply_patches_to_grf_ip(grf_file_path, thor_archives, options, true, on_progress)
+        }
+        GrfPatchingMethod::OutOfPlace => {
+            apply_patches_to_grf_oop(grf_file_path, thor_archives, options, on_progress)
+        }
+    }
+}
+
+/// Creates an empty GRF 0x200 archive at `grf_file_path`, along with its
+/// parent directories, if it doesn't exist yet. This lets patches bootstrap a
+/// fresh installation. Fails if the GRF is missing and `create_if_needed` is
+/// false.
+pub fn create_missing_grf(
+    grf_file_path: impl AsRef<Path>,
+    create_if_needed: bool,
+    name_encoding: NameEncoding,
+) -> Result<()> {
+    let grf_file_path = grf_file_path.as_ref();
+    if grf_file_path.exists() {
+        return Ok(());
+    }
+    if !create_if_needed {
+        return Err(anyhow!(
+            "'{}' doesn't exist (see `patching.create_grf`)",
+            grf_file_path.display()
+        ));
+    }
+    log::info!("Creating '{}'", grf_file_path.display());
+    if let Some(parent_directory) = grf_file_path.parent() {
+        fs::create_dir_all(parent_directory)?;
+    }
+    let new_grf = fs::File::create(grf_file_path)?;
+    let mut builder = GrfArchiveBuilder::create_with_encoding(new_grf, 2, 0, name_encoding)?;
+    Ok(builder.finish()?)
+}
+
+/// Returns true if the GRF located at `grf_file_path` uses the 0x102 or 0x103
+/// format.
+pub fn is_legacy_grf(grf_file_path: impl AsRef<Path>) -> Result<bool> {
+    let (version_major, _) = GrfArchive::read_version(grf_file_path)?;
+    Ok(version_major < 2)
+}
+
+/// Rebuilds a GRF 1.x archive as a GRF 2.0 archive, so that it can be patched
+/// in place.
+pub fn upgrade_legacy_grf(
+    grf_file_path: impl AsRef<Path>,
+    name_encoding: NameEncoding,
 ) -> Result<()> {
-    if !grf_file_path.as_ref().exists() && create_if_needed {
-        // Create a new GRF file if needed
-        let new_grf = fs::File::create(&grf_file_path)?;
-        GrfArchiveBuilder::create(new_grf, 2, 0)?;
+    let mut backup_file_path = grf_file_path.as_ref().to_path_buf();
+    backup_file_path.set_extension("grf.bak");
+    fs::rename(grf_file_path.as_ref(), &backup_file_path)?;
+    {
+        let mut grf_archive = GrfArchive::open_with_encoding(&backup_file_path, name_encoding)?;
+        let grf_file = fs::File::create(grf_file_path)?;
+        let mut builder = GrfArchiveBuilder::create_with_encoding(grf_file, 2, 0, name_encoding)?;
+        let entry_paths: Vec<String> = grf_archive
+            .get_entries()
+            .map(|entry| entry.relative_path.clone())
+            .collect();
+        for relative_path in entry_paths {
+            builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
+        }
+        builder.finish()?;
+    }
+    // Remove backup file once the new GRF has been built
+    Ok(fs::remove_file(backup_file_path)?)
+}
+
+/// Applies the journal left by an interrupted in-place patching of the GRF
+/// located at `grf_file_path`, if any.
+pub fn recover_grf_journal(grf_file_path: impl AsRef<Path>) -> Result<()> {
+    if GrfJournal::recover(&grf_file_path)? {
+        log::info!(
+            "Recovered interrupted modifications of '{}'",
+            grf_file_path.as_ref().display()
+        );
     }
-    match patching_method {
-        GrfPatchingMethod::InPlace => apply_patch_to_grf_ip(grf_file_path, thor_archive),
-        GrfPatchingMethod::OutOfPlace => apply_patch_to_grf_oop(grf_file_path, thor_archive),
+    Ok(())
+}
+
+/// Recovers the journals of the GRFs located in `directory`.
+pub fn recover_grf_journals(directory: impl AsRef<Path>) -> Result<()> {
+    for dir_entry in fs::read_dir(directory)? {
+        if let Some(grf_file_path) = GrfJournal::grf_path(dir_entry?.path()) {
+            recover_grf_journal(grf_file_path)?;
+        }
     }
+    Ok(())
 }
 
 /// Patches a GRF in an in-place manner.
 ///
 /// This is faster but produces output of bigger size and can corrupt file in
-/// case of error.
-fn apply_patch_to_grf_ip<R: Read + Seek>(
+/// case of error, unless `journaled` is set. In that case, modifications are
+/// written to a journal first and the GRF is only modified once the journal
+/// is complete.
+fn apply_patches_to_grf_ip<R: Read + Seek>(
     grf_file_path: impl AsRef<Path>,
-    thor_archive: &mut ThorArchive<R>,
+    thor_archives: &mut [ThorArchive<R>],
+    options: &GrfPatchingOptions,
+    journaled: bool,
+    on_progress: &mut dyn FnMut(PatchProgress),
 ) -> Result<()> {
-    let mut builder = GrfArchiveBuilder::open(grf_file_path)?;
-    let mut thor_entries: Vec<ThorFileEntry> = thor_archive
-        .get_entries()
-        .filter(|e| !e.is_internal())
-        .cloned()
+    // Entries' names are decoded and written with the patches' encoding so
+    // that they match
+    let name_encoding = thor_archives[0].name_encoding();
+    let mut grf_archive = open_grf_with_cached_index(
+        fs::File::open(&grf_file_path)?,
+        &grf_file_path,
+        name_encoding,
+        options.index_cache_file_path,
+    )?;
+    let entry_names = GrfEntryNames::new(&grf_archive, options.path_matching);
+    // Apply delta entries before modifying the GRF
+    let patched_files = match thor_archives {
+        [thor_archive] if contains_delta_entries(thor_archive) => {
+            apply_delta_entries(thor_archive, options.protected_files, |path| {
+                grf_archive
+                    .read_file_content(entry_names.resolve(path))
+                    .ok()
+            })?
+        }
+        _ => HashMap::new(),
+    };
+    let index = if journaled {
+        let mut journal = GrfJournal::create(&grf_file_path)?;
+        let index = {
+            let mut builder =
+                GrfArchiveBuilder::from_archive(&grf_archive, &mut journal, name_encoding)?;
+            drop(grf_archive);
+            write_patch_entries(
+                &mut builder,
+                thor_archives,
+                patched_files,
+                entry_names,
+                options.protected_files,
+                on_progress,
+            )?;
+            builder.finish()?;
+            builder.index()
+        };
+        journal.commit()?;
+        index
+    } else {
+        let grf_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&grf_file_path)?;
+        let mut builder = GrfArchiveBuilder::from_archive(&grf_archive, grf_file, name_encoding)?;
+        drop(grf_archive);
+        write_patch_entries(
+            &mut builder,
+            thor_archives,
+            patched_files,
+            entry_names,
+            options.protected_files,
+            on_progress,
+        )?;
+        builder.finish()?;
+        builder.index()
+    };
+    update_grf_index(
+        options.index_cache_file_path,
+        grf_file_path,
+        name_encoding,
+        index,
+    );
+    Ok(())
+}
+
+/// Writes the entries of `thor_archives`, in order, and the files patched
+/// with delta entries into a GRF. Entries that are replaced under another
+/// name (i.e. with a different case) are removed, protected entries are
+/// skipped.
+fn write_patch_entries<W: Write + Seek, R: Read + Seek>(
+    builder: &mut GrfArchiveBuilder<W>,
+    thor_archives: &mut [ThorArchive<R>],
+    patched_files: HashMap<String, Vec<u8>>,
+    mut entry_names: GrfEntryNames,
+    protected_files: &[String],
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<()> {
+    let thor_entries: Vec<Vec<ThorFileEntry>> = thor_archives
+        .iter()
+        .map(|thor_archive| {
+            let mut entries: Vec<ThorFileEntry> = thor_archive
+                .get_entries()
+                .filter(|e| {
+                    !e.is_internal()
+                        && !is_handled_by_delta(e, &patched_files)
+                        && !is_protected(&e.relative_path, protected_files)
+                })
+                .cloned()
+                .collect();
+            entries.sort_unstable_by(|a, b| a.offset.cmp(&b.offset));
+            entries
+        })
         .collect();
-    thor_entries.sort_unstable_by(|a, b| a.offset.cmp(&b.offset));
-    for entry in thor_entries {
-        if entry.is_removed {
-            let _ = builder.remove_file(&entry.relative_path);
-        } else {
-            builder.import_raw_entry_from_thor(thor_archive, entry.relative_path)?;
+    let total_entries = thor_entries.iter().map(Vec::len).sum::<usize>() + patched_files.len();
+    let mut progress = PatchProgress::new(total_entries);
+    for (thor_archive, entries) in thor_archives.iter_mut().zip(thor_entries) {
+        for entry in entries {
+            if entry.is_removed {
+                let _ = builder.remove_file(entry_names.remove(&entry.relative_path));
+                progress.advance(0, on_progress);
+            } else {
+                if let Some(previous_name) = entry_names.rename(&entry.relative_path) {
+                    let _ = builder.remove_file(previous_name);
+                }
+                builder.import_raw_entry_from_thor(thor_archive, entry.relative_path)?;
+                progress.advance(entry.size_compressed as u64, on_progress);
+            }
+        }
+    }
+    for (relative_path, content) in patched_files {
+        if let Some(previous_name) = entry_names.rename(&relative_path) {
+            let _ = builder.remove_file(previous_name);
         }
+        builder.add_file(relative_path, content.as_slice())?;
+        progress.advance(content.len() as u64, on_progress);
     }
     Ok(())
 }
 
 /// Patches a GRF in an out-of-place manner.
 ///
-/// This is safer and produces output of smaller size but slower.
-fn apply_patch_to_grf_oop<R: Read + Seek>(
+/// This is safer and produces output of smaller size but slower. The original
+/// GRF is restored if patching fails.
+fn apply_patches_to_grf_oop<R: Read + Seek>(
     grf_file_path: impl AsRef<Path>,
-    thor_archive: &mut ThorArchive<R>,
+    thor_archives: &mut [ThorArchive<R>],
+    options: &GrfPatchingOptions,
+    on_progress: &mut dyn FnMut(PatchProgress),
 ) -> Result<()> {
+    let name_encoding = thor_archives[0].name_encoding();
+    let index_cache_file_path = options.index_cache_file_path;
+    // Look the index up before the GRF is renamed
+    let original_index = index_cache_file_path.and_then(|index_cache_file_path| {
+        load_grf_index(index_cache_file_path, &grf_file_path, name_encoding)
+    });
     // Rename file to back it up
     let mut backup_file_path = grf_file_path.as_ref().to_path_buf();
     backup_file_path.set_extension("grf.bak");
     fs::rename(grf_file_path.as_ref(), &backup_file_path)?;
 
-    // Prepare file entries that'll be used to make the patched GRF
+    // Note: The original GRF must be closed before removing its backup
+    let result = if options.memory_mapped {
+        match original_index {
+            Some(index) => GrfArchive::open_mmap_with_index(&backup_file_path, index),
+            None => GrfArchive::open_mmap_with_encoding(&backup_file_path, name_encoding),
+        }
+        .map_err(anyhow::Error::from)
+        .and_then(|mut grf_archive| {
+            merge_patches_into_grf(
+                &mut grf_archive,
+                &grf_file_path,
+                thor_archives,
+                options,
+                on_progress,
+            )
+        })
+    } else {
+        match original_index {
+            Some(index) => GrfArchive::open_with_index(&backup_file_path, index),
+            None => GrfArchive::open_with_encoding(&backup_file_path, name_encoding),
+        }
+        .map_err(anyhow::Error::from)
+        .and_then(|mut grf_archive| {
+            merge_patches_into_grf(
+                &mut grf_archive,
+                &grf_file_path,
+                thor_archives,
+                options,
+                on_progress,
+            )
+        })
+    };
+    let index = match result {
+        Ok(index) => index,
+        Err(e) => {
+            // Put the original GRF back in place
+            let _ = fs::remove_file(grf_file_path.as_ref());
+            fs::rename(&backup_file_path, grf_file_path.as_ref())?;
+            return Err(e);
+        }
+    };
+    update_grf_index(index_cache_file_path, grf_file_path, name_encoding, index);
+    // Remove backup file once the patched GRF has been built
+    Ok(fs::remove_file(backup_file_path)?)
+}
+
+/// Writes a new GRF at `grf_file_path`, made of the entries of `grf_archive`
+/// patched with `thor_archives`. Returns the index of the new GRF.
+fn merge_patches_into_grf<G: Read + Seek, R: Read + Seek>(
+    grf_archive: &mut GrfArchive<G>,
+    grf_file_path: impl AsRef<Path>,
+    thor_archives: &mut [ThorArchive<R>],
+    options: &GrfPatchingOptions,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Option<GrfIndex>> {
+    let path_matching = options.path_matching;
+    // Prepare file entries that'll be used to make the patched GRF, indexed
+    // by their folded path
     let mut merge_entries: HashMap<String, MergeEntry> = HashMap::new();
-    // Add files from the original archive while discarding files remove in the patch
-    let mut grf_archive = GrfArchive::open(&backup_file_path)?;
-    for entry in grf_archive.get_entries() {
-        if let Some(e) = thor_archive.get_file_entry(&entry.relative_path) {
-            if e.is_removed {
-                continue;
-            }
+    let patched_files = match thor_archives {
+        [thor_archive] if contains_delta_entries(thor_archive) => {
+            let entry_names = GrfEntryNames::new(grf_archive, path_matching);
+            apply_delta_entries(thor_archive, options.protected_files, |path| {
+                grf_archive
+                    .read_file_content(entry_names.resolve(path))
+                    .ok()
+            })?
         }
+        _ => HashMap::new(),
+    };
+    // Add files from the original archive
+    for entry in grf_archive.get_entries() {
         merge_entries.insert(
-            entry.relative_path.clone(),
+            path_matching.fold(&entry.relative_path).into_owned(),
             MergeEntry {
+                relative_path: entry.relative_path.clone(),
                 source: MergeEntrySource::GrfArchive,
                 source_offset: entry.offset,
                 data_size: entry.size_compressed,
@@ -108,85 +603,459 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
             },
         );
     }
-    // Add files from the patch
-    for entry in thor_archive.get_entries() {
-        if entry.is_removed || entry.is_internal() {
-            continue;
+    for relative_path in patched_files.keys() {
+        merge_entries.remove(path_matching.fold(relative_path).as_ref());
+    }
+    // Add files from the patches, in order, while discarding removed files
+    for (archive_index, thor_archive) in thor_archives.iter().enumerate() {
+        for entry in thor_archive.get_entries() {
+            if entry.is_internal()
+                || is_handled_by_delta(entry, &patched_files)
+                || is_protected(&entry.relative_path, options.protected_files)
+            {
+                continue;
+            }
+            let folded_path = path_matching.fold(&entry.relative_path);
+            if entry.is_removed {
+                merge_entries.remove(folded_path.as_ref());
+                continue;
+            }
+            merge_entries.insert(
+                folded_path.into_owned(),
+                MergeEntry {
+                    relative_path: entry.relative_path.clone(),
+                    source: MergeEntrySource::ThorArchive(archive_index),
+                    source_offset: entry.offset,
+                    data_size: entry.size_compressed,
+                    transformation: DataTransformation::None,
+                },
+            );
         }
-        merge_entries.insert(
-            entry.relative_path.clone(),
-            MergeEntry {
-                source: MergeEntrySource::ThorArchive,
-                source_offset: entry.offset,
-                data_size: entry.size_compressed,
-                transformation: DataTransformation::None,
-            },
-        );
     }
 
-    {
-        let grf_file = fs::File::create(grf_file_path)?;
-        let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0)?;
-        for (relative_path, entry) in merge_entries {
-            match entry.source {
-                MergeEntrySource::GrfArchive => {
-                    builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
+    let grf_file = fs::File::create(&grf_file_path)?;
+    // Keep 64-bit offsets for GRF 3.0 archives, use GRF 2.0 otherwise
+    let (version_major, version_minor) = match grf_archive.version_major() {
+        3 => (3, grf_archive.version_minor()),
+        _ => (2, 0),
+    };
+    let mut builder = GrfArchiveBuilder::create_with_encoding(
+        grf_file,
+        version_major,
+        version_minor,
+        thor_archives[0].name_encoding(),
+    )?;
+    // Every entry of the patched GRF is written, including the original ones
+    let mut progress = PatchProgress {
+        rebuilt_grf: grf_file_path
+            .as_ref()
+            .file_name()
+            .map(|grf_name| grf_name.to_string_lossy().into()),
+        ..PatchProgress::new(merge_entries.len() + patched_files.len())
+    };
+    // Entries that cannot be copied as is are compressed in parallel while
+    // the other ones are copied
+    let compression_pool = CompressionPool::new();
+    for entry in merge_entries.into_values() {
+        let relative_path = entry.relative_path;
+        let content_to_compress = match entry.source {
+            MergeEntrySource::GrfArchive => match grf_archive.get_file_entry(&relative_path) {
+                Some(e) if e.encryption != GrfFileEncryption::Unencrypted => {
+                    Some(grf_archive.read_file_content(&relative_path)?)
                 }
-                MergeEntrySource::ThorArchive => {
-                    builder.import_raw_entry_from_thor(thor_archive, relative_path)?;
+                _ => None,
+            },
+            MergeEntrySource::ThorArchive(archive_index) => {
+                let thor_archive = &mut thor_archives[archive_index];
+                match thor_archive.get_file_entry(&relative_path) {
+                    Some(e) if e.compression != ThorCompression::Zlib || e.is_encrypted() => {
+                        Some(thor_archive.read_file_content(&relative_path)?)
+                    }
+                    _ => None,
                 }
             }
+        };
+        match content_to_compress {
+            Some(content) => compression_pool.submit(relative_path, content)?,
+            None => {
+                match entry.source {
+                    MergeEntrySource::GrfArchive => {
+                        builder.import_raw_entry_from_grf(grf_archive, relative_path)?;
+                    }
+                    MergeEntrySource::ThorArchive(archive_index) => {
+                        builder.import_raw_entry_from_thor(
+                            &mut thor_archives[archive_index],
+                            relative_path,
+                        )?;
+                    }
+                }
+                progress.advance(entry.data_size as u64, on_progress);
+            }
         }
+        add_compressed_entries(
+            &mut builder,
+            compression_pool.completed_entries(),
+            &mut progress,
+            on_progress,
+        )?;
+    }
+    for (relative_path, content) in patched_files {
+        compression_pool.submit(relative_path, content)?;
+    }
+    add_compressed_entries(
+        &mut builder,
+        compression_pool.finish(),
+        &mut progress,
+        on_progress,
+    )?;
+    builder.finish()?;
+    Ok(builder.index())
+}
+
+/// Adds entries compressed by a `CompressionPool` to a GRF.
+fn add_compressed_entries<W: Write + Seek>(
+    builder: &mut GrfArchiveBuilder<W>,
+    compressed_entries: impl IntoIterator<Item = Result<CompressedEntry>>,
+    progress: &mut PatchProgress,
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<()> {
+    for compressed_entry in compressed_entries {
+        let compressed_entry = compressed_entry?;
+        builder.add_compressed_file(
+            compressed_entry.relative_path,
+            compressed_entry.data_size,
+            &compressed_entry.compressed_data,
+        )?;
+        progress.advance(compressed_entry.compressed_data.len() as u64, on_progress);
+    }
+    Ok(())
+}
+
+/// Names of the entries of a GRF, used to find the entry a path designates
+/// when paths aren't compared exactly.
+pub struct GrfEntryNames {
+    path_matching: PathMatching,
+    names: HashMap<String, String>, // Folded path -> name of the entry
+}
+
+impl GrfEntryNames {
+    pub fn new<R: Read + Seek>(grf_archive: &GrfArchive<R>, path_matching: PathMatching) -> Self {
+        let names = match path_matching {
+            PathMatching::Exact => HashMap::new(),
+            PathMatching::CaseInsensitive => grf_archive
+                .get_entries()
+                .map(|entry| {
+                    (
+                        path_matching.fold(&entry.relative_path).into_owned(),
+                        entry.relative_path.clone(),
+                    )
+                })
+                .collect(),
+        };
+        Self {
+            path_matching,
+            names,
+        }
+    }
+
+    /// Returns the name of the entry designated by `relative_path`.
+    pub fn resolve<'a>(&'a self, relative_path: &'a str) -> &'a str {
+        match self
+            .names
+            .get(self.path_matching.fold(relative_path).as_ref())
+        {
+            Some(name) => name,
+            None => relative_path,
+        }
+    }
+
+    /// Records that the entry designated by `relative_path` is now named
+    /// `relative_path`. Returns its previous name if it was different.
+    pub fn rename(&mut self, relative_path: &str) -> Option<String> {
+        if self.path_matching == PathMatching::Exact {
+            return None;
+        }
+        let folded_path = self.path_matching.fold(relative_path).into_owned();
+        match self.names.insert(folded_path, relative_path.to_string()) {
+            Some(previous_name) if previous_name != relative_path => Some(previous_name),
+            _ => None,
+        }
+    }
+
+    /// Forgets the entry designated by `relative_path` and returns its name.
+    pub fn remove(&mut self, relative_path: &str) -> String {
+        self.names
+            .remove(self.path_matching.fold(relative_path).as_ref())
+            .unwrap_or_else(|| relative_path.to_string())
     }
-    // Remove backup file once the patched GRF has been built
-    Ok(fs::remove_file(backup_file_path)?)
 }
 
 /// Patches files located in the game client's directory with a THOR
 /// archive/patch.
+///
+/// Entries are never extracted outside of `root_directory`. `on_progress` is
+/// called each time an entry has been extracted or removed.
+///
+/// When paths are compared case-insensitively, existing files and directories
+/// are updated in place even if their case differs from the entries'.
+///
+/// Entries matching `protected_files` are skipped. Returns their paths.
 pub fn apply_patch_to_disk<R: Read + Seek>(
     root_directory: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
-) -> Result<()> {
+    unsafe_path_handling: UnsafePathHandling,
+    path_matching: PathMatching,
+    protected_files: &[String],
+    on_progress: &mut dyn FnMut(PatchProgress),
+) -> Result<Vec<String>> {
     // TODO(LinkZ): Save original files before updating/removing them in order
     // to be able to restore them in case of failure
     // TODO(LinkZ): Make async?
+    let root_directory = root_directory.as_ref();
+    let protected_paths = protected_entries(
+        thor_archive
+            .get_entries()
+            .filter(|e| !e.is_internal())
+            .map(|e| e.relative_path.as_str()),
+        protected_files,
+    );
+    let patched_files = apply_delta_entries(thor_archive, protected_files, |path| {
+        let file_path = join_windows_relative_path(root_directory, path)?;
+        fs::read(resolve_path_case(root_directory, file_path, path_matching)).ok()
+    })?;
     let mut file_entries: Vec<ThorFileEntry> = thor_archive
         .get_entries()
-        .filter(|e| !e.is_internal())
+        .filter(|e| {
+            !e.is_internal()
+                && !is_handled_by_delta(e, &patched_files)
+                && !is_protected(&e.relative_path, protected_files)
+        })
         .cloned()
         .collect();
     file_entries.sort_unstable_by(|a, b| a.offset.cmp(&b.offset));
+    // Validate all the paths before modifying anything
+    let mut extracted_entries = Vec::with_capacity(file_entries.len());
     for entry in file_entries {
-        let dest_path = join_windows_relative_path(root_directory.as_ref(), &entry.relative_path);
+        if let Some(dest_path) =
+            destination_path(root_directory, &entry.relative_path, unsafe_path_handling)?
+        {
+            extracted_entries.push((entry, dest_path));
+        }
+    }
+    let mut progress = PatchProgress::new(extracted_entries.len() + patched_files.len());
+    // Entries are decompressed and written on several threads, while
+    // directories are created and files removed on this thread, in order
+    let extraction_pool = ExtractionPool::new();
+    let mut pending_paths: HashSet<PathBuf> = HashSet::new();
+    for (entry, dest_path) in extracted_entries {
+        // Note: Paths are resolved as entries are extracted since previous
+        // entries may have created some of their parent directories
+        let dest_path = resolve_path_case(root_directory, dest_path, path_matching);
+        // Entries modifying the same file are applied in order
+        if pending_paths.contains(&dest_path) {
+            while !pending_paths.is_empty() {
+                let extracted_file = extraction_pool.next_completed_file()?;
+                pending_paths.remove(&extracted_file.path);
+                progress.advance(extracted_file.size, on_progress);
+            }
+        }
         if entry.is_removed {
             // Try to remove file and ignore errors (file might not exist)
             let _ignore = fs::remove_file(dest_path);
+            progress.advance(0, on_progress);
         } else {
             // Create parent directory if needed
             if let Some(parent_dir) = dest_path.parent() {
                 fs::create_dir_all(parent_dir)?
             }
-            // Extract file
-            thor_archive.extract_file(&entry.relative_path, &dest_path)?;
+            if entry.size > PARALLEL_EXTRACTION_MAX_SIZE {
+                // Extract file chunk by chunk
+                thor_archive.extract_file(&entry.relative_path, &dest_path)?;
+                progress.advance(entry.size as u64, on_progress);
+            } else {
+                let content = thor_archive.read_compressed_file_content(&entry.relative_path)?;
+                extraction_pool.submit(dest_path.clone(), content)?;
+                pending_paths.insert(dest_path);
+            }
+        }
+        for extracted_file in extraction_pool.completed_files() {
+            let extracted_file = extracted_file?;
+            pending_paths.remove(&extracted_file.path);
+            progress.advance(extracted_file.size, on_progress);
         }
     }
-    Ok(())
+    for extracted_file in extraction_pool.finish() {
+        progress.advance(extracted_file?.size, on_progress);
+    }
+    for (relative_path, content) in patched_files {
+        // Note: Delta entries with unsafe paths are never applied
+        if let Some(dest_path) = join_windows_relative_path(root_directory, &relative_path) {
+            fs::write(
+                resolve_path_case(root_directory, dest_path, path_matching),
+                &content,
+            )?;
+        }
+        progress.advance(content.len() as u64, on_progress);
+    }
+    Ok(protected_paths)
+}
+
+/// Returns true if `thor_archive` contains delta entries.
+pub fn contains_delta_entries<R: Read + Seek>(thor_archive: &ThorArchive<R>) -> bool {
+    thor_archive
+        .get_entries()
+        .any(|e| !e.is_removed && delta_target_path(&e.relative_path).is_some())
+}
+
+/// Applies the delta entries of a THOR archive, using `read_base` to retrieve
+/// the current content of the files to patch.
+///
+/// Returns the patched content of the files, indexed by relative path. Files
+/// whose current content doesn't match the delta's base are left out, so that
+/// their full replacement (which must be present in the archive) gets used
+/// instead. Protected files are left out as well.
+fn apply_delta_entries<R: Read + Seek>(
+    thor_archive: &mut ThorArchive<R>,
+    protected_files: &[String],
+    mut read_base: impl FnMut(&str) -> Option<Vec<u8>>,
+) -> Result<HashMap<String, Vec<u8>>> {
+    let delta_entries: Vec<String> = thor_archive
+        .get_entries()
+        .filter(|e| {
+            !e.is_removed
+                && delta_target_path(&e.relative_path).is_some()
+                && !is_protected(&e.relative_path, protected_files)
+        })
+        .map(|e| e.relative_path.clone())
+        .collect();
+    let mut patched_files = HashMap::new();
+    for delta_path in delta_entries {
+        let target_path = delta_target_path(&delta_path).unwrap_or_default();
+        let delta = thor_archive.read_file_content(&delta_path)?;
+        let patched_content = match read_base(target_path) {
+            Some(base) => apply_delta(&base, &delta)?,
+            None => None,
+        };
+        match patched_content {
+            Some(content) => {
+                patched_files.insert(target_path.to_string(), content);
+            }
+            None => {
+                if thor_archive.get_file_entry(target_path).is_none() {
+                    return Err(anyhow!(
+                        "Cannot patch '{}': base doesn't match and no full replacement is available",
+                        target_path
+                    ));
+                }
+                log::info!(
+                    "Base of '{}' doesn't match, using full replacement",
+                    target_path
+                );
+            }
+        }
+    }
+    Ok(patched_files)
+}
+
+/// Indicates whether an entry is a delta entry or is superseded by one.
+fn is_handled_by_delta(entry: &ThorFileEntry, patched_files: &HashMap<String, Vec<u8>>) -> bool {
+    delta_target_path(&entry.relative_path).is_some()
+        || patched_files.contains_key(&entry.relative_path)
+}
+
+/// Returns the path an entry should be extracted to, or `None` if the entry
+/// must be skipped because its path is unsafe.
+pub fn destination_path(
+    root_directory: &Path,
+    relative_path: &str,
+    unsafe_path_handling: UnsafePathHandling,
+) -> Result<Option<PathBuf>> {
+    match join_windows_relative_path(root_directory, relative_path) {
+        Some(dest_path) => Ok(Some(dest_path)),
+        None => match unsafe_path_handling {
+            UnsafePathHandling::Reject => Err(anyhow!(
+                "Entry '{}' would be extracted outside of the game directory",
+                relative_path
+            )),
+            UnsafePathHandling::Skip => {
+                log::warn!("Skipping entry with an unsafe path '{}'", relative_path);
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// Returns the path of the file or directory designated by `path`, located
+/// under `root_directory`.
+///
+/// When paths are compared case-insensitively, the components of `path` are
+/// replaced with the names of the existing files and directories that only
+/// differ in case (e.g. "data/Texture" resolves to "Data/texture" if the
+/// latter exists).
+pub fn resolve_path_case(
+    root_directory: &Path,
+    path: PathBuf,
+    path_matching: PathMatching,
+) -> PathBuf {
+    if path_matching == PathMatching::Exact || path.exists() {
+        return path;
+    }
+    let relative_path = match path.strip_prefix(root_directory) {
+        Ok(relative_path) => relative_path,
+        Err(_) => return path,
+    };
+    let mut resolved_path = root_directory.to_path_buf();
+    let mut is_resolving = true;
+    for component in relative_path.iter() {
+        if is_resolving && !resolved_path.join(component).exists() {
+            let component_name = component.to_string_lossy();
+            let existing_name = fs::read_dir(&resolved_path).ok().and_then(|dir_entries| {
+                dir_entries
+                    .filter_map(|dir_entry| dir_entry.ok())
+                    .map(|dir_entry| dir_entry.file_name())
+                    .find(|name| name.to_string_lossy().eq_ignore_ascii_case(&component_name))
+            });
+            match existing_name {
+                Some(existing_name) => {
+                    resolved_path.push(existing_name);
+                    continue;
+                }
+                // Nothing exists past this component
+                None => is_resolving = false,
+            }
+        }
+        resolved_path.push(component);
+    }
+    resolved_path
 }
 
 /// Utility function used to join path-like segments the same way it's done in
 /// the GRF file format (Windows style).
-fn join_windows_relative_path(path: &Path, windows_relative_path: &str) -> PathBuf {
+///
+/// Returns `None` if the resulting path wouldn't be located under `path`
+/// (i.e., `windows_relative_path` is absolute, has a drive prefix or contains
+/// ".." components).
+fn join_windows_relative_path(path: &Path, windows_relative_path: &str) -> Option<PathBuf> {
+    // Note: Forward slashes are separators on all platforms
+    if windows_relative_path.starts_with(&['\\', '/'][..]) {
+        return None;
+    }
     let mut result = PathBuf::from(path);
-    for component in windows_relative_path.split('\\') {
-        result.push(component);
+    for component in windows_relative_path.split(&['\\', '/'][..]) {
+        match component {
+            "" | "." => continue,
+            ".." => return None,
+            _ if component.contains(':') => return None,
+            _ => result.push(component),
+        }
     }
-    result
+    Some(result)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use gruf::NameEncoding;
     use tempfile::tempdir;
     use walkdir::WalkDir;
 
@@ -203,6 +1072,15 @@ mod tests {
                     .filter(|metadata| metadata.is_file())
                     .count()
             };
+            let count_bytes = |dir_path| {
+                WalkDir::new(dir_path)
+                    .into_iter()
+                    .filter_map(|e| e.ok())
+                    .filter_map(|entry| entry.metadata().ok())
+                    .filter(|metadata| metadata.is_file())
+                    .map(|metadata| metadata.len())
+                    .sum::<u64>()
+            };
             let expected_file_path = temp_dir
                 .path()
                 .join("data/wav/se_subterranean_rustyengine.wav");
@@ -214,15 +1092,323 @@ mod tests {
             assert!(!expected_file_path.exists());
             assert_eq!(0, count_files(temp_dir.path()));
 
-            apply_patch_to_disk(temp_dir.path(), &mut thor_archive).unwrap();
+            let mut reports = vec![];
+            apply_patch_to_disk(
+                temp_dir.path(),
+                &mut thor_archive,
+                UnsafePathHandling::Reject,
+                PathMatching::Exact,
+                &[],
+                &mut |progress| reports.push(progress),
+            )
+            .unwrap();
 
             // After patching
             assert!(expected_file_path.exists());
             assert_eq!(nb_of_added_files, count_files(temp_dir.path()));
+            // Progress is reported once per entry
+            assert_eq!(nb_of_added_files, reports.len());
+            for (i, progress) in reports.iter().enumerate() {
+                assert_eq!(i + 1, progress.processed_entries);
+                assert_eq!(nb_of_added_files, progress.total_entries);
+            }
+            let last_progress = reports.last().unwrap();
+            assert_eq!(count_bytes(temp_dir.path()), last_progress.bytes_written);
             // TODO(LinkZ): Check content
         }
     }
 
+    #[test]
+    fn test_apply_delta_patch_to_disk() {
+        use crate::patcher::delta::make_delta;
+        use gruf::thor::ThorArchiveBuilder;
+
+        let temp_dir = tempdir().unwrap();
+        let base = b"base content".repeat(100);
+        let target = b"target content".repeat(100);
+        let file_path = temp_dir.path().join("data").join("file.txt");
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+
+        let thor_archive_path = temp_dir.path().join("delta.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update(
+                    "data\\file.txt.rpdelta".to_string(),
+                    make_delta(&base, &target).as_slice(),
+                )
+                .unwrap();
+            builder.finish().unwrap();
+        }
+
+        // Matching base
+        fs::write(&file_path, &base).unwrap();
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patch_to_disk(
+            temp_dir.path(),
+            &mut thor_archive,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(target, fs::read(&file_path).unwrap());
+        assert!(!temp_dir.path().join("data/file.txt.rpdelta").exists());
+
+        // Mismatching base, without full replacement
+        fs::write(&file_path, b"something else").unwrap();
+        assert!(apply_patch_to_disk(
+            temp_dir.path(),
+            &mut thor_archive,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {}
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_join_windows_relative_path() {
+        let root = Path::new("game");
+        assert_eq!(
+            Some(root.join("data").join("file.txt")),
+            join_windows_relative_path(root, "data\\file.txt")
+        );
+        assert_eq!(
+            Some(root.join("data").join("file.txt")),
+            join_windows_relative_path(root, "data\\.\\\\file.txt")
+        );
+        assert_eq!(None, join_windows_relative_path(root, "..\\file.txt"));
+        assert_eq!(
+            None,
+            join_windows_relative_path(root, "data\\..\\..\\file.txt")
+        );
+        assert_eq!(
+            None,
+            join_windows_relative_path(root, "data/../../file.txt")
+        );
+        assert_eq!(None, join_windows_relative_path(root, "\\file.txt"));
+        assert_eq!(None, join_windows_relative_path(root, "/etc/passwd"));
+        assert_eq!(None, join_windows_relative_path(root, "C:\\file.txt"));
+        assert_eq!(
+            None,
+            join_windows_relative_path(root, "\\\\server\\share\\file.txt")
+        );
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_unsafe_paths() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let temp_dir = tempdir().unwrap();
+        let root_directory = temp_dir.path().join("game");
+        fs::create_dir(&root_directory).unwrap();
+        let thor_archive_path = temp_dir.path().join("unsafe.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("..\\evil.txt".to_string(), &b"evil"[..])
+                .unwrap();
+            builder
+                .append_file_update("data\\file.txt".to_string(), &b"content"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        assert!(apply_patch_to_disk(
+            &root_directory,
+            &mut thor_archive,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {}
+        )
+        .is_err());
+        assert!(!temp_dir.path().join("evil.txt").exists());
+
+        apply_patch_to_disk(
+            &root_directory,
+            &mut thor_archive,
+            UnsafePathHandling::Skip,
+            PathMatching::Exact,
+            &[],
+            &mut |_| {},
+        )
+        .unwrap();
+        assert!(!temp_dir.path().join("evil.txt").exists());
+        assert_eq!(
+            b"content".to_vec(),
+            fs::read(root_directory.join("data").join("file.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_case_insensitive() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let temp_dir = tempdir().unwrap();
+        let root_directory = temp_dir.path().join("game");
+        let texture_directory = root_directory.join("Data").join("Texture");
+        fs::create_dir_all(&texture_directory).unwrap();
+        fs::write(texture_directory.join("Old.bmp"), b"old").unwrap();
+        fs::write(texture_directory.join("removed.bmp"), b"removed").unwrap();
+        let thor_archive_path = temp_dir.path().join("case.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("data\\texture\\old.bmp".to_string(), &b"new"[..])
+                .unwrap();
+            builder
+                .append_file_update("DATA\\TEXTURE\\added.bmp".to_string(), &b"added"[..])
+                .unwrap();
+            builder.append_file_removal("data\\texture\\Removed.BMP".to_string());
+            builder.finish().unwrap();
+        }
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patch_to_disk(
+            &root_directory,
+            &mut thor_archive,
+            UnsafePathHandling::Reject,
+            PathMatching::CaseInsensitive,
+            &[],
+            &mut |_| {},
+        )
+        .unwrap();
+
+        // Existing files and directories are reused
+        let file_names: Vec<String> = WalkDir::new(&root_directory)
+            .min_depth(1)
+            .into_iter()
+            .map(|e| {
+                e.unwrap()
+                    .path()
+                    .strip_prefix(&root_directory)
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .collect::<std::collections::BTreeSet<String>>()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            vec![
+                "Data",
+                "Data/Texture",
+                "Data/Texture/Old.bmp",
+                "Data/Texture/added.bmp"
+            ],
+            file_names
+        );
+        assert_eq!(
+            b"new".to_vec(),
+            fs::read(texture_directory.join("Old.bmp")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_apply_patch_to_disk_protected_files() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let temp_dir = tempdir().unwrap();
+        let root_directory = temp_dir.path().join("game");
+        fs::create_dir_all(root_directory.join("System")).unwrap();
+        fs::write(root_directory.join("clientinfo.xml"), b"custom").unwrap();
+        fs::write(root_directory.join("System/OptionInfo.lua"), b"settings").unwrap();
+        let thor_archive_path = temp_dir.path().join("protected.thor");
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, false).unwrap();
+            builder
+                .append_file_update("ClientInfo.xml".to_string(), &b"official"[..])
+                .unwrap();
+            builder
+                .append_file_update("data\\file.txt".to_string(), &b"content"[..])
+                .unwrap();
+            builder.append_file_removal("System\\OptionInfo.lua".to_string());
+            builder.finish().unwrap();
+        }
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let protected_paths = apply_patch_to_disk(
+            &root_directory,
+            &mut thor_archive,
+            UnsafePathHandling::Reject,
+            PathMatching::Exact,
+            &["clientinfo.xml".to_string(), "System/*.lua".to_string()],
+            &mut |_| {},
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec!["ClientInfo.xml", "System\\OptionInfo.lua"],
+            protected_paths
+        );
+        assert_eq!(
+            b"custom".to_vec(),
+            fs::read(root_directory.join("clientinfo.xml")).unwrap()
+        );
+        assert!(root_directory.join("System/OptionInfo.lua").exists());
+        assert!(root_directory.join("data/file.txt").exists());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_protected_files() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let protected_files = vec!["data\\*.rsw".to_string()];
+        for method in &[GrfPatchingMethod::InPlace, GrfPatchingMethod::OutOfPlace] {
+            let temp_dir = tempdir().unwrap();
+            let grf_archive_path = temp_dir.path().join("data.grf");
+            fs::copy(grf_dir_path.join("200-small.grf"), &grf_archive_path).unwrap();
+            let protected_entry_path = "data\\06guild_r.rsw";
+            let original_content = GrfArchive::open(&grf_archive_path)
+                .unwrap()
+                .read_file_content(protected_entry_path)
+                .unwrap();
+            let thor_archive_path = temp_dir.path().join("protected.thor");
+            {
+                let thor_file = fs::File::create(&thor_archive_path).unwrap();
+                let mut builder = ThorArchiveBuilder::new(thor_file, true, None, false).unwrap();
+                builder
+                    .append_file_update(protected_entry_path.to_string(), &b"replaced"[..])
+                    .unwrap();
+                builder
+                    .append_file_update("data\\added.txt".to_string(), &b"added"[..])
+                    .unwrap();
+                builder.finish().unwrap();
+            }
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let protected_paths = apply_patch_to_grf(
+                &grf_archive_path,
+                &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: *method,
+                    protected_files: &protected_files,
+                    ..Default::default()
+                },
+                &mut |_| {},
+            )
+            .unwrap();
+
+            assert_eq!(vec![protected_entry_path], protected_paths);
+            let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(
+                original_content,
+                grf_archive.read_file_content(protected_entry_path).unwrap()
+            );
+            assert!(grf_archive.contains_file("data\\added.txt"));
+        }
+    }
+
     #[test]
     fn test_apply_patch_to_grf_ip_empty() {
         let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
@@ -242,10 +1428,13 @@ mod tests {
             let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
             let nb_of_added_files = thor_archive.file_count() - 1;
             apply_patch_to_grf(
-                GrfPatchingMethod::InPlace,
-                false,
                 &grf_archive_path,
                 &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::InPlace,
+                    ..Default::default()
+                },
+                &mut |_| {},
             )
             .unwrap();
 
@@ -258,6 +1447,44 @@ mod tests {
         assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
     }
 
+    #[test]
+    fn test_apply_patch_to_grf_journaled() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("small.grf");
+        let journal_path = temp_dir.path().join("small.grf.journal");
+        {
+            fs::copy(grf_dir_path.join("200-small.grf"), &grf_archive_path).unwrap();
+            let original_file_count = GrfArchive::open(&grf_archive_path).unwrap().file_count();
+            // Incomplete journals left by a crash are discarded
+            fs::write(&journal_path, b"GRFJRNL1").unwrap();
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patch_to_grf(
+                &grf_archive_path,
+                &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::Journaled,
+                    ..Default::default()
+                },
+                &mut |_| {},
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(
+                original_file_count + nb_of_added_files,
+                grf_archive.file_count()
+            );
+            assert!(!journal_path.exists());
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
     #[test]
     fn test_apply_patch_to_grf_ip_empty_create() {
         let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
@@ -268,10 +1495,14 @@ mod tests {
             let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
             let nb_of_added_files = thor_archive.file_count() - 1;
             apply_patch_to_grf(
-                GrfPatchingMethod::InPlace,
-                true,
                 &grf_archive_path,
                 &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::InPlace,
+                    create_if_needed: true,
+                    ..Default::default()
+                },
+                &mut |_| {},
             )
             .unwrap();
 
@@ -284,6 +1515,22 @@ mod tests {
         assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
     }
 
+    #[test]
+    fn test_create_missing_grf() {
+        let temp_dir = tempdir().unwrap();
+        let grf_archive_path = temp_dir.path().join("data/new.grf");
+        assert!(create_missing_grf(&grf_archive_path, false, NameEncoding::Windows1252).is_err());
+        assert!(!grf_archive_path.exists());
+
+        create_missing_grf(&grf_archive_path, true, NameEncoding::Windows1252).unwrap();
+
+        let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+        assert_eq!(grf_archive.file_count(), 0);
+        assert_eq!(grf_archive.version_major(), 2);
+        // Existing GRFs are left untouched
+        create_missing_grf(&grf_archive_path, false, NameEncoding::Windows1252).unwrap();
+    }
+
     #[test]
     fn test_apply_patch_to_grf_oop_empty() {
         let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
@@ -302,13 +1549,22 @@ mod tests {
 
             let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
             let nb_of_added_files = thor_archive.file_count() - 1;
+            let mut last_progress = None;
             apply_patch_to_grf(
-                GrfPatchingMethod::OutOfPlace,
-                false,
                 &grf_archive_path,
                 &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::OutOfPlace,
+                    ..Default::default()
+                },
+                &mut |progress| last_progress = Some(progress),
             )
             .unwrap();
+            // Progress is reported as the rebuild of the GRF
+            let last_progress = last_progress.unwrap();
+            assert_eq!(last_progress.rebuilt_grf.as_deref(), Some("empty.grf"));
+            assert_eq!(last_progress.processed_entries, nb_of_added_files);
+            assert_eq!(last_progress.total_entries, nb_of_added_files);
 
             // After patching
             let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
@@ -329,10 +1585,14 @@ mod tests {
             let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
             let nb_of_added_files = thor_archive.file_count() - 1;
             apply_patch_to_grf(
-                GrfPatchingMethod::OutOfPlace,
-                true,
                 &grf_archive_path,
                 &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::OutOfPlace,
+                    create_if_needed: true,
+                    ..Default::default()
+                },
+                &mut |_| {},
             )
             .unwrap();
 
@@ -345,12 +1605,508 @@ mod tests {
         assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
     }
 
+    #[test]
+    fn test_apply_patch_to_grf_oop_mmap() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("small.grf");
+        {
+            fs::copy(grf_dir_path.join("200-small.grf"), &grf_archive_path).unwrap();
+            let original_file_count = GrfArchive::open(&grf_archive_path).unwrap().file_count();
+
+            let mut thor_archive = ThorArchive::open_mmap(&thor_archive_path).unwrap();
+            let nb_of_added_files = thor_archive.file_count() - 1;
+            apply_patch_to_grf(
+                &grf_archive_path,
+                &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::OutOfPlace,
+                    memory_mapped: true,
+                    ..Default::default()
+                },
+                &mut |_| {},
+            )
+            .unwrap();
+
+            // After patching
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(
+                original_file_count + nb_of_added_files,
+                grf_archive.file_count()
+            );
+            assert!(!temp_dir.path().join("small.grf.bak").exists());
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_oop_recompressed() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_archive_path = temp_dir.path().join("small.grf");
+        let thor_archive_path = temp_dir.path().join("zstd.thor");
+        // Entries of GRF 1.x archives and zstd-compressed entries must be
+        // recompressed
+        fs::copy(grf_dir_path.join("103-small.grf"), &grf_archive_path).unwrap();
+        {
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new_with_compression(
+                thor_file,
+                true,
+                None,
+                false,
+                ThorCompression::Zstd,
+            )
+            .unwrap();
+            for i in 0..16 {
+                builder
+                    .append_file_update(
+                        format!("data\\file{}.txt", i),
+                        vec![i as u8; 1000 * i].as_slice(),
+                    )
+                    .unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        let original_content: HashMap<String, Vec<u8>> = {
+            let mut original_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            let entry_paths: Vec<String> = original_archive
+                .get_entries()
+                .map(|entry| entry.relative_path.clone())
+                .collect();
+            entry_paths
+                .into_iter()
+                .map(|path| {
+                    let content = original_archive.read_file_content(&path).unwrap();
+                    (path, content)
+                })
+                .collect()
+        };
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let mut reports = vec![];
+        apply_patch_to_grf(
+            &grf_archive_path,
+            &mut thor_archive,
+            &GrfPatchingOptions {
+                method: GrfPatchingMethod::OutOfPlace,
+                ..Default::default()
+            },
+            &mut |progress| reports.push(progress),
+        )
+        .unwrap();
+
+        let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+        assert_eq!(grf_archive.file_count(), original_content.len() + 16);
+        assert_eq!(reports.len(), grf_archive.file_count());
+        assert_eq!(reports.last().unwrap().processed_entries, reports.len());
+        for (relative_path, content) in original_content {
+            assert_eq!(
+                content,
+                grf_archive.read_file_content(&relative_path).unwrap()
+            );
+        }
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_with_encoding() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let grf_archive_path = temp_dir.path().join("encoding.grf");
+        let thor_archive_path = thor_dir_path.join("tiny.thor");
+        let entry_path = "data\\texture\\유저인터페이스\\inventory\\icon_num.bmp";
+        {
+            let mut thor_archive =
+                ThorArchive::open_with_encoding(&thor_archive_path, NameEncoding::Cp949).unwrap();
+            apply_patch_to_grf(
+                &grf_archive_path,
+                &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::OutOfPlace,
+                    create_if_needed: true,
+                    ..Default::default()
+                },
+                &mut |_| {},
+            )
+            .unwrap();
+            // Entries are matched with the ones already present in the GRF
+            let mut thor_archive =
+                ThorArchive::open_with_encoding(&thor_archive_path, NameEncoding::Auto).unwrap();
+            apply_patch_to_grf(
+                &grf_archive_path,
+                &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: GrfPatchingMethod::InPlace,
+                    ..Default::default()
+                },
+                &mut |_| {},
+            )
+            .unwrap();
+        }
+        let grf_archive =
+            GrfArchive::open_with_encoding(&grf_archive_path, NameEncoding::Cp949).unwrap();
+        assert_eq!(grf_archive.file_count(), 1);
+        assert!(grf_archive.contains_file(entry_path));
+        // Names are stored with their original bytes
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_with_index_cache() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let grf_archive_path = temp_dir.path().join("small.grf");
+        let index_cache_file_path = temp_dir.path().join("rpatchur.small.grf.idx");
+        fs::copy(grf_dir_path.join("200-small.grf"), &grf_archive_path).unwrap();
+        for (thor_name, patching_method) in [
+            ("small.thor", GrfPatchingMethod::InPlace),
+            ("dir1.thor", GrfPatchingMethod::OutOfPlace),
+            ("dir2.thor", GrfPatchingMethod::Journaled),
+        ] {
+            let thor_archive_path = thor_dir_path.join(thor_name);
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            apply_patch_to_grf(
+                &grf_archive_path,
+                &mut thor_archive,
+                &GrfPatchingOptions {
+                    method: patching_method,
+                    index_cache_file_path: Some(&index_cache_file_path),
+                    ..Default::default()
+                },
+                &mut |_| {},
+            )
+            .unwrap();
+
+            // The index of the patched GRF is up to date
+            let index = load_grf_index(
+                &index_cache_file_path,
+                &grf_archive_path,
+                NameEncoding::Windows1252,
+            )
+            .unwrap();
+            let grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(index, grf_archive.index());
+            let indexed_grf_archive =
+                GrfArchive::open_with_index(&grf_archive_path, index).unwrap();
+            for entry in grf_archive.get_entries() {
+                let indexed_entry = indexed_grf_archive
+                    .get_file_entry(&entry.relative_path)
+                    .unwrap();
+                assert_eq!(
+                    (indexed_entry.offset, indexed_entry.size_compressed),
+                    (entry.offset, entry.size_compressed)
+                );
+            }
+            assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_apply_patches_to_grf() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let small_thor_archive = ThorArchive::open(&thor_dir_path.join("small.thor")).unwrap();
+        let removed_file_path = small_thor_archive
+            .get_entries()
+            .find(|e| !e.is_internal())
+            .unwrap()
+            .relative_path
+            .clone();
+        let nb_of_small_files = small_thor_archive.file_count() - 1;
+        // Later patches update a file added by earlier patches and remove one
+        let build_patch = |name: &str, content: &[u8], removed_file_path: Option<&String>| {
+            let thor_archive_path = temp_dir.path().join(name);
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder =
+                ThorArchiveBuilder::new(thor_file, true, Some("data.grf".to_string()), false)
+                    .unwrap();
+            builder
+                .append_file_update("data\\new.txt".to_string(), content)
+                .unwrap();
+            if let Some(removed_file_path) = removed_file_path {
+                builder.append_file_removal(removed_file_path.clone());
+            }
+            builder.finish().unwrap();
+            thor_archive_path
+        };
+        let first_patch_path = build_patch("1.thor", b"first", None);
+        let second_patch_path = build_patch("2.thor", b"second", Some(&removed_file_path));
+        for patching_method in [
+            GrfPatchingMethod::OutOfPlace,
+            GrfPatchingMethod::InPlace,
+            GrfPatchingMethod::Journaled,
+        ] {
+            let grf_archive_path = temp_dir.path().join("data.grf");
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+            let mut thor_archives = vec![
+                ThorArchive::open(&thor_dir_path.join("small.thor")).unwrap(),
+                ThorArchive::open(&first_patch_path).unwrap(),
+                ThorArchive::open(&second_patch_path).unwrap(),
+            ];
+            let mut last_progress = None;
+            apply_patches_to_grf(
+                &grf_archive_path,
+                &mut thor_archives,
+                &GrfPatchingOptions {
+                    method: patching_method,
+                    ..Default::default()
+                },
+                &mut |progress| last_progress = Some(progress),
+            )
+            .unwrap();
+
+            let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(nb_of_small_files, grf_archive.file_count());
+            assert!(!grf_archive.contains_file(&removed_file_path));
+            assert_eq!(
+                b"second",
+                grf_archive
+                    .read_file_content("data\\new.txt")
+                    .unwrap()
+                    .as_slice()
+            );
+            let last_progress = last_progress.unwrap();
+            assert_eq!(last_progress.processed_entries, last_progress.total_entries);
+        }
+    }
+
+    #[test]
+    fn test_apply_patch_to_grf_case_insensitive() {
+        use gruf::thor::ThorArchiveBuilder;
+
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let build_patch = |name: &str, entries: &[(&str, &[u8])], removed_files: &[&str]| {
+            let thor_archive_path = temp_dir.path().join(name);
+            let thor_file = fs::File::create(&thor_archive_path).unwrap();
+            let mut builder =
+                ThorArchiveBuilder::new(thor_file, true, Some("data.grf".to_string()), false)
+                    .unwrap();
+            for (relative_path, content) in entries {
+                builder
+                    .append_file_update(relative_path.to_string(), *content)
+                    .unwrap();
+            }
+            for relative_path in removed_files {
+                builder.append_file_removal(relative_path.to_string());
+            }
+            builder.finish().unwrap();
+            thor_archive_path
+        };
+        let first_patch_path = build_patch(
+            "1.thor",
+            &[
+                ("Data\\Texture\\file.bmp", b"old"),
+                ("Data\\removed.txt", b"removed"),
+            ],
+            &[],
+        );
+        let second_patch_path = build_patch(
+            "2.thor",
+            &[("data/texture/FILE.BMP", b"new")],
+            &["DATA\\REMOVED.TXT"],
+        );
+        for patching_method in [GrfPatchingMethod::OutOfPlace, GrfPatchingMethod::InPlace] {
+            let grf_archive_path = temp_dir.path().join("data.grf");
+            fs::copy(grf_dir_path.join("200-empty.grf"), &grf_archive_path).unwrap();
+            for thor_archive_path in &[&first_patch_path, &second_patch_path] {
+                let mut thor_archive = ThorArchive::open(thor_archive_path).unwrap();
+                apply_patch_to_grf(
+                    &grf_archive_path,
+                    &mut thor_archive,
+                    &GrfPatchingOptions {
+                        method: patching_method,
+                        path_matching: PathMatching::CaseInsensitive,
+                        ..Default::default()
+                    },
+                    &mut |_| {},
+                )
+                .unwrap();
+            }
+
+            // Entries are replaced and removed regardless of their case
+            let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(1, grf_archive.file_count());
+            assert_eq!(
+                b"new",
+                grf_archive
+                    .read_file_content("data/texture/FILE.BMP")
+                    .unwrap()
+                    .as_slice()
+            );
+        }
+    }
+
+    #[test]
+    fn test_apply_patch_to_corrupt_grf() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        let grf_archive_path = temp_dir.path().join("corrupt.grf");
+        {
+            let grf_file = fs::File::create(&grf_archive_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &b"first file"[..])
+                .unwrap();
+            builder
+                .add_file("data\\b.txt".to_string(), &b"second file"[..])
+                .unwrap();
+        }
+        overlap_grf_entries(&grf_archive_path, "data\\b.txt", "data\\a.txt");
+        // Archives with overlapping entries cannot be patched in place
+        assert!(GrfArchiveBuilder::open(&grf_archive_path).is_err());
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        let nb_of_added_files = thor_archive.file_count() - 1;
+        apply_patch_to_grf(
+            &grf_archive_path,
+            &mut thor_archive,
+            &GrfPatchingOptions {
+                method: GrfPatchingMethod::InPlace,
+                ..Default::default()
+            },
+            &mut |_| {},
+        )
+        .unwrap();
+
+        // The overlapping entry has been dropped
+        let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+        assert_eq!(grf_archive.file_count(), nb_of_added_files + 1);
+        assert!(!grf_archive.contains_file("data\\b.txt"));
+        assert_eq!(
+            grf_archive.read_file_content("data\\a.txt").unwrap(),
+            b"first file"
+        );
+        assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+    }
+
+    /// Rewrites the file table of a GRF 2.0 archive so that the entry at
+    /// `entry_path` points to the content of the entry at `other_entry_path`.
+    fn overlap_grf_entries(grf_path: &Path, entry_path: &str, other_entry_path: &str) {
+        use flate2::read::ZlibDecoder;
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+        use std::convert::TryInto;
+        use std::io::SeekFrom;
+
+        const GRF_HEADER_SIZE: u64 = 0x2E;
+        let mut grf_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(grf_path)
+            .unwrap();
+        let mut header = [0; GRF_HEADER_SIZE as usize];
+        grf_file.read_exact(&mut header).unwrap();
+        let table_offset =
+            GRF_HEADER_SIZE + u32::from_le_bytes(header[0x1E..0x22].try_into().unwrap()) as u64;
+        grf_file.seek(SeekFrom::Start(table_offset + 8)).unwrap();
+        let mut table = vec![];
+        ZlibDecoder::new(&mut grf_file)
+            .read_to_end(&mut table)
+            .unwrap();
+        // Entries' offsets are stored after their name and 13 bytes of sizes
+        // and flags
+        let offset_position = |name: &str| {
+            let name_position = table
+                .windows(name.len() + 1)
+                .position(|window| {
+                    window[..name.len()] == *name.as_bytes() && window[name.len()] == 0
+                })
+                .unwrap();
+            name_position + name.len() + 1 + 13
+        };
+        let source_position = offset_position(other_entry_path);
+        let destination_position = offset_position(entry_path);
+        let other_offset = table[source_position..source_position + 4].to_vec();
+        table[destination_position..destination_position + 4].copy_from_slice(&other_offset);
+
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&table).unwrap();
+        let compressed_table = encoder.finish().unwrap();
+        grf_file.seek(SeekFrom::Start(table_offset)).unwrap();
+        grf_file
+            .write_all(&(compressed_table.len() as u32).to_le_bytes())
+            .unwrap();
+        grf_file
+            .write_all(&(table.len() as u32).to_le_bytes())
+            .unwrap();
+        grf_file.write_all(&compressed_table).unwrap();
+        let table_end = grf_file.stream_position().unwrap();
+        grf_file.set_len(table_end).unwrap();
+    }
+
+    #[test]
+    fn test_apply_patch_to_legacy_grf() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let thor_archive_path = thor_dir_path.join("small.thor");
+        for grf_name in &["102-small.grf", "103-small.grf"] {
+            for patching_method in [GrfPatchingMethod::InPlace, GrfPatchingMethod::OutOfPlace] {
+                let temp_dir = tempdir().unwrap();
+                let grf_archive_path = temp_dir.path().join(grf_name);
+                fs::copy(grf_dir_path.join(grf_name), &grf_archive_path).unwrap();
+                let original_content: HashMap<String, Vec<u8>> = {
+                    let mut original_archive = GrfArchive::open(&grf_archive_path).unwrap();
+                    let entry_paths: Vec<String> = original_archive
+                        .get_entries()
+                        .map(|entry| entry.relative_path.clone())
+                        .collect();
+                    entry_paths
+                        .into_iter()
+                        .map(|path| {
+                            let content = original_archive.read_file_content(&path).unwrap();
+                            (path, content)
+                        })
+                        .collect()
+                };
+                assert!(!original_content.is_empty());
+
+                let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+                apply_patch_to_grf(
+                    &grf_archive_path,
+                    &mut thor_archive,
+                    &GrfPatchingOptions {
+                        method: patching_method,
+                        ..Default::default()
+                    },
+                    &mut |_| {},
+                )
+                .unwrap();
+
+                // Legacy GRFs are converted to GRF 2.0
+                let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+                assert_eq!(grf_archive.version_major(), 2);
+                assert_eq!(grf_archive.version_minor(), 0);
+                for (relative_path, content) in original_content {
+                    if thor_archive.get_file_entry(&relative_path).is_some() {
+                        continue;
+                    }
+                    assert_eq!(
+                        content,
+                        grf_archive.read_file_content(&relative_path).unwrap()
+                    );
+                }
+                assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
+            }
+        }
+    }
+
     fn patch_maintained_integrity(
         thor_file_path: &PathBuf,
         grf_file_path: &PathBuf,
     ) -> Result<bool> {
         let mut thor_archive = ThorArchive::open(&thor_file_path)?;
-        let mut grf_archive = GrfArchive::open(&grf_file_path)?;
+        let mut grf_archive = GrfArchive::open(grf_file_path)?;
         let thor_entries: Vec<ThorFileEntry> = thor_archive.get_entries().cloned().collect();
         for file_entry in thor_entries {
             if file_entry.is_internal() || file_entry.is_removed {
@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use gruf::grf::GrfArchive;
+use gruf::thor::ThorArchive;
+use gruf::NameEncoding;
+use serde::Serialize;
+
+use super::delta::delta_target_path;
+use super::patching::{
+    destination_path, is_protected, resolve_path_case, PathMatching, UnsafePathHandling,
+};
+
+/// Change a patch would make to a file of the game directory or to an entry
+/// of a GRF.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryChange {
+    Add,
+    Replace,
+    Delete,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EntryPreview {
+    pub relative_path: String,
+    pub change: EntryChange,
+}
+
+/// Changes a patch would make if it were applied.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PatchPreview {
+    pub patch_name: String,
+    pub target_grf: Option<String>, // GRF the patch is merged into, `None` for the game directory
+    pub changes: Vec<EntryPreview>,
+}
+
+/// Computes the changes consecutive patches would make, without modifying
+/// anything. Files and entries added or removed by previous patches are taken
+/// into account.
+pub struct PatchPreviewer {
+    install_directory: PathBuf,
+    name_encoding: NameEncoding,
+    unsafe_path_handling: UnsafePathHandling,
+    path_matching: PathMatching,
+    protected_files: Vec<String>, // Patterns of the entries left untouched
+    // Existence of the entries changed by previous patches, indexed by target
+    // GRF (`None` for the game directory) then by folded path
+    changed_entries: HashMap<Option<String>, HashMap<String, bool>>,
+    // Folded paths of the entries of the GRFs read so far
+    grf_entries: HashMap<String, HashSet<String>>,
+}
+
+impl PatchPreviewer {
+    pub fn new(
+        install_directory: impl Into<PathBuf>,
+        name_encoding: NameEncoding,
+        unsafe_path_handling: UnsafePathHandling,
+        path_matching: PathMatching,
+        protected_files: Vec<String>,
+    ) -> Self {
+        Self {
+            install_directory: install_directory.into(),
+            name_encoding,
+            unsafe_path_handling,
+            path_matching,
+            protected_files,
+            changed_entries: HashMap::new(),
+            grf_entries: HashMap::new(),
+        }
+    }
+
+    /// Previews the application of a THOR archive. GRF-merge archives are
+    /// merged into `target_grf_name`.
+    pub fn preview_thor_archive<R: Read + Seek>(
+        &mut self,
+        patch_name: String,
+        thor_archive: &ThorArchive<R>,
+        target_grf_name: &str,
+    ) -> Result<PatchPreview> {
+        // Delta entries replace the file they're applied to
+        let entries: Vec<(String, bool)> = thor_archive
+            .get_entries()
+            .filter(|e| !e.is_internal())
+            .map(|e| match delta_target_path(&e.relative_path) {
+                Some(target_path) if !e.is_removed => (target_path.to_string(), false),
+                _ => (e.relative_path.clone(), e.is_removed),
+            })
+            .collect();
+        let target_grf_name = if thor_archive.use_grf_merging() {
+            Some(target_grf_name)
+        } else {
+            None
+        };
+        self.preview_entries(patch_name, target_grf_name, entries)
+    }
+
+    /// Previews the application of a patch made of `entries` (paths along
+    /// with whether they're removed), merged into `target_grf_name` or
+    /// extracted to the game directory if `None`.
+    pub fn preview_entries(
+        &mut self,
+        patch_name: String,
+        target_grf_name: Option<&str>,
+        entries: impl IntoIterator<Item = (String, bool)>,
+    ) -> Result<PatchPreview> {
+        let mut patch_entries = BTreeMap::new();
+        for (relative_path, is_removed) in entries {
+            let is_skipped = is_protected(&relative_path, &self.protected_files)
+                || target_grf_name.is_none()
+                    && destination_path(
+                        &self.install_directory,
+                        &relative_path,
+                        self.unsafe_path_handling,
+                    )?
+                    .is_none();
+            if is_skipped {
+                // Skipped when the patch is applied
+                continue;
+            }
+            let folded_path = self
+                .path_matching
+                .fold(&relative_path.replace('/', "\\"))
+                .into_owned();
+            patch_entries.insert(folded_path, (relative_path, is_removed));
+        }
+
+        let target_key = target_grf_name.map(str::to_string);
+        let mut changes = Vec::with_capacity(patch_entries.len());
+        for (folded_path, (relative_path, is_removed)) in patch_entries {
+            let exists = self.entry_exists(target_grf_name, &relative_path, &folded_path)?;
+            let change = match (is_removed, exists) {
+                (true, true) => EntryChange::Delete,
+                (true, false) => continue,
+                (false, true) => EntryChange::Replace,
+                (false, false) => EntryChange::Add,
+            };
+            self.changed_entries
+                .entry(target_key.clone())
+                .or_default()
+                .insert(folded_path, !is_removed);
+            changes.push(EntryPreview {
+                relative_path,
+                change,
+            });
+        }
+        Ok(PatchPreview {
+            patch_name,
+            target_grf: target_key,
+            changes,
+        })
+    }
+
+    fn entry_exists(
+        &mut self,
+        target_grf_name: Option<&str>,
+        relative_path: &str,
+        folded_path: &str,
+    ) -> Result<bool> {
+        let changed_entry = self
+            .changed_entries
+            .get(&target_grf_name.map(str::to_string))
+            .and_then(|entries| entries.get(folded_path));
+        if let Some(exists) = changed_entry {
+            return Ok(*exists);
+        }
+        match target_grf_name {
+            None => {
+                let file_path = destination_path(
+                    &self.install_directory,
+                    relative_path,
+                    self.unsafe_path_handling,
+                )?
+                .unwrap_or_default();
+                Ok(
+                    resolve_path_case(&self.install_directory, file_path, self.path_matching)
+                        .is_file(),
+                )
+            }
+            Some(grf_name) => {
+                if !self.grf_entries.contains_key(grf_name) {
+                    let entries = self.read_grf_entries(grf_name)?;
+                    self.grf_entries.insert(grf_name.to_string(), entries);
+                }
+                Ok(self.grf_entries[grf_name].contains(folded_path))
+            }
+        }
+    }
+
+    /// Returns the folded paths of the entries of a GRF, which is considered
+    /// empty if it doesn't exist yet.
+    fn read_grf_entries(&self, grf_name: &str) -> Result<HashSet<String>> {
+        let grf_path = self.install_directory.join(grf_name);
+        if !grf_path.exists() {
+            return Ok(HashSet::new());
+        }
+        let grf_archive = GrfArchive::open_with_encoding(&grf_path, self.name_encoding)
+            .with_context(|| format!("Failed to open '{}'", grf_path.display()))?;
+        Ok(grf_archive
+            .get_entries()
+            .map(|entry| {
+                self.path_matching
+                    .fold(&entry.relative_path.replace('/', "\\"))
+                    .into_owned()
+            })
+            .collect())
+    }
+}
+
+/// Parts of a patch fetched from a patch server, such as its header and its
+/// file table. Bytes that haven't been fetched read as the end of the patch.
+#[derive(Default)]
+pub struct PartialPatch {
+    parts: Vec<(u64, Vec<u8>)>, // Offset, Content
+    position: u64,
+}
+
+impl PartialPatch {
+    pub fn add_part(&mut self, offset: u64, content: Vec<u8>) {
+        self.parts.push((offset, content));
+    }
+}
+
+impl Read for PartialPatch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        let part = self.parts.iter().find(|(offset, content)| {
+            *offset <= position && position < offset + content.len() as u64
+        });
+        let read_size = match part {
+            None => 0,
+            Some((offset, content)) => {
+                let part_content = &content[(position - offset) as usize..];
+                let read_size = part_content.len().min(buf.len());
+                buf[..read_size].copy_from_slice(&part_content[..read_size]);
+                read_size
+            }
+        };
+        self.position += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for PartialPatch {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => {
+                if delta < 0 {
+                    self.position.checked_sub(delta.unsigned_abs())
+                } else {
+                    self.position.checked_add(delta as u64)
+                }
+            }
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Size of partial patches is unknown",
+                ))
+            }
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::{self, ThorArchiveBuilder};
+    use std::fs;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    fn previewer(install_directory: &std::path::Path) -> PatchPreviewer {
+        PatchPreviewer::new(
+            install_directory,
+            NameEncoding::Windows1252,
+            UnsafePathHandling::Skip,
+            PathMatching::CaseInsensitive,
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_preview_entries() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir(temp_dir.path().join("data")).unwrap();
+        fs::write(temp_dir.path().join("data/existing.txt"), b"").unwrap();
+        let mut previewer = previewer(temp_dir.path());
+        let entries = vec![
+            ("data\\Existing.txt".to_string(), false),
+            ("data\\new.txt".to_string(), false),
+            ("data\\missing.txt".to_string(), true),
+            ("..\\outside.txt".to_string(), false),
+        ];
+
+        let preview = previewer
+            .preview_entries("1.thor".to_string(), None, entries)
+            .unwrap();
+
+        assert_eq!(preview.target_grf, None);
+        assert_eq!(
+            preview.changes,
+            vec![
+                EntryPreview {
+                    relative_path: "data\\Existing.txt".to_string(),
+                    change: EntryChange::Replace,
+                },
+                EntryPreview {
+                    relative_path: "data\\new.txt".to_string(),
+                    change: EntryChange::Add,
+                },
+            ]
+        );
+        // Changes of previous patches are taken into account
+        let entries = vec![
+            ("data\\new.txt".to_string(), true),
+            ("data\\existing.txt".to_string(), true),
+        ];
+        let preview = previewer
+            .preview_entries("2.thor".to_string(), None, entries)
+            .unwrap();
+        assert!(preview
+            .changes
+            .iter()
+            .all(|entry| entry.change == EntryChange::Delete));
+        assert_eq!(preview.changes.len(), 2);
+        // Nothing has been modified
+        assert!(!temp_dir.path().join("data/new.txt").exists());
+        assert!(temp_dir.path().join("data/existing.txt").exists());
+    }
+
+    #[test]
+    fn test_preview_partial_thor_archive() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempdir().unwrap();
+        let content = fs::read(thor_dir_path.join("small.thor")).unwrap();
+        // Only the header and the file table are needed
+        let mut partial_patch = PartialPatch::default();
+        let header_size = content.len().min(thor::reader::HEADER_EXTENDED_MAX_SIZE);
+        partial_patch.add_part(0, content[..header_size].to_vec());
+        if let Some(table_range) = thor::parse_thor_file_table_range(&content).unwrap() {
+            let table_content =
+                content[table_range.start as usize..table_range.end as usize].to_vec();
+            partial_patch.add_part(table_range.start, table_content);
+        }
+        let partial_archive = ThorArchive::new(partial_patch).unwrap();
+
+        let preview = previewer(temp_dir.path())
+            .preview_thor_archive("small.thor".to_string(), &partial_archive, "default.grf")
+            .unwrap();
+
+        let archive = ThorArchive::new(Cursor::new(content)).unwrap();
+        assert_eq!(preview.target_grf.as_deref(), Some("default.grf"));
+        assert_eq!(
+            preview.changes.len(),
+            archive.get_entries().filter(|e| !e.is_internal()).count()
+        );
+        assert!(preview
+            .changes
+            .iter()
+            .all(|entry| entry.change == EntryChange::Add));
+        assert!(!temp_dir.path().join("default.grf").exists());
+    }
+
+    #[test]
+    fn test_preview_grf_merge() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        fs::copy(
+            grf_dir_path.join("200-small.grf"),
+            temp_dir.path().join("data.grf"),
+        )
+        .unwrap();
+        let mut thor_content = Cursor::new(vec![]);
+        {
+            let mut builder =
+                ThorArchiveBuilder::new(&mut thor_content, true, None, false).unwrap();
+            builder
+                .append_file_update("DATA\\06guild_r.rsw".to_string(), &b"updated"[..])
+                .unwrap();
+            builder
+                .append_file_update("data\\new.txt".to_string(), &b"new"[..])
+                .unwrap();
+            builder.append_file_removal("data\\06guild_r.gnd".to_string());
+            builder.finish().unwrap();
+        }
+        thor_content.set_position(0);
+        let thor_archive = ThorArchive::new(thor_content).unwrap();
+
+        let preview = previewer(temp_dir.path())
+            .preview_thor_archive("grf.thor".to_string(), &thor_archive, "data.grf")
+            .unwrap();
+
+        let change_of = |relative_path: &str| {
+            preview
+                .changes
+                .iter()
+                .find(|entry| entry.relative_path == relative_path)
+                .map(|entry| entry.change)
+        };
+        assert_eq!(change_of("DATA\\06guild_r.rsw"), Some(EntryChange::Replace));
+        assert_eq!(change_of("data\\new.txt"), Some(EntryChange::Add));
+        assert_eq!(change_of("data\\06guild_r.gnd"), Some(EntryChange::Delete));
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Minimum interval between two progress reports
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Snapshot of the progress of a download session.
+#[derive(Debug, PartialEq)]
+pub struct DownloadStatistics {
+    pub downloaded_bytes: u64,
+    pub total_bytes: u64,
+    pub bytes_per_sec: u64,         // Speed since the last report
+    pub average_bytes_per_sec: u64, // Speed since the beginning of the session
+    pub eta_secs: Option<u64>,      // `None` until every file's size is known
+}
+
+/// Aggregates the progress of concurrent downloads.
+pub struct DownloadProgress {
+    file_count: usize,
+    state: Mutex<ProgressState>,
+}
+
+struct ProgressState {
+    start: Instant,
+    last_report: Instant,
+    transferred_bytes: u64, // Includes data discarded because of retries
+    transferred_bytes_at_last_report: u64,
+    downloaded_bytes: u64,
+    total_bytes: u64,
+    sized_file_count: usize,
+}
+
+impl DownloadProgress {
+    pub fn new(file_count: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            file_count,
+            state: Mutex::new(ProgressState {
+                start: now,
+                last_report: now,
+                transferred_bytes: 0,
+                transferred_bytes_at_last_report: 0,
+                downloaded_bytes: 0,
+                total_bytes: 0,
+                sized_file_count: 0,
+            }),
+        }
+    }
+
+    /// Registers the size of a file, once its download has started.
+    pub fn add_file_size(&self, size: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.total_bytes += size;
+            state.sized_file_count += 1;
+        }
+    }
+
+    /// Accounts for bytes that are already available locally (and thus
+    /// don't count towards the download speed).
+    pub fn add_completed_bytes(&self, byte_count: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.downloaded_bytes += byte_count;
+        }
+    }
+
+    /// Returns the number of bytes received so far, including data discarded
+    /// because of retries.
+    pub fn transferred_bytes(&self) -> u64 {
+        self.state
+            .lock()
+            .map(|state| state.transferred_bytes)
+            .unwrap_or(0)
+    }
+
+    /// Returns the total size of the session, once every file's size is
+    /// known.
+    pub fn total_bytes(&self) -> Option<u64> {
+        let state = self.state.lock().ok()?;
+        if state.sized_file_count < self.file_count {
+            return None;
+        }
+        Some(state.total_bytes)
+    }
+
+    /// Accounts for a file's downloaded byte count going from `previous` to
+    /// `current` (which is lower than `previous` when a download restarts).
+    ///
+    /// Returns statistics at most once per second.
+    pub fn update(&self, previous: u64, current: u64) -> Option<DownloadStatistics> {
+        let mut state = self.state.lock().ok()?;
+        state.downloaded_bytes = (state.downloaded_bytes + current).saturating_sub(previous);
+        state.transferred_bytes += current.saturating_sub(previous);
+
+        let now = Instant::now();
+        let elapsed_since_report = now.duration_since(state.last_report);
+        if elapsed_since_report < REPORT_INTERVAL {
+            return None;
+        }
+        let bytes_per_sec = compute_speed(
+            state.transferred_bytes - state.transferred_bytes_at_last_report,
+            elapsed_since_report,
+        );
+        let average_bytes_per_sec =
+            compute_speed(state.transferred_bytes, now.duration_since(state.start));
+        state.last_report = now;
+        state.transferred_bytes_at_last_report = state.transferred_bytes;
+
+        Some(DownloadStatistics {
+            downloaded_bytes: state.downloaded_bytes,
+            total_bytes: state.total_bytes,
+            bytes_per_sec,
+            average_bytes_per_sec,
+            eta_secs: self.eta_secs(&state, average_bytes_per_sec),
+        })
+    }
+
+    fn eta_secs(&self, state: &ProgressState, average_bytes_per_sec: u64) -> Option<u64> {
+        if state.sized_file_count < self.file_count || average_bytes_per_sec == 0 {
+            return None;
+        }
+        let remaining_bytes = state.total_bytes.saturating_sub(state.downloaded_bytes);
+        Some((remaining_bytes as f64 / average_bytes_per_sec as f64).ceil() as u64)
+    }
+}
+
+fn compute_speed(byte_count: u64, elapsed: Duration) -> u64 {
+    (byte_count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        let progress = DownloadProgress::new(2);
+        progress.add_file_size(1000);
+        let state = progress.state.lock().unwrap();
+        // The size of the second file is unknown
+        assert_eq!(None, progress.eta_secs(&state, 100));
+        drop(state);
+
+        progress.add_file_size(1000);
+        let mut state = progress.state.lock().unwrap();
+        state.downloaded_bytes = 500;
+        assert_eq!(Some(15), progress.eta_secs(&state, 100));
+        assert_eq!(Some(2), progress.eta_secs(&state, 1000));
+        assert_eq!(None, progress.eta_secs(&state, 0));
+    }
+
+    #[test]
+    fn test_restarted_download() {
+        let progress = DownloadProgress::new(1);
+        progress.add_file_size(1000);
+        assert_eq!(None, progress.update(0, 600));
+        // The download restarts from scratch
+        assert_eq!(None, progress.update(600, 0));
+        assert_eq!(None, progress.update(0, 100));
+        let state = progress.state.lock().unwrap();
+        assert_eq!(100, state.downloaded_bytes);
+        assert_eq!(700, state.transferred_bytes);
+    }
+
+    #[test]
+    fn test_completed_bytes() {
+        let progress = DownloadProgress::new(2);
+        progress.add_file_size(1000);
+        assert_eq!(None, progress.total_bytes());
+        progress.add_file_size(500);
+        assert_eq!(Some(1500), progress.total_bytes());
+        // Reused files don't count as transferred data
+        progress.add_completed_bytes(500);
+        let state = progress.state.lock().unwrap();
+        assert_eq!(500, state.downloaded_bytes);
+        assert_eq!(0, state.transferred_bytes);
+    }
+}
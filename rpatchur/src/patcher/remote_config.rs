@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tokio::runtime;
+use url::Url;
+
+use super::config::{
+    expand_templates, validate_configuration, ChannelConfiguration, InvalidConfiguration,
+    PatchServerInfo, PatcherConfiguration,
+};
+use super::data_dir::get_instance_data_file_path;
+use super::http::{build_http_client, fetch_text};
+use super::signature::verify_signature;
+
+/// Maximum time spent fetching the remote configuration, which delays the
+/// patcher's startup
+const REMOTE_CONFIGURATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Values of the configuration that the remote configuration can override.
+/// Others (e.g. executables or keys) can only be set in the local
+/// configuration, and are ignored.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ConfigurationOverlay {
+    pub web: WebOverlay,
+    pub play: PlayOverlay,
+    pub channels: Option<HashMap<String, ChannelConfiguration>>, // Replaces all the channels
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct WebOverlay {
+    pub index_url: Option<String>,
+    pub preferred_patch_server: Option<String>,
+    pub patch_servers: Option<Vec<PatchServerInfo>>, // Replaces all the patch servers
+    pub repair_url: Option<String>,
+    pub news_url: Option<String>,
+    pub changelog_url: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct PlayOverlay {
+    pub arguments: Option<Vec<String>>,
+    pub login_arguments: Option<Vec<String>>,
+}
+
+impl ConfigurationOverlay {
+    /// Overrides the values of `config` that the overlay sets, except those
+    /// set by environment variables or on the command line. `config` is left
+    /// untouched if the resulting configuration is invalid.
+    pub fn apply_to(self, config: &mut PatcherConfiguration) -> Result<()> {
+        let mut new_config = config.clone();
+        let is_applicable = |field: &str| {
+            let is_overridden = config.is_overridden(field);
+            if is_overridden {
+                log::info!("Keeping '{}', which has been overridden", field);
+            }
+            !is_overridden
+        };
+        let web = self.web;
+        if let Some(index_url) = web.index_url.filter(|_| is_applicable("web.index_url")) {
+            new_config.web.index_url = index_url;
+        }
+        if web.preferred_patch_server.is_some() && is_applicable("web.preferred_patch_server") {
+            new_config.web.preferred_patch_server = web.preferred_patch_server;
+        }
+        if let Some(patch_servers) = web
+            .patch_servers
+            .filter(|_| is_applicable("web.patch_servers"))
+        {
+            new_config.web.patch_servers = patch_servers;
+        }
+        if web.repair_url.is_some() && is_applicable("web.repair_url") {
+            new_config.web.repair_url = web.repair_url;
+        }
+        if web.news_url.is_some() && is_applicable("web.news_url") {
+            new_config.web.news_url = web.news_url;
+        }
+        if web.changelog_url.is_some() && is_applicable("web.changelog_url") {
+            new_config.web.changelog_url = web.changelog_url;
+        }
+        if let Some(arguments) = self
+            .play
+            .arguments
+            .filter(|_| is_applicable("play.arguments"))
+        {
+            new_config.play.arguments = arguments;
+        }
+        if self.play.login_arguments.is_some() && is_applicable("play.login_arguments") {
+            new_config.play.login_arguments = self.play.login_arguments;
+        }
+        if let Some(channels) = self.channels.filter(|_| is_applicable("channels")) {
+            new_config.channels = channels;
+        }
+        expand_templates(&mut new_config)?;
+        let issues = validate_configuration(&new_config);
+        if !issues.is_empty() {
+            return Err(InvalidConfiguration(issues)).context("Invalid remote configuration");
+        }
+        *config = new_config;
+        Ok(())
+    }
+}
+
+/// Merges the remote configuration pointed at by `remote_config_url` over
+/// `config`, so that patch servers can be moved without shipping a new
+/// configuration to every player.
+///
+/// The last remote configuration fetched is used when the server cannot be
+/// reached. Failures are only logged, the local configuration is used as is.
+pub fn apply_remote_configuration(config: &mut PatcherConfiguration) {
+    let remote_config_url = match &config.remote_config_url {
+        None => return,
+        Some(remote_config_url) => remote_config_url.clone(),
+    };
+    // Note: The remote configuration can redirect downloads and logins, it
+    // mustn't be alterable by the network
+    if !is_authenticated_source(config, &remote_config_url) {
+        log::warn!(
+            "Ignoring remote configuration '{}', it must be signed (see \
+             `web.plist_public_key`) or fetched over HTTPS",
+            remote_config_url
+        );
+        return;
+    }
+    let res = get_instance_data_file_path("remote.yml").and_then(|cache_file_path| {
+        load_remote_configuration(config, &remote_config_url, &cache_file_path)
+    });
+    match res.and_then(|overlay| overlay.apply_to(config)) {
+        Err(e) => log::warn!("Ignoring remote configuration: {:#}", e),
+        Ok(()) => log::info!("Applied remote configuration '{}'", remote_config_url),
+    }
+}
+
+/// Indicates whether the remote configuration at `remote_config_url` can be
+/// trusted: it must either be signed (i.e. `web.plist_public_key` is set),
+/// fetched over HTTPS or read from the local file system.
+fn is_authenticated_source(config: &PatcherConfiguration, remote_config_url: &str) -> bool {
+    config.web.plist_public_key.is_some()
+        || Url::parse(remote_config_url)
+            .map(|url| matches!(url.scheme(), "https" | "file"))
+            .unwrap_or(false)
+}
+
+/// Fetches and parses the remote configuration, and saves it into
+/// `cache_file_path`. The saved copy is used if it cannot be fetched.
+fn load_remote_configuration(
+    config: &PatcherConfiguration,
+    remote_config_url: &str,
+    cache_file_path: &Path,
+) -> Result<ConfigurationOverlay> {
+    let res = fetch_remote_configuration(config, remote_config_url).and_then(|content| {
+        let overlay = parse_overlay(&content)?;
+        Ok((overlay, content))
+    });
+    match res {
+        Ok((overlay, content)) => {
+            if let Err(e) = fs::write(cache_file_path, content) {
+                log::warn!("Failed to save the remote configuration: {}", e);
+            }
+            Ok(overlay)
+        }
+        Err(e) => {
+            log::warn!("Failed to fetch the remote configuration: {:#}", e);
+            let content = fs::read_to_string(cache_file_path)
+                .context("No copy of the remote configuration is available")?;
+            log::info!("Using the last remote configuration fetched");
+            parse_overlay(&content)
+        }
+    }
+}
+
+/// Parses a remote configuration, written in YAML or JSON.
+fn parse_overlay(content: &str) -> Result<ConfigurationOverlay> {
+    let value: serde_yaml::Value =
+        serde_yaml::from_str(content).context("Failed to parse the remote configuration")?;
+    // Note: serde also deserializes structs from sequences, whose missing
+    // elements get default values
+    if !value.is_mapping() {
+        return Err(anyhow!("The remote configuration must be a mapping"));
+    }
+    for section in &["web", "play"] {
+        if value.get(section).map_or(false, |v| !v.is_mapping()) {
+            return Err(anyhow!("'{}' must be a mapping", section));
+        }
+    }
+    serde_yaml::from_value(value).context("Failed to parse the remote configuration")
+}
+
+/// Downloads the remote configuration. Like patch lists, it must be signed
+/// ('<remote_config_url>.sig') if `web.plist_public_key` is set.
+fn fetch_remote_configuration(
+    config: &PatcherConfiguration,
+    remote_config_url: &str,
+) -> Result<String> {
+    let tokio_rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to build a tokio runtime")?;
+    tokio_rt.block_on(async {
+        let remote_config_url = Url::parse(remote_config_url).context("Invalid URL")?;
+        let http_client = build_http_client(config)?;
+        let fetch = async {
+            let content = fetch_text(&http_client, remote_config_url.as_str(), &config.web).await?;
+            if let Some(public_key) = &config.web.plist_public_key {
+                let mut signature_url = remote_config_url.clone();
+                signature_url.set_path(&format!("{}.sig", remote_config_url.path()));
+                let signature = fetch_text(&http_client, signature_url.as_str(), &config.web)
+                    .await
+                    .context("Remote configuration signature is unavailable")?;
+                verify_signature(content.as_bytes(), &signature, public_key)
+                    .context("Failed to verify the remote configuration's signature")?;
+            }
+            Ok(content)
+        };
+        tokio::time::timeout(REMOTE_CONFIGURATION_TIMEOUT, fetch)
+            .await
+            .context("Timed out")?
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn example_configuration() -> PatcherConfiguration {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = fs::File::open(config_file_path).unwrap();
+        serde_yaml::from_reader(config_file).unwrap()
+    }
+
+    #[test]
+    fn test_load_remote_configuration() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let remote_config_path = temp_dir.path().join("remote.json");
+        let cache_file_path = temp_dir.path().join("rpatchur.remote.yml");
+        let remote_config_url = Url::from_file_path(&remote_config_path).unwrap();
+        let mut config = example_configuration();
+        fs::write(
+            &remote_config_path,
+            r#"{
+                "web": {
+                    "patch_servers": [{
+                        "name": "New Patch Server",
+                        "plist_url": "https://new.myserver.com/plist.txt",
+                        "patch_url": "https://new.myserver.com/data/"
+                    }],
+                    "news_url": "https://new.myserver.com/news.json"
+                },
+                "play": {"path": "malware.exe", "arguments": ["1rag1"]}
+            }"#,
+        )
+        .unwrap();
+
+        let overlay =
+            load_remote_configuration(&config, remote_config_url.as_str(), &cache_file_path)
+                .unwrap();
+        overlay.apply_to(&mut config).unwrap();
+        assert_eq!(config.web.patch_servers.len(), 1);
+        assert_eq!(config.web.patch_servers[0].name, "New Patch Server");
+        assert_eq!(
+            config.web.news_url.as_deref(),
+            Some("https://new.myserver.com/news.json")
+        );
+        assert_eq!(config.play.arguments, vec!["1rag1".to_string()]);
+        // Values that cannot be overridden are ignored
+        assert_eq!(config.play.path, "ragexe.exe");
+        assert_eq!(config.web.index_url, "https://myserver.com/index.html");
+
+        // The last remote configuration fetched is used when it's unavailable
+        fs::remove_file(&remote_config_path).unwrap();
+        let overlay =
+            load_remote_configuration(&config, remote_config_url.as_str(), &cache_file_path)
+                .unwrap();
+        assert_eq!(overlay.web.patch_servers.unwrap().len(), 1);
+        fs::remove_file(&cache_file_path).unwrap();
+        assert!(
+            load_remote_configuration(&config, remote_config_url.as_str(), &cache_file_path)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_invalid_overlay() {
+        let mut config = example_configuration();
+        let overlay = parse_overlay(
+            "web:\n  index_url: not a URL\n  news_url: https://new.myserver.com/news.json\n",
+        )
+        .unwrap();
+        assert!(overlay.apply_to(&mut config).is_err());
+        assert_eq!(config.web.index_url, "https://myserver.com/index.html");
+        assert!(config.web.news_url.is_none());
+        assert!(parse_overlay("web: [1, 2]").is_err());
+        assert!(parse_overlay("play: [-login]").is_err());
+        assert!(parse_overlay("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_unauthenticated_source() {
+        let mut config = example_configuration();
+        assert!(is_authenticated_source(
+            &config,
+            "https://myserver.com/rpatchur.json"
+        ));
+        assert!(!is_authenticated_source(
+            &config,
+            "http://myserver.com/rpatchur.json"
+        ));
+        assert!(is_authenticated_source(
+            &config,
+            "file:///home/user/rpatchur.json"
+        ));
+        // Signed remote configurations can be fetched from anywhere
+        config.web.plist_public_key = Some("BASE64_ENCODED_PUBLIC_KEY".to_string());
+        assert!(is_authenticated_source(
+            &config,
+            "http://myserver.com/rpatchur.json"
+        ));
+    }
+
+    #[test]
+    fn test_overridden_fields() {
+        let mut config = example_configuration();
+        config.overridden_fields = vec![
+            "web.patch_servers.0.plist_url".to_string(),
+            "web.news".to_string(),
+        ];
+        let overlay = parse_overlay(
+            "web:\n  patch_servers: []\n  news_url: https://new.myserver.com/news.json\n",
+        )
+        .unwrap();
+        overlay.apply_to(&mut config).unwrap();
+        // Fields set by environment variables or on the command line win
+        assert_eq!(config.web.patch_servers.len(), 2);
+        assert_eq!(
+            config.web.news_url.as_deref(),
+            Some("https://new.myserver.com/news.json")
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use gruf::grf::GrfArchive;
+use gruf::NameEncoding;
+use serde::Deserialize;
+use url::Url;
+
+use super::installed_files::sha256_hex;
+use super::patching::{destination_path, UnsafePathHandling};
+
+/// Name of the manifest file, located in the directory given by
+/// `web.repair_url`
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Expected content of one of the client's files, or of an entry of one of
+/// its GRFs.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String, // Path relative to the installation directory or to `grf`, with Windows-style separators
+    pub grf: Option<String>, // GRF containing the entry, `None` for files on disk
+    pub sha256: String, // Hex-encoded SHA-256 hash of the content
+}
+
+impl ManifestEntry {
+    /// Returns the location of the entry, as reported to the user.
+    pub fn display_path(&self) -> String {
+        match &self.grf {
+            Some(grf_name) => format!("{}:{}", grf_name, self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Returns the URL the pristine content of the entry is downloaded from.
+    /// It mirrors the client's layout under `repair_url`, GRF entries being
+    /// located under a directory named after their GRF.
+    pub fn url(&self, repair_url: &Url) -> Result<Url> {
+        let relative_url = match &self.grf {
+            Some(grf_name) => format!("{}/{}", grf_name, self.path.replace('\\', "/")),
+            None => self.path.replace('\\', "/"),
+        };
+        repair_url
+            .join(&relative_url)
+            .with_context(|| format!("Invalid path '{}'", self.path))
+    }
+
+    /// Indicates whether `content` matches the entry's hash.
+    pub fn is_content_valid(&self, content: impl Read) -> Result<bool> {
+        let hash = sha256_hex(content)?;
+        Ok(hash.eq_ignore_ascii_case(self.sha256.trim()))
+    }
+}
+
+/// List of the files (and GRF entries) that make up an intact client,
+/// published by the server.
+#[derive(Deserialize, Default, Debug)]
+pub struct RepairManifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+pub fn parse_manifest(content: &str) -> Result<RepairManifest> {
+    serde_json::from_str(content).context("Invalid repair manifest")
+}
+
+/// Returns the entries of `manifest` that are missing from the client
+/// installed in `install_directory` or whose content differs.
+///
+/// `on_progress` is called with the number of entries checked so far and the
+/// total number of entries.
+pub fn find_broken_entries(
+    manifest: &RepairManifest,
+    install_directory: &Path,
+    name_encoding: NameEncoding,
+    on_progress: &mut dyn FnMut(usize, usize),
+) -> Result<Vec<ManifestEntry>> {
+    let total_count = manifest.files.len();
+    let mut checked_count = 0;
+    let mut broken_entries = vec![];
+    // Note: GRFs are only opened once
+    let mut grf_entries: BTreeMap<&str, Vec<&ManifestEntry>> = BTreeMap::new();
+    for entry in &manifest.files {
+        match &entry.grf {
+            Some(grf_name) => grf_entries.entry(grf_name).or_default().push(entry),
+            None => {
+                if !is_file_intact(install_directory, entry)? {
+                    broken_entries.push(entry.clone());
+                }
+                checked_count += 1;
+                on_progress(checked_count, total_count);
+            }
+        }
+    }
+    for (grf_name, entries) in grf_entries {
+        let grf_path = destination_path(install_directory, grf_name, UnsafePathHandling::Reject)?
+            .unwrap_or_default();
+        // Entries of missing or unreadable GRFs are all broken
+        let mut grf_archive = match GrfArchive::open_with_encoding(&grf_path, name_encoding) {
+            Ok(grf_archive) => Some(grf_archive),
+            Err(e) => {
+                log::warn!("Failed to open '{}': {}", grf_path.display(), e);
+                None
+            }
+        };
+        for entry in entries {
+            let is_intact = match grf_archive.as_mut() {
+                Some(grf_archive) => match grf_archive.read_file_content(&entry.path) {
+                    Ok(content) => entry.is_content_valid(content.as_slice())?,
+                    Err(_) => false,
+                },
+                None => false,
+            };
+            if !is_intact {
+                broken_entries.push(entry.clone());
+            }
+            checked_count += 1;
+            on_progress(checked_count, total_count);
+        }
+    }
+    Ok(broken_entries)
+}
+
+fn is_file_intact(install_directory: &Path, entry: &ManifestEntry) -> Result<bool> {
+    let file_path = destination_path(install_directory, &entry.path, UnsafePathHandling::Reject)?
+        .unwrap_or_default();
+    match File::open(&file_path) {
+        Ok(file) => entry.is_content_valid(BufReader::new(file)),
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::grf::GrfArchiveBuilder;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // SHA-256 hash of "content"
+    const CONTENT_HASH: &str = "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73";
+
+    #[test]
+    fn test_find_broken_entries() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(temp_dir.path().join("intact.txt"), b"content").unwrap();
+        fs::write(temp_dir.path().join("modified.txt"), b"modified").unwrap();
+        {
+            let grf_file = File::create(temp_dir.path().join("data.grf")).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\intact.txt".to_string(), &b"content"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        let manifest = parse_manifest(&format!(
+            r#"{{"files": [
+                {{"path": "intact.txt", "sha256": "{0}"}},
+                {{"path": "modified.txt", "sha256": "{0}"}},
+                {{"path": "missing.txt", "sha256": "{0}"}},
+                {{"path": "data\\intact.txt", "grf": "data.grf", "sha256": "{0}"}},
+                {{"path": "data\\missing.txt", "grf": "data.grf", "sha256": "{0}"}},
+                {{"path": "data\\missing.txt", "grf": "missing.grf", "sha256": "{0}"}}
+            ]}}"#,
+            CONTENT_HASH
+        ))
+        .unwrap();
+
+        let mut last_progress = (0, 0);
+        let broken_entries = find_broken_entries(
+            &manifest,
+            temp_dir.path(),
+            NameEncoding::Windows1252,
+            &mut |checked_count, total_count| last_progress = (checked_count, total_count),
+        )
+        .unwrap();
+
+        let broken_paths: Vec<String> = broken_entries
+            .iter()
+            .map(ManifestEntry::display_path)
+            .collect();
+        assert_eq!(
+            broken_paths,
+            vec![
+                "modified.txt",
+                "missing.txt",
+                "data.grf:data\\missing.txt",
+                "missing.grf:data\\missing.txt"
+            ]
+        );
+        assert_eq!(last_progress, (6, 6));
+    }
+
+    #[test]
+    fn test_manifest_entry_url() {
+        let repair_url = Url::parse("https://example.com/client/").unwrap();
+        let entry = ManifestEntry {
+            path: "data\\texture\\file.bmp".to_string(),
+            grf: Some("data.grf".to_string()),
+            sha256: CONTENT_HASH.to_string(),
+        };
+        assert_eq!(
+            entry.url(&repair_url).unwrap().as_str(),
+            "https://example.com/client/data.grf/data/texture/file.bmp"
+        );
+    }
+}
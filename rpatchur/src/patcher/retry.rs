@@ -0,0 +1,159 @@
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::StatusCode;
+use tokio::time::error::Elapsed;
+
+use super::config::RetryConfiguration;
+
+/// Keeps track of the attempts made for a single operation and computes the
+/// delay to wait before the next one (exponential backoff with optional
+/// jitter).
+pub struct Backoff<'a> {
+    config: &'a RetryConfiguration,
+    attempt: u32,
+}
+
+impl<'a> Backoff<'a> {
+    pub fn new(config: &'a RetryConfiguration) -> Self {
+        Self { config, attempt: 1 }
+    }
+
+    /// Returns the number of the current attempt (starting at 1).
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.config.max_attempts.max(1)
+    }
+
+    /// Returns the delay to wait before retrying after `err`, or `None` if the
+    /// operation should not be retried (permanent error or no attempts left).
+    pub fn next_delay(&mut self, err: &anyhow::Error) -> Option<Duration> {
+        if self.attempt >= self.max_attempts() || !is_transient_error(err) {
+            return None;
+        }
+        let delay = backoff_delay(self.config, self.attempt);
+        self.attempt += 1;
+        Some(delay)
+    }
+}
+
+/// Computes the delay to wait after the given (failed) attempt.
+fn backoff_delay(config: &RetryConfiguration, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(31);
+    let delay_ms = config
+        .initial_delay_ms
+        .saturating_mul(1_u64 << exponent)
+        .min(config.max_delay_ms);
+    if config.jitter && delay_ms > 0 {
+        // "Equal jitter": pick a random delay in [delay / 2, delay]
+        Duration::from_millis(rand::thread_rng().gen_range(delay_ms / 2..=delay_ms))
+    } else {
+        Duration::from_millis(delay_ms)
+    }
+}
+
+/// Error returned when a response's body ends before all the expected bytes
+/// have been received (e.g. dropped connection or file changed on the
+/// server).
+#[derive(Debug)]
+pub struct TruncatedBody {
+    pub end: u64,      // Offset at which the body ended
+    pub expected: u64, // Offset at which it should have ended
+}
+
+impl fmt::Display for TruncatedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Response ended at byte {}, expected {}",
+            self.end, self.expected
+        )
+    }
+}
+
+impl std::error::Error for TruncatedBody {}
+
+/// Indicates whether an error is worth retrying (connection issues, timeouts,
+/// truncated responses and server-side errors).
+fn is_transient_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if cause.is::<Elapsed>() || cause.is::<TruncatedBody>() {
+            return true;
+        }
+        match cause.downcast_ref::<reqwest::Error>() {
+            Some(e) => match e.status() {
+                Some(status) => {
+                    status.is_server_error()
+                        || status == StatusCode::TOO_MANY_REQUESTS
+                        || status == StatusCode::REQUEST_TIMEOUT
+                }
+                None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            },
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let config = RetryConfiguration {
+            max_attempts: 10,
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter: false,
+        };
+        assert_eq!(Duration::from_millis(100), backoff_delay(&config, 1));
+        assert_eq!(Duration::from_millis(200), backoff_delay(&config, 2));
+        assert_eq!(Duration::from_millis(800), backoff_delay(&config, 4));
+        // Capped
+        assert_eq!(Duration::from_millis(1000), backoff_delay(&config, 5));
+        assert_eq!(Duration::from_millis(1000), backoff_delay(&config, 100));
+
+        let config = RetryConfiguration {
+            jitter: true,
+            ..config
+        };
+        for attempt in 1..10 {
+            let delay = backoff_delay(&config, attempt);
+            assert!(delay <= Duration::from_millis(1000));
+            assert!(delay >= Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let config = RetryConfiguration::default();
+        let mut backoff = Backoff::new(&config);
+        let err = anyhow::anyhow!("Archive is corrupt");
+        assert!(backoff.next_delay(&err).is_none());
+        assert_eq!(1, backoff.attempt());
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_are_retried() {
+        let config = RetryConfiguration::default();
+        let mut backoff = Backoff::new(&config);
+        let elapsed =
+            tokio::time::timeout(Duration::from_millis(1), futures::future::pending::<()>())
+                .await
+                .unwrap_err();
+        let err = anyhow::Error::from(elapsed).context("Failed to download file");
+        assert!(backoff.next_delay(&err).is_some());
+        assert_eq!(2, backoff.attempt());
+
+        let err = anyhow::Error::from(TruncatedBody {
+            end: 100,
+            expected: 200,
+        });
+        assert!(backoff.next_delay(&err).is_some());
+        assert_eq!(3, backoff.attempt());
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Progress of an update session, saved as it goes so that an update
+/// interrupted by a crash resumes at the patch it stopped at instead of
+/// downloading everything again.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SessionJournal {
+    pub download_directory: PathBuf, // Directory the patches are downloaded into
+    pub downloaded_patches: Vec<String>, // Patches downloaded but not applied yet
+    pub applied_patches: Vec<String>, // Patches applied during the session
+}
+
+impl SessionJournal {
+    /// Reads the journal saved in `file_path`. There's none if the last
+    /// session completed.
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Option<Self>> {
+        match File::open(file_path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .map(Some)
+                .context("Failed to deserialize session journal"),
+        }
+    }
+
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer(file, self).context("Failed to serialize session journal")
+    }
+
+    pub fn is_applied(&self, patch_name: &str) -> bool {
+        self.applied_patches.iter().any(|name| name == patch_name)
+    }
+
+    pub fn record_applied_patch(&mut self, patch_name: &str) {
+        self.downloaded_patches.retain(|name| name != patch_name);
+        if !self.is_applied(patch_name) {
+            self.applied_patches.push(patch_name.to_string());
+        }
+    }
+}
+
+/// Records that the patch named `patch_name` has been applied, in the journal
+/// saved in `file_path`.
+pub fn record_applied_patch(file_path: impl AsRef<Path>, patch_name: &str) -> Result<()> {
+    let mut session_journal = SessionJournal::load(&file_path)?.unwrap_or_default();
+    session_journal.record_applied_patch(patch_name);
+    session_journal.save(file_path)
+}
+
+/// Removes the journal saved in `file_path`, once its session is over.
+pub fn remove_session_journal(file_path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_file(file_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_session_journal() {
+        let temp_dir = tempdir().unwrap();
+        let journal_path = temp_dir.path().join("rpatchur.session");
+        assert_eq!(SessionJournal::load(&journal_path).unwrap(), None);
+        SessionJournal {
+            download_directory: temp_dir.path().join("downloads"),
+            downloaded_patches: vec!["patch1.thor".to_string(), "patch2.thor".to_string()],
+            applied_patches: vec![],
+        }
+        .save(&journal_path)
+        .unwrap();
+
+        record_applied_patch(&journal_path, "patch1.thor").unwrap();
+        record_applied_patch(&journal_path, "patch1.thor").unwrap();
+
+        let session_journal = SessionJournal::load(&journal_path).unwrap().unwrap();
+        assert_eq!(session_journal.downloaded_patches, vec!["patch2.thor"]);
+        assert_eq!(session_journal.applied_patches, vec!["patch1.thor"]);
+        assert!(session_journal.is_applied("patch1.thor"));
+        assert!(!session_journal.is_applied("patch2.thor"));
+        remove_session_journal(&journal_path).unwrap();
+        remove_session_journal(&journal_path).unwrap();
+        assert_eq!(SessionJournal::load(&journal_path).unwrap(), None);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{get_patcher_name, PatcherConfiguration};
+
+/// Settings the UI can read and change at runtime, named after their path in
+/// the configuration. Others (e.g. patch servers or keys) can only be set in
+/// the configuration file.
+const USER_SETTINGS: &[&str] = &[
+    "client.install_directory",
+    "web.preferred_patch_server",
+    "web.max_download_speed",
+    "web.keep_downloads",
+    "web.update_check_interval",
+    "web.auto_update",
+    "patching.in_place",
+    "patching.in_place_max_size",
+    "patching.check_integrity",
+    "patching.backup",
+    "patching.exclude_patterns",
+];
+
+/// Choices made by the user through the UI, which override the configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct UserSettings(BTreeMap<String, Value>);
+
+impl UserSettings {
+    /// Reads the settings saved in `file_path`, there are none if the file
+    /// doesn't exist.
+    pub fn load(file_path: impl AsRef<Path>) -> Result<Self> {
+        match File::open(file_path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .context("Failed to deserialize user settings"),
+        }
+    }
+
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer_pretty(file, self).context("Failed to serialize user settings")
+    }
+
+    /// Changes the setting named `name` and applies it to `config`. Nothing
+    /// changes if the setting is unknown or the value is invalid.
+    pub fn set(
+        &mut self,
+        config: &mut PatcherConfiguration,
+        name: &str,
+        value: Value,
+    ) -> Result<()> {
+        apply_setting(config, name, value.clone())?;
+        self.0.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Overrides the configuration with the settings. Invalid settings (e.g.
+    /// saved by another version of the patcher) are ignored.
+    pub fn apply(&self, config: &mut PatcherConfiguration) {
+        for (name, value) in &self.0 {
+            if let Err(e) = apply_setting(config, name, value.clone()) {
+                log::warn!("Ignoring setting '{}': {:#}", name, e);
+            }
+        }
+    }
+}
+
+/// Returns the current value of each setting the UI can change.
+pub fn get_user_settings(config: &PatcherConfiguration) -> BTreeMap<String, Value> {
+    USER_SETTINGS
+        .iter()
+        .filter_map(|name| get_setting(config, name).map(|value| (name.to_string(), value)))
+        .collect()
+}
+
+/// Returns the path of the file the user settings are saved in (e.g.
+/// 'rpatchur.settings.json').
+pub fn get_user_settings_file_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(get_patcher_name()?).with_extension("settings.json"))
+}
+
+fn get_setting(config: &PatcherConfiguration, name: &str) -> Option<Value> {
+    let value = match name {
+        "client.install_directory" => json!(config.client.install_directory),
+        "web.preferred_patch_server" => json!(config.web.preferred_patch_server),
+        "web.max_download_speed" => json!(config.web.max_download_speed),
+        "web.keep_downloads" => json!(config.web.keep_downloads),
+        "web.update_check_interval" => json!(config.web.update_check_interval),
+        "web.auto_update" => json!(config.web.auto_update),
+        "patching.in_place" => json!(config.patching.in_place),
+        "patching.in_place_max_size" => json!(config.patching.in_place_max_size),
+        "patching.check_integrity" => json!(config.patching.check_integrity),
+        "patching.backup" => json!(config.patching.backup),
+        "patching.exclude_patterns" => json!(config.patching.exclude_patterns),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn apply_setting(config: &mut PatcherConfiguration, name: &str, value: Value) -> Result<()> {
+    let invalid_value = || format!("Invalid value for '{}'", name);
+    match name {
+        "client.install_directory" => {
+            config.client.install_directory =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "web.preferred_patch_server" => {
+            config.web.preferred_patch_server =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "web.max_download_speed" => {
+            config.web.max_download_speed =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "web.keep_downloads" => {
+            config.web.keep_downloads = serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "web.update_check_interval" => {
+            config.web.update_check_interval =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "web.auto_update" => {
+            config.web.auto_update = serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "patching.in_place" => {
+            config.patching.in_place = serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "patching.in_place_max_size" => {
+            config.patching.in_place_max_size =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "patching.check_integrity" => {
+            config.patching.check_integrity =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "patching.backup" => {
+            config.patching.backup = serde_json::from_value(value).with_context(invalid_value)?
+        }
+        "patching.exclude_patterns" => {
+            config.patching.exclude_patterns =
+                serde_json::from_value(value).with_context(invalid_value)?
+        }
+        _ => return Err(anyhow!("Unknown setting '{}'", name)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_user_settings() {
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = File::open(config_file_path).unwrap();
+        let mut config: PatcherConfiguration = serde_yaml::from_reader(config_file).unwrap();
+        let user_settings = get_user_settings(&config);
+        assert_eq!(user_settings.len(), USER_SETTINGS.len());
+        // Settings can be set to their current value
+        let mut settings = UserSettings::default();
+        for (name, value) in user_settings {
+            settings.set(&mut config, &name, value).unwrap();
+        }
+
+        let mut settings = UserSettings::default();
+        let in_place = !config.patching.in_place;
+        settings
+            .set(&mut config, "patching.in_place", json!(in_place))
+            .unwrap();
+        settings
+            .set(&mut config, "web.max_download_speed", json!(512))
+            .unwrap();
+        assert_eq!(config.web.max_download_speed, Some(512));
+        assert!(settings
+            .set(&mut config, "web.max_download_speed", json!("fast"))
+            .is_err());
+        assert!(settings
+            .set(&mut config, "patching.encryption_key", json!("key"))
+            .is_err());
+        assert_eq!(config.web.max_download_speed, Some(512));
+        assert_eq!(config.patching.encryption_key, None);
+
+        let temp_dir = tempdir().unwrap();
+        let settings_file_path = temp_dir.path().join("rpatchur.settings.json");
+        assert_eq!(
+            UserSettings::load(&settings_file_path).unwrap(),
+            UserSettings::default()
+        );
+        settings.save(&settings_file_path).unwrap();
+        let loaded_settings = UserSettings::load(&settings_file_path).unwrap();
+        assert_eq!(loaded_settings, settings);
+        let config_file_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../examples/rpatchur.yml");
+        let config_file = File::open(config_file_path).unwrap();
+        let mut default_config: PatcherConfiguration =
+            serde_yaml::from_reader(config_file).unwrap();
+        loaded_settings.apply(&mut default_config);
+        assert_eq!(default_config.patching.in_place, in_place);
+        assert_eq!(default_config.web.max_download_speed, Some(512));
+    }
+}
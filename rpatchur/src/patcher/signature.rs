@@ -0,0 +1,43 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+
+/// Verifies that `content` has been signed with the private key matching
+/// `public_key`.
+///
+/// Both `signature` and `public_key` are expected to be base64-encoded.
+pub fn verify_signature(content: &[u8], signature: &str, public_key: &str) -> Result<()> {
+    let public_key = base64::decode(public_key.trim()).with_context(|| "Invalid public key")?;
+    let public_key = PublicKey::from_bytes(&public_key).with_context(|| "Invalid public key")?;
+    let signature = base64::decode(signature.trim()).with_context(|| "Invalid signature")?;
+    let signature =
+        Signature::try_from(signature.as_slice()).map_err(|_| anyhow!("Invalid signature"))?;
+    public_key
+        .verify(content, &signature)
+        .map_err(|_| anyhow!("Signature doesn't match"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{ExpandedSecretKey, SecretKey};
+
+    #[test]
+    fn test_verify_signature() {
+        let secret_key = SecretKey::from_bytes(&[42_u8; 32]).unwrap();
+        let public_key = PublicKey::from(&secret_key);
+        let content = b"1 patch1.thor\n2 patch2.thor\n";
+        let signature = ExpandedSecretKey::from(&secret_key).sign(content, &public_key);
+
+        let encoded_public_key = base64::encode(public_key.as_bytes());
+        let encoded_signature = base64::encode(signature.to_bytes());
+        assert!(verify_signature(content, &encoded_signature, &encoded_public_key).is_ok());
+        // Tampered content
+        assert!(
+            verify_signature(b"1 evil.thor\n", &encoded_signature, &encoded_public_key).is_err()
+        );
+        // Malformed signature
+        assert!(verify_signature(content, "AAAA", &encoded_public_key).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use url::Url;
+
+/// Parses a URL read from the configuration.
+///
+/// Paths to local files or directories (including network shares) are
+/// accepted as well, and converted into 'file://' URLs.
+pub fn parse_source_url(value: &str, is_directory: bool) -> Result<Url> {
+    match Url::parse(value) {
+        // Single-letter "schemes" are actually Windows drive letters
+        Ok(url) if url.scheme().len() > 1 => Ok(url),
+        _ => {
+            let path = std::env::current_dir()
+                .with_context(|| "Failed to resolve current working directory")?
+                .join(value);
+            let url = if is_directory {
+                Url::from_directory_path(&path)
+            } else {
+                Url::from_file_path(&path)
+            };
+            url.map_err(|_| anyhow!("Invalid URL or path '{}'", value))
+        }
+    }
+}
+
+/// Indicates whether `url` designates a file on the local file system.
+pub fn is_local_url(url: &Url) -> bool {
+    url.scheme() == "file"
+}
+
+/// Converts a 'file://' URL into a path.
+pub fn local_path_from_url(url: &Url) -> Result<PathBuf> {
+    url.to_file_path()
+        .map_err(|_| anyhow!("Invalid file URL '{}'", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source_url() {
+        let url = parse_source_url("https://myserver.com/plist.txt", false).unwrap();
+        assert!(!is_local_url(&url));
+
+        let url = parse_source_url("file:///srv/patches/plist.txt", false).unwrap();
+        assert!(is_local_url(&url));
+
+        let url = parse_source_url("patches", true).unwrap();
+        assert!(is_local_url(&url));
+        assert!(url.path().ends_with("/patches/"));
+        let expected_path = std::env::current_dir().unwrap().join("patches");
+        assert_eq!(expected_path, local_path_from_url(&url).unwrap());
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket shared by concurrent downloads in order to cap the overall
+/// download speed.
+pub struct BandwidthLimiter {
+    max_bytes_per_sec: u64,
+    state: Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    available_bytes: f64, // Can be negative, in which case callers have to wait
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    pub fn new(max_bytes_per_sec: u64) -> Self {
+        Self {
+            max_bytes_per_sec,
+            state: Mutex::new(LimiterState {
+                available_bytes: max_bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn max_bytes_per_sec(&self) -> u64 {
+        self.max_bytes_per_sec
+    }
+
+    /// Accounts for `byte_count` downloaded bytes and waits as long as needed
+    /// to stay under the configured speed.
+    pub async fn consume(&self, byte_count: usize) {
+        let delay = self.reserve(byte_count);
+        if delay > Duration::from_secs(0) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Takes `byte_count` bytes from the bucket and returns the time to wait
+    /// before the bytes are "paid for".
+    fn reserve(&self, byte_count: usize) -> Duration {
+        let rate = self.max_bytes_per_sec.max(1) as f64;
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Duration::from_secs(0),
+        };
+        // Refill the bucket, allowing bursts of at most one second
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.available_bytes = (state.available_bytes + elapsed * rate).min(rate);
+        state.last_refill = now;
+
+        state.available_bytes -= byte_count as f64;
+        if state.available_bytes < 0.0 {
+            Duration::from_secs_f64(-state.available_bytes / rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let limiter = BandwidthLimiter::new(1000);
+        // Bursts of up to one second worth of data aren't delayed
+        assert_eq!(Duration::from_secs(0), limiter.reserve(1000));
+        // Subsequent reservations have to wait for the bucket to refill
+        let delay = limiter.reserve(500);
+        assert!(delay > Duration::from_millis(400));
+        assert!(delay <= Duration::from_millis(500));
+        let delay = limiter.reserve(1000);
+        assert!(delay > Duration::from_millis(1400));
+        assert!(delay <= Duration::from_millis(1500));
+    }
+}
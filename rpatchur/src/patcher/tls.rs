@@ -0,0 +1,288 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::{Certificate, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use sha2::{Digest, Sha256};
+
+use super::config::TlsConfiguration;
+
+/// Configures how the client verifies the certificates of the servers it
+/// connects to.
+pub fn configure_tls(
+    builder: reqwest::ClientBuilder,
+    tls_config: &TlsConfiguration,
+) -> Result<reqwest::ClientBuilder> {
+    let mut builder = builder;
+    if tls_config.is_pinning_enabled() {
+        // Note: Pins replace the verification against root certificates,
+        // the other options would be silently ignored
+        if tls_config.ca_certificate.is_some() || tls_config.accept_invalid_certs {
+            return Err(anyhow!(
+                "Pinned certificates and public keys cannot be combined with \
+                 `ca_certificate` or `accept_invalid_certs`"
+            ));
+        }
+        let parse_fingerprints = |fingerprints: &[String]| {
+            fingerprints
+                .iter()
+                .map(|fingerprint| parse_fingerprint(fingerprint))
+                .collect::<Result<Vec<_>>>()
+        };
+        let verifier = PinnedCertificateVerifier {
+            fingerprints: parse_fingerprints(&tls_config.pinned_certificates)?,
+            public_key_fingerprints: parse_fingerprints(&tls_config.pinned_public_keys)?,
+        };
+        let mut rustls_config = rustls::ClientConfig::new();
+        rustls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+        log::info!("Using certificate pinning");
+        // Note: Pinned certificates are verified with rustls, as the default
+        // backend doesn't allow custom verifications
+        return Ok(builder.use_preconfigured_tls(rustls_config));
+    }
+    if let Some(ca_certificate_path) = &tls_config.ca_certificate {
+        let content = std::fs::read(ca_certificate_path)
+            .with_context(|| format!("Failed to read '{}'", ca_certificate_path))?;
+        let certificate = if content.starts_with(b"-----BEGIN") {
+            reqwest::Certificate::from_pem(&content)
+        } else {
+            reqwest::Certificate::from_der(&content)
+        }
+        .with_context(|| format!("Invalid certificate '{}'", ca_certificate_path))?;
+        log::info!("Trusting root certificate '{}'", ca_certificate_path);
+        builder = builder.add_root_certificate(certificate);
+    }
+    if tls_config.accept_invalid_certs {
+        log::warn!("!!! TLS certificate verification is DISABLED, connections are insecure !!!");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder)
+}
+
+/// Parses a hex-encoded SHA-256 fingerprint. Bytes can be separated with
+/// colons (e.g. "AB:CD:...").
+fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32]> {
+    let hex_digits: Vec<u8> = fingerprint
+        .bytes()
+        .filter(|c| *c != b':' && !c.is_ascii_whitespace())
+        .collect();
+    let invalid_fingerprint = || anyhow!("Invalid SHA-256 fingerprint '{}'", fingerprint);
+    if hex_digits.len() != 64 {
+        return Err(invalid_fingerprint());
+    }
+    let mut result = [0_u8; 32];
+    for (byte, digits) in result.iter_mut().zip(hex_digits.chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid_fingerprint())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid_fingerprint())?;
+    }
+    Ok(result)
+}
+
+/// Only accepts server certificates whose SHA-256 fingerprint is in
+/// `fingerprints`, or whose public key's is in `public_key_fingerprints`.
+/// Pinning public keys allows certificates to be renewed with the same key.
+///
+/// Certificate chains aren't validated: pinned certificates are trusted as
+/// they are (which allows self-signed certificates to be used).
+struct PinnedCertificateVerifier {
+    fingerprints: Vec<[u8; 32]>,
+    public_key_fingerprints: Vec<[u8; 32]>, // Fingerprints of DER-encoded SubjectPublicKeyInfo structures
+}
+
+impl PinnedCertificateVerifier {
+    fn is_pinned(&self, certificate: &Certificate) -> bool {
+        let is_fingerprint_pinned = |fingerprints: &[[u8; 32]], content: &[u8]| {
+            let fingerprint = Sha256::digest(content);
+            fingerprints
+                .iter()
+                .any(|pinned| pinned[..] == fingerprint[..])
+        };
+        if is_fingerprint_pinned(&self.fingerprints, &certificate.0) {
+            return true;
+        }
+        match subject_public_key_info(&certificate.0) {
+            Some(public_key_info) => {
+                is_fingerprint_pinned(&self.public_key_fingerprints, public_key_info)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Element of a DER-encoded structure.
+struct DerElement<'a> {
+    tag: u8,
+    encoded: &'a [u8], // Whole element, including its tag and length
+    content: &'a [u8],
+    rest: &'a [u8], // Elements that follow this one
+}
+
+/// Reads the first element of `input`.
+fn read_der_element(input: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, tail) = input.split_first()?;
+    let (&first_length_byte, mut tail) = tail.split_first()?;
+    let length = if first_length_byte < 0x80 {
+        usize::from(first_length_byte)
+    } else {
+        let length_size = usize::from(first_length_byte & 0x7F);
+        if length_size == 0 || length_size > 4 || tail.len() < length_size {
+            return None;
+        }
+        let (length_bytes, content) = tail.split_at(length_size);
+        tail = content;
+        length_bytes
+            .iter()
+            .fold(0_usize, |length, byte| (length << 8) | usize::from(*byte))
+    };
+    if tail.len() < length {
+        return None;
+    }
+    let header_size = input.len() - tail.len();
+    Some(DerElement {
+        tag,
+        encoded: &input[..header_size + length],
+        content: &tail[..length],
+        rest: &tail[length..],
+    })
+}
+
+/// Returns the DER-encoded SubjectPublicKeyInfo of a DER-encoded X.509
+/// certificate (see RFC 5280, section 4.1).
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xA0;
+    let certificate = read_der_element(certificate).filter(|e| e.tag == SEQUENCE)?;
+    let tbs_certificate = read_der_element(certificate.content).filter(|e| e.tag == SEQUENCE)?;
+    let mut element = read_der_element(tbs_certificate.content)?;
+    if element.tag == VERSION {
+        element = read_der_element(element.rest)?;
+    }
+    // Skip the serial number, the signature's algorithm, the issuer, the
+    // validity period and the subject
+    for _ in 0..5 {
+        element = read_der_element(element.rest)?;
+    }
+    Some(element.encoded).filter(|_| element.tag == SEQUENCE)
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let end_entity_cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        if self.is_pinned(end_entity_cert) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::General(
+                "Server certificate doesn't match any pinned certificate".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_pinning() {
+        let certificate = Certificate(b"not really a certificate".to_vec());
+        let fingerprint = Sha256::digest(&certificate.0);
+        let mut expected_fingerprint = [0_u8; 32];
+        expected_fingerprint.copy_from_slice(&fingerprint);
+        let hex_fingerprint: Vec<String> = fingerprint
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+
+        assert_eq!(
+            expected_fingerprint,
+            parse_fingerprint(&hex_fingerprint.join(":")).unwrap()
+        );
+        assert_eq!(
+            expected_fingerprint,
+            parse_fingerprint(&hex_fingerprint.join("").to_lowercase()).unwrap()
+        );
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint(&"ZZ".repeat(32)).is_err());
+
+        let verifier = PinnedCertificateVerifier {
+            fingerprints: vec![expected_fingerprint],
+            public_key_fingerprints: vec![],
+        };
+        assert!(verifier.is_pinned(&certificate));
+        assert!(!verifier.is_pinned(&Certificate(b"another certificate".to_vec())));
+    }
+
+    /// Encodes a DER element, with a long-form length if needed.
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        match content.len() {
+            length if length < 0x80 => encoded.push(length as u8),
+            length => encoded.extend_from_slice(&[0x82, (length >> 8) as u8, length as u8]),
+        }
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    fn build_certificate(serial_number: u8, public_key: &[u8]) -> (Certificate, Vec<u8>) {
+        let public_key_info = der(0x30, &[der(0x30, b"rsa"), der(0x03, public_key)].concat());
+        let tbs_certificate = [
+            der(0xA0, &der(0x02, &[2])),
+            der(0x02, &[serial_number]),
+            der(0x30, b"sha256WithRSAEncryption"),
+            der(0x30, b"issuer"),
+            der(0x30, b"validity"),
+            der(0x30, b"subject"),
+            public_key_info.clone(),
+            der(0xA3, b"extensions"),
+        ]
+        .concat();
+        let certificate = [
+            der(0x30, &tbs_certificate),
+            der(0x30, b"sha256WithRSAEncryption"),
+            der(0x03, b"signature"),
+        ]
+        .concat();
+        (Certificate(der(0x30, &certificate)), public_key_info)
+    }
+
+    #[test]
+    fn test_public_key_pinning() {
+        let (certificate, public_key_info) = build_certificate(1, &[0x2A; 270]);
+        assert_eq!(
+            subject_public_key_info(&certificate.0),
+            Some(public_key_info.as_slice())
+        );
+        assert_eq!(subject_public_key_info(&certificate.0[..100]), None);
+        assert_eq!(subject_public_key_info(b"not a certificate"), None);
+
+        let mut public_key_fingerprint = [0_u8; 32];
+        public_key_fingerprint.copy_from_slice(&Sha256::digest(&public_key_info));
+        let verifier = PinnedCertificateVerifier {
+            fingerprints: vec![],
+            public_key_fingerprints: vec![public_key_fingerprint],
+        };
+        assert!(verifier.is_pinned(&certificate));
+        // Renewed certificates are accepted as long as their key is the same
+        assert!(verifier.is_pinned(&build_certificate(2, &[0x2A; 270]).0));
+        assert!(!verifier.is_pinned(&build_certificate(2, &[0x2B; 270]).0));
+    }
+
+    #[test]
+    fn test_conflicting_options() {
+        let tls_config = TlsConfiguration {
+            ca_certificate: Some("my_ca.pem".to_string()),
+            pinned_public_keys: vec!["AB".repeat(32)],
+            ..Default::default()
+        };
+        assert!(configure_tls(reqwest::Client::builder(), &tls_config).is_err());
+    }
+}
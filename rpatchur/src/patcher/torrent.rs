@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::process::Command;
+use url::Url;
+
+use super::config::TorrentConfiguration;
+
+/// Schemes of the torrent URLs that can be passed to the BitTorrent client
+const TORRENT_URL_SCHEMES: [&str; 3] = ["http", "https", "magnet"];
+
+/// Downloads the torrent (or magnet link) located at `torrent_url` with an
+/// external BitTorrent client and moves the downloaded file to `dest_path`.
+///
+/// The torrent is expected to contain a single file named `file_name`.
+pub async fn download_torrent(
+    config: &TorrentConfiguration,
+    torrent_url: &str,
+    file_name: &str,
+    dest_path: &Path,
+) -> Result<()> {
+    let torrent_url = parse_torrent_url(torrent_url)?;
+    let work_dir = dest_path.with_extension("torrent.d");
+    tokio::fs::create_dir_all(&work_dir)
+        .await
+        .with_context(|| format!("Failed to create directory '{}'", work_dir.display()))?;
+    let _guard = scopeguard::guard((), |_| {
+        let _ = std::fs::remove_dir_all(&work_dir);
+    });
+
+    let work_dir_str = work_dir.to_string_lossy();
+    // Note: '{url}' is replaced last, so that references in the URL aren't
+    // expanded
+    let arguments = config.arguments.iter().map(|arg| {
+        arg.replace("{dir}", work_dir_str.as_ref())
+            .replace("{url}", torrent_url.as_str())
+    });
+    log::debug!("Starting BitTorrent client for '{}'", torrent_url);
+    let status = Command::new(&config.client_path)
+        .args(arguments)
+        .kill_on_drop(true) // Stop the client if the download is canceled
+        .status()
+        .await
+        .with_context(|| format!("Failed to start '{}'", config.client_path))?;
+    if !status.success() {
+        return Err(anyhow!("BitTorrent client exited with status '{}'", status));
+    }
+
+    let downloaded_file_path = work_dir.join(file_name);
+    tokio::fs::rename(&downloaded_file_path, dest_path)
+        .await
+        .with_context(|| format!("Torrent didn't provide '{}'", file_name))
+}
+
+/// Parses a torrent URL coming from a patch list, which must be an HTTP(S) URL
+/// or a magnet link. These can't be mistaken for options by the client.
+fn parse_torrent_url(torrent_url: &str) -> Result<Url> {
+    let url = Url::parse(torrent_url)
+        .with_context(|| format!("Invalid torrent URL '{}'", torrent_url))?;
+    if !TORRENT_URL_SCHEMES.contains(&url.scheme()) {
+        return Err(anyhow!(
+            "Unsupported torrent URL scheme '{}', expected one of {}",
+            url.scheme(),
+            TORRENT_URL_SCHEMES.join(", ")
+        ));
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_torrent_url() {
+        assert!(parse_torrent_url("https://myserver.com/patch.thor.torrent").is_ok());
+        assert!(parse_torrent_url("magnet:?xt=urn:btih:0123").is_ok());
+        for torrent_url in &[
+            "--on-download-complete=cmd.exe",
+            "-d/tmp",
+            "file:///etc/passwd",
+            "patch.thor.torrent",
+        ] {
+            assert!(parse_torrent_url(torrent_url).is_err());
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use advisory_lock::{AdvisoryFileLock, FileLockError, FileLockMode};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::process::is_process_id_running;
+
+/// Error returned when the update lock is held by another instance of the
+/// patcher.
+#[derive(Debug)]
+pub struct AlreadyPatching {
+    pub pid: Option<u32>, // Process of the other instance, if known
+}
+
+impl fmt::Display for AlreadyPatching {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "Another instance (PID {}) is already patching", pid),
+            None => write!(f, "Another instance is already patching"),
+        }
+    }
+}
+
+impl std::error::Error for AlreadyPatching {}
+
+/// Process holding the update lock, recorded in the lock file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct LockOwner {
+    pid: u32,
+    timestamp: u64, // Seconds since the Unix epoch, when the lock was taken
+}
+
+/// Lock that prevents multiple instances of the patcher from modifying the
+/// game's files at the same time.
+pub struct UpdateLock {
+    file: File,
+}
+
+impl UpdateLock {
+    /// Takes the lock stored at `lock_file_path`. Fails with `AlreadyPatching`
+    /// if another instance holds it.
+    ///
+    /// The lock file is locked with an advisory lock, which the OS releases
+    /// when its owner dies. Locks left by instances that crashed are thus
+    /// taken over. On file systems that don't support locking (e.g. some
+    /// network shares), the owner recorded in the file is checked instead.
+    pub fn acquire(lock_file_path: impl AsRef<Path>) -> Result<Self> {
+        let lock_file_path = lock_file_path.as_ref();
+        // Note: The file mustn't be truncated before it's locked, not to erase
+        // the owner recorded by another instance
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_file_path)
+            .with_context(|| format!("Failed to open '{}'", lock_file_path.display()))?;
+        match AdvisoryFileLock::try_lock(&file, FileLockMode::Exclusive) {
+            Ok(()) => {}
+            Err(FileLockError::AlreadyLocked) => {
+                return Err(AlreadyPatching {
+                    pid: read_lock_owner(&file).map(|owner| owner.pid),
+                }
+                .into());
+            }
+            Err(FileLockError::Io(e)) => {
+                log::warn!(
+                    "Failed to lock '{}': {}, relying on its owner",
+                    lock_file_path.display(),
+                    e
+                );
+                if let Some(owner) = read_lock_owner(&file) {
+                    if is_owner_running(&owner) {
+                        return Err(AlreadyPatching {
+                            pid: Some(owner.pid),
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+        if let Some(owner) = read_lock_owner(&file) {
+            log::info!("Taking over stale update lock of process {}", owner.pid);
+        }
+        let update_lock = Self { file };
+        update_lock.write_owner(Some(LockOwner {
+            pid: std::process::id(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+        }))?;
+        Ok(update_lock)
+    }
+
+    /// Releases the lock, which can then be taken by other instances.
+    pub fn release(&self) {
+        if let Err(e) = self.write_owner(None) {
+            log::warn!("Failed to clear the update lock's owner: {:#}", e);
+        }
+        let _ = AdvisoryFileLock::unlock(&self.file);
+    }
+
+    fn write_owner(&self, owner: Option<LockOwner>) -> Result<()> {
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        if let Some(owner) = owner {
+            serde_json::to_writer(&mut file, &owner)
+                .context("Failed to serialize update lock owner")?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns the owner recorded in a lock file, if any.
+///
+/// Note: This fails on Windows while another process holds the lock.
+fn read_lock_owner(mut file: &File) -> Option<LockOwner> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Indicates whether the owner of a lock that couldn't be locked is still
+/// running. Owners that can't be checked are considered gone, not to lock
+/// users out because of a stale lock.
+fn is_owner_running(owner: &LockOwner) -> bool {
+    if owner.pid == std::process::id() {
+        return false;
+    }
+    match is_process_id_running(owner.pid) {
+        Ok(is_running) => is_running,
+        Err(e) => {
+            log::warn!("Failed to check process {}: {:#}", owner.pid, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_update_lock() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lock_file_path = temp_dir.path().join("rpatchur.lock");
+
+        let update_lock = UpdateLock::acquire(&lock_file_path).unwrap();
+        let err = UpdateLock::acquire(&lock_file_path).err().unwrap();
+        let already_patching = err.downcast_ref::<AlreadyPatching>().unwrap();
+        assert_eq!(already_patching.pid, Some(std::process::id()));
+
+        // Released locks can be taken again, and don't record an owner anymore
+        update_lock.release();
+        assert!(fs::read(&lock_file_path).unwrap().is_empty());
+        let update_lock = UpdateLock::acquire(&lock_file_path).unwrap();
+        update_lock.release();
+
+        // Locks left behind by instances that crashed are taken over
+        fs::write(&lock_file_path, "{\"pid\": 4294967295, \"timestamp\": 0}").unwrap();
+        let update_lock = UpdateLock::acquire(&lock_file_path).unwrap();
+        let owner = read_lock_owner(&update_lock.file).unwrap();
+        assert_eq!(owner.pid, std::process::id());
+        assert!(owner.timestamp > 0);
+    }
+
+    #[test]
+    fn test_is_owner_running() {
+        assert!(!is_owner_running(&LockOwner {
+            pid: std::process::id(),
+            timestamp: 0,
+        }));
+        assert!(!is_owner_running(&LockOwner {
+            pid: u32::MAX,
+            timestamp: 0,
+        }));
+    }
+}
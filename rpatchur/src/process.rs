@@ -1,10 +1,17 @@
+use std::path::Path;
+
 use anyhow::Result;
 
-/// Starts an executable file in a cross-platform way.
+/// Starts an executable file in a cross-platform way. Relative paths are
+/// resolved from `working_directory`, in which the process is started.
 ///
 /// This is the Windows version.
 #[cfg(windows)]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    working_directory: &Path,
+    exe_arguments: I,
+) -> Result<bool>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
@@ -13,14 +20,19 @@ where
     let exe_parameter = exe_arguments
         .into_iter()
         .fold(String::new(), |a: String, b| a + " " + b.as_ref() + "");
-    windows::win32_spawn_process_runas(exe_path, &exe_parameter)
+    windows::win32_spawn_process_runas(exe_path, working_directory, &exe_parameter)
 }
 
-/// Starts an executable file in a cross-platform way.
+/// Starts an executable file in a cross-platform way. Relative paths are
+/// resolved from `working_directory`, in which the process is started.
 ///
 /// This is the non-Windows version.
 #[cfg(not(windows))]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    working_directory: &Path,
+    exe_arguments: I,
+) -> Result<bool>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
@@ -31,18 +43,169 @@ where
         .into_iter()
         .map(|e| e.as_ref().into())
         .collect();
-    Command::new(exe_path)
+    // Note: Executables that aren't in `working_directory` are looked up in
+    // the PATH
+    let local_exe_path = working_directory.join(exe_path);
+    let mut command = if local_exe_path.is_file() {
+        Command::new(local_exe_path)
+    } else {
+        Command::new(exe_path)
+    };
+    command
         .args(exe_arguments)
+        .current_dir(working_directory)
         .spawn()
         .map(|_| Ok(true))?
 }
 
+/// Indicates whether a process started from the executable named
+/// `executable_name` (e.g. 'ragexe.exe') is running.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn is_process_running(executable_name: &str) -> Result<bool> {
+    use std::process::Command;
+
+    let output = Command::new("tasklist")
+        .args(["/NH", "/FO", "CSV", "/FI"])
+        .arg(format!("IMAGENAME eq {}", executable_name))
+        .output()?;
+    // Note: Matching processes are listed as CSV rows starting with their
+    // quoted name, a message is printed otherwise
+    let quoted_name = format!("\"{}\"", executable_name.to_lowercase());
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.to_lowercase().starts_with(&quoted_name)))
+}
+
+/// Indicates whether a process started from the executable named
+/// `executable_name` (e.g. 'ragexe.exe') is running.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn is_process_running(executable_name: &str) -> Result<bool> {
+    use std::process::Command;
+
+    // Note: pgrep exits with code 1 when no process matches
+    let output = Command::new("pgrep")
+        .args(["-x", executable_name])
+        .output()?;
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(anyhow::anyhow!(
+            "'pgrep' exited with status '{}'",
+            output.status
+        )),
+    }
+}
+
+/// Indicates whether the process identified by `pid` is running.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn is_process_id_running(pid: u32) -> Result<bool> {
+    use std::process::Command;
+
+    let output = Command::new("tasklist")
+        .args(["/NH", "/FO", "CSV", "/FI"])
+        .arg(format!("PID eq {}", pid))
+        .output()?;
+    // Note: The matching process is listed as a CSV row, a message is printed
+    // otherwise
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with('"')))
+}
+
+/// Indicates whether the process identified by `pid` is running.
+///
+/// This is the non-Windows version.
+#[cfg(not(windows))]
+pub fn is_process_id_running(pid: u32) -> Result<bool> {
+    use std::process::Command;
+
+    // Note: ps exits with code 1 when no process matches
+    let output = Command::new("ps").args(["-p", &pid.to_string()]).output()?;
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(anyhow::anyhow!(
+            "'ps' exited with status '{}'",
+            output.status
+        )),
+    }
+}
+
+/// Starts the patcher again with administrator privileges, which shows a UAC
+/// prompt, passing it `arguments`. Returns false if the user refused.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn spawn_patcher_elevated(arguments: &[String]) -> Result<bool> {
+    let (exe_path, working_directory) = windows::current_exe_location()?;
+    let parameter = windows::join_arguments(arguments);
+    windows::win32_spawn_process_runas(
+        exe_path.as_os_str(),
+        &working_directory,
+        std::ffi::OsStr::new(&parameter),
+    )
+}
+
+/// Starts the patcher again with administrator privileges, passing it
+/// `arguments`.
+///
+/// This is the non-Windows version, elevation isn't supported.
+#[cfg(not(windows))]
+pub fn spawn_patcher_elevated(_arguments: &[String]) -> Result<bool> {
+    Err(anyhow::anyhow!("Elevation is only supported on Windows"))
+}
+
+/// Runs the patcher with administrator privileges, which shows a UAC prompt,
+/// passing it `arguments`. Waits for it to exit and returns its exit code.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn run_patcher_elevated(arguments: &[String]) -> Result<u32> {
+    let (exe_path, working_directory) = windows::current_exe_location()?;
+    windows::win32_run_process_runas(
+        &exe_path,
+        &working_directory,
+        &windows::join_arguments(arguments),
+    )
+}
+
+/// Runs the patcher with administrator privileges, passing it `arguments`.
+///
+/// This is the non-Windows version, elevation isn't supported.
+#[cfg(not(windows))]
+pub fn run_patcher_elevated(_arguments: &[String]) -> Result<u32> {
+    Err(anyhow::anyhow!("Elevation is only supported on Windows"))
+}
+
+/// Indicates whether the patcher runs with administrator privileges.
+///
+/// This is the Windows version.
+#[cfg(windows)]
+pub fn is_elevated() -> bool {
+    unsafe { winapi::um::shlobj::IsUserAnAdmin() != 0 }
+}
+
+/// Indicates whether the patcher runs with administrator privileges.
+///
+/// This is the non-Windows version, elevation isn't supported.
+#[cfg(not(windows))]
+pub fn is_elevated() -> bool {
+    false
+}
+
 // Note: Taken from the rustup project
 #[cfg(windows)]
 mod windows {
-    use anyhow::{anyhow, Result};
+    use anyhow::{anyhow, Context, Result};
     use std::ffi::OsStr;
     use std::os::windows::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
 
     fn to_u16s<S: AsRef<OsStr>>(s: S) -> Result<Vec<u16>> {
         fn inner(s: &OsStr) -> Result<Vec<u16>> {
@@ -58,7 +221,11 @@ mod windows {
 
     /// This function is required to start processes that require elevation, from
     /// a non-elevated process.
-    pub fn win32_spawn_process_runas<S>(path: S, parameter: S) -> Result<bool>
+    pub fn win32_spawn_process_runas<S>(
+        path: S,
+        working_directory: &Path,
+        parameter: S,
+    ) -> Result<bool>
     where
         S: AsRef<OsStr>,
     {
@@ -73,9 +240,10 @@ mod windows {
         const SW_SHOW: c_int = 5;
 
         // Note: It seems `path` has to be absolute for the class overwrite to work
-        let exe_path = std::env::current_dir()?.join(path.as_ref());
+        let exe_path = working_directory.join(path.as_ref());
         let exe_path = to_u16s(exe_path.to_str().unwrap_or(""))?;
         let parameter = to_u16s(parameter)?;
+        let directory = to_u16s(working_directory)?;
         let operation = to_u16s("runas")?;
         let class = to_u16s("exefile")?;
         let mut execute_info = SHELLEXECUTEINFOW {
@@ -85,7 +253,7 @@ mod windows {
             lpVerb: operation.as_ptr(),
             lpFile: exe_path.as_ptr(),
             lpParameters: parameter.as_ptr(),
-            lpDirectory: ptr::null_mut(),
+            lpDirectory: directory.as_ptr(),
             nShow: SW_SHOW,
             hInstApp: ptr::null_mut(),
             lpIDList: ptr::null_mut(),
@@ -99,4 +267,95 @@ mod windows {
         let result = unsafe { ShellExecuteExW(&mut execute_info) };
         Ok(result != 0)
     }
+
+    /// Starts a process with administrator privileges like
+    /// `win32_spawn_process_runas`, waits for it to exit and returns its exit
+    /// code. `exe_path` must be absolute.
+    pub fn win32_run_process_runas(
+        exe_path: &Path,
+        working_directory: &Path,
+        parameter: &str,
+    ) -> Result<u32> {
+        use std::ptr;
+        use winapi::ctypes::c_int;
+        use winapi::shared::minwindef::{BOOL, DWORD, ULONG};
+        use winapi::um::handleapi::CloseHandle;
+        use winapi::um::processthreadsapi::GetExitCodeProcess;
+        use winapi::um::shellapi::SHELLEXECUTEINFOW;
+        use winapi::um::synchapi::WaitForSingleObject;
+        use winapi::um::winbase::INFINITE;
+        extern "system" {
+            pub fn ShellExecuteExW(pExecInfo: *mut SHELLEXECUTEINFOW) -> BOOL;
+        }
+        const SEE_MASK_NOCLOSEPROCESS: ULONG = 0x40;
+        const SW_HIDE: c_int = 0;
+
+        let exe_path = to_u16s(exe_path)?;
+        let parameter = to_u16s(parameter)?;
+        let directory = to_u16s(working_directory)?;
+        let operation = to_u16s("runas")?;
+        let mut execute_info = SHELLEXECUTEINFOW {
+            cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
+            fMask: SEE_MASK_NOCLOSEPROCESS,
+            hwnd: ptr::null_mut(),
+            lpVerb: operation.as_ptr(),
+            lpFile: exe_path.as_ptr(),
+            lpParameters: parameter.as_ptr(),
+            lpDirectory: directory.as_ptr(),
+            nShow: SW_HIDE,
+            hInstApp: ptr::null_mut(),
+            lpIDList: ptr::null_mut(),
+            lpClass: ptr::null(),
+            hkeyClass: ptr::null_mut(),
+            dwHotKey: 0,
+            hMonitor: ptr::null_mut(),
+            hProcess: ptr::null_mut(),
+        };
+
+        unsafe {
+            if ShellExecuteExW(&mut execute_info) == 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to start elevated process");
+            }
+            if execute_info.hProcess.is_null() {
+                return Err(anyhow!("Failed to start elevated process"));
+            }
+            WaitForSingleObject(execute_info.hProcess, INFINITE);
+            let mut exit_code: DWORD = 0;
+            let res = GetExitCodeProcess(execute_info.hProcess, &mut exit_code);
+            CloseHandle(execute_info.hProcess);
+            if res == 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to retrieve the exit code of elevated process");
+            }
+            Ok(exit_code)
+        }
+    }
+
+    /// Returns the absolute path of the patcher's executable and the current
+    /// working directory.
+    pub fn current_exe_location() -> Result<(PathBuf, PathBuf)> {
+        let exe_path =
+            std::env::current_exe().context("Failed to find the patcher's executable")?;
+        let working_directory =
+            std::env::current_dir().context("Failed to find the working directory")?;
+        Ok((exe_path, working_directory))
+    }
+
+    /// Joins command-line arguments into a single string, quoting the ones
+    /// that contain spaces.
+    pub fn join_arguments(arguments: &[String]) -> String {
+        arguments
+            .iter()
+            .map(|argument| {
+                if argument.is_empty() || argument.contains(char::is_whitespace) {
+                    // Note: A trailing backslash would escape the closing quote
+                    format!("\"{}\"", argument.trim_end_matches('\\'))
+                } else {
+                    argument.clone()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
 }
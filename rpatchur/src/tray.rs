@@ -0,0 +1,271 @@
+use anyhow::Result;
+
+/// Icon in the system tray, which lets the patcher run minimized and patch in
+/// the background.
+///
+/// The icon is only available on Windows. On other platforms, the window is
+/// minimized instead and notifications are sent with `notify-send`.
+pub struct Tray {
+    inner: platform::Tray,
+}
+
+impl Tray {
+    /// Adds an icon to the system tray. `on_action` is called with the
+    /// actions picked from its menu, on the window's thread which must be the
+    /// current thread.
+    pub fn new(tooltip: &str, on_action: TrayActionHandler) -> Result<Tray> {
+        Ok(Tray {
+            inner: platform::Tray::new(tooltip, on_action)?,
+        })
+    }
+
+    /// Returns true if the window can be hidden, since it can be shown again
+    /// from the tray icon's menu.
+    pub fn has_icon(&self) -> bool {
+        platform::HAS_ICON
+    }
+
+    /// Pops a notification, even if the window is hidden.
+    pub fn notify(&self, title: &str, message: &str) {
+        self.inner.notify(title, message);
+    }
+}
+
+/// Actions of the tray icon's menu.
+// Note: Only the Windows tray icon has a menu
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrayAction {
+    ShowWindow,
+    CheckForUpdates,
+    PatchNow,
+    LaunchGame,
+    Exit,
+}
+
+/// Performs the actions picked from the tray icon's menu.
+pub type TrayActionHandler = Box<dyn Fn(TrayAction)>;
+
+#[cfg(windows)]
+mod platform {
+    use std::cell::{Cell, RefCell};
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::{mem, ptr};
+
+    use anyhow::{anyhow, Result};
+    use winapi::shared::minwindef::{DWORD, LPARAM, LRESULT, UINT, WPARAM};
+    use winapi::shared::windef::{HMENU, HWND, POINT};
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::shellapi::{
+        Shell_NotifyIconW, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_TIP, NIIF_INFO, NIM_ADD,
+        NIM_DELETE, NIM_MODIFY, NOTIFYICONDATAW,
+    };
+    use winapi::um::winuser::{
+        AppendMenuW, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DestroyMenu, DestroyWindow,
+        GetCursorPos, LoadIconW, RegisterClassW, SetForegroundWindow, TrackPopupMenu, HWND_MESSAGE,
+        IDI_APPLICATION, MAKEINTRESOURCEW, MF_STRING, TPM_NONOTIFY, TPM_RETURNCMD, TPM_RIGHTBUTTON,
+        WM_APP, WM_LBUTTONDBLCLK, WM_RBUTTONUP, WNDCLASSW,
+    };
+
+    use super::{TrayAction, TrayActionHandler};
+    use crate::patcher::tr;
+
+    pub const HAS_ICON: bool = true;
+    // Message sent to the tray icon's window when the icon is clicked
+    const WM_TRAY_ICON: UINT = WM_APP + 1;
+    // Resource ID of the executable's icon (see build.rs)
+    const ICON_RESOURCE_ID: u16 = 1;
+    // Actions and message keys of their labels
+    const MENU_ITEMS: [(TrayAction, &str); 5] = [
+        (TrayAction::ShowWindow, "tray.show"),
+        (TrayAction::CheckForUpdates, "tray.check_for_updates"),
+        (TrayAction::PatchNow, "tray.patch_now"),
+        (TrayAction::LaunchGame, "tray.launch_game"),
+        (TrayAction::Exit, "tray.exit"),
+    ];
+
+    thread_local! {
+        // Note: The window procedure has no context, the tray icon's state
+        // lives on the thread that owns its window
+        static ACTION_HANDLER: RefCell<Option<TrayActionHandler>> = RefCell::new(None);
+        static MENU: Cell<HMENU> = Cell::new(ptr::null_mut());
+    }
+
+    pub struct Tray {
+        window: HWND, // Message-only window receiving the icon's events
+        menu: HMENU,
+    }
+
+    impl Tray {
+        pub fn new(tooltip: &str, on_action: TrayActionHandler) -> Result<Tray> {
+            let class_name = to_wide("rpatchur_tray");
+            unsafe {
+                let instance = GetModuleHandleW(ptr::null());
+                let mut window_class: WNDCLASSW = mem::zeroed();
+                window_class.lpfnWndProc = Some(window_proc);
+                window_class.hInstance = instance;
+                window_class.lpszClassName = class_name.as_ptr();
+                // Note: This fails if the class is already registered, which
+                // is fine
+                RegisterClassW(&window_class);
+                let window = CreateWindowExW(
+                    0,
+                    class_name.as_ptr(),
+                    class_name.as_ptr(),
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    HWND_MESSAGE,
+                    ptr::null_mut(),
+                    instance,
+                    ptr::null_mut(),
+                );
+                if window.is_null() {
+                    return Err(anyhow!("Failed to create the tray icon's window"));
+                }
+                let menu = CreatePopupMenu();
+                for (i, (_, label_key)) in MENU_ITEMS.iter().enumerate() {
+                    let label = to_wide(&tr(label_key, &[]));
+                    AppendMenuW(menu, MF_STRING, i + 1, label.as_ptr());
+                }
+
+                let mut icon_data = notify_icon_data(window);
+                icon_data.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP;
+                icon_data.uCallbackMessage = WM_TRAY_ICON;
+                icon_data.hIcon = LoadIconW(instance, MAKEINTRESOURCEW(ICON_RESOURCE_ID));
+                if icon_data.hIcon.is_null() {
+                    icon_data.hIcon = LoadIconW(ptr::null_mut(), IDI_APPLICATION);
+                }
+                copy_wide(&mut icon_data.szTip, tooltip);
+                if Shell_NotifyIconW(NIM_ADD, &mut icon_data) == 0 {
+                    DestroyMenu(menu);
+                    DestroyWindow(window);
+                    return Err(anyhow!("Failed to add the tray icon"));
+                }
+                ACTION_HANDLER.with(|handler| *handler.borrow_mut() = Some(on_action));
+                MENU.with(|current_menu| current_menu.set(menu));
+                Ok(Tray { window, menu })
+            }
+        }
+
+        pub fn notify(&self, title: &str, message: &str) {
+            unsafe {
+                let mut icon_data = notify_icon_data(self.window);
+                icon_data.uFlags = NIF_INFO;
+                icon_data.dwInfoFlags = NIIF_INFO;
+                copy_wide(&mut icon_data.szInfoTitle, title);
+                copy_wide(&mut icon_data.szInfo, message);
+                if Shell_NotifyIconW(NIM_MODIFY, &mut icon_data) == 0 {
+                    log::warn!("Failed to show notification");
+                }
+            }
+        }
+    }
+
+    impl Drop for Tray {
+        fn drop(&mut self) {
+            ACTION_HANDLER.with(|handler| *handler.borrow_mut() = None);
+            MENU.with(|menu| menu.set(ptr::null_mut()));
+            unsafe {
+                let mut icon_data = notify_icon_data(self.window);
+                Shell_NotifyIconW(NIM_DELETE, &mut icon_data);
+                DestroyMenu(self.menu);
+                DestroyWindow(self.window);
+            }
+        }
+    }
+
+    unsafe extern "system" fn window_proc(
+        window: HWND,
+        message: UINT,
+        w_param: WPARAM,
+        l_param: LPARAM,
+    ) -> LRESULT {
+        if message != WM_TRAY_ICON {
+            return DefWindowProcW(window, message, w_param, l_param);
+        }
+        match l_param as UINT {
+            WM_LBUTTONDBLCLK => dispatch_action(TrayAction::ShowWindow),
+            WM_RBUTTONUP => {
+                let mut cursor_position = POINT { x: 0, y: 0 };
+                GetCursorPos(&mut cursor_position);
+                // Note: Required for the menu to close when clicking elsewhere
+                SetForegroundWindow(window);
+                let selected_item = TrackPopupMenu(
+                    MENU.with(|menu| menu.get()),
+                    TPM_RETURNCMD | TPM_NONOTIFY | TPM_RIGHTBUTTON,
+                    cursor_position.x,
+                    cursor_position.y,
+                    0,
+                    window,
+                    ptr::null(),
+                );
+                if selected_item > 0 {
+                    if let Some((action, _)) = MENU_ITEMS.get(selected_item as usize - 1) {
+                        dispatch_action(*action);
+                    }
+                }
+            }
+            _ => {}
+        }
+        0
+    }
+
+    /// Passes `action` to the handler of the tray icon.
+    fn dispatch_action(action: TrayAction) {
+        ACTION_HANDLER.with(|handler| {
+            if let Some(handler) = handler.borrow().as_ref() {
+                handler(action);
+            }
+        });
+    }
+
+    unsafe fn notify_icon_data(window: HWND) -> NOTIFYICONDATAW {
+        let mut icon_data: NOTIFYICONDATAW = mem::zeroed();
+        icon_data.cbSize = mem::size_of::<NOTIFYICONDATAW>() as DWORD;
+        icon_data.hWnd = window;
+        icon_data.uID = 1;
+        icon_data
+    }
+
+    fn to_wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+
+    /// Copies `s` into a fixed-size, null-terminated wide string buffer,
+    /// truncating it if needed.
+    fn copy_wide(buffer: &mut [u16], s: &str) {
+        let wide: Vec<u16> = OsStr::new(s).encode_wide().take(buffer.len() - 1).collect();
+        buffer[..wide.len()].copy_from_slice(&wide);
+        buffer[wide.len()] = 0;
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::process::Command;
+
+    use anyhow::Result;
+
+    use super::TrayActionHandler;
+
+    pub const HAS_ICON: bool = false;
+
+    pub struct Tray;
+
+    impl Tray {
+        pub fn new(_tooltip: &str, _on_action: TrayActionHandler) -> Result<Tray> {
+            log::info!("The system tray isn't supported on this platform");
+            Ok(Tray)
+        }
+
+        pub fn notify(&self, title: &str, message: &str) {
+            if let Err(e) = Command::new("notify-send").args([title, message]).spawn() {
+                log::debug!("Failed to show notification: {}", e);
+            }
+        }
+    }
+}
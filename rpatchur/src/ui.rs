@@ -1,21 +1,67 @@
-use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use crate::patcher::{get_patcher_name, PatcherCommand, PatcherConfiguration};
+use crate::deep_link::DeepLink;
+use crate::logs::get_recent_logs;
+use crate::patcher::{
+    build_login_arguments, get_credentials_file_path, get_history_file_path,
+    get_instance_data_file_path, get_user_settings, get_user_settings_file_path, patcher_info,
+    read_history, remove_cache_file, resolve_install_directory, store_credentials, tr, Credentials,
+    LockedFileAction, NewsItem, PatchPreview, PatcherChangelog, PatcherCommand,
+    PatcherConfiguration, PreviewMode, StoredCredentials, UserSettings, HISTORY_LIMIT,
+};
 use crate::process::start_executable;
-use serde::Deserialize;
-use serde_json::Value;
+use crate::tray::{Tray, TrayAction};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tinyfiledialogs as tfd;
-use web_view::{Content, Handle, WebView};
+use url::Url;
+use web_view::{Handle, WebView};
+
+/// Frontend the patching thread reports to (e.g. the web view or the
+/// console).
+///
+/// Methods are called from the patching thread, prompts block it until the
+/// user answers.
+pub trait UiFrontend: Send + Sync {
+    /// Indicates the current status of the patching process.
+    fn dispatch_patching_status(&self, status: PatchingStatus);
+
+    /// Asks the user whether a patch that couldn't be downloaded or applied
+    /// should be skipped.
+    fn prompt_skip_patch(&self, patch_name: &str, error_msg: &str) -> bool;
+
+    /// Warns the user that the game client is running while it's being
+    /// patched.
+    fn warn_running_client(&self, executable_name: &str);
+
+    /// Asks the user whether to patch the game client while it's running.
+    fn prompt_running_client(&self, executable_name: &str) -> bool;
+
+    /// Indicates whether an operation is in progress, for the frontend to
+    /// reject new ones in the meantime.
+    fn set_patch_in_progress(&self, _value: bool) {}
+
+    /// Reports the items of the news feed.
+    fn dispatch_news(&self, _news: Vec<NewsItem>) {}
+
+    /// Reports the changelog of the patcher.
+    fn dispatch_changelog(&self, _changelog: PatcherChangelog) {}
+
+    /// Closes the frontend, and the patcher along with it.
+    fn request_exit(&self) {}
+}
 
 /// 'Opaque" struct that can be used to update the UI.
+#[derive(Clone)]
 pub struct UiController {
-    web_view_handle: Handle<WebViewUserData>,
+    frontend: Arc<dyn UiFrontend>,
 }
 impl UiController {
-    pub fn new(web_view: &WebView<'_, WebViewUserData>) -> UiController {
+    pub fn new(frontend: impl UiFrontend + 'static) -> UiController {
         UiController {
-            web_view_handle: web_view.handle(),
+            frontend: Arc::new(frontend),
         }
     }
 
@@ -23,69 +69,467 @@ impl UiController {
     ///
     /// This updates the UI with useful information.
     pub fn dispatch_patching_status(&self, status: PatchingStatus) {
-        if let Err(e) = self.web_view_handle.dispatch(move |webview| {
-            let result = match status {
-                PatchingStatus::Ready => webview.eval("patchingStatusReady()"),
-                PatchingStatus::Error(msg) => {
-                    webview.eval(&format!("patchingStatusError(\"{}\")", msg))
-                }
-                PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => {
-                    webview.eval(&format!(
-                        "patchingStatusDownloading({}, {}, {})",
-                        nb_downloaded, nb_total, bytes_per_sec
-                    ))
-                }
-                PatchingStatus::InstallationInProgress(nb_installed, nb_total) => webview.eval(
-                    &format!("patchingStatusInstalling({}, {})", nb_installed, nb_total),
-                ),
-                PatchingStatus::ManualPatchApplied(name) => {
-                    webview.eval(&format!("patchingStatusPatchApplied(\"{}\")", name))
-                }
-            };
-            if let Err(e) = result {
+        self.frontend.dispatch_patching_status(status);
+    }
+
+    /// Asks the user whether a patch that couldn't be downloaded or applied
+    /// should be skipped. Blocks until the user answers.
+    pub fn prompt_skip_patch(&self, patch_name: &str, error_msg: &str) -> bool {
+        self.frontend.prompt_skip_patch(patch_name, error_msg)
+    }
+
+    /// Warns the user that the game client is running while it's being
+    /// patched. Blocks until the user acknowledges it.
+    pub fn warn_running_client(&self, executable_name: &str) {
+        self.frontend.warn_running_client(executable_name);
+    }
+
+    /// Asks the user whether to patch the game client while it's running.
+    /// Blocks until the user answers.
+    pub fn prompt_running_client(&self, executable_name: &str) -> bool {
+        self.frontend.prompt_running_client(executable_name)
+    }
+
+    pub fn set_patch_in_progress(&self, value: bool) {
+        self.frontend.set_patch_in_progress(value);
+    }
+
+    pub fn dispatch_news(&self, news: Vec<NewsItem>) {
+        self.frontend.dispatch_news(news);
+    }
+
+    pub fn dispatch_changelog(&self, changelog: PatcherChangelog) {
+        self.frontend.dispatch_changelog(changelog);
+    }
+
+    pub fn request_exit(&self) {
+        self.frontend.request_exit();
+    }
+}
+
+/// Window displaying the launcher's page, whose JS bindings are handled by
+/// the patcher.
+///
+/// Implemented by each web view backend (`web-view`, or `wry` when the
+/// feature is enabled).
+pub trait PatcherWindow {
+    fn user_data(&self) -> &WebViewUserData;
+
+    fn user_data_mut(&mut self) -> &mut WebViewUserData;
+
+    /// Evaluates `js` in the launcher's page.
+    fn eval(&mut self, js: &str) -> Result<()>;
+
+    /// Closes the window, which stops the patcher.
+    fn exit(&mut self);
+
+    fn set_visible(&mut self, visible: bool);
+
+    fn set_minimized(&mut self, minimized: bool);
+
+    /// Returns a dispatcher that runs closures on the window's thread.
+    fn dispatcher(&self) -> Arc<dyn WindowDispatcher>;
+}
+
+/// Closure run on the window's thread.
+pub type WindowTask = Box<dyn FnOnce(&mut dyn PatcherWindow) + Send>;
+
+/// Runs closures on the thread of a `PatcherWindow`, from any thread.
+pub trait WindowDispatcher: Send + Sync {
+    fn dispatch(&self, task: WindowTask) -> Result<()>;
+}
+
+impl PatcherWindow for WebView<'_, WebViewUserData> {
+    fn user_data(&self) -> &WebViewUserData {
+        WebView::user_data(self)
+    }
+
+    fn user_data_mut(&mut self) -> &mut WebViewUserData {
+        WebView::user_data_mut(self)
+    }
+
+    fn eval(&mut self, js: &str) -> Result<()> {
+        Ok(WebView::eval(self, js)?)
+    }
+
+    fn exit(&mut self) {
+        WebView::exit(self);
+    }
+
+    fn set_visible(&mut self, visible: bool) {
+        WebView::set_visible(self, visible);
+    }
+
+    fn set_minimized(&mut self, minimized: bool) {
+        WebView::set_minimized(self, minimized);
+    }
+
+    fn dispatcher(&self) -> Arc<dyn WindowDispatcher> {
+        Arc::new(self.handle())
+    }
+}
+
+impl WindowDispatcher for Handle<WebViewUserData> {
+    fn dispatch(&self, task: WindowTask) -> Result<()> {
+        Handle::dispatch(self, move |webview| {
+            task(webview);
+            Ok(())
+        })
+        .map_err(|e| anyhow!("{}", e))
+    }
+}
+
+/// Frontend that updates the web view.
+pub struct WebViewFrontend {
+    dispatcher: Arc<dyn WindowDispatcher>,
+}
+impl WebViewFrontend {
+    pub fn new(webview: &dyn PatcherWindow) -> WebViewFrontend {
+        WebViewFrontend {
+            dispatcher: webview.dispatcher(),
+        }
+    }
+}
+impl WebViewFrontend {
+    /// Lets the user know that the patcher waits for them to answer a prompt.
+    fn notify_user_action(&self, message: String) {
+        if let Err(e) = self.dispatcher.dispatch(Box::new(move |webview| {
+            notify(
+                webview,
+                &tr("notification.action_required.title", &[]),
+                &message,
+            );
+        })) {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+    }
+}
+impl UiFrontend for WebViewFrontend {
+    fn dispatch_patching_status(&self, status: PatchingStatus) {
+        if let Err(e) = self.dispatcher.dispatch(Box::new(move |webview| {
+            // Note: Lets the user know even if the window is hidden or
+            // in the background
+            if let Some((title, message)) = status_notification(&status) {
+                notify(webview, &title, &message);
+            }
+            let (function_name, arguments) = patching_status_call(status);
+            let script = format!(
+                "{}({})",
+                function_name,
+                arguments
+                    .iter()
+                    .map(Value::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            // Kept for UIs that poll the status with 'get_patching_status'
+            webview.user_data_mut().last_patching_status = Some(json!({
+                "function": function_name,
+                "arguments": arguments,
+            }));
+            if let Err(e) = webview.eval(&script) {
                 log::warn!("Failed to dispatch patching status: {}.", e);
             }
-            Ok(())
-        }) {
+        })) {
             log::warn!("Failed to dispatch patching status: {}.", e);
         }
     }
 
-    pub fn set_patch_in_progress(&self, value: bool) {
-        if let Err(e) = self.web_view_handle.dispatch(move |webview| {
+    fn prompt_skip_patch(&self, patch_name: &str, error_msg: &str) -> bool {
+        self.notify_user_action(tr("notification.patch_failed", &[("patch", patch_name)]));
+        // Note: Dialogs cannot display quotes
+        let message = tr(
+            "dialog.patching_error.message",
+            &[("error", error_msg), ("patch", patch_name)],
+        )
+        .replace(&['"', '\''][..], "");
+        let answer = tfd::message_box_yes_no(
+            &tr("dialog.patching_error.title", &[]),
+            &message,
+            tfd::MessageBoxIcon::Warning,
+            tfd::YesNo::No,
+        );
+        answer == tfd::YesNo::Yes
+    }
+
+    fn warn_running_client(&self, executable_name: &str) {
+        let message = tr(
+            "dialog.client_running.warning",
+            &[("client", executable_name)],
+        )
+        .replace(&['"', '\''][..], "");
+        tfd::message_box_ok(
+            &tr("dialog.client_running.title", &[]),
+            &message,
+            tfd::MessageBoxIcon::Warning,
+        );
+    }
+
+    fn prompt_running_client(&self, executable_name: &str) -> bool {
+        self.notify_user_action(tr(
+            "notification.client_running",
+            &[("client", executable_name)],
+        ));
+        let message = tr(
+            "dialog.client_running.prompt",
+            &[("client", executable_name)],
+        )
+        .replace(&['"', '\''][..], "");
+        let answer = tfd::message_box_yes_no(
+            &tr("dialog.client_running.title", &[]),
+            &message,
+            tfd::MessageBoxIcon::Warning,
+            tfd::YesNo::No,
+        );
+        answer == tfd::YesNo::Yes
+    }
+
+    fn set_patch_in_progress(&self, value: bool) {
+        if let Err(e) = self.dispatcher.dispatch(Box::new(move |webview| {
             webview.user_data_mut().patching_in_progress = value;
-            Ok(())
-        }) {
+        })) {
             log::warn!("Failed to dispatch patching status: {}.", e);
         }
     }
+
+    fn dispatch_news(&self, news: Vec<NewsItem>) {
+        if let Err(e) = self.dispatcher.dispatch(Box::new(move |webview| {
+            let script = format!("patcherNews({})", json!(news));
+            // Kept for UIs that request the news once loaded
+            webview.user_data_mut().news = Some(news);
+            if let Err(e) = webview.eval(&script) {
+                log::warn!("Failed to dispatch news: {}.", e);
+            }
+        })) {
+            log::warn!("Failed to dispatch news: {}.", e);
+        }
+    }
+
+    fn dispatch_changelog(&self, changelog: PatcherChangelog) {
+        if let Err(e) = self.dispatcher.dispatch(Box::new(move |webview| {
+            if let Err(e) = webview.eval(&format!("patcherChangelog({})", json!(changelog))) {
+                log::warn!("Failed to dispatch changelog: {}.", e);
+            }
+        })) {
+            log::warn!("Failed to dispatch changelog: {}.", e);
+        }
+    }
+
+    fn request_exit(&self) {
+        if let Err(e) = self.dispatcher.dispatch(Box::new(|webview| {
+            webview.exit();
+        })) {
+            log::warn!("Failed to exit: {}.", e);
+        }
+    }
+}
+
+/// Returns the JS function that reports `status` to the UI, along with its
+/// arguments.
+pub fn patching_status_call(status: PatchingStatus) -> (&'static str, Vec<Value>) {
+    match status {
+        PatchingStatus::Ready => ("patchingStatusReady", vec![]),
+        PatchingStatus::Error(msg) => ("patchingStatusError", vec![json!(msg)]),
+        PatchingStatus::PatchServerSelected(name) => {
+            ("patchingStatusServerSelected", vec![json!(name)])
+        }
+        PatchingStatus::ChannelSelected(name) => {
+            ("patchingStatusChannelSelected", vec![json!(name)])
+        }
+        PatchingStatus::DownloadInProgress(nb_downloaded, nb_total, bytes_per_sec) => (
+            "patchingStatusDownloading",
+            vec![json!(nb_downloaded), json!(nb_total), json!(bytes_per_sec)],
+        ),
+        PatchingStatus::DownloadProgress(
+            downloaded_bytes,
+            total_bytes,
+            bytes_per_sec,
+            average_bytes_per_sec,
+            eta_secs,
+        ) => (
+            "patchingStatusProgress",
+            vec![
+                json!(downloaded_bytes),
+                json!(total_bytes),
+                json!(bytes_per_sec),
+                json!(average_bytes_per_sec),
+                json!(eta_secs),
+            ],
+        ),
+        PatchingStatus::DownloadThrottled(bytes_per_sec, max_bytes_per_sec) => (
+            "patchingStatusThrottled",
+            vec![json!(bytes_per_sec), json!(max_bytes_per_sec)],
+        ),
+        PatchingStatus::DownloadRetry(name, attempt, max_attempts) => (
+            "patchingStatusRetrying",
+            vec![json!(name), json!(attempt), json!(max_attempts)],
+        ),
+        PatchingStatus::InstallationInProgress(nb_installed, nb_total) => (
+            "patchingStatusInstalling",
+            vec![json!(nb_installed), json!(nb_total)],
+        ),
+        PatchingStatus::PatchInProgress {
+            file,
+            entry,
+            total_entries,
+            bytes_written,
+        } => (
+            "patchingStatusPatchProgress",
+            vec![
+                json!(file),
+                json!(entry),
+                json!(total_entries),
+                json!(bytes_written),
+            ],
+        ),
+        PatchingStatus::GrfRebuildInProgress {
+            grf,
+            entries_done,
+            entries_total,
+        } => (
+            "patchingStatusGrfRebuild",
+            vec![json!(grf), json!(entries_done), json!(entries_total)],
+        ),
+        PatchingStatus::ManualPatchApplied(name) => {
+            ("patchingStatusPatchApplied", vec![json!(name)])
+        }
+        PatchingStatus::PatchesSkipped(names) => {
+            ("patchingStatusPatchesSkipped", vec![json!(names)])
+        }
+        PatchingStatus::Summary(summary) => ("patchingStatusSummary", vec![json!(summary)]),
+        PatchingStatus::Report(patch_reports) => {
+            ("patchingStatusReport", vec![json!(patch_reports)])
+        }
+        PatchingStatus::Preview(previews) => ("patchingStatusPreview", vec![json!(previews)]),
+        PatchingStatus::RolledBack(file_count) => {
+            ("patchingStatusRolledBack", vec![json!(file_count)])
+        }
+        PatchingStatus::VerificationInProgress(nb_checked, nb_total) => (
+            "patchingStatusVerifying",
+            vec![json!(nb_checked), json!(nb_total)],
+        ),
+        PatchingStatus::Repaired(paths) => ("patchingStatusRepaired", vec![json!(paths)]),
+        PatchingStatus::UpdatesAvailable(patch_count) => {
+            ("patchingStatusUpdatesAvailable", vec![json!(patch_count)])
+        }
+        PatchingStatus::FileLocked(path, can_schedule) => (
+            "patchingStatusFileLocked",
+            vec![json!(path), json!(can_schedule)],
+        ),
+        PatchingStatus::Paused => ("patchingStatusPaused", vec![]),
+        PatchingStatus::Resumed => ("patchingStatusResumed", vec![]),
+        PatchingStatus::Finishing => ("patchingStatusFinishing", vec![]),
+        PatchingStatus::ElevatedUpdateInProgress => ("patchingStatusElevated", vec![]),
+        PatchingStatus::AlreadyPatching => ("patchingStatusAlreadyPatching", vec![]),
+    }
 }
 
 /// Used to indicate the current status of the patching process.
 pub enum PatchingStatus {
     Ready,
     Error(String),                         // Error message
+    PatchServerSelected(String),           // Patch server name
+    ChannelSelected(Option<String>),       // Channel name, `None` for the default channel
     DownloadInProgress(usize, usize, u64), // Downloaded files, Total number, Bytes per second
+    DownloadThrottled(u64, u64),           // Effective bytes per second, Limit in bytes per second
+    DownloadRetry(String, u32, u32),       // File name, Attempt number, Maximum number of attempts
     InstallationInProgress(usize, usize),  // Installed patches, Total number
-    ManualPatchApplied(String),            // Patch file name
+    PatchInProgress {
+        file: String,         // Name of the patch being applied
+        entry: usize,         // Processed entries
+        total_entries: usize, // Total number of entries
+        bytes_written: u64,   // Bytes written to GRFs or to disk so far
+    },
+    // Out-of-place rebuild of a GRF, whose original entries are copied too
+    GrfRebuildInProgress {
+        grf: String,          // Name of the GRF being rebuilt
+        entries_done: usize,  // Entries written to the new GRF
+        entries_total: usize, // Total number of entries of the new GRF
+    },
+    ManualPatchApplied(String),  // Patch file name
+    PatchesSkipped(Vec<String>), // Names of the patches that were skipped
+    Summary(UpdateSummary),
+    Report(Vec<PatchReport>),   // Outcome of each patch of an installation
+    Preview(Vec<PatchPreview>), // Changes the pending patches would make
+    RolledBack(usize),          // Number of files restored
+    VerificationInProgress(usize, usize), // Checked files, Total number
+    Repaired(Vec<String>),      // Paths of the files that were repaired
+    UpdatesAvailable(usize),    // Number of new patches found by a background check
+    FileLocked(String, bool), // Path of the locked file (empty if unknown), Whether it can be replaced on the next start
+    Paused,
+    Resumed,
+    Finishing,                // Canceled, stops once the current patch has been applied
+    ElevatedUpdateInProgress, // Update applied by a helper with administrator privileges
+    AlreadyPatching,          // Another instance of the patcher is updating the game
+    // Downloaded bytes, Total bytes, Bytes per second, Average bytes per second, ETA in seconds
+    DownloadProgress(u64, u64, u64, u64, Option<u64>),
+}
+
+/// Statistics about an update session, reported once it's done.
+#[derive(Serialize, Default, Debug)]
+pub struct UpdateSummary {
+    pub downloaded_bytes: u64,
+    pub elapsed_secs: u64,
+    pub average_bytes_per_sec: u64, // Average download speed
+    pub applied_patch_count: usize,
+    pub skipped_patches: Vec<String>, // Names of the patches that were skipped
+    pub protected_files: Vec<String>, // Files left untouched because they're protected
+    pub excluded_files: Vec<String>,  // Files left untouched because they're excluded
+}
+
+/// Outcome of the application of a patch, reported once the installation is
+/// over.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PatchReport {
+    pub name: String,
+    pub index: usize,
+    pub timestamp: u64, // Seconds since the Unix epoch, when the patch was processed
+    pub size: Option<u64>, // Size of the patch in bytes, if known
+    pub duration_ms: u64,
+    pub outcome: PatchOutcome,
+    pub reason: Option<String>, // Why the patch was skipped or failed
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchOutcome {
+    Applied,
+    Skipped, // Failed, but the installation went on
+    Failed,  // Failed and aborted the installation
 }
 
 pub struct WebViewUserData {
-    patcher_config: PatcherConfiguration,
+    patcher_config: PatcherConfiguration, // Configuration of the selected profile
+    default_config: PatcherConfiguration, // Configuration of the default profile
     patching_thread_tx: flume::Sender<PatcherCommand>,
     patching_in_progress: bool,
+    dry_run: Option<PreviewMode>, // Set when updates must only be previewed
+    last_patching_status: Option<Value>, // Last status dispatched to the UI
+    tray: Option<Tray>,           // Set when running minimized to the tray
+    news: Option<Vec<NewsItem>>,  // Items of the news feed, once fetched
 }
 impl WebViewUserData {
     pub fn new(
         patcher_config: PatcherConfiguration,
         patching_thread_tx: flume::Sender<PatcherCommand>,
+        dry_run: Option<PreviewMode>,
     ) -> WebViewUserData {
         WebViewUserData {
+            default_config: patcher_config.clone(),
             patcher_config,
             patching_thread_tx,
             patching_in_progress: false,
+            dry_run,
+            last_patching_status: None,
+            tray: None,
+            news: None,
         }
     }
+
+    /// Returns the configuration of the selected profile.
+    pub fn patcher_config(&self) -> &PatcherConfiguration {
+        &self.patcher_config
+    }
 }
 impl Drop for WebViewUserData {
     fn drop(&mut self) {
@@ -94,40 +538,210 @@ impl Drop for WebViewUserData {
     }
 }
 
+/// Performs the action requested through a deep link, once the user has
+/// confirmed it.
+pub fn open_deep_link(webview: &mut dyn PatcherWindow, deep_link: &DeepLink) {
+    // Note: tinyfiledialogs doesn't support quotes in messages
+    let message = deep_link
+        .confirmation_message()
+        .replace(&['"', '\''][..], "");
+    let answer = tfd::message_box_yes_no(
+        &tr("dialog.deep_link.title", &[]),
+        &message,
+        tfd::MessageBoxIcon::Question,
+        tfd::YesNo::No,
+    );
+    if answer != tfd::YesNo::Yes {
+        log::info!("Deep link declined by the user");
+        return;
+    }
+    if let DeepLink::Update {
+        profile: Some(profile),
+    } = deep_link
+    {
+        match webview
+            .user_data()
+            .default_config
+            .with_profile(Some(profile))
+        {
+            Err(e) => {
+                log::error!("Failed to select profile: {:#}", e);
+                return;
+            }
+            Ok(patcher_config) => {
+                log::info!("Selected profile '{}'", profile);
+                update_patcher_configuration(webview, patcher_config);
+            }
+        }
+    }
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(deep_link.command())
+        .is_ok()
+    {
+        log::trace!("Sent deep link command to patching thread");
+    }
+}
+
 /// Creates a `WebView` object with the appropriate settings for our needs.
+#[cfg(not(feature = "wry"))]
 pub fn build_webview<'a>(
     title: &'a str,
     user_data: WebViewUserData,
 ) -> web_view::WVResult<WebView<'a, WebViewUserData>> {
+    let patcher_config = user_data.patcher_config();
     web_view::builder()
         .title(title)
-        .content(Content::Url(user_data.patcher_config.web.index_url.clone()))
-        .size(
-            user_data.patcher_config.window.width,
-            user_data.patcher_config.window.height,
-        )
-        .resizable(user_data.patcher_config.window.resizable)
+        .content(web_view::Content::Url(patcher_config.web.index_url.clone()))
+        .size(patcher_config.window.width, patcher_config.window.height)
+        .resizable(patcher_config.window.resizable)
         .user_data(user_data)
         .invoke_handler(|webview, arg| {
-            match arg {
-                "play" => handle_play(webview),
-                "setup" => handle_setup(webview),
-                "exit" => handle_exit(webview),
-                "start_update" => handle_start_update(webview),
-                "cancel_update" => handle_cancel_update(webview),
-                "reset_cache" => handle_reset_cache(webview),
-                "manual_patch" => handle_manual_patch(webview),
-                request => handle_json_request(webview, request),
-            }
+            handle_invoke(webview, arg);
             Ok(())
         })
         .build()
 }
 
+/// Calls the handler of the JS binding `arg`, invoked by the launcher's page
+/// with `external.invoke(arg)`.
+pub fn handle_invoke(webview: &mut dyn PatcherWindow, arg: &str) {
+    match arg {
+        "play" => handle_play(webview),
+        "setup" => handle_setup(webview),
+        "exit" => handle_exit(webview),
+        "start_update" => handle_start_update(webview),
+        "cancel_update" => handle_cancel_update(webview),
+        "pause_update" => handle_pause_update(webview),
+        "resume_update" => handle_resume_update(webview),
+        "reset_cache" => handle_reset_cache(webview),
+        "manual_patch" => handle_manual_patch(webview),
+        "apply_local" => handle_apply_local(webview),
+        "rollback" => handle_rollback(webview),
+        "repair" => handle_repair(webview),
+        "get_patching_status" => handle_get_patching_status(webview),
+        "get_config" => handle_get_config(webview),
+        "get_credentials" => handle_get_credentials(webview),
+        "get_profiles" => handle_get_profiles(webview),
+        "check_for_updates" => handle_check_for_updates(webview),
+        "get_logs" => handle_get_logs(webview),
+        "get_news" => handle_get_news(webview),
+        "get_patcher_info" => handle_get_patcher_info(webview),
+        "get_changelog" => handle_get_changelog(webview),
+        "get_history" => handle_get_history(webview),
+        "retry_locked_file" => handle_resolve_locked_file(webview, LockedFileAction::Retry),
+        "schedule_locked_file" => handle_resolve_locked_file(webview, LockedFileAction::Schedule),
+        request => handle_json_request(webview, request),
+    }
+}
+
+/// Adds an icon to the system tray and hides the window, the patcher then
+/// keeps running in the background.
+///
+/// The window is minimized instead on platforms without a tray icon.
+pub fn minimize_to_tray(webview: &mut dyn PatcherWindow) -> Result<()> {
+    let tooltip = webview.user_data().patcher_config.window.title.clone();
+    let tray = new_tray(webview, &tooltip)?;
+    if tray.has_icon() {
+        webview.set_visible(false);
+    } else {
+        webview.set_minimized(true);
+    }
+    webview.user_data_mut().tray = Some(tray);
+    Ok(())
+}
+
+/// Adds an icon to the system tray, whose actions are performed on
+/// `webview`.
+fn new_tray(webview: &dyn PatcherWindow, tooltip: &str) -> Result<Tray> {
+    let dispatcher = webview.dispatcher();
+    Tray::new(
+        tooltip,
+        Box::new(move |action| {
+            let result = dispatcher.dispatch(Box::new(move |webview| {
+                handle_tray_action(webview, action);
+            }));
+            if let Err(e) = result {
+                log::warn!("Failed to dispatch tray action: {}.", e);
+            }
+        }),
+    )
+}
+
+/// Performs an action picked from the tray icon's menu.
+fn handle_tray_action(webview: &mut dyn PatcherWindow, action: TrayAction) {
+    match action {
+        TrayAction::ShowWindow => {
+            webview.set_visible(true);
+            webview.set_minimized(false);
+        }
+        TrayAction::CheckForUpdates => handle_check_for_updates(webview),
+        TrayAction::PatchNow => handle_start_update(webview),
+        TrayAction::LaunchGame => handle_play(webview),
+        TrayAction::Exit => handle_exit(webview),
+    }
+}
+
+/// Pops a desktop notification if enabled, through the tray icon which is
+/// added on first use if the patcher isn't running in the tray.
+fn notify(webview: &mut dyn PatcherWindow, title: &str, message: &str) {
+    let window_config = &webview.user_data().patcher_config.window;
+    if !window_config.tray && !window_config.notifications {
+        return;
+    }
+    if webview.user_data().tray.is_none() {
+        let tooltip = window_config.title.clone();
+        match new_tray(webview, &tooltip) {
+            Err(e) => {
+                log::warn!("Failed to show notification: {:#}", e);
+                return;
+            }
+            Ok(tray) => webview.user_data_mut().tray = Some(tray),
+        }
+    }
+    if let Some(tray) = &webview.user_data().tray {
+        tray.notify(title, message);
+    }
+}
+
+/// Returns the title and message of the desktop notification to pop for
+/// `status`, if it deserves one.
+fn status_notification(status: &PatchingStatus) -> Option<(String, String)> {
+    let (title_key, message) = match status {
+        PatchingStatus::Ready => (
+            "notification.ready.title",
+            tr("notification.ready.message", &[]),
+        ),
+        PatchingStatus::Error(msg) => ("notification.error.title", msg.clone()),
+        PatchingStatus::AlreadyPatching => (
+            "notification.error.title",
+            tr("notification.already_patching", &[]),
+        ),
+        PatchingStatus::UpdatesAvailable(patch_count) => (
+            "notification.updates_available.title",
+            tr(
+                "notification.updates_available.message",
+                &[("count", &patch_count.to_string())],
+            ),
+        ),
+        PatchingStatus::FileLocked(path, _) if path.is_empty() => (
+            "notification.action_required.title",
+            tr("notification.unknown_file_locked", &[]),
+        ),
+        PatchingStatus::FileLocked(path, _) => (
+            "notification.action_required.title",
+            tr("notification.file_locked", &[("path", path)]),
+        ),
+        _ => return None,
+    };
+    Some((tr(title_key, &[]), message))
+}
+
 /// Opens the configured game client with the configured arguments.
 ///
 /// This function can create elevated processes on Windows with UAC activated.
-fn handle_play(webview: &mut WebView<WebViewUserData>) {
+fn handle_play(webview: &mut dyn PatcherWindow) {
     let client_arguments = webview.user_data().patcher_config.play.arguments.clone();
     start_game_client(webview, &client_arguments);
 }
@@ -135,7 +749,7 @@ fn handle_play(webview: &mut WebView<WebViewUserData>) {
 /// Opens the configured 'Setup' software with the configured arguments.
 ///
 /// This function can create elevated processes on Windows with UAC activated.
-fn handle_setup(webview: &mut WebView<WebViewUserData>) {
+fn handle_setup(webview: &mut dyn PatcherWindow) {
     let setup_exe: &String = &webview.user_data().patcher_config.setup.path;
     let setup_arguments = &webview.user_data().patcher_config.setup.arguments;
     let exit_on_success = webview
@@ -144,7 +758,15 @@ fn handle_setup(webview: &mut WebView<WebViewUserData>) {
         .setup
         .exit_on_success
         .unwrap_or(false);
-    match start_executable(setup_exe, setup_arguments) {
+    let install_directory =
+        match resolve_install_directory(&webview.user_data().patcher_config.client) {
+            Err(e) => {
+                log::warn!("Failed to start setup software: {:#}", e);
+                return;
+            }
+            Ok(v) => v,
+        };
+    match start_executable(setup_exe, &install_directory, setup_arguments) {
         Ok(success) => {
             if success {
                 log::trace!("Setup software started");
@@ -160,12 +782,35 @@ fn handle_setup(webview: &mut WebView<WebViewUserData>) {
 }
 
 /// Exits the patcher cleanly.
-fn handle_exit(webview: &mut WebView<WebViewUserData>) {
+fn handle_exit(webview: &mut dyn PatcherWindow) {
     webview.exit();
 }
 
 /// Starts the patching task/thread.
-fn handle_start_update(webview: &mut WebView<WebViewUserData>) {
+fn handle_start_update(webview: &mut dyn PatcherWindow) {
+    // Patching is already in progress, abort.
+    if webview.user_data().patching_in_progress {
+        let res = webview.eval("notificationInProgress()");
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return;
+    }
+
+    // Note: Updates are only previewed in dry-run mode
+    let command = match webview.user_data().dry_run {
+        Some(preview_mode) => PatcherCommand::Preview(preview_mode),
+        None => PatcherCommand::StartUpdate,
+    };
+    let send_res = webview.user_data_mut().patching_thread_tx.send(command);
+    if send_res.is_ok() {
+        log::trace!("Sent update command to patching thread");
+    }
+}
+
+/// Checks whether new patches are available, like the background update
+/// checks do.
+fn handle_check_for_updates(webview: &mut dyn PatcherWindow) {
     // Patching is already in progress, abort.
     if webview.user_data().patching_in_progress {
         let res = webview.eval("notificationInProgress()");
@@ -175,17 +820,18 @@ fn handle_start_update(webview: &mut WebView<WebViewUserData>) {
         return;
     }
 
-    let send_res = webview
+    if webview
         .user_data_mut()
         .patching_thread_tx
-        .send(PatcherCommand::StartUpdate);
-    if send_res.is_ok() {
-        log::trace!("Sent StartUpdate command to patching thread");
+        .send(PatcherCommand::CheckForUpdates)
+        .is_ok()
+    {
+        log::trace!("Sent CheckForUpdates command to patching thread");
     }
 }
 
 /// Cancels the patching task/thread.
-fn handle_cancel_update(webview: &mut WebView<WebViewUserData>) {
+fn handle_cancel_update(webview: &mut dyn PatcherWindow) {
     if webview
         .user_data_mut()
         .patching_thread_tx
@@ -196,19 +842,70 @@ fn handle_cancel_update(webview: &mut WebView<WebViewUserData>) {
     }
 }
 
+/// Pauses the patching task/thread.
+fn handle_pause_update(webview: &mut dyn PatcherWindow) {
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::PauseUpdate)
+        .is_ok()
+    {
+        log::trace!("Sent PauseUpdate command to patching thread");
+    }
+}
+
+/// Resumes the patching task/thread.
+fn handle_resume_update(webview: &mut dyn PatcherWindow) {
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::ResumeUpdate)
+        .is_ok()
+    {
+        log::trace!("Sent ResumeUpdate command to patching thread");
+    }
+}
+
+/// Tells the patching task/thread what to do with the file that prevents a
+/// patch from being applied.
+fn handle_resolve_locked_file(webview: &mut dyn PatcherWindow, action: LockedFileAction) {
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::ResolveLockedFile(action))
+        .is_ok()
+    {
+        log::trace!("Sent ResolveLockedFile command to patching thread");
+    }
+}
+
 /// Resets the patcher cache (which is used to keep track of already applied
-/// patches).
-fn handle_reset_cache(_webview: &mut WebView<WebViewUserData>) {
-    if let Ok(patcher_name) = get_patcher_name() {
-        let cache_file_path = PathBuf::from(patcher_name).with_extension("dat");
-        if let Err(e) = fs::remove_file(cache_file_path) {
+/// patches), including the caches of all the channels.
+fn handle_reset_cache(webview: &mut dyn PatcherWindow) {
+    if let Ok(cache_file_path) = get_instance_data_file_path("dat") {
+        if let Err(e) = remove_cache_file(&cache_file_path) {
             log::warn!("Failed to remove the cache file: {}", e);
         }
     }
+    for channel in webview.user_data().default_config.channels.keys() {
+        // Channels that haven't been used have no cache file
+        if let Ok(channel_cache_file_path) = get_instance_data_file_path(format!("{}.dat", channel))
+        {
+            let _ = remove_cache_file(channel_cache_file_path);
+        }
+    }
+    for profile in webview.user_data().default_config.profiles.keys() {
+        // Same for profiles
+        if let Ok(profile_cache_file_path) =
+            get_instance_data_file_path(format!("profile-{}.dat", profile))
+        {
+            let _ = remove_cache_file(profile_cache_file_path);
+        }
+    }
 }
 
 /// Asks the user to provide a patch file to apply
-fn handle_manual_patch(webview: &mut WebView<WebViewUserData>) {
+fn handle_manual_patch(webview: &mut dyn PatcherWindow) {
     // Patching is already in progress, abort.
     if webview.user_data().patching_in_progress {
         let res = webview.eval("notificationInProgress()");
@@ -217,11 +914,18 @@ fn handle_manual_patch(webview: &mut WebView<WebViewUserData>) {
         }
         return;
     }
+    if webview.user_data().dry_run.is_some() {
+        log::warn!("Patches cannot be applied in dry-run mode");
+        return;
+    }
 
     let opt_path = tfd::open_file_dialog(
-        "Select a file",
+        &tr("dialog.select_file", &[]),
         "",
-        Some((&["*.thor"], "Patch Files (*.thor)")),
+        Some((
+            &["*.thor", "*.rgz", "*.gpf"],
+            &tr("dialog.patch_files", &[]),
+        )),
     );
     if let Some(path) = opt_path {
         log::info!("Requesting manual patch '{}'", path);
@@ -236,9 +940,38 @@ fn handle_manual_patch(webview: &mut WebView<WebViewUserData>) {
     }
 }
 
+/// Asks the user to provide a directory containing patches to apply
+fn handle_apply_local(webview: &mut dyn PatcherWindow) {
+    // Patching is already in progress, abort.
+    if webview.user_data().patching_in_progress {
+        let res = webview.eval("notificationInProgress()");
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return;
+    }
+    if webview.user_data().dry_run.is_some() {
+        log::warn!("Patches cannot be applied in dry-run mode");
+        return;
+    }
+
+    let opt_path = tfd::select_folder_dialog(&tr("dialog.select_folder", &[]), "");
+    if let Some(path) = opt_path {
+        log::info!("Requesting offline patching from '{}'", path);
+        if webview
+            .user_data_mut()
+            .patching_thread_tx
+            .send(PatcherCommand::ApplyLocal(PathBuf::from(path)))
+            .is_ok()
+        {
+            log::trace!("Sent ApplyLocal command to patching thread");
+        }
+    }
+}
+
 /// Parses JSON requests (for invoking functions with parameters) and dispatches
 /// them to the invoked function.
-fn handle_json_request(webview: &mut WebView<WebViewUserData>, request: &str) {
+fn handle_json_request(webview: &mut dyn PatcherWindow, request: &str) {
     let result: serde_json::Result<Value> = serde_json::from_str(request);
     match result {
         Err(e) => {
@@ -251,6 +984,11 @@ fn handle_json_request(webview: &mut WebView<WebViewUserData>, request: &str) {
                 match function_name {
                     "login" => handle_login(webview, function_params),
                     "open_url" => handle_open_url(function_params),
+                    "select_channel" => handle_select_channel(webview, function_params),
+                    "apply_file" => handle_apply_file(webview, function_params),
+                    "preview_update" => handle_preview_update(webview, function_params),
+                    "set_setting" => handle_set_setting(webview, function_params),
+                    "select_profile" => handle_select_profile(webview, function_params),
                     _ => {
                         log::error!("Unknown function '{}'", function_name);
                     }
@@ -264,35 +1002,67 @@ fn handle_json_request(webview: &mut WebView<WebViewUserData>, request: &str) {
 #[derive(Deserialize)]
 struct LoginParameters {
     login: String,
-    password: String,
+    password: String,       // Empty to use the password saved for `login`
+    token: Option<String>,  // One-time token issued by the server
+    remember: Option<bool>, // Whether to remember the credentials, defaults to false
 }
 
 /// Launches the game client with the given credentials
-fn handle_login(webview: &mut WebView<WebViewUserData>, parameters: Value) {
+fn handle_login(webview: &mut dyn PatcherWindow, parameters: Value) {
     let result: serde_json::Result<LoginParameters> = serde_json::from_value(parameters);
     match result {
         Err(e) => log::error!("Invalid arguments given for 'login': {}", e),
         Ok(login_params) => {
-            // Push credentials to the list of arguments first
-            let mut play_arguments: Vec<String> = vec![
-                format!("-t:{}", login_params.password),
-                login_params.login,
-                "server".to_string(),
-            ];
-            play_arguments.extend(
-                webview
-                    .user_data()
-                    .patcher_config
-                    .play
-                    .arguments
-                    .iter()
-                    .cloned(),
-            );
+            let remember = login_params.remember.unwrap_or(false);
+            let credentials_file_path = get_credentials_file_path();
+            let password = if login_params.password.is_empty() {
+                // Fall back to the password saved for this login, if any
+                credentials_file_path
+                    .as_ref()
+                    .ok()
+                    .and_then(|file_path| StoredCredentials::load(file_path).ok().flatten())
+                    .and_then(|stored_credentials| {
+                        stored_credentials
+                            .password_for(&login_params.login)
+                            .map(str::to_string)
+                    })
+                    .unwrap_or_default()
+            } else {
+                login_params.password
+            };
+            let credentials = Credentials {
+                login: login_params.login,
+                password,
+                token: login_params.token,
+            };
+            let play_config = &webview.user_data().patcher_config.play;
+            if let Err(e) = credentials_file_path.and_then(|file_path| {
+                store_credentials(file_path, play_config, &credentials, remember)
+            }) {
+                log::warn!("Failed to store credentials: {:#}", e);
+            }
+            let play_arguments = build_login_arguments(play_config, &credentials);
             start_game_client(webview, &play_arguments);
         }
     }
 }
 
+/// Reports the login remembered from the last login, and whether its password
+/// is saved, with `patcherCredentials` (`null` if there are none), to fill in
+/// login forms.
+fn handle_get_credentials(webview: &mut dyn PatcherWindow) {
+    let saved_credentials = get_credentials_file_path()
+        .and_then(StoredCredentials::load)
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to read stored credentials: {:#}", e);
+            None
+        })
+        .map(|stored_credentials| stored_credentials.to_saved_credentials());
+    if let Err(e) = webview.eval(&format!("patcherCredentials({})", json!(saved_credentials))) {
+        log::warn!("Failed to dispatch credentials: {}.", e);
+    }
+}
+
 /// Parameters expected for the open_url function
 #[derive(Deserialize)]
 struct OpenUrlParameters {
@@ -319,7 +1089,179 @@ fn handle_open_url(parameters: Value) {
     }
 }
 
-fn start_game_client(webview: &mut WebView<WebViewUserData>, client_arguments: &[String]) {
+/// Parameters expected for the select_channel function
+#[derive(Deserialize)]
+struct SelectChannelParameters {
+    channel: Option<String>, // `None` selects the default channel
+}
+
+/// Switches to another release channel for the next updates
+fn handle_select_channel(webview: &mut dyn PatcherWindow, parameters: Value) {
+    let result: serde_json::Result<SelectChannelParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!("Invalid arguments given for 'select_channel': {}", e),
+        Ok(params) => {
+            // Patching is already in progress, abort.
+            if webview.user_data().patching_in_progress {
+                let res = webview.eval("notificationInProgress()");
+                if let Err(e) = res {
+                    log::warn!("Failed to dispatch notification: {}.", e);
+                }
+                return;
+            }
+
+            if webview
+                .user_data_mut()
+                .patching_thread_tx
+                .send(PatcherCommand::SelectChannel(params.channel))
+                .is_ok()
+            {
+                log::trace!("Sent SelectChannel command to patching thread");
+            }
+        }
+    }
+}
+
+/// Parameters expected for the apply_file function
+#[derive(Deserialize)]
+struct ApplyFileParameters {
+    path: String, // Path or 'file://' URL of the patch file (e.g. dropped on the window)
+}
+
+/// Applies a patch file given by the UI like the patches of an update, for
+/// patches distributed outside of the patch list (e.g. hotfixes)
+fn handle_apply_file(webview: &mut dyn PatcherWindow, parameters: Value) {
+    let result: serde_json::Result<ApplyFileParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!("Invalid arguments given for 'apply_file': {}", e),
+        Ok(params) => {
+            // Patching is already in progress, abort.
+            if webview.user_data().patching_in_progress {
+                let res = webview.eval("notificationInProgress()");
+                if let Err(e) = res {
+                    log::warn!("Failed to dispatch notification: {}.", e);
+                }
+                return;
+            }
+            if webview.user_data().dry_run.is_some() {
+                log::warn!("Patches cannot be applied in dry-run mode");
+                return;
+            }
+
+            // Note: Files dropped on web pages are given as 'file://' URLs
+            let patch_file_path = match Url::parse(&params.path) {
+                Ok(url) if url.scheme() == "file" => match url.to_file_path() {
+                    Err(_) => {
+                        log::error!("Invalid file URL '{}'", params.path);
+                        return;
+                    }
+                    Ok(v) => v,
+                },
+                _ => PathBuf::from(params.path),
+            };
+            log::info!("Requesting patch '{}'", patch_file_path.display());
+            if webview
+                .user_data_mut()
+                .patching_thread_tx
+                .send(PatcherCommand::ApplyFile(patch_file_path))
+                .is_ok()
+            {
+                log::trace!("Sent ApplyFile command to patching thread");
+            }
+        }
+    }
+}
+
+/// Restores the files modified by the last update.
+fn handle_rollback(webview: &mut dyn PatcherWindow) {
+    // Patching is already in progress, abort.
+    if webview.user_data().patching_in_progress {
+        let res = webview.eval("notificationInProgress()");
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return;
+    }
+    if webview.user_data().dry_run.is_some() {
+        log::warn!("Updates cannot be rolled back in dry-run mode");
+        return;
+    }
+
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::Rollback)
+        .is_ok()
+    {
+        log::trace!("Sent Rollback command to patching thread");
+    }
+}
+
+/// Verifies the client's files and repairs the broken ones.
+fn handle_repair(webview: &mut dyn PatcherWindow) {
+    // Patching is already in progress, abort.
+    if webview.user_data().patching_in_progress {
+        let res = webview.eval("notificationInProgress()");
+        if let Err(e) = res {
+            log::warn!("Failed to dispatch notification: {}.", e);
+        }
+        return;
+    }
+    if webview.user_data().dry_run.is_some() {
+        log::warn!("Installations cannot be repaired in dry-run mode");
+        return;
+    }
+
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::Repair)
+        .is_ok()
+    {
+        log::trace!("Sent Repair command to patching thread");
+    }
+}
+
+/// Parameters expected for the preview_update function
+#[derive(Deserialize)]
+struct PreviewUpdateParameters {
+    #[serde(default)]
+    headers_only: bool, // Only fetch the headers of the patches
+}
+
+/// Reports the changes the pending patches would make, without applying them
+fn handle_preview_update(webview: &mut dyn PatcherWindow, parameters: Value) {
+    let result: serde_json::Result<PreviewUpdateParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!("Invalid arguments given for 'preview_update': {}", e),
+        Ok(params) => {
+            // Patching is already in progress, abort.
+            if webview.user_data().patching_in_progress {
+                let res = webview.eval("notificationInProgress()");
+                if let Err(e) = res {
+                    log::warn!("Failed to dispatch notification: {}.", e);
+                }
+                return;
+            }
+
+            let preview_mode = if params.headers_only {
+                PreviewMode::HeadersOnly
+            } else {
+                PreviewMode::FullDownload
+            };
+            if webview
+                .user_data_mut()
+                .patching_thread_tx
+                .send(PatcherCommand::Preview(preview_mode))
+                .is_ok()
+            {
+                log::trace!("Sent Preview command to patching thread");
+            }
+        }
+    }
+}
+
+fn start_game_client(webview: &mut dyn PatcherWindow, client_arguments: &[String]) {
     let client_exe: &String = &webview.user_data().patcher_config.play.path;
     let exit_on_success = webview
         .user_data()
@@ -327,7 +1269,15 @@ fn start_game_client(webview: &mut WebView<WebViewUserData>, client_arguments: &
         .play
         .exit_on_success
         .unwrap_or(true);
-    match start_executable(client_exe, client_arguments) {
+    let install_directory =
+        match resolve_install_directory(&webview.user_data().patcher_config.client) {
+            Err(e) => {
+                log::warn!("Failed to start client: {:#}", e);
+                return;
+            }
+            Ok(v) => v,
+        };
+    match start_executable(client_exe, &install_directory, client_arguments) {
         Ok(success) => {
             if success {
                 log::trace!("Client started");
@@ -341,3 +1291,239 @@ fn start_game_client(webview: &mut WebView<WebViewUserData>, client_arguments: &
         }
     }
 }
+
+/// Reports the current state of the patcher with `patcherState`, for UIs that
+/// poll it.
+fn handle_get_patching_status(webview: &mut dyn PatcherWindow) {
+    let state = json!({
+        "patching_in_progress": webview.user_data().patching_in_progress,
+        "last_status": webview.user_data().last_patching_status,
+    });
+    if let Err(e) = webview.eval(&format!("patcherState({})", state)) {
+        log::warn!("Failed to dispatch patcher state: {}.", e);
+    }
+}
+
+/// Reports the most recent log records with `patcherLogs`, so that users can
+/// copy them from the UI.
+fn handle_get_logs(webview: &mut dyn PatcherWindow) {
+    let records = json!(get_recent_logs());
+    if let Err(e) = webview.eval(&format!("patcherLogs({})", records)) {
+        log::warn!("Failed to dispatch logs: {}.", e);
+    }
+}
+
+/// Reports the items of the news feed with `patcherNews`. The feed is fetched
+/// again if that failed when the patcher started.
+fn handle_get_news(webview: &mut dyn PatcherWindow) {
+    if webview.user_data().patcher_config.web.news_url.is_none() {
+        if let Err(e) = webview.eval("patcherNews([])") {
+            log::warn!("Failed to dispatch news: {}.", e);
+        }
+        return;
+    }
+    match &webview.user_data().news {
+        Some(news) => {
+            let script = format!("patcherNews({})", json!(news));
+            if let Err(e) = webview.eval(&script) {
+                log::warn!("Failed to dispatch news: {}.", e);
+            }
+        }
+        None => {
+            if webview
+                .user_data_mut()
+                .patching_thread_tx
+                .send(PatcherCommand::FetchNews)
+                .is_ok()
+            {
+                log::trace!("Sent FetchNews command to patching thread");
+            }
+        }
+    }
+}
+
+/// Reports the version and build information of the patcher with
+/// `patcherInfo`.
+fn handle_get_patcher_info(webview: &mut dyn PatcherWindow) {
+    if let Err(e) = webview.eval(&format!("patcherInfo({})", json!(patcher_info()))) {
+        log::warn!("Failed to dispatch patcher info: {}.", e);
+    }
+}
+
+/// Reports the most recent patches processed on this machine with
+/// `patchHistory`.
+fn handle_get_history(webview: &mut dyn PatcherWindow) {
+    let history =
+        match get_history_file_path().and_then(|path| read_history(path, Some(HISTORY_LIMIT))) {
+            Ok(history) => history,
+            Err(e) => {
+                log::warn!("Failed to read the patch history: {:#}", e);
+                vec![]
+            }
+        };
+    if let Err(e) = webview.eval(&format!("patchHistory({})", json!(history))) {
+        log::warn!("Failed to dispatch patch history: {}.", e);
+    }
+}
+
+/// Fetches the changelog of the patcher, reported with `patcherChangelog`
+/// (`null` if there's none).
+fn handle_get_changelog(webview: &mut dyn PatcherWindow) {
+    if webview
+        .user_data()
+        .patcher_config
+        .web
+        .changelog_url
+        .is_none()
+    {
+        if let Err(e) = webview.eval("patcherChangelog(null)") {
+            log::warn!("Failed to dispatch changelog: {}.", e);
+        }
+        return;
+    }
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::FetchChangelog)
+        .is_ok()
+    {
+        log::trace!("Sent FetchChangelog command to patching thread");
+    }
+}
+
+/// Reports the settings the UI can change with `patcherSettings`.
+fn handle_get_config(webview: &mut dyn PatcherWindow) {
+    let settings = json!(get_user_settings(&webview.user_data().patcher_config));
+    if let Err(e) = webview.eval(&format!("patcherSettings({})", settings)) {
+        log::warn!("Failed to dispatch patcher settings: {}.", e);
+    }
+}
+
+/// Parameters expected for the set_setting function
+#[derive(Deserialize)]
+struct SetSettingParameters {
+    name: String, // Path of the setting in the configuration (e.g. 'patching.in_place')
+    value: Value,
+}
+
+/// Changes a setting and saves it in the user settings file, so that it's
+/// remembered the next time the patcher starts
+fn handle_set_setting(webview: &mut dyn PatcherWindow, parameters: Value) {
+    let result: serde_json::Result<SetSettingParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!("Invalid arguments given for 'set_setting': {}", e),
+        Ok(params) => {
+            // Patching is already in progress, abort.
+            if webview.user_data().patching_in_progress {
+                let res = webview.eval("notificationInProgress()");
+                if let Err(e) = res {
+                    log::warn!("Failed to dispatch notification: {}.", e);
+                }
+                return;
+            }
+
+            let mut default_config = webview.user_data().default_config.clone();
+            let profile = webview.user_data().patcher_config.profile.clone();
+            let result = get_user_settings_file_path()
+                .and_then(|settings_file_path| {
+                    let mut user_settings = UserSettings::load(&settings_file_path)?;
+                    user_settings.set(&mut default_config, &params.name, params.value.clone())?;
+                    user_settings.save(&settings_file_path)
+                })
+                .and_then(|_| default_config.with_profile(profile.as_deref()));
+            match result {
+                Err(e) => log::error!("Failed to change setting '{}': {:#}", params.name, e),
+                Ok(patcher_config) => {
+                    webview.user_data_mut().default_config = default_config;
+                    update_patcher_configuration(webview, patcher_config);
+                    handle_get_config(webview);
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the configuration used by the UI and by the patching thread.
+fn update_patcher_configuration(
+    webview: &mut dyn PatcherWindow,
+    patcher_config: PatcherConfiguration,
+) {
+    webview.user_data_mut().patcher_config = patcher_config.clone();
+    if webview
+        .user_data_mut()
+        .patching_thread_tx
+        .send(PatcherCommand::UpdateConfiguration(Box::new(
+            patcher_config,
+        )))
+        .is_ok()
+    {
+        log::trace!("Sent UpdateConfiguration command to patching thread");
+    }
+}
+
+/// Reports the profiles listed in the configuration and the selected one with
+/// `patcherProfiles`.
+fn handle_get_profiles(webview: &mut dyn PatcherWindow) {
+    let mut profiles: Vec<Value> = webview
+        .user_data()
+        .default_config
+        .profiles
+        .iter()
+        .map(|(name, profile)| {
+            json!({
+                "name": name,
+                "display_name": profile.display_name.as_deref().unwrap_or(name),
+            })
+        })
+        .collect();
+    profiles.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    let result = webview.eval(&format!(
+        "patcherProfiles({}, {})",
+        json!(profiles),
+        json!(webview.user_data().patcher_config.profile)
+    ));
+    if let Err(e) = result {
+        log::warn!("Failed to dispatch profiles: {}.", e);
+    }
+}
+
+/// Parameters expected for the select_profile function
+#[derive(Deserialize)]
+struct SelectProfileParameters {
+    profile: Option<String>, // `None` for the default profile
+}
+
+/// Switches to another profile (i.e. game client) for the next updates and
+/// launches
+fn handle_select_profile(webview: &mut dyn PatcherWindow, parameters: Value) {
+    let result: serde_json::Result<SelectProfileParameters> = serde_json::from_value(parameters);
+    match result {
+        Err(e) => log::error!("Invalid arguments given for 'select_profile': {}", e),
+        Ok(params) => {
+            // Patching is already in progress, abort.
+            if webview.user_data().patching_in_progress {
+                let res = webview.eval("notificationInProgress()");
+                if let Err(e) = res {
+                    log::warn!("Failed to dispatch notification: {}.", e);
+                }
+                return;
+            }
+
+            let result = webview
+                .user_data()
+                .default_config
+                .with_profile(params.profile.as_deref());
+            match result {
+                Err(e) => log::error!("Failed to select profile: {:#}", e),
+                Ok(patcher_config) => {
+                    log::info!(
+                        "Selected profile '{}'",
+                        params.profile.as_deref().unwrap_or("default")
+                    );
+                    update_patcher_configuration(webview, patcher_config);
+                    handle_get_profiles(webview);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use wry::application::dpi::LogicalSize;
+use wry::application::event::{Event, WindowEvent};
+use wry::application::event_loop::{ControlFlow, EventLoop, EventLoopProxy};
+use wry::application::platform::run_return::EventLoopExtRunReturn;
+use wry::application::window::WindowBuilder;
+use wry::webview::{WebView, WebViewBuilder};
+
+use crate::ui::{handle_invoke, PatcherWindow, WebViewUserData, WindowDispatcher, WindowTask};
+
+// Exposes wry's IPC channel as `external.invoke`, the binding of the
+// `web-view` backend, so that launcher pages work with both backends
+const EXTERNAL_INVOKE_SCRIPT: &str =
+    "window.external = { invoke: function (arg) { window.ipc.postMessage(String(arg)); } };";
+
+/// Events sent to the window's event loop.
+pub enum UserEvent {
+    Invoke(String), // Argument passed to `external.invoke` by the page
+    Dispatch(WindowTask),
+}
+
+/// Web view implemented with `wry`, an alternative to the `web-view` backend
+/// relying on the platform's current web engine.
+pub struct WryWebView {
+    event_loop: EventLoop<UserEvent>,
+    window: WryWindow,
+}
+impl WryWebView {
+    /// Runs the event loop until the window is closed.
+    pub fn run(self) -> Result<()> {
+        let WryWebView {
+            mut event_loop,
+            mut window,
+        } = self;
+        event_loop.run_return(move |event, _, control_flow| {
+            *control_flow = ControlFlow::Wait;
+            match event {
+                Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    ..
+                } => window.exit(),
+                Event::UserEvent(UserEvent::Invoke(arg)) => handle_invoke(&mut window, &arg),
+                Event::UserEvent(UserEvent::Dispatch(task)) => task(&mut window),
+                _ => {}
+            }
+            if window.exit_requested {
+                *control_flow = ControlFlow::Exit;
+            }
+        });
+        Ok(())
+    }
+}
+
+impl PatcherWindow for WryWebView {
+    fn user_data(&self) -> &WebViewUserData {
+        self.window.user_data()
+    }
+
+    fn user_data_mut(&mut self) -> &mut WebViewUserData {
+        self.window.user_data_mut()
+    }
+
+    fn eval(&mut self, js: &str) -> Result<()> {
+        self.window.eval(js)
+    }
+
+    fn exit(&mut self) {
+        self.window.exit();
+    }
+
+    fn set_visible(&mut self, visible: bool) {
+        self.window.set_visible(visible);
+    }
+
+    fn set_minimized(&mut self, minimized: bool) {
+        self.window.set_minimized(minimized);
+    }
+
+    fn dispatcher(&self) -> Arc<dyn WindowDispatcher> {
+        self.window.dispatcher()
+    }
+}
+
+struct WryWindow {
+    webview: WebView,
+    user_data: WebViewUserData,
+    event_loop_proxy: EventLoopProxy<UserEvent>,
+    exit_requested: bool, // Set once the event loop must stop
+}
+
+impl PatcherWindow for WryWindow {
+    fn user_data(&self) -> &WebViewUserData {
+        &self.user_data
+    }
+
+    fn user_data_mut(&mut self) -> &mut WebViewUserData {
+        &mut self.user_data
+    }
+
+    fn eval(&mut self, js: &str) -> Result<()> {
+        Ok(self.webview.evaluate_script(js)?)
+    }
+
+    fn exit(&mut self) {
+        self.exit_requested = true;
+    }
+
+    fn set_visible(&mut self, visible: bool) {
+        self.webview.window().set_visible(visible);
+    }
+
+    fn set_minimized(&mut self, minimized: bool) {
+        self.webview.window().set_minimized(minimized);
+    }
+
+    fn dispatcher(&self) -> Arc<dyn WindowDispatcher> {
+        Arc::new(WryDispatcher {
+            event_loop_proxy: Mutex::new(self.event_loop_proxy.clone()),
+        })
+    }
+}
+
+struct WryDispatcher {
+    // Note: The proxy isn't `Sync` on Windows
+    event_loop_proxy: Mutex<EventLoopProxy<UserEvent>>,
+}
+
+impl WindowDispatcher for WryDispatcher {
+    fn dispatch(&self, task: WindowTask) -> Result<()> {
+        let event_loop_proxy = self
+            .event_loop_proxy
+            .lock()
+            .map_err(|_| anyhow!("Failed to lock the event loop proxy"))?;
+        event_loop_proxy
+            .send_event(UserEvent::Dispatch(task))
+            .map_err(|_| anyhow!("The window has been closed"))
+    }
+}
+
+/// Creates a `WryWebView` object with the same settings as the `web-view`
+/// backend's (see `ui::build_webview`).
+pub fn build_webview(title: &str, user_data: WebViewUserData) -> Result<WryWebView> {
+    let patcher_config = user_data.patcher_config();
+    let event_loop = EventLoop::with_user_event();
+    let window = WindowBuilder::new()
+        .with_title(title)
+        .with_inner_size(LogicalSize::new(
+            patcher_config.window.width,
+            patcher_config.window.height,
+        ))
+        .with_resizable(patcher_config.window.resizable)
+        .build(&event_loop)?;
+    let event_loop_proxy = event_loop.create_proxy();
+    let ipc_proxy = event_loop_proxy.clone();
+    let webview = WebViewBuilder::new(window)?
+        .with_url(&patcher_config.web.index_url)?
+        .with_initialization_script(EXTERNAL_INVOKE_SCRIPT)
+        .with_ipc_handler(move |_, arg| {
+            if ipc_proxy.send_event(UserEvent::Invoke(arg)).is_err() {
+                log::warn!("Failed to dispatch invocation: the window has been closed.");
+            }
+        })
+        .build()?;
+
+    Ok(WryWebView {
+        event_loop,
+        window: WryWindow {
+            webview,
+            user_data,
+            event_loop_proxy,
+            exit_requested: false,
+        },
+    })
+}